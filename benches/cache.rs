@@ -0,0 +1,104 @@
+//! Cache reads and writes of a processed image, in memory and on disk. The
+//! `miss` benchmarks of `cache_filesystem` and `cache_filesystem_indexed`
+//! compare a lookup that checks the disk to one answered by the key index.
+//! `cache_large` reads a multi-megabyte entry from disk; compare runs with
+//! and without `--features mmap`.
+//!
+//! `cargo bench --bench cache`
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use img_optimizer::cache::{ImageCache, ImageMetadata};
+use img_optimizer::image_processor::{Fit, ImageProcessor, OutputFormat, ProcessingPlan};
+use tempfile::TempDir;
+
+#[allow(dead_code)] // The other fixtures are for the processing benchmarks
+mod fixtures;
+
+const KEY: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+fn cache(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().expect("Failed to start the runtime");
+    // A typical entry: the photo resized for a desktop layout
+    let plan = ProcessingPlan {
+        steps: Vec::new(),
+        width: Some(1280),
+        height: None,
+        fit: Fit::Contain,
+        background: None,
+        quality: img_optimizer::DEFAULT_QUALITY,
+        format: Some(OutputFormat::Jpeg),
+    };
+    let processed = ImageProcessor::process_sync(&fixtures::photo_jpeg().bytes, &plan)
+        .expect("Failed to process the fixture");
+    let metadata = ImageMetadata {
+        width: processed.width,
+        height: processed.height,
+        original_width: processed.original_width,
+        original_height: processed.original_height,
+        alpha_flattened: processed.alpha_flattened,
+        upscale_refused: processed.upscale_refused,
+    };
+    let entry = processed.bytes;
+
+    let temp_dir = TempDir::new().expect("Failed to create the cache directory");
+    let indexed_dir = TempDir::new().expect("Failed to create the cache directory");
+    let indexed = ImageCache::indexed(indexed_dir.path().to_path_buf());
+    while !indexed.index_loaded() {
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    let backends = [
+        ("memory", ImageCache::in_memory()),
+        ("filesystem", ImageCache::new(temp_dir.path().to_path_buf())),
+        ("filesystem_indexed", indexed),
+    ];
+    for (backend, mut cache) in backends {
+        let mut group = c.benchmark_group(format!("cache_{backend}"));
+        group.throughput(Throughput::Bytes(entry.len() as u64));
+
+        group.bench_function("put", |b| {
+            b.iter_batched(
+                || entry.clone(),
+                |data| runtime.block_on(cache.put_with_metadata(KEY.to_string(), data, metadata)),
+                BatchSize::SmallInput,
+            )
+        });
+        group.bench_function("get", |b| {
+            b.iter(|| {
+                runtime
+                    .block_on(cache.get(KEY))
+                    .expect("The entry was not cached")
+            })
+        });
+        group.bench_function("metadata", |b| {
+            b.iter(|| runtime.block_on(cache.metadata(KEY)))
+        });
+        group.bench_function("miss", |b| {
+            b.iter(|| runtime.block_on(cache.get("missing")))
+        });
+        group.finish();
+    }
+}
+
+/// Reads a 16 MiB entry and every byte of it, as sending the response would.
+fn large_entry(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().expect("Failed to start the runtime");
+    let entry: Vec<u8> = (0..16 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let temp_dir = TempDir::new().expect("Failed to create the cache directory");
+    let mut cache = ImageCache::new(temp_dir.path().to_path_buf());
+    runtime.block_on(cache.put(KEY.to_string(), entry.clone()));
+
+    let mut group = c.benchmark_group("cache_large");
+    group.throughput(Throughput::Bytes(entry.len() as u64));
+    group.bench_function("get", |b| {
+        b.iter(|| {
+            let data = runtime
+                .block_on(cache.get(KEY))
+                .expect("The entry was not cached");
+            data.iter().fold(0u8, |acc, byte| acc ^ byte)
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, cache, large_entry);
+criterion_main!(benches);
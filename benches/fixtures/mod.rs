@@ -0,0 +1,123 @@
+//! Benchmark inputs, generated from a fixed seed on every run so results
+//! compare the same bytes without committing large binaries.
+
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::codecs::jpeg::JpegEncoder;
+use image::{Delay, DynamicImage, Frame, ImageFormat, Rgb, RgbImage, Rgba, RgbaImage};
+use std::io::Cursor;
+
+pub struct Fixture {
+    pub name: &'static str,
+    pub bytes: Vec<u8>,
+}
+
+pub fn all() -> Vec<Fixture> {
+    vec![photo_jpeg(), transparent_png(), animated_gif()]
+}
+
+/// 4000x3000 JPEG at quality 90: smooth gradients under sensor-like noise,
+/// which keeps the encoder from finding the flat areas synthetic images have.
+pub fn photo_jpeg() -> Fixture {
+    let mut noise = Noise::new(0x5eed_0001);
+    let img = RgbImage::from_fn(4000, 3000, |x, y| {
+        let (fx, fy) = (x as f32 / 4000.0, y as f32 / 3000.0);
+        let wave = ((fx * 23.0).sin() * (fy * 17.0).cos() * 40.0) as i32;
+        Rgb([
+            channel(60 + (fx * 150.0) as i32 + wave, noise.next()),
+            channel(90 + (fy * 120.0) as i32 - wave / 2, noise.next()),
+            channel(140 - ((fx + fy) * 50.0) as i32 + wave, noise.next()),
+        ])
+    });
+
+    let mut bytes = Vec::new();
+    JpegEncoder::new_with_quality(&mut bytes, 90)
+        .encode_image(&img)
+        .expect("Failed to encode the JPEG fixture");
+    Fixture {
+        name: "photo_jpeg",
+        bytes,
+    }
+}
+
+/// 2000x2000 PNG of overlapping soft-edged discs on a transparent
+/// background, like a large logo or illustration.
+pub fn transparent_png() -> Fixture {
+    let discs = [
+        (600.0, 700.0, 450.0, [220, 60, 60]),
+        (1300.0, 800.0, 500.0, [60, 160, 220]),
+        (1000.0, 1400.0, 550.0, [240, 200, 40]),
+    ];
+    let img = RgbaImage::from_fn(2000, 2000, |x, y| {
+        let mut pixel = Rgba([0, 0, 0, 0]);
+        for (cx, cy, radius, [r, g, b]) in discs {
+            let distance = ((x as f32 - cx).powi(2) + (y as f32 - cy).powi(2)).sqrt();
+            // Fully opaque inside, fading out over the last 60 pixels
+            let alpha = ((radius - distance) / 60.0).clamp(0.0, 1.0);
+            if alpha > 0.0 {
+                pixel = Rgba([r, g, b, ((alpha * 255.0) as u8).max(pixel[3])]);
+            }
+        }
+        pixel
+    });
+
+    let mut bytes = Vec::new();
+    DynamicImage::ImageRgba8(img)
+        .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+        .expect("Failed to encode the PNG fixture");
+    Fixture {
+        name: "transparent_png",
+        bytes,
+    }
+}
+
+/// 640x480 GIF of 10 frames of a moving gradient. Only the first frame is
+/// processed, but the whole file is read.
+pub fn animated_gif() -> Fixture {
+    let frames = (0..10u32).map(|index| {
+        let img = RgbaImage::from_fn(640, 480, |x, y| {
+            let shift = index * 24;
+            Rgba([
+                ((x + shift) % 256) as u8,
+                ((y + shift) % 256) as u8,
+                ((x + y) / 5 % 256) as u8,
+                255,
+            ])
+        });
+        Frame::from_parts(img, 0, 0, Delay::from_numer_denom_ms(80, 1))
+    });
+
+    let mut bytes = Vec::new();
+    {
+        let mut encoder = GifEncoder::new(&mut bytes);
+        encoder
+            .set_repeat(Repeat::Infinite)
+            .expect("Failed to set the GIF repeat");
+        encoder
+            .encode_frames(frames)
+            .expect("Failed to encode the GIF fixture");
+    }
+    Fixture {
+        name: "animated_gif",
+        bytes,
+    }
+}
+
+fn channel(base: i32, noise: u8) -> u8 {
+    (base + i32::from(noise % 25) - 12).clamp(0, 255) as u8
+}
+
+/// xorshift32, so fixtures do not depend on a random number crate.
+struct Noise(u32);
+
+impl Noise {
+    fn new(seed: u32) -> Self {
+        Self(seed)
+    }
+
+    fn next(&mut self) -> u8 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        (self.0 >> 24) as u8
+    }
+}
@@ -0,0 +1,48 @@
+//! Decode, resize and encode through `ImageProcessor::process_sync`, for
+//! each fixture, available output format and width.
+//!
+//! `cargo bench --bench processing`, optionally filtered, e.g.
+//! `cargo bench --bench processing -- photo_jpeg/webp`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use img_optimizer::image_processor::{Fit, ImageProcessor, OutputFormat, ProcessingPlan};
+use img_optimizer::DEFAULT_QUALITY;
+use std::hint::black_box;
+
+mod fixtures;
+
+const WIDTHS: [u32; 3] = [320, 1280, 2560];
+
+fn process(c: &mut Criterion) {
+    for fixture in fixtures::all() {
+        let mut group = c.benchmark_group(fixture.name);
+        // Every iteration decodes the full-size source, so few samples are
+        // needed and more would take minutes
+        group.sample_size(10);
+        group.throughput(Throughput::Bytes(fixture.bytes.len() as u64));
+
+        for format in OutputFormat::available() {
+            for width in WIDTHS {
+                let plan = ProcessingPlan {
+                    steps: Vec::new(),
+                    width: Some(width),
+                    height: None,
+                    fit: Fit::Contain,
+                    background: None,
+                    quality: DEFAULT_QUALITY,
+                    format: Some(format),
+                };
+                group.bench_with_input(BenchmarkId::new(format.name(), width), &plan, |b, plan| {
+                    b.iter(|| {
+                        ImageProcessor::process_sync(black_box(&fixture.bytes), plan)
+                            .expect("Failed to process the fixture")
+                    })
+                });
+            }
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, process);
+criterion_main!(benches);
@@ -0,0 +1,68 @@
+//! Arbitrary strings through every parser of request parameters: native,
+//! Next.js and imgix queries, path-style options, colors, base64url and
+//! `data:` URLs, then validation and the rendering of the errors they return.
+//!
+//! Input: a query string, or `{options}/{src_b64}` for path-style requests.
+
+#![no_main]
+
+use img_optimizer::config::Limits;
+use img_optimizer::error::AppError;
+use img_optimizer::{
+    data_url, decode_base64url, imgix, parse_color, parse_query, path_options, ImageParams,
+    NextImageParams, MAX_IMAGE_SIZE,
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(input) = std::str::from_utf8(data) else {
+        return;
+    };
+
+    match parse_query::<ImageParams>(input) {
+        Ok(params) => check(params),
+        Err(err) => render(&err),
+    }
+    if let Err(err) = parse_query::<NextImageParams>(input) {
+        render(&err);
+    }
+    if imgix::is_imgix_query(input) {
+        for strict in [false, true] {
+            match imgix::translate(input, strict) {
+                Ok(translation) => check(translation.params),
+                Err(err) => render(&err),
+            }
+        }
+    }
+    if let Some((options, src_b64)) = input.split_once('/') {
+        match path_options::parse(options, src_b64) {
+            Ok(params) => check(params),
+            Err(err) => render(&err),
+        }
+    }
+
+    let _ = parse_color(input);
+    if let Err(err) = decode_base64url(input) {
+        render(&err);
+    }
+});
+
+/// Validates `params` as the pipeline does, then decodes `data:` sources.
+fn check(params: ImageParams) {
+    match params.validate(&Limits::default()) {
+        Ok(validated) => {
+            if let Some(source) = validated.source.filter(|src| src.starts_with("data:")) {
+                if let Err(err) = data_url::decode(&source, MAX_IMAGE_SIZE) {
+                    render(&err);
+                }
+            }
+        }
+        Err(err) => render(&err),
+    }
+}
+
+/// Errors embed the offending values in their messages and bodies.
+fn render(err: &AppError) {
+    let _ = err.to_string();
+    let _ = serde_json::to_string(&err.to_response());
+}
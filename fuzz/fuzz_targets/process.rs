@@ -0,0 +1,60 @@
+//! Arbitrary bytes through validation and `ImageProcessor::process_sync`,
+//! the path uploads and fetched sources take. Decoding is bounded by the
+//! pixel budget, so any input must return, as an image or an error.
+//!
+//! Input layout: 8 option bytes, then the image.
+//!
+//! | byte | option                                              |
+//! |------|-----------------------------------------------------|
+//! | 0-1  | `w`, little endian, 0 for none                      |
+//! | 2-3  | `h`, little endian, 0 for none                      |
+//! | 4    | `fit`: contain, cover, pad or none                  |
+//! | 5    | `f`: jpeg, png, webp or none                        |
+//! | 6    | `q`, 0 for none                                     |
+//! | 7    | `bg`: low bit set for one, from the first 4 bytes   |
+
+#![no_main]
+
+use img_optimizer::config::Limits;
+use img_optimizer::image_processor::ImageProcessor;
+use img_optimizer::ImageParams;
+use libfuzzer_sys::fuzz_target;
+
+const OPTIONS_LEN: usize = 8;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((options, image)) = data.split_first_chunk::<OPTIONS_LEN>() else {
+        return;
+    };
+
+    let number = |value: u16| (value != 0).then(|| value.to_string());
+    let params = ImageParams {
+        w: number(u16::from_le_bytes([options[0], options[1]])),
+        h: number(u16::from_le_bytes([options[2], options[3]])),
+        fit: ["contain", "cover", "pad"]
+            .get(usize::from(options[4] % 4))
+            .map(|fit| fit.to_string()),
+        f: ["jpeg", "png", "webp"]
+            .get(usize::from(options[5] % 4))
+            .map(|format| format.to_string()),
+        q: number(u16::from(options[6])),
+        bg: (options[7] & 1 == 1).then(|| hex(&options[..4])),
+        ..Default::default()
+    };
+
+    // Out-of-range values must be rejected here, as they are for requests
+    let Ok(validated) = params.validate(&Limits::default()) else {
+        return;
+    };
+    if let Ok(processed) = ImageProcessor::process_sync(image, &validated.plan) {
+        assert!(!processed.bytes.is_empty());
+        assert_eq!(
+            ImageProcessor::dimensions(&processed.bytes),
+            Some((processed.width, processed.height))
+        );
+    }
+});
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
@@ -0,0 +1,435 @@
+//! Audit log of the sources fetched, for abuse investigations and licensing
+//! audits: one JSON line per image request for a remote source, appended to
+//! `audit-YYYY-MM-DD.jsonl` in `audit.dir`, one file per UTC day.
+//!
+//! Records are written by a dedicated thread, so requests only queue them.
+//! The same thread deletes the files older than `audit.retention_days`.
+//! `GET /img-optimizer/v1/audit` reads the files back, see [`summarize`].
+
+use crate::config::AuditConfig;
+use crate::error::{truncate_src, AppError, AppResult};
+use crate::ImageParams;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Records queued for writing; further ones are dropped.
+const QUEUE_CAPACITY: usize = 4096;
+
+/// How often files past their retention are looked for.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+const SECS_PER_DAY: u64 = 86_400;
+
+/// One request for a remote source.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditRecord {
+    /// Unix time, in seconds.
+    pub ts: u64,
+    pub host: String,
+    pub path: String,
+    /// Transformation parameters, as a query string.
+    pub params: String,
+    /// HTTP status answered.
+    pub status: u16,
+    /// Error code, for failed requests.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Bytes read from the source, 0 when served from the cache.
+    pub bytes_in: u64,
+    /// Bytes of the image served.
+    pub bytes_out: u64,
+}
+
+impl AuditRecord {
+    /// Record of a successful request for `src`, timestamped now. `None`
+    /// for sources without a host, such as `data:` URLs.
+    pub fn new(src: &str, params: String) -> Option<Self> {
+        let url = url::Url::parse(src).ok()?;
+        let host = url.host_str()?.to_ascii_lowercase();
+        Some(Self {
+            ts: unix_time(),
+            host,
+            path: truncate_src(url.path()).into_owned(),
+            params,
+            status: 200,
+            error: None,
+            bytes_in: 0,
+            bytes_out: 0,
+        })
+    }
+
+    /// Marks the request as failed with `err`.
+    pub fn fail(&mut self, err: &AppError) {
+        self.status = err.metadata().status.as_u16();
+        self.error = Some(err.error_code().to_string());
+    }
+}
+
+/// The transformation parameters of `params`, the source left out, as a
+/// query string.
+pub fn params_query(params: &ImageParams) -> String {
+    let pairs = [
+        ("w", &params.w),
+        ("h", &params.h),
+        ("fit", &params.fit),
+        ("bg", &params.bg),
+        ("q", &params.q),
+        ("f", &params.f),
+        ("tx", &params.tx),
+    ];
+    let mut query = url::form_urlencoded::Serializer::new(String::new());
+    for (name, value) in pairs {
+        if let Some(value) = value {
+            query.append_pair(name, value);
+        }
+    }
+    query.finish()
+}
+
+enum Message {
+    Record(AuditRecord),
+    /// Answered once the records queued before it are written.
+    Flush(mpsc::Sender<()>),
+}
+
+/// Handle on the thread writing the audit log.
+pub struct AuditLog {
+    dir: PathBuf,
+    sender: SyncSender<Message>,
+    dropped: AtomicU64,
+}
+
+impl std::fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuditLog")
+            .field("dir", &self.dir)
+            .field("dropped", &self.dropped)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AuditLog {
+    /// Starts the thread writing to `dir`, created when missing, and
+    /// deleting the files older than `retention_days`.
+    pub fn start(dir: PathBuf, retention_days: u32) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_CAPACITY);
+        let writer = Writer {
+            dir: dir.clone(),
+            retention_days,
+            file: None,
+            pruned_at: Instant::now(),
+        };
+        std::thread::Builder::new()
+            .name("audit-log".to_string())
+            .spawn(move || writer.run(&receiver))?;
+        Ok(Self {
+            dir,
+            sender,
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// The log `config` asks for, `None` when `audit.dir` is unset or the
+    /// log can't be started.
+    pub fn from_config(config: &AuditConfig) -> Option<Self> {
+        let dir = config.dir.clone()?;
+        Self::start(dir.clone(), config.retention_days)
+            .inspect_err(|e| warn!("Audit log disabled, failed to start it in {dir:?}: {e}"))
+            .ok()
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Queues `record` for writing. It is dropped, and counted, when the
+    /// writer is that far behind.
+    pub fn record(&self, record: AuditRecord) {
+        if self.sender.try_send(Message::Record(record)).is_err() {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped.is_power_of_two() {
+                warn!("Audit log writer is behind, {dropped} records dropped so far");
+            }
+        }
+    }
+
+    /// Records dropped because the writer was behind.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Blocks until the records queued so far are written.
+    pub fn flush(&self) {
+        let (done, written) = mpsc::channel();
+        if self.sender.send(Message::Flush(done)).is_ok() {
+            let _ = written.recv();
+        }
+    }
+
+    /// [`summarize`] of this log, once the records queued so far are
+    /// written. Blocks on the filesystem.
+    pub fn summarize(&self, host: Option<&str>, since: Option<u64>) -> io::Result<AuditSummary> {
+        self.flush();
+        summarize(&self.dir, host, since)
+    }
+}
+
+struct Writer {
+    dir: PathBuf,
+    retention_days: u32,
+    /// File of the day records are being written for.
+    file: Option<(u64, File)>,
+    pruned_at: Instant,
+}
+
+impl Writer {
+    fn run(mut self, receiver: &Receiver<Message>) {
+        self.prune();
+        loop {
+            match receiver.recv_timeout(PRUNE_INTERVAL) {
+                Ok(Message::Record(record)) => {
+                    if let Err(e) = self.write(&record) {
+                        warn!("Failed to write audit record: {e}");
+                    }
+                }
+                Ok(Message::Flush(done)) => {
+                    let _ = done.send(());
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+            if self.pruned_at.elapsed() >= PRUNE_INTERVAL {
+                self.prune();
+            }
+        }
+    }
+
+    fn write(&mut self, record: &AuditRecord) -> io::Result<()> {
+        let day = record.ts / SECS_PER_DAY;
+        let file = match &mut self.file {
+            Some((open_day, file)) if *open_day == day => file,
+            slot => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(self.dir.join(file_name(day)))?;
+                &mut slot.insert((day, file)).1
+            }
+        };
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        file.write_all(&line)
+    }
+
+    fn prune(&mut self) {
+        self.pruned_at = Instant::now();
+        match prune(&self.dir, self.retention_days, unix_time() / SECS_PER_DAY) {
+            Ok(0) => {}
+            Ok(removed) => info!("Removed {removed} audit log files past retention"),
+            Err(e) => warn!("Failed to prune the audit log: {e}"),
+        }
+    }
+}
+
+/// Deletes the files of `dir` for days `retention_days` or more before
+/// `today`, counted in days since the Unix epoch. Returns how many.
+pub fn prune(dir: &Path, retention_days: u32, today: u64) -> io::Result<usize> {
+    let mut removed = 0;
+    for (day, path) in day_files(dir)? {
+        if today.saturating_sub(day) >= u64::from(retention_days) {
+            fs::remove_file(path)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Query of `GET /img-optimizer/v1/audit`.
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditQuery {
+    /// Only the sources on this host.
+    pub host: Option<String>,
+    /// Only the requests from this day on, as `YYYY-MM-DD` in UTC.
+    pub since: Option<String>,
+}
+
+impl AuditQuery {
+    /// `since` in days since the Unix epoch.
+    pub fn since_day(&self) -> AppResult<Option<u64>> {
+        self.since
+            .as_deref()
+            .map(|since| {
+                parse_day(since).ok_or_else(|| AppError::InvalidParameterValue {
+                    param: "since".to_string(),
+                    value: since.to_string(),
+                    expected: "a date as YYYY-MM-DD, from 1970-01-01 on".to_string(),
+                })
+            })
+            .transpose()
+    }
+}
+
+/// Counts of the requests of one source.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SourceSummary {
+    pub host: String,
+    pub path: String,
+    pub count: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Requests per HTTP status answered.
+    pub statuses: BTreeMap<u16, u64>,
+    /// Failed requests per error code.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub errors: BTreeMap<String, u64>,
+    /// Unix time of the latest request.
+    pub last_seen: u64,
+}
+
+/// Body of `GET /img-optimizer/v1/audit`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct AuditSummary {
+    /// Requests counted.
+    pub total: u64,
+    /// Most requested first.
+    pub sources: Vec<SourceSummary>,
+}
+
+/// Counts, per source, of the requests logged in `dir` since the start of
+/// day `since` (in days since the Unix epoch, every file kept when `None`),
+/// only those for `host` when given. Lines that don't parse, such as one cut
+/// short by a crash, are skipped.
+pub fn summarize(dir: &Path, host: Option<&str>, since: Option<u64>) -> io::Result<AuditSummary> {
+    let since_day = since.unwrap_or(0);
+    let mut sources: HashMap<(String, String), SourceSummary> = HashMap::new();
+    for (day, path) in day_files(dir)? {
+        if day < since_day {
+            continue;
+        }
+        for line in BufReader::new(File::open(path)?).lines() {
+            let Ok(record) = serde_json::from_str::<AuditRecord>(&line?) else {
+                continue;
+            };
+            if record.ts < since_day * SECS_PER_DAY
+                || host.is_some_and(|host| !host.eq_ignore_ascii_case(&record.host))
+            {
+                continue;
+            }
+
+            let source = sources
+                .entry((record.host.clone(), record.path.clone()))
+                .or_insert_with(|| SourceSummary {
+                    host: record.host,
+                    path: record.path,
+                    ..Default::default()
+                });
+            source.count += 1;
+            source.bytes_in += record.bytes_in;
+            source.bytes_out += record.bytes_out;
+            *source.statuses.entry(record.status).or_default() += 1;
+            if let Some(code) = record.error {
+                *source.errors.entry(code).or_default() += 1;
+            }
+            source.last_seen = source.last_seen.max(record.ts);
+        }
+    }
+
+    let mut sources: Vec<SourceSummary> = sources.into_values().collect();
+    sources.sort_by(|a, b| {
+        b.count
+            .cmp(&a.count)
+            .then_with(|| (&a.host, &a.path).cmp(&(&b.host, &b.path)))
+    });
+    Ok(AuditSummary {
+        total: sources.iter().map(|source| source.count).sum(),
+        sources,
+    })
+}
+
+/// The audit files of `dir` with their day, other files left out.
+fn day_files(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let day = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("audit-"))
+            .and_then(|name| name.strip_suffix(".jsonl"))
+            .and_then(parse_day);
+        if let Some(day) = day {
+            files.push((day, entry.path()));
+        }
+    }
+    Ok(files)
+}
+
+fn file_name(day: u64) -> String {
+    format!("audit-{}.jsonl", format_day(day))
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// `YYYY-MM-DD` of the day `days` after 1970-01-01.
+pub fn format_day(days: u64) -> String {
+    // Howard Hinnant's `civil_from_days`, for days after the epoch
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// Days since 1970-01-01 of a `YYYY-MM-DD` date, from then on.
+pub fn parse_day(value: &str) -> Option<u64> {
+    let mut parts = value.splitn(3, '-');
+    let (year, month, day) = (parts.next()?, parts.next()?, parts.next()?);
+    if year.len() != 4 || month.len() != 2 || day.len() != 2 {
+        return None;
+    }
+    let number = |part: &str| {
+        part.bytes()
+            .all(|b| b.is_ascii_digit())
+            .then(|| part.parse::<u64>().ok())
+            .flatten()
+    };
+    let (year, month, day) = (number(year)?, number(month)?, number(day)?);
+    if year < 1970 || !(1..=12).contains(&month) || day == 0 {
+        return None;
+    }
+
+    // Inverse of `format_day`, with March as the first month
+    let year = year - u64::from(month <= 2);
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let shifted_month = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * shifted_month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = (era * 146_097 + day_of_era).checked_sub(719_468)?;
+    // Days past the end of the month roll over into the next one
+    (format_day(days) == value).then_some(days)
+}
@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+#[cfg(feature = "actix")]
+use {
+    crate::error::{AppError, AppResult},
+    crate::AppState,
+    actix_web::{
+        body::{EitherBody, MessageBody},
+        dev::{ServiceRequest, ServiceResponse},
+        http::header,
+        middleware::Next,
+        web, Error, HttpMessage, HttpRequest, HttpResponse,
+    },
+    log::debug,
+};
+
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+pub const API_KEY_QUERY_PARAM: &str = "key";
+
+/// Set of accepted API keys, each mapped to a label used for log attribution.
+///
+/// Parsed from a comma-separated list where every entry is either `key` or
+/// `label:key`. An empty set disables authentication entirely.
+#[derive(Debug, Clone, Default)]
+pub struct ApiKeys {
+    keys: HashMap<String, String>,
+}
+
+/// Label of the API key that authorized the current request, stored in the
+/// request extensions by [`require_api_key`]. Logged as `key` by the access
+/// log, and counted by `img_optimizer_requests_by_key_total`.
+#[derive(Debug, Clone)]
+pub struct ApiKeyLabel(pub String);
+
+impl ApiKeys {
+    pub fn parse(raw: &str) -> Self {
+        let keys = raw
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .enumerate()
+            .filter_map(|(index, entry)| {
+                let (label, key) = match entry.split_once(':') {
+                    Some((label, key)) => (label.trim().to_string(), key.trim()),
+                    None => (format!("key-{}", index + 1), entry),
+                };
+                (!key.is_empty()).then(|| (key.to_string(), label))
+            })
+            .collect();
+        Self { keys }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Returns the label of the matching key, comparing in constant time so
+    /// the check does not leak how much of a key was correct.
+    pub fn label_for(&self, candidate: &str) -> Option<&str> {
+        self.keys.iter().fold(None, |found, (key, label)| {
+            if constant_time_eq(key.as_bytes(), candidate.as_bytes()) {
+                Some(label.as_str())
+            } else {
+                found
+            }
+        })
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Checks the `Authorization: Bearer <token>` header against the configured
+/// admin token. Always fails when no admin token is configured.
+#[cfg(feature = "actix")]
+pub fn require_admin_token(req: &HttpRequest, expected: Option<&str>) -> AppResult<()> {
+    let provided = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match (expected, provided) {
+        (Some(expected), Some(provided))
+            if constant_time_eq(expected.as_bytes(), provided.trim().as_bytes()) =>
+        {
+            Ok(())
+        }
+        _ => Err(AppError::InvalidAdminToken),
+    }
+}
+
+#[cfg(feature = "actix")]
+fn extract_api_key(req: &ServiceRequest) -> Option<String> {
+    if let Some(value) = req.headers().get(API_KEY_HEADER) {
+        return value.to_str().ok().map(str::to_string);
+    }
+
+    url::form_urlencoded::parse(req.query_string().as_bytes())
+        .find(|(name, _)| name == API_KEY_QUERY_PARAM)
+        .map(|(_, value)| value.into_owned())
+}
+
+/// Middleware rejecting requests without a valid API key before any fetching
+/// or processing happens. A no-op when no keys are configured.
+#[cfg(feature = "actix")]
+pub async fn require_api_key(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let state = req
+        .app_data::<web::Data<AppState>>()
+        .filter(|state| state.api_keys.is_enabled())
+        .cloned();
+
+    if let Some(state) = state {
+        let label = extract_api_key(&req)
+            .and_then(|key| state.api_keys.label_for(&key).map(str::to_string));
+
+        match label {
+            Some(label) => {
+                debug!("Request to {} authorized with key '{label}'", req.path());
+                state.metrics.record_key(&label);
+                req.extensions_mut().insert(ApiKeyLabel(label));
+            }
+            None => {
+                let response = HttpResponse::from_error(AppError::Unauthorized);
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+        }
+    }
+
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
@@ -0,0 +1,708 @@
+//! tower/axum adapter serving the core pipeline, behind the `axum` feature.
+//!
+//! [`router`] (or [`ImageOptimizerService`], its `tower::Service` form) serves
+//! the image routes of the actix-web server with the same RFC 7807 error
+//! bodies, so an axum application can mount the optimizer under a path:
+//!
+//! ```ignore
+//! let app = axum::Router::new().nest_service("/images", ImageOptimizerService::new(state));
+//! ```
+//!
+//! Operational endpoints (`/status`, `/metrics`, `/debug`, readiness) and the
+//! admin-only storage routes stay with the standalone server.
+
+use crate::auth::{ApiKeyLabel, API_KEY_HEADER, API_KEY_QUERY_PARAM};
+use crate::config::{AppConfig, ErrorDetail};
+use crate::error::{problem_instance, AppError, AppResult};
+use crate::metrics::PhaseTimings;
+use crate::range::{self, RangeRequest};
+use crate::{
+    bundle, check_query_length, download_filename, imgix, jobs, metadata_headers,
+    parse_image_query, parse_query, path_options, pre_route, process_image_request,
+    process_stored_request, process_upload_request, read_limited, srcset,
+    stored_image_content_type, warnings_header, AppState, Conditions, ErrorListParams, Freshness,
+    IfNoneMatch, ImageOutput, ImageParams, NextImageParams, PreRouteDecision, ResponseInputs,
+};
+use axum::{
+    body::Body,
+    extract::{OriginalUri, Path, Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{future::RouteFuture, get, post, MethodRouter},
+    Router,
+};
+use serde::de::DeserializeOwned;
+use std::convert::Infallible;
+use std::task::{Context, Poll};
+
+/// Policy Next.js's optimizer sends with images, as in the actix handler.
+const NEXT_IMAGE_CONTENT_SECURITY_POLICY: &str = "script-src 'none'; frame-src 'none'; sandbox;";
+
+/// `tower::Service` answering the optimizer routes, for stacks that mount
+/// services rather than routers.
+#[derive(Clone)]
+pub struct ImageOptimizerService {
+    router: Router,
+}
+
+impl ImageOptimizerService {
+    pub fn new(state: AppState) -> Self {
+        Self {
+            router: router(state),
+        }
+    }
+
+    pub fn into_router(self) -> Router {
+        self.router
+    }
+}
+
+impl tower_service::Service<Request<Body>> for ImageOptimizerService {
+    type Response = Response;
+    type Error = Infallible;
+    type Future = RouteFuture<Infallible>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        tower_service::Service::<Request<Body>>::poll_ready(&mut self.router, cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        self.router.call(req)
+    }
+}
+
+/// Router with the image routes of the actix-web server, API key checks
+/// included.
+pub fn router(state: AppState) -> Router {
+    let images = Router::new()
+        .route(
+            "/img-optimizer/v1/img",
+            get(optimize_image)
+                .post(upload_image)
+                .fallback(method_not_allowed("GET, POST")),
+        )
+        .route("/img-optimizer/v1/img/srcset", get_only(srcset))
+        .route("/img-optimizer/v1/img/bundle", get_only(bundle))
+        .route("/img-optimizer/v1/img/{image_id}", get_only(direct_image))
+        .route(
+            "/img-optimizer/v1/jobs",
+            post(create_job).fallback(method_not_allowed("POST")),
+        )
+        .route("/img-optimizer/v1/jobs/{id}", get_only(job_status))
+        .route(
+            "/img-optimizer/v1/t/{options}/{src_b64}",
+            get_only(transform_path),
+        )
+        .route("/_next/image", get_only(next_image))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            require_api_key,
+        ));
+
+    Router::new()
+        .route("/health", get_only(health_check))
+        .route("/health/live", get_only(health_check))
+        .route("/errors", get_only(list_errors))
+        .merge(images)
+        .fallback(|uri: Uri| async move {
+            AppError::RouteNotFound {
+                path: uri.path().to_string(),
+            }
+        })
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            problem_details_context,
+        ))
+        .with_state(state)
+}
+
+/// Route serving `handler` on `GET` and a 405 on any other method.
+fn get_only<H, T>(handler: H) -> MethodRouter<AppState>
+where
+    H: axum::handler::Handler<T, AppState>,
+    T: 'static,
+{
+    get(handler).fallback(method_not_allowed("GET"))
+}
+
+fn method_not_allowed(allowed: &'static str) -> MethodRouter<AppState> {
+    axum::routing::any(move |method: Method| async move {
+        AppError::MethodNotAllowed {
+            method: method.to_string(),
+            allowed: allowed.to_string(),
+        }
+    })
+}
+
+/// Fills `instance` in the ProblemDetails body of `AppError` responses,
+/// withholds the error's values and the query when `ERROR_DETAIL=minimal`
+/// and counts the error, like the actix-web middleware.
+async fn problem_details_context(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let uri = match req.extensions().get::<OriginalUri>() {
+        Some(OriginalUri(uri)) => uri,
+        None => req.uri(),
+    };
+    let path = uri.path().to_string();
+    let instance = problem_instance(&path, uri.query());
+    let mut response = next.run(req).await;
+
+    let Some(err) = response.extensions_mut().remove::<AppError>() else {
+        return response;
+    };
+    state.metrics.record_error_response(&err);
+
+    let details = if state.config().server.error_detail == ErrorDetail::Minimal {
+        let reference = uuid::Uuid::new_v4().to_string();
+        tracing::warn!(reference = %reference, "{err}");
+        err.to_minimal_response(&reference).with_instance(path)
+    } else {
+        err.to_response().with_instance(instance)
+    };
+
+    response.headers_mut().remove(header::CONTENT_LENGTH);
+    *response.body_mut() = Body::from(details.to_json());
+    response
+}
+
+/// Rejects requests without a valid API key, see [`crate::auth::ApiKeys`].
+/// A no-op when no keys are configured.
+async fn require_api_key(State(state): State<AppState>, mut req: Request, next: Next) -> Response {
+    if state.api_keys.is_enabled() {
+        let key = match req.headers().get(API_KEY_HEADER) {
+            Some(value) => value.to_str().ok().map(str::to_string),
+            None => url::form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+                .find(|(name, _)| name == API_KEY_QUERY_PARAM)
+                .map(|(_, value)| value.into_owned()),
+        };
+        let Some(label) = key.and_then(|key| state.api_keys.label_for(&key).map(str::to_string))
+        else {
+            return AppError::Unauthorized.into_response();
+        };
+        state.metrics.record_key(&label);
+        req.extensions_mut().insert(ApiKeyLabel(label));
+    }
+
+    next.run(req).await
+}
+
+async fn health_check() -> Response {
+    json_response(serde_json::json!({
+        "status": "ok",
+        "service": "img-optimizer"
+    }))
+}
+
+async fn list_errors(uri: Uri) -> AppResult<Response> {
+    let query: ErrorListParams = query(&uri)?;
+    if query.format.as_deref() == Some("json") {
+        let catalog = AppError::catalog();
+        return Ok(json_response(serde_json::json!({
+            "errors": catalog,
+            "total": catalog.len()
+        })));
+    }
+
+    let errors = AppError::list_all_errors();
+    Ok(json_response(serde_json::json!({
+        "errors": errors,
+        "total": errors.len()
+    })))
+}
+
+async fn optimize_image(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> AppResult<Response> {
+    let raw_query = uri.query().unwrap_or_default();
+    check_query_length(raw_query, &state.config().limits)?;
+    if !state.config().imgix.enabled && !imgix::is_imgix_query(raw_query) {
+        return serve_image(&state, &headers, query(&uri)?).await;
+    }
+
+    let imgix::Translation { params, ignored } =
+        imgix::translate(raw_query, state.config().imgix.strict)?;
+    let mut response = serve_image(&state, &headers, params).await?;
+    if !ignored.is_empty() {
+        if let Ok(value) = HeaderValue::from_str(&ignored.join(", ")) {
+            response
+                .headers_mut()
+                .insert(HeaderName::from_static("x-imgix-ignored"), value);
+        }
+    }
+    Ok(response)
+}
+
+async fn transform_path(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((options, src_b64)): Path<(String, String)>,
+) -> AppResult<Response> {
+    let params = path_options::parse(&options, &src_b64)?;
+    serve_image(&state, &headers, params).await
+}
+
+async fn next_image(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> AppResult<Response> {
+    if !state.config().features.nextjs_compat {
+        return Err(AppError::RouteNotFound {
+            path: uri.path().to_string(),
+        });
+    }
+
+    let NextImageParams { url, w, q } = image_query(&uri, &state)?;
+    let missing = |param: &str| AppError::MissingRequiredParameter {
+        param: param.to_string(),
+    };
+    let url = url.ok_or_else(|| missing("url"))?;
+    let w = w.ok_or_else(|| missing("w"))?;
+    let q = q.ok_or_else(|| missing("q"))?;
+
+    // Next.js names the file after the last path segment of the source
+    let name = url.split(['?', '#']).next().unwrap_or_default().to_string();
+    let params = ImageParams {
+        src: Some(url),
+        w: Some(w),
+        q: Some(q),
+        ..Default::default()
+    };
+    let mut response = serve_image(&state, &headers, params).await?;
+
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CONTENT_SECURITY_POLICY,
+        HeaderValue::from_static(NEXT_IMAGE_CONTENT_SECURITY_POLICY),
+    );
+    let disposition = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|content_type| content_disposition("inline", &name, content_type));
+    if let Some(disposition) = disposition {
+        headers.insert(header::CONTENT_DISPOSITION, disposition);
+    }
+
+    Ok(response)
+}
+
+async fn serve_image(
+    state: &AppState,
+    headers: &HeaderMap,
+    mut params: ImageParams,
+) -> AppResult<Response> {
+    let inputs = resolve_inputs(headers, &mut params, state);
+    if let PreRouteDecision::Redirect(location) = pre_route(&params) {
+        return Ok((StatusCode::FOUND, [(header::LOCATION, location)]).into_response());
+    }
+
+    let conditions = read_conditions(headers);
+    let download = params.dl.clone();
+
+    let mut timings = PhaseTimings::default();
+    let output = process_image_request(
+        params,
+        state,
+        conditions.if_none_match.as_ref(),
+        &mut timings,
+    )
+    .await?;
+
+    image_response(
+        output,
+        &conditions,
+        download.as_deref(),
+        inputs,
+        Freshness::MaxAge,
+        &timings,
+        &state.config(),
+    )
+}
+
+/// `POST /img-optimizer/v1/img` with the image as the raw request body.
+/// Unlike the actix handler, `multipart/form-data` bodies are not accepted.
+async fn upload_image(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    uri: Uri,
+    body: Body,
+) -> AppResult<Response> {
+    let mut params: ImageParams = image_query(&uri, &state)?;
+    let inputs = resolve_inputs(&headers, &mut params, &state);
+    let image_data = read_limited(
+        body.into_data_stream(),
+        state.config().limits.max_image_size,
+    )
+    .await?;
+    if image_data.is_empty() {
+        return Err(AppError::MissingRequiredParameter {
+            param: "body".to_string(),
+        });
+    }
+
+    // Ranges only apply to GET
+    let conditions = Conditions {
+        if_none_match: read_if_none_match(&headers),
+        range: None,
+    };
+    let download = params.dl.clone();
+
+    let mut timings = PhaseTimings::default();
+    let output = process_upload_request(
+        image_data,
+        params,
+        &state,
+        conditions.if_none_match.as_ref(),
+        &mut timings,
+    )
+    .await?;
+
+    image_response(
+        output,
+        &conditions,
+        download.as_deref(),
+        inputs,
+        Freshness::MaxAge,
+        &timings,
+        &state.config(),
+    )
+}
+
+async fn srcset(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    OriginalUri(uri): OriginalUri,
+) -> AppResult<Response> {
+    let params = image_query(&uri, &state)?;
+    let endpoint = srcset::image_endpoint(
+        state.config().server.public_url.as_deref(),
+        &request_origin(&headers),
+        uri.path(),
+    );
+    let accept = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok());
+
+    let manifest = srcset::build_manifest(params, &endpoint, accept, &state).await?;
+    Ok(json_response(serde_json::json!(manifest)))
+}
+
+async fn bundle(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    OriginalUri(uri): OriginalUri,
+) -> AppResult<Response> {
+    let params = image_query(&uri, &state)?;
+    let accept = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok());
+
+    let bundle = bundle::build_bundle(params, accept, &state).await?;
+    let disposition = format!("attachment; filename=\"{}\"", bundle.filename);
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        Body::from_stream(bundle.into_stream()),
+    )
+        .into_response())
+}
+
+async fn create_job(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    OriginalUri(uri): OriginalUri,
+) -> AppResult<Response> {
+    let mut params: ImageParams = image_query(&uri, &state)?;
+    resolve_inputs(&headers, &mut params, &state);
+    let url = jobs::image_url(
+        state.config().server.public_url.as_deref(),
+        &request_origin(&headers),
+        uri.path(),
+        uri.query().unwrap_or_default(),
+    );
+
+    let id = jobs::submit(params, url, &state)?;
+    let mut response =
+        json_response(jobs::JobStatus::Pending.to_json(&id, state.config().server.error_detail));
+    *response.status_mut() = StatusCode::ACCEPTED;
+    if let Ok(location) = HeaderValue::from_str(&format!("{}/{id}", uri.path())) {
+        response.headers_mut().insert(header::LOCATION, location);
+    }
+    Ok(response)
+}
+
+async fn job_status(State(state): State<AppState>, Path(id): Path<String>) -> AppResult<Response> {
+    let status = state
+        .jobs
+        .get(&id)
+        .ok_or_else(|| AppError::JobNotFound { id: id.clone() })?;
+    let mut response = json_response(status.to_json(&id, state.config().server.error_detail));
+    response
+        .headers_mut()
+        .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    Ok(response)
+}
+
+/// Scheme and host the request was made to, from the `X-Forwarded-Proto`
+/// and `X-Forwarded-Host` headers of a proxy, or the `Host` header.
+fn request_origin(headers: &HeaderMap) -> String {
+    let first = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').next())
+            .map(str::trim)
+    };
+    let scheme = first("x-forwarded-proto").unwrap_or("http");
+    let host = first("x-forwarded-host")
+        .or_else(|| first(header::HOST.as_str()))
+        .unwrap_or("localhost");
+    format!("{scheme}://{host}")
+}
+
+async fn direct_image(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(image_id): Path<String>,
+    uri: Uri,
+) -> AppResult<Response> {
+    let content_type = stored_image_content_type(&image_id)?;
+    let mut params: ImageParams = image_query(&uri, &state)?;
+    let inputs = resolve_inputs(&headers, &mut params, &state);
+    let conditions = read_conditions(&headers);
+    let download = params.dl.clone();
+
+    let mut timings = PhaseTimings::default();
+    let output = process_stored_request(
+        &image_id,
+        content_type,
+        params,
+        &state,
+        conditions.if_none_match.as_ref(),
+        &mut timings,
+    )
+    .await?;
+
+    image_response(
+        output,
+        &conditions,
+        download.as_deref(),
+        inputs,
+        Freshness::Immutable,
+        &timings,
+        &state.config(),
+    )
+}
+
+/// Resolves `f=auto` and client hints from `headers`, see [`ResponseInputs`].
+fn resolve_inputs(
+    headers: &HeaderMap,
+    params: &mut ImageParams,
+    state: &AppState,
+) -> ResponseInputs {
+    ResponseInputs::resolve(params, &state.config(), &state.api_keys, |name| {
+        headers.get(name).and_then(|value| value.to_str().ok())
+    })
+}
+
+/// The query string parsed as by the actix handlers, see [`parse_query`].
+fn query<T: DeserializeOwned>(uri: &Uri) -> AppResult<T> {
+    parse_query(uri.query().unwrap_or_default())
+}
+
+/// The query string of an image route, see [`parse_image_query`].
+fn image_query<T: DeserializeOwned>(uri: &Uri, state: &AppState) -> AppResult<T> {
+    parse_image_query(uri.query().unwrap_or_default(), &state.config().limits)
+}
+
+/// The request's `If-None-Match` header, for the core pipeline.
+fn read_if_none_match(headers: &HeaderMap) -> Option<IfNoneMatch> {
+    let value = headers.get(header::IF_NONE_MATCH)?.to_str().ok()?.trim();
+    if value == "*" {
+        return Some(IfNoneMatch::Any);
+    }
+    let tags = value
+        .split(',')
+        .map(|tag| {
+            tag.trim()
+                .trim_start_matches("W/")
+                .trim_matches('"')
+                .to_string()
+        })
+        .filter(|tag| !tag.is_empty())
+        .collect();
+    Some(IfNoneMatch::Tags(tags))
+}
+
+/// The request's `If-None-Match`, `Range` and `If-Range` headers.
+fn read_conditions(headers: &HeaderMap) -> Conditions {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    Conditions {
+        if_none_match: read_if_none_match(headers),
+        range: RangeRequest::from_headers(header(header::RANGE), header(header::IF_RANGE)),
+    }
+}
+
+/// Builds the response for a pipeline outcome: the image with its validators,
+/// `304 Not Modified` when the client's `If-None-Match` matches, or the
+/// `206 Partial Content` asked for with `Range`.
+fn image_response(
+    output: ImageOutput,
+    conditions: &Conditions,
+    download: Option<&str>,
+    inputs: ResponseInputs,
+    freshness: Freshness,
+    timings: &PhaseTimings,
+    config: &AppConfig,
+) -> AppResult<Response> {
+    let cache_control = freshness.cache_control(config);
+
+    let mut response = match output {
+        ImageOutput::Image { etag, .. }
+            if conditions
+                .if_none_match
+                .as_ref()
+                .is_some_and(|header| header.matches(&etag)) =>
+        {
+            not_modified(&etag, &cache_control)
+        }
+        ImageOutput::Image {
+            data,
+            content_type,
+            etag,
+            metadata,
+            warnings,
+        } => {
+            let range = match &conditions.range {
+                Some(range) => range.resolve(&etag, data.len())?,
+                None => None,
+            };
+            let length = data.len();
+            let mut response = (
+                [
+                    (header::CONTENT_TYPE, content_type.clone()),
+                    (header::ETAG, format!("\"{etag}\"")),
+                    (header::CACHE_CONTROL, cache_control),
+                    (header::ACCEPT_RANGES, range::ACCEPT_RANGES.to_string()),
+                ],
+                match range {
+                    Some(range) => data.slice(range.start..=range.end),
+                    None => data,
+                },
+            )
+                .into_response();
+            if let Some(range) = range {
+                *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+                if let Ok(value) = HeaderValue::from_str(&range.content_range(length)) {
+                    response.headers_mut().insert(header::CONTENT_RANGE, value);
+                }
+            }
+            let headers = metadata
+                .iter()
+                .flat_map(metadata_headers)
+                .chain(warnings_header(&warnings));
+            for (name, value) in headers {
+                if let Ok(value) = HeaderValue::from_str(&value) {
+                    response
+                        .headers_mut()
+                        .insert(HeaderName::from_static(name), value);
+                }
+            }
+            let disposition = download
+                .and_then(|filename| content_disposition("attachment", filename, &content_type));
+            if let Some(disposition) = disposition {
+                response
+                    .headers_mut()
+                    .insert(header::CONTENT_DISPOSITION, disposition);
+            }
+            response
+        }
+        ImageOutput::NotModified { etag } => not_modified(&etag, &cache_control),
+    };
+
+    if let Some(vary) = inputs
+        .vary()
+        .and_then(|vary| HeaderValue::from_str(&vary).ok())
+    {
+        response.headers_mut().append(header::VARY, vary);
+    }
+    if let Some(hints) = inputs.accept_ch() {
+        response.headers_mut().insert(
+            HeaderName::from_static("accept-ch"),
+            HeaderValue::from_static(hints),
+        );
+    }
+    if config.features.server_timing {
+        if let Ok(value) = HeaderValue::from_str(&timings.server_timing()) {
+            response
+                .headers_mut()
+                .insert(HeaderName::from_static("server-timing"), value);
+        }
+    }
+
+    Ok(response)
+}
+
+fn not_modified(etag: &str, cache_control: &str) -> Response {
+    (
+        StatusCode::NOT_MODIFIED,
+        [
+            (header::ETAG, format!("\"{etag}\"")),
+            (header::CACHE_CONTROL, cache_control.to_string()),
+        ],
+    )
+        .into_response()
+}
+
+/// `Content-Disposition` naming the image `requested`, see
+/// [`download_filename`]. Non-ASCII names are sent as an RFC 5987
+/// `filename*` with an ASCII `filename` fallback.
+fn content_disposition(
+    disposition: &str,
+    requested: &str,
+    content_type: &str,
+) -> Option<HeaderValue> {
+    let filename = download_filename(requested, content_type);
+
+    let mut value = format!("{disposition}; filename=\"");
+    for c in filename.chars() {
+        match c {
+            '"' | '\\' => {
+                value.push('\\');
+                value.push(c);
+            }
+            c if c.is_ascii() => value.push(c),
+            _ => value.push('_'),
+        }
+    }
+    value.push('"');
+
+    if !filename.is_ascii() {
+        value.push_str("; filename*=UTF-8''");
+        for byte in filename.bytes() {
+            if byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte) {
+                value.push(byte as char);
+            } else {
+                value.push_str(&format!("%{byte:02X}"));
+            }
+        }
+    }
+
+    HeaderValue::from_str(&value).ok()
+}
+
+fn json_response(value: serde_json::Value) -> Response {
+    (
+        [(header::CONTENT_TYPE, "application/json")],
+        value.to_string(),
+    )
+        .into_response()
+}
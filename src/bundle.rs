@@ -0,0 +1,321 @@
+//! Bundles: several variants of one source in a single zip download, for
+//! design handoffs wanting e.g. an image at 1x/2x/3x in WebP and PNG.
+//!
+//! `variants` lists the variants separated by commas, each as `_`-separated
+//! key and value pairs: `w_400_f_webp,w_800_f_webp,w_400_f_png`. Keys are
+//! `w`, `h`, `fit`, `q` and `f`, with the same values as the parameters of
+//! the same name.
+
+use crate::error::{AppError, AppResult};
+use crate::sniff::DetectedFormat;
+#[cfg(feature = "runtime")]
+use crate::{
+    finish_audit, metrics::PhaseTimings, resolve_source, start_audit, transform_many, AppState,
+    ImageParams,
+};
+use serde::Deserialize;
+
+/// Most variants a bundle may have.
+pub const MAX_VARIANTS: usize = 12;
+
+/// Longest stem of the file names in and of an archive.
+const MAX_STEM_LEN: usize = 64;
+
+/// Query of `GET /img-optimizer/v1/img/bundle`. `src`, `srcb64` and `tx`
+/// are as in [`crate::ImageParams`]; `q` and `f` apply to the variants
+/// that don't set their own.
+#[derive(Debug, Default, Deserialize)]
+pub struct BundleParams {
+    #[serde(alias = "url")]
+    pub src: Option<String>,
+    pub srcb64: Option<String>,
+    pub tx: Option<String>,
+    /// Comma-separated variants, see [`parse_variants`].
+    pub variants: Option<String>,
+    pub q: Option<String>,
+    pub f: Option<String>,
+}
+
+/// One variant of a bundle, its values not validated yet.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VariantSpec {
+    pub w: Option<String>,
+    pub h: Option<String>,
+    pub fit: Option<String>,
+    pub q: Option<String>,
+    pub f: Option<String>,
+}
+
+/// Parses a `variants` value. Errors name the failing variant as
+/// `variants[<index>]`, counting from 0, and every invalid one is reported.
+pub fn parse_variants(value: &str) -> AppResult<Vec<VariantSpec>> {
+    let tokens: Vec<&str> = value.split(',').collect();
+    if tokens.len() > MAX_VARIANTS {
+        return Err(AppError::InvalidParameterValue {
+            param: "variants".to_string(),
+            value: value.to_string(),
+            expected: format!("at most {MAX_VARIANTS} variants"),
+        });
+    }
+
+    let mut variants = Vec::with_capacity(tokens.len());
+    let mut errors = Vec::new();
+    for (index, token) in tokens.into_iter().enumerate() {
+        match parse_variant(token.trim()) {
+            Some(variant) => variants.push(variant),
+            None => errors.push(AppError::InvalidParameterValue {
+                param: format!("variants[{index}]"),
+                value: token.to_string(),
+                expected: "key_value pairs such as w_400_f_webp, with keys w, h, fit, q and f"
+                    .to_string(),
+            }),
+        }
+    }
+    AppError::from_validation(errors)?;
+    Ok(variants)
+}
+
+fn parse_variant(token: &str) -> Option<VariantSpec> {
+    let parts: Vec<&str> = token.split('_').collect();
+    if token.is_empty() || !parts.len().is_multiple_of(2) {
+        return None;
+    }
+
+    let mut variant = VariantSpec::default();
+    for pair in parts.chunks(2) {
+        let (key, value) = (pair[0], pair[1]);
+        let slot = match key {
+            "w" => &mut variant.w,
+            "h" => &mut variant.h,
+            "fit" => &mut variant.fit,
+            "q" => &mut variant.q,
+            "f" => &mut variant.f,
+            _ => return None,
+        };
+        if value.is_empty() || slot.is_some() {
+            return None;
+        }
+        *slot = Some(value.to_string());
+    }
+    Some(variant)
+}
+
+/// Stem of the file names of a bundle of `src`: the last segment of its
+/// path without the extension, limited to ASCII letters, digits, `-` and
+/// `_`. `image` when nothing is left, as for `data:` URLs.
+pub fn file_stem(src: &str) -> String {
+    let path = url::Url::parse(src)
+        .ok()
+        .filter(|url| url.scheme() != "data")
+        .map(|url| url.path().to_string())
+        .unwrap_or_default();
+    let name = path.rsplit('/').next().unwrap_or_default();
+    let name = name.trim_matches('.');
+    let stem = match name.rsplit_once('.') {
+        Some((stem, _)) if !stem.is_empty() => stem,
+        _ => name,
+    };
+
+    let stem: String = stem
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(MAX_STEM_LEN)
+        .collect();
+    if stem.trim_matches('_').is_empty() {
+        "image".to_string()
+    } else {
+        stem
+    }
+}
+
+/// Name of a variant in the archive, `<stem>-<width>w.<extension>`, unique
+/// among the names already `taken`: repeats get `-2`, `-3`, ...
+pub fn member_name(stem: &str, width: u32, content_type: &str, taken: &[String]) -> String {
+    let extension =
+        DetectedFormat::from_content_type(content_type).map_or("bin", |format| format.extension());
+    let base = format!("{stem}-{width}w");
+    let mut name = format!("{base}.{extension}");
+    let mut repeat = 1;
+    while taken.contains(&name) {
+        repeat += 1;
+        name = format!("{base}-{repeat}.{extension}");
+    }
+    name
+}
+
+#[cfg(feature = "runtime")]
+/// A bundle ready to be archived.
+#[derive(Debug)]
+pub struct Bundle {
+    /// File name of the archive.
+    pub filename: String,
+    /// Name and content of each variant, in the order requested.
+    pub members: Vec<(String, bytes::Bytes)>,
+}
+
+#[cfg(feature = "runtime")]
+/// Renders the variants of `params`, reading them from the cache or
+/// processing them from a single fetch and decode of the source. `f=auto`
+/// follows the client's `accept`. Fails with `BundleTooLarge` when the
+/// variants add up to more than `limits.max_bundle_size` bytes.
+pub async fn build_bundle(
+    params: BundleParams,
+    accept: Option<&str>,
+    state: &AppState,
+) -> AppResult<Bundle> {
+    let shared = ImageParams {
+        src: params.src.clone(),
+        srcb64: params.srcb64.clone(),
+        tx: params.tx.clone(),
+        q: params.q.clone(),
+        f: params.f.clone(),
+        ..Default::default()
+    };
+    let mut audit = start_audit(state, &shared);
+    if let (Some(record), Some(variants)) = (&mut audit, &params.variants) {
+        let mut query = url::form_urlencoded::Serializer::new(std::mem::take(&mut record.params));
+        query.append_pair("variants", variants);
+        record.params = query.finish();
+    }
+
+    let mut timings = PhaseTimings::default();
+    let result = render(params, accept, state, &mut timings).await;
+    if let Some(mut record) = audit {
+        match &result {
+            Ok(bundle) => {
+                record.bytes_out = bundle
+                    .members
+                    .iter()
+                    .map(|(_, data)| data.len() as u64)
+                    .sum()
+            }
+            Err(err) => record.fail(err),
+        }
+        finish_audit(state, record, &timings);
+    }
+    result
+}
+
+#[cfg(feature = "runtime")]
+async fn render(
+    params: BundleParams,
+    accept: Option<&str>,
+    state: &AppState,
+    timings: &mut PhaseTimings,
+) -> AppResult<Bundle> {
+    let limits = &state.config().limits;
+    let variants =
+        params
+            .variants
+            .as_deref()
+            .ok_or_else(|| AppError::MissingRequiredParameter {
+                param: "variants".to_string(),
+            })?;
+    let variants = parse_variants(variants)?;
+
+    let template = ImageParams {
+        src: params.src,
+        srcb64: params.srcb64,
+        tx: params.tx,
+        ..Default::default()
+    };
+    // Errors of the parameters every variant shares are reported once
+    let src = template.clone().validate(limits)?.source.ok_or_else(|| {
+        AppError::MissingRequiredParameter {
+            param: "src".to_string(),
+        }
+    })?;
+
+    let mut plans = Vec::with_capacity(variants.len());
+    let mut errors = Vec::new();
+    for variant in variants {
+        let mut variant = ImageParams {
+            w: variant.w,
+            h: variant.h,
+            fit: variant.fit,
+            q: variant.q.or_else(|| params.q.clone()),
+            f: variant.f.or_else(|| params.f.clone()),
+            ..template.clone()
+        };
+        variant.resolve_auto_format(accept);
+        match variant.validate(limits) {
+            Ok(validated) => plans.push(validated.plan),
+            Err(AppError::ValidationFailed { errors: failed }) => errors.extend(failed),
+            Err(err) => errors.push(err),
+        }
+    }
+    AppError::from_validation(errors)?;
+
+    let (source, identity) = resolve_source(&src, state)?;
+    let images = transform_many(source, &identity, &plans, state, timings).await?;
+
+    let total: usize = images.iter().map(|image| image.data.len()).sum();
+    if total > limits.max_bundle_size {
+        return Err(AppError::BundleTooLarge {
+            limit: limits.max_bundle_size,
+        });
+    }
+
+    let stem = file_stem(&src);
+    let mut members: Vec<(String, bytes::Bytes)> = Vec::with_capacity(images.len());
+    let mut names = Vec::with_capacity(images.len());
+    for image in images {
+        let name = member_name(&stem, image.metadata.width, &image.content_type, &names);
+        names.push(name.clone());
+        members.push((name, image.data));
+    }
+    Ok(Bundle {
+        filename: format!("{stem}.zip"),
+        members,
+    })
+}
+
+#[cfg(feature = "runtime")]
+impl Bundle {
+    /// The archive as a stream of chunks, written as it is read. Entries are
+    /// stored rather than deflated, since encoded images don't compress.
+    pub fn into_stream(
+        self,
+    ) -> impl futures_util::Stream<Item = std::io::Result<bytes::Bytes>> + Send + 'static {
+        use async_zip::{tokio::write::ZipFileWriter, Compression, ZipEntryBuilder};
+        use tokio::io::AsyncReadExt;
+
+        const CHUNK_SIZE: usize = 64 * 1024;
+
+        let (writer, reader) = tokio::io::duplex(CHUNK_SIZE);
+        let members = self.members;
+        tokio::spawn(async move {
+            let mut zip = ZipFileWriter::with_tokio(writer);
+            for (name, data) in members {
+                let entry = ZipEntryBuilder::new(name.into(), Compression::Stored);
+                if let Err(e) = zip.write_entry_whole(entry, &data).await {
+                    // The client went away
+                    log::debug!("Stopped writing bundle: {e}");
+                    return;
+                }
+            }
+            if let Err(e) = zip.close().await {
+                log::debug!("Stopped writing bundle: {e}");
+            }
+        });
+
+        futures_util::stream::unfold(Some(reader), |reader| async move {
+            let mut reader = reader?;
+            let mut chunk = vec![0; CHUNK_SIZE];
+            match reader.read(&mut chunk).await {
+                Ok(0) => None,
+                Ok(read) => {
+                    chunk.truncate(read);
+                    Some((Ok(bytes::Bytes::from(chunk)), Some(reader)))
+                }
+                Err(e) => Some((Err(e), None)),
+            }
+        })
+    }
+}
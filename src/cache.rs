@@ -1,40 +1,677 @@
-use std::path::PathBuf;
+use bytes::Bytes;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::Duration;
 use tokio::fs;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::runtime::Handle;
+use tokio_util::task::TaskTracker;
 
+const PROBE_KEY: &str = ".readiness-probe";
+/// Extension of the files holding the [`ImageMetadata`] of an entry.
+const METADATA_EXTENSION: &str = "meta";
+/// Extension of the hidden files entries are written to before being renamed
+/// into place.
+const TEMP_EXTENSION: &str = "tmp";
+/// Age under which [`ImageCache::clear`] leaves temporary files alone, as
+/// they may belong to writes still in progress, in this process or another.
+const TEMP_GRACE: Duration = Duration::from_secs(60);
+/// Entries from this size are served from a memory mapping, smaller ones are
+/// read, which costs less than setting up the mapping.
+#[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+const MMAP_MIN_SIZE: u64 = 256 * 1024;
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct CacheStats {
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+/// Dimensions stored with a cached image, so that hits report them without
+/// decoding it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageMetadata {
+    pub width: u32,
+    pub height: u32,
+    pub original_width: u32,
+    pub original_height: u32,
+    /// Whether the source had transparency the output format dropped.
+    #[serde(default)]
+    pub alpha_flattened: bool,
+    /// Whether the box asked for was larger than the image, which was not
+    /// enlarged to fill it.
+    #[serde(default)]
+    pub upscale_refused: bool,
+}
+
+/// Cache of processed images. Clones are cheap and share the same entries,
+/// so callers holding it behind a lock can clone it and release the lock
+/// before any I/O.
+///
+/// Reads, writes and deletes of the same key may run concurrently, from
+/// this process or another sharing the directory. A read returns a whole
+/// entry, the one in place when its file was opened, or `None`:
+///
+/// - entries and their metadata are written to a temporary file renamed
+///   into place, never rewritten, so an open file keeps its contents when
+///   the entry is replaced or deleted, on Unix until the last handle or
+///   mapping is gone;
+/// - Windows refuses to delete files that are mapped or open without
+///   sharing. Such keys are tombstoned: they read as missing
+///   right away, and removing their file is retried by later deletes, and by
+///   the next write of the key, which doesn't replace the file before that;
+/// - [`clear`](Self::clear) leaves temporary files younger than a minute,
+///   the writes they belong to renaming them into place later.
+#[derive(Clone)]
 pub struct ImageCache {
-    cache_dir: PathBuf,
+    backend: Backend,
+    /// Keys on disk, when the filesystem backend is [indexed](Self::indexed).
+    index: Option<Arc<KeyIndex>>,
+    tombstones: Arc<Tombstones>,
+}
+
+#[derive(Clone)]
+enum Backend {
+    Filesystem(PathBuf),
+    /// Entries held by the process, for embedding and tests. Unbounded.
+    Memory(Arc<Mutex<HashMap<String, MemoryEntry>>>),
+}
+
+struct MemoryEntry {
+    data: Bytes,
+    metadata: Option<ImageMetadata>,
+}
+
+/// Keys of the entries in the cache directory, so that lookups of missing
+/// entries skip the filesystem. Entries added to the directory by another
+/// process are not seen, and are processed again; entries it removes are
+/// dropped from the index when opening them fails.
+#[derive(Default)]
+struct KeyIndex {
+    keys: RwLock<HashSet<String>>,
+    /// Set once the directory was scanned. Until then, lookups use the
+    /// filesystem.
+    loaded: AtomicBool,
+}
+
+/// Keys deleted while their file couldn't be removed, as Windows refuses
+/// for files still mapped by a response.
+#[derive(Default)]
+struct Tombstones {
+    keys: Mutex<HashSet<String>>,
 }
 
 impl ImageCache {
     pub fn new(cache_dir: PathBuf) -> Self {
-        Self { cache_dir }
+        Self {
+            backend: Backend::Filesystem(cache_dir),
+            index: None,
+            tombstones: Arc::default(),
+        }
+    }
+
+    /// Cache in `cache_dir` keeping the keys of its entries in memory, so
+    /// that misses don't touch the filesystem. The directory is scanned on a
+    /// background thread; lookups use the filesystem until it is done.
+    pub fn indexed(cache_dir: PathBuf) -> Self {
+        let index = Arc::new(KeyIndex::default());
+        let scanned = index.clone();
+        let dir = cache_dir.clone();
+        let spawned = std::thread::Builder::new()
+            .name("cache-index".to_string())
+            .spawn(move || scanned.load(&dir));
+        if let Err(e) = spawned {
+            warn!("Failed to start the cache index scan, lookups will use the filesystem: {e}");
+        }
+        Self {
+            backend: Backend::Filesystem(cache_dir),
+            index: Some(index),
+            tombstones: Arc::default(),
+        }
+    }
+
+    /// Cache keeping entries in memory, lost when the process exits.
+    pub fn in_memory() -> Self {
+        Self {
+            backend: Backend::Memory(Arc::default()),
+            index: None,
+            tombstones: Arc::default(),
+        }
     }
 
-    pub async fn get(&self, key: &str) -> Option<Vec<u8>> {
-        let file_path = self.cache_dir.join(key);
+    /// The cached image. With the `mmap` feature, large entries borrow a
+    /// memory mapping of their file, kept alive by the returned `Bytes` and
+    /// its clones. Entries are replaced by renaming, never rewritten in place,
+    /// so the mapping stays valid when the entry is replaced or deleted.
+    #[cfg_attr(feature = "otel", tracing::instrument(name = "cache_get", skip(self)))]
+    pub async fn get(&self, key: &str) -> Option<Bytes> {
+        self.read(key).await
+    }
 
-        if !file_path.exists() {
+    /// An entry along with the metadata stored by
+    /// [`ImageCache::put_with_metadata`], or `None` when either is missing.
+    #[cfg_attr(feature = "otel", tracing::instrument(name = "cache_get", skip(self)))]
+    pub async fn get_with_metadata(&self, key: &str) -> Option<(Bytes, ImageMetadata)> {
+        let metadata = self.metadata(key).await?;
+        self.read(key).await.map(|data| (data, metadata))
+    }
+
+    async fn read(&self, key: &str) -> Option<Bytes> {
+        let cache_dir = match &self.backend {
+            Backend::Filesystem(cache_dir) => cache_dir,
+            Backend::Memory(entries) => {
+                return lock(entries).get(key).map(|entry| entry.data.clone())
+            }
+        };
+        if self.known(key) == Some(false) || self.tombstones.contains(key) {
             return None;
         }
 
-        match fs::File::open(&file_path).await {
-            Ok(mut file) => {
-                let mut contents = Vec::new();
-                match file.read_to_end(&mut contents).await {
-                    Ok(_) => Some(contents),
-                    Err(_) => None,
+        match read_entry(cache_dir.join(key)).await {
+            Ok(contents) => Some(contents),
+            Err(e) => {
+                // Removed by another process
+                if e.kind() == std::io::ErrorKind::NotFound {
+                    if let Some(index) = &self.index {
+                        index.remove(key);
+                    }
                 }
+                None
             }
-            Err(_) => None,
         }
     }
 
-    pub async fn put(&mut self, key: String, data: Vec<u8>) {
-        let file_path = self.cache_dir.join(&key);
+    /// Whether the scan of an [indexed](Self::indexed) cache is done, so that
+    /// misses are answered from memory. Always `false` for other caches.
+    pub fn index_loaded(&self) -> bool {
+        self.index.as_ref().is_some_and(|index| index.is_loaded())
+    }
+
+    /// Whether the index knows of `key`, or `None` when it can't tell.
+    fn known(&self, key: &str) -> Option<bool> {
+        self.index.as_ref().and_then(|index| index.contains(key))
+    }
+
+    /// Name of the storage backing the cache, as reported by `/status`.
+    pub fn backend(&self) -> &'static str {
+        match self.backend {
+            Backend::Filesystem(_) => "filesystem",
+            Backend::Memory(_) => "memory",
+        }
+    }
+
+    /// Whether an entry exists, without reading it.
+    pub fn contains(&self, key: &str) -> bool {
+        match &self.backend {
+            Backend::Filesystem(cache_dir) => {
+                !self.tombstones.contains(key)
+                    && self
+                        .known(key)
+                        .unwrap_or_else(|| cache_dir.join(key).exists())
+            }
+            Backend::Memory(entries) => lock(entries).contains_key(key),
+        }
+    }
 
-        if let Ok(mut file) = fs::File::create(&file_path).await {
-            let _ = file.write_all(&data).await;
+    /// Metadata stored with an entry by [`ImageCache::put_with_metadata`].
+    pub async fn metadata(&self, key: &str) -> Option<ImageMetadata> {
+        let cache_dir = match &self.backend {
+            Backend::Filesystem(cache_dir) => cache_dir,
+            Backend::Memory(entries) => {
+                return lock(entries).get(key).and_then(|entry| entry.metadata)
+            }
+        };
+        if self.known(key) == Some(false) || self.tombstones.contains(key) {
+            return None;
         }
+        let contents = fs::read(metadata_path(cache_dir, key)).await.ok()?;
+        serde_json::from_slice(&contents)
+            .inspect_err(|e| warn!("Ignoring unreadable metadata of cache entry {key}: {e}"))
+            .ok()
+    }
+
+    pub async fn put(&mut self, key: String, data: impl Into<Bytes>) {
+        self.write(key, data.into(), None).await;
+    }
+
+    /// Stores an entry along with the dimensions to report when it is served.
+    pub async fn put_with_metadata(
+        &mut self,
+        key: String,
+        data: impl Into<Bytes>,
+        metadata: ImageMetadata,
+    ) {
+        self.write(key, data.into(), Some(metadata)).await;
+    }
+
+    #[cfg_attr(
+        feature = "otel",
+        tracing::instrument(name = "cache_put", skip(self, data, metadata), fields(size = data.len()))
+    )]
+    async fn write(&mut self, key: String, data: Bytes, metadata: Option<ImageMetadata>) {
+        let cache_dir = match &mut self.backend {
+            Backend::Filesystem(cache_dir) => cache_dir,
+            Backend::Memory(entries) => {
+                lock(entries).insert(key, MemoryEntry { data, metadata });
+                return;
+            }
+        };
+        if !self.tombstones.make_way(cache_dir, &key) {
+            debug!("Not caching {key}, the file of its deleted entry is still in use");
+            return;
+        }
+        if let Err(e) = replace(cache_dir, &key, &data).await {
+            // The previous entry, if any, keeps its metadata
+            warn!("Failed to write cache entry {key}: {e}");
+            return;
+        }
+        if let Some(index) = &self.index {
+            index.insert(&key);
+        }
+
+        // Written after the image, so readers never see metadata without it,
+        // and replaced the same way, so they never see half of it
+        let result = match metadata {
+            Some(metadata) => match serde_json::to_vec(&metadata) {
+                Ok(contents) => replace(cache_dir, &metadata_key(&key), &contents).await,
+                Err(e) => Err(std::io::Error::other(e)),
+            },
+            None => remove_if_exists(&metadata_path(cache_dir, &key)).await,
+        };
+        if let Err(e) = result {
+            warn!("Failed to write metadata of cache entry {key}: {e}");
+        }
+    }
+
+    pub async fn delete(&mut self, key: &str) {
+        let cache_dir = match &mut self.backend {
+            Backend::Filesystem(cache_dir) => cache_dir,
+            Backend::Memory(entries) => {
+                lock(entries).remove(key);
+                return;
+            }
+        };
+        if let Some(index) = &self.index {
+            index.remove(key);
+        }
+        if let Err(e) = remove_if_exists(&cache_dir.join(key)).await {
+            debug!("Deferring the deletion of cache entry {key}: {e}");
+            self.tombstones.insert(key);
+        }
+        if let Err(e) = remove_if_exists(&metadata_path(cache_dir, key)).await {
+            warn!("Failed to delete the metadata of cache entry {key}: {e}");
+        }
+        self.tombstones.retry(cache_dir);
+    }
+
+    /// Writes, reads back and deletes a probe entry to verify the cache
+    /// directory is usable. The memory backend is always usable.
+    pub async fn check(&self) -> std::io::Result<()> {
+        let Backend::Filesystem(cache_dir) = &self.backend else {
+            return Ok(());
+        };
+        let file_path = cache_dir.join(PROBE_KEY);
+        let payload = b"img-optimizer readiness probe";
+
+        fs::write(&file_path, payload).await?;
+        let read_back = fs::read(&file_path).await;
+        fs::remove_file(&file_path).await?;
+
+        if read_back? != payload {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "probe entry read back with different content",
+            ));
+        }
+        Ok(())
+    }
+
+    pub async fn stats(&self) -> std::io::Result<CacheStats> {
+        let mut stats = CacheStats::default();
+        let cache_dir = match &self.backend {
+            Backend::Filesystem(cache_dir) => cache_dir,
+            Backend::Memory(entries) => {
+                let entries = lock(entries);
+                stats.entries = entries.len() as u64;
+                stats.bytes = entries.values().map(|entry| entry.data.len() as u64).sum();
+                return Ok(stats);
+            }
+        };
+        let mut entries = fs::read_dir(cache_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            // Deleted since the directory was listed
+            let Some(metadata) = file_metadata(&entry).await? else {
+                continue;
+            };
+            if metadata.is_file() && is_entry(&entry.path()) {
+                stats.entries += 1;
+                stats.bytes += metadata.len();
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Deletes every cached entry, returning how many were removed. Entries
+    /// whose file is in use are tombstoned and counted, and
+    /// temporary files are left for a minute to the writes they belong to.
+    pub async fn clear(&mut self) -> std::io::Result<u64> {
+        let cache_dir = match &mut self.backend {
+            Backend::Filesystem(cache_dir) => cache_dir,
+            Backend::Memory(entries) => {
+                let mut entries = lock(entries);
+                let removed = entries.len() as u64;
+                entries.clear();
+                return Ok(removed);
+            }
+        };
+        if let Some(index) = &self.index {
+            index.clear();
+        }
+        let mut removed = 0;
+        let mut entries = fs::read_dir(&cache_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let Some(metadata) = file_metadata(&entry).await? else {
+                continue;
+            };
+            let removable = if is_temp(&path) {
+                let age = metadata.modified()?.elapsed().unwrap_or_default();
+                age >= TEMP_GRACE
+            } else {
+                !is_hidden(&path)
+            };
+            if !metadata.is_file() || !removable {
+                continue;
+            }
+            match remove_if_exists(&path).await {
+                Ok(()) => {}
+                Err(e) if is_entry(&path) => {
+                    let key = entry.file_name().to_string_lossy().into_owned();
+                    debug!("Deferring the deletion of cache entry {key}: {e}");
+                    self.tombstones.insert(&key);
+                }
+                Err(e) => return Err(e),
+            }
+            if is_entry(&path) {
+                removed += 1;
+            }
+        }
+        self.tombstones.retry(cache_dir);
+        Ok(removed)
+    }
+}
+
+/// Cache writes still running after their response, see
+/// [`crate::config::CacheWriteMode::Deferred`], so that shutdown can wait
+/// for them. They run on the runtime the tracker was created on, when
+/// there is one: the HTTP workers' runtimes stop with the server, dropping
+/// their tasks.
+pub struct PendingWrites {
+    tracker: TaskTracker,
+    runtime: Option<Handle>,
+}
+
+impl Default for PendingWrites {
+    fn default() -> Self {
+        Self {
+            tracker: TaskTracker::new(),
+            runtime: Handle::try_current().ok(),
+        }
+    }
+}
+
+impl PendingWrites {
+    /// Runs `write` in the background.
+    pub fn spawn(&self, write: impl Future<Output = ()> + Send + 'static) {
+        let write = self.tracker.track_future(write);
+        match &self.runtime {
+            Some(runtime) => drop(runtime.spawn(write)),
+            None => drop(tokio::spawn(write)),
+        }
+    }
+
+    /// Number of writes not finished yet.
+    pub fn len(&self) -> usize {
+        self.tracker.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tracker.is_empty()
+    }
+
+    /// Waits up to `timeout` for the pending writes, including those
+    /// spawned meanwhile. Returns whether they all finished.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        self.tracker.close();
+        let drained = tokio::time::timeout(timeout, self.tracker.wait())
+            .await
+            .is_ok();
+        self.tracker.reopen();
+        drained
+    }
+}
+
+impl KeyIndex {
+    /// Adds the entries in `cache_dir` to the index and marks it loaded. A
+    /// missing directory has no entries; on other errors, the index stays
+    /// unloaded.
+    fn load(&self, cache_dir: &Path) {
+        let entries = match std::fs::read_dir(cache_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                self.loaded.store(true, Ordering::Release);
+                return;
+            }
+            Err(e) => {
+                warn!("Failed to scan the cache directory, lookups will use the filesystem: {e}");
+                return;
+            }
+        };
+        let mut keys = Vec::new();
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!(
+                        "Failed to scan the cache directory, lookups will use the filesystem: {e}"
+                    );
+                    return;
+                }
+            };
+            let path = entry.path();
+            if entry.file_type().is_ok_and(|kind| kind.is_file()) && is_entry(&path) {
+                keys.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        self.write().extend(keys);
+        self.loaded.store(true, Ordering::Release);
+    }
+
+    fn is_loaded(&self) -> bool {
+        self.loaded.load(Ordering::Acquire)
+    }
+
+    fn contains(&self, key: &str) -> Option<bool> {
+        self.is_loaded().then(|| {
+            self.keys
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .contains(key)
+        })
+    }
+
+    fn insert(&self, key: &str) {
+        self.write().insert(key.to_string());
+    }
+
+    fn remove(&self, key: &str) {
+        self.write().remove(key);
+    }
+
+    fn clear(&self) {
+        self.write().clear();
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, HashSet<String>> {
+        self.keys.write().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Tombstones {
+    fn contains(&self, key: &str) -> bool {
+        // Checked on every read, and empty unless a deletion failed
+        let keys = self.lock();
+        !keys.is_empty() && keys.contains(key)
+    }
+
+    fn insert(&self, key: &str) {
+        self.lock().insert(key.to_string());
+    }
+
+    /// Retries removing the files of the tombstoned keys, forgetting those
+    /// that are gone.
+    fn retry(&self, cache_dir: &Path) {
+        let mut keys = self.lock();
+        keys.retain(|key| !remove_stale(cache_dir, key));
+    }
+
+    /// Whether a new entry may be written under `key`: `false` while the
+    /// file of its deleted entry can't be removed. Holding the lock until
+    /// the tombstone is gone keeps [`retry`](Self::retry) from removing the
+    /// new entry instead.
+    fn make_way(&self, cache_dir: &Path, key: &str) -> bool {
+        let mut keys = self.lock();
+        if !keys.contains(key) {
+            return true;
+        }
+        let removed = remove_stale(cache_dir, key);
+        if removed {
+            keys.remove(key);
+        }
+        removed
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashSet<String>> {
+        self.keys.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Entries of the memory backend. Held without awaiting, so a panic while
+/// holding it can't leave an entry half-written.
+fn lock(
+    entries: &Mutex<HashMap<String, MemoryEntry>>,
+) -> MutexGuard<'_, HashMap<String, MemoryEntry>> {
+    entries
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Dotfiles (`.gitkeep`, the readiness probe) are not cache entries.
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+}
+
+/// Whether a file is a cached image, rather than a dotfile or metadata.
+fn is_entry(path: &Path) -> bool {
+    !is_hidden(path) && path.extension() != Some(std::ffi::OsStr::new(METADATA_EXTENSION))
+}
+
+/// Files left by writes interrupted before their rename.
+fn is_temp(path: &Path) -> bool {
+    is_hidden(path) && path.extension() == Some(std::ffi::OsStr::new(TEMP_EXTENSION))
+}
+
+/// Writes `data` to a new file, renamed over the entry `key` once complete,
+/// so readers see either the previous entry or the whole new one, and
+/// mappings of the previous file stay valid.
+async fn replace(cache_dir: &Path, key: &str, data: &[u8]) -> std::io::Result<()> {
+    static WRITES: AtomicU64 = AtomicU64::new(0);
+    let write = WRITES.fetch_add(1, Ordering::Relaxed);
+    let temp_path = cache_dir.join(format!(
+        ".{key}.{}-{write}.{TEMP_EXTENSION}",
+        std::process::id()
+    ));
+
+    let result = match fs::write(&temp_path, data).await {
+        Ok(()) => fs::rename(&temp_path, cache_dir.join(key)).await,
+        Err(e) => Err(e),
+    };
+    if result.is_err() {
+        let _ = remove_if_exists(&temp_path).await;
+    }
+    result
+}
+
+#[cfg(not(all(feature = "mmap", not(target_arch = "wasm32"))))]
+async fn read_entry(path: PathBuf) -> std::io::Result<Bytes> {
+    fs::read(path).await.map(Bytes::from)
+}
+
+#[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+async fn read_entry(path: PathBuf) -> std::io::Result<Bytes> {
+    tokio::task::spawn_blocking(move || map_or_read(&path))
+        .await
+        .map_err(std::io::Error::other)?
+}
+
+/// Maps the file at `path` when it is large enough, reading it otherwise or
+/// when the mapping fails.
+#[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+fn map_or_read(path: &Path) -> std::io::Result<Bytes> {
+    use std::io::Read;
+
+    let mut file = std::fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    if len >= MMAP_MIN_SIZE {
+        // SAFETY: the cache never truncates or rewrites an entry in place (see
+        // `replace`), and an unlinked file stays mapped until the mapping is
+        // dropped. Only another process truncating the file could invalidate
+        // it, which operators must not do to a live cache directory.
+        match unsafe { memmap2::Mmap::map(&file) } {
+            Ok(mapping) => return Ok(Bytes::from_owner(mapping)),
+            Err(e) => log::debug!("Failed to map {}, reading it: {e}", path.display()),
+        }
+    }
+    let mut contents = Vec::with_capacity(usize::try_from(len).unwrap_or_default());
+    file.read_to_end(&mut contents)?;
+    Ok(contents.into())
+}
+
+/// Removes the file of a tombstoned entry, returning whether it is gone.
+/// Blocking, but only ever called with files Windows refused to delete.
+fn remove_stale(cache_dir: &Path, key: &str) -> bool {
+    match std::fs::remove_file(cache_dir.join(key)) {
+        Ok(()) => true,
+        Err(e) => e.kind() == std::io::ErrorKind::NotFound,
+    }
+}
+
+/// Metadata of a listed file, or `None` when it was deleted since.
+async fn file_metadata(entry: &fs::DirEntry) -> std::io::Result<Option<std::fs::Metadata>> {
+    match entry.metadata().await {
+        Ok(metadata) => Ok(Some(metadata)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn metadata_path(cache_dir: &Path, key: &str) -> PathBuf {
+    cache_dir.join(metadata_key(key))
+}
+
+/// Name of the metadata file of the entry `key`.
+fn metadata_key(key: &str) -> String {
+    format!("{key}.{METADATA_EXTENSION}")
+}
+
+async fn remove_if_exists(path: &Path) -> std::io::Result<()> {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
     }
 }
@@ -0,0 +1,386 @@
+use crate::config::{parse_size, AppConfig};
+use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub const VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), " (", env!("GIT_HASH"), ")");
+
+/// High-performance image optimization service compatible with img.plasmic.app.
+///
+/// Settings are resolved with the following precedence: command-line flags,
+/// then environment variables, then the config file, then built-in defaults.
+#[derive(Debug, Parser)]
+#[command(name = "img-optimizer", version = VERSION, about, long_about)]
+#[command(args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    /// Path to a TOML config file (default: ./config.toml when present)
+    #[arg(long, global = true, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Options for the `serve` command, accepted without the subcommand name
+    #[command(flatten)]
+    pub serve: ServeArgs,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the HTTP server (the default when no command is given)
+    Serve(Box<ServeArgs>),
+    /// Inspect or clear the on-disk cache
+    Cache(CacheArgs),
+    /// Optimize a local image file or an http(s) URL with the same pipeline
+    /// as the server
+    Optimize(OptimizeArgs),
+    /// Check that a running server is ready, for container health checks;
+    /// exits 0 when it is, 1 otherwise
+    Check(CheckArgs),
+    /// Process the images listed in a manifest into the cache, resuming an
+    /// interrupted run; exits 1 when any image failed
+    Pregen(PregenArgs),
+}
+
+#[derive(Debug, Clone, Default, Args)]
+pub struct ServeArgs {
+    /// IP address to bind [env: BIND_ADDRESS] [default: 0.0.0.0]
+    #[arg(long, value_name = "IP")]
+    pub bind_address: Option<String>,
+
+    /// HTTP port [env: PORT] [default: 3000]
+    #[arg(long, short = 'p')]
+    pub port: Option<u16>,
+
+    /// Directory storing processed images [env: CACHE_DIR] [default: cache]
+    #[arg(long, value_name = "DIR")]
+    pub cache_dir: Option<PathBuf>,
+
+    /// `Cache-Control` max-age sent with images [env: CACHE_MAX_AGE] [default: 31536000]
+    #[arg(long, value_name = "SECS")]
+    pub cache_max_age: Option<u32>,
+
+    /// Directory of originals served by the direct image route [env: STORAGE_DIR] [default: storage]
+    #[arg(long, value_name = "DIR")]
+    pub storage_dir: Option<PathBuf>,
+
+    /// Bearer token allowing image ingestion with PUT [env: STORAGE_ADMIN_TOKEN]
+    #[arg(long, value_name = "TOKEN")]
+    pub storage_admin_token: Option<String>,
+
+    /// Origin fetch timeout in seconds [env: FETCH_TIMEOUT] [default: 30]
+    #[arg(long, value_name = "SECS")]
+    pub fetch_timeout: Option<u64>,
+
+    /// User-Agent sent to origins [env: FETCH_USER_AGENT]
+    #[arg(long, value_name = "UA")]
+    pub user_agent: Option<String>,
+
+    /// Directory `file://` sources are read from; disabled when unset [env: LOCAL_SOURCE_ROOT]
+    #[arg(long, value_name = "DIR")]
+    pub local_source_root: Option<PathBuf>,
+
+    /// Maximum accepted `w` parameter [env: MAX_WIDTH] [default: 3840]
+    #[arg(long, value_name = "PX")]
+    pub max_width: Option<u32>,
+
+    /// Maximum accepted `h` parameter [env: MAX_HEIGHT] [default: 3840]
+    #[arg(long, value_name = "PX")]
+    pub max_height: Option<u32>,
+
+    /// Maximum source image size, e.g. `20MB` [env: MAX_IMAGE_SIZE] [default: 50MB]
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub max_image_size: Option<usize>,
+
+    /// Quality used when `q` is omitted [env: DEFAULT_QUALITY] [default: 75]
+    #[arg(long, value_name = "1-100")]
+    pub default_quality: Option<u8>,
+
+    /// Bucket `s3://` sources may be read from, repeatable [env: S3_ALLOWED_BUCKETS]
+    #[arg(long = "s3-allowed-bucket", value_name = "BUCKET")]
+    pub s3_allowed_buckets: Vec<String>,
+
+    /// Allowed CORS origin, repeatable; any origin when unset [env: CORS_ALLOWED_ORIGINS]
+    #[arg(long = "cors-allowed-origin", value_name = "ORIGIN")]
+    pub cors_allowed_origins: Vec<String>,
+
+    /// Comma-separated `key` or `label:key` API keys [env: API_KEYS]
+    #[arg(long, value_name = "KEYS")]
+    pub api_keys: Option<String>,
+
+    /// URL probed with HEAD by /health/ready [env: READINESS_CANARY_URL]
+    #[arg(long, value_name = "URL")]
+    pub canary_url: Option<String>,
+
+    /// PEM certificate chain; serves HTTPS together with --tls-key [env: TLS_CERT_PATH]
+    #[arg(long, value_name = "FILE", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert [env: TLS_KEY_PATH]
+    #[arg(long, value_name = "FILE", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Plain-HTTP port serving only the health endpoints when TLS is on [env: TLS_HTTP_PORT]
+    #[arg(long, value_name = "PORT")]
+    pub tls_http_port: Option<u16>,
+
+    /// Read every image query as imgix parameters [env: IMGIX_COMPAT_ENABLED] [default: false]
+    #[arg(long, value_name = "BOOL")]
+    pub imgix_compat: Option<bool>,
+
+    /// Reject imgix parameters that have no equivalent [env: IMGIX_STRICT] [default: false]
+    #[arg(long, value_name = "BOOL")]
+    pub imgix_strict: Option<bool>,
+
+    /// Enable or disable the /metrics endpoint [env: METRICS_ENABLED] [default: true]
+    #[arg(long, value_name = "BOOL")]
+    pub metrics: Option<bool>,
+
+    /// Send a `Server-Timing` header with per-phase durations [env: SERVER_TIMING_ENABLED] [default: false]
+    #[arg(long, value_name = "BOOL")]
+    pub server_timing: Option<bool>,
+
+    /// Serve Next.js-compatible `/_next/image` [env: NEXTJS_COMPAT_ENABLED] [default: false]
+    #[arg(long, value_name = "BOOL")]
+    pub nextjs_compat: Option<bool>,
+
+    /// Serve the admin-only `/debug` playground page [env: DEBUG_PAGE_ENABLED] [default: false]
+    #[arg(long, value_name = "BOOL")]
+    pub debug_page: Option<bool>,
+
+    /// Seconds to wait for in-flight requests on shutdown [env: SHUTDOWN_TIMEOUT] [default: 30]
+    #[arg(long, value_name = "SECS")]
+    pub shutdown_timeout: Option<u64>,
+
+    /// Seconds to keep serving with a failing readiness check before closing
+    /// listeners [env: SHUTDOWN_DELAY] [default: 0]
+    #[arg(long, value_name = "SECS")]
+    pub shutdown_delay: Option<u64>,
+
+    /// Print the effective configuration (secrets redacted) and exit
+    #[arg(long)]
+    pub print_config: bool,
+}
+
+impl ServeArgs {
+    /// Overrides `config` with every flag given on the command line.
+    pub fn apply(&self, config: &mut AppConfig) {
+        if let Some(bind_address) = &self.bind_address {
+            config.server.bind_address = bind_address.clone();
+        }
+        if let Some(port) = self.port {
+            config.server.port = port;
+        }
+        if let Some(cache_dir) = &self.cache_dir {
+            config.cache.dir = cache_dir.clone();
+        }
+        if let Some(cache_max_age) = self.cache_max_age {
+            config.cache.max_age_secs = cache_max_age;
+        }
+        if let Some(storage_dir) = &self.storage_dir {
+            config.storage.dir = storage_dir.clone();
+        }
+        if let Some(storage_admin_token) = &self.storage_admin_token {
+            config.storage.admin_token = Some(storage_admin_token.clone());
+        }
+        if let Some(fetch_timeout) = self.fetch_timeout {
+            config.fetch.timeout_secs = fetch_timeout;
+        }
+        if let Some(user_agent) = &self.user_agent {
+            config.fetch.user_agent = user_agent.clone();
+        }
+        if let Some(local_source_root) = &self.local_source_root {
+            config.fetch.local_source_root = Some(local_source_root.clone());
+        }
+        if let Some(max_width) = self.max_width {
+            config.limits.max_width = max_width;
+        }
+        if let Some(max_height) = self.max_height {
+            config.limits.max_height = max_height;
+        }
+        if let Some(max_image_size) = self.max_image_size {
+            config.limits.max_image_size = max_image_size;
+        }
+        if let Some(default_quality) = self.default_quality {
+            config.limits.default_quality = default_quality;
+        }
+        if !self.s3_allowed_buckets.is_empty() {
+            config.s3.allowed_buckets = self.s3_allowed_buckets.clone();
+        }
+        if !self.cors_allowed_origins.is_empty() {
+            config.cors.allowed_origins = self.cors_allowed_origins.clone();
+        }
+        if let Some(api_keys) = &self.api_keys {
+            config.auth.api_keys = Some(api_keys.clone());
+        }
+        if let Some(canary_url) = &self.canary_url {
+            config.health.canary_url = Some(canary_url.clone());
+        }
+        if let Some(tls_cert) = &self.tls_cert {
+            config.tls.cert_path = Some(tls_cert.clone());
+        }
+        if let Some(tls_key) = &self.tls_key {
+            config.tls.key_path = Some(tls_key.clone());
+        }
+        if let Some(tls_http_port) = self.tls_http_port {
+            config.tls.http_port = Some(tls_http_port);
+        }
+        if let Some(imgix_compat) = self.imgix_compat {
+            config.imgix.enabled = imgix_compat;
+        }
+        if let Some(imgix_strict) = self.imgix_strict {
+            config.imgix.strict = imgix_strict;
+        }
+        if let Some(metrics) = self.metrics {
+            config.features.metrics = metrics;
+        }
+        if let Some(server_timing) = self.server_timing {
+            config.features.server_timing = server_timing;
+        }
+        if let Some(nextjs_compat) = self.nextjs_compat {
+            config.features.nextjs_compat = nextjs_compat;
+        }
+        if let Some(debug_page) = self.debug_page {
+            config.features.debug_page = debug_page;
+        }
+        if let Some(shutdown_timeout) = self.shutdown_timeout {
+            config.server.shutdown_timeout_secs = shutdown_timeout;
+        }
+        if let Some(shutdown_delay) = self.shutdown_delay {
+            config.server.shutdown_delay_secs = shutdown_delay;
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct CacheArgs {
+    /// Cache directory [env: CACHE_DIR] [default: cache]
+    #[arg(long, value_name = "DIR")]
+    pub cache_dir: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: CacheCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CacheCommand {
+    /// Print the number of cached entries and their total size
+    Stats,
+    /// Delete every cached entry
+    Clear,
+}
+
+#[derive(Debug, Args)]
+pub struct OptimizeArgs {
+    /// Source image file, or http(s) URL fetched like the server's `src`
+    pub input: String,
+
+    /// Destination file, `-` for standard output
+    #[arg(long, short = 'o')]
+    pub output: PathBuf,
+
+    /// Target width in pixels
+    #[arg(long, short = 'w')]
+    pub width: Option<u32>,
+
+    /// Output quality (1-100)
+    #[arg(long, short = 'q')]
+    pub quality: Option<u8>,
+
+    /// Output format (jpeg, jpg, png, webp); detected from the image when omitted
+    #[arg(long, short = 'f')]
+    pub format: Option<String>,
+
+    /// Print the summary, or the error, to stderr as a JSON object
+    #[arg(long)]
+    pub json: bool,
+}
+
+impl OptimizeArgs {
+    /// Whether the image is written to standard output.
+    pub fn writes_to_stdout(&self) -> bool {
+        self.output.as_os_str() == "-"
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct CheckArgs {
+    /// Base URL of the server [default: http://127.0.0.1:<configured port>]
+    #[arg(long)]
+    pub url: Option<String>,
+
+    /// Seconds the whole check may take, e.g. `0.5` [default: 2]
+    #[arg(long, value_name = "SECS", value_parser = parse_timeout)]
+    pub timeout: Option<Duration>,
+
+    /// Also optimize a bundled 1x1 PNG through the image endpoint
+    #[arg(long)]
+    pub optimize: bool,
+
+    /// API key sent with the optimization, when the server requires one
+    #[arg(long, value_name = "KEY")]
+    pub api_key: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct PregenArgs {
+    /// JSON manifest: an array of `{"src", "widths", "format", "quality"}`
+    #[arg(long, value_name = "FILE")]
+    pub manifest: PathBuf,
+
+    /// Images processed at once
+    #[arg(long, value_name = "N", default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..))]
+    pub concurrency: u16,
+
+    /// Most images started per second, minute or hour, e.g. `10/s` or `300/m`
+    #[arg(long, value_name = "N/UNIT", value_parser = parse_rate)]
+    pub rate: Option<Duration>,
+
+    /// File recording completed images, removed once all are
+    /// [default: <manifest>.checkpoint]
+    #[arg(long, value_name = "FILE")]
+    pub checkpoint: Option<PathBuf>,
+
+    /// Cache directory [env: CACHE_DIR] [default: cache]
+    #[arg(long, value_name = "DIR")]
+    pub cache_dir: Option<PathBuf>,
+}
+
+impl PregenArgs {
+    pub fn checkpoint(&self) -> PathBuf {
+        self.checkpoint.clone().unwrap_or_else(|| {
+            let mut path = self.manifest.clone().into_os_string();
+            path.push(".checkpoint");
+            path.into()
+        })
+    }
+}
+
+/// Parses a rate such as `10/s`, `300/m` or `5000/h` (a bare number is per
+/// second) into the least time between two starts.
+pub fn parse_rate(value: &str) -> Result<Duration, String> {
+    let (count, unit) = value.trim().split_once('/').unwrap_or((value.trim(), "s"));
+    let unit_secs = match unit {
+        "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => f64::NAN,
+    };
+    match count.parse::<f64>() {
+        Ok(count) if count > 0.0 && count.is_finite() && unit_secs.is_finite() => {
+            Ok(Duration::from_secs_f64(unit_secs / count))
+        }
+        _ => Err(format!(
+            "expected a positive rate such as `10/s`, `300/m` or `5000/h`, got `{value}`"
+        )),
+    }
+}
+
+fn parse_timeout(value: &str) -> Result<Duration, String> {
+    match value.trim().parse::<f64>() {
+        Ok(secs) if secs > 0.0 && secs.is_finite() => Ok(Duration::from_secs_f64(secs)),
+        _ => Err(format!(
+            "expected a positive number of seconds, got `{value}`"
+        )),
+    }
+}
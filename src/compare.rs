@@ -0,0 +1,219 @@
+//! Quality scores of an optimized image against its original, for tuning
+//! quality settings: PSNR over RGB, and SSIM over luma in 8×8 windows. The
+//! original is downscaled to the output's dimensions with the filter of the
+//! pipeline before scoring. Scores only depend on the pixels: the same
+//! images always score the same.
+
+use crate::error::AppResult;
+use crate::image_processor::decode;
+#[cfg(feature = "runtime")]
+use crate::{
+    acquire_permit, error::AppError, limiter::JobClass, load_source, metrics::PhaseTimings,
+    resolve_source, transform_many, AppState, ImageParams, ImageSource, ValidatedParams,
+};
+use image::{imageops, RgbImage};
+use serde::Deserialize;
+#[cfg(feature = "runtime")]
+use serde::Serialize;
+
+/// Side of the SSIM windows, smaller for images narrower than that.
+const SSIM_WINDOW: u32 = 8;
+
+/// Query of `GET /img-optimizer/v1/img/compare`, with the parameters of the
+/// same name of [`crate::ImageParams`].
+#[derive(Debug, Default, Deserialize)]
+pub struct CompareParams {
+    #[serde(alias = "url")]
+    pub src: Option<String>,
+    pub srcb64: Option<String>,
+    pub w: Option<String>,
+    pub q: Option<String>,
+    pub f: Option<String>,
+}
+
+/// Scores of an output against its original, see [`score`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Scores {
+    /// Peak signal-to-noise ratio in decibels, `None` when the pixels are
+    /// identical. Above 40 is hard to tell apart from the original.
+    pub psnr: Option<f64>,
+    /// Structural similarity, 1 for identical images.
+    pub ssim: f64,
+}
+
+/// Decodes `original` and `output`, downscales the original to the output's
+/// dimensions and scores the output against it. Transparency is ignored.
+pub fn score(original: &[u8], output: &[u8]) -> AppResult<Scores> {
+    let output = decode(output)?.to_rgb8();
+    let original = decode(original)?;
+    let reference = if original.width() == output.width() && original.height() == output.height() {
+        original.to_rgb8()
+    } else {
+        original
+            .resize_exact(
+                output.width(),
+                output.height(),
+                imageops::FilterType::Lanczos3,
+            )
+            .to_rgb8()
+    };
+    Ok(Scores {
+        psnr: psnr(&reference, &output),
+        ssim: ssim(&reference, &output),
+    })
+}
+
+/// PSNR of `distorted` against `reference`, of the same dimensions, over
+/// the three channels. `None` when they are identical.
+pub fn psnr(reference: &RgbImage, distorted: &RgbImage) -> Option<f64> {
+    let squared_error: u64 = reference
+        .as_raw()
+        .iter()
+        .zip(distorted.as_raw())
+        .map(|(&a, &b)| u64::from(a.abs_diff(b)).pow(2))
+        .sum();
+    if squared_error == 0 {
+        return None;
+    }
+    let mse = squared_error as f64 / reference.as_raw().len() as f64;
+    Some(10.0 * (255.0 * 255.0 / mse).log10())
+}
+
+/// Mean SSIM of `distorted` against `reference`, of the same dimensions,
+/// over the luma of windows overlapping by half.
+pub fn ssim(reference: &RgbImage, distorted: &RgbImage) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    let (width, height) = reference.dimensions();
+    let (x, y) = (luma(reference), luma(distorted));
+    let window = SSIM_WINDOW.min(width).min(height).max(1);
+    let step = (window / 2).max(1) as usize;
+    let pixels = f64::from(window * window);
+
+    let mut total = 0.0;
+    let mut windows = 0u32;
+    for top in (0..=(height - window) as usize).step_by(step) {
+        for left in (0..=(width - window) as usize).step_by(step) {
+            let (mut sum_x, mut sum_y) = (0.0, 0.0);
+            let (mut sum_xx, mut sum_yy, mut sum_xy) = (0.0, 0.0, 0.0);
+            for row in top..top + window as usize {
+                let start = row * width as usize + left;
+                for i in start..start + window as usize {
+                    sum_x += x[i];
+                    sum_y += y[i];
+                    sum_xx += x[i] * x[i];
+                    sum_yy += y[i] * y[i];
+                    sum_xy += x[i] * y[i];
+                }
+            }
+            let (mean_x, mean_y) = (sum_x / pixels, sum_y / pixels);
+            let variance_x = sum_xx / pixels - mean_x * mean_x;
+            let variance_y = sum_yy / pixels - mean_y * mean_y;
+            let covariance = sum_xy / pixels - mean_x * mean_y;
+            total += ((2.0 * mean_x * mean_y + C1) * (2.0 * covariance + C2))
+                / ((mean_x * mean_x + mean_y * mean_y + C1) * (variance_x + variance_y + C2));
+            windows += 1;
+        }
+    }
+    total / f64::from(windows)
+}
+
+/// BT.601 luma of each pixel, row by row.
+fn luma(image: &RgbImage) -> Vec<f64> {
+    image
+        .pixels()
+        .map(|pixel| {
+            let [r, g, b] = pixel.0.map(f64::from);
+            0.299 * r + 0.587 * g + 0.114 * b
+        })
+        .collect()
+}
+
+#[cfg(feature = "runtime")]
+/// Response of `GET /img-optimizer/v1/img/compare`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Comparison {
+    pub src: String,
+    pub content_type: String,
+    pub width: u32,
+    pub height: u32,
+    pub quality: u8,
+    pub original_bytes: usize,
+    pub output_bytes: usize,
+    /// Bytes saved, in percent of the original; negative when the output
+    /// is larger.
+    pub savings_percent: f64,
+    /// `null` when the output has the pixels of the original.
+    pub psnr: Option<f64>,
+    pub ssim: f64,
+}
+
+#[cfg(feature = "runtime")]
+/// Processes the image `params` describe, or reads it from the cache, and
+/// scores it against its original, fetched once for both.
+pub async fn compare(
+    params: CompareParams,
+    accept: Option<&str>,
+    state: &AppState,
+) -> AppResult<Comparison> {
+    let mut params = ImageParams {
+        src: params.src,
+        srcb64: params.srcb64,
+        w: params.w,
+        q: params.q,
+        f: params.f,
+        ..Default::default()
+    };
+    params.resolve_auto_format(accept);
+    let ValidatedParams { source, plan, .. } = params.validate(&state.config().limits)?;
+    let src = source.ok_or_else(|| AppError::MissingRequiredParameter {
+        param: "src".to_string(),
+    })?;
+    let (source, identity) = resolve_source(&src, state)?;
+
+    let mut timings = PhaseTimings::default();
+    let original = load_source(source, state, &mut timings).await?;
+    let original_bytes = original.len();
+    let output = transform_many(
+        ImageSource::Bytes(original.clone()),
+        &identity,
+        std::slice::from_ref(&plan),
+        state,
+        &mut timings,
+    )
+    .await?
+    .remove(0);
+
+    let permit = acquire_permit(state, JobClass::Heavy).await?;
+    let scores = {
+        let data = output.data.clone();
+        tokio::task::spawn_blocking(move || score(&original, &data))
+            .await
+            .map_err(|e| AppError::ImageProcessingFailed {
+                reason: format!("Image comparison task failed: {e}"),
+            })??
+    };
+    drop(permit);
+
+    let savings = 100.0 * (1.0 - output.data.len() as f64 / original_bytes as f64);
+    Ok(Comparison {
+        src,
+        content_type: output.content_type,
+        width: output.metadata.width,
+        height: output.metadata.height,
+        quality: plan.quality,
+        original_bytes,
+        output_bytes: output.data.len(),
+        savings_percent: round(savings, 2),
+        psnr: scores.psnr.map(|psnr| round(psnr, 2)),
+        ssim: round(scores.ssim, 4),
+    })
+}
+
+#[cfg(feature = "runtime")]
+fn round(value: f64, decimals: i32) -> f64 {
+    let scale = 10f64.powi(decimals);
+    (value * scale).round() / scale
+}
@@ -0,0 +1,1173 @@
+use crate::image_processor::OutputFormat;
+use crate::{
+    DEFAULT_QUALITY, MAX_BUNDLE_SIZE, MAX_HEIGHT, MAX_IMAGE_SIZE, MAX_OUTPUT_PIXELS,
+    MAX_QUERY_LENGTH, MAX_SRC_LENGTH, MAX_WIDTH,
+};
+use anyhow::{anyhow, bail, Context};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+const REDACTED: &str = "<redacted>";
+
+/// Runtime configuration, resolved from defaults, then an optional TOML file,
+/// then environment variables, then command-line flags.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AppConfig {
+    pub server: ServerConfig,
+    pub cache: CacheConfig,
+    pub storage: StorageConfig,
+    pub fetch: FetchConfig,
+    pub s3: S3Config,
+    pub ipfs: IpfsConfig,
+    pub limits: Limits,
+    pub processing: ProcessingConfig,
+    pub cors: CorsConfig,
+    pub auth: AuthConfig,
+    pub health: HealthConfig,
+    pub tls: TlsConfig,
+    pub imgix: ImgixConfig,
+    pub jobs: JobsConfig,
+    pub audit: AuditConfig,
+    pub features: FeatureToggles,
+    /// Fetch profiles of particular origins, by host pattern.
+    pub origin: BTreeMap<String, OriginProfile>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind_address: String,
+    pub port: u16,
+    /// Seconds to wait for in-flight requests on shutdown.
+    pub shutdown_timeout_secs: u64,
+    /// Seconds to keep serving with a failing readiness check before closing
+    /// the listeners.
+    pub shutdown_delay_secs: u64,
+    /// How much of an error's context its ProblemDetails `detail` reveals.
+    pub error_detail: ErrorDetail,
+    /// Base URL clients reach the service at, such as
+    /// `https://cdn.example.com`, used for the URLs of srcset manifests.
+    /// Derived from each request when unset.
+    pub public_url: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: "0.0.0.0".to_string(),
+            port: 3000,
+            shutdown_timeout_secs: 30,
+            shutdown_delay_secs: 0,
+            error_detail: ErrorDetail::Full,
+            public_url: None,
+        }
+    }
+}
+
+/// Verbosity of error responses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorDetail {
+    /// `detail` includes source URLs, parameter values and upstream causes.
+    Full,
+    /// `detail` keeps the message template, with its values replaced by a
+    /// reference ID logged server-side along with the full error.
+    Minimal,
+}
+
+impl std::str::FromStr for ErrorDetail {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "full" => Ok(Self::Full),
+            "minimal" => Ok(Self::Minimal),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub dir: PathBuf,
+    /// `max-age` of the `Cache-Control` header sent with images.
+    pub max_age_secs: u32,
+    /// Keep the keys of cached entries in memory, so that misses skip the
+    /// filesystem. Turn off when other processes write to `dir`.
+    pub index: bool,
+    pub write_mode: CacheWriteMode,
+}
+
+/// When processed images are written to the cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheWriteMode {
+    /// Before responding.
+    Sync,
+    /// In the background once the response is on its way, so cold requests
+    /// don't wait on the cache. Shutdown waits for writes still pending,
+    /// within `server.shutdown_timeout_secs`.
+    Deferred,
+}
+
+impl std::str::FromStr for CacheWriteMode {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "sync" => Ok(Self::Sync),
+            "deferred" => Ok(Self::Deferred),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("cache"),
+            max_age_secs: 31_536_000,
+            index: true,
+            write_mode: CacheWriteMode::Sync,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// Directory holding the originals served by `/img-optimizer/v1/img/{id}`.
+    pub dir: PathBuf,
+    /// Bearer token required to ingest images; ingestion is disabled when unset.
+    pub admin_token: Option<String>,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("storage"),
+            admin_token: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FetchConfig {
+    pub timeout_secs: u64,
+    pub user_agent: String,
+    /// Header carrying the request ID on origin fetches.
+    pub request_id_header: String,
+    /// Directory `file://` sources are read from; they are rejected when unset.
+    pub local_source_root: Option<PathBuf>,
+    /// Fetches to the same host at once; more wait for a slot, for up to
+    /// `timeout_secs`.
+    pub max_per_host: usize,
+    /// Least milliseconds between the starts of two fetches to the same host.
+    pub min_interval_ms: u64,
+    /// Limits of particular hosts, overriding the two above.
+    pub hosts: BTreeMap<String, HostLimitConfig>,
+    /// Idle connections kept open to each host.
+    pub pool_max_idle_per_host: usize,
+    /// Seconds an idle connection is kept open.
+    pub pool_idle_timeout_secs: u64,
+    /// Hosts http(s) sources may be fetched from and redirected to, in
+    /// lower case, or `*.example.com` for the subdomains of a domain. Empty
+    /// allows any host.
+    pub allowed_hosts: Vec<String>,
+    /// Whether redirects from https to http are followed.
+    pub allow_https_downgrade: bool,
+    /// Redirects followed for one source.
+    pub max_redirects: usize,
+}
+
+impl Default for FetchConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 30,
+            user_agent: "Plasmic-Image-Optimizer/1.0".to_string(),
+            request_id_header: crate::logging::REQUEST_ID_HEADER.to_string(),
+            local_source_root: None,
+            max_per_host: 8,
+            min_interval_ms: 0,
+            hosts: BTreeMap::new(),
+            pool_max_idle_per_host: 8,
+            pool_idle_timeout_secs: 90,
+            allowed_hosts: Vec::new(),
+            allow_https_downgrade: true,
+            max_redirects: 10,
+        }
+    }
+}
+
+impl FetchConfig {
+    /// Whether `host` is on `allowed_hosts`, or the list is empty.
+    pub fn allows_host(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        self.allowed_hosts.is_empty()
+            || self
+                .allowed_hosts
+                .iter()
+                .any(|allowed| match allowed.strip_prefix("*.") {
+                    Some(domain) => host
+                        .strip_suffix(domain)
+                        .is_some_and(|subdomain| subdomain.ends_with('.')),
+                    None => *allowed == host,
+                })
+    }
+}
+
+/// Limits of one host in `fetch.hosts`; unset ones are the defaults of
+/// `fetch`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HostLimitConfig {
+    pub max_concurrent: Option<usize>,
+    pub min_interval_ms: Option<u64>,
+}
+
+/// Treatment of the sources of one origin, an `[origin."<pattern>"]` section
+/// whose pattern is a host, `*.example.com` for the subdomains of a domain,
+/// or `*` for the default profile. Values left unset are those of the
+/// default profile, then of `fetch`; see [`crate::origin`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OriginProfile {
+    /// Seconds each attempt may take, redirects included.
+    pub timeout_secs: Option<u64>,
+    /// Attempts after the first for failures that may pass: origin errors
+    /// and timeouts. None by default.
+    pub retries: Option<u32>,
+    /// Milliseconds before the first retry, doubled before each next one.
+    pub retry_backoff_ms: Option<u64>,
+    pub max_concurrent: Option<usize>,
+    pub min_interval_ms: Option<u64>,
+    /// Headers sent with every fetch, replacing the default `User-Agent`.
+    pub headers: BTreeMap<String, String>,
+    /// Whether sources may be fetched from, and redirected to, the origin,
+    /// whatever `fetch.allowed_hosts` says.
+    pub allowed: Option<bool>,
+}
+
+/// `s3://bucket/key` sources, served when built with the `s3-source`
+/// feature. Credentials and region come from the standard AWS environment.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct S3Config {
+    /// Buckets sources may be read from; no bucket can be read when empty.
+    pub allowed_buckets: Vec<String>,
+    /// Endpoint of an S3-compatible service such as MinIO, instead of AWS.
+    pub endpoint_url: Option<String>,
+    /// Address buckets as `endpoint/bucket` rather than `bucket.endpoint`.
+    pub force_path_style: bool,
+}
+
+/// `ipfs://<cid>/path` sources, fetched through HTTP gateways; they are
+/// rejected unless `gateway` is set.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IpfsConfig {
+    /// Base URL of the gateway, such as `https://cloudflare-ipfs.com/ipfs/`.
+    pub gateway: Option<String>,
+    /// Gateway tried when fetching from `gateway` fails.
+    pub fallback_gateway: Option<String>,
+}
+
+impl IpfsConfig {
+    /// The gateways to fetch from, in order.
+    pub fn gateways(&self) -> impl Iterator<Item = &str> {
+        self.gateway
+            .iter()
+            .chain(&self.fallback_gateway)
+            .map(String::as_str)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub max_width: u32,
+    pub max_height: u32,
+    /// Maximum size in bytes of a downloaded source image.
+    pub max_image_size: usize,
+    /// Quality used when `q` is omitted, for formats without one of their
+    /// own in `format_quality`.
+    pub default_quality: u8,
+    pub format_quality: FormatQuality,
+    /// Maximum size in bytes of the variants of a bundle, together.
+    pub max_bundle_size: usize,
+    /// Largest `w`×`h` box, in pixels, of the output or of a `resize` step,
+    /// whatever the source.
+    pub max_output_pixels: u64,
+    /// Longest `src` or `srcb64`, in bytes.
+    pub max_src_length: usize,
+    /// Longest query string of an image route, in bytes.
+    pub max_query_length: usize,
+    /// What JPEG output of a transparent source without `bg` does, unless
+    /// the request's `alpha` says otherwise.
+    pub alpha_policy: AlphaPolicy,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_width: MAX_WIDTH,
+            max_height: MAX_HEIGHT,
+            max_image_size: MAX_IMAGE_SIZE,
+            default_quality: DEFAULT_QUALITY,
+            format_quality: FormatQuality::default(),
+            max_bundle_size: MAX_BUNDLE_SIZE,
+            max_output_pixels: MAX_OUTPUT_PIXELS,
+            max_src_length: MAX_SRC_LENGTH,
+            max_query_length: MAX_QUERY_LENGTH,
+            alpha_policy: AlphaPolicy::Flatten,
+        }
+    }
+}
+
+impl Limits {
+    /// Quality of `format` when `q` is omitted. Output in the format of the
+    /// source, unknown until it is fetched, uses `default_quality`.
+    pub fn quality_for(&self, format: Option<OutputFormat>) -> u8 {
+        format
+            .and_then(|format| self.format_quality.get(format))
+            .unwrap_or(self.default_quality)
+    }
+
+    /// Quality used when `q` is omitted, for each output format and for the
+    /// format of the source.
+    pub fn effective_quality(&self) -> BTreeMap<&'static str, u8> {
+        OutputFormat::ALL
+            .into_iter()
+            .map(|format| (format.name(), self.quality_for(Some(format))))
+            .chain([("source", self.default_quality)])
+            .collect()
+    }
+}
+
+/// Handling of transparency an output format can't keep, when no `bg` says
+/// what to flatten it onto.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlphaPolicy {
+    /// Drop the alpha channel silently.
+    Flatten,
+    /// Drop it, and say so with `X-Alpha-Flattened: true`.
+    Warn,
+    /// Refuse the request with `400`.
+    Reject,
+}
+
+impl std::str::FromStr for AlphaPolicy {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "flatten" => Ok(Self::Flatten),
+            "warn" => Ok(Self::Warn),
+            "reject" => Ok(Self::Reject),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Default quality of each output format, `limits.default_quality` when
+/// unset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FormatQuality {
+    pub jpeg: Option<u8>,
+    pub png: Option<u8>,
+    pub webp: Option<u8>,
+}
+
+impl FormatQuality {
+    pub fn get(&self, format: OutputFormat) -> Option<u8> {
+        match format {
+            OutputFormat::Jpeg => self.jpeg,
+            OutputFormat::Png => self.png,
+            OutputFormat::WebP => self.webp,
+        }
+    }
+
+    fn get_mut(&mut self, format: OutputFormat) -> &mut Option<u8> {
+        match format {
+            OutputFormat::Jpeg => &mut self.jpeg,
+            OutputFormat::Png => &mut self.png,
+            OutputFormat::WebP => &mut self.webp,
+        }
+    }
+}
+
+/// Concurrency of image fetching and processing, see
+/// [`crate::limiter::ProcessingLimiter`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProcessingConfig {
+    /// Images fetched and processed at once.
+    pub max_concurrent: usize,
+    /// Requests allowed to wait for a processing slot; further ones are shed.
+    pub max_waiting: usize,
+    /// Status of shed requests, 429 or 503.
+    pub shed_status: u16,
+    /// Slots reserved for small jobs, on top of `max_concurrent`; 0 puts
+    /// every job in the same queue.
+    pub small_max_concurrent: usize,
+    /// Largest source, in pixels, of a small job.
+    pub small_max_source_pixels: u64,
+    /// Largest output, in pixels, of a small job.
+    pub small_max_target_pixels: u64,
+    /// Threads decoding, resizing and encoding images.
+    pub workers: usize,
+    /// Images waiting for a worker; further ones are shed.
+    pub queue_capacity: usize,
+}
+
+impl Default for ProcessingConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent: std::thread::available_parallelism().map_or(4, |n| n.get()),
+            max_waiting: 64,
+            shed_status: 429,
+            small_max_concurrent: 1,
+            small_max_source_pixels: 2_000_000,
+            small_max_target_pixels: 250_000,
+            workers: std::thread::available_parallelism().map_or(4, |n| n.get()),
+            queue_capacity: 64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Allowed origins; empty (or `*`) allows any origin.
+    pub allowed_origins: Vec<String>,
+    pub max_age_secs: usize,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            max_age_secs: 3600,
+        }
+    }
+}
+
+impl CorsConfig {
+    pub fn allows_any_origin(&self) -> bool {
+        self.allowed_origins.is_empty() || self.allowed_origins.iter().any(|o| o == "*")
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Comma-separated `key` or `label:key` entries, see [`crate::auth::ApiKeys`].
+    pub api_keys: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    /// URL probed with a HEAD request by the readiness check.
+    pub canary_url: Option<String>,
+}
+
+/// HTTPS is served when both `cert_path` and `key_path` are set.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM file holding the certificate chain, leaf first.
+    pub cert_path: Option<PathBuf>,
+    /// PEM file holding the private key.
+    pub key_path: Option<PathBuf>,
+    /// Optional plain-HTTP port serving only the health endpoints.
+    pub http_port: Option<u16>,
+}
+
+impl TlsConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.cert_path.is_some() && self.key_path.is_some()
+    }
+}
+
+/// Translation of imgix query parameters on `/img-optimizer/v1/img`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImgixConfig {
+    /// Read every query as imgix parameters. Otherwise only queries using an
+    /// imgix-only parameter are.
+    pub enabled: bool,
+    /// Reject imgix parameters without an equivalent instead of ignoring them.
+    pub strict: bool,
+}
+
+/// Asynchronous jobs of `/img-optimizer/v1/jobs`, see [`crate::jobs`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobsConfig {
+    /// Seconds a job is kept after it was submitted or, once done, after it
+    /// finished.
+    pub ttl_secs: u64,
+    /// Jobs pending at once; further submissions are shed.
+    pub max_pending: usize,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        Self {
+            ttl_secs: 3600,
+            max_pending: 64,
+        }
+    }
+}
+
+/// Audit log of the sources fetched, see [`crate::audit`]. Off unless `dir`
+/// is set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    /// Directory of the daily `audit-YYYY-MM-DD.jsonl` files.
+    pub dir: Option<PathBuf>,
+    /// Days of files kept, today's included.
+    pub retention_days: u32,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            retention_days: 90,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeatureToggles {
+    pub metrics: bool,
+    /// Send per-phase durations in a `Server-Timing` header. Off by default
+    /// since it exposes cache status and origin latency to clients.
+    pub server_timing: bool,
+    /// Ask browsers for `Sec-CH-DPR` and `Sec-CH-Width` and size images from
+    /// them. Off by default: each hint value is a separate cached variant.
+    pub client_hints: bool,
+    /// Serve `/_next/image` with Next.js's parameter names and semantics.
+    pub nextjs_compat: bool,
+    /// Serve the `/debug` playground page to holders of the admin token.
+    pub debug_page: bool,
+}
+
+impl Default for FeatureToggles {
+    fn default() -> Self {
+        Self {
+            metrics: true,
+            server_timing: false,
+            client_hints: false,
+            nextjs_compat: false,
+            debug_page: false,
+        }
+    }
+}
+
+impl AppConfig {
+    /// Loads the configuration from `path` (or `config.toml` when it exists),
+    /// applies environment overrides, then `overrides` (command-line flags),
+    /// and validates the result.
+    pub fn load(path: Option<&Path>, overrides: impl FnOnce(&mut Self)) -> anyhow::Result<Self> {
+        let mut config = match path {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Self::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => Self::default(),
+        };
+        config.apply_env(|name| std::env::var(name).ok())?;
+        overrides(&mut config);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let raw = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        Self::from_toml(&raw).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    pub fn from_toml(raw: &str) -> anyhow::Result<Self> {
+        Ok(toml::from_str(raw)?)
+    }
+
+    /// Overrides settings from environment variables, looked up through
+    /// `lookup` so tests don't have to touch the process environment.
+    pub fn apply_env(&mut self, lookup: impl Fn(&str) -> Option<String>) -> anyhow::Result<()> {
+        fn parse<T: std::str::FromStr>(name: &str, value: String) -> anyhow::Result<T> {
+            value
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("Invalid value for {name}: '{value}'"))
+        }
+
+        if let Some(value) = lookup("BIND_ADDRESS") {
+            self.server.bind_address = value;
+        }
+        if let Some(value) = lookup("PORT") {
+            self.server.port = parse("PORT", value)?;
+        }
+        if let Some(value) = lookup("SHUTDOWN_TIMEOUT") {
+            self.server.shutdown_timeout_secs = parse("SHUTDOWN_TIMEOUT", value)?;
+        }
+        if let Some(value) = lookup("SHUTDOWN_DELAY") {
+            self.server.shutdown_delay_secs = parse("SHUTDOWN_DELAY", value)?;
+        }
+        if let Some(value) = lookup("ERROR_DETAIL") {
+            self.server.error_detail = parse("ERROR_DETAIL", value)?;
+        }
+        if let Some(value) = lookup("PUBLIC_URL") {
+            self.server.public_url = Some(value);
+        }
+        if let Some(value) = lookup("CACHE_DIR") {
+            self.cache.dir = PathBuf::from(value);
+        }
+        if let Some(value) = lookup("CACHE_MAX_AGE") {
+            self.cache.max_age_secs = parse("CACHE_MAX_AGE", value)?;
+        }
+        if let Some(value) = lookup("CACHE_INDEX") {
+            self.cache.index = parse("CACHE_INDEX", value)?;
+        }
+        if let Some(value) = lookup("CACHE_WRITE_MODE") {
+            self.cache.write_mode = parse("CACHE_WRITE_MODE", value)?;
+        }
+        if let Some(value) = lookup("STORAGE_DIR") {
+            self.storage.dir = PathBuf::from(value);
+        }
+        if let Some(value) = lookup("STORAGE_ADMIN_TOKEN") {
+            self.storage.admin_token = Some(value);
+        }
+        if let Some(value) = lookup("FETCH_TIMEOUT") {
+            self.fetch.timeout_secs = parse("FETCH_TIMEOUT", value)?;
+        }
+        if let Some(value) = lookup("FETCH_USER_AGENT") {
+            self.fetch.user_agent = value;
+        }
+        if let Some(value) = lookup("FETCH_REQUEST_ID_HEADER") {
+            self.fetch.request_id_header = value;
+        }
+        if let Some(value) = lookup("LOCAL_SOURCE_ROOT") {
+            self.fetch.local_source_root = Some(PathBuf::from(value));
+        }
+        if let Some(value) = lookup("FETCH_MAX_PER_HOST") {
+            self.fetch.max_per_host = parse("FETCH_MAX_PER_HOST", value)?;
+        }
+        if let Some(value) = lookup("FETCH_MIN_INTERVAL_MS") {
+            self.fetch.min_interval_ms = parse("FETCH_MIN_INTERVAL_MS", value)?;
+        }
+        if let Some(value) = lookup("FETCH_HOST_LIMITS") {
+            for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+                let (host, max) = entry
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("Invalid value for FETCH_HOST_LIMITS: '{entry}'"))?;
+                let max = parse("FETCH_HOST_LIMITS", max.to_string())?;
+                self.fetch
+                    .hosts
+                    .entry(host.trim().to_ascii_lowercase())
+                    .or_default()
+                    .max_concurrent = Some(max);
+            }
+        }
+        if let Some(value) = lookup("FETCH_POOL_MAX_IDLE_PER_HOST") {
+            self.fetch.pool_max_idle_per_host = parse("FETCH_POOL_MAX_IDLE_PER_HOST", value)?;
+        }
+        if let Some(value) = lookup("FETCH_POOL_IDLE_TIMEOUT") {
+            self.fetch.pool_idle_timeout_secs = parse("FETCH_POOL_IDLE_TIMEOUT", value)?;
+        }
+        if let Some(value) = lookup("FETCH_ALLOWED_HOSTS") {
+            self.fetch.allowed_hosts = value
+                .split(',')
+                .map(str::trim)
+                .filter(|host| !host.is_empty())
+                .map(str::to_ascii_lowercase)
+                .collect();
+        }
+        if let Some(value) = lookup("FETCH_ALLOW_HTTPS_DOWNGRADE") {
+            self.fetch.allow_https_downgrade = parse("FETCH_ALLOW_HTTPS_DOWNGRADE", value)?;
+        }
+        if let Some(value) = lookup("FETCH_MAX_REDIRECTS") {
+            self.fetch.max_redirects = parse("FETCH_MAX_REDIRECTS", value)?;
+        }
+        if let Some(value) = lookup("MAX_WIDTH") {
+            self.limits.max_width = parse("MAX_WIDTH", value)?;
+        }
+        if let Some(value) = lookup("MAX_HEIGHT") {
+            self.limits.max_height = parse("MAX_HEIGHT", value)?;
+        }
+        if let Some(value) = lookup("MAX_IMAGE_SIZE") {
+            self.limits.max_image_size =
+                parse_size(&value).map_err(|e| anyhow!("Invalid value for MAX_IMAGE_SIZE: {e}"))?;
+        }
+        if let Some(value) = lookup("DEFAULT_QUALITY") {
+            self.limits.default_quality = parse("DEFAULT_QUALITY", value)?;
+        }
+        for format in OutputFormat::ALL {
+            let name = format!("DEFAULT_QUALITY_{}", format.name().to_uppercase());
+            if let Some(value) = lookup(&name) {
+                *self.limits.format_quality.get_mut(format) = Some(parse(&name, value)?);
+            }
+        }
+        if let Some(value) = lookup("ALPHA_POLICY") {
+            self.limits.alpha_policy = parse("ALPHA_POLICY", value)?;
+        }
+        if let Some(value) = lookup("MAX_BUNDLE_SIZE") {
+            self.limits.max_bundle_size = parse_size(&value)
+                .map_err(|e| anyhow!("Invalid value for MAX_BUNDLE_SIZE: {e}"))?;
+        }
+        if let Some(value) = lookup("MAX_OUTPUT_PIXELS") {
+            self.limits.max_output_pixels = parse("MAX_OUTPUT_PIXELS", value)?;
+        }
+        if let Some(value) = lookup("MAX_SRC_LENGTH") {
+            self.limits.max_src_length = parse("MAX_SRC_LENGTH", value)?;
+        }
+        if let Some(value) = lookup("MAX_QUERY_LENGTH") {
+            self.limits.max_query_length = parse("MAX_QUERY_LENGTH", value)?;
+        }
+        if let Some(value) = lookup("PROCESSING_MAX_CONCURRENT") {
+            self.processing.max_concurrent = parse("PROCESSING_MAX_CONCURRENT", value)?;
+        }
+        if let Some(value) = lookup("PROCESSING_MAX_WAITING") {
+            self.processing.max_waiting = parse("PROCESSING_MAX_WAITING", value)?;
+        }
+        if let Some(value) = lookup("PROCESSING_SHED_STATUS") {
+            self.processing.shed_status = parse("PROCESSING_SHED_STATUS", value)?;
+        }
+        if let Some(value) = lookup("PROCESSING_SMALL_MAX_CONCURRENT") {
+            self.processing.small_max_concurrent = parse("PROCESSING_SMALL_MAX_CONCURRENT", value)?;
+        }
+        if let Some(value) = lookup("PROCESSING_SMALL_MAX_SOURCE_PIXELS") {
+            self.processing.small_max_source_pixels =
+                parse("PROCESSING_SMALL_MAX_SOURCE_PIXELS", value)?;
+        }
+        if let Some(value) = lookup("PROCESSING_SMALL_MAX_TARGET_PIXELS") {
+            self.processing.small_max_target_pixels =
+                parse("PROCESSING_SMALL_MAX_TARGET_PIXELS", value)?;
+        }
+        if let Some(value) = lookup("PROCESSING_WORKERS") {
+            self.processing.workers = parse("PROCESSING_WORKERS", value)?;
+        }
+        if let Some(value) = lookup("PROCESSING_QUEUE_CAPACITY") {
+            self.processing.queue_capacity = parse("PROCESSING_QUEUE_CAPACITY", value)?;
+        }
+        if let Some(value) = lookup("S3_ALLOWED_BUCKETS") {
+            self.s3.allowed_buckets = value
+                .split(',')
+                .map(str::trim)
+                .filter(|bucket| !bucket.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+        if let Some(value) = lookup("S3_ENDPOINT_URL") {
+            self.s3.endpoint_url = Some(value);
+        }
+        if let Some(value) = lookup("S3_FORCE_PATH_STYLE") {
+            self.s3.force_path_style = parse("S3_FORCE_PATH_STYLE", value)?;
+        }
+        if let Some(value) = lookup("IPFS_GATEWAY") {
+            self.ipfs.gateway = Some(value);
+        }
+        if let Some(value) = lookup("IPFS_FALLBACK_GATEWAY") {
+            self.ipfs.fallback_gateway = Some(value);
+        }
+        if let Some(value) = lookup("CORS_ALLOWED_ORIGINS") {
+            self.cors.allowed_origins = value
+                .split(',')
+                .map(str::trim)
+                .filter(|origin| !origin.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+        if let Some(value) = lookup("API_KEYS") {
+            self.auth.api_keys = Some(value);
+        }
+        if let Some(value) = lookup("READINESS_CANARY_URL") {
+            self.health.canary_url = Some(value);
+        }
+        if let Some(value) = lookup("TLS_CERT_PATH") {
+            self.tls.cert_path = Some(PathBuf::from(value));
+        }
+        if let Some(value) = lookup("TLS_KEY_PATH") {
+            self.tls.key_path = Some(PathBuf::from(value));
+        }
+        if let Some(value) = lookup("TLS_HTTP_PORT") {
+            self.tls.http_port = Some(parse("TLS_HTTP_PORT", value)?);
+        }
+        if let Some(value) = lookup("IMGIX_COMPAT_ENABLED") {
+            self.imgix.enabled = parse("IMGIX_COMPAT_ENABLED", value)?;
+        }
+        if let Some(value) = lookup("IMGIX_STRICT") {
+            self.imgix.strict = parse("IMGIX_STRICT", value)?;
+        }
+        if let Some(value) = lookup("JOB_TTL_SECS") {
+            self.jobs.ttl_secs = parse("JOB_TTL_SECS", value)?;
+        }
+        if let Some(value) = lookup("JOB_MAX_PENDING") {
+            self.jobs.max_pending = parse("JOB_MAX_PENDING", value)?;
+        }
+        if let Some(value) = lookup("AUDIT_DIR") {
+            self.audit.dir = Some(PathBuf::from(value));
+        }
+        if let Some(value) = lookup("AUDIT_RETENTION_DAYS") {
+            self.audit.retention_days = parse("AUDIT_RETENTION_DAYS", value)?;
+        }
+        if let Some(value) = lookup("METRICS_ENABLED") {
+            self.features.metrics = parse("METRICS_ENABLED", value)?;
+        }
+        if let Some(value) = lookup("SERVER_TIMING_ENABLED") {
+            self.features.server_timing = parse("SERVER_TIMING_ENABLED", value)?;
+        }
+        if let Some(value) = lookup("CLIENT_HINTS_ENABLED") {
+            self.features.client_hints = parse("CLIENT_HINTS_ENABLED", value)?;
+        }
+        if let Some(value) = lookup("NEXTJS_COMPAT_ENABLED") {
+            self.features.nextjs_compat = parse("NEXTJS_COMPAT_ENABLED", value)?;
+        }
+        if let Some(value) = lookup("DEBUG_PAGE_ENABLED") {
+            self.features.debug_page = parse("DEBUG_PAGE_ENABLED", value)?;
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.server.bind_address.parse::<IpAddr>().map_err(|_| {
+            anyhow!(
+                "server.bind_address '{}' is not an IP address",
+                self.server.bind_address
+            )
+        })?;
+        if let Some(public_url) = &self.server.public_url {
+            let url = url::Url::parse(public_url)
+                .map_err(|e| anyhow!("server.public_url '{public_url}' is invalid: {e}"))?;
+            if !matches!(url.scheme(), "http" | "https") {
+                bail!("server.public_url '{public_url}' must be an http or https URL");
+            }
+        }
+        if self.cache.dir.as_os_str().is_empty() {
+            bail!("cache.dir must not be empty");
+        }
+        if self.storage.dir.as_os_str().is_empty() {
+            bail!("storage.dir must not be empty");
+        }
+        if http::HeaderName::from_bytes(self.fetch.request_id_header.as_bytes()).is_err() {
+            bail!(
+                "fetch.request_id_header '{}' is not a valid header name",
+                self.fetch.request_id_header
+            );
+        }
+        if let Some(root) = &self.fetch.local_source_root {
+            if !root.is_dir() {
+                bail!(
+                    "fetch.local_source_root {} is not a directory",
+                    root.display()
+                );
+            }
+        }
+        for (name, gateway) in [
+            ("ipfs.gateway", &self.ipfs.gateway),
+            ("ipfs.fallback_gateway", &self.ipfs.fallback_gateway),
+        ] {
+            if let Some(gateway) = gateway {
+                let url = url::Url::parse(gateway)
+                    .map_err(|e| anyhow!("{name} '{gateway}' is invalid: {e}"))?;
+                if !matches!(url.scheme(), "http" | "https") {
+                    bail!("{name} '{gateway}' must be an http or https URL");
+                }
+            }
+        }
+        if self.ipfs.fallback_gateway.is_some() && self.ipfs.gateway.is_none() {
+            bail!("ipfs.fallback_gateway requires ipfs.gateway");
+        }
+        if self.fetch.timeout_secs == 0 {
+            bail!("fetch.timeout_secs must be greater than 0");
+        }
+        if self.fetch.max_per_host == 0 {
+            bail!("fetch.max_per_host must be greater than 0");
+        }
+        for (host, limit) in &self.fetch.hosts {
+            if limit.max_concurrent == Some(0) {
+                bail!("fetch.hosts.\"{host}\".max_concurrent must be greater than 0");
+            }
+        }
+        for (pattern, profile) in &self.origin {
+            let host = pattern.strip_prefix("*.").unwrap_or(pattern);
+            if pattern != "*" && (host.is_empty() || host.contains(['*', '/', ':'])) {
+                bail!(
+                    "origin.\"{pattern}\" must be a host, *.<domain> or *, without scheme or port"
+                );
+            }
+            if profile.timeout_secs == Some(0) {
+                bail!("origin.\"{pattern}\".timeout_secs must be greater than 0");
+            }
+            if profile.max_concurrent == Some(0) {
+                bail!("origin.\"{pattern}\".max_concurrent must be greater than 0");
+            }
+            for (name, value) in &profile.headers {
+                if http::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                    bail!("origin.\"{pattern}\".headers: '{name}' is not a valid header name");
+                }
+                if http::HeaderValue::from_str(value).is_err() {
+                    bail!("origin.\"{pattern}\".headers.{name} is not a valid header value");
+                }
+            }
+        }
+        if self.limits.max_width == 0 {
+            bail!("limits.max_width must be greater than 0");
+        }
+        if self.limits.max_height == 0 {
+            bail!("limits.max_height must be greater than 0");
+        }
+        if self.limits.max_image_size == 0 {
+            bail!("limits.max_image_size must be greater than 0");
+        }
+        if self.limits.max_bundle_size == 0 {
+            bail!("limits.max_bundle_size must be greater than 0");
+        }
+        if self.limits.max_output_pixels == 0 {
+            bail!("limits.max_output_pixels must be greater than 0");
+        }
+        if self.limits.max_src_length == 0 {
+            bail!("limits.max_src_length must be greater than 0");
+        }
+        if self.limits.max_query_length < self.limits.max_src_length {
+            bail!(
+                "limits.max_query_length ({}) must be at least limits.max_src_length ({})",
+                self.limits.max_query_length,
+                self.limits.max_src_length
+            );
+        }
+        if !(1..=100).contains(&self.limits.default_quality) {
+            bail!(
+                "limits.default_quality must be between 1 and 100, got {}",
+                self.limits.default_quality
+            );
+        }
+        for format in OutputFormat::ALL {
+            match self.limits.format_quality.get(format) {
+                Some(quality) if !(1..=100).contains(&quality) => bail!(
+                    "limits.format_quality.{} must be between 1 and 100, got {quality}",
+                    format.name()
+                ),
+                _ => {}
+            }
+        }
+        if self.processing.max_concurrent == 0 {
+            bail!("processing.max_concurrent must be greater than 0");
+        }
+        if self.processing.workers == 0 {
+            bail!("processing.workers must be greater than 0");
+        }
+        if self.processing.queue_capacity == 0 {
+            bail!("processing.queue_capacity must be greater than 0");
+        }
+        if !matches!(self.processing.shed_status, 429 | 503) {
+            bail!(
+                "processing.shed_status must be 429 or 503, got {}",
+                self.processing.shed_status
+            );
+        }
+        if self.jobs.ttl_secs == 0 {
+            bail!("jobs.ttl_secs must be greater than 0");
+        }
+        if self.jobs.max_pending == 0 {
+            bail!("jobs.max_pending must be greater than 0");
+        }
+        if self.audit.retention_days == 0 {
+            bail!("audit.retention_days must be greater than 0");
+        }
+        if let Some(canary_url) = &self.health.canary_url {
+            url::Url::parse(canary_url)
+                .map_err(|e| anyhow!("health.canary_url '{canary_url}' is invalid: {e}"))?;
+        }
+        if self.tls.cert_path.is_some() != self.tls.key_path.is_some() {
+            bail!("tls.cert_path and tls.key_path must be set together");
+        }
+        if self.tls.http_port.is_some() && !self.tls.is_enabled() {
+            bail!("tls.http_port requires tls.cert_path and tls.key_path");
+        }
+        if self.tls.http_port == Some(self.server.port) {
+            bail!("tls.http_port must differ from server.port");
+        }
+        Ok(())
+    }
+
+    /// Copy of the configuration safe to print or log.
+    pub fn redacted(&self) -> Self {
+        let mut config = self.clone();
+        if config.auth.api_keys.is_some() {
+            config.auth.api_keys = Some(REDACTED.to_string());
+        }
+        if config.storage.admin_token.is_some() {
+            config.storage.admin_token = Some(REDACTED.to_string());
+        }
+        // Static headers may carry credentials
+        for profile in config.origin.values_mut() {
+            for value in profile.headers.values_mut() {
+                *value = REDACTED.to_string();
+            }
+        }
+        config
+    }
+
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+
+    /// This configuration with the [`RELOADABLE_SETTINGS`] of `new`, and
+    /// every setting `new` changes, whether taken or not.
+    pub fn reloaded(&self, new: &AppConfig) -> anyhow::Result<(AppConfig, Vec<ConfigChange>)> {
+        let mut merged = serde_json::to_value(self)?;
+        let incoming = serde_json::to_value(new)?;
+        for setting in RELOADABLE_SETTINGS {
+            let pointer = format!("/{}", setting.replace('.', "/"));
+            if let (Some(slot), Some(value)) =
+                (merged.pointer_mut(&pointer), incoming.pointer(&pointer))
+            {
+                *slot = value.clone();
+            }
+        }
+
+        let mut changed = BTreeMap::new();
+        diff_values(
+            String::new(),
+            &serde_json::to_value(self)?,
+            &incoming,
+            &mut changed,
+        );
+        // Values are shown redacted; changes of redacted ones show as such
+        let mut shown = BTreeMap::new();
+        diff_values(
+            String::new(),
+            &serde_json::to_value(self.redacted())?,
+            &serde_json::to_value(new.redacted())?,
+            &mut shown,
+        );
+        let changes = changed
+            .into_keys()
+            .map(|path| {
+                let (old, new) = shown
+                    .remove(&path)
+                    .unwrap_or_else(|| (REDACTED.to_string(), REDACTED.to_string()));
+                ConfigChange {
+                    reloadable: is_reloadable(&path),
+                    path,
+                    old,
+                    new,
+                }
+            })
+            .collect();
+
+        Ok((serde_json::from_value(merged)?, changes))
+    }
+}
+
+/// Settings that may change while serving, as dotted paths, with everything
+/// under them: each is read anew by every request. Others, such as the
+/// port, the cache or the worker threads, need a restart.
+pub const RELOADABLE_SETTINGS: &[&str] = &[
+    "fetch.allowed_hosts",
+    "fetch.max_per_host",
+    "fetch.min_interval_ms",
+    "fetch.hosts",
+    "origin",
+    "s3.allowed_buckets",
+    "limits",
+    "cache.max_age_secs",
+    "server.error_detail",
+];
+
+/// A setting a reload changes, see [`AppConfig::reloaded`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigChange {
+    /// Dotted path, such as `fetch.allowed_hosts`.
+    pub path: String,
+    /// Values as JSON, redacted like [`AppConfig::redacted`].
+    pub old: String,
+    pub new: String,
+    /// Whether the new value is taken, or only after a restart.
+    pub reloadable: bool,
+}
+
+impl std::fmt::Display for ConfigChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {} -> {}", self.path, self.old, self.new)
+    }
+}
+
+fn is_reloadable(path: &str) -> bool {
+    RELOADABLE_SETTINGS.iter().any(|setting| {
+        path.strip_prefix(setting)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+    })
+}
+
+/// Records the paths of the leaves differing between `old` and `new`, with
+/// their values. Tables are compared key by key, anything else as a whole.
+fn diff_values(
+    path: String,
+    old: &serde_json::Value,
+    new: &serde_json::Value,
+    changes: &mut BTreeMap<String, (String, String)>,
+) {
+    use serde_json::Value;
+
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            let keys: std::collections::BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+            for key in keys {
+                let child = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{path}.{key}")
+                };
+                diff_values(
+                    child,
+                    old.get(key).unwrap_or(&Value::Null),
+                    new.get(key).unwrap_or(&Value::Null),
+                    changes,
+                );
+            }
+        }
+        _ if old != new => {
+            changes.insert(path, (old.to_string(), new.to_string()));
+        }
+        _ => {}
+    }
+}
+
+/// Parses a human-friendly byte size such as `20MB`, `512KiB` or `1048576`.
+/// Units are binary: `1KB` is 1024 bytes.
+pub fn parse_size(raw: &str) -> Result<usize, String> {
+    let raw = raw.trim();
+    let split = raw.find(|c: char| !c.is_ascii_digit()).unwrap_or(raw.len());
+    let (number, unit) = raw.split_at(split);
+
+    let number: usize = number
+        .parse()
+        .map_err(|_| format!("'{raw}' is not a size (expected e.g. 20MB)"))?;
+    let multiplier: usize = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1024,
+        "m" | "mb" | "mib" => 1024 * 1024,
+        "g" | "gb" | "gib" => 1024 * 1024 * 1024,
+        other => return Err(format!("unknown size unit '{other}' in '{raw}'")),
+    };
+
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("'{raw}' is too large"))
+}
@@ -0,0 +1,60 @@
+//! Decoding of `data:` URLs passed as `src`, so small inline images go
+//! through the pipeline without any network fetch.
+
+use crate::error::{AppError, AppResult};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+
+/// Media types accepted in `data:` URLs.
+const SUPPORTED_MEDIA_TYPES: &[&str] = &["image/png", "image/jpeg", "image/webp", "image/gif"];
+
+/// Decodes a base64 `data:` URL (`data:image/png;base64,...`), rejecting
+/// non-image media types and payloads larger than `max_size` once decoded.
+pub fn decode(src: &str, max_size: usize) -> AppResult<Vec<u8>> {
+    let rest = src
+        .get(..5)
+        .filter(|scheme| scheme.eq_ignore_ascii_case("data:"))
+        .map(|_| &src[5..])
+        .ok_or(AppError::InvalidImageUrl)?;
+    let (header, payload) = rest.split_once(',').ok_or(AppError::InvalidImageUrl)?;
+
+    let mut parts = header.split(';').map(str::trim);
+    let media_type = parts.next().unwrap_or_default().to_ascii_lowercase();
+    let is_base64 = parts.any(|part| part.eq_ignore_ascii_case("base64"));
+
+    // RFC 2397 defaults to text/plain when the media type is omitted
+    let media_type = if media_type.is_empty() {
+        "text/plain".to_string()
+    } else {
+        media_type
+    };
+    if !SUPPORTED_MEDIA_TYPES.contains(&media_type.as_str()) {
+        return Err(AppError::InvalidImageFormat { format: media_type });
+    }
+    if !is_base64 {
+        return Err(AppError::InvalidImageData);
+    }
+
+    let payload: String = payload
+        .chars()
+        .filter(|c| !c.is_ascii_whitespace())
+        .collect();
+    // Reject oversized payloads before allocating the decoded buffer
+    let estimated_size = payload.len() / 4 * 3;
+    if estimated_size > max_size + 2 {
+        return Err(AppError::SourceTooLargeBytes {
+            limit: max_size,
+            actual: estimated_size,
+        });
+    }
+
+    let data = STANDARD
+        .decode(payload)
+        .map_err(|_| AppError::InvalidImageData)?;
+    if data.len() > max_size {
+        return Err(AppError::SourceTooLargeBytes {
+            limit: max_size,
+            actual: data.len(),
+        });
+    }
+    Ok(data)
+}
@@ -1,46 +1,206 @@
-use actix_web::{error::ResponseError, http::StatusCode, HttpResponse};
+use crate::auth::API_KEY_QUERY_PARAM;
+use crate::image_processor::OutputFormat;
+use http::StatusCode;
 use serde::Serialize;
+use std::borrow::Cow;
+#[cfg(feature = "runtime")]
+use std::time::Duration;
 use strum::EnumIter;
 
+#[cfg(feature = "actix")]
+use {
+    crate::{config::ErrorDetail, logging::RequestContext, AppState},
+    actix_web::{
+        body::{BoxBody, EitherBody, MessageBody},
+        dev::{ServiceRequest, ServiceResponse},
+        error::ResponseError,
+        http::header,
+        middleware::Next,
+        web, HttpMessage, HttpResponse,
+    },
+    std::sync::Arc,
+};
+
 pub type AppResult<T> = Result<T, AppError>;
 
-#[derive(Debug, Clone, EnumIter, thiserror::Error)]
+/// Media type of RFC 7807 bodies.
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+#[derive(Debug, Clone, EnumIter)]
 pub enum AppError {
-    #[error("IMG_001: Invalid image URL - The provided URL is not valid")]
     InvalidImageUrl,
-
-    #[error("IMG_002: Image fetch failed - Unable to download image from {url}")]
-    ImageFetchFailed { url: String },
-
-    #[error("IMG_003: Image processing failed - Error processing image: {reason}")]
-    ImageProcessingFailed { reason: String },
-
-    #[error("IMG_004: Invalid image format - Format '{format}' is not supported")]
-    InvalidImageFormat { format: String },
-
-    #[error("IMG_005: Image too large - Image dimensions exceed maximum allowed size")]
-    ImageTooLarge,
-
-    #[error("IMG_006: Invalid image data - The image data is corrupted or invalid")]
+    UnsupportedUrlScheme {
+        scheme: String,
+        reason: String,
+    },
+    ImageFetchFailed {
+        url: String,
+        reason: String,
+    },
+    SourceNotFound {
+        url: String,
+        status: u16,
+    },
+    SourceAccessDenied {
+        url: String,
+        status: u16,
+    },
+    OriginUnavailable {
+        url: String,
+        failure: FetchFailure,
+        reason: String,
+    },
+    ImageFetchTimeout {
+        url: String,
+        /// `connect`, `response` (waiting for headers), `body`, or `queue`
+        /// (waiting for a slot to the host).
+        phase: String,
+        budget_ms: u64,
+    },
+    ImageProcessingFailed {
+        reason: String,
+    },
+    InvalidImageFormat {
+        format: String,
+    },
+    /// SVG content served by a source whose URL doesn't name an SVG.
+    SvgContent {
+        url: String,
+    },
+    SourceTooLargeBytes {
+        limit: usize,
+        actual: usize,
+    },
+    SourceTooLargePixels {
+        limit: u64,
+        width: u32,
+        height: u32,
+    },
+    /// The variants of a bundle add up to more than the configured size.
+    BundleTooLarge {
+        limit: usize,
+    },
+    /// JPEG output of a transparent source without `bg`, refused by the
+    /// alpha policy.
+    TransparencyLost,
     InvalidImageData,
-
-    #[error("VAL_001: Invalid width - Width must be between 1 and 3840, got {width}")]
-    InvalidWidth { width: u32 },
-
-    #[error("VAL_002: Invalid quality - Quality must be between 1 and 100, got {quality}")]
-    InvalidQuality { quality: u8 },
-
-    #[error("VAL_003: Missing required parameter - {param} is required")]
-    MissingRequiredParameter { param: String },
-
-    #[error("CACHE_001: Cache error - Failed to access cache: {reason}")]
-    CacheError { reason: String },
-
-    #[error("SYS_001: Internal server error - An unexpected error occurred")]
+    ImageNotFound {
+        id: String,
+    },
+    /// No asynchronous job has this id, or it expired.
+    JobNotFound {
+        id: String,
+    },
+    InvalidWidth {
+        width: u32,
+        max: u32,
+    },
+    InvalidHeight {
+        height: u32,
+        max: u32,
+    },
+    InvalidQuality {
+        quality: u32,
+    },
+    MissingRequiredParameter {
+        param: String,
+    },
+    ConflictingParameters {
+        first: String,
+        second: String,
+    },
+    InvalidOption {
+        token: String,
+    },
+    InvalidParameterValue {
+        param: String,
+        value: String,
+        expected: String,
+    },
+    UnsupportedParameter {
+        param: String,
+    },
+    /// A `w`×`h` box over `limits.max_output_pixels`, asked for by `param`:
+    /// `w,h`, or the `tx` step.
+    OutputTooLarge {
+        param: String,
+        width: u32,
+        height: u32,
+        limit: u64,
+    },
+    /// A value longer than its limit: `src`, `srcb64` or the whole `query`.
+    /// The value itself is left out, as it may be arbitrarily long.
+    ParameterTooLong {
+        param: String,
+        length: usize,
+        max: usize,
+    },
+    /// Several parameters failed validation at once.
+    ValidationFailed {
+        errors: Vec<AppError>,
+    },
+    CacheError {
+        reason: String,
+    },
     InternalServerError,
-
-    #[error("SYS_002: Service unavailable - The service is temporarily unavailable")]
     ServiceUnavailable,
+    Overloaded {
+        retry_after_secs: u64,
+        unavailable: bool,
+    },
+    RouteNotFound {
+        path: String,
+    },
+    MethodNotAllowed {
+        method: String,
+        allowed: String,
+    },
+    /// The `Range` header selects no byte of the `length`-byte image.
+    RangeNotSatisfiable {
+        length: usize,
+    },
+    Unauthorized,
+    InvalidAdminToken,
+    /// The source resolves somewhere the service must not read from. The
+    /// response does not say where.
+    BlockedDestination,
+    /// The source's origin is not on an allowlist.
+    OriginNotAllowed,
+    /// Redirect `hop` of a fetch, counting from 1, broke `rule`. The rule
+    /// never names the target, which may be an internal address.
+    RedirectRefused {
+        hop: usize,
+        rule: String,
+    },
+    InvalidSignature,
+}
+
+/// Static description of an error variant. Messages are templates whose
+/// `{field}` placeholders are filled from the variant's fields.
+#[derive(Debug, Clone, Copy)]
+pub struct ErrorMetadata {
+    pub code: &'static str,
+    pub status: StatusCode,
+    pub title: &'static str,
+    pub message_template: &'static str,
+    pub how_to_fix_template: &'static str,
+}
+
+/// Entry of the machine-readable error catalog served by `/errors?format=json`.
+#[derive(Debug, Serialize)]
+pub struct ErrorCatalogEntry {
+    pub code: &'static str,
+    #[serde(rename = "httpStatus")]
+    pub http_status: u16,
+    pub title: &'static str,
+    #[serde(rename = "messageTemplate")]
+    pub message_template: &'static str,
+    /// Template, with the values that depend on the build (the supported
+    /// formats) filled in.
+    #[serde(rename = "howToFix")]
+    pub how_to_fix: String,
+    #[serde(rename = "moreInfo")]
+    pub more_info: String,
 }
 
 #[derive(Serialize)]
@@ -57,114 +217,863 @@ pub struct ProblemDetails {
     how_to_fix: String,
     #[serde(rename = "moreInfo")]
     more_info: String,
+    #[serde(rename = "requestId", skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    /// Each failure of a [`AppError::ValidationFailed`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errors: Option<Vec<InvalidParameter>>,
+}
+
+/// One entry of the `errors` array of a `VAL_009` response.
+#[derive(Debug, Serialize)]
+pub struct InvalidParameter {
+    #[serde(rename = "errorCode")]
+    pub error_code: &'static str,
+    pub detail: String,
+    pub param: String,
+    pub value: String,
+    pub constraint: String,
+}
+
+impl ProblemDetails {
+    /// Sets the URI (path and query) of the request that caused the error,
+    /// cut as by [`truncate_src`].
+    pub fn with_instance(mut self, instance: impl Into<String>) -> Self {
+        let instance = instance.into();
+        self.instance = Some(truncate_src(&instance).into_owned());
+        self
+    }
+
+    pub fn with_request_id(mut self, request_id: impl Into<String>) -> Self {
+        self.request_id = Some(request_id.into());
+        self
+    }
+
+    pub fn to_json(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap_or_default()
+    }
 }
 
 impl AppError {
-    fn error_code(&self) -> &'static str {
-        match self {
-            AppError::InvalidImageUrl => "IMG_001",
-            AppError::ImageFetchFailed { .. } => "IMG_002",
-            AppError::ImageProcessingFailed { .. } => "IMG_003",
-            AppError::InvalidImageFormat { .. } => "IMG_004",
-            AppError::ImageTooLarge => "IMG_005",
-            AppError::InvalidImageData => "IMG_006",
-            AppError::InvalidWidth { .. } => "VAL_001",
-            AppError::InvalidQuality { .. } => "VAL_002",
-            AppError::MissingRequiredParameter { .. } => "VAL_003",
-            AppError::CacheError { .. } => "CACHE_001",
-            AppError::InternalServerError => "SYS_001",
-            AppError::ServiceUnavailable => "SYS_002",
+    pub fn metadata(&self) -> ErrorMetadata {
+        const BAD_REQUEST: &str = "Bad Request";
+        const PROCESSING_ERROR: &str = "Processing Error";
+
+        let (code, status, title, message_template, how_to_fix_template) = match self {
+            AppError::InvalidImageUrl => (
+                "IMG_001",
+                StatusCode::BAD_REQUEST,
+                BAD_REQUEST,
+                "Invalid image URL - The provided URL is not valid",
+                "Provide a valid URL starting with http:// or https://",
+            ),
+            AppError::UnsupportedUrlScheme { .. } => (
+                "IMG_001",
+                StatusCode::BAD_REQUEST,
+                BAD_REQUEST,
+                "Invalid image URL - '{scheme}:' URLs cannot be fetched: {reason}",
+                "Provide an http:// or https:// URL, a base64 data: URL, or upload the image with POST",
+            ),
+            AppError::ImageFetchFailed { .. } => (
+                "IMG_002",
+                StatusCode::UNPROCESSABLE_ENTITY,
+                PROCESSING_ERROR,
+                "Image fetch failed - Unable to download image from {url}: {reason}",
+                "Ensure the image URL is accessible and the server is responding",
+            ),
+            AppError::SourceNotFound { .. } => (
+                "IMG_008",
+                StatusCode::NOT_FOUND,
+                "Not Found",
+                "Source image not found - The origin responded {status} for {url}",
+                "Check the src URL for typos; the origin does not have an image at this address",
+            ),
+            AppError::SourceAccessDenied { .. } => (
+                "IMG_009",
+                StatusCode::BAD_GATEWAY,
+                "Bad Gateway",
+                "Source access denied - The origin responded {status} for {url}",
+                "Make the image publicly readable, or allow the optimizer's requests (User-Agent, IP) at the origin",
+            ),
+            AppError::OriginUnavailable { failure, .. } => (
+                "IMG_010",
+                StatusCode::BAD_GATEWAY,
+                "Bad Gateway",
+                "Origin unavailable - Unable to download image from {url} ({failure} error): {reason}",
+                match failure {
+                    FetchFailure::Dns => "Check the host name of the image URL; it does not resolve",
+                    FetchFailure::Tls => "Check the origin's TLS certificate: it must be unexpired, issued for the requested host and signed by a trusted authority",
+                    _ => "The origin is failing or unreachable; check its health and retry later",
+                },
+            ),
+            AppError::ImageFetchTimeout { .. } => (
+                "IMG_011",
+                StatusCode::GATEWAY_TIMEOUT,
+                "Gateway Timeout",
+                "Image fetch timed out - {url} exceeded the {budget_ms} ms budget during {phase}",
+                "Check the origin's latency, or increase FETCH_TIMEOUT if it is legitimately slow",
+            ),
+            AppError::ImageProcessingFailed { .. } => (
+                "IMG_003",
+                StatusCode::UNPROCESSABLE_ENTITY,
+                PROCESSING_ERROR,
+                "Image processing failed - Error processing image: {reason}",
+                "Try a different image or check if the image file is corrupted",
+            ),
+            AppError::InvalidImageFormat { .. } => (
+                "IMG_004",
+                StatusCode::BAD_REQUEST,
+                BAD_REQUEST,
+                "Invalid image format - Format '{format}' is not supported",
+                "Use one of the supported formats: {supported}. Got '{format}'",
+            ),
+            AppError::SvgContent { .. } => (
+                "IMG_004",
+                StatusCode::BAD_REQUEST,
+                BAD_REQUEST,
+                "Invalid image format - {url} serves an SVG image, which is not processed",
+                "Link SVG images directly, or serve them from a path ending in .svg so requests for them redirect to the source",
+            ),
+            AppError::SourceTooLargeBytes { .. } => (
+                "IMG_005",
+                StatusCode::UNPROCESSABLE_ENTITY,
+                PROCESSING_ERROR,
+                "Source image too large - At least {actual} bytes, over the {limit} byte limit",
+                "Compress the source image below {limit} bytes, or raise MAX_IMAGE_SIZE",
+            ),
+            AppError::SourceTooLargePixels { .. } => (
+                "IMG_012",
+                StatusCode::UNPROCESSABLE_ENTITY,
+                PROCESSING_ERROR,
+                "Source image too large - {width}x{height} pixels, over the {limit} pixel limit",
+                "Downscale the source image to at most {limit} pixels before serving it through the optimizer",
+            ),
+            AppError::BundleTooLarge { .. } => (
+                "IMG_013",
+                StatusCode::UNPROCESSABLE_ENTITY,
+                PROCESSING_ERROR,
+                "Bundle too large - The variants add up to more than the {limit} byte limit",
+                "Request fewer or smaller variants, or raise MAX_BUNDLE_SIZE",
+            ),
+            AppError::TransparencyLost => (
+                "IMG_014",
+                StatusCode::BAD_REQUEST,
+                BAD_REQUEST,
+                "Transparency would be lost - The image has transparent pixels, which JPEG output cannot keep",
+                "Use f=webp or f=png to keep the transparency, or pass bg to choose the color it is flattened onto",
+            ),
+            AppError::InvalidImageData => (
+                "IMG_006",
+                StatusCode::UNPROCESSABLE_ENTITY,
+                PROCESSING_ERROR,
+                "Invalid image data - The image data is corrupted or invalid",
+                "Ensure the image file is not corrupted and is a valid image format",
+            ),
+            AppError::ImageNotFound { .. } => (
+                "IMG_007",
+                StatusCode::NOT_FOUND,
+                "Not Found",
+                "Image not found - No stored image with id '{id}'",
+                "Check the image id, or ingest the image before requesting it",
+            ),
+            AppError::JobNotFound { .. } => (
+                "JOB_001",
+                StatusCode::NOT_FOUND,
+                "Not Found",
+                "Job not found - No job with id '{id}', or it expired",
+                "Check the job id, and fetch the result of a job within JOB_TTL_SECS of its completion",
+            ),
+            AppError::InvalidWidth { .. } => (
+                "VAL_001",
+                StatusCode::BAD_REQUEST,
+                BAD_REQUEST,
+                "Invalid width - Width must be between 1 and {max}, got {width}",
+                "Provide a width value between 1 and {max}",
+            ),
+            AppError::InvalidQuality { .. } => (
+                "VAL_002",
+                StatusCode::BAD_REQUEST,
+                BAD_REQUEST,
+                "Invalid quality - Quality must be between 1 and 100, got {quality}",
+                "Provide a quality value between 1 and 100",
+            ),
+            AppError::MissingRequiredParameter { .. } => (
+                "VAL_003",
+                StatusCode::BAD_REQUEST,
+                BAD_REQUEST,
+                "Missing required parameter - {param} is required",
+                "Include the '{param}' parameter in your request",
+            ),
+            AppError::ConflictingParameters { .. } => (
+                "VAL_004",
+                StatusCode::BAD_REQUEST,
+                BAD_REQUEST,
+                "Conflicting parameters - {first} and {second} cannot be used together",
+                "Send either '{first}' or '{second}', not both",
+            ),
+            AppError::InvalidOption { .. } => (
+                "VAL_005",
+                StatusCode::BAD_REQUEST,
+                BAD_REQUEST,
+                "Invalid transformation option - '{token}' is unknown, malformed or repeated",
+                "Use comma-separated w_<width>, q_<quality> and f_<format> options, each at most once, or '-' for none",
+            ),
+            AppError::InvalidHeight { .. } => (
+                "VAL_006",
+                StatusCode::BAD_REQUEST,
+                BAD_REQUEST,
+                "Invalid height - Height must be between 1 and {max}, got {height}",
+                "Provide a height value between 1 and {max}",
+            ),
+            AppError::InvalidParameterValue { .. } => (
+                "VAL_007",
+                StatusCode::BAD_REQUEST,
+                BAD_REQUEST,
+                "Invalid parameter value - '{value}' is not a valid {param}",
+                "Set '{param}' to {expected}",
+            ),
+            AppError::UnsupportedParameter { .. } => (
+                "VAL_008",
+                StatusCode::BAD_REQUEST,
+                BAD_REQUEST,
+                "Unsupported parameter - '{param}' is not supported",
+                "Remove '{param}' from the request, or turn off strict imgix compatibility to have it ignored",
+            ),
+            AppError::OutputTooLarge { .. } => (
+                "VAL_010",
+                StatusCode::BAD_REQUEST,
+                BAD_REQUEST,
+                "Output too large - {param} asks for {width}x{height}, {pixels} pixels, over the {limit} pixel limit",
+                "Ask for a smaller box, with width times height at most {limit} pixels",
+            ),
+            AppError::ParameterTooLong { .. } => (
+                "VAL_011",
+                StatusCode::BAD_REQUEST,
+                BAD_REQUEST,
+                "Parameter too long - {param} is {length} bytes, over the {max} byte limit",
+                "Shorten {param} to at most {max} bytes, e.g. by hosting the image rather than inlining it",
+            ),
+            AppError::ValidationFailed { .. } => (
+                "VAL_009",
+                StatusCode::BAD_REQUEST,
+                BAD_REQUEST,
+                "Invalid parameters - {count} parameters are invalid: {params}",
+                "Fix every parameter listed in 'errors'",
+            ),
+            AppError::CacheError { .. } => (
+                "CACHE_001",
+                StatusCode::UNPROCESSABLE_ENTITY,
+                PROCESSING_ERROR,
+                "Cache error - Failed to access cache: {reason}",
+                "Try again later or contact support if the issue persists",
+            ),
+            AppError::InternalServerError => (
+                "SYS_001",
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Internal Server Error",
+                "Internal server error - An unexpected error occurred",
+                "Try again later. If the problem persists, contact support",
+            ),
+            AppError::ServiceUnavailable => (
+                "SYS_002",
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Service Unavailable",
+                "Service unavailable - The service is temporarily unavailable",
+                "The service is temporarily down. Please try again in a few minutes",
+            ),
+            AppError::Overloaded { unavailable, .. } => (
+                "SYS_003",
+                if *unavailable {
+                    StatusCode::SERVICE_UNAVAILABLE
+                } else {
+                    StatusCode::TOO_MANY_REQUESTS
+                },
+                "Server Overloaded",
+                "Server overloaded - Too many images are waiting to be processed",
+                "Retry after {retry_after_secs} seconds, as the Retry-After header indicates",
+            ),
+            AppError::RouteNotFound { .. } => (
+                "SYS_404",
+                StatusCode::NOT_FOUND,
+                "Not Found",
+                "Not found - No endpoint matches {path}",
+                "Check the path against the documented endpoints, e.g. /img-optimizer/v1/img",
+            ),
+            AppError::MethodNotAllowed { .. } => (
+                "SYS_405",
+                StatusCode::METHOD_NOT_ALLOWED,
+                "Method Not Allowed",
+                "Method not allowed - {method} is not supported by this endpoint",
+                "Use one of the methods listed in the Allow header: {allowed}",
+            ),
+            AppError::RangeNotSatisfiable { .. } => (
+                "SYS_416",
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                "Range Not Satisfiable",
+                "Range not satisfiable - The requested range is outside the {length} byte image",
+                "Request bytes between 0 and {length} minus one, or omit the Range header",
+            ),
+            AppError::Unauthorized => (
+                "SEC_001",
+                StatusCode::UNAUTHORIZED,
+                "Unauthorized",
+                "Unauthorized - A valid API key is required",
+                "Send your API key in the X-Api-Key header or the 'key' query parameter",
+            ),
+            AppError::InvalidAdminToken => (
+                "SEC_002",
+                StatusCode::UNAUTHORIZED,
+                "Unauthorized",
+                "Unauthorized - A valid admin token is required",
+                "Send the admin token as 'Authorization: Bearer <token>'; ingestion is disabled unless an admin token is configured",
+            ),
+            AppError::BlockedDestination => (
+                "SEC_003",
+                StatusCode::FORBIDDEN,
+                "Forbidden",
+                "Destination blocked - The source cannot be fetched by this service",
+                "Use a source the service is allowed to read, such as a public URL",
+            ),
+            AppError::OriginNotAllowed => (
+                "SEC_004",
+                StatusCode::FORBIDDEN,
+                "Forbidden",
+                "Origin not allowed - The source's origin is not on the allowlist",
+                "Use a source from an allowed origin, or ask the operator to allow it",
+            ),
+            AppError::RedirectRefused { .. } => (
+                "SEC_006",
+                StatusCode::FORBIDDEN,
+                "Forbidden",
+                "Redirect refused - Redirect {hop} of the source {rule}",
+                "Use the final URL of the image, or ask the operator to allow where it redirects to",
+            ),
+            AppError::InvalidSignature => (
+                "SEC_005",
+                StatusCode::UNAUTHORIZED,
+                "Unauthorized",
+                "Invalid signature - The request signature is missing or does not match",
+                "Sign the exact path and query sent with the shared secret",
+            ),
+        };
+
+        ErrorMetadata {
+            code,
+            status,
+            title,
+            message_template,
+            how_to_fix_template,
         }
     }
 
-    fn how_to_fix(&self) -> String {
+    /// Values substituted into the metadata templates.
+    fn template_fields(&self) -> Vec<(&'static str, String)> {
         match self {
-            AppError::InvalidImageUrl => {
-                "Provide a valid URL starting with http:// or https://".to_string()
+            AppError::UnsupportedUrlScheme { scheme, reason } => {
+                vec![("scheme", scheme.clone()), ("reason", reason.clone())]
             }
-            AppError::ImageFetchFailed { .. } => {
-                "Ensure the image URL is accessible and the server is responding".to_string()
+            AppError::ImageFetchFailed { url, reason } => {
+                vec![("url", url.clone()), ("reason", reason.clone())]
             }
-            AppError::ImageProcessingFailed { .. } => {
-                "Try a different image or check if the image file is corrupted".to_string()
+            AppError::OriginUnavailable {
+                url,
+                failure,
+                reason,
+            } => vec![
+                ("url", url.clone()),
+                ("failure", failure.as_str().to_string()),
+                ("reason", reason.clone()),
+            ],
+            AppError::SourceNotFound { url, status }
+            | AppError::SourceAccessDenied { url, status } => {
+                vec![("url", url.clone()), ("status", status.to_string())]
             }
-            AppError::InvalidImageFormat { format } => {
-                format!("Use one of the supported formats: jpeg, jpg, png, webp. Got '{format}'")
+            AppError::ImageFetchTimeout {
+                url,
+                phase,
+                budget_ms,
+            } => vec![
+                ("url", url.clone()),
+                ("phase", phase.clone()),
+                ("budget_ms", budget_ms.to_string()),
+            ],
+            AppError::ImageProcessingFailed { reason } | AppError::CacheError { reason } => {
+                vec![("reason", reason.clone())]
             }
-            AppError::ImageTooLarge => {
-                "Reduce the image dimensions or use a smaller source image".to_string()
+            AppError::InvalidImageFormat { format } => vec![
+                ("format", format.clone()),
+                ("supported", OutputFormat::supported_values()),
+            ],
+            AppError::SvgContent { url } => vec![("url", url.clone())],
+            AppError::InvalidWidth { width, max } => {
+                vec![("width", width.to_string()), ("max", max.to_string())]
             }
-            AppError::InvalidImageData => {
-                "Ensure the image file is not corrupted and is a valid image format".to_string()
+            AppError::InvalidHeight { height, max } => {
+                vec![("height", height.to_string()), ("max", max.to_string())]
             }
-            AppError::InvalidWidth { .. } => "Provide a width value between 1 and 3840".to_string(),
-            AppError::InvalidQuality { .. } => {
-                "Provide a quality value between 1 and 100".to_string()
+            AppError::InvalidParameterValue {
+                param,
+                value,
+                expected,
+            } => vec![
+                ("param", param.clone()),
+                ("value", value.clone()),
+                ("expected", expected.clone()),
+            ],
+            AppError::UnsupportedParameter { param } => vec![("param", param.clone())],
+            AppError::OutputTooLarge {
+                param,
+                width,
+                height,
+                limit,
+            } => vec![
+                ("param", param.clone()),
+                ("width", width.to_string()),
+                ("height", height.to_string()),
+                (
+                    "pixels",
+                    (u64::from(*width) * u64::from(*height)).to_string(),
+                ),
+                ("limit", limit.to_string()),
+            ],
+            AppError::ParameterTooLong { param, length, max } => vec![
+                ("param", param.clone()),
+                ("length", length.to_string()),
+                ("max", max.to_string()),
+            ],
+            AppError::ValidationFailed { errors } => vec![
+                ("count", errors.len().to_string()),
+                (
+                    "params",
+                    errors
+                        .iter()
+                        .filter_map(|err| err.invalid_parameter().map(|p| p.param))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                ),
+            ],
+            AppError::InvalidQuality { quality } => vec![("quality", quality.to_string())],
+            AppError::MissingRequiredParameter { param } => vec![("param", param.clone())],
+            AppError::InvalidOption { token } => vec![("token", token.clone())],
+            AppError::ConflictingParameters { first, second } => {
+                vec![("first", first.clone()), ("second", second.clone())]
             }
-            AppError::MissingRequiredParameter { param } => {
-                format!("Include the '{param}' parameter in your request")
+            AppError::ImageNotFound { id } | AppError::JobNotFound { id } => {
+                vec![("id", id.clone())]
             }
-            AppError::CacheError { .. } => {
-                "Try again later or contact support if the issue persists".to_string()
+            AppError::SourceTooLargeBytes { limit, actual } => {
+                vec![("limit", limit.to_string()), ("actual", actual.to_string())]
             }
-            AppError::InternalServerError => {
-                "Try again later. If the problem persists, contact support".to_string()
+            AppError::SourceTooLargePixels {
+                limit,
+                width,
+                height,
+            } => vec![
+                ("limit", limit.to_string()),
+                ("width", width.to_string()),
+                ("height", height.to_string()),
+            ],
+            AppError::BundleTooLarge { limit } => vec![("limit", limit.to_string())],
+            AppError::Overloaded {
+                retry_after_secs, ..
+            } => vec![("retry_after_secs", retry_after_secs.to_string())],
+            AppError::RouteNotFound { path } => vec![("path", path.clone())],
+            AppError::RedirectRefused { hop, rule } => {
+                vec![("hop", hop.to_string()), ("rule", rule.clone())]
             }
-            AppError::ServiceUnavailable => {
-                "The service is temporarily down. Please try again in a few minutes".to_string()
+            AppError::MethodNotAllowed { method, allowed } => {
+                vec![("method", method.clone()), ("allowed", allowed.clone())]
             }
+            AppError::RangeNotSatisfiable { length } => vec![("length", length.to_string())],
+            AppError::InvalidImageUrl
+            | AppError::InvalidImageData
+            | AppError::TransparencyLost
+            | AppError::InternalServerError
+            | AppError::ServiceUnavailable
+            | AppError::Unauthorized
+            | AppError::InvalidAdminToken
+            | AppError::BlockedDestination
+            | AppError::OriginNotAllowed
+            | AppError::InvalidSignature => Vec::new(),
         }
     }
 
+    fn render(&self, template: &str) -> String {
+        self.template_fields()
+            .into_iter()
+            .fold(template.to_string(), |rendered, (name, value)| {
+                rendered.replace(&format!("{{{name}}}"), &value)
+            })
+    }
+
+    /// The message with every value replaced by `reference`, for responses
+    /// that must not reveal URLs, parameters or upstream causes.
+    fn minimal_detail(&self, reference: &str) -> String {
+        let metadata = self.metadata();
+        let message = self.template_fields().into_iter().fold(
+            metadata.message_template.to_string(),
+            |rendered, (name, _)| {
+                rendered.replace(&format!("{{{name}}}"), &format!("[ref {reference}]"))
+            },
+        );
+        format!("{}: {message}", metadata.code)
+    }
+
+    pub fn error_code(&self) -> &'static str {
+        self.metadata().code
+    }
+
+    fn how_to_fix(&self) -> String {
+        self.render(self.metadata().how_to_fix_template)
+    }
+
     pub fn list_all_errors() -> Vec<String> {
         use strum::IntoEnumIterator;
         AppError::iter()
-            .map(|e| format!("{}: {}", e.error_code(), e))
+            .map(|e| {
+                let metadata = e.metadata();
+                format!("{}: {}", metadata.code, metadata.message_template)
+            })
+            .collect()
+    }
+
+    pub fn catalog() -> Vec<ErrorCatalogEntry> {
+        use strum::IntoEnumIterator;
+        AppError::iter()
+            .map(|e| {
+                let metadata = e.metadata();
+                ErrorCatalogEntry {
+                    code: metadata.code,
+                    http_status: metadata.status.as_u16(),
+                    title: metadata.title,
+                    message_template: metadata.message_template,
+                    how_to_fix: metadata
+                        .how_to_fix_template
+                        .replace("{supported}", &OutputFormat::supported_values()),
+                    more_info: more_info_url(metadata.code),
+                }
+            })
             .collect()
     }
 
+    /// Combines the failures found while validating a request's parameters:
+    /// none is `Ok`, a single one is returned as is.
+    pub fn from_validation(mut errors: Vec<AppError>) -> AppResult<()> {
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.remove(0)),
+            _ => Err(AppError::ValidationFailed { errors }),
+        }
+    }
+
+    /// The parameter, value and constraint a validation error is about.
+    fn invalid_parameter(&self) -> Option<InvalidParameter> {
+        let (param, value, constraint) = match self {
+            AppError::InvalidWidth { width, max } => (
+                "w".to_string(),
+                width.to_string(),
+                format!("between 1 and {max}"),
+            ),
+            AppError::InvalidHeight { height, max } => (
+                "h".to_string(),
+                height.to_string(),
+                format!("between 1 and {max}"),
+            ),
+            AppError::InvalidQuality { quality } => (
+                "q".to_string(),
+                quality.to_string(),
+                "between 1 and 100".to_string(),
+            ),
+            AppError::InvalidParameterValue {
+                param,
+                value,
+                expected,
+            } => (param.clone(), value.clone(), expected.clone()),
+            AppError::InvalidImageFormat { format } => (
+                "f".to_string(),
+                format.clone(),
+                format!("one of {}", OutputFormat::supported_values()),
+            ),
+            AppError::OutputTooLarge {
+                param,
+                width,
+                height,
+                limit,
+            } => (
+                param.clone(),
+                format!("{width}x{height}"),
+                format!("at most {limit} pixels"),
+            ),
+            AppError::ParameterTooLong { param, length, max } => (
+                param.clone(),
+                format!("{length} bytes"),
+                format!("at most {max} bytes"),
+            ),
+            _ => return None,
+        };
+        Some(InvalidParameter {
+            error_code: self.error_code(),
+            detail: self.to_string(),
+            param,
+            value,
+            constraint,
+        })
+    }
+
     pub fn to_response(&self) -> ProblemDetails {
         ProblemDetails {
-            error_type: format!(
-                "https://github.com/fgribreau/plasmic-img-optimizer/errors/{}",
-                self.error_code()
-            ),
-            title: match self {
-                AppError::InvalidImageUrl
-                | AppError::InvalidImageFormat { .. }
-                | AppError::InvalidWidth { .. }
-                | AppError::InvalidQuality { .. }
-                | AppError::MissingRequiredParameter { .. } => "Bad Request",
-                AppError::ImageFetchFailed { .. }
-                | AppError::ImageProcessingFailed { .. }
-                | AppError::ImageTooLarge
-                | AppError::InvalidImageData
-                | AppError::CacheError { .. } => "Processing Error",
-                AppError::InternalServerError => "Internal Server Error",
-                AppError::ServiceUnavailable => "Service Unavailable",
-            }
-            .to_string(),
-            status: self.status_code().as_u16(),
+            error_type: type_url(self.error_code()),
+            title: self.metadata().title.to_string(),
+            status: self.metadata().status.as_u16(),
             detail: self.to_string(),
             instance: None,
             error_code: self.error_code().to_string(),
             how_to_fix: self.how_to_fix(),
-            more_info: format!(
-                "https://github.com/fgribreau/plasmic-img-optimizer#error-{}",
-                self.error_code().to_lowercase()
+            more_info: more_info_url(self.error_code()),
+            request_id: None,
+            errors: match self {
+                AppError::ValidationFailed { errors } => Some(
+                    errors
+                        .iter()
+                        .filter_map(AppError::invalid_parameter)
+                        .collect(),
+                ),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl AppError {
+    /// Like [`AppError::to_response`], with `detail` limited to
+    /// [`AppError::minimal_detail`].
+    pub fn to_minimal_response(&self, reference: &str) -> ProblemDetails {
+        let mut details = self.to_response();
+        details.detail = self.minimal_detail(reference);
+        if let AppError::ValidationFailed { errors } = self {
+            let entries = errors.iter().filter_map(|err| {
+                let mut entry = err.invalid_parameter()?;
+                entry.detail = err.minimal_detail(reference);
+                entry.value = format!("[ref {reference}]");
+                Some(entry)
+            });
+            details.errors = Some(entries.collect());
+        }
+        details
+    }
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let metadata = self.metadata();
+        write!(
+            f,
+            "{}: {}",
+            metadata.code,
+            self.render(metadata.message_template)
+        )
+    }
+}
+
+impl std::error::Error for AppError {}
+
+fn type_url(code: &str) -> String {
+    format!("https://github.com/fgribreau/plasmic-img-optimizer/errors/{code}")
+}
+
+fn more_info_url(code: &str) -> String {
+    format!(
+        "https://github.com/fgribreau/plasmic-img-optimizer#error-{}",
+        code.to_lowercase()
+    )
+}
+
+#[cfg(feature = "runtime")]
+impl AppError {
+    /// Maps a failed request to `url`, made with a timeout of `budget`:
+    /// timeouts are gateway timeouts, any other failure makes the origin
+    /// unavailable, with its category and underlying cause. Credentials in
+    /// `url` are removed.
+    pub fn from_fetch_error(url: &str, err: &reqwest::Error, budget: Duration) -> Self {
+        let url = strip_userinfo(url);
+        if err.is_timeout() {
+            let phase = if err.is_connect() {
+                "connect"
+            } else if err.is_body() || err.is_decode() {
+                "body"
+            } else {
+                "response"
+            };
+            return AppError::ImageFetchTimeout {
+                url,
+                phase: phase.to_string(),
+                budget_ms: budget.as_millis() as u64,
+            };
+        }
+        AppError::OriginUnavailable {
+            failure: FetchFailure::of(err),
+            reason: fetch_error_cause(err),
+            url,
+        }
+    }
+
+    /// Maps an unsuccessful origin status: a missing source is a 404 of our
+    /// own, while denied access and origin failures are gateway errors.
+    pub fn from_origin_status(url: &str, status: reqwest::StatusCode) -> Self {
+        let url = strip_userinfo(url);
+        match status.as_u16() {
+            code @ (404 | 410) => AppError::SourceNotFound { url, status: code },
+            code @ (401 | 403) => AppError::SourceAccessDenied { url, status: code },
+            code if code >= 500 => AppError::OriginUnavailable {
+                url,
+                failure: FetchFailure::Status,
+                reason: format!("the origin responded {status}"),
+            },
+            _ => AppError::ImageFetchFailed {
+                url,
+                reason: format!("the origin responded {status}"),
+            },
+        }
+    }
+
+    /// Whether a fetch failing this way may succeed if tried again: origin
+    /// server errors, dropped connections and timeouts, but not failing
+    /// DNS, TLS or decoding, nor a wait for a fetch slot.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            AppError::OriginUnavailable { failure, .. } => !matches!(
+                failure,
+                FetchFailure::Dns | FetchFailure::Tls | FetchFailure::Decode
             ),
+            AppError::ImageFetchTimeout { phase, .. } => phase != "queue",
+            _ => false,
+        }
+    }
+}
+
+/// Category of an origin fetch failure, reported in `IMG_010` errors.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FetchFailure {
+    #[default]
+    Request,
+    Dns,
+    Connect,
+    Tls,
+    Body,
+    Decode,
+    /// The origin answered with a server error status.
+    Status,
+}
+
+impl FetchFailure {
+    #[cfg(feature = "runtime")]
+    fn of(err: &reqwest::Error) -> Self {
+        let cause = fetch_error_cause(err).to_lowercase();
+        if cause.contains("dns error") || cause.contains("failed to lookup address") {
+            FetchFailure::Dns
+        } else if ["certificate", "tls", "ssl", "handshake"]
+            .iter()
+            .any(|needle| cause.contains(needle))
+        {
+            FetchFailure::Tls
+        } else if err.is_connect() {
+            FetchFailure::Connect
+        } else if err.is_body() {
+            FetchFailure::Body
+        } else if err.is_decode() {
+            FetchFailure::Decode
+        } else {
+            FetchFailure::Request
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FetchFailure::Request => "request",
+            FetchFailure::Dns => "dns",
+            FetchFailure::Connect => "connect",
+            FetchFailure::Tls => "tls",
+            FetchFailure::Body => "body",
+            FetchFailure::Decode => "decode",
+            FetchFailure::Status => "http",
+        }
+    }
+}
+
+#[cfg(feature = "runtime")]
+/// Underlying causes of a reqwest error, without reqwest's own message,
+/// which embeds the URL and so possibly its credentials.
+fn fetch_error_cause(err: &reqwest::Error) -> String {
+    let mut causes = Vec::new();
+    let mut source = std::error::Error::source(err);
+    while let Some(cause) = source {
+        let message = cause.to_string();
+        // Wrappers often repeat the message of their source
+        if !causes
+            .last()
+            .is_some_and(|last: &String| last.contains(&message))
+        {
+            causes.push(message);
         }
+        source = cause.source();
+    }
+    if causes.is_empty() {
+        "the request failed".to_string()
+    } else {
+        causes.join(": ")
     }
 }
 
-impl From<reqwest::Error> for AppError {
-    fn from(err: reqwest::Error) -> Self {
-        AppError::ImageFetchFailed {
-            url: err.url().map(|u| u.to_string()).unwrap_or_default(),
+/// Bytes of a source URL shown in logs and error details.
+pub const DISPLAY_SRC_LEN: usize = 256;
+
+/// `url` without its `user:password@` part, safe to show and log, see
+/// [`truncate_src`].
+pub fn strip_userinfo(url: &str) -> String {
+    match url::Url::parse(url) {
+        Ok(mut parsed) if !parsed.username().is_empty() || parsed.password().is_some() => {
+            let _ = parsed.set_username("");
+            let _ = parsed.set_password(None);
+            truncate_src(parsed.as_str()).into_owned()
         }
+        _ => truncate_src(url).into_owned(),
     }
 }
 
+/// `instance` of a request's ProblemDetails: its path and query, without
+/// the `key` parameter so an API key passed in the URL is never sent back.
+pub fn problem_instance(path: &str, query: Option<&str>) -> String {
+    let query = query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| {
+            url::form_urlencoded::parse(pair.as_bytes())
+                .next()
+                .is_some_and(|(name, _)| name != API_KEY_QUERY_PARAM)
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    if query.is_empty() {
+        path.to_string()
+    } else {
+        format!("{path}?{query}")
+    }
+}
+
+/// `src` cut to its first [`DISPLAY_SRC_LEN`] bytes, on a character
+/// boundary, and its length noted, so no value logged or sent back can be
+/// arbitrarily long. Only for display: cache keys hash the whole source.
+pub fn truncate_src(src: &str) -> Cow<'_, str> {
+    if src.len() <= DISPLAY_SRC_LEN {
+        return Cow::Borrowed(src);
+    }
+    let end = (0..=DISPLAY_SRC_LEN)
+        .rev()
+        .find(|&end| src.is_char_boundary(end))
+        .unwrap_or(0);
+    Cow::Owned(format!("{}... ({} bytes)", &src[..end], src.len()))
+}
+
 impl From<image::ImageError> for AppError {
     fn from(err: image::ImageError) -> Self {
         AppError::ImageProcessingFailed {
@@ -187,57 +1096,117 @@ impl From<anyhow::Error> for AppError {
     }
 }
 
+#[cfg(feature = "actix")]
 impl ResponseError for AppError {
     fn error_response(&self) -> HttpResponse {
-        let status_code = self.status_code();
-        let problem_details = ProblemDetails {
-            error_type: format!(
-                "https://github.com/fgribreau/plasmic-img-optimizer/errors/{}",
-                self.error_code()
-            ),
-            title: match self {
-                AppError::InvalidImageUrl
-                | AppError::InvalidImageFormat { .. }
-                | AppError::InvalidWidth { .. }
-                | AppError::InvalidQuality { .. }
-                | AppError::MissingRequiredParameter { .. } => "Bad Request",
-                AppError::ImageFetchFailed { .. }
-                | AppError::ImageProcessingFailed { .. }
-                | AppError::ImageTooLarge
-                | AppError::InvalidImageData
-                | AppError::CacheError { .. } => "Processing Error",
-                AppError::InternalServerError => "Internal Server Error",
-                AppError::ServiceUnavailable => "Service Unavailable",
-            }
-            .to_string(),
-            status: status_code.as_u16(),
-            detail: self.to_string(),
-            instance: None,
-            error_code: self.error_code().to_string(),
-            how_to_fix: self.how_to_fix(),
-            more_info: format!(
-                "https://github.com/fgribreau/plasmic-img-optimizer#error-{}",
-                self.error_code().to_lowercase()
-            ),
-        };
+        let mut response = HttpResponse::build(self.status_code());
+        response.content_type(PROBLEM_JSON);
+        match self {
+            AppError::Overloaded {
+                retry_after_secs, ..
+            } => {
+                response.insert_header((header::RETRY_AFTER, retry_after_secs.to_string()));
+            }
+            AppError::MethodNotAllowed { allowed, .. } => {
+                response.insert_header((header::ALLOW, allowed.as_str()));
+            }
+            AppError::RangeNotSatisfiable { length } => {
+                response.insert_header((header::CONTENT_RANGE, format!("bytes */{length}")));
+            }
+            _ => {}
+        }
+        response.body(self.to_response().to_json())
+    }
 
-        HttpResponse::build(status_code).json(problem_details)
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        // actix-web is on `http` 0.2, the metadata on 1.x
+        actix_web::http::StatusCode::from_u16(self.metadata().status.as_u16())
+            .unwrap_or(actix_web::http::StatusCode::INTERNAL_SERVER_ERROR)
     }
+}
 
-    fn status_code(&self) -> StatusCode {
-        match self {
-            AppError::InvalidImageUrl
-            | AppError::InvalidImageFormat { .. }
-            | AppError::InvalidWidth { .. }
-            | AppError::InvalidQuality { .. }
-            | AppError::MissingRequiredParameter { .. } => StatusCode::BAD_REQUEST,
-            AppError::ImageFetchFailed { .. }
-            | AppError::ImageProcessingFailed { .. }
-            | AppError::ImageTooLarge
-            | AppError::InvalidImageData
-            | AppError::CacheError { .. } => StatusCode::UNPROCESSABLE_ENTITY,
-            AppError::InternalServerError => StatusCode::INTERNAL_SERVER_ERROR,
-            AppError::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+/// RFC 7807 response for axum handlers. The error is kept in the response
+/// extensions so the adapter can add `instance` and apply `ERROR_DETAIL`.
+#[cfg(feature = "axum")]
+impl axum::response::IntoResponse for AppError {
+    fn into_response(self) -> axum::response::Response {
+        use http::header;
+
+        let mut response = (
+            self.metadata().status,
+            [(header::CONTENT_TYPE, PROBLEM_JSON)],
+            self.to_response().to_json(),
+        )
+            .into_response();
+        let headers = response.headers_mut();
+        match &self {
+            AppError::Overloaded {
+                retry_after_secs, ..
+            } => {
+                headers.insert(header::RETRY_AFTER, (*retry_after_secs).into());
+            }
+            AppError::MethodNotAllowed { allowed, .. } => {
+                if let Ok(value) = allowed.parse() {
+                    headers.insert(header::ALLOW, value);
+                }
+            }
+            AppError::RangeNotSatisfiable { length } => {
+                if let Ok(value) = format!("bytes */{length}").parse() {
+                    headers.insert(header::CONTENT_RANGE, value);
+                }
+            }
+            _ => {}
         }
+        response.extensions_mut().insert(self);
+        response
     }
 }
+
+/// Middleware filling `instance` (and `requestId`, when a request ID was
+/// assigned) in the ProblemDetails body of `AppError` responses, and
+/// withholding the error's values from `detail`, and the query from
+/// `instance`, when `ERROR_DETAIL=minimal`.
+#[cfg(feature = "actix")]
+pub async fn problem_details_context(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let path = req.path().to_string();
+    let instance = problem_instance(&path, req.uri().query());
+    let res = next.call(req).await?;
+
+    let Some(err) = res
+        .response()
+        .error()
+        .and_then(|err| err.as_error::<AppError>())
+    else {
+        return Ok(res.map_into_left_body());
+    };
+
+    let request_id = res
+        .request()
+        .extensions()
+        .get::<Arc<RequestContext>>()
+        .map(|context| context.id.clone());
+    let minimal = res
+        .request()
+        .app_data::<web::Data<AppState>>()
+        .is_some_and(|state| state.config().server.error_detail == ErrorDetail::Minimal);
+
+    let mut details = if minimal {
+        let reference = request_id
+            .clone()
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+        tracing::warn!(reference = %reference, "{err}");
+        err.to_minimal_response(&reference).with_instance(path)
+    } else {
+        err.to_response().with_instance(instance)
+    };
+
+    if let Some(request_id) = request_id {
+        details = details.with_request_id(request_id);
+    }
+
+    let body = details.to_json();
+    Ok(res.map_body(|_, _| EitherBody::right(BoxBody::new(body))))
+}
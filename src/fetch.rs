@@ -0,0 +1,104 @@
+//! How http(s) sources are downloaded. The pipeline goes through
+//! [`HttpFetcher`], so runtimes without reqwest, or tests, can bring their
+//! own client.
+
+use crate::config::{AppConfig, FetchConfig};
+use crate::error::{AppError, AppResult};
+use crate::origin::OriginPolicies;
+use crate::{fetch_image, FetchContext};
+use futures_util::future::BoxFuture;
+use std::time::Duration;
+use url::Url;
+
+/// Client for origin fetches, pooling connections as `config` says.
+/// Redirects are left to [`fetch_image`], which checks each of them.
+pub fn build_client(config: &FetchConfig) -> reqwest::Client {
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .pool_max_idle_per_host(config.pool_max_idle_per_host)
+        .pool_idle_timeout(Duration::from_secs(config.pool_idle_timeout_secs))
+        .tcp_keepalive(Duration::from_secs(60))
+        .build()
+        .expect("Failed to create the HTTP client")
+}
+
+/// Checks redirect `hop` of a fetch, from `from` to `to`, against the policy
+/// of `config`: http(s) only, no https to http downgrade unless allowed, a
+/// host `origins` allows, and at most `max_redirects` of them.
+pub fn check_redirect(
+    config: &FetchConfig,
+    origins: &OriginPolicies,
+    hop: usize,
+    from: &Url,
+    to: &Url,
+) -> AppResult<()> {
+    let refused = |rule: String| Err(AppError::RedirectRefused { hop, rule });
+    if hop > config.max_redirects {
+        return refused(format!(
+            "goes over FETCH_MAX_REDIRECTS ({})",
+            config.max_redirects
+        ));
+    }
+    if !matches!(to.scheme(), "http" | "https") {
+        return refused(format!("leads to a '{}:' URL", to.scheme()));
+    }
+    if from.scheme() == "https" && to.scheme() == "http" && !config.allow_https_downgrade {
+        return refused("downgrades from https to http".to_string());
+    }
+    if !origins.allows(to.host_str().unwrap_or_default()) {
+        return refused("leads to a host that is not allowed".to_string());
+    }
+    Ok(())
+}
+
+/// Downloads http(s) sources for the pipeline.
+///
+/// Implementations send the headers of `context`, the configured
+/// User-Agent and the headers of the origin profile, follow redirects
+/// passing [`check_redirect`], refuse sources whose host the profiles and
+/// `config.fetch` don't allow with `OriginNotAllowed`, give up after the
+/// timeout of the profile for the whole chain, retrying transient failures
+/// as many times as the profile says (see [`crate::origin`]), report non-2xx
+/// responses with [`crate::error::AppError::from_origin_status`], and stop
+/// reading with `SourceTooLargeBytes` as soon as the body exceeds
+/// `config.limits.max_image_size`, rather than once it is complete.
+pub trait HttpFetcher: Send + Sync {
+    fn fetch<'a>(
+        &'a self,
+        url: &'a str,
+        config: &'a AppConfig,
+        context: &'a FetchContext,
+    ) -> BoxFuture<'a, AppResult<Vec<u8>>>;
+}
+
+/// The default fetcher, see [`fetch_image`].
+#[derive(Debug, Clone)]
+pub struct ReqwestFetcher {
+    client: reqwest::Client,
+}
+
+impl ReqwestFetcher {
+    /// Fetches with `client`, which should leave redirects to the fetcher
+    /// as [`build_client`] does: only where a client following them itself
+    /// ends up can be checked.
+    pub fn new(client: reqwest::Client) -> Self {
+        Self { client }
+    }
+}
+
+impl Default for ReqwestFetcher {
+    fn default() -> Self {
+        Self::new(build_client(&FetchConfig::default()))
+    }
+}
+
+impl HttpFetcher for ReqwestFetcher {
+    fn fetch<'a>(
+        &'a self,
+        url: &'a str,
+        config: &'a AppConfig,
+        context: &'a FetchContext,
+    ) -> BoxFuture<'a, AppResult<Vec<u8>>> {
+        Box::pin(fetch_image(&self.client, url, config, context))
+    }
+}
@@ -0,0 +1,167 @@
+//! Politeness towards origins: at most `fetch.max_per_host` fetches to the
+//! same host at once, optionally started `fetch.min_interval_ms` apart, with
+//! per-host overrides in `fetch.hosts` and in origin profiles. A burst of cache misses for one
+//! origin then queues here rather than opening as many connections.
+
+use crate::config::FetchConfig;
+use crate::origin::OriginPolicies;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+/// Hosts tracked before idle ones are forgotten.
+const MAX_TRACKED_HOSTS: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HostLimit {
+    pub max_concurrent: usize,
+    /// Least time between the starts of two fetches.
+    pub min_interval: Duration,
+}
+
+#[derive(Debug)]
+pub struct HostLimiter {
+    default: HostLimit,
+    overrides: HashMap<String, HostLimit>,
+    origins: Option<Arc<OriginPolicies>>,
+    hosts: Mutex<HashMap<String, Arc<HostSlot>>>,
+}
+
+#[derive(Debug)]
+struct HostSlot {
+    permits: Arc<Semaphore>,
+    in_flight: AtomicUsize,
+    min_interval: Duration,
+    /// When the next fetch may start, with a minimum interval.
+    next_start: Mutex<Instant>,
+}
+
+/// Slot for one fetch to a host, released when dropped.
+#[derive(Debug)]
+pub struct HostPermit {
+    slot: Arc<HostSlot>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Drop for HostPermit {
+    fn drop(&mut self) {
+        self.slot.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl HostLimiter {
+    /// Applies `default` to every host but those of `overrides`, named in
+    /// lower case.
+    pub fn new(default: HostLimit, overrides: HashMap<String, HostLimit>) -> Self {
+        Self {
+            default,
+            overrides,
+            origins: None,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Applies the limits of origin profiles to the hosts without an
+    /// override in `fetch.hosts`.
+    pub fn with_origins(mut self, origins: Arc<OriginPolicies>) -> Self {
+        self.origins = Some(origins);
+        self
+    }
+
+    pub fn from_config(config: &FetchConfig) -> Self {
+        let default = HostLimit {
+            max_concurrent: config.max_per_host,
+            min_interval: Duration::from_millis(config.min_interval_ms),
+        };
+        let overrides = config
+            .hosts
+            .iter()
+            .map(|(host, limit)| {
+                let limit = HostLimit {
+                    max_concurrent: limit.max_concurrent.unwrap_or(default.max_concurrent),
+                    min_interval: limit
+                        .min_interval_ms
+                        .map_or(default.min_interval, Duration::from_millis),
+                };
+                (host.to_ascii_lowercase(), limit)
+            })
+            .collect();
+        Self::new(default, overrides)
+    }
+
+    /// The limit applying to `host`.
+    pub fn limit(&self, host: &str) -> HostLimit {
+        if let Some(limit) = self.overrides.get(&host.to_ascii_lowercase()) {
+            return *limit;
+        }
+        self.origins
+            .as_ref()
+            .map_or(self.default, |origins| origins.policy(host).limit)
+    }
+
+    /// Waits up to `wait` for a slot to `host`, and for its minimum interval
+    /// since the previous fetch to pass. `None` when it took longer.
+    pub async fn acquire(&self, host: &str, wait: Duration) -> Option<HostPermit> {
+        let slot = self.slot(host);
+        let acquire = async {
+            let permit = slot.permits.clone().acquire_owned().await.ok()?;
+            if !slot.min_interval.is_zero() {
+                let start = {
+                    let mut next_start = slot.next_start.lock().unwrap();
+                    let start = (*next_start).max(Instant::now());
+                    *next_start = start + slot.min_interval;
+                    start
+                };
+                tokio::time::sleep_until(start).await;
+            }
+            Some(permit)
+        };
+        let permit = tokio::time::timeout(wait, acquire).await.ok()??;
+
+        slot.in_flight.fetch_add(1, Ordering::Relaxed);
+        Some(HostPermit {
+            slot,
+            _permit: permit,
+        })
+    }
+
+    /// Fetches in flight per host, for hosts with any.
+    pub fn in_flight(&self) -> Vec<(String, usize)> {
+        let hosts = self.hosts.lock().unwrap();
+        let mut in_flight: Vec<(String, usize)> = hosts
+            .iter()
+            .map(|(host, slot)| (host.clone(), slot.in_flight.load(Ordering::Relaxed)))
+            .filter(|&(_, count)| count > 0)
+            .collect();
+        in_flight.sort();
+        in_flight
+    }
+
+    fn slot(&self, host: &str) -> Arc<HostSlot> {
+        let host = host.to_ascii_lowercase();
+        let mut hosts = self.hosts.lock().unwrap();
+        if let Some(slot) = hosts.get(&host) {
+            return slot.clone();
+        }
+
+        // Forget hosts no fetch holds or waits for, and whose interval passed
+        if hosts.len() >= MAX_TRACKED_HOSTS {
+            let now = Instant::now();
+            hosts.retain(|_, slot| {
+                Arc::strong_count(slot) > 1 || *slot.next_start.lock().unwrap() > now
+            });
+        }
+        let limit = self.limit(&host);
+        let slot = Arc::new(HostSlot {
+            permits: Arc::new(Semaphore::new(limit.max_concurrent)),
+            in_flight: AtomicUsize::new(0),
+            min_interval: limit.min_interval,
+            next_start: Mutex::new(Instant::now()),
+        });
+        hosts.insert(host, slot.clone());
+        slot
+    }
+}
@@ -1,70 +1,530 @@
 use crate::error::{AppError, AppResult};
-use image::{DynamicImage, ImageFormat, ImageReader};
+use crate::metrics::{Phase, PhaseTimings};
+use crate::transform_chain::{self, Axis, Filter, Gravity, Rotation, TransformStep};
+use crate::MAX_SOURCE_PIXELS;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use image::{imageops, DynamicImage, ImageFormat, ImageReader, Rgba, RgbaImage};
 use std::io::Cursor;
-use webp::Encoder;
 
 pub struct ImageProcessor;
 
+/// How the image fits the box when both a width and a height are given.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Fit {
+    /// Scale to fit inside the box, keeping the aspect ratio.
+    #[default]
+    Contain,
+    /// Scale to cover the box, then crop the overflow around the center.
+    Cover,
+    /// Scale like `Contain`, then pad to the box with the background color.
+    Pad,
+}
+
+impl Fit {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "contain" => Some(Fit::Contain),
+            "cover" => Some(Fit::Cover),
+            "pad" => Some(Fit::Pad),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Fit::Contain => "contain",
+            Fit::Cover => "cover",
+            Fit::Pad => "pad",
+        }
+    }
+}
+
+/// Validated transformation to apply to an image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessingPlan {
+    /// Chained steps, applied in order before the resize below.
+    pub steps: Vec<TransformStep>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fit: Fit,
+    /// RGBA color of padding, and behind transparent pixels in JPEG output.
+    pub background: Option<[u8; 4]>,
+    pub quality: u8,
+    /// Detected from the source image when `None`.
+    pub format: Option<OutputFormat>,
+}
+
+impl ProcessingPlan {
+    /// Every parameter of the plan, resolved, in a fixed order and format:
+    /// `w=400&h=auto&fit=contain&bg=none&q=75&f=source&tx=`. Plans producing
+    /// the same image have the same form, so values without effect are
+    /// replaced by their default: `fit` unless both `w` and `h` are set, and
+    /// `bg` when the output is neither padded nor possibly JPEG.
+    pub fn canonical(&self) -> String {
+        let boxed = self.width.is_some() && self.height.is_some();
+        let fit = if boxed { self.fit } else { Fit::default() };
+        let flattened = !matches!(self.format, Some(OutputFormat::Png | OutputFormat::WebP));
+        let background = self
+            .background
+            .filter(|_| fit == Fit::Pad || flattened)
+            .map_or_else(|| "none".to_string(), hex::encode);
+        let dimension =
+            |value: Option<u32>| value.map_or_else(|| "auto".to_string(), |v| v.to_string());
+        format!(
+            "w={}&h={}&fit={}&bg={background}&q={}&f={}&tx={}",
+            dimension(self.width),
+            dimension(self.height),
+            fit.as_str(),
+            self.quality,
+            self.format.map_or("source", |format| format.name()),
+            transform_chain::canonical(&self.steps),
+        )
+    }
+}
+
+/// Output of [`ImageProcessor::process_sync`].
+#[derive(Debug, Clone)]
+pub struct ProcessedImage {
+    pub bytes: Vec<u8>,
+    pub content_type: &'static str,
+    pub width: u32,
+    pub height: u32,
+    pub original_width: u32,
+    pub original_height: u32,
+    /// Whether transparent pixels were made opaque by JPEG output without a
+    /// background color to composite them over.
+    pub alpha_flattened: bool,
+    /// Whether the box of the plan was larger than the image, which was not
+    /// enlarged to fill it.
+    pub upscale_refused: bool,
+}
+
+/// Format and dimensions of an image that decoded successfully.
+#[derive(Debug, Clone, Copy)]
+pub struct ImageInfo {
+    /// Canonical file extension of the detected format.
+    pub extension: &'static str,
+    pub width: u32,
+    pub height: u32,
+}
+
 impl ImageProcessor {
-    pub async fn process(
-        image_data: Vec<u8>,
-        width: Option<u32>,
-        quality: u8,
-        format: Option<&str>,
-    ) -> AppResult<Vec<u8>> {
-        // Load image
+    /// Fully decodes `image_data` to check it is a valid image in one of the
+    /// formats internal storage serves.
+    pub fn inspect(image_data: &[u8]) -> AppResult<ImageInfo> {
         let reader = ImageReader::new(Cursor::new(image_data))
             .with_guessed_format()
-            .map_err(|e| AppError::ImageProcessingFailed {
-                reason: format!("Failed to read image: {e}"),
-            })?;
-
-        let mut img = reader
-            .decode()
-            .map_err(|e| AppError::ImageProcessingFailed {
-                reason: format!("Failed to decode image: {e}"),
-            })?;
-
-        // Resize if needed
-        if let Some(target_width) = width {
-            let (current_width, current_height) = (img.width(), img.height());
-            if target_width < current_width {
-                let target_height =
-                    (target_width as f32 * current_height as f32 / current_width as f32) as u32;
-                img = img.resize_exact(
-                    target_width,
-                    target_height,
-                    image::imageops::FilterType::Lanczos3,
-                );
+            .map_err(|_| AppError::InvalidImageData)?;
+        let extension = match reader.format() {
+            Some(ImageFormat::Jpeg) => "jpg",
+            Some(ImageFormat::Png) => "png",
+            Some(ImageFormat::WebP) => "webp",
+            Some(ImageFormat::Gif) => "gif",
+            Some(other) => {
+                return Err(AppError::InvalidImageFormat {
+                    format: format!("{other:?}").to_lowercase(),
+                })
             }
+            None => return Err(AppError::InvalidImageData),
+        };
+        check_pixel_count(image_data)?;
+        let img = reader.decode().map_err(|_| AppError::InvalidImageData)?;
+        if img.width() == 0 || img.height() == 0 {
+            return Err(AppError::InvalidImageData);
         }
 
-        // Convert format and encode
-        let output_format = match format {
-            Some(f) => match f {
-                "jpeg" | "jpg" => OutputFormat::Jpeg,
-                "png" => OutputFormat::Png,
-                "webp" => OutputFormat::WebP,
-                _ => {
-                    return Err(AppError::InvalidImageFormat {
-                        format: f.to_string(),
-                    })
+        Ok(ImageInfo {
+            extension,
+            width: img.width(),
+            height: img.height(),
+        })
+    }
+
+    /// Applies `plan` to `image_data` on the calling thread. Needs no async
+    /// runtime, for embedders with their own threading.
+    pub fn process_sync(image_data: &[u8], plan: &ProcessingPlan) -> AppResult<ProcessedImage> {
+        Self::process_sync_timed(image_data, plan, &mut PhaseTimings::default())
+    }
+
+    /// [`Self::process_sync`], recording the decode, resize and encode
+    /// durations into `timings`.
+    pub fn process_sync_timed(
+        image_data: &[u8],
+        plan: &ProcessingPlan,
+        timings: &mut PhaseTimings,
+    ) -> AppResult<ProcessedImage> {
+        let img = timings.time(Phase::Decode, || decode(image_data))?;
+        render(img, plan, timings)
+    }
+
+    /// Applies each of `plans` to `image_data`, decoding it only once. A
+    /// source that does not decode fails every plan.
+    pub fn process_many_sync_timed(
+        image_data: &[u8],
+        plans: &[ProcessingPlan],
+        timings: &mut PhaseTimings,
+    ) -> Vec<AppResult<ProcessedImage>> {
+        match timings.time(Phase::Decode, || decode(image_data)) {
+            Ok(img) => {
+                let mut results = Vec::with_capacity(plans.len());
+                if let Some((last, rest)) = plans.split_last() {
+                    for plan in rest {
+                        results.push(render(img.clone(), plan, timings));
+                    }
+                    // The last plan takes the decoded image instead of a copy
+                    results.push(render(img, last, timings));
                 }
-            },
-            None => detect_format(&img),
-        };
+                results
+            }
+            Err(err) => plans.iter().map(|_| Err(err.clone())).collect(),
+        }
+    }
 
-        encode_image(&img, output_format, quality)
+    /// [`Self::process_sync_timed`] on tokio's blocking pool, so decoding
+    /// and encoding never stall the server's async workers.
+    #[cfg(feature = "runtime")]
+    #[cfg_attr(
+        feature = "otel",
+        tracing::instrument(name = "process_image", skip(image_data, timings))
+    )]
+    pub async fn process_timed(
+        image_data: Vec<u8>,
+        plan: &ProcessingPlan,
+        timings: &mut PhaseTimings,
+    ) -> AppResult<ProcessedImage> {
+        let plan = plan.clone();
+        let span = tracing::Span::current();
+        let (result, phases) = tokio::task::spawn_blocking(move || {
+            let _entered = span.enter();
+            let mut phases = PhaseTimings::default();
+            let result = Self::process_sync_timed(&image_data, &plan, &mut phases);
+            (result, phases)
+        })
+        .await
+        .map_err(|e| AppError::ImageProcessingFailed {
+            reason: format!("Image processing task failed: {e}"),
+        })?;
+
+        for &(phase, duration) in phases.iter() {
+            timings.record(phase, duration);
+        }
+        result
+    }
+
+    /// Width and height of an encoded image, read from its header.
+    pub fn dimensions(image_data: &[u8]) -> Option<(u32, u32)> {
+        ImageReader::new(Cursor::new(image_data))
+            .with_guessed_format()
+            .ok()
+            .and_then(|reader| reader.into_dimensions().ok())
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-enum OutputFormat {
+/// Transforms and encodes a decoded image according to `plan`.
+fn render(
+    img: DynamicImage,
+    plan: &ProcessingPlan,
+    timings: &mut PhaseTimings,
+) -> AppResult<ProcessedImage> {
+    let (original_width, original_height) = (img.width(), img.height());
+    let (img, upscale_refused) = timings.time(Phase::Transform, || {
+        let img = plan.steps.iter().fold(img, apply_step);
+        let upscale_refused = enlarges(&img, plan.width, plan.height, plan.fit);
+        (resize(img, plan), upscale_refused)
+    });
+
+    // Convert format and encode
+    let output_format = plan.format.unwrap_or_else(|| detect_format(&img));
+    let alpha_flattened =
+        output_format == OutputFormat::Jpeg && plan.background.is_none() && is_transparent(&img);
+
+    let bytes = timings.time(Phase::Encode, || {
+        encode_image(&img, output_format, plan.quality, plan.background)
+    })?;
+    Ok(ProcessedImage {
+        bytes,
+        content_type: output_format.content_type(),
+        width: img.width(),
+        height: img.height(),
+        original_width,
+        original_height,
+        alpha_flattened,
+        upscale_refused,
+    })
+}
+
+pub(crate) fn decode(image_data: &[u8]) -> AppResult<DynamicImage> {
+    check_pixel_count(image_data)?;
+
+    let reader = ImageReader::new(Cursor::new(image_data))
+        .with_guessed_format()
+        .map_err(|e| AppError::ImageProcessingFailed {
+            reason: format!("Failed to read image: {e}"),
+        })?;
+
+    let img = reader
+        .decode()
+        .map_err(|e| AppError::ImageProcessingFailed {
+            reason: format!("Failed to decode image: {e}"),
+        })?;
+    // Headers may declare a side of zero, which passes the pixel budget
+    if img.width() == 0 || img.height() == 0 {
+        return Err(AppError::ImageProcessingFailed {
+            reason: format!(
+                "Failed to decode image: it is {}x{} pixels",
+                img.width(),
+                img.height()
+            ),
+        });
+    }
+    Ok(img)
+}
+
+/// Rejects images over [`MAX_SOURCE_PIXELS`] from their header, before any
+/// pixel buffer is allocated. Unreadable headers are left for `decode` to
+/// report.
+fn check_pixel_count(image_data: &[u8]) -> AppResult<()> {
+    match ImageProcessor::dimensions(image_data) {
+        Some((width, height)) if u64::from(width) * u64::from(height) > MAX_SOURCE_PIXELS => {
+            Err(AppError::SourceTooLargePixels {
+                limit: MAX_SOURCE_PIXELS,
+                width,
+                height,
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Resizes per the plan's box and fit. Images are never enlarged.
+fn resize(img: DynamicImage, plan: &ProcessingPlan) -> DynamicImage {
+    resize_to_box(img, plan.width, plan.height, plan.fit, plan.background)
+}
+
+/// Resizes to the box per `fit`. Images are never enlarged.
+fn resize_to_box(
+    img: DynamicImage,
+    width: Option<u32>,
+    height: Option<u32>,
+    fit: Fit,
+    background: Option<[u8; 4]>,
+) -> DynamicImage {
+    match (width, height, fit) {
+        (Some(box_width), Some(box_height), Fit::Cover) => {
+            let img = scale(img, width, height, true);
+            let (crop_width, crop_height) =
+                (box_width.min(img.width()), box_height.min(img.height()));
+            img.crop_imm(
+                (img.width() - crop_width) / 2,
+                (img.height() - crop_height) / 2,
+                crop_width,
+                crop_height,
+            )
+        }
+        (Some(box_width), Some(box_height), Fit::Pad) => {
+            let img = scale(img, width, height, false);
+            let background = Rgba(background.unwrap_or([0, 0, 0, 0]));
+            let mut canvas = RgbaImage::from_pixel(box_width, box_height, background);
+            imageops::overlay(
+                &mut canvas,
+                &img.to_rgba8(),
+                i64::from((box_width - img.width()) / 2),
+                i64::from((box_height - img.height()) / 2),
+            );
+            DynamicImage::ImageRgba8(canvas)
+        }
+        _ => scale(img, width, height, false),
+    }
+}
+
+/// Applies one step of a [`ProcessingPlan`]'s chain.
+fn apply_step(img: DynamicImage, step: &TransformStep) -> DynamicImage {
+    match *step {
+        TransformStep::Crop {
+            width,
+            height,
+            gravity,
+        } => crop_to_aspect(img, width, height, gravity),
+        TransformStep::Resize {
+            width,
+            height,
+            fit,
+            background,
+        } => resize_to_box(img, width, height, fit, background),
+        TransformStep::Rotate(Rotation::Quarter) => img.rotate90(),
+        TransformStep::Rotate(Rotation::Half) => img.rotate180(),
+        TransformStep::Rotate(Rotation::ThreeQuarters) => img.rotate270(),
+        TransformStep::Flip(Axis::Horizontal) => img.fliph(),
+        TransformStep::Flip(Axis::Vertical) => img.flipv(),
+        TransformStep::Filter(Filter::Grayscale) => img.grayscale(),
+        TransformStep::Filter(Filter::Invert) => {
+            let mut img = img;
+            img.invert();
+            img
+        }
+        TransformStep::Filter(Filter::Blur(sigma)) => img.fast_blur(f32::from(sigma)),
+    }
+}
+
+/// Crops to the largest region with the aspect ratio `width:height`, on the
+/// side `gravity` names.
+fn crop_to_aspect(img: DynamicImage, width: u32, height: u32, gravity: Gravity) -> DynamicImage {
+    let (current_width, current_height) = (u64::from(img.width()), u64::from(img.height()));
+    let (width, height) = (u64::from(width), u64::from(height));
+    // current_width / current_height > width / height, cross-multiplied
+    let (crop_width, crop_height) = if current_width * height > current_height * width {
+        ((current_height * width / height).max(1), current_height)
+    } else {
+        (current_width, (current_width * height / width).max(1))
+    };
+    let (crop_width, crop_height) = (crop_width as u32, crop_height as u32);
+    let (spare_width, spare_height) = (img.width() - crop_width, img.height() - crop_height);
+    let (x, y) = match gravity {
+        Gravity::Center => (spare_width / 2, spare_height / 2),
+        Gravity::Top => (spare_width / 2, 0),
+        Gravity::Bottom => (spare_width / 2, spare_height),
+        Gravity::Left => (0, spare_height / 2),
+        Gravity::Right => (spare_width, spare_height / 2),
+    };
+    img.crop_imm(x, y, crop_width, crop_height)
+}
+
+/// Downscales to fit inside the box or, with `cover`, to cover it, keeping
+/// the aspect ratio.
+fn scale(img: DynamicImage, width: Option<u32>, height: Option<u32>, cover: bool) -> DynamicImage {
+    let (current_width, current_height) = (img.width(), img.height());
+    if current_width == 0 || current_height == 0 {
+        return img;
+    }
+    // Exact integer arithmetic: with f32, large sides could round past the
+    // box, and `pad` then underflows computing its margins
+    let proportional = |length: u32, numerator: u32, denominator: u32| {
+        let scaled = u64::from(length) * u64::from(numerator) / u64::from(denominator);
+        u32::try_from(scaled).unwrap_or(u32::MAX).max(1)
+    };
+
+    let Some(by_width) = binds_by_width(current_width, current_height, width, height, cover) else {
+        return img;
+    };
+
+    let (target_width, target_height) = match (by_width, width, height) {
+        (true, Some(w), _) if w < current_width => {
+            (w, proportional(w, current_height, current_width))
+        }
+        (false, _, Some(h)) if h < current_height => {
+            (proportional(h, current_width, current_height), h)
+        }
+        _ => return img,
+    };
+
+    img.resize_exact(target_width, target_height, imageops::FilterType::Lanczos3)
+}
+
+/// Whether [`scale`] fits an image of `current_width`×`current_height` to
+/// the box by its width rather than its height; `None` without a box.
+fn binds_by_width(
+    current_width: u32,
+    current_height: u32,
+    width: Option<u32>,
+    height: Option<u32>,
+    cover: bool,
+) -> Option<bool> {
+    match (width, height) {
+        (None, None) => None,
+        (Some(_), None) => Some(true),
+        (None, Some(_)) => Some(false),
+        (Some(w), Some(h)) => {
+            // w / current_width < h / current_height, cross-multiplied
+            let width_binds =
+                u64::from(w) * u64::from(current_height) < u64::from(h) * u64::from(current_width);
+            Some(width_binds != cover)
+        }
+    }
+}
+
+/// Whether fitting `img` to the box per `fit` would take enlarging it,
+/// which [`scale`] refuses.
+fn enlarges(img: &DynamicImage, width: Option<u32>, height: Option<u32>, fit: Fit) -> bool {
+    let (current_width, current_height) = (img.width(), img.height());
+    match binds_by_width(
+        current_width,
+        current_height,
+        width,
+        height,
+        fit == Fit::Cover,
+    ) {
+        Some(true) => width.is_some_and(|w| w > current_width),
+        Some(false) => height.is_some_and(|h| h > current_height),
+        None => false,
+    }
+}
+
+/// Formats images can be encoded to, the values of the `f` parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
     Jpeg,
     Png,
+    /// Requires the `webp` feature, and `webp-native` for lossy output,
+    /// which wasm32 builds never have.
     WebP,
 }
 
+impl OutputFormat {
+    pub const ALL: [OutputFormat; 3] = [OutputFormat::Jpeg, OutputFormat::Png, OutputFormat::WebP];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "jpeg",
+            OutputFormat::Png => "png",
+            OutputFormat::WebP => "webp",
+        }
+    }
+
+    /// Other values of `f` selecting this format.
+    pub fn aliases(&self) -> &'static [&'static str] {
+        match self {
+            OutputFormat::Jpeg => &["jpg"],
+            OutputFormat::Png | OutputFormat::WebP => &[],
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Png => "image/png",
+            OutputFormat::WebP => "image/webp",
+        }
+    }
+
+    /// Whether this binary was built with an encoder for the format.
+    pub fn is_available(&self) -> bool {
+        match self {
+            OutputFormat::Jpeg | OutputFormat::Png => true,
+            OutputFormat::WebP => cfg!(feature = "webp"),
+        }
+    }
+
+    /// The available format named `value` or one of its aliases.
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::available().find(|format| format.name() == value || format.aliases().contains(&value))
+    }
+
+    pub fn available() -> impl Iterator<Item = OutputFormat> {
+        Self::ALL.into_iter().filter(OutputFormat::is_available)
+    }
+
+    /// Names and aliases of the available formats, e.g. `jpeg, jpg, png`.
+    pub fn supported_values() -> String {
+        Self::available()
+            .flat_map(|format| {
+                std::iter::once(format.name()).chain(format.aliases().iter().copied())
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
 fn detect_format(img: &DynamicImage) -> OutputFormat {
     // Default to JPEG for photos, PNG for images with transparency
     if img.color().has_alpha() {
@@ -74,35 +534,137 @@ fn detect_format(img: &DynamicImage) -> OutputFormat {
     }
 }
 
-fn encode_image(img: &DynamicImage, format: OutputFormat, quality: u8) -> AppResult<Vec<u8>> {
+/// Whether any pixel of `img` is less than opaque.
+fn is_transparent(img: &DynamicImage) -> bool {
+    match img {
+        _ if !img.color().has_alpha() => false,
+        DynamicImage::ImageRgba8(rgba) => rgba.pixels().any(|pixel| pixel[3] < u8::MAX),
+        _ => img.to_rgba8().pixels().any(|pixel| pixel[3] < u8::MAX),
+    }
+}
+
+/// Composites transparent pixels over `background`, ignoring its alpha.
+fn flatten(img: &DynamicImage, background: [u8; 4]) -> image::RgbImage {
+    let mut rgba = img.to_rgba8();
+    for pixel in rgba.pixels_mut() {
+        let alpha = u16::from(pixel[3]);
+        for channel in 0..3 {
+            let blended =
+                u16::from(pixel[channel]) * alpha + u16::from(background[channel]) * (255 - alpha);
+            pixel[channel] = (blended / 255) as u8;
+        }
+        pixel[3] = 255;
+    }
+    DynamicImage::ImageRgba8(rgba).to_rgb8()
+}
+
+/// Encodes `img`, the same pixels always to the same bytes, so replicas
+/// agree on the ETag of an output. Encoder options are set explicitly rather
+/// than left to the defaults of a dependency, and nothing time-dependent is
+/// written: no PNG `tIME` or EXIF, metadata of the source being dropped with
+/// decoding.
+fn encode_image(
+    img: &DynamicImage,
+    format: OutputFormat,
+    quality: u8,
+    background: Option<[u8; 4]>,
+) -> AppResult<Vec<u8>> {
     let mut output = Vec::new();
     let mut cursor = Cursor::new(&mut output);
 
     match format {
         OutputFormat::Jpeg => {
             // JPEG doesn't support transparency, convert to RGB
-            let rgb_img = img.to_rgb8();
+            let rgb_img = match background {
+                Some(background) if img.color().has_alpha() => flatten(img, background),
+                _ => img.to_rgb8(),
+            };
             rgb_img
-                .write_to(&mut cursor, ImageFormat::Jpeg)
+                .write_with_encoder(JpegEncoder::new_with_quality(&mut cursor, quality))
                 .map_err(|e| AppError::ImageProcessingFailed {
                     reason: format!("Failed to encode JPEG: {e}"),
                 })?;
         }
         OutputFormat::Png => {
-            img.write_to(&mut cursor, ImageFormat::Png).map_err(|e| {
-                AppError::ImageProcessingFailed {
+            let encoder = PngEncoder::new_with_quality(
+                &mut cursor,
+                CompressionType::Fast,
+                FilterType::Adaptive,
+            );
+            img.write_with_encoder(encoder)
+                .map_err(|e| AppError::ImageProcessingFailed {
                     reason: format!("Failed to encode PNG: {e}"),
-                }
-            })?;
+                })?;
         }
         OutputFormat::WebP => {
-            let rgba_img = img.to_rgba8();
-            let (width, height) = rgba_img.dimensions();
-            let encoder = Encoder::from_rgba(&rgba_img, width, height);
-            let webp_data = encoder.encode(quality as f32);
-            output.extend_from_slice(&webp_data);
+            if img.width() > WEBP_MAX_DIMENSION || img.height() > WEBP_MAX_DIMENSION {
+                return Err(AppError::ImageProcessingFailed {
+                    reason: format!(
+                        "Failed to encode WebP: {}x{} exceeds the format's {WEBP_MAX_DIMENSION}x{WEBP_MAX_DIMENSION} limit; resize with w or h",
+                        img.width(),
+                        img.height()
+                    ),
+                });
+            }
+            output.extend_from_slice(&encode_webp(img, quality)?)
         }
     }
 
     Ok(output)
 }
+
+/// Largest width and height of a WebP image.
+const WEBP_MAX_DIMENSION: u32 = 16383;
+
+#[cfg(all(feature = "webp-native", not(target_arch = "wasm32")))]
+fn encode_webp(img: &DynamicImage, quality: u8) -> AppResult<Vec<u8>> {
+    let rgba_img = img.to_rgba8();
+    let (width, height) = rgba_img.dimensions();
+    let encoder = webp::Encoder::from_rgba(&rgba_img, width, height);
+    let mut config = webp::WebPConfig::new().map_err(|()| AppError::ImageProcessingFailed {
+        reason: "Failed to configure the WebP encoder".to_string(),
+    })?;
+    config.lossless = 0;
+    config.quality = f32::from(quality);
+    config.method = 4;
+    config.alpha_compression = 1;
+    config.alpha_quality = 100;
+    config.exact = 0;
+    config.thread_level = 0;
+    // Not `encode`, which panics on libwebp errors
+    encoder
+        .encode_advanced(&config)
+        .map(|memory| memory.to_vec())
+        .map_err(|e| AppError::ImageProcessingFailed {
+            reason: format!("Failed to encode WebP: {e:?}"),
+        })
+}
+
+/// Pure-Rust fallback for builds without libwebp, e.g. cross-compiled to
+/// musl, or for wasm32, where libwebp does not build. The image crate only
+/// encodes lossless WebP: `quality` is ignored, and photos come out several
+/// times larger than with libwebp, often larger than the JPEG.
+#[cfg(all(
+    feature = "webp",
+    any(not(feature = "webp-native"), target_arch = "wasm32")
+))]
+fn encode_webp(img: &DynamicImage, _quality: u8) -> AppResult<Vec<u8>> {
+    use image::{codecs::webp::WebPEncoder, ExtendedColorType, ImageEncoder};
+
+    let rgba_img = img.to_rgba8();
+    let (width, height) = rgba_img.dimensions();
+    let mut output = Vec::new();
+    WebPEncoder::new_lossless(&mut output)
+        .write_image(&rgba_img, width, height, ExtendedColorType::Rgba8)
+        .map_err(|e| AppError::ImageProcessingFailed {
+            reason: format!("Failed to encode WebP: {e}"),
+        })?;
+    Ok(output)
+}
+
+#[cfg(not(feature = "webp"))]
+fn encode_webp(_img: &DynamicImage, _quality: u8) -> AppResult<Vec<u8>> {
+    Err(AppError::InvalidImageFormat {
+        format: OutputFormat::WebP.name().to_string(),
+    })
+}
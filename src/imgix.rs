@@ -0,0 +1,138 @@
+//! Translation of the common imgix rendering parameters, so image URLs
+//! written for imgix keep working after a migration.
+//!
+//! | imgix                              | here                          |
+//! |------------------------------------|-------------------------------|
+//! | `w`, `h`, `q`                      | same                          |
+//! | `fm=jpg\|pjpg\|png\|webp`          | `f`                           |
+//! | `fit=clip\|max`                    | `fit=contain`                 |
+//! | `fit=crop`                         | `fit=cover`                   |
+//! | `fit=fill`                         | `fit=pad`                     |
+//! | `bg=RGB\|ARGB\|RRGGBB\|AARRGGBB`   | `bg` as `RRGGBBAA`            |
+//! | `auto=compress`                    | `q=45` unless `q` is given    |
+//!
+//! Anything else, such as `crop=faces`, `auto=format` or `blur`, is
+//! unsupported: ignored and reported, or rejected in strict mode.
+
+use crate::auth::API_KEY_QUERY_PARAM;
+use crate::error::{AppError, AppResult};
+use crate::ImageParams;
+
+/// Parameters only imgix URLs use; any of them makes a query imgix.
+const IMGIX_ONLY_PARAMS: &[&str] = &["fm", "auto", "crop"];
+
+/// Quality imgix defaults to with `auto=compress`.
+const COMPRESS_QUALITY: &str = "45";
+
+/// Result of translating an imgix query.
+#[derive(Debug)]
+pub struct Translation {
+    pub params: ImageParams,
+    /// Unsupported parameters that were dropped, as `name=value`.
+    pub ignored: Vec<String>,
+}
+
+/// Whether `query` uses an imgix-only parameter or imgix `fit` value.
+pub fn is_imgix_query(query: &str) -> bool {
+    url::form_urlencoded::parse(query.as_bytes()).any(|(name, value)| {
+        IMGIX_ONLY_PARAMS.contains(&name.as_ref()) || (name == "fit" && imgix_fit(&value).is_some())
+    })
+}
+
+/// Translates an imgix query string. Unsupported parameters are collected in
+/// [`Translation::ignored`], or rejected when `strict`.
+pub fn translate(query: &str, strict: bool) -> AppResult<Translation> {
+    let mut params = ImageParams::default();
+    let mut unsupported = Vec::new();
+    let mut compress = false;
+
+    for (name, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        let value = value.into_owned();
+        let supported = match name.as_ref() {
+            "src" | "url" => set(&mut params.src, Some(value.clone())),
+            "srcb64" => set(&mut params.srcb64, Some(value.clone())),
+            "dl" => set(&mut params.dl, Some(value.clone())),
+            API_KEY_QUERY_PARAM => true,
+            // Validated with the other parameters, like native queries
+            "w" => set(&mut params.w, Some(value.clone())),
+            "h" => set(&mut params.h, Some(value.clone())),
+            "q" => set(&mut params.q, Some(value.clone())),
+            "fm" => set(&mut params.f, imgix_format(&value).map(str::to_string)),
+            "fit" => set(&mut params.fit, imgix_fit(&value).map(str::to_string)),
+            "bg" => set(&mut params.bg, imgix_color(&value)),
+            "auto" => {
+                for feature in value.split(',') {
+                    match feature.trim() {
+                        "compress" => compress = true,
+                        other => unsupported.push(format!("auto={other}")),
+                    }
+                }
+                true
+            }
+            _ => false,
+        };
+        if !supported {
+            unsupported.push(format!("{name}={value}"));
+        }
+    }
+
+    if compress && params.q.is_none() {
+        params.q = Some(COMPRESS_QUALITY.to_string());
+    }
+
+    if strict {
+        if let Some(param) = unsupported.into_iter().next() {
+            return Err(AppError::UnsupportedParameter { param });
+        }
+        unsupported = Vec::new();
+    }
+
+    Ok(Translation {
+        params,
+        ignored: unsupported,
+    })
+}
+
+/// Stores a translated value, reporting whether there was one.
+fn set<T>(field: &mut Option<T>, value: Option<T>) -> bool {
+    let supported = value.is_some();
+    if supported {
+        *field = value;
+    }
+    supported
+}
+
+fn imgix_format(fm: &str) -> Option<&'static str> {
+    match fm {
+        "jpg" | "pjpg" => Some("jpeg"),
+        "png" => Some("png"),
+        "webp" => Some("webp"),
+        _ => None,
+    }
+}
+
+fn imgix_fit(fit: &str) -> Option<&'static str> {
+    match fit {
+        "clip" | "max" => Some("contain"),
+        "crop" => Some("cover"),
+        "fill" => Some("pad"),
+        _ => None,
+    }
+}
+
+/// Converts an imgix `RGB`, `ARGB`, `RRGGBB` or `AARRGGBB` color, alpha
+/// first, into `RRGGBBAA`.
+fn imgix_color(bg: &str) -> Option<String> {
+    let bg = bg.strip_prefix('#').unwrap_or(bg).to_ascii_lowercase();
+    if !bg.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let double = |digits: &str| digits.chars().flat_map(|c| [c, c]).collect::<String>();
+    match bg.len() {
+        3 => Some(format!("{}ff", double(&bg))),
+        4 => Some(format!("{}{}", double(&bg[1..]), double(&bg[..1]))),
+        6 => Some(format!("{bg}ff")),
+        8 => Some(format!("{}{}", &bg[2..], &bg[..2])),
+        _ => None,
+    }
+}
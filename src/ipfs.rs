@@ -0,0 +1,204 @@
+//! `ipfs://<cid>/path` sources, fetched through the HTTP gateway of
+//! `ipfs.gateway`, then `ipfs.fallback_gateway` when the first one fails.
+//!
+//! Sources are named in cache keys by their canonical `ipfs://` form, never
+//! by a gateway URL, so switching gateways keeps the cache. CIDs are
+//! validated before any URL is built: version 0 (`Qm...`, base58btc) and
+//! version 1 in base32 (`bafy...`), the forms IPFS tools print.
+
+use crate::error::{truncate_src, AppError, AppResult};
+use crate::{fetch_url, AppState, FetchContext};
+use log::warn;
+use url::Url;
+
+const BASE58_ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+const BASE32_ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Multihash code of SHA-256, the only hash of version 0 CIDs.
+const SHA2_256: u8 = 0x12;
+
+/// A parsed `ipfs://` source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IpfsPath {
+    cid: String,
+    /// Path within the content, empty or starting with `/`.
+    path: String,
+}
+
+impl IpfsPath {
+    /// Parses `ipfs://<cid>/path`, validating the CID. Dot segments are
+    /// resolved and the path percent-encoded; the query and fragment, which
+    /// gateways ignore, are dropped.
+    pub fn parse(src: &str) -> AppResult<Self> {
+        let url = Url::parse(src).map_err(|_| AppError::InvalidImageUrl)?;
+        if url.scheme() != "ipfs"
+            || url.port().is_some()
+            || !url.username().is_empty()
+            || url.password().is_some()
+        {
+            return Err(AppError::InvalidImageUrl);
+        }
+        let cid = url.host_str().unwrap_or_default();
+        if !is_valid_cid(cid) {
+            return Err(AppError::InvalidParameterValue {
+                param: "src".to_string(),
+                value: truncate_src(src).into_owned(),
+                expected: "ipfs://<cid>/path, with a version 0 or base32 version 1 CID".to_string(),
+            });
+        }
+
+        let path = match url.path() {
+            "/" => "",
+            path => path,
+        };
+        Ok(Self {
+            cid: cid.to_string(),
+            path: path.to_string(),
+        })
+    }
+
+    pub fn cid(&self) -> &str {
+        &self.cid
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// `ipfs://<cid>/path`, naming the source whatever the gateway.
+    pub fn canonical(&self) -> String {
+        format!("ipfs://{}{}", self.cid, self.path)
+    }
+
+    /// URL of the content on `gateway`, a base URL such as
+    /// `https://cloudflare-ipfs.com/ipfs/`.
+    pub fn gateway_url(&self, gateway: &str) -> String {
+        format!(
+            "{}/{}{}",
+            gateway.trim_end_matches('/'),
+            self.cid,
+            self.path
+        )
+    }
+}
+
+/// Downloads `path` from the configured gateways in turn, returning the
+/// first success, or the failure of the last gateway tried. A source that
+/// is too large isn't retried, as it is as large on every gateway.
+pub async fn fetch(
+    path: &IpfsPath,
+    state: &AppState,
+    context: &FetchContext,
+) -> AppResult<Vec<u8>> {
+    let config = state.config();
+    let mut gateways = config.ipfs.gateways().peekable();
+    let mut failure = AppError::UnsupportedUrlScheme {
+        scheme: "ipfs".to_string(),
+        reason: "IPFS sources are disabled unless IPFS_GATEWAY is set".to_string(),
+    };
+    while let Some(gateway) = gateways.next() {
+        let url = path.gateway_url(gateway);
+        match fetch_url(&url, state, context).await {
+            Ok(bytes) => return Ok(bytes),
+            Err(err @ AppError::SourceTooLargeBytes { .. }) => return Err(err),
+            Err(err) => {
+                if gateways.peek().is_some() {
+                    warn!(
+                        "Failed to fetch {} from {gateway}, trying the fallback gateway: {err}",
+                        path.canonical()
+                    );
+                }
+                failure = err;
+            }
+        }
+    }
+    Err(failure)
+}
+
+/// Whether `cid` is a version 0 CID, or a version 1 CID in base32.
+pub fn is_valid_cid(cid: &str) -> bool {
+    if cid.len() == 46 && cid.starts_with("Qm") {
+        return decode(cid, BASE58_ALPHABET, decode_base58)
+            .is_some_and(|bytes| bytes.len() == 34 && bytes[..2] == [SHA2_256, 32]);
+    }
+    match cid.strip_prefix('b') {
+        Some(encoded) => {
+            decode(encoded, BASE32_ALPHABET, decode_base32).is_some_and(|bytes| is_cid_v1(&bytes))
+        }
+        None => false,
+    }
+}
+
+/// Checks `encoded` only uses `alphabet` before decoding it.
+fn decode(encoded: &str, alphabet: &[u8], decoder: fn(&[u8]) -> Vec<u8>) -> Option<Vec<u8>> {
+    let digits = encoded
+        .bytes()
+        .map(|c| alphabet.iter().position(|&a| a == c).map(|d| d as u8))
+        .collect::<Option<Vec<u8>>>()?;
+    (!digits.is_empty()).then(|| decoder(&digits))
+}
+
+fn decode_base58(digits: &[u8]) -> Vec<u8> {
+    // Accumulated little-endian, then reversed
+    let mut bytes: Vec<u8> = Vec::new();
+    for &digit in digits {
+        let mut carry = u32::from(digit);
+        for byte in bytes.iter_mut() {
+            carry += u32::from(*byte) * 58;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push(carry as u8);
+            carry >>= 8;
+        }
+    }
+    // Each leading zero digit stands for a zero byte
+    let zeros = digits.iter().take_while(|&&digit| digit == 0).count();
+    bytes.resize(bytes.len() + zeros, 0);
+    bytes.reverse();
+    bytes
+}
+
+fn decode_base32(digits: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(digits.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for &digit in digits {
+        buffer = (buffer << 5) | u32::from(digit);
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    bytes
+}
+
+/// Whether `bytes` are a version 1 CID: the version, the content codec,
+/// then a multihash whose digest is as long as it says.
+fn is_cid_v1(bytes: &[u8]) -> bool {
+    let mut rest = bytes;
+    let mut next = || {
+        let (value, len) = read_varint(rest)?;
+        rest = &rest[len..];
+        Some(value)
+    };
+    let (Some(1), Some(_codec), Some(_hash), Some(digest_len)) = (next(), next(), next(), next())
+    else {
+        return false;
+    };
+    digest_len > 0 && rest.len() as u64 == digest_len
+}
+
+/// Reads an unsigned LEB128 varint, returning it and its length.
+fn read_varint(bytes: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0u64;
+    for (i, &byte) in bytes.iter().enumerate().take(9) {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
@@ -0,0 +1,227 @@
+//! Asynchronous jobs, for transformations too large or slow for an
+//! interactive request: `POST /img-optimizer/v1/jobs` takes the parameters
+//! of `GET /img-optimizer/v1/img` and answers right away with a job id, the
+//! image is processed into the cache in the background, and
+//! `GET /img-optimizer/v1/jobs/{id}` reports how the job went.
+//!
+//! Jobs are kept in memory by a [`JobStore`], for `jobs.ttl_secs`.
+
+use crate::config::{ErrorDetail, JobsConfig};
+use crate::error::{AppError, AppResult};
+use crate::metrics::PhaseTimings;
+use crate::{process_image_request, AppState, ImageOutput, ImageParams};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::Instrument;
+
+/// Where a job is at.
+#[derive(Debug, Clone)]
+pub enum JobStatus {
+    Pending,
+    Succeeded(JobResult),
+    Failed(AppError),
+}
+
+/// The image a job produced, now in the cache.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobResult {
+    /// URL serving the image, from the cache.
+    pub url: String,
+    /// Cache key, which is also the ETag, of the image.
+    pub cache_key: String,
+    pub content_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+}
+
+impl JobStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Pending => "pending",
+            JobStatus::Succeeded(_) => "succeeded",
+            JobStatus::Failed(_) => "failed",
+        }
+    }
+
+    /// Body of `GET /img-optimizer/v1/jobs/{id}`. The error of a failed
+    /// job is a ProblemDetails object, as detailed as `error_detail` allows.
+    pub fn to_json(&self, id: &str, error_detail: ErrorDetail) -> serde_json::Value {
+        let mut body = serde_json::json!({ "id": id, "status": self.as_str() });
+        match self {
+            JobStatus::Pending => {}
+            JobStatus::Succeeded(result) => {
+                if let (Some(body), serde_json::Value::Object(result)) =
+                    (body.as_object_mut(), serde_json::json!(result))
+                {
+                    body.extend(result);
+                }
+            }
+            JobStatus::Failed(err) => {
+                let details = match error_detail {
+                    ErrorDetail::Full => err.to_response(),
+                    ErrorDetail::Minimal => {
+                        tracing::warn!(job = %id, "{err}");
+                        err.to_minimal_response(id)
+                    }
+                };
+                body["error"] = serde_json::json!(details);
+            }
+        }
+        body
+    }
+}
+
+struct Entry {
+    status: JobStatus,
+    expires_at: Instant,
+}
+
+/// Jobs in memory, each dropped `ttl` after it was submitted or, once
+/// done, after it finished.
+pub struct JobStore {
+    ttl: Duration,
+    max_pending: usize,
+    jobs: Mutex<HashMap<String, Entry>>,
+}
+
+impl std::fmt::Debug for JobStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JobStore")
+            .field("ttl", &self.ttl)
+            .field("max_pending", &self.max_pending)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for JobStore {
+    fn default() -> Self {
+        Self::from_config(&JobsConfig::default())
+    }
+}
+
+impl JobStore {
+    /// Keeps jobs for `ttl`, with at most `max_pending` of them pending.
+    pub fn new(ttl: Duration, max_pending: usize) -> Self {
+        Self {
+            ttl,
+            max_pending,
+            jobs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn from_config(config: &JobsConfig) -> Self {
+        Self::new(Duration::from_secs(config.ttl_secs), config.max_pending)
+    }
+
+    /// Adds a pending job and returns its id, or fails with `Overloaded`
+    /// when `max_pending` jobs are pending already.
+    pub fn create(&self) -> AppResult<String> {
+        let mut jobs = self.lock();
+        let now = Instant::now();
+        jobs.retain(|_, entry| entry.expires_at > now);
+        let pending = jobs
+            .values()
+            .filter(|entry| matches!(entry.status, JobStatus::Pending))
+            .count();
+        if pending >= self.max_pending {
+            return Err(AppError::Overloaded {
+                retry_after_secs: 1,
+                unavailable: false,
+            });
+        }
+
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        jobs.insert(
+            id.clone(),
+            Entry {
+                status: JobStatus::Pending,
+                expires_at: now + self.ttl,
+            },
+        );
+        Ok(id)
+    }
+
+    /// Records the outcome of job `id`, unless it expired meanwhile.
+    pub fn finish(&self, id: &str, status: JobStatus) {
+        let mut jobs = self.lock();
+        let now = Instant::now();
+        if let Some(entry) = jobs.get_mut(id).filter(|entry| entry.expires_at > now) {
+            entry.status = status;
+            entry.expires_at = now + self.ttl;
+        }
+    }
+
+    /// Status of job `id`, if it exists and has not expired.
+    pub fn get(&self, id: &str) -> Option<JobStatus> {
+        let jobs = self.lock();
+        jobs.get(id)
+            .filter(|entry| entry.expires_at > Instant::now())
+            .map(|entry| entry.status.clone())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Entry>> {
+        // A panic while holding the lock leaves the map consistent
+        self.jobs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// URL of the image a job's `query` produces: `public_url` when configured,
+/// the origin the request was made to otherwise, followed by the path of
+/// the image route next to `jobs_path`.
+pub fn image_url(
+    public_url: Option<&str>,
+    request_origin: &str,
+    jobs_path: &str,
+    query: &str,
+) -> String {
+    let base = public_url.unwrap_or(request_origin).trim_end_matches('/');
+    let prefix = jobs_path.strip_suffix("/jobs").unwrap_or(jobs_path);
+    format!("{base}{prefix}/img?{query}")
+}
+
+/// Validates `params` and starts processing them in the background, as
+/// `GET /img-optimizer/v1/img` would, so the image ends up in the cache.
+/// `url` is where the image is served once done. Returns the job's id.
+pub fn submit(params: ImageParams, url: String, state: &AppState) -> AppResult<String> {
+    let validated = params.clone().validate(&state.config().limits)?;
+    if validated.source.is_none() {
+        return Err(AppError::MissingRequiredParameter {
+            param: "src".to_string(),
+        });
+    }
+
+    let id = state.jobs.create()?;
+    let job = {
+        let (id, state) = (id.clone(), state.clone());
+        async move {
+            let mut timings = PhaseTimings::default();
+            let status = match process_image_request(params, &state, None, &mut timings).await {
+                Ok(ImageOutput::Image {
+                    content_type,
+                    etag,
+                    metadata,
+                    ..
+                }) => JobStatus::Succeeded(JobResult {
+                    url,
+                    cache_key: etag,
+                    content_type,
+                    width: metadata.map(|metadata| metadata.width),
+                    height: metadata.map(|metadata| metadata.height),
+                }),
+                // Only answered to requests with `If-None-Match`
+                Ok(ImageOutput::NotModified { .. }) => {
+                    JobStatus::Failed(AppError::InternalServerError)
+                }
+                Err(err) => JobStatus::Failed(err),
+            };
+            state.jobs.finish(&id, status);
+        }
+    };
+    tokio::spawn(job.instrument(tracing::info_span!("job", id = %id)));
+    Ok(id)
+}
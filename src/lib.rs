@@ -1,35 +1,313 @@
+//! Image optimizer core: parameters, fetching, processing, caching and
+//! errors, usable from any HTTP stack. The actix-web server (handlers,
+//! middleware, `ResponseError`) is behind the default `actix` feature.
+
+#[cfg(feature = "runtime")]
+pub mod audit;
+pub mod auth;
+#[cfg(feature = "axum")]
+pub mod axum_service;
+pub mod bundle;
+#[cfg(feature = "runtime")]
 pub mod cache;
+pub mod cli;
+pub mod compare;
+pub mod config;
+pub mod data_url;
 pub mod error;
+#[cfg(feature = "runtime")]
+pub mod fetch;
+#[cfg(feature = "runtime")]
+pub mod host_limits;
 pub mod image_processor;
+pub mod imgix;
+#[cfg(feature = "runtime")]
+pub mod ipfs;
+#[cfg(feature = "runtime")]
+pub mod jobs;
+#[cfg(feature = "runtime")]
+pub mod limiter;
+#[cfg(feature = "runtime")]
+pub mod local_source;
+pub mod logging;
+pub mod metrics;
+#[cfg(feature = "runtime")]
+pub mod optimizer;
+#[cfg(feature = "runtime")]
+pub mod origin;
+pub mod path_options;
+#[cfg(feature = "runtime")]
+pub mod pregen;
+pub mod range;
+#[cfg(feature = "s3-source")]
+pub mod s3;
+#[cfg(feature = "runtime")]
+pub mod self_check;
+#[cfg(feature = "actix")]
+mod server;
+pub mod sniff;
+pub mod srcset;
+pub mod stats;
+#[cfg(feature = "runtime")]
+pub mod storage;
+#[cfg(feature = "otel")]
+pub mod telemetry;
+#[cfg(feature = "runtime")]
+pub mod test_support;
+#[cfg(feature = "actix")]
+pub mod tls;
+pub mod transform_chain;
+pub mod warnings;
+#[cfg(feature = "runtime")]
+pub mod worker_pool;
+
+#[cfg(feature = "runtime")]
+pub use optimizer::{CacheStatus, OptimizeOptions, OptimizedImage, Optimizer};
+#[cfg(feature = "actix")]
+pub use server::*;
 
 use error::{AppError, AppResult};
 use once_cell::sync::Lazy;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use url::Url;
+use std::borrow::Cow;
 
+#[cfg(feature = "runtime")]
 use {
-    actix_web::{web, HttpResponse, Result},
-    cache::ImageCache,
-    image_processor::ImageProcessor,
-    std::sync::Arc,
+    arc_swap::ArcSwap,
+    audit::{AuditLog, AuditRecord},
+    auth::ApiKeys,
+    cache::{ImageCache, ImageMetadata, PendingWrites},
+    config::{AppConfig, CacheWriteMode, ConfigChange, FetchConfig},
+    fetch::HttpFetcher,
+    host_limits::HostLimiter,
+    image_processor::{ImageProcessor, ProcessedImage},
+    jobs::JobStore,
+    limiter::{JobClass, ProcessingLimiter, ProcessingPermit},
+    log::{info, warn},
+    metrics::{Metrics, Phase, PhaseTimings},
+    origin::{OriginPolicies, OriginPolicy},
+    sniff::DetectedFormat,
+    stats::Stats,
+    std::{
+        path::PathBuf,
+        sync::{atomic::AtomicBool, Arc},
+        time::Instant,
+    },
+    storage::ImageStorage,
     tokio::sync::RwLock,
+    tracing::Instrument,
+    url::Url,
+    warnings::{Warning, Warnings},
+    worker_pool::WorkerPool,
+};
+use {
+    config::{AlphaPolicy, Limits},
+    image_processor::{Fit, OutputFormat, ProcessingPlan},
+    transform_chain::TransformStep,
 };
 
 pub const MAX_WIDTH: u32 = 3840;
+pub const MAX_HEIGHT: u32 = 3840;
 pub const DEFAULT_QUALITY: u8 = 75;
 pub const MAX_IMAGE_SIZE: usize = 50 * 1024 * 1024; // 50MB
+pub const MAX_BUNDLE_SIZE: usize = 100 * 1024 * 1024; // 100MB
+/// Largest box an output may be asked to fill, in pixels: 3840×2160.
+pub const MAX_OUTPUT_PIXELS: u64 = 3840 * 2160;
+/// Longest `src` or `srcb64` accepted, in bytes, as most CDNs cap URLs.
+pub const MAX_SRC_LENGTH: usize = 8 * 1024;
+/// Longest query string accepted by image routes, in bytes.
+pub const MAX_QUERY_LENGTH: usize = 16 * 1024;
+#[cfg(any(feature = "actix", feature = "axum"))]
+const MAX_DOWNLOAD_FILENAME_LEN: usize = 128;
+/// Largest source image decoded, in pixels, so a small file cannot expand
+/// into gigabytes of memory.
+#[cfg(not(target_arch = "wasm32"))]
+pub const MAX_SOURCE_PIXELS: u64 = 100_000_000;
+/// Lower on wasm32, whose runtimes cap memory at a few hundred megabytes:
+/// a 25 megapixel source decodes to 100 MB of RGBA.
+#[cfg(target_arch = "wasm32")]
+pub const MAX_SOURCE_PIXELS: u64 = 25_000_000;
 
 pub static IMAGE_ID_REGEX: Lazy<Regex> =
     Lazy::new(|| Regex::new(r"^([a-f0-9]{32})\.(\w+)$").expect("Failed to compile regex"));
 
-#[derive(Debug, Deserialize)]
+/// Request parameters as received, numbers included, so that values that do
+/// not parse are reported like any other invalid value by
+/// [`ImageParams::validate`].
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct ImageParams {
+    /// Also accepted as `url`, the name Next.js loaders use.
+    #[serde(alias = "url")]
     pub src: Option<String>,
-    pub w: Option<u32>,
-    pub q: Option<u8>,
+    /// Base64url-encoded alternative to `src`, immune to integrators
+    /// forgetting to percent-encode the source URL.
+    pub srcb64: Option<String>,
+    pub w: Option<String>,
+    pub h: Option<String>,
+    /// `contain` (default), `cover` or `pad`, when both `w` and `h` are given.
+    pub fit: Option<String>,
+    /// Hex background color (`RGB`, `RRGGBB` or `RRGGBBAA`) for padding and
+    /// for transparency in JPEG output.
+    pub bg: Option<String>,
+    pub q: Option<String>,
     pub f: Option<String>,
+    /// Chained transformations applied before the others, see
+    /// [`transform_chain`].
+    pub tx: Option<String>,
+    /// Download filename; sets `Content-Disposition: attachment`. Not part
+    /// of the cache key since it doesn't affect the bytes.
+    pub dl: Option<String>,
+    /// `flatten`, `warn` or `reject`, overriding `limits.alpha_policy`. Not
+    /// part of the cache key either.
+    pub alpha: Option<String>,
+}
+
+/// Query of the Next.js-compatible `/_next/image` route. Unlike
+/// [`ImageParams`], every parameter is required, as in Next.js's optimizer.
+#[derive(Debug, Deserialize)]
+pub struct NextImageParams {
+    pub url: Option<String>,
+    pub w: Option<String>,
+    pub q: Option<String>,
+}
+
+/// [`ImageParams`] checked against the limits, ready for the pipeline.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidatedParams {
+    /// Source URL from `src`, or decoded from `srcb64`. `None` for images
+    /// sent in the request or read from storage.
+    pub source: Option<String>,
+    pub plan: ProcessingPlan,
+    /// What to do should the output lose the transparency of the source.
+    pub alpha: AlphaPolicy,
+}
+
+/// What a `GET` for [`ImageParams`] turns into, decided from the parameters
+/// alone before anything is fetched. Shared by the HTTP adapters so the same
+/// URL behaves the same whichever serves it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PreRouteDecision {
+    /// Send the client to the source itself: SVGs are served as they are.
+    Redirect(String),
+    /// Run the image pipeline.
+    Process,
+}
+
+/// Decides how to serve `params`, see [`PreRouteDecision`]. Invalid
+/// parameters are left for the pipeline to report.
+pub fn pre_route(params: &ImageParams) -> PreRouteDecision {
+    match params.source() {
+        Ok(src) if is_svg_source(&src) => PreRouteDecision::Redirect(src.into_owned()),
+        _ => PreRouteDecision::Process,
+    }
+}
+
+/// Whether `src` names an SVG image, judging by the extension of its path
+/// in any case. The query and fragment don't count: `/a.svg?v=3` is an SVG,
+/// `/a.png?as=.svg` isn't.
+pub fn is_svg_source(src: &str) -> bool {
+    let path = match url::Url::parse(src) {
+        Ok(url) => url.path().to_string(),
+        Err(_) => src.split(['?', '#']).next().unwrap_or_default().to_string(),
+    };
+    path.to_ascii_lowercase().ends_with(".svg")
+}
+
+/// Value of `f` choosing the output format from the request's `Accept`
+/// header, see [`negotiate_format`].
+pub const AUTO_FORMAT: &str = "auto";
+
+/// Client hints asked for with `Accept-CH` when `features.client_hints` is
+/// on, see [`ImageParams::apply_client_hints`].
+pub const CLIENT_HINTS: &str = "Sec-CH-DPR, Sec-CH-Width";
+
+/// Largest device pixel ratio `Sec-CH-DPR` scales sizes by.
+pub const MAX_CLIENT_HINT_DPR: f64 = 4.0;
+
+/// Formats `f=auto` picks from, most preferred first. JPEG and PNG are left
+/// to the choice made without `f`, which keeps transparency.
+const NEGOTIATED_FORMATS: [OutputFormat; 1] = [OutputFormat::WebP];
+
+/// Output format of `f=auto` for a client sending `accept`: the preferred
+/// format among `available` that it lists explicitly. Wildcards don't count,
+/// as browsers send them whatever they decode. `None` keeps the format
+/// chosen without `f`.
+pub fn negotiate_format(accept: Option<&str>, available: &[OutputFormat]) -> Option<OutputFormat> {
+    let accept = accept?;
+    NEGOTIATED_FORMATS
+        .into_iter()
+        .filter(|format| available.contains(format))
+        .find(|format| accepts(accept, format.content_type()))
+}
+
+/// Whether an `Accept` header lists `media_type` with a non-zero quality.
+fn accepts(accept: &str, media_type: &str) -> bool {
+    accept.split(',').any(|range| {
+        let mut parts = range.split(';');
+        let name = parts.next().unwrap_or_default().trim();
+        name.eq_ignore_ascii_case(media_type)
+            && parts.all(|param| match param.split_once('=') {
+                Some((key, value)) if key.trim().eq_ignore_ascii_case("q") => {
+                    value.trim().parse::<f32>().map_or(true, |q| q > 0.0)
+                }
+                _ => true,
+            })
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ErrorListParams {
+    pub format: Option<String>,
+}
+
+#[cfg(feature = "runtime")]
+/// Outcome of the image pipeline. `etag` is the opaque value of the output's
+/// strong entity tag, without quotes.
+#[derive(Debug)]
+pub enum ImageOutput {
+    Image {
+        data: bytes::Bytes,
+        content_type: String,
+        etag: String,
+        /// Reported as `X-Image-Width`, `X-Image-Height`,
+        /// `X-Original-Size` and `X-Alpha-Flattened`. `None` for stored
+        /// originals whose header does not give their dimensions.
+        metadata: Option<ImageMetadata>,
+        /// Ways the image falls short of the request, reported as
+        /// `X-Optimizer-Warnings`.
+        warnings: Warnings,
+    },
+    /// The copy the client holds, per its `If-None-Match`, is still current.
+    NotModified { etag: String },
+}
+
+/// Entity tags of the copies a client holds, from its `If-None-Match`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IfNoneMatch {
+    /// `*`: any current representation.
+    Any,
+    /// Opaque tag values, weak or strong.
+    Tags(Vec<String>),
+}
+
+impl IfNoneMatch {
+    /// Weak comparison, as RFC 9110 mandates for `If-None-Match`.
+    pub fn matches(&self, etag: &str) -> bool {
+        match self {
+            IfNoneMatch::Any => true,
+            IfNoneMatch::Tags(tags) => tags.iter().any(|tag| tag == etag),
+        }
+    }
+}
+
+/// Headers of an image request deciding how much of the image is sent: all
+/// of it, none (`304`) or a byte range (`206`).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Conditions {
+    pub if_none_match: Option<IfNoneMatch>,
+    pub range: Option<range::RangeRequest>,
 }
 
 #[derive(Debug, Serialize)]
@@ -39,186 +317,1566 @@ pub struct ErrorResponse {
     pub message: Option<String>,
 }
 
+#[cfg(feature = "runtime")]
 #[derive(Clone)]
 pub struct AppState {
     pub cache: Arc<RwLock<ImageCache>>,
+    /// Deferred cache writes, waited for on shutdown.
+    pub pending_writes: Arc<PendingWrites>,
+    pub storage: Arc<ImageStorage>,
+    /// Client of the readiness canary.
     pub client: reqwest::Client,
+    /// Downloads http(s) sources.
+    pub fetcher: Arc<dyn HttpFetcher>,
+    /// Bounds concurrent fetches to each origin host. Replaced when a reload
+    /// changes the host limits, see [`AppState::reload_config`].
+    pub hosts: Arc<ArcSwap<HostLimiter>>,
+    /// Fetch profiles of origins, from `config.origin`.
+    pub origins: Arc<ArcSwap<OriginPolicies>>,
+    pub api_keys: Arc<ApiKeys>,
+    /// Counters of the hot path, updated without locks.
+    pub stats: Arc<Stats>,
+    pub metrics: Arc<Metrics>,
+    /// Bounds concurrent fetching and processing; cache hits bypass it.
+    pub limiter: Arc<ProcessingLimiter>,
+    /// Threads decoding, resizing and encoding images.
+    pub workers: Arc<WorkerPool>,
+    /// Asynchronous jobs of `/img-optimizer/v1/jobs`.
+    pub jobs: Arc<JobStore>,
+    /// Audit log of the sources fetched, when `audit.dir` is set.
+    pub audit: Option<Arc<AuditLog>>,
+    /// Set once a shutdown signal is received so readiness checks fail while
+    /// in-flight requests drain.
+    pub shutting_down: Arc<AtomicBool>,
+    /// Read through [`AppState::config`], so that requests see the
+    /// configuration current when they read it.
+    pub config: Arc<ArcSwap<AppConfig>>,
 }
 
-pub async fn health_check() -> Result<HttpResponse> {
-    Ok(HttpResponse::Ok().json(serde_json::json!({
-        "status": "ok",
-        "service": "img-optimizer"
-    })))
-}
+#[cfg(feature = "runtime")]
+impl AppState {
+    /// The current configuration.
+    pub fn config(&self) -> Arc<AppConfig> {
+        self.config.load_full()
+    }
+
+    pub fn origins(&self) -> Arc<OriginPolicies> {
+        self.origins.load_full()
+    }
+
+    pub fn hosts(&self) -> Arc<HostLimiter> {
+        self.hosts.load_full()
+    }
+
+    /// Swaps in the settings of `new` that may change while serving, see
+    /// [`config::RELOADABLE_SETTINGS`], once it validates. Other settings
+    /// keep their current value until a restart, with a warning. Requests
+    /// in flight finish with the configuration they started with.
+    pub fn reload_config(&self, new: AppConfig) -> anyhow::Result<Vec<ConfigChange>> {
+        new.validate()?;
+        let current = self.config();
+        let (config, changes) = current.reloaded(&new)?;
+        config.validate()?;
+
+        let limits_changed = changes.iter().any(|change| {
+            change.reloadable && matches!(change.path.split('.').next(), Some("fetch" | "origin"))
+        });
+        if limits_changed {
+            let origins = Arc::new(OriginPolicies::from_config(&config));
+            self.hosts.store(Arc::new(
+                HostLimiter::from_config(&config.fetch).with_origins(origins.clone()),
+            ));
+            self.origins.store(origins);
+        }
+        self.config.store(Arc::new(config));
 
-pub async fn list_errors() -> Result<HttpResponse> {
-    let errors = AppError::list_all_errors();
-    Ok(HttpResponse::Ok().json(serde_json::json!({
-        "errors": errors,
-        "total": errors.len()
-    })))
+        if changes.is_empty() {
+            info!("Configuration reloaded, nothing changed");
+        }
+        for change in &changes {
+            if change.reloadable {
+                info!("Configuration reloaded: {change}");
+            } else {
+                warn!("Configuration change needs a restart, ignored: {change}");
+            }
+        }
+        Ok(changes)
+    }
 }
 
+#[cfg(feature = "runtime")]
+/// Runs the pipeline, recording phase durations into `timings` and the
+/// outcome into the metrics.
 pub async fn process_image_request(
     params: ImageParams,
     state: &AppState,
-) -> AppResult<(Vec<u8>, String)> {
-    let src = params
-        .src
-        .as_ref()
+    if_none_match: Option<&IfNoneMatch>,
+    timings: &mut PhaseTimings,
+) -> AppResult<ImageOutput> {
+    let _in_flight = state.stats.track_in_flight();
+    let format = params.f.clone();
+    let audit = start_audit(state, &params);
+    let result = run_pipeline(params, state, if_none_match, timings).await;
+    record_outcome(state, format.as_deref(), timings, &result);
+    if let Some(mut record) = audit {
+        match &result {
+            Ok(ImageOutput::Image { data, .. }) => record.bytes_out = data.len() as u64,
+            Ok(ImageOutput::NotModified { .. }) => record.status = 304,
+            Err(err) => record.fail(err),
+        }
+        finish_audit(state, record, timings);
+    }
+    result
+}
+
+#[cfg(feature = "runtime")]
+/// Same as [`process_image_request`] for an image uploaded in the request
+/// body, cached under the hash of its content.
+pub async fn process_upload_request(
+    image_data: Vec<u8>,
+    params: ImageParams,
+    state: &AppState,
+    if_none_match: Option<&IfNoneMatch>,
+    timings: &mut PhaseTimings,
+) -> AppResult<ImageOutput> {
+    let _in_flight = state.stats.track_in_flight();
+    let format = params.f.clone();
+    let result = async {
+        let validated = params.validate(&state.config().limits)?;
+        let identity = content_identity(&image_data);
+        let output = transform(
+            ImageSource::Bytes(image_data),
+            &identity,
+            &validated.plan,
+            state,
+            if_none_match,
+            timings,
+        )
+        .await?;
+        apply_alpha_policy(output, validated.alpha)
+    }
+    .await;
+    record_outcome(state, format.as_deref(), timings, &result);
+    result
+}
+
+#[cfg(feature = "runtime")]
+/// Serves an image from internal storage: the original as stored, or
+/// transformed (and cached) when any transformation parameter is given.
+pub async fn process_stored_request(
+    id: &str,
+    content_type: &str,
+    params: ImageParams,
+    state: &AppState,
+    if_none_match: Option<&IfNoneMatch>,
+    timings: &mut PhaseTimings,
+) -> AppResult<ImageOutput> {
+    let _in_flight = state.stats.track_in_flight();
+    let format = params.f.clone();
+    let result = async {
+        if !params.transforms() {
+            return serve_original(id, content_type, state, if_none_match, timings).await;
+        }
+
+        let validated = params.validate(&state.config().limits)?;
+        let output = transform(
+            ImageSource::Stored(id),
+            &format!("storage:{id}"),
+            &validated.plan,
+            state,
+            if_none_match,
+            timings,
+        )
+        .await?;
+        apply_alpha_policy(output, validated.alpha)
+    }
+    .await;
+    record_outcome(state, format.as_deref(), timings, &result);
+    result
+}
+
+#[cfg(feature = "runtime")]
+/// Stored originals are immutable, their content hash id is a strong ETag.
+async fn serve_original(
+    id: &str,
+    content_type: &str,
+    state: &AppState,
+    if_none_match: Option<&IfNoneMatch>,
+    timings: &mut PhaseTimings,
+) -> AppResult<ImageOutput> {
+    let etag = id.to_string();
+    if if_none_match.is_some_and(|header| header.matches(&etag)) && state.storage.contains(id) {
+        return Ok(ImageOutput::NotModified { etag });
+    }
+
+    let data = timings
+        .time_async(Phase::Fetch, state.storage.get(id))
+        .await
+        .ok_or_else(|| AppError::ImageNotFound { id: id.to_string() })?;
+    // Operators may place files whose extension does not match their
+    // content. They are served as what they are, unless that isn't a raster
+    // image, which a browser could run as a document from this origin
+    let detected = match DetectedFormat::detect(&data) {
+        Some(DetectedFormat::Svg) => None,
+        Some(format) => Some(format.content_type().to_string()),
+        None => image::guess_format(&data)
+            .is_ok()
+            .then(|| content_type.to_string()),
+    };
+    let Some(content_type) = detected else {
+        warn!("Stored image {id} is not a raster image, refusing to serve it");
+        return Err(AppError::InvalidImageData);
+    };
+    let metadata = ImageProcessor::dimensions(&data).map(|(width, height)| ImageMetadata {
+        width,
+        height,
+        original_width: width,
+        original_height: height,
+        alpha_flattened: false,
+        upscale_refused: false,
+    });
+    Ok(ImageOutput::Image {
+        data: data.into(),
+        content_type,
+        etag,
+        metadata,
+        warnings: Warnings::default(),
+    })
+}
+
+#[cfg(feature = "runtime")]
+fn record_outcome(
+    state: &AppState,
+    format: Option<&str>,
+    timings: &PhaseTimings,
+    result: &AppResult<ImageOutput>,
+) {
+    state.metrics.observe_timings(timings);
+    match result {
+        Ok(ImageOutput::Image {
+            content_type,
+            warnings,
+            ..
+        }) => {
+            if let Some(codes) = warnings.header_value() {
+                info!("Served a degraded image: {codes}");
+            }
+            state.metrics.record_success(content_type)
+        }
+        Ok(ImageOutput::NotModified { .. }) => state.metrics.record_not_modified(format),
+        Err(err) => state.metrics.record_error(format, err),
+    }
+}
+
+#[cfg(feature = "runtime")]
+/// Audit record of a request for the source of `params`, when the audit log
+/// is on and the source is remote.
+pub(crate) fn start_audit(state: &AppState, params: &ImageParams) -> Option<AuditRecord> {
+    state.audit.as_ref()?;
+    let src = params.source().ok()?;
+    AuditRecord::new(&src, audit::params_query(params))
+}
+
+#[cfg(feature = "runtime")]
+/// Queues `record`, its outcome set, along with the bytes read from the
+/// source.
+pub(crate) fn finish_audit(state: &AppState, mut record: AuditRecord, timings: &PhaseTimings) {
+    if let Some(log) = &state.audit {
+        record.bytes_in = timings.source_size().unwrap_or(0) as u64;
+        log.record(record);
+    }
+}
+
+#[cfg(feature = "runtime")]
+async fn run_pipeline(
+    params: ImageParams,
+    state: &AppState,
+    if_none_match: Option<&IfNoneMatch>,
+    timings: &mut PhaseTimings,
+) -> AppResult<ImageOutput> {
+    let ValidatedParams {
+        source,
+        plan,
+        alpha,
+    } = params.validate(&state.config().limits)?;
+    let src = source
+        .as_deref()
         .ok_or_else(|| AppError::MissingRequiredParameter {
             param: "src".to_string(),
         })?;
 
-    // Validate URL
-    let url = Url::parse(src).map_err(|_| AppError::InvalidImageUrl)?;
+    let (source, identity) = resolve_source(src, state)?;
+    let output = transform(source, &identity, &plan, state, if_none_match, timings).await?;
+    apply_alpha_policy(output, alpha)
+}
 
-    if url.scheme() != "http" && url.scheme() != "https" {
-        return Err(AppError::InvalidImageUrl);
+#[cfg(feature = "runtime")]
+/// Applies `policy` to `output`, which reports whether its transparency was
+/// flattened: refused, or reported only for [`AlphaPolicy::Warn`]. Checked
+/// on cache hits too, as the policy isn't part of the cache key.
+fn apply_alpha_policy(mut output: ImageOutput, policy: AlphaPolicy) -> AppResult<ImageOutput> {
+    if let ImageOutput::Image {
+        metadata: Some(metadata),
+        warnings,
+        ..
+    } = &mut output
+    {
+        metadata.alpha_flattened = check_alpha(policy, metadata.alpha_flattened)?;
+        if metadata.alpha_flattened {
+            warnings.insert(Warning::AlphaFlattened);
+        }
     }
+    Ok(output)
+}
+
+/// Whether to report that an output's transparency was `flattened`, under
+/// `policy`. Fails with [`AppError::TransparencyLost`] when it refuses that.
+pub fn check_alpha(policy: AlphaPolicy, flattened: bool) -> AppResult<bool> {
+    match (policy, flattened) {
+        (_, false) | (AlphaPolicy::Flatten, true) => Ok(false),
+        (AlphaPolicy::Warn, true) => Ok(true),
+        (AlphaPolicy::Reject, true) => Err(AppError::TransparencyLost),
+    }
+}
+
+#[cfg(feature = "runtime")]
+/// Where `src` is read from, and the identity naming it in cache keys:
+/// the URL itself, or the hash of the bytes of `data:` URLs.
+fn resolve_source<'a>(
+    src: &'a str,
+    state: &'a AppState,
+) -> AppResult<(ImageSource<'a>, Cow<'a, str>)> {
+    let url = Url::parse(src).map_err(|_| AppError::InvalidImageUrl)?;
+    if let Some(host) = url.host_str() {
+        tracing::Span::current().record("src_host", host);
+    }
+
+    let source = match url.scheme() {
+        "http" | "https" => ImageSource::Url(src),
+        "file" => match state.config().fetch.local_source_root.clone() {
+            Some(root) => ImageSource::File { src, root },
+            None => {
+                return Err(AppError::UnsupportedUrlScheme {
+                    scheme: "file".to_string(),
+                    reason: "local sources are disabled unless LOCAL_SOURCE_ROOT is set"
+                        .to_string(),
+                })
+            }
+        },
+        #[cfg(feature = "s3-source")]
+        "s3" => ImageSource::S3(src),
+        #[cfg(not(feature = "s3-source"))]
+        "s3" => {
+            return Err(AppError::UnsupportedUrlScheme {
+                scheme: "s3".to_string(),
+                reason: "this build does not include the s3-source feature".to_string(),
+            })
+        }
+        // Named by its canonical form, whatever the gateway
+        "ipfs" if state.config().ipfs.gateway.is_some() => {
+            let path = ipfs::IpfsPath::parse(src)?;
+            let identity = path.canonical();
+            if is_svg_source(&identity) {
+                return Err(AppError::InvalidImageFormat {
+                    format: "svg".to_string(),
+                });
+            }
+            return Ok((ImageSource::Ipfs(path), Cow::Owned(identity)));
+        }
+        "ipfs" => {
+            return Err(AppError::UnsupportedUrlScheme {
+                scheme: "ipfs".to_string(),
+                reason: "IPFS sources are disabled unless IPFS_GATEWAY is set".to_string(),
+            })
+        }
+        "data" => {
+            let image_data = data_url::decode(src, state.config().limits.max_image_size)?;
+            let identity = content_identity(&image_data);
+            return Ok((ImageSource::Bytes(image_data), Cow::Owned(identity)));
+        }
+        "blob" => {
+            return Err(AppError::UnsupportedUrlScheme {
+                scheme: "blob".to_string(),
+                reason: "blob URLs only exist inside the browser page that created them"
+                    .to_string(),
+            })
+        }
+        other => {
+            return Err(AppError::UnsupportedUrlScheme {
+                scheme: other.to_string(),
+                reason: "only http, https, s3, ipfs, file and data URLs are supported".to_string(),
+            })
+        }
+    };
 
     // SVG files are not processed in the core logic
-    if src.to_lowercase().ends_with(".svg") {
+    if is_svg_source(src) {
         return Err(AppError::InvalidImageFormat {
             format: "svg".to_string(),
         });
     }
 
-    // Parse parameters
-    let width = match params.w {
-        Some(w) if w == 0 || w > MAX_WIDTH => return Err(AppError::InvalidWidth { width: w }),
-        Some(w) => Some(w),
-        None => None,
+    Ok((source, Cow::Borrowed(src)))
+}
+
+#[cfg(feature = "runtime")]
+/// Request-scoped data sent along with origin fetches, so origins can
+/// correlate their logs with ours.
+#[derive(Debug, Clone, Default)]
+pub struct FetchContext {
+    /// ID of the request being handled, inbound or generated.
+    pub request_id: Option<String>,
+    /// Headers forwarded to the origin. With the `otel` feature, the trace
+    /// context of the fetch span is added when sending.
+    pub headers: reqwest::header::HeaderMap,
+    /// Origin profiles of the fetch, resolved from the configuration of the
+    /// fetch when unset.
+    pub origins: Option<Arc<OriginPolicies>>,
+}
+
+#[cfg(feature = "runtime")]
+impl FetchContext {
+    /// Context of the request currently being handled, forwarding its ID
+    /// under `fetch.request_id_header`.
+    pub fn current(config: &FetchConfig) -> Self {
+        let request_id = logging::current_request_id();
+        let mut headers = reqwest::header::HeaderMap::new();
+        if let Some(id) = &request_id {
+            if let (Ok(name), Ok(value)) = (
+                reqwest::header::HeaderName::from_bytes(config.request_id_header.as_bytes()),
+                reqwest::header::HeaderValue::from_str(id),
+            ) {
+                headers.insert(name, value);
+            }
+        }
+        Self {
+            request_id,
+            headers,
+            origins: None,
+        }
+    }
+}
+
+#[cfg(feature = "runtime")]
+/// Where the original image comes from.
+enum ImageSource<'a> {
+    Url(&'a str),
+    /// `file://` path, which must resolve within `root`.
+    File {
+        src: &'a str,
+        root: PathBuf,
+    },
+    /// `s3://bucket/key` object.
+    #[cfg(feature = "s3-source")]
+    S3(&'a str),
+    /// `ipfs://<cid>/path` content, fetched through the configured gateways.
+    Ipfs(ipfs::IpfsPath),
+    /// Bytes already in hand: uploads and `data:` URLs.
+    Bytes(Vec<u8>),
+    /// Id of an image in internal storage.
+    Stored(&'a str),
+}
+
+#[cfg(feature = "runtime")]
+impl ImageSource<'_> {
+    /// URL the image is read from, for sources that have one.
+    fn url(&self) -> Option<String> {
+        match self {
+            ImageSource::Url(src) | ImageSource::File { src, .. } => Some(src.to_string()),
+            #[cfg(feature = "s3-source")]
+            ImageSource::S3(src) => Some(src.to_string()),
+            ImageSource::Ipfs(path) => Some(path.canonical()),
+            ImageSource::Bytes(_) | ImageSource::Stored(_) => None,
+        }
+    }
+}
+
+impl ImageParams {
+    /// Source URL from `src`, or decoded from `srcb64`.
+    pub fn source(&self) -> AppResult<Cow<'_, str>> {
+        match (&self.src, &self.srcb64) {
+            (Some(_), Some(_)) => Err(AppError::ConflictingParameters {
+                first: "src".to_string(),
+                second: "srcb64".to_string(),
+            }),
+            (Some(src), None) => Ok(Cow::Borrowed(src)),
+            (None, Some(encoded)) => decode_base64url(encoded).map(Cow::Owned),
+            (None, None) => Err(AppError::MissingRequiredParameter {
+                param: "src".to_string(),
+            }),
+        }
+    }
+
+    /// Replaces `f=auto` with the format negotiated from `accept`, see
+    /// [`negotiate_format`]. Returns whether it did, so the response varies
+    /// with `Accept`.
+    pub fn resolve_auto_format(&mut self, accept: Option<&str>) -> bool {
+        if !self
+            .f
+            .as_deref()
+            .is_some_and(|f| f.eq_ignore_ascii_case(AUTO_FORMAT))
+        {
+            return false;
+        }
+        let available: Vec<_> = OutputFormat::available().collect();
+        self.f = negotiate_format(accept, &available).map(|format| format.name().to_string());
+        true
+    }
+
+    /// Sizes the output from client hints: `width` (`Sec-CH-Width`, in
+    /// device pixels) stands in for an omitted `w`, and `dpr` (`Sec-CH-DPR`)
+    /// scales a `w` or `h` given in CSS pixels. Sizes are capped by `limits`
+    /// rather than rejected, and values that aren't valid sizes are left for
+    /// validation to report. Returns whether the output depends on each hint,
+    /// as `(dpr, width)`, whether or not it was sent.
+    pub fn apply_client_hints(
+        &mut self,
+        dpr: Option<&str>,
+        width: Option<&str>,
+        limits: &Limits,
+    ) -> (bool, bool) {
+        let positive = |hint: Option<&str>| {
+            hint.and_then(|value| value.trim().parse::<f64>().ok())
+                .filter(|value| value.is_finite() && *value > 0.0)
+        };
+        let dpr = positive(dpr).map(|dpr| dpr.min(MAX_CLIENT_HINT_DPR));
+        let scale = |side: &mut Option<String>, max: u32| match side.as_deref().map(str::parse) {
+            Some(Ok(css)) if (1..=max).contains(&css) => {
+                if let Some(dpr) = dpr {
+                    let device = (f64::from(css) * dpr).round() as u32;
+                    *side = Some(device.clamp(1, max).to_string());
+                }
+                true
+            }
+            _ => false,
+        };
+
+        let depends_on_width = self.w.is_none();
+        let depends_on_dpr =
+            scale(&mut self.w, limits.max_width) | scale(&mut self.h, limits.max_height);
+        if depends_on_width {
+            if let Some(width) = positive(width) {
+                let width = (width.ceil() as u32).clamp(1, limits.max_width);
+                self.w = Some(width.to_string());
+            }
+        }
+        (depends_on_dpr, depends_on_width)
+    }
+
+    /// Whether any parameter changes the image, as opposed to serving it as is.
+    #[cfg(feature = "runtime")]
+    fn transforms(&self) -> bool {
+        self.w.is_some()
+            || self.h.is_some()
+            || self.fit.is_some()
+            || self.bg.is_some()
+            || self.q.is_some()
+            || self.f.is_some()
+            || self.tx.is_some()
+    }
+
+    /// Parses and checks every parameter against `limits`, reporting all
+    /// invalid ones at once. Values that do not parse are errors, never
+    /// ignored, whichever entry point received them.
+    pub fn validate(self, limits: &Limits) -> AppResult<ValidatedParams> {
+        let mut errors = Vec::new();
+
+        // Checked before anything decodes, hashes or logs the source
+        let too_long = [("src", &self.src), ("srcb64", &self.srcb64)]
+            .into_iter()
+            .find_map(|(param, value)| {
+                let length = value.as_deref()?.len();
+                (length > limits.max_src_length).then(|| AppError::ParameterTooLong {
+                    param: param.to_string(),
+                    length,
+                    max: limits.max_src_length,
+                })
+            });
+        let source = match (&self.src, &self.srcb64, too_long) {
+            (_, _, Some(err)) => {
+                errors.push(err);
+                None
+            }
+            (None, None, None) => None,
+            _ => self
+                .source()
+                .map(Cow::into_owned)
+                .map_err(|e| errors.push(e))
+                .ok(),
+        };
+        let width = self.w.as_deref().and_then(|w| match w.parse() {
+            Ok(width) if width == 0 || width > limits.max_width => {
+                errors.push(AppError::InvalidWidth {
+                    width,
+                    max: limits.max_width,
+                });
+                None
+            }
+            Ok(width) => Some(width),
+            Err(_) => {
+                errors.push(not_a_number("w", w, limits.max_width));
+                None
+            }
+        });
+        let height = self.h.as_deref().and_then(|h| match h.parse() {
+            Ok(height) if height == 0 || height > limits.max_height => {
+                errors.push(AppError::InvalidHeight {
+                    height,
+                    max: limits.max_height,
+                });
+                None
+            }
+            Ok(height) => Some(height),
+            Err(_) => {
+                errors.push(not_a_number("h", h, limits.max_height));
+                None
+            }
+        });
+        let fit = match self.fit.as_deref() {
+            Some(fit) => Fit::parse(fit).unwrap_or_else(|| {
+                errors.push(AppError::InvalidParameterValue {
+                    param: "fit".to_string(),
+                    value: fit.to_string(),
+                    expected: "one of contain, cover or pad".to_string(),
+                });
+                Fit::default()
+            }),
+            None => Fit::default(),
+        };
+        let background = match self.bg.as_deref() {
+            Some(bg) => parse_color(bg).or_else(|| {
+                errors.push(AppError::InvalidParameterValue {
+                    param: "bg".to_string(),
+                    value: bg.to_string(),
+                    expected: "a hex color (RGB, RRGGBB or RRGGBBAA)".to_string(),
+                });
+                None
+            }),
+            None => None,
+        };
+        let format = self.f.as_deref().and_then(|f| {
+            OutputFormat::parse(f).or_else(|| {
+                errors.push(AppError::InvalidImageFormat {
+                    format: f.to_string(),
+                });
+                None
+            })
+        });
+        let default_quality = limits.quality_for(format);
+        let quality = match self.q.as_deref().map(|q| (q, q.parse::<u32>())) {
+            Some((_, Ok(q))) if (1..=100).contains(&q) => q as u8,
+            Some((_, Ok(q))) => {
+                errors.push(AppError::InvalidQuality { quality: q });
+                default_quality
+            }
+            Some((q, Err(_))) => {
+                errors.push(not_a_number("q", q, 100));
+                default_quality
+            }
+            None => default_quality,
+        };
+
+        let steps = match self.tx.as_deref() {
+            Some(tx) => transform_chain::parse(tx, limits).unwrap_or_else(|err| {
+                match err {
+                    AppError::ValidationFailed { errors: failed } => errors.extend(failed),
+                    err => errors.push(err),
+                }
+                Vec::new()
+            }),
+            None => Vec::new(),
+        };
+        let alpha = match self.alpha.as_deref() {
+            Some(alpha) => alpha.parse().unwrap_or_else(|_| {
+                errors.push(AppError::InvalidParameterValue {
+                    param: "alpha".to_string(),
+                    value: alpha.to_string(),
+                    expected: "one of flatten, warn or reject".to_string(),
+                });
+                limits.alpha_policy
+            }),
+            None => limits.alpha_policy,
+        };
+
+        AppError::from_validation(errors)?;
+        let plan = ProcessingPlan {
+            steps,
+            width,
+            height,
+            fit,
+            background,
+            quality,
+            format,
+        };
+        check_output_pixels(&plan, limits)?;
+        Ok(ValidatedParams {
+            source,
+            plan,
+            alpha,
+        })
+    }
+}
+
+/// Refuses `plan` when a box it asks for, of the output or of a `resize`
+/// step, is over `limits.max_output_pixels`: padding fills the whole box
+/// whatever the size of the source. A box with one side left to the aspect
+/// ratio never enlarges the image, which the source limits then bound.
+fn check_output_pixels(plan: &ProcessingPlan, limits: &Limits) -> AppResult<()> {
+    let steps = plan
+        .steps
+        .iter()
+        .enumerate()
+        .filter_map(|(index, step)| match *step {
+            TransformStep::Resize {
+                width: Some(width),
+                height: Some(height),
+                ..
+            } => Some((format!("tx[{index}]"), width, height)),
+            _ => None,
+        });
+    let output = plan
+        .width
+        .zip(plan.height)
+        .map(|(width, height)| ("w,h".to_string(), width, height));
+    let errors = steps
+        .chain(output)
+        .filter(|&(_, width, height)| {
+            u64::from(width) * u64::from(height) > limits.max_output_pixels
+        })
+        .map(|(param, width, height)| AppError::OutputTooLarge {
+            param,
+            width,
+            height,
+            limit: limits.max_output_pixels,
+        })
+        .collect();
+    AppError::from_validation(errors)
+}
+
+/// Error for a numeric parameter whose value is not a whole number.
+fn not_a_number(param: &str, value: &str, max: u32) -> AppError {
+    AppError::InvalidParameterValue {
+        param: param.to_string(),
+        value: value.to_string(),
+        expected: format!("a whole number between 1 and {max}"),
+    }
+}
+
+/// Deserializes a query string into request parameters, reporting failures
+/// (such as a repeated parameter) as a ProblemDetails error rather than the
+/// HTTP framework's own rejection.
+pub fn parse_query<T: serde::de::DeserializeOwned>(query: &str) -> AppResult<T> {
+    serde_urlencoded::from_str(query).map_err(|e| AppError::InvalidParameterValue {
+        param: "query".to_string(),
+        value: error::truncate_src(query).into_owned(),
+        expected: format!("a valid query string ({e})"),
+    })
+}
+
+/// Refuses query strings over `limits.max_query_length`, before anything
+/// parses them.
+pub fn check_query_length(query: &str, limits: &Limits) -> AppResult<()> {
+    if query.len() > limits.max_query_length {
+        return Err(AppError::ParameterTooLong {
+            param: "query".to_string(),
+            length: query.len(),
+            max: limits.max_query_length,
+        });
+    }
+    Ok(())
+}
+
+/// [`parse_query`] for image routes, after [`check_query_length`].
+pub fn parse_image_query<T: serde::de::DeserializeOwned>(
+    query: &str,
+    limits: &Limits,
+) -> AppResult<T> {
+    check_query_length(query, limits)?;
+    parse_query(query)
+}
+
+/// Parses a `RGB`, `RRGGBB` or `RRGGBBAA` hex color, with an optional `#`.
+pub fn parse_color(value: &str) -> Option<[u8; 4]> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let expanded = match hex.len() {
+        3 => hex
+            .chars()
+            .flat_map(|c| [c, c])
+            .chain("ff".chars())
+            .collect(),
+        6 => format!("{hex}ff"),
+        8 => hex.to_string(),
+        _ => return None,
     };
-    let quality = match params.q {
-        Some(q) if q == 0 || q > 100 => return Err(AppError::InvalidQuality { quality: q }),
-        Some(q) => q,
-        None => DEFAULT_QUALITY,
+    let bytes = hex::decode(expanded).ok()?;
+    bytes.try_into().ok()
+}
+
+/// Decodes a base64url-encoded source URL, padded or not.
+pub fn decode_base64url(encoded: &str) -> AppResult<String> {
+    use base64::{
+        alphabet,
+        engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig},
+        Engine as _,
     };
-    let format = params.f.as_deref();
 
-    // Generate cache key
-    let cache_key = generate_cache_key(src, width, quality, format);
+    const BASE64URL: GeneralPurpose = GeneralPurpose::new(
+        &alphabet::URL_SAFE,
+        GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+    );
+
+    BASE64URL
+        .decode(encoded.trim())
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .ok_or(AppError::InvalidImageUrl)
+}
+
+#[cfg(feature = "runtime")]
+/// Names inline image bytes in cache keys by their content hash.
+fn content_identity(image_data: &[u8]) -> String {
+    format!("sha256:{}", hex::encode(Sha256::digest(image_data)))
+}
+
+#[cfg(feature = "runtime")]
+/// Serves the transformed image from the cache, or loads the original from
+/// `source`, processes and caches it. `identity` names the original in the
+/// cache key.
+async fn transform(
+    source: ImageSource<'_>,
+    identity: &str,
+    plan: &ProcessingPlan,
+    state: &AppState,
+    if_none_match: Option<&IfNoneMatch>,
+    timings: &mut PhaseTimings,
+) -> AppResult<ImageOutput> {
+    // Generate cache key, which doubles as the strong ETag of the output
+    let cache_key = generate_cache_key(identity, plan);
+    let etag = cache_key.clone();
 
     // Check cache
     {
-        let cache = state.cache.read().await;
-        if let Some(cached_data) = cache.get(&cache_key).await {
-            let content_type = guess_content_type(&cached_data);
-            return Ok((cached_data, content_type.to_string()));
+        let cache_start = Instant::now();
+        // A clone shares the entries; the lock is released before any I/O
+        let cache = state.cache.read().await.clone();
+
+        // Answer revalidations without reading the cached bytes
+        if if_none_match.is_some_and(|header| header.matches(&etag)) && cache.contains(&cache_key) {
+            record_cache_status(state, timings, true);
+            return Ok(ImageOutput::NotModified { etag });
+        }
+
+        // Entries cached without their dimensions are processed again
+        let cached = cache.get_with_metadata(&cache_key).await;
+        timings.record(Phase::CacheRead, cache_start.elapsed());
+        if let Some((cached_data, metadata)) = cached {
+            record_cache_status(state, timings, true);
+            return Ok(ImageOutput::Image {
+                content_type: sniff::content_type(&cached_data).to_string(),
+                data: cached_data,
+                etag,
+                warnings: image_warnings(&metadata),
+                metadata: Some(metadata),
+            });
         }
     }
 
+    record_cache_status(state, timings, false);
+
+    // Jobs that may be small wait in their own queue, and move to the
+    // general one once their source turns out too large
+    let class = state.limiter.classify_target(target_pixels(plan, None));
+    let mut permit = acquire_permit(state, class).await?;
+
     // Fetch and process image
-    let image_data = fetch_image(&state.client, src).await?;
-    let processed_data = ImageProcessor::process(image_data, width, quality, format).await?;
+    let image_data = load_source(source, state, timings).await?;
+    if permit.class() == JobClass::Small {
+        let source = ImageProcessor::dimensions(&image_data);
+        let source_pixels = source.map(|(width, height)| u64::from(width) * u64::from(height));
+        let class = state
+            .limiter
+            .classify(source_pixels, target_pixels(plan, source));
+        if class == JobClass::Heavy {
+            drop(permit);
+            permit = acquire_permit(state, class).await?;
+        }
+    }
+    let processed = state
+        .workers
+        .process(image_data, plan, timings)
+        .await
+        .inspect_err(|err| {
+            if matches!(err, AppError::Overloaded { .. }) {
+                state.stats.record_shed();
+            }
+        })?;
+    drop(permit);
+    Ok(store_processed(state, cache_key, processed, timings)
+        .await
+        .into())
+}
 
-    // Cache the result
-    {
-        let mut cache = state.cache.write().await;
-        cache.put(cache_key, processed_data.clone()).await;
+#[cfg(feature = "runtime")]
+/// An image out of the pipeline, processed or read from the cache, for
+/// callers that never answer `304`.
+pub(crate) struct RenderedImage {
+    pub data: bytes::Bytes,
+    pub content_type: String,
+    /// Cache key, which is also the ETag.
+    pub etag: String,
+    pub metadata: ImageMetadata,
+}
+
+#[cfg(feature = "runtime")]
+impl From<RenderedImage> for ImageOutput {
+    fn from(image: RenderedImage) -> Self {
+        ImageOutput::Image {
+            data: image.data,
+            content_type: image.content_type,
+            etag: image.etag,
+            warnings: image_warnings(&image.metadata),
+            metadata: Some(image.metadata),
+        }
     }
+}
 
-    let content_type = guess_content_type(&processed_data);
-    Ok((processed_data, content_type.to_string()))
+#[cfg(feature = "runtime")]
+/// Warnings of a processed image, cached or not, that hold whatever the
+/// request; the alpha policy adds its own.
+fn image_warnings(metadata: &ImageMetadata) -> Warnings {
+    let mut warnings = Warnings::default();
+    if metadata.upscale_refused {
+        warnings.insert(Warning::UpscaleRefused);
+    }
+    warnings
 }
 
-pub async fn optimize_image_handler(
-    query: web::Query<ImageParams>,
-    state: web::Data<AppState>,
-) -> Result<HttpResponse> {
-    // Handle SVG redirect specially for actix-web
-    if let Some(src) = &query.src {
-        if src.to_lowercase().ends_with(".svg") {
-            return Ok(HttpResponse::Found()
-                .append_header(("Location", src.as_str()))
-                .finish());
+#[cfg(feature = "runtime")]
+/// Each of `plans` applied to the one source, as [`transform`] would: read
+/// from the cache when there, the rest processed together on one worker so
+/// the source is fetched and decoded at most once. The first plan that
+/// fails fails them all.
+pub(crate) async fn transform_many(
+    source: ImageSource<'_>,
+    identity: &str,
+    plans: &[ProcessingPlan],
+    state: &AppState,
+    timings: &mut PhaseTimings,
+) -> AppResult<Vec<RenderedImage>> {
+    let cache_start = Instant::now();
+    let cache = state.cache.read().await.clone();
+    let mut images = Vec::with_capacity(plans.len());
+    let mut misses = Vec::new();
+    for (index, plan) in plans.iter().enumerate() {
+        let cache_key = generate_cache_key(identity, plan);
+        match cache.get_with_metadata(&cache_key).await {
+            Some((data, metadata)) => images.push(Some(RenderedImage {
+                content_type: sniff::content_type(&data).to_string(),
+                data,
+                etag: cache_key,
+                metadata,
+            })),
+            None => {
+                images.push(None);
+                misses.push(index);
+            }
         }
     }
+    timings.record(Phase::CacheRead, cache_start.elapsed());
+    record_cache_status(state, timings, misses.is_empty());
 
-    match process_image_request(query.into_inner(), &state).await {
-        Ok((data, content_type)) => Ok(HttpResponse::Ok().content_type(content_type).body(data)),
-        Err(err) => Err(err.into()),
+    if !misses.is_empty() {
+        let permit = acquire_permit(state, JobClass::Heavy).await?;
+        let image_data = load_source(source, state, timings).await?;
+        let missing: Vec<ProcessingPlan> = misses.iter().map(|&i| plans[i].clone()).collect();
+        let results = state
+            .workers
+            .process_many(image_data, &missing, timings)
+            .await
+            .inspect_err(|err| {
+                if matches!(err, AppError::Overloaded { .. }) {
+                    state.stats.record_shed();
+                }
+            })?;
+        drop(permit);
+        for (index, result) in misses.into_iter().zip(results) {
+            let cache_key = generate_cache_key(identity, &plans[index]);
+            images[index] = Some(store_processed(state, cache_key, result?, timings).await);
+        }
     }
+    Ok(images.into_iter().flatten().collect())
 }
 
-pub async fn direct_image_handler(image_id: web::Path<String>) -> Result<HttpResponse> {
-    // Validate image ID format
-    if !IMAGE_ID_REGEX.is_match(&image_id) {
-        return Err(AppError::InvalidImageUrl.into());
+#[cfg(feature = "runtime")]
+/// Caches a processed image under `cache_key`, as `cache.write_mode` says.
+async fn store_processed(
+    state: &AppState,
+    cache_key: String,
+    processed: ProcessedImage,
+    timings: &mut PhaseTimings,
+) -> RenderedImage {
+    let metadata = ImageMetadata {
+        width: processed.width,
+        height: processed.height,
+        original_width: processed.original_width,
+        original_height: processed.original_height,
+        alpha_flattened: processed.alpha_flattened,
+        upscale_refused: processed.upscale_refused,
+    };
+
+    let cache_start = Instant::now();
+    // Written through a clone, so a slow disk doesn't block lookups waiting
+    // on the lock
+    let data = bytes::Bytes::from(processed.bytes);
+    let mut cache = state.cache.read().await.clone();
+    let write = {
+        let data = data.clone();
+        let cache_key = cache_key.clone();
+        async move { cache.put_with_metadata(cache_key, data, metadata).await }
+    };
+    match state.config().cache.write_mode {
+        CacheWriteMode::Sync => {
+            write.await;
+            timings.record(Phase::CacheWrite, cache_start.elapsed());
+        }
+        CacheWriteMode::Deferred => state.pending_writes.spawn(write.in_current_span()),
     }
 
-    // In a real implementation, this would fetch from internal storage
-    Err(AppError::ImageFetchFailed {
-        url: image_id.to_string(),
+    RenderedImage {
+        data,
+        content_type: processed.content_type.to_string(),
+        etag: cache_key,
+        metadata,
     }
-    .into())
 }
 
-pub async fn fetch_image(client: &reqwest::Client, url: &str) -> AppResult<Vec<u8>> {
+#[cfg(feature = "runtime")]
+/// Reads the original image from `source`, recording the time taken as the
+/// fetch phase.
+async fn load_source(
+    source: ImageSource<'_>,
+    state: &AppState,
+    timings: &mut PhaseTimings,
+) -> AppResult<Vec<u8>> {
+    let fetched = !matches!(source, ImageSource::Bytes(_));
+    let url = source.url();
+    let image_data = match source {
+        ImageSource::Url(src) => {
+            let context = FetchContext::current(&state.config().fetch);
+            let fetch = fetch_url(src, state, &context);
+            timings.time_async(Phase::Fetch, fetch).await?
+        }
+        ImageSource::File { src, root } => {
+            let read = local_source::read_file(src, &root, state.config().limits.max_image_size);
+            timings.time_async(Phase::Fetch, read).await?
+        }
+        #[cfg(feature = "s3-source")]
+        ImageSource::S3(src) => {
+            timings
+                .time_async(Phase::Fetch, s3::fetch_object(src, &state.config()))
+                .await?
+        }
+        ImageSource::Ipfs(path) => {
+            let context = FetchContext::current(&state.config().fetch);
+            let fetch = ipfs::fetch(&path, state, &context);
+            timings.time_async(Phase::Fetch, fetch).await?
+        }
+        ImageSource::Bytes(image_data) => image_data,
+        ImageSource::Stored(id) => timings
+            .time_async(Phase::Fetch, state.storage.get(id))
+            .await
+            .ok_or_else(|| AppError::ImageNotFound { id: id.to_string() })?,
+    };
+    if fetched {
+        timings.set_source_size(image_data.len());
+    }
+    // SVGs named as such were redirected to or refused before fetching
+    if DetectedFormat::detect(&image_data) == Some(DetectedFormat::Svg) {
+        return Err(match url {
+            Some(url) => AppError::SvgContent {
+                url: error::strip_userinfo(&url),
+            },
+            None => AppError::InvalidImageFormat {
+                format: DetectedFormat::Svg.extension().to_string(),
+            },
+        });
+    }
+    Ok(image_data)
+}
+
+/// Fetches `url` with the fetcher of `state` once a slot to its host is
+/// free. Waiting longer than the timeout of its origin for one is a timeout
+/// too.
+#[cfg(feature = "runtime")]
+pub(crate) async fn fetch_url(
+    url: &str,
+    state: &AppState,
+    context: &FetchContext,
+) -> AppResult<Vec<u8>> {
+    let host = Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default();
+    let budget = state.origins().policy(&host).timeout;
+    let context = FetchContext {
+        origins: Some(state.origins()),
+        ..context.clone()
+    };
+    let _permit = state.hosts().acquire(&host, budget).await.ok_or_else(|| {
+        let err = AppError::ImageFetchTimeout {
+            url: error::strip_userinfo(url),
+            phase: "queue".to_string(),
+            budget_ms: budget.as_millis() as u64,
+        };
+        warn!("No fetch slot to {host} was free in time: {err}");
+        err
+    })?;
+    state.fetcher.fetch(url, &state.config(), &context).await
+}
+
+/// Waits for a processing slot, counting the request when it is shed.
+#[cfg(feature = "runtime")]
+async fn acquire_permit(state: &AppState, class: JobClass) -> AppResult<ProcessingPermit<'_>> {
+    state.limiter.acquire_for(class).await.inspect_err(|err| {
+        if matches!(err, AppError::Overloaded { .. }) {
+            state.stats.record_shed();
+        }
+    })
+}
+
+/// Pixels of the output of `plan`, taking a dimension it leaves out from the
+/// aspect ratio of `source`, when known, and assuming a square otherwise.
+#[cfg(feature = "runtime")]
+fn target_pixels(plan: &ProcessingPlan, source: Option<(u32, u32)>) -> Option<u64> {
+    let scaled = |length: u32, from: u32, to: u32| {
+        u64::from(length) * u64::from(to) / u64::from(from.max(1))
+    };
+    let (width, height) = match (plan.width, plan.height, source) {
+        (Some(width), Some(height), _) => (u64::from(width), u64::from(height)),
+        (Some(width), None, Some((source_width, source_height))) => {
+            (u64::from(width), scaled(width, source_width, source_height))
+        }
+        (None, Some(height), Some((source_width, source_height))) => (
+            scaled(height, source_height, source_width),
+            u64::from(height),
+        ),
+        (Some(side), None, None) | (None, Some(side), None) => (u64::from(side), u64::from(side)),
+        (None, None, source) => {
+            source.map(|(width, height)| (u64::from(width), u64::from(height)))?
+        }
+    };
+    Some(width * height)
+}
+
+#[cfg(feature = "runtime")]
+fn record_cache_status(state: &AppState, timings: &mut PhaseTimings, hit: bool) {
+    if hit {
+        state.stats.record_cache_hit();
+    } else {
+        state.stats.record_cache_miss();
+    }
+    let status = if hit { "hit" } else { "miss" };
+    logging::record_cache_status(status);
+    timings.set_cache_status(status);
+    tracing::Span::current().record("cache_hit", hit);
+}
+
+#[cfg(feature = "runtime")]
+#[cfg_attr(
+    feature = "otel",
+    tracing::instrument(
+        skip(client, config, context),
+        fields(src_host = Url::parse(url).ok().and_then(|u| u.host_str().map(str::to_string)))
+    )
+)]
+pub async fn fetch_image(
+    client: &reqwest::Client,
+    url: &str,
+    config: &AppConfig,
+    context: &FetchContext,
+) -> AppResult<Vec<u8>> {
+    let location = Url::parse(url).map_err(|_| AppError::InvalidImageUrl)?;
+    let origins = context
+        .origins
+        .clone()
+        .unwrap_or_else(|| Arc::new(OriginPolicies::from_config(config)));
+    let host = location.host_str().unwrap_or_default();
+    if !origins.allows(host) {
+        warn!(
+            "Refused {}: the host is not allowed",
+            error::strip_userinfo(url)
+        );
+        return Err(AppError::OriginNotAllowed);
+    }
+
+    let policy = origins.policy(host);
+    let mut attempt = 0;
+    loop {
+        let result = fetch_once(
+            client,
+            url,
+            location.clone(),
+            config,
+            context,
+            &origins,
+            &policy,
+        )
+        .await;
+        match result {
+            Err(err) if attempt < policy.retries && err.is_transient() => {
+                let backoff = policy
+                    .retry_backoff
+                    .saturating_mul(2u32.saturating_pow(attempt));
+                attempt += 1;
+                warn!(
+                    "Retrying {} in {backoff:?}, attempt {attempt} of {}: {err}",
+                    error::strip_userinfo(url),
+                    policy.retries
+                );
+                tokio::time::sleep(backoff).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// One attempt of [`fetch_image`], within the timeout of `policy`.
+#[cfg(feature = "runtime")]
+async fn fetch_once(
+    client: &reqwest::Client,
+    url: &str,
+    mut location: Url,
+    config: &AppConfig,
+    context: &FetchContext,
+    origins: &OriginPolicies,
+    policy: &OriginPolicy,
+) -> AppResult<Vec<u8>> {
     use futures_util::StreamExt;
 
-    let response = client
-        .get(url)
-        .header("User-Agent", "Plasmic-Image-Optimizer/1.0")
-        .timeout(std::time::Duration::from_secs(30))
-        .send()
-        .await
-        .map_err(|_| AppError::ImageFetchFailed {
-            url: url.to_string(),
+    // Redirects are followed here, each checked before it is, within one
+    // time budget. Their bodies are never read, so the size limit below
+    // bounds the bytes downloaded across the whole chain.
+    let budget = policy.timeout;
+    let deadline = tokio::time::Instant::now() + budget;
+    let mut hop = 0;
+    let response = loop {
+        let request = client
+            .get(location.clone())
+            .headers(context.headers.clone())
+            .header("User-Agent", config.fetch.user_agent.as_str())
+            .headers(policy.headers.clone())
+            .timeout(deadline.saturating_duration_since(tokio::time::Instant::now()));
+        #[cfg(feature = "otel")]
+        let request = telemetry::inject_trace_context(request);
+
+        // Logged through the error, whose URL has its credentials stripped
+        let response = request.send().await.map_err(|e| {
+            let err = AppError::from_fetch_error(url, &e, budget);
+            warn!("Failed to fetch image: {err}");
+            err
         })?;
+        // Clients following redirects themselves only show where they ended
+        if response.url() != &location {
+            fetch::check_redirect(&config.fetch, origins, hop + 1, &location, response.url())?;
+        }
+
+        let target = response
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|value| value.to_str().ok())
+            .filter(|_| response.status().is_redirection());
+        let Some(target) = target else {
+            break response;
+        };
+        hop += 1;
+        let next = response
+            .url()
+            .join(target)
+            .map_err(|_| AppError::RedirectRefused {
+                hop,
+                rule: "has an invalid Location".to_string(),
+            })?;
+        fetch::check_redirect(&config.fetch, origins, hop, response.url(), &next).inspect_err(
+            |err| {
+                warn!(
+                    "Refused a redirect of {}: {err}",
+                    error::strip_userinfo(url)
+                )
+            },
+        )?;
+        location = next;
+    };
 
     if !response.status().is_success() {
-        return Err(AppError::ImageFetchFailed {
-            url: url.to_string(),
-        });
+        let err = AppError::from_origin_status(url, response.status());
+        warn!("Origin rejected image fetch: {err}");
+        return Err(err);
     }
 
-    let mut bytes = Vec::new();
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("none")
+        .to_string();
+    // Error pages served with a 200 are refused from their first bytes,
+    // rather than downloaded whole and failing to decode
+    let check_content = |bytes: &[u8]| match sniff::non_image_content(bytes) {
+        Some(kind) => {
+            let err = AppError::ImageFetchFailed {
+                url: error::strip_userinfo(url),
+                reason: format!(
+                    "the origin returned non-image content ({kind}, Content-Type: {content_type})"
+                ),
+            };
+            warn!("Origin did not serve an image: {err}");
+            Err(err)
+        }
+        None => Ok(()),
+    };
+
+    // Sources declared too large are refused unread; the others are read
+    // into a buffer sized from the declaration, which may be missing or wrong
+    let limit = config.limits.max_image_size;
+    let declared = response.content_length();
+    if let Some(length) = declared.filter(|&length| length > limit as u64) {
+        return Err(AppError::SourceTooLargeBytes {
+            limit,
+            actual: usize::try_from(length).unwrap_or(usize::MAX),
+        });
+    }
+    let mut bytes = Vec::with_capacity(declared.map_or(0, |length| length as usize));
+    let mut sniffed = false;
     let mut stream = response.bytes_stream();
 
     while let Some(chunk) = stream.next().await {
-        let chunk = chunk.map_err(|_| AppError::ImageFetchFailed {
-            url: url.to_string(),
+        let chunk = chunk.map_err(|e| {
+            let err = AppError::from_fetch_error(url, &e, budget);
+            warn!("Image download was interrupted: {err}");
+            err
         })?;
+        // Returning drops the stream, closing the connection, before the
+        // chunk going over the limit is buffered
+        if bytes.len() + chunk.len() > limit {
+            return Err(AppError::SourceTooLargeBytes {
+                limit,
+                actual: bytes.len() + chunk.len(),
+            });
+        }
         bytes.extend_from_slice(&chunk);
 
-        if bytes.len() > MAX_IMAGE_SIZE {
-            return Err(AppError::ImageTooLarge);
+        if !sniffed && bytes.len() >= sniff::SNIFF_LEN {
+            sniffed = true;
+            check_content(&bytes)?;
         }
     }
+    if !sniffed {
+        check_content(&bytes)?;
+    }
 
     Ok(bytes)
 }
 
-pub fn generate_cache_key(
-    src: &str,
-    width: Option<u32>,
-    quality: u8,
-    format: Option<&str>,
-) -> String {
+/// Version of the cache key scheme, hashed into every key. Bumped whenever
+/// keys change, or the output they name does, so entries of the previous
+/// scheme are never mistaken for current ones.
+pub const CACHE_KEY_VERSION: u32 = 3;
+
+/// Cache key of `plan` applied to the source named `src`: the hash of the
+/// canonical form of the plan, see [`ProcessingPlan::canonical`], so
+/// requests for the same image share an entry however they spell it. `src`
+/// is hashed whole, never truncated for display, as two long sources may
+/// share a prefix; validation bounds its length.
+pub fn generate_cache_key(src: &str, plan: &ProcessingPlan) -> String {
     let mut hasher = Sha256::new();
-    hasher.update(src.as_bytes());
-    if let Some(w) = width {
-        hasher.update(w.to_string().as_bytes());
+    hasher.update(format!("v{CACHE_KEY_VERSION}\n{src}\n{}", plan.canonical()).as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+/// Collects `stream`, failing as soon as it exceeds `max_size` bytes.
+#[cfg(any(feature = "actix", feature = "axum"))]
+pub(crate) async fn read_limited<S, E>(mut stream: S, max_size: usize) -> AppResult<Vec<u8>>
+where
+    S: futures_util::Stream<Item = std::result::Result<bytes::Bytes, E>> + Unpin,
+    E: std::fmt::Display,
+{
+    use futures_util::StreamExt;
+
+    let mut bytes = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(upload_failed)?;
+        if bytes.len() + chunk.len() > max_size {
+            return Err(AppError::SourceTooLargeBytes {
+                limit: max_size,
+                actual: bytes.len() + chunk.len(),
+            });
+        }
+        bytes.extend_from_slice(&chunk);
     }
-    hasher.update(quality.to_string().as_bytes());
-    if let Some(f) = format {
-        hasher.update(f.as_bytes());
+    Ok(bytes)
+}
+
+#[cfg(any(feature = "actix", feature = "axum"))]
+pub(crate) fn upload_failed(e: impl std::fmt::Display) -> AppError {
+    AppError::ImageProcessingFailed {
+        reason: format!("Failed to read upload: {e}"),
+    }
+}
+
+/// Request headers, beyond the URL, an image response was derived from.
+/// They are listed in its `Vary` header so shared caches keep a variant per
+/// value; responses decided by the URL alone vary on nothing, which keeps
+/// CDN hit rates high.
+#[cfg(any(feature = "actix", feature = "axum"))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResponseInputs {
+    /// `f=auto` chose the format from `Accept`.
+    pub accept: bool,
+    /// The API key authorizing the request was sent in `X-Api-Key`, so a
+    /// cached response must not be served to clients without it.
+    pub api_key: bool,
+    /// `Sec-CH-DPR` scales the size asked for.
+    pub dpr: bool,
+    /// `Sec-CH-Width` stands in for an omitted `w`.
+    pub width: bool,
+    /// Client hints are on, and asked for with `Accept-CH`.
+    pub client_hints: bool,
+}
+
+#[cfg(any(feature = "actix", feature = "axum"))]
+impl ResponseInputs {
+    /// Resolves `f=auto` and, when enabled, client hints in `params`,
+    /// recording the headers read along the way. `header` looks up a request
+    /// header by name.
+    pub fn resolve<'a>(
+        params: &mut ImageParams,
+        config: &AppConfig,
+        api_keys: &ApiKeys,
+        header: impl Fn(&str) -> Option<&'a str>,
+    ) -> Self {
+        let client_hints = config.features.client_hints;
+        let (dpr, width) = if client_hints {
+            params.apply_client_hints(header("sec-ch-dpr"), header("sec-ch-width"), &config.limits)
+        } else {
+            (false, false)
+        };
+        Self {
+            accept: params.resolve_auto_format(header("accept")),
+            api_key: api_keys.is_enabled() && header(auth::API_KEY_HEADER).is_some(),
+            dpr,
+            width,
+            client_hints,
+        }
+    }
+
+    /// Value of the `Vary` header, `None` when the URL alone decided the
+    /// response.
+    pub fn vary(&self) -> Option<String> {
+        let headers: Vec<&str> = [
+            (self.accept, "Accept"),
+            (self.dpr, "Sec-CH-DPR"),
+            (self.width, "Sec-CH-Width"),
+            (self.api_key, auth::API_KEY_HEADER),
+        ]
+        .into_iter()
+        .filter_map(|(read, name)| read.then_some(name))
+        .collect();
+        (!headers.is_empty()).then(|| headers.join(", "))
+    }
+
+    /// Value of the `Accept-CH` header, when client hints are on.
+    pub fn accept_ch(&self) -> Option<&'static str> {
+        self.client_hints.then_some(CLIENT_HINTS)
     }
-    hex::encode(hasher.finalize())
 }
 
-pub fn guess_content_type(data: &[u8]) -> &'static str {
-    if data.len() < 12 {
-        return "application/octet-stream";
+/// How long clients and shared caches may keep an image response, sent as
+/// its `Cache-Control`.
+#[cfg(any(feature = "actix", feature = "axum"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Freshness {
+    /// `cache.max_age_secs`.
+    MaxAge,
+    /// `cache.max_age_secs`, never revalidated: the URL names the content,
+    /// as stored image ids do.
+    Immutable,
+}
+
+#[cfg(any(feature = "actix", feature = "axum"))]
+impl Freshness {
+    pub fn cache_control(self, config: &AppConfig) -> String {
+        let max_age = config.cache.max_age_secs;
+        match self {
+            Self::MaxAge => format!("public, max-age={max_age}"),
+            Self::Immutable => format!("public, max-age={max_age}, immutable"),
+        }
     }
+}
+
+/// File name for an image downloaded as `requested`: path components and
+/// control characters are dropped, the length is capped and the extension is
+/// replaced by the one of the output format.
+#[cfg(any(feature = "actix", feature = "axum"))]
+pub(crate) fn download_filename(requested: &str, content_type: &str) -> String {
+    let name = requested.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name.chars().filter(|c| !c.is_control()).collect();
+    let name = name.trim().trim_matches('.');
 
-    match &data[..12] {
-        [0xFF, 0xD8, 0xFF, ..] => "image/jpeg",
-        [0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A, ..] => "image/png",
-        [0x52, 0x49, 0x46, 0x46, _, _, _, _, 0x57, 0x45, 0x42, 0x50] => "image/webp",
-        _ => "application/octet-stream",
+    let stem = match name.rsplit_once('.') {
+        Some((stem, _)) if !stem.is_empty() => stem,
+        _ => name,
+    };
+    let mut stem: String = stem.chars().take(MAX_DOWNLOAD_FILENAME_LEN).collect();
+    if stem.trim().is_empty() {
+        stem = "image".to_string();
     }
+
+    match DetectedFormat::from_content_type(content_type) {
+        Some(format) => format!("{stem}.{}", format.extension()),
+        None => stem,
+    }
+}
+
+/// `X-Image-Width`, `X-Image-Height` and `X-Original-Size`
+/// (`<width>x<height>`) headers of an image response, so clients can lay it
+/// out without decoding it, and `X-Alpha-Flattened: true` when its
+/// transparency was dropped under [`AlphaPolicy::Warn`]. Warnings are sent
+/// apart, with [`warnings_header`].
+#[cfg(any(feature = "actix", feature = "axum"))]
+pub(crate) fn metadata_headers(metadata: &ImageMetadata) -> Vec<(&'static str, String)> {
+    let mut headers = vec![
+        ("x-image-width", metadata.width.to_string()),
+        ("x-image-height", metadata.height.to_string()),
+        (
+            "x-original-size",
+            format!("{}x{}", metadata.original_width, metadata.original_height),
+        ),
+    ];
+    if metadata.alpha_flattened {
+        headers.push(("x-alpha-flattened", "true".to_string()));
+    }
+    headers
+}
+
+/// `X-Optimizer-Warnings` header of an image response with `warnings`.
+#[cfg(any(feature = "actix", feature = "axum"))]
+pub(crate) fn warnings_header(warnings: &Warnings) -> Option<(&'static str, String)> {
+    warnings
+        .header_value()
+        .map(|codes| (warnings::WARNINGS_HEADER, codes))
+}
+
+/// Validates a `<hash>.<ext>` image id and returns the content type of its
+/// extension.
+#[cfg(any(feature = "actix", feature = "axum"))]
+pub(crate) fn stored_image_content_type(image_id: &str) -> AppResult<&'static str> {
+    let captures = IMAGE_ID_REGEX
+        .captures(image_id)
+        .ok_or(AppError::InvalidImageUrl)?;
+    storage::content_type_for_extension(&captures[2]).ok_or_else(|| AppError::InvalidImageFormat {
+        format: captures[2].to_string(),
+    })
 }
@@ -0,0 +1,273 @@
+//! Bounds how many images are fetched and processed at once, shedding load
+//! once too many requests are already waiting instead of queueing them
+//! without limit.
+//!
+//! Small jobs, such as thumbnails of modest sources, draw from a pool of
+//! permits of their own, so a burst of heavy jobs never delays them.
+
+use crate::config::ProcessingConfig;
+use crate::error::{AppError, AppResult};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Weight of the latest sample in the processing time average.
+const AVERAGE_WEIGHT: f64 = 0.2;
+
+/// Pool a job draws its permit from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobClass {
+    /// Within the small job thresholds, served from the reserved pool.
+    Small,
+    /// Anything else, served from the general pool.
+    Heavy,
+}
+
+impl JobClass {
+    pub const ALL: [JobClass; 2] = [JobClass::Small, JobClass::Heavy];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobClass::Small => "small",
+            JobClass::Heavy => "heavy",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ProcessingLimiter {
+    heavy: Pool,
+    small: Pool,
+    max_waiting: usize,
+    /// Jobs whose source and output have at most these many pixels are small.
+    small_max_source_pixels: u64,
+    small_max_target_pixels: u64,
+    /// Moving average of how long a permit is held, in microseconds.
+    average_micros: AtomicU64,
+    /// Shed requests get a 503 instead of a 429.
+    shed_unavailable: bool,
+}
+
+#[derive(Debug)]
+struct Pool {
+    permits: Semaphore,
+    max_permits: usize,
+    waiting: AtomicUsize,
+}
+
+/// Processing slot, released when dropped. Its lifetime is recorded in the
+/// average processing time.
+pub struct ProcessingPermit<'a> {
+    limiter: &'a ProcessingLimiter,
+    class: JobClass,
+    _permit: SemaphorePermit<'a>,
+    acquired_at: Instant,
+}
+
+impl ProcessingLimiter {
+    /// Allows `max_permits` concurrent jobs and `max_waiting` requests queued
+    /// behind them. Shed requests get a 503 rather than a 429 when
+    /// `shed_unavailable`. No permits are reserved for small jobs.
+    pub fn new(max_permits: usize, max_waiting: usize, shed_unavailable: bool) -> Self {
+        Self {
+            heavy: Pool::new(max_permits),
+            small: Pool::new(0),
+            max_waiting,
+            small_max_source_pixels: 0,
+            small_max_target_pixels: 0,
+            average_micros: AtomicU64::new(0),
+            shed_unavailable,
+        }
+    }
+
+    /// Reserves `permits` for jobs reading at most `max_source_pixels` and
+    /// producing at most `max_target_pixels`, on top of the general pool.
+    pub fn with_small_pool(
+        mut self,
+        permits: usize,
+        max_source_pixels: u64,
+        max_target_pixels: u64,
+    ) -> Self {
+        self.small = Pool::new(permits);
+        self.small_max_source_pixels = max_source_pixels;
+        self.small_max_target_pixels = max_target_pixels;
+        self
+    }
+
+    pub fn from_config(config: &ProcessingConfig) -> Self {
+        Self::new(
+            config.max_concurrent,
+            config.max_waiting,
+            config.shed_status == 503,
+        )
+        .with_small_pool(
+            config.small_max_concurrent,
+            config.small_max_source_pixels,
+            config.small_max_target_pixels,
+        )
+    }
+
+    /// Class of a job before its source is known: small when its output
+    /// may be. Jobs starting small are checked again with [`Self::classify`].
+    pub fn classify_target(&self, target_pixels: Option<u64>) -> JobClass {
+        self.small_if(target_pixels.is_some_and(|pixels| pixels <= self.small_max_target_pixels))
+    }
+
+    /// Class of a job reading `source_pixels` to produce `target_pixels`.
+    /// Unknown sizes are heavy.
+    pub fn classify(&self, source_pixels: Option<u64>, target_pixels: Option<u64>) -> JobClass {
+        let small_source =
+            source_pixels.is_some_and(|pixels| pixels <= self.small_max_source_pixels);
+        self.small_if(small_source && self.classify_target(target_pixels) == JobClass::Small)
+    }
+
+    fn small_if(&self, small: bool) -> JobClass {
+        if small && self.small.max_permits > 0 {
+            JobClass::Small
+        } else {
+            JobClass::Heavy
+        }
+    }
+
+    /// Waits for a slot of the general pool, see [`Self::acquire_for`].
+    pub async fn acquire(&self) -> AppResult<ProcessingPermit<'_>> {
+        self.acquire_for(JobClass::Heavy).await
+    }
+
+    /// Waits for a processing slot of the pool of `class`, or fails with
+    /// `Overloaded` right away when its wait queue is full.
+    pub async fn acquire_for(&self, class: JobClass) -> AppResult<ProcessingPermit<'_>> {
+        let pool = self.pool(class);
+        let permit = match pool.permits.try_acquire() {
+            Ok(permit) => permit,
+            Err(_) => {
+                let waiting = WaitingGuard::enter(&pool.waiting);
+                if waiting.position > self.max_waiting {
+                    return Err(AppError::Overloaded {
+                        retry_after_secs: self.retry_after_secs_for(class),
+                        unavailable: self.shed_unavailable,
+                    });
+                }
+                pool.permits
+                    .acquire()
+                    .await
+                    .map_err(|_| AppError::ServiceUnavailable)?
+            }
+        };
+
+        Ok(ProcessingPermit {
+            limiter: self,
+            class,
+            _permit: permit,
+            acquired_at: Instant::now(),
+        })
+    }
+
+    fn pool(&self, class: JobClass) -> &Pool {
+        match class {
+            JobClass::Small => &self.small,
+            JobClass::Heavy => &self.heavy,
+        }
+    }
+
+    /// Permits of the general pool currently held.
+    pub fn in_use(&self) -> usize {
+        self.in_use_by(JobClass::Heavy)
+    }
+
+    pub fn in_use_by(&self, class: JobClass) -> usize {
+        let pool = self.pool(class);
+        pool.max_permits - pool.permits.available_permits()
+    }
+
+    /// Size of the general pool.
+    pub fn max_permits(&self) -> usize {
+        self.max_permits_for(JobClass::Heavy)
+    }
+
+    pub fn max_permits_for(&self, class: JobClass) -> usize {
+        self.pool(class).max_permits
+    }
+
+    /// Requests waiting for a permit of the general pool.
+    pub fn waiting(&self) -> usize {
+        self.waiting_for(JobClass::Heavy)
+    }
+
+    pub fn waiting_for(&self, class: JobClass) -> usize {
+        self.pool(class).waiting.load(Ordering::Relaxed)
+    }
+
+    pub fn average_processing_time(&self) -> Duration {
+        Duration::from_micros(self.average_micros.load(Ordering::Relaxed))
+    }
+
+    /// Estimated time until the queue of the general pool ahead of a new
+    /// request has drained, rounded up to whole seconds.
+    pub fn retry_after_secs(&self) -> u64 {
+        self.retry_after_secs_for(JobClass::Heavy)
+    }
+
+    fn retry_after_secs_for(&self, class: JobClass) -> u64 {
+        let rounds = self.waiting_for(class) / self.max_permits_for(class).max(1) + 1;
+        let wait = self.average_processing_time() * rounds as u32;
+        wait.as_secs_f64().ceil().max(1.0) as u64
+    }
+
+    fn record(&self, elapsed: Duration) {
+        let sample = elapsed.as_micros() as f64;
+        let _ = self
+            .average_micros
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |average| {
+                let updated = if average == 0 {
+                    sample
+                } else {
+                    average as f64 + AVERAGE_WEIGHT * (sample - average as f64)
+                };
+                Some(updated as u64)
+            });
+    }
+}
+
+impl Pool {
+    fn new(max_permits: usize) -> Self {
+        Self {
+            permits: Semaphore::new(max_permits),
+            max_permits,
+            waiting: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl ProcessingPermit<'_> {
+    /// Pool the permit was drawn from.
+    pub fn class(&self) -> JobClass {
+        self.class
+    }
+}
+
+impl Drop for ProcessingPermit<'_> {
+    fn drop(&mut self) {
+        self.limiter.record(self.acquired_at.elapsed());
+    }
+}
+
+/// Counts a request in the wait queue for as long as it is alive.
+struct WaitingGuard<'a> {
+    waiting: &'a AtomicUsize,
+    /// 1-based position in the queue when entering it.
+    position: usize,
+}
+
+impl<'a> WaitingGuard<'a> {
+    fn enter(waiting: &'a AtomicUsize) -> Self {
+        let position = waiting.fetch_add(1, Ordering::Relaxed) + 1;
+        Self { waiting, position }
+    }
+}
+
+impl Drop for WaitingGuard<'_> {
+    fn drop(&mut self) {
+        self.waiting.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,78 @@
+//! `file://` sources, for originals on a local or network mount. Disabled
+//! unless `fetch.local_source_root` is set, and confined to that directory:
+//! paths are canonicalized, so neither `..` segments nor symlinks can reach
+//! files outside it.
+
+use crate::error::{AppError, AppResult};
+use log::warn;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncReadExt;
+use url::Url;
+
+/// Stands in for the source in errors, so responses never reveal paths.
+const REDACTED_URL: &str = "file://<redacted>";
+
+/// Reads the file `src` points to, which must resolve within `root`.
+pub async fn read_file(src: &str, root: &Path, max_size: usize) -> AppResult<Vec<u8>> {
+    let failed = |reason: &str| {
+        warn!("Failed to read {src}: {reason}");
+        AppError::ImageFetchFailed {
+            url: REDACTED_URL.to_string(),
+            reason: reason.to_string(),
+        }
+    };
+
+    let path = Url::parse(src)
+        .ok()
+        .and_then(|url| url.to_file_path().ok())
+        .ok_or(AppError::InvalidImageUrl)?;
+    let (root, path) = canonicalize(root, &path).await.map_err(failed)?;
+    if !path.starts_with(&root) {
+        warn!("Refused {src}: the path is outside the local source root");
+        return Err(AppError::BlockedDestination);
+    }
+
+    let file = tokio::fs::File::open(&path)
+        .await
+        .map_err(|_| failed("the file cannot be read"))?;
+    let metadata = file
+        .metadata()
+        .await
+        .map_err(|_| failed("the file cannot be read"))?;
+    if !metadata.is_file() {
+        return Err(failed("the path is not a file"));
+    }
+
+    if metadata.len() > max_size as u64 {
+        return Err(AppError::SourceTooLargeBytes {
+            limit: max_size,
+            actual: metadata.len() as usize,
+        });
+    }
+
+    // The file may grow between the size check and the read
+    let mut bytes = Vec::new();
+    file.take(max_size as u64 + 1)
+        .read_to_end(&mut bytes)
+        .await
+        .map_err(|_| failed("the file cannot be read"))?;
+    if bytes.len() > max_size {
+        return Err(AppError::SourceTooLargeBytes {
+            limit: max_size,
+            actual: bytes.len(),
+        });
+    }
+
+    Ok(bytes)
+}
+
+/// Canonicalizes `root` and `path`, resolving `..` segments and symlinks.
+async fn canonicalize(root: &Path, path: &Path) -> Result<(PathBuf, PathBuf), &'static str> {
+    let root = tokio::fs::canonicalize(root)
+        .await
+        .map_err(|_| "the local source root is unavailable")?;
+    let path = tokio::fs::canonicalize(path)
+        .await
+        .map_err(|_| "the file does not exist")?;
+    Ok((root, path))
+}
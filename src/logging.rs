@@ -0,0 +1,211 @@
+#[cfg(feature = "runtime")]
+use std::sync::{Arc, Mutex};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+#[cfg(feature = "actix")]
+use {
+    crate::{auth::ApiKeyLabel, error::AppError},
+    actix_web::{
+        body::{BodySize, MessageBody},
+        dev::{ServiceRequest, ServiceResponse},
+        http::header::{HeaderName, HeaderValue},
+        middleware::Next,
+        Error, HttpMessage,
+    },
+    std::time::Instant,
+    tracing::Instrument,
+};
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+#[cfg(feature = "actix")]
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+#[cfg(feature = "runtime")]
+tokio::task_local! {
+    static REQUEST_CONTEXT: Arc<RequestContext>;
+}
+
+#[cfg(feature = "runtime")]
+/// Per-request data shared between the access log middleware and the code
+/// running inside the request (via a task-local).
+#[derive(Debug)]
+pub struct RequestContext {
+    pub id: String,
+    cache_status: Mutex<Option<&'static str>>,
+}
+
+#[cfg(feature = "runtime")]
+impl RequestContext {
+    pub fn new(id: String) -> Self {
+        Self {
+            id,
+            cache_status: Mutex::new(None),
+        }
+    }
+
+    #[cfg(feature = "actix")]
+    fn cache_status(&self) -> Option<&'static str> {
+        *self.cache_status.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Installs the global `tracing` subscriber. `LOG_FORMAT=json` switches to
+/// one JSON object per line; `RUST_LOG` controls the filter (default `info`).
+/// Records emitted through the `log` crate are forwarded as well. With the
+/// `otel` feature, spans are also exported over OTLP.
+pub fn init() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let json = std::env::var("LOG_FORMAT")
+        .map(|format| format.eq_ignore_ascii_case("json"))
+        .unwrap_or(false);
+
+    let fmt_layer = if json {
+        tracing_subscriber::fmt::layer()
+            .json()
+            .flatten_event(true)
+            .boxed()
+    } else {
+        tracing_subscriber::fmt::layer().boxed()
+    };
+
+    let registry = tracing_subscriber::registry().with(fmt_layer);
+    #[cfg(feature = "otel")]
+    let registry = registry.with(crate::telemetry::layer());
+    registry.with(filter).init();
+}
+
+/// Flushes any buffered telemetry before the process exits.
+pub fn shutdown() {
+    #[cfg(feature = "otel")]
+    crate::telemetry::shutdown();
+}
+
+#[cfg(feature = "runtime")]
+/// ID of the request currently being handled, if called from inside
+/// [`access_log`].
+pub fn current_request_id() -> Option<String> {
+    REQUEST_CONTEXT.try_with(|context| context.id.clone()).ok()
+}
+
+#[cfg(feature = "runtime")]
+/// Records whether the current request was served from the cache, for the
+/// access log line.
+pub fn record_cache_status(status: &'static str) {
+    let _ = REQUEST_CONTEXT.try_with(|context| {
+        *context
+            .cache_status
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = Some(status);
+    });
+}
+
+#[cfg(feature = "actix")]
+fn inbound_request_id(req: &ServiceRequest) -> Option<String> {
+    let value = req.headers().get(REQUEST_ID_HEADER)?.to_str().ok()?;
+    let valid = !value.is_empty()
+        && value.len() <= MAX_REQUEST_ID_LEN
+        && value.bytes().all(|b| b.is_ascii_graphic());
+    valid.then(|| value.to_string())
+}
+
+/// Query parameters worth logging, extracted without failing on bad input.
+#[cfg(feature = "actix")]
+#[derive(Default)]
+struct LoggedParams {
+    src_host: Option<String>,
+    w: Option<String>,
+    q: Option<String>,
+    f: Option<String>,
+}
+
+#[cfg(feature = "actix")]
+impl LoggedParams {
+    fn from_query(query: &str) -> Self {
+        let mut params = Self::default();
+        for (name, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            match name.as_ref() {
+                "src" => params.src_host = host_of(&value),
+                "srcb64" => {
+                    params.src_host = crate::decode_base64url(&value)
+                        .ok()
+                        .and_then(|src| host_of(&src))
+                }
+                "w" => params.w = Some(value.into_owned()),
+                "q" => params.q = Some(value.into_owned()),
+                "f" => params.f = Some(value.into_owned()),
+                _ => {}
+            }
+        }
+        params
+    }
+}
+
+#[cfg(feature = "actix")]
+fn host_of(src: &str) -> Option<String> {
+    url::Url::parse(src)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+}
+
+/// Middleware assigning a request ID (honoring an inbound `X-Request-Id`),
+/// echoing it back, and emitting one structured access log line per request.
+#[cfg(feature = "actix")]
+pub async fn access_log(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let start = Instant::now();
+    let request_id = inbound_request_id(&req).unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let context = Arc::new(RequestContext::new(request_id.clone()));
+    req.extensions_mut().insert(context.clone());
+
+    let method = req.method().to_string();
+    let path = req.path().to_string();
+    let params = LoggedParams::from_query(req.query_string());
+
+    let span = tracing::info_span!("request", request_id = %request_id);
+    let mut res = REQUEST_CONTEXT
+        .scope(context.clone(), next.call(req))
+        .instrument(span)
+        .await?;
+
+    let key = res
+        .request()
+        .extensions()
+        .get::<ApiKeyLabel>()
+        .map(|label| label.0.clone());
+    let error_code = res
+        .response()
+        .error()
+        .and_then(|err| err.as_error::<AppError>())
+        .map(AppError::error_code);
+    let bytes_out = match res.response().body().size() {
+        BodySize::Sized(size) => Some(size),
+        _ => None,
+    };
+
+    tracing::info!(
+        target: "access",
+        request_id = %request_id,
+        method = %method,
+        path = %path,
+        status = res.status().as_u16(),
+        src_host = params.src_host,
+        w = params.w,
+        q = params.q,
+        f = params.f,
+        cache = context.cache_status(),
+        duration_ms = start.elapsed().as_millis() as u64,
+        bytes_out,
+        error_code,
+        key,
+        "request completed"
+    );
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        res.headers_mut()
+            .insert(HeaderName::from_static("x-request-id"), value);
+    }
+
+    Ok(res)
+}
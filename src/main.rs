@@ -1,56 +1,573 @@
 use actix_cors::Cors;
-use actix_web::{web, App, HttpServer};
-use log::info;
-use std::path::PathBuf;
+use actix_web::{
+    middleware::{from_fn, Condition},
+    web, App, HttpServer,
+};
+use anyhow::Context;
+use clap::Parser;
+use log::{error, info, warn};
+use serde::Serialize;
+use std::io::Write;
+use std::path::Path;
+use std::process::ExitCode;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::fs;
-use tokio::sync::RwLock;
 
 // Re-export from lib.rs
 use img_optimizer::{
-    cache::ImageCache, direct_image_handler, health_check, list_errors, optimize_image_handler,
-    AppState,
+    auth::ApiKeys,
+    cache::ImageCache,
+    check_alpha,
+    cli::{CacheArgs, CacheCommand, CheckArgs, Cli, Command, OptimizeArgs, PregenArgs, ServeArgs},
+    config::AppConfig,
+    error::{problem_details_context, AppError},
+    fetch::build_client,
+    fetch_image,
+    image_processor::ImageProcessor,
+    logging::{self, access_log},
+    metrics::{count_errors, PhaseTimings},
+    pregen::{self, PregenOptions, PregenSummary},
+    routes,
+    self_check::{self, CheckOptions},
+    tls::{self, plain_http_health_only, ReloadableCert},
+    warnings::{Warning, Warnings, EXPOSED_HEADERS},
+    AppState, FetchContext, ImageParams, Optimizer, ValidatedParams,
 };
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+fn main() -> ExitCode {
+    let cli = Cli::parse();
 
-    let port = std::env::var("PORT").unwrap_or_else(|_| "3000".to_string());
-    let cache_dir = PathBuf::from("cache");
+    let result = match cli.command {
+        None => run_serve(cli.config.as_deref(), &cli.serve),
+        Some(Command::Serve(args)) => run_serve(cli.config.as_deref(), &args),
+        Some(Command::Cache(args)) => run_cache(cli.config.as_deref(), args),
+        Some(Command::Optimize(args)) => return run_optimize(cli.config.as_deref(), args),
+        Some(Command::Check(args)) => return run_check(cli.config.as_deref(), args),
+        Some(Command::Pregen(args)) => return run_pregen(cli.config.as_deref(), args),
+    };
 
-    // Ensure cache directory exists
-    fs::create_dir_all(&cache_dir).await?;
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run_serve(config_path: Option<&Path>, args: &ServeArgs) -> anyhow::Result<()> {
+    let config = AppConfig::load(config_path, |config| args.apply(config))
+        .context("Invalid configuration")?;
+
+    if args.print_config {
+        print!("{}", config.redacted().to_toml()?);
+        return Ok(());
+    }
+
+    // Fail fast on unreadable or mismatched certificates, before serving
+    let tls = match (&config.tls.cert_path, &config.tls.key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            Some(Arc::new(ReloadableCert::load(cert_path, key_path)?))
+        }
+        _ => None,
+    };
+
+    let config_path = config_path.map(Path::to_path_buf);
+    let args = args.clone();
+    let reload = move || AppConfig::load(config_path.as_deref(), |config| args.apply(config));
+    actix_web::rt::System::new().block_on(serve(config, tls, reload))?;
+    Ok(())
+}
+
+fn run_cache(config_path: Option<&Path>, args: CacheArgs) -> anyhow::Result<()> {
+    let config = AppConfig::load(config_path, |config| {
+        if let Some(cache_dir) = &args.cache_dir {
+            config.cache.dir = cache_dir.clone();
+        }
+    })
+    .context("Invalid configuration")?;
+    let mut cache = ImageCache::new(config.cache.dir.clone());
+
+    actix_web::rt::System::new().block_on(async {
+        match args.command {
+            CacheCommand::Stats => {
+                let stats = cache.stats().await?;
+                println!(
+                    "{}: {} entries, {} bytes",
+                    config.cache.dir.display(),
+                    stats.entries,
+                    stats.bytes
+                );
+            }
+            CacheCommand::Clear => {
+                let removed = cache.clear().await?;
+                println!(
+                    "Removed {removed} entries from {}",
+                    config.cache.dir.display()
+                );
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Prints a one-line summary: `OK <url>: ...` on stdout, or `FAIL <url>: ...`
+/// on stderr.
+fn run_check(config_path: Option<&Path>, args: CheckArgs) -> ExitCode {
+    let url = match args.url {
+        Some(url) => url,
+        None => match AppConfig::load(config_path, |_| {}) {
+            Ok(config) => format!("http://127.0.0.1:{}", config.server.port),
+            Err(e) => {
+                eprintln!("FAIL: invalid configuration: {e:#}");
+                return ExitCode::FAILURE;
+            }
+        },
+    };
+    let options = CheckOptions {
+        url,
+        timeout: args.timeout.unwrap_or(self_check::DEFAULT_TIMEOUT),
+        optimize: args.optimize,
+        api_key: args.api_key,
+    };
+
+    match actix_web::rt::System::new().block_on(self_check::run(&options)) {
+        Ok(report) => {
+            println!("OK {}: {report}", options.url);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("FAIL {}: {e:#}", options.url);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Result of the `optimize` command, printed to stderr.
+#[derive(Debug, Serialize)]
+struct OptimizeSummary {
+    input: String,
+    output: String,
+    content_type: &'static str,
+    width: u32,
+    height: u32,
+    original_width: u32,
+    original_height: u32,
+    bytes_before: usize,
+    bytes_after: usize,
+    /// Negative when the output is larger than the input.
+    savings_percent: f64,
+    /// Set when the output dropped the transparency of the input, under
+    /// the `warn` alpha policy.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    alpha_flattened: bool,
+    /// Codes of the ways the output falls short of what was asked.
+    #[serde(skip_serializing_if = "Warnings::is_empty")]
+    warnings: Warnings,
+}
+
+/// Failure of the `optimize` command, printed to stderr with `--json`.
+#[derive(Debug, Serialize)]
+struct OptimizeFailure {
+    error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_code: Option<&'static str>,
+}
+
+/// Runs `optimize`, reporting its own errors so that `--json` gets them as
+/// JSON too.
+fn run_optimize(config_path: Option<&Path>, args: OptimizeArgs) -> ExitCode {
+    match optimize_file(config_path, &args) {
+        Ok(summary) => {
+            if args.json {
+                eprintln!("{}", serde_json::json!(summary));
+            } else {
+                eprintln!(
+                    "{}: {}x{} -> {}x{} {}, {} -> {} bytes ({:.1}% {})",
+                    summary.input,
+                    summary.original_width,
+                    summary.original_height,
+                    summary.width,
+                    summary.height,
+                    summary.content_type,
+                    summary.bytes_before,
+                    summary.bytes_after,
+                    summary.savings_percent.abs(),
+                    if summary.savings_percent < 0.0 {
+                        "larger"
+                    } else {
+                        "smaller"
+                    },
+                );
+            }
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            if args.json {
+                let failure = OptimizeFailure {
+                    error: format!("{e:#}"),
+                    error_code: e.downcast_ref::<AppError>().map(AppError::error_code),
+                };
+                eprintln!("{}", serde_json::json!(failure));
+            } else {
+                // AppError messages start with their code, e.g. `IMG_006: ...`
+                eprintln!("Error: {e:#}");
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn optimize_file(
+    config_path: Option<&Path>,
+    args: &OptimizeArgs,
+) -> anyhow::Result<OptimizeSummary> {
+    let config = AppConfig::load(config_path, |_| {}).context("Invalid configuration")?;
+    // Validated like the query parameters of the HTTP API
+    let is_url = ["http://", "https://"].iter().any(|scheme| {
+        args.input
+            .get(..scheme.len())
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case(scheme))
+    });
+    let params = ImageParams {
+        src: is_url.then(|| args.input.clone()),
+        w: args.width.map(|width| width.to_string()),
+        q: args.quality.map(|quality| quality.to_string()),
+        f: args.format.clone(),
+        ..Default::default()
+    };
+    let ValidatedParams {
+        source,
+        plan,
+        alpha,
+    } = params.validate(&config.limits)?;
+
+    let (bytes_before, processed) = actix_web::rt::System::new().block_on(async {
+        let input = match &source {
+            Some(url) => {
+                let context = FetchContext::current(&config.fetch);
+                fetch_image(&build_client(&config.fetch), url, &config, &context).await?
+            }
+            None => {
+                let input = fs::read(&args.input)
+                    .await
+                    .with_context(|| format!("Failed to read {}", args.input))?;
+                if input.len() > config.limits.max_image_size {
+                    return Err(AppError::SourceTooLargeBytes {
+                        limit: config.limits.max_image_size,
+                        actual: input.len(),
+                    }
+                    .into());
+                }
+                input
+            }
+        };
+        let bytes_before = input.len();
+        let processed =
+            ImageProcessor::process_timed(input, &plan, &mut PhaseTimings::default()).await?;
+        anyhow::Ok((bytes_before, processed))
+    })?;
+    let alpha_flattened = check_alpha(alpha, processed.alpha_flattened)?;
+    let mut warnings = Warnings::default();
+    if alpha_flattened {
+        warnings.insert(Warning::AlphaFlattened);
+    }
+    if processed.upscale_refused {
+        warnings.insert(Warning::UpscaleRefused);
+    }
+
+    let output = if args.writes_to_stdout() {
+        let mut stdout = std::io::stdout().lock();
+        stdout
+            .write_all(&processed.bytes)
+            .and_then(|()| stdout.flush())
+            .context("Failed to write to standard output")?;
+        "-".to_string()
+    } else {
+        std::fs::write(&args.output, &processed.bytes)
+            .with_context(|| format!("Failed to write {}", args.output.display()))?;
+        args.output.display().to_string()
+    };
+
+    let bytes_after = processed.bytes.len();
+    let savings = 100.0 * (1.0 - bytes_after as f64 / bytes_before as f64);
+    Ok(OptimizeSummary {
+        input: args.input.clone(),
+        output,
+        content_type: processed.content_type,
+        width: processed.width,
+        height: processed.height,
+        original_width: processed.original_width,
+        original_height: processed.original_height,
+        bytes_before,
+        bytes_after,
+        savings_percent: (savings * 10.0).round() / 10.0,
+        alpha_flattened,
+        warnings,
+    })
+}
+
+/// Runs `pregen`: failed images on stderr as they are reported, then the
+/// summary on stdout. Exits 1 when any image failed.
+fn run_pregen(config_path: Option<&Path>, args: PregenArgs) -> ExitCode {
+    let summary = match pregenerate(config_path, &args) {
+        Ok(summary) => summary,
+        Err(e) => {
+            eprintln!("Error: {e:#}");
+            return ExitCode::FAILURE;
+        }
+    };
 
-    let app_state = AppState {
-        cache: Arc::new(RwLock::new(ImageCache::new(cache_dir))),
-        client: reqwest::Client::new(),
+    for failure in &summary.failures {
+        eprintln!("FAIL {}: {}", failure.key, failure.message);
+    }
+    println!(
+        "{} images: {} processed, {} already cached, {} done by an earlier run, {} failed",
+        summary.total, summary.processed, summary.cached, summary.resumed, summary.failed
+    );
+    for (code, count) in &summary.errors {
+        println!("  {code}: {count}");
+    }
+    if summary.failed == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+fn pregenerate(config_path: Option<&Path>, args: &PregenArgs) -> anyhow::Result<PregenSummary> {
+    let config = AppConfig::load(config_path, |config| {
+        if let Some(cache_dir) = &args.cache_dir {
+            config.cache.dir = cache_dir.clone();
+        }
+    })
+    .context("Invalid configuration")?;
+    let jobs = pregen::jobs(&pregen::load_manifest(&args.manifest)?);
+    let options = PregenOptions {
+        concurrency: args.concurrency.into(),
+        interval: args.rate,
+        checkpoint: args.checkpoint(),
     };
 
-    info!("Starting image optimizer service on port {port}");
+    actix_web::rt::System::new().block_on(async {
+        fs::create_dir_all(&config.cache.dir).await?;
+        let optimizer = Optimizer::builder().config(config).build();
+        pregen::run(&optimizer, jobs, &options).await
+    })
+}
+
+async fn serve(
+    config: AppConfig,
+    tls: Option<Arc<ReloadableCert>>,
+    reload: impl Fn() -> anyhow::Result<AppConfig> + 'static,
+) -> std::io::Result<()> {
+    logging::init();
+
+    let shutdown_timeout = config.server.shutdown_timeout_secs;
+    let shutdown_delay = config.server.shutdown_delay_secs;
+    let bind_address = (config.server.bind_address.clone(), config.server.port);
+    let tls_http_port = config.tls.http_port;
+
+    // Ensure cache directory exists
+    fs::create_dir_all(&config.cache.dir).await?;
+    fs::create_dir_all(&config.storage.dir).await?;
+
+    let api_keys = config
+        .auth
+        .api_keys
+        .as_deref()
+        .map(ApiKeys::parse)
+        .unwrap_or_default();
+    if api_keys.is_enabled() {
+        info!(
+            "API key authentication enabled with {} key(s)",
+            api_keys.len()
+        );
+    }
+
+    let optimizer = Optimizer::builder()
+        .config(config)
+        .api_keys(api_keys)
+        .build();
+    let app_state = optimizer.state().clone();
+    let reload_state = app_state.clone();
+    let exit_state = app_state.clone();
+    let shutting_down = app_state.shutting_down.clone();
+
+    info!(
+        "Starting image optimizer service on {}://{}:{}",
+        if tls.is_some() { "https" } else { "http" },
+        bind_address.0,
+        bind_address.1
+    );
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(app_state.clone()))
+            .wrap(Condition::new(
+                tls_http_port.is_some(),
+                from_fn(plain_http_health_only),
+            ))
+            .wrap(from_fn(count_errors))
+            .wrap(from_fn(problem_details_context))
             .wrap(
-                Cors::default()
-                    .allow_any_origin()
-                    .allowed_methods(vec!["GET", "OPTIONS"])
-                    .allowed_headers(vec!["Origin", "X-Requested-With", "Content-Type", "Accept"])
-                    .max_age(3600),
-            )
-            .route("/health", web::get().to(health_check))
-            .route("/errors", web::get().to(list_errors))
-            .route(
-                "/img-optimizer/v1/img",
-                web::get().to(optimize_image_handler),
-            )
-            .route(
-                "/img-optimizer/v1/img/{image_id}",
-                web::get().to(direct_image_handler),
+                cors(&app_state.config())
+                    .allowed_methods(vec!["GET", "POST", "PUT", "OPTIONS"])
+                    .allowed_headers(vec![
+                        "Origin",
+                        "X-Requested-With",
+                        "Content-Type",
+                        "Accept",
+                        "X-Api-Key",
+                        "X-Request-Id",
+                    ])
+                    .expose_headers(EXPOSED_HEADERS.iter().copied())
+                    .max_age(app_state.config().cors.max_age_secs),
             )
+            .wrap(from_fn(access_log))
+            .configure(routes)
     })
-    .bind(format!("0.0.0.0:{port}"))?
-    .run()
-    .await
+    .disable_signals()
+    .shutdown_timeout(shutdown_timeout);
+
+    let server = match &tls {
+        Some(cert) => {
+            let tls_config = tls::server_config(cert.clone()).map_err(std::io::Error::other)?;
+            let server = server.bind_rustls_0_23(bind_address.clone(), tls_config)?;
+            match tls_http_port {
+                Some(port) => {
+                    info!(
+                        "Serving health checks over plain HTTP on {}:{port}",
+                        bind_address.0
+                    );
+                    server.bind((bind_address.0.clone(), port))?
+                }
+                None => server,
+            }
+        }
+        None => server.bind(bind_address)?,
+    }
+    .run();
+
+    actix_web::rt::spawn(reload_on_sighup(reload_state, reload, tls));
+
+    let handle = server.handle();
+    let (stopping_tx, mut stopping_rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        wait_for_shutdown_signal().await;
+        info!(
+            "Shutdown signal received, draining in-flight requests (timeout: {shutdown_timeout}s)"
+        );
+        shutting_down.store(true, Ordering::SeqCst);
+
+        // Keep serving while load balancers notice the failing readiness check
+        if shutdown_delay > 0 {
+            tokio::time::sleep(Duration::from_secs(shutdown_delay)).await;
+        }
+
+        let _ = stopping_tx.send(Instant::now() + Duration::from_secs(shutdown_timeout));
+        handle.stop(true).await;
+    });
+
+    server.await?;
+    info!("Server stopped");
+
+    // Requests and pending writes share the shutdown timeout
+    let deadline = stopping_rx
+        .try_recv()
+        .unwrap_or_else(|_| Instant::now() + Duration::from_secs(shutdown_timeout));
+    flush_before_exit(&exit_state, deadline).await;
+
+    logging::shutdown();
+    Ok(())
+}
+
+/// Waits until `deadline` at most for the work still pending once the
+/// server stopped: deferred cache writes, then queued audit records.
+async fn flush_before_exit(state: &AppState, deadline: Instant) {
+    let pending_writes = &state.pending_writes;
+    if !pending_writes.is_empty() {
+        info!("Waiting for {} deferred cache writes", pending_writes.len());
+        let budget = deadline.saturating_duration_since(Instant::now());
+        if !pending_writes.drain(budget).await {
+            warn!("Deferred cache writes still pending at the shutdown timeout were dropped");
+        }
+    }
+
+    if let Some(audit) = state.audit.clone() {
+        let budget = deadline.saturating_duration_since(Instant::now());
+        let flush = tokio::task::spawn_blocking(move || audit.flush());
+        if tokio::time::timeout(budget, flush).await.is_err() {
+            warn!("Audit records still queued at the shutdown timeout were dropped");
+        }
+    }
+}
+
+fn cors(config: &AppConfig) -> Cors {
+    if config.cors.allows_any_origin() {
+        return Cors::default().allow_any_origin();
+    }
+
+    config
+        .cors
+        .allowed_origins
+        .iter()
+        .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+}
+
+/// Re-reads the configuration, then the TLS certificate and key, on SIGHUP,
+/// keeping the current ones if the new files are invalid.
+async fn reload_on_sighup(
+    state: AppState,
+    reload: impl Fn() -> anyhow::Result<AppConfig>,
+    cert: Option<Arc<ReloadableCert>>,
+) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let Ok(mut sighup) = signal(SignalKind::hangup()) else {
+            return;
+        };
+        while sighup.recv().await.is_some() {
+            if let Err(e) = reload().and_then(|config| state.reload_config(config)) {
+                error!("Failed to reload configuration: {e:#}");
+            }
+            if let Some(cert) = &cert {
+                match cert.reload() {
+                    Ok(()) => info!("TLS certificate reloaded"),
+                    Err(e) => error!("Failed to reload TLS certificate: {e:#}"),
+                }
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = (state, reload, cert);
+}
+
+async fn wait_for_shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = sigterm.recv() => {}
+                    _ = tokio::signal::ctrl_c() => {}
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
 }
@@ -0,0 +1,353 @@
+use crate::error::AppError;
+use crate::stats::Stats;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use std::future::Future;
+use std::time::{Duration, Instant};
+#[cfg(feature = "actix")]
+use {
+    crate::AppState,
+    actix_web::{
+        body::MessageBody,
+        dev::{ServiceRequest, ServiceResponse},
+        middleware::Next,
+        web, Error,
+    },
+};
+
+/// Pipeline phases timed inside `process_image_request`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    CacheRead,
+    Fetch,
+    Decode,
+    Transform,
+    Encode,
+    CacheWrite,
+}
+
+impl Phase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phase::CacheRead => "cache_read",
+            Phase::Fetch => "fetch",
+            Phase::Decode => "decode",
+            Phase::Transform => "transform",
+            Phase::Encode => "encode",
+            Phase::CacheWrite => "cache_write",
+        }
+    }
+}
+
+/// Durations measured for a single request, in the order they happened,
+/// whether it was served from the cache, and how much was read from its
+/// source.
+#[derive(Debug, Clone, Default)]
+pub struct PhaseTimings {
+    phases: Vec<(Phase, Duration)>,
+    cache_status: Option<&'static str>,
+    source_size: Option<usize>,
+}
+
+impl PhaseTimings {
+    pub fn record(&mut self, phase: Phase, duration: Duration) {
+        self.phases.push((phase, duration));
+    }
+
+    /// Runs `f`, recording how long it took under `phase`.
+    pub fn time<T>(&mut self, phase: Phase, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.record(phase, start.elapsed());
+        result
+    }
+
+    /// Awaits `future`, recording how long it took under `phase`.
+    pub async fn time_async<T>(&mut self, phase: Phase, future: impl Future<Output = T>) -> T {
+        let start = Instant::now();
+        let result = future.await;
+        self.record(phase, start.elapsed());
+        result
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(Phase, Duration)> {
+        self.phases.iter()
+    }
+
+    pub fn set_cache_status(&mut self, status: &'static str) {
+        self.cache_status = Some(status);
+    }
+
+    /// `hit` or `miss`, once the cache was looked up.
+    pub fn cache_status(&self) -> Option<&'static str> {
+        self.cache_status
+    }
+
+    pub fn set_source_size(&mut self, size: usize) {
+        self.source_size = Some(size);
+    }
+
+    /// Bytes read from the source, once fetched.
+    pub fn source_size(&self) -> Option<usize> {
+        self.source_size
+    }
+
+    /// Formats the timings as a `Server-Timing` header value, e.g.
+    /// `fetch;dur=123.4, encode;dur=45.0, cache;desc="miss"`.
+    pub fn server_timing(&self) -> String {
+        let mut entries: Vec<String> = self
+            .phases
+            .iter()
+            .map(|(phase, duration)| {
+                format!(
+                    "{};dur={:.1}",
+                    phase.as_str(),
+                    duration.as_secs_f64() * 1000.0
+                )
+            })
+            .collect();
+        if let Some(status) = self.cache_status {
+            entries.push(format!("cache;desc=\"{status}\""));
+        }
+        entries.join(", ")
+    }
+}
+
+/// Prometheus registry and the collectors the request pipeline reports to.
+/// Unlabeled counters of the hot path are kept in [`Stats`], exported here.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    phase_duration: HistogramVec,
+    requests: IntCounterVec,
+    errors: IntCounterVec,
+    requests_by_key: IntCounterVec,
+    processing_waiting: IntGaugeVec,
+    worker_queue_depth: IntGauge,
+    fetch_in_flight: IntGaugeVec,
+}
+
+impl Metrics {
+    /// Metrics exporting the counters of `stats` along with their own.
+    pub fn with_stats(stats: &Stats) -> Self {
+        let metrics = Self::new();
+        stats.register(&metrics.registry);
+        metrics
+    }
+
+    pub fn new() -> Self {
+        let registry = Registry::new();
+
+        let phase_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "img_optimizer_phase_duration_seconds",
+                "Time spent in each phase of the image pipeline",
+            )
+            .buckets(vec![
+                0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+            ]),
+            &["phase"],
+        )
+        .expect("Failed to create phase duration histogram");
+
+        let requests = IntCounterVec::new(
+            Opts::new(
+                "img_optimizer_requests_total",
+                "Image requests by response status, output format and error code",
+            ),
+            &["status", "format", "error_code"],
+        )
+        .expect("Failed to create requests counter");
+
+        let errors = IntCounterVec::new(
+            Opts::new(
+                "img_optimizer_errors_total",
+                "Error responses of any endpoint by error code",
+            ),
+            &["code"],
+        )
+        .expect("Failed to create errors counter");
+
+        let requests_by_key = IntCounterVec::new(
+            Opts::new(
+                "img_optimizer_requests_by_key_total",
+                "Requests authorized by each API key, by key label",
+            ),
+            &["key"],
+        )
+        .expect("Failed to create requests by key counter");
+
+        let processing_waiting = IntGaugeVec::new(
+            Opts::new(
+                "img_optimizer_processing_waiting",
+                "Requests waiting for a processing slot by job class",
+            ),
+            &["class"],
+        )
+        .expect("Failed to create processing waiting gauge");
+
+        let worker_queue_depth = IntGauge::new(
+            "img_optimizer_worker_queue_depth",
+            "Images waiting for a processing worker thread",
+        )
+        .expect("Failed to create worker queue depth gauge");
+
+        let fetch_in_flight = IntGaugeVec::new(
+            Opts::new(
+                "img_optimizer_fetch_in_flight",
+                "Origin fetches currently in flight by host",
+            ),
+            &["host"],
+        )
+        .expect("Failed to create fetch in-flight gauge");
+
+        registry
+            .register(Box::new(phase_duration.clone()))
+            .expect("Failed to register phase duration histogram");
+        registry
+            .register(Box::new(requests.clone()))
+            .expect("Failed to register requests counter");
+        registry
+            .register(Box::new(errors.clone()))
+            .expect("Failed to register errors counter");
+        registry
+            .register(Box::new(requests_by_key.clone()))
+            .expect("Failed to register requests by key counter");
+        registry
+            .register(Box::new(processing_waiting.clone()))
+            .expect("Failed to register processing waiting gauge");
+        registry
+            .register(Box::new(worker_queue_depth.clone()))
+            .expect("Failed to register worker queue depth gauge");
+        registry
+            .register(Box::new(fetch_in_flight.clone()))
+            .expect("Failed to register fetch in-flight gauge");
+
+        Self {
+            registry,
+            phase_duration,
+            requests,
+            errors,
+            requests_by_key,
+            processing_waiting,
+            worker_queue_depth,
+            fetch_in_flight,
+        }
+    }
+
+    pub fn observe_timings(&self, timings: &PhaseTimings) {
+        for (phase, duration) in timings.iter() {
+            self.phase_duration
+                .with_label_values(&[phase.as_str()])
+                .observe(duration.as_secs_f64());
+        }
+    }
+
+    pub fn record_success(&self, content_type: &str) {
+        let format = content_type.strip_prefix("image/").unwrap_or("unknown");
+        self.requests
+            .with_label_values(&["200", format, "none"])
+            .inc();
+    }
+
+    pub fn record_not_modified(&self, format: Option<&str>) {
+        self.requests
+            .with_label_values(&["304", format_label(format), "none"])
+            .inc();
+    }
+
+    pub fn record_error(&self, format: Option<&str>, error: &AppError) {
+        self.requests
+            .with_label_values(&[
+                error.metadata().status.as_str(),
+                format_label(format),
+                error.error_code(),
+            ])
+            .inc();
+    }
+
+    /// Sets the queue depth of a job class, read from the limiter when
+    /// rendering.
+    pub fn set_processing_waiting(&self, class: &str, waiting: usize) {
+        self.processing_waiting
+            .with_label_values(&[class])
+            .set(waiting as i64);
+    }
+
+    /// Sets the number of images waiting for a worker, read from the pool
+    /// when rendering.
+    pub fn set_worker_queue_depth(&self, queued: usize) {
+        self.worker_queue_depth.set(queued as i64);
+    }
+
+    /// Sets the fetches in flight per host, read from the host limiter when
+    /// rendering. Hosts without any are left out, so the series stay few.
+    pub fn set_fetch_in_flight(&self, in_flight: &[(String, usize)]) {
+        self.fetch_in_flight.reset();
+        for (host, count) in in_flight {
+            self.fetch_in_flight
+                .with_label_values(&[host.as_str()])
+                .set(*count as i64);
+        }
+    }
+
+    /// Counts an error response by code, see [`count_errors`].
+    pub fn record_error_response(&self, error: &AppError) {
+        self.errors.with_label_values(&[error.error_code()]).inc();
+    }
+
+    /// Counts a request authorized by the API key labeled `label`. Labels
+    /// come from the configured keys, so the series stay few.
+    pub fn record_key(&self, label: &str) {
+        self.requests_by_key.with_label_values(&[label]).inc();
+    }
+
+    /// Renders every registered collector in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("Failed to encode metrics");
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+/// Middleware counting every `AppError` response by error code, whichever
+/// endpoint or middleware produced it.
+#[cfg(feature = "actix")]
+pub async fn count_errors(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let res = next.call(req).await?;
+
+    let error = res
+        .response()
+        .error()
+        .and_then(|err| err.as_error::<AppError>());
+    let state = res.request().app_data::<web::Data<AppState>>();
+    if let (Some(error), Some(state)) = (error, state) {
+        state.metrics.record_error_response(error);
+    }
+
+    Ok(res)
+}
+
+/// Keeps the `format` label bounded whatever the caller put in `f`.
+fn format_label(format: Option<&str>) -> &'static str {
+    match format {
+        None => "auto",
+        Some("jpeg" | "jpg") => "jpeg",
+        Some("png") => "png",
+        Some("webp") => "webp",
+        Some(_) => "other",
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
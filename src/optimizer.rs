@@ -0,0 +1,256 @@
+//! [`Optimizer`], the entry point for using the pipeline as a library.
+
+use crate::audit::AuditLog;
+use crate::auth::ApiKeys;
+use crate::cache::{ImageCache, PendingWrites};
+use crate::config::{AppConfig, Limits};
+use crate::error::{AppError, AppResult};
+use crate::fetch::{self, HttpFetcher, ReqwestFetcher};
+use crate::host_limits::HostLimiter;
+use crate::image_processor::{Fit, OutputFormat};
+use crate::jobs::JobStore;
+use crate::limiter::ProcessingLimiter;
+use crate::metrics::{Metrics, PhaseTimings};
+use crate::origin::OriginPolicies;
+use crate::stats::Stats;
+use crate::storage::ImageStorage;
+use crate::transform_chain::{self, TransformStep};
+use crate::warnings::Warnings;
+use crate::worker_pool::WorkerPool;
+use crate::{process_image_request, AppState, ImageOutput, ImageParams};
+use arc_swap::ArcSwap;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+/// Image optimizer with its cache, HTTP client and limits, running the same
+/// pipeline as the HTTP handlers.
+///
+/// ```
+/// use img_optimizer::cache::ImageCache;
+/// use img_optimizer::image_processor::OutputFormat;
+/// use img_optimizer::{CacheStatus, OptimizeOptions, Optimizer};
+///
+/// # #[tokio::main]
+/// # async fn main() -> img_optimizer::error::AppResult<()> {
+/// let optimizer = Optimizer::builder().cache(ImageCache::in_memory()).build();
+///
+/// // A 1x1 PNG; any http(s), data: or configured file:// source works
+/// let src = "data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mNkYPhfDwAChwGA60e6kgAAAABJRU5ErkJggg==";
+/// let options = OptimizeOptions {
+///     format: Some(OutputFormat::Png),
+///     ..Default::default()
+/// };
+///
+/// let image = optimizer.optimize(src, &options).await?;
+/// assert_eq!(image.content_type, "image/png");
+/// assert_eq!((image.width, image.height), (1, 1));
+/// assert_eq!(image.cache_status, CacheStatus::Miss);
+///
+/// let again = optimizer.optimize(src, &options).await?;
+/// assert_eq!(again.cache_status, CacheStatus::Hit);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Optimizer {
+    state: AppState,
+}
+
+/// Transformation to apply, the library counterpart of the `tx`, `w`, `h`,
+/// `fit`, `bg`, `q` and `f` query parameters.
+#[derive(Debug, Clone, Default)]
+pub struct OptimizeOptions {
+    /// Chained steps, applied in order before the resize.
+    pub steps: Vec<TransformStep>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fit: Option<Fit>,
+    /// RGBA background for padding and for transparency in JPEG output.
+    pub background: Option<[u8; 4]>,
+    pub quality: Option<u32>,
+    /// Negotiated like an absent `f` when `None`.
+    pub format: Option<OutputFormat>,
+}
+
+/// An optimized image, as returned by [`Optimizer::optimize`].
+#[derive(Debug, Clone)]
+pub struct OptimizedImage {
+    pub data: Vec<u8>,
+    pub content_type: String,
+    pub width: u32,
+    pub height: u32,
+    /// Dimensions of the source image.
+    pub original_width: u32,
+    pub original_height: u32,
+    pub cache_status: CacheStatus,
+    /// Opaque strong entity tag of the output, without quotes.
+    pub etag: String,
+    /// Ways the image falls short of what was asked.
+    pub warnings: Warnings,
+}
+
+/// Whether an image was served from the cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheStatus {
+    Hit,
+    Miss,
+}
+
+impl Optimizer {
+    pub fn builder() -> OptimizerBuilder {
+        OptimizerBuilder::default()
+    }
+
+    /// Shared state, for serving the optimizer over HTTP.
+    pub fn state(&self) -> &AppState {
+        &self.state
+    }
+
+    /// Optimizes the image at `src`, using and filling the cache.
+    pub async fn optimize(&self, src: &str, opts: &OptimizeOptions) -> AppResult<OptimizedImage> {
+        let params = ImageParams {
+            src: Some(src.to_string()),
+            w: opts.width.map(|width| width.to_string()),
+            h: opts.height.map(|height| height.to_string()),
+            fit: opts.fit.map(|fit| fit.as_str().to_string()),
+            bg: opts.background.map(hex::encode),
+            q: opts.quality.map(|quality| quality.to_string()),
+            f: opts.format.map(|format| format.name().to_string()),
+            tx: (!opts.steps.is_empty()).then(|| transform_chain::canonical(&opts.steps)),
+            ..Default::default()
+        };
+
+        let mut timings = PhaseTimings::default();
+        let output = process_image_request(params, &self.state, None, &mut timings).await?;
+        let ImageOutput::Image {
+            data,
+            content_type,
+            etag,
+            metadata: Some(metadata),
+            warnings,
+        } = output
+        else {
+            // Only revalidations are answered with Not Modified, and
+            // transformed images always carry their dimensions
+            return Err(AppError::InternalServerError);
+        };
+
+        let cache_status = match timings.cache_status() {
+            Some("hit") => CacheStatus::Hit,
+            _ => CacheStatus::Miss,
+        };
+
+        Ok(OptimizedImage {
+            data: data.into(),
+            content_type,
+            width: metadata.width,
+            height: metadata.height,
+            original_width: metadata.original_width,
+            original_height: metadata.original_height,
+            cache_status,
+            etag,
+            warnings,
+        })
+    }
+}
+
+impl From<AppState> for Optimizer {
+    fn from(state: AppState) -> Self {
+        Self { state }
+    }
+}
+
+/// Builder of an [`Optimizer`]. Anything not set comes from
+/// [`AppConfig::default`]; the cache defaults to the configured directory.
+#[derive(Default)]
+pub struct OptimizerBuilder {
+    config: Option<AppConfig>,
+    cache: Option<ImageCache>,
+    client: Option<reqwest::Client>,
+    fetcher: Option<Arc<dyn HttpFetcher>>,
+    limits: Option<Limits>,
+    api_keys: Option<ApiKeys>,
+}
+
+impl OptimizerBuilder {
+    pub fn config(mut self, config: AppConfig) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    pub fn cache(mut self, cache: ImageCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Client fetching http(s) sources, unless [`Self::fetcher`] is set.
+    pub fn client(mut self, client: reqwest::Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Fetches http(s) sources with something other than reqwest.
+    pub fn fetcher(mut self, fetcher: impl HttpFetcher + 'static) -> Self {
+        self.fetcher = Some(Arc::new(fetcher));
+        self
+    }
+
+    /// Replaces the limits of the configuration.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    /// Keys the HTTP server requires, see [`ApiKeys`].
+    pub fn api_keys(mut self, api_keys: ApiKeys) -> Self {
+        self.api_keys = Some(api_keys);
+        self
+    }
+
+    pub fn build(self) -> Optimizer {
+        let mut config = self.config.unwrap_or_default();
+        if let Some(limits) = self.limits {
+            config.limits = limits;
+        }
+        let cache = self.cache.unwrap_or_else(|| {
+            if config.cache.index {
+                ImageCache::indexed(config.cache.dir.clone())
+            } else {
+                ImageCache::new(config.cache.dir.clone())
+            }
+        });
+
+        let client = self
+            .client
+            .unwrap_or_else(|| fetch::build_client(&config.fetch));
+        let fetcher = self
+            .fetcher
+            .unwrap_or_else(|| Arc::new(ReqwestFetcher::new(client.clone())));
+        let origins = Arc::new(OriginPolicies::from_config(&config));
+        let stats = Stats::new();
+
+        Optimizer {
+            state: AppState {
+                cache: Arc::new(RwLock::new(cache)),
+                pending_writes: Arc::new(PendingWrites::default()),
+                storage: Arc::new(ImageStorage::new(config.storage.dir.clone())),
+                client,
+                fetcher,
+                hosts: Arc::new(ArcSwap::from_pointee(
+                    HostLimiter::from_config(&config.fetch).with_origins(origins.clone()),
+                )),
+                origins: Arc::new(ArcSwap::new(origins)),
+                api_keys: Arc::new(self.api_keys.unwrap_or_default()),
+                metrics: Arc::new(Metrics::with_stats(&stats)),
+                stats: Arc::new(stats),
+                limiter: Arc::new(ProcessingLimiter::from_config(&config.processing)),
+                workers: Arc::new(WorkerPool::from_config(&config.processing)),
+                jobs: Arc::new(JobStore::from_config(&config.jobs)),
+                audit: AuditLog::from_config(&config.audit).map(Arc::new),
+                shutting_down: Arc::new(AtomicBool::new(false)),
+                config: Arc::new(ArcSwap::from_pointee(config)),
+            },
+        }
+    }
+}
@@ -0,0 +1,162 @@
+//! Per-origin fetch profiles, the `[origin."<pattern>"]` sections of the
+//! configuration: timeout, retries, concurrency, static headers and
+//! allowlist membership, resolved by the host of each source.
+//!
+//! A host gets the profile naming it exactly, else the `*.domain` profile
+//! of the longest domain it is a subdomain of, else the default profile,
+//! `*`. Values a profile leaves unset are those of the default profile,
+//! then of `fetch`.
+
+use crate::config::{AppConfig, FetchConfig, OriginProfile};
+use crate::host_limits::HostLimit;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Pattern of the default profile.
+pub const DEFAULT_PROFILE: &str = "*";
+
+/// Pause before the first retry when no profile sets one.
+const DEFAULT_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How the sources of one origin are fetched.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OriginPolicy {
+    /// Pattern of the profile applying, `*` for the default one, also when
+    /// the configuration has none.
+    pub profile: String,
+    /// Budget of each attempt, redirects included, and of the wait for a
+    /// fetch slot to the host.
+    pub timeout: Duration,
+    /// Attempts after the first one, for failures that may pass, see
+    /// [`crate::error::AppError::is_transient`].
+    pub retries: u32,
+    /// Pause before the first retry, doubled before each next one.
+    pub retry_backoff: Duration,
+    pub limit: HostLimit,
+    /// Sent with every fetch, replacing the default `User-Agent`.
+    pub headers: HeaderMap,
+    /// Allowlist membership; `None` leaves it to `fetch.allowed_hosts`.
+    pub allowed: Option<bool>,
+}
+
+/// The [`OriginPolicy`] of every host.
+#[derive(Debug)]
+pub struct OriginPolicies {
+    exact: HashMap<String, Arc<OriginPolicy>>,
+    /// `*.domain` profiles, by domain, longest first.
+    wildcards: Vec<(String, Arc<OriginPolicy>)>,
+    default: Arc<OriginPolicy>,
+    fetch: FetchConfig,
+}
+
+impl OriginPolicies {
+    /// Resolves the profiles of `config.origin` over `config.fetch`.
+    /// Patterns are matched in any case; invalid header names or values,
+    /// which validation reports, are skipped.
+    pub fn from_config(config: &AppConfig) -> Self {
+        let profiles: HashMap<String, &OriginProfile> = config
+            .origin
+            .iter()
+            .map(|(pattern, profile)| (pattern.to_ascii_lowercase(), profile))
+            .collect();
+        let base = OriginProfile::default();
+        let base = profiles.get(DEFAULT_PROFILE).copied().unwrap_or(&base);
+        let resolve = |pattern: &str, profile: &OriginProfile| {
+            Arc::new(resolve(pattern, profile, base, &config.fetch))
+        };
+
+        let mut exact = HashMap::new();
+        let mut wildcards = Vec::new();
+        for (pattern, profile) in &profiles {
+            if pattern == DEFAULT_PROFILE {
+                continue;
+            }
+            let policy = resolve(pattern, profile);
+            match pattern.strip_prefix("*.") {
+                Some(domain) => wildcards.push((domain.to_string(), policy)),
+                None => {
+                    exact.insert(pattern.clone(), policy);
+                }
+            }
+        }
+        wildcards.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+
+        Self {
+            exact,
+            wildcards,
+            default: resolve(DEFAULT_PROFILE, base),
+            fetch: config.fetch.clone(),
+        }
+    }
+
+    /// The policy applying to `host`.
+    pub fn policy(&self, host: &str) -> Arc<OriginPolicy> {
+        let host = host.to_ascii_lowercase();
+        if let Some(policy) = self.exact.get(&host) {
+            return policy.clone();
+        }
+        self.wildcards
+            .iter()
+            .find(|(domain, _)| {
+                host.strip_suffix(domain.as_str())
+                    .is_some_and(|subdomain| subdomain.ends_with('.'))
+            })
+            .map_or_else(|| self.default.clone(), |(_, policy)| policy.clone())
+    }
+
+    /// Whether sources may be fetched from, and redirected to, `host`: as
+    /// its profile says, or else as `fetch.allowed_hosts` does.
+    pub fn allows(&self, host: &str) -> bool {
+        self.policy(host)
+            .allowed
+            .unwrap_or_else(|| self.fetch.allows_host(host))
+    }
+}
+
+fn resolve(
+    pattern: &str,
+    profile: &OriginProfile,
+    base: &OriginProfile,
+    fetch: &FetchConfig,
+) -> OriginPolicy {
+    let mut headers = HeaderMap::new();
+    for (name, value) in base.headers.iter().chain(&profile.headers) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            headers.insert(name, value);
+        }
+    }
+
+    OriginPolicy {
+        profile: pattern.to_string(),
+        timeout: Duration::from_secs(
+            profile
+                .timeout_secs
+                .or(base.timeout_secs)
+                .unwrap_or(fetch.timeout_secs),
+        ),
+        retries: profile.retries.or(base.retries).unwrap_or(0),
+        retry_backoff: profile
+            .retry_backoff_ms
+            .or(base.retry_backoff_ms)
+            .map_or(DEFAULT_RETRY_BACKOFF, Duration::from_millis),
+        limit: HostLimit {
+            max_concurrent: profile
+                .max_concurrent
+                .or(base.max_concurrent)
+                .unwrap_or(fetch.max_per_host),
+            min_interval: Duration::from_millis(
+                profile
+                    .min_interval_ms
+                    .or(base.min_interval_ms)
+                    .unwrap_or(fetch.min_interval_ms),
+            ),
+        },
+        headers,
+        allowed: profile.allowed.or(base.allowed),
+    }
+}
@@ -0,0 +1,48 @@
+//! Path-style transformation URLs, `/img-optimizer/v1/t/{options}/{src_b64}`,
+//! for CDNs that cache more reliably on path segments than query strings.
+//!
+//! Grammar of the `{options}` segment:
+//!
+//! ```text
+//! options = "-" | option *( "," option )
+//! option  = key "_" value
+//! key     = "w" | "q" | "f"
+//! ```
+//!
+//! `-` means no options. Each key maps to the query parameter of the same
+//! name and may appear once, e.g. `w_800,q_75,f_webp`. `{src_b64}` is the
+//! base64url-encoded source URL, as in the `srcb64` query parameter.
+
+use crate::error::{AppError, AppResult};
+use crate::ImageParams;
+
+/// Builds the parameters of a path-style request.
+pub fn parse(options: &str, src_b64: &str) -> AppResult<ImageParams> {
+    let mut params = ImageParams {
+        srcb64: Some(src_b64.to_string()),
+        ..ImageParams::default()
+    };
+    if options == "-" {
+        return Ok(params);
+    }
+
+    for token in options.split(',') {
+        let invalid = || AppError::InvalidOption {
+            token: token.to_string(),
+        };
+        let (key, value) = token.split_once('_').ok_or_else(invalid)?;
+
+        match key {
+            "w" if params.w.is_none() => {
+                params.w = Some(value.parse::<u32>().map_err(|_| invalid())?.to_string())
+            }
+            "q" if params.q.is_none() => {
+                params.q = Some(value.parse::<u8>().map_err(|_| invalid())?.to_string())
+            }
+            "f" if params.f.is_none() && !value.is_empty() => params.f = Some(value.to_string()),
+            _ => return Err(invalid()),
+        }
+    }
+
+    Ok(params)
+}
@@ -0,0 +1,221 @@
+//! Pre-generation of the images a site needs, listed in a manifest, so that
+//! visitors get cache hits: `img-optimizer pregen --manifest manifest.json`.
+//!
+//! The manifest is a JSON array of entries such as
+//! `{"src": "https://example.com/hero.jpg", "widths": [640, 1280], "format": "webp"}`.
+//! Each width of each entry is one image, processed through the
+//! [`Optimizer`] and its cache. Completed images are appended to a checkpoint
+//! file as they finish, so an interrupted run resumes where it stopped.
+
+use crate::error::AppError;
+use crate::image_processor::OutputFormat;
+use crate::optimizer::{CacheStatus, OptimizeOptions, Optimizer};
+use anyhow::Context;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::time::Instant;
+
+/// One image of the manifest, at each of its widths.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestEntry {
+    pub src: String,
+    /// Widths to generate; the original width when empty.
+    #[serde(default)]
+    pub widths: Vec<u32>,
+    /// Output format, as the `f` parameter; negotiated like an absent `f`
+    /// when omitted.
+    pub format: Option<String>,
+    pub quality: Option<u32>,
+}
+
+/// Reads a manifest file.
+pub fn load_manifest(path: &Path) -> anyhow::Result<Vec<ManifestEntry>> {
+    let raw = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read manifest {}", path.display()))?;
+    serde_json::from_str(&raw).with_context(|| format!("Invalid manifest {}", path.display()))
+}
+
+/// One image to generate.
+#[derive(Debug, Clone)]
+pub struct PregenJob {
+    /// Identifies the image in the checkpoint: the source and options, as
+    /// the query of `GET /img-optimizer/v1/img`.
+    pub key: String,
+    pub src: String,
+    /// The options, or why the entry can't be processed.
+    pub options: Result<OptimizeOptions, AppError>,
+}
+
+/// The images of `entries`, in order.
+pub fn jobs(entries: &[ManifestEntry]) -> Vec<PregenJob> {
+    let mut jobs = Vec::new();
+    for entry in entries {
+        let format = entry.format.as_deref().map(|name| {
+            OutputFormat::parse(name).ok_or_else(|| AppError::InvalidImageFormat {
+                format: name.to_string(),
+            })
+        });
+        let widths: Vec<Option<u32>> = if entry.widths.is_empty() {
+            vec![None]
+        } else {
+            entry.widths.iter().copied().map(Some).collect()
+        };
+
+        for width in widths {
+            let mut key = url::form_urlencoded::Serializer::new(String::new());
+            key.append_pair("src", &entry.src);
+            if let Some(width) = width {
+                key.append_pair("w", &width.to_string());
+            }
+            if let Some(quality) = entry.quality {
+                key.append_pair("q", &quality.to_string());
+            }
+            if let Some(format) = &entry.format {
+                key.append_pair("f", format);
+            }
+
+            let options = match &format {
+                Some(Err(err)) => Err(err.clone()),
+                Some(Ok(format)) => Ok(Some(*format)),
+                None => Ok(None),
+            }
+            .map(|format| OptimizeOptions {
+                width,
+                quality: entry.quality,
+                format,
+                ..Default::default()
+            });
+            jobs.push(PregenJob {
+                key: key.finish(),
+                src: entry.src.clone(),
+                options,
+            });
+        }
+    }
+    jobs
+}
+
+/// How a run goes.
+#[derive(Debug, Clone)]
+pub struct PregenOptions {
+    /// Images processed at once.
+    pub concurrency: usize,
+    /// Least time between the starts of two images, to bound the rate.
+    pub interval: Option<Duration>,
+    /// File recording the keys of the completed images.
+    pub checkpoint: PathBuf,
+}
+
+/// Image that failed, by key.
+#[derive(Debug, Clone, Serialize)]
+pub struct PregenFailure {
+    pub key: String,
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// Outcome of a run.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PregenSummary {
+    pub total: usize,
+    /// Processed and cached by this run.
+    pub processed: usize,
+    /// Found in the cache already.
+    pub cached: usize,
+    /// Completed by an earlier run, according to the checkpoint.
+    pub resumed: usize,
+    pub failed: usize,
+    /// Failures per error code.
+    pub errors: BTreeMap<&'static str, usize>,
+    pub failures: Vec<PregenFailure>,
+}
+
+/// Generates the images of `jobs` that the checkpoint doesn't list as
+/// completed, appending each one that completes to it. Failures are
+/// counted, not fatal, and left out of the checkpoint so the next run
+/// retries them. The checkpoint is removed once every image completed.
+/// Fails only when the checkpoint can't be read or written.
+pub async fn run(
+    optimizer: &Optimizer,
+    jobs: Vec<PregenJob>,
+    options: &PregenOptions,
+) -> anyhow::Result<PregenSummary> {
+    let checkpoint = &options.checkpoint;
+    let completed: HashSet<String> = match tokio::fs::read_to_string(checkpoint).await {
+        Ok(raw) => raw.lines().map(str::to_string).collect(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashSet::new(),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read {}", checkpoint.display()))
+        }
+    };
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(checkpoint)
+        .await
+        .with_context(|| format!("Failed to open {}", checkpoint.display()))?;
+
+    let mut summary = PregenSummary {
+        total: jobs.len(),
+        ..Default::default()
+    };
+    let pending: Vec<PregenJob> = jobs
+        .into_iter()
+        .filter(|job| !completed.contains(&job.key))
+        .collect();
+    summary.resumed = summary.total - pending.len();
+
+    let start = Instant::now();
+    let mut outcomes = futures_util::stream::iter(pending.into_iter().enumerate())
+        .map(|(index, job)| async move {
+            if let Some(interval) = options.interval {
+                tokio::time::sleep_until(start + interval * index as u32).await;
+            }
+            let result = match &job.options {
+                Ok(opts) => optimizer.optimize(&job.src, opts).await,
+                Err(err) => Err(err.clone()),
+            };
+            (job.key, result)
+        })
+        .buffer_unordered(options.concurrency.max(1));
+
+    while let Some((key, result)) = outcomes.next().await {
+        match result {
+            Ok(image) => {
+                match image.cache_status {
+                    CacheStatus::Hit => summary.cached += 1,
+                    CacheStatus::Miss => summary.processed += 1,
+                }
+                // A line at a time, so an interruption loses at most this one
+                let written = async {
+                    file.write_all(format!("{key}\n").as_bytes()).await?;
+                    file.flush().await
+                };
+                written
+                    .await
+                    .with_context(|| format!("Failed to write {}", checkpoint.display()))?;
+            }
+            Err(err) => {
+                summary.failed += 1;
+                *summary.errors.entry(err.error_code()).or_default() += 1;
+                summary.failures.push(PregenFailure {
+                    key,
+                    code: err.error_code(),
+                    message: err.to_string(),
+                });
+            }
+        }
+    }
+
+    if summary.failed == 0 {
+        tokio::fs::remove_file(checkpoint)
+            .await
+            .with_context(|| format!("Failed to remove {}", checkpoint.display()))?;
+    }
+    Ok(summary)
+}
@@ -0,0 +1,103 @@
+//! Single byte ranges of image responses (`Range: bytes=start-end`), so
+//! download managers can resume large originals. A range is cut from the
+//! response body, which for cache hits is the cache file's bytes. Multipart
+//! ranges are not supported: those requests get the whole image.
+
+use crate::error::{AppError, AppResult};
+
+/// Value of `Accept-Ranges` on complete image responses.
+pub const ACCEPT_RANGES: &str = "bytes";
+
+/// Bytes `start..=end` of a representation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl ByteRange {
+    /// Value of `Content-Range` for this range of a `length`-byte body.
+    pub fn content_range(&self, length: usize) -> String {
+        format!("bytes {}-{}/{length}", self.start, self.end)
+    }
+}
+
+/// The `Range` and `If-Range` headers of a request.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RangeRequest {
+    pub range: String,
+    pub if_range: Option<String>,
+}
+
+impl RangeRequest {
+    /// `None` without a `Range` header.
+    pub fn from_headers(range: Option<&str>, if_range: Option<&str>) -> Option<Self> {
+        Some(Self {
+            range: range?.to_string(),
+            if_range: if_range.map(str::to_string),
+        })
+    }
+
+    /// The range to send of the `length`-byte representation tagged `etag`,
+    /// `None` for the whole of it: the range is malformed or multipart, or
+    /// `If-Range` names another representation. Dates in `If-Range` never
+    /// match, as image responses carry no `Last-Modified`.
+    pub fn resolve(&self, etag: &str, length: usize) -> AppResult<Option<ByteRange>> {
+        if let Some(if_range) = &self.if_range {
+            if if_range.trim() != format!("\"{etag}\"") {
+                return Ok(None);
+            }
+        }
+        parse_range(&self.range, length)
+    }
+}
+
+/// Parses a `Range` header against a `length`-byte body. `Ok(None)` when it
+/// is to be ignored, being malformed, in another unit or multipart;
+/// [`AppError::RangeNotSatisfiable`] when it selects no byte of the body.
+pub fn parse_range(value: &str, length: usize) -> AppResult<Option<ByteRange>> {
+    let Some((unit, spec)) = value.trim().split_once('=') else {
+        return Ok(None);
+    };
+    if !unit.trim().eq_ignore_ascii_case("bytes") || spec.contains(',') {
+        return Ok(None);
+    }
+    let Some((first, last)) = spec.trim().split_once('-') else {
+        return Ok(None);
+    };
+    let (first, last) = (first.trim(), last.trim());
+    let unsatisfiable = || AppError::RangeNotSatisfiable { length };
+
+    if first.is_empty() {
+        // Suffix range: the last `last` bytes
+        let Ok(suffix) = last.parse::<usize>() else {
+            return Ok(None);
+        };
+        if suffix == 0 || length == 0 {
+            return Err(unsatisfiable());
+        }
+        return Ok(Some(ByteRange {
+            start: length.saturating_sub(suffix),
+            end: length - 1,
+        }));
+    }
+
+    let Ok(start) = first.parse::<usize>() else {
+        return Ok(None);
+    };
+    let end = if last.is_empty() {
+        usize::MAX
+    } else {
+        match last.parse::<usize>() {
+            Ok(end) if end >= start => end,
+            _ => return Ok(None),
+        }
+    };
+    if start >= length {
+        return Err(unsatisfiable());
+    }
+    Ok(Some(ByteRange {
+        start,
+        end: end.min(length - 1),
+    }))
+}
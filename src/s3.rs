@@ -0,0 +1,125 @@
+//! `s3://bucket/key` sources, compiled in with the `s3-source` feature.
+//!
+//! Credentials and region are resolved by the AWS SDK from the standard
+//! environment (`AWS_ACCESS_KEY_ID`, `AWS_REGION`, profiles, instance roles,
+//! ...). Only buckets listed in `s3.allowed_buckets` can be read.
+
+use crate::config::AppConfig;
+use crate::error::{AppError, AppResult};
+use aws_sdk_s3::{error::SdkError, operation::get_object::GetObjectError, Client};
+use aws_smithy_http_client::tls::{self, rustls_provider::CryptoMode};
+use log::warn;
+use std::time::Duration;
+use tokio::sync::OnceCell;
+
+static CLIENT: OnceCell<Client> = OnceCell::const_new();
+
+/// Shared client, created on the first S3 fetch.
+async fn client(config: &AppConfig) -> &'static Client {
+    CLIENT
+        .get_or_init(|| async {
+            let http_client = aws_smithy_http_client::Builder::new()
+                .tls_provider(tls::Provider::Rustls(CryptoMode::Ring))
+                .build_https();
+            let mut loader = aws_config::defaults(aws_config::BehaviorVersion::latest())
+                .http_client(http_client);
+            if let Some(endpoint_url) = &config.s3.endpoint_url {
+                loader = loader.endpoint_url(endpoint_url);
+            }
+            let shared = loader.load().await;
+
+            let s3_config = aws_sdk_s3::config::Builder::from(&shared)
+                .force_path_style(config.s3.force_path_style)
+                .build();
+            Client::from_conf(s3_config)
+        })
+        .await
+}
+
+/// Splits `s3://bucket/key` into its bucket and key.
+pub fn parse_url(src: &str) -> AppResult<(&str, &str)> {
+    src.strip_prefix("s3://")
+        .and_then(|rest| rest.split_once('/'))
+        .filter(|(bucket, key)| !bucket.is_empty() && !key.is_empty())
+        .ok_or(AppError::InvalidImageUrl)
+}
+
+/// Downloads the object `src` points to, enforcing the bucket allowlist,
+/// the fetch timeout and the maximum image size.
+pub async fn fetch_object(src: &str, config: &AppConfig) -> AppResult<Vec<u8>> {
+    let failed = |reason: &str| AppError::ImageFetchFailed {
+        url: src.to_string(),
+        reason: reason.to_string(),
+    };
+
+    let (bucket, key) = parse_url(src)?;
+    if !config
+        .s3
+        .allowed_buckets
+        .iter()
+        .any(|allowed| allowed == bucket)
+    {
+        warn!("Refused {src}: the bucket is not allowed");
+        return Err(AppError::OriginNotAllowed);
+    }
+
+    let download = async {
+        let output = client(config)
+            .await
+            .get_object()
+            .bucket(bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(|e| {
+                warn!("Failed to fetch {src}: {e:?}");
+                failed(get_object_failure(&e))
+            })?;
+
+        let max_size = config.limits.max_image_size;
+        if let Some(length) = output
+            .content_length()
+            .filter(|&length| length > max_size as i64)
+        {
+            return Err(AppError::SourceTooLargeBytes {
+                limit: max_size,
+                actual: length as usize,
+            });
+        }
+
+        let mut body = output.body;
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body
+            .try_next()
+            .await
+            .map_err(|_| failed("the download was interrupted"))?
+        {
+            if bytes.len() + chunk.len() > max_size {
+                return Err(AppError::SourceTooLargeBytes {
+                    limit: max_size,
+                    actual: bytes.len() + chunk.len(),
+                });
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        Ok(bytes)
+    };
+
+    tokio::time::timeout(Duration::from_secs(config.fetch.timeout_secs), download)
+        .await
+        .map_err(|_| failed("the download timed out"))?
+}
+
+fn get_object_failure(err: &SdkError<GetObjectError>) -> &'static str {
+    match err {
+        SdkError::ServiceError(service_error) => match service_error.err() {
+            GetObjectError::NoSuchKey(_) => "the object does not exist",
+            _ => match service_error.raw().status().as_u16() {
+                403 => "access to the object was denied",
+                404 => "the object does not exist",
+                _ => "S3 rejected the request",
+            },
+        },
+        _ => "S3 could not be reached",
+    }
+}
@@ -0,0 +1,147 @@
+//! Self-test run by `img-optimizer check`, for container health checks that
+//! invoke a binary rather than an HTTP client.
+
+use crate::sniff::DetectedFormat;
+use anyhow::{anyhow, bail, Context};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// 1x1 transparent PNG optimized by [`CheckOptions::optimize`], sent as a
+/// `data:` URL so the check never depends on an origin.
+pub const PROBE_PNG: &str = "data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mNkYPhfDwAChwGA60e6kgAAAABJRU5ErkJggg==";
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone)]
+pub struct CheckOptions {
+    /// Base URL of the server, e.g. `http://127.0.0.1:3000`.
+    pub url: String,
+    /// Budget of the whole check, both requests included.
+    pub timeout: Duration,
+    /// Also optimize [`PROBE_PNG`] through the image endpoint.
+    pub optimize: bool,
+    /// Sent as `X-Api-Key` with the optimization, for servers requiring keys.
+    pub api_key: Option<String>,
+}
+
+/// Durations of the steps of a passing check.
+#[derive(Debug, Clone, Copy)]
+pub struct CheckReport {
+    pub ready: Duration,
+    pub optimize: Option<Duration>,
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ready in {}ms", self.ready.as_millis())?;
+        if let Some(optimize) = self.optimize {
+            write!(f, ", optimized a 1x1 PNG in {}ms", optimize.as_millis())?;
+        }
+        Ok(())
+    }
+}
+
+/// Calls `/health/ready` and, when asked, optimizes [`PROBE_PNG`]. Fails
+/// with a one-line reason when either does not succeed within the timeout.
+pub async fn run(options: &CheckOptions) -> anyhow::Result<CheckReport> {
+    let base = options.url.trim_end_matches('/');
+    let client = reqwest::Client::builder()
+        .timeout(options.timeout)
+        .build()
+        .context("Failed to create HTTP client")?;
+
+    tokio::time::timeout(options.timeout, async {
+        let start = Instant::now();
+        check_readiness(&client, &format!("{base}/health/ready")).await?;
+        let ready = start.elapsed();
+
+        let optimize = if options.optimize {
+            let start = Instant::now();
+            check_optimization(&client, base, options.api_key.as_deref()).await?;
+            Some(start.elapsed())
+        } else {
+            None
+        };
+        Ok(CheckReport { ready, optimize })
+    })
+    .await
+    .map_err(|_| anyhow!("timed out after {}ms", options.timeout.as_millis()))?
+}
+
+async fn check_readiness(client: &reqwest::Client, url: &str) -> anyhow::Result<()> {
+    let response = client.get(url).send().await.map_err(request_failed)?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+
+    // Name the failing checks from the readiness body when there is one
+    let body = json_body(response).await;
+    let failed: Vec<String> = body["checks"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter(|(_, check)| check["status"] != "ok")
+        .map(|(name, check)| match check["error"].as_str() {
+            Some(error) => format!("{name}: {error}"),
+            None => name.clone(),
+        })
+        .collect();
+    match (body["status"].as_str(), failed.is_empty()) {
+        (_, false) => bail!("readiness returned {status} ({})", failed.join(", ")),
+        (Some(reason), true) => bail!("readiness returned {status} ({reason})"),
+        (None, true) => bail!("readiness returned {status}"),
+    }
+}
+
+async fn check_optimization(
+    client: &reqwest::Client,
+    base: &str,
+    api_key: Option<&str>,
+) -> anyhow::Result<()> {
+    let mut request = client.get(format!("{base}/img-optimizer/v1/img")).query(&[
+        ("src", PROBE_PNG),
+        ("w", "1"),
+        ("f", "png"),
+    ]);
+    if let Some(api_key) = api_key {
+        request = request.header("X-Api-Key", api_key);
+    }
+    let response = request.send().await.map_err(request_failed)?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let body = json_body(response).await;
+        match body["errorCode"].as_str() {
+            Some(code) => bail!("optimization returned {status} ({code})"),
+            None => bail!("optimization returned {status}"),
+        }
+    }
+
+    let data = response.bytes().await.map_err(request_failed)?;
+    match DetectedFormat::detect(&data) {
+        Some(DetectedFormat::Png) => Ok(()),
+        _ => bail!(
+            "optimization returned {} bytes that are not a PNG",
+            data.len()
+        ),
+    }
+}
+
+/// Body of an error response, `Null` when it is not JSON.
+async fn json_body(response: reqwest::Response) -> serde_json::Value {
+    let body = response.bytes().await.unwrap_or_default();
+    serde_json::from_slice(&body).unwrap_or_default()
+}
+
+/// Keeps the cause, e.g. the refused connection, in the one-line summary.
+fn request_failed(e: reqwest::Error) -> anyhow::Error {
+    let e = e.without_url();
+    let mut message = e.to_string();
+    let mut source = std::error::Error::source(&e);
+    while let Some(cause) = source {
+        message = format!("{message}: {cause}");
+        source = cause.source();
+    }
+    anyhow!(message)
+}
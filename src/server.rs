@@ -0,0 +1,935 @@
+//! actix-web handlers serving the core pipeline over HTTP.
+
+use crate::config::AppConfig;
+use crate::error::{AppError, AppResult};
+use crate::image_processor::ImageProcessor;
+use crate::limiter::JobClass;
+use crate::metrics::PhaseTimings;
+use crate::range::{self, RangeRequest};
+use crate::{
+    audit, auth, bundle, check_query_length, compare, download_filename, imgix, jobs,
+    metadata_headers, parse_image_query, parse_query, path_options, pre_route,
+    process_image_request, process_stored_request, process_upload_request, read_limited, srcset,
+    stored_image_content_type, upload_failed, warnings_header, AppState, Conditions,
+    ErrorListParams, Freshness, IfNoneMatch, ImageOutput, ImageParams, NextImageParams,
+    PreRouteDecision, ResponseInputs,
+};
+use actix_multipart::Multipart;
+use actix_web::{
+    http::header::{
+        self, CacheControl, CacheDirective, Charset, ContentDisposition, DispositionParam,
+        DispositionType, ETag, EntityTag, ExtendedValue, HeaderName, HeaderValue,
+        IfNoneMatch as IfNoneMatchHeader,
+    },
+    middleware::from_fn,
+    web, HttpMessage, HttpRequest, HttpResponse, Result,
+};
+use log::warn;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
+/// Policy Next.js's optimizer sends with images, so that an image URL opened
+/// directly can't run scripts even if the origin serves something else.
+const NEXT_IMAGE_CONTENT_SECURITY_POLICY: &str = "script-src 'none'; frame-src 'none'; sandbox;";
+
+/// Self-contained playground served by [`debug_page_handler`].
+const DEBUG_PAGE: &str = include_str!("debug.html");
+
+#[derive(Debug, Serialize)]
+struct CheckResult {
+    status: &'static str,
+    #[serde(rename = "latencyMs")]
+    latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl CheckResult {
+    fn from_outcome(outcome: std::result::Result<(), String>, start: Instant) -> Self {
+        let latency_ms = start.elapsed().as_millis() as u64;
+        match outcome {
+            Ok(()) => Self {
+                status: "ok",
+                latency_ms,
+                error: None,
+            },
+            Err(error) => Self {
+                status: "failed",
+                latency_ms,
+                error: Some(error),
+            },
+        }
+    }
+
+    fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Every route of the service. Middleware wrapping the whole app, such as
+/// CORS and the access log, is left to the caller.
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_resource("/health", health_check))
+        .service(get_resource("/health/live", health_check))
+        .service(get_resource("/health/ready", readiness_check))
+        .service(get_resource("/status", status_handler))
+        .service(get_resource("/debug", debug_page_handler))
+        .service(get_resource("/errors", list_errors))
+        .service(get_resource("/metrics", metrics_handler))
+        .service(
+            web::scope("/img-optimizer/v1")
+                .wrap(from_fn(auth::require_api_key))
+                .service(
+                    web::resource("/img")
+                        .get(optimize_image_handler)
+                        .post(upload_image_handler)
+                        .default_service(method_not_allowed("GET, POST")),
+                )
+                .service(get_resource("/img/srcset", srcset_handler))
+                .service(get_resource("/img/bundle", bundle_handler))
+                .service(get_resource("/img/compare", compare_handler))
+                .service(
+                    web::resource("/img/{image_id}")
+                        .get(direct_image_handler)
+                        .put(ingest_image_handler)
+                        .default_service(method_not_allowed("GET, PUT")),
+                )
+                .service(
+                    web::resource("/jobs")
+                        .post(create_job_handler)
+                        .default_service(method_not_allowed("POST")),
+                )
+                .service(get_resource("/jobs/{id}", job_status_handler))
+                .service(get_resource("/audit", audit_handler))
+                .service(
+                    web::resource("/upload")
+                        .post(upload_handler)
+                        .default_service(method_not_allowed("POST")),
+                )
+                .service(get_resource(
+                    "/t/{options}/{src_b64}",
+                    transform_path_handler,
+                )),
+        )
+        .service(
+            web::scope("/_next")
+                .wrap(from_fn(auth::require_api_key))
+                .service(get_resource("/image", next_image_handler)),
+        )
+        .default_service(web::to(not_found_handler));
+}
+
+pub async fn health_check() -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "ok",
+        "service": "img-optimizer"
+    })))
+}
+
+/// `GET /status`: version, uptime and runtime figures for fleet dashboards.
+pub async fn status_handler(state: web::Data<AppState>) -> Result<HttpResponse> {
+    let cache_backend = state.cache.read().await.backend();
+    let stats = state.stats.snapshot();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "service": "img-optimizer",
+        "version": env!("CARGO_PKG_VERSION"),
+        "gitSha": env!("GIT_HASH"),
+        "uptimeMs": stats.uptime.as_millis() as u64,
+        "inFlightRequests": stats.in_flight_requests,
+        "shedRequests": stats.shed_requests,
+        "processing": {
+            "permitsInUse": state.limiter.in_use(),
+            "maxPermits": state.limiter.max_permits(),
+            "waiting": state.limiter.waiting(),
+            "small": {
+                "permitsInUse": state.limiter.in_use_by(JobClass::Small),
+                "maxPermits": state.limiter.max_permits_for(JobClass::Small),
+                "waiting": state.limiter.waiting_for(JobClass::Small),
+            },
+            "workers": state.workers.workers(),
+            "queued": state.workers.queued(),
+        },
+        "cache": {
+            "backend": cache_backend,
+            "hits": stats.cache_hits,
+            "misses": stats.cache_misses,
+            "hitRatio": stats.cache_hit_ratio(),
+        },
+        "defaultQuality": state.config().limits.effective_quality(),
+        "residentMemoryBytes": resident_memory_bytes(),
+    })))
+}
+
+/// Resident set size of the process, read from `/proc` so only available
+/// on Linux.
+fn resident_memory_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kib: u64 = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kib * 1024)
+}
+
+pub async fn readiness_check(state: web::Data<AppState>) -> Result<HttpResponse> {
+    if state.shutting_down.load(Ordering::SeqCst) {
+        return Ok(HttpResponse::ServiceUnavailable().json(serde_json::json!({
+            "status": "shutting_down",
+            "service": "img-optimizer"
+        })));
+    }
+
+    let mut checks = serde_json::Map::new();
+    let mut ready = true;
+
+    let start = Instant::now();
+    let cache = state.cache.read().await.clone();
+    let outcome = cache.check().await.map_err(|e| e.to_string());
+    let cache_check = CheckResult::from_outcome(outcome, start);
+    ready &= cache_check.is_ok();
+    checks.insert("cache".to_string(), serde_json::json!(cache_check));
+
+    if let Some(canary_url) = &state.config().health.canary_url {
+        let start = Instant::now();
+        let outcome = match state
+            .client
+            .head(canary_url)
+            .timeout(std::time::Duration::from_secs(5))
+            .send()
+            .await
+        {
+            Ok(response) if response.status().is_success() => Ok(()),
+            Ok(response) => Err(format!("canary returned {}", response.status())),
+            Err(e) => Err(e.to_string()),
+        };
+        let canary_check = CheckResult::from_outcome(outcome, start);
+        ready &= canary_check.is_ok();
+        checks.insert("canary".to_string(), serde_json::json!(canary_check));
+    }
+
+    let body = serde_json::json!({
+        "status": if ready { "ready" } else { "not_ready" },
+        "service": "img-optimizer",
+        "checks": checks
+    });
+
+    if ready {
+        Ok(HttpResponse::Ok().json(body))
+    } else {
+        Ok(HttpResponse::ServiceUnavailable().json(body))
+    }
+}
+
+pub async fn metrics_handler(req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse> {
+    if !state.config().features.metrics {
+        return Err(AppError::RouteNotFound {
+            path: req.path().to_string(),
+        }
+        .into());
+    }
+
+    for class in JobClass::ALL {
+        let waiting = state.limiter.waiting_for(class);
+        state
+            .metrics
+            .set_processing_waiting(class.as_str(), waiting);
+    }
+    state.metrics.set_worker_queue_depth(state.workers.queued());
+    state
+        .metrics
+        .set_fetch_in_flight(&state.hosts().in_flight());
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(state.metrics.render()))
+}
+
+/// `GET /debug`: playground page previewing an optimized image next to its
+/// original, with the response headers. It only calls the public image
+/// endpoint from the browser. Enabled with `features.debug_page` and
+/// restricted to the admin token.
+pub async fn debug_page_handler(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if !state.config().features.debug_page {
+        return Err(AppError::RouteNotFound {
+            path: req.path().to_string(),
+        }
+        .into());
+    }
+    auth::require_admin_token(&req, state.config().storage.admin_token.as_deref())?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .insert_header(CacheControl(vec![CacheDirective::NoStore]))
+        .body(DEBUG_PAGE))
+}
+
+/// Default service answering paths no route matches.
+pub async fn not_found_handler(req: HttpRequest) -> Result<HttpResponse> {
+    Err(AppError::RouteNotFound {
+        path: req.path().to_string(),
+    }
+    .into())
+}
+
+/// Default route of a resource, answering methods it has no route for.
+/// `allowed` lists the ones it has, as sent in the `Allow` header.
+pub fn method_not_allowed(allowed: &'static str) -> actix_web::Route {
+    web::route().to(move |req: HttpRequest| async move {
+        Err::<HttpResponse, _>(AppError::MethodNotAllowed {
+            method: req.method().to_string(),
+            allowed: allowed.to_string(),
+        })
+    })
+}
+
+/// Resource serving `handler` on `GET` and a 405 on any other method.
+pub fn get_resource<F, Args>(path: &str, handler: F) -> actix_web::Resource
+where
+    F: actix_web::Handler<Args>,
+    Args: actix_web::FromRequest + 'static,
+    F::Output: actix_web::Responder + 'static,
+{
+    web::resource(path)
+        .get(handler)
+        .default_service(method_not_allowed("GET"))
+}
+
+pub async fn list_errors(req: HttpRequest) -> Result<HttpResponse> {
+    let query: ErrorListParams = parse_query(req.query_string())?;
+    if query.format.as_deref() == Some("json") {
+        let catalog = AppError::catalog();
+        return Ok(HttpResponse::Ok().json(serde_json::json!({
+            "errors": catalog,
+            "total": catalog.len()
+        })));
+    }
+
+    let errors = AppError::list_all_errors();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "errors": errors,
+        "total": errors.len()
+    })))
+}
+
+#[cfg_attr(
+    feature = "otel",
+    tracing::instrument(
+        name = "optimize_image",
+        skip_all,
+        fields(
+            src_host = tracing::field::Empty,
+            width = tracing::field::Empty,
+            format = tracing::field::Empty,
+            cache_hit = tracing::field::Empty,
+        )
+    )
+)]
+pub async fn optimize_image_handler(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    check_query_length(req.query_string(), &state.config().limits)?;
+    if !state.config().imgix.enabled && !imgix::is_imgix_query(req.query_string()) {
+        let params = parse_query(req.query_string())?;
+        return serve_image(req, params, state).await;
+    }
+
+    let imgix::Translation { params, ignored } =
+        imgix::translate(req.query_string(), state.config().imgix.strict)?;
+    let mut response = serve_image(req, params, state).await?;
+    if !ignored.is_empty() {
+        if let Ok(value) = HeaderValue::from_str(&ignored.join(", ")) {
+            response
+                .headers_mut()
+                .insert(HeaderName::from_static("x-imgix-ignored"), value);
+        }
+    }
+    Ok(response)
+}
+
+/// `GET /img-optimizer/v1/t/{options}/{src_b64}`: path-style equivalent of
+/// [`optimize_image_handler`], see [`path_options`] for the grammar.
+pub async fn transform_path_handler(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (options, src_b64) = path.into_inner();
+    let params = path_options::parse(&options, &src_b64)?;
+    serve_image(req, params, state).await
+}
+
+/// `GET /_next/image`: drop-in target for Next.js's `images.loader`, enabled
+/// with `features.nextjs_compat`. Responds like Next.js's own optimizer:
+/// `url`, `w` and `q` are all required, and images are sent inline with a
+/// restrictive `Content-Security-Policy`.
+pub async fn next_image_handler(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if !state.config().features.nextjs_compat {
+        return Err(AppError::RouteNotFound {
+            path: req.path().to_string(),
+        }
+        .into());
+    }
+
+    let NextImageParams { url, w, q } =
+        parse_image_query(req.query_string(), &state.config().limits)?;
+    let missing = |param: &str| AppError::MissingRequiredParameter {
+        param: param.to_string(),
+    };
+    let url = url.ok_or_else(|| missing("url"))?;
+    let w = w.ok_or_else(|| missing("w"))?;
+    let q = q.ok_or_else(|| missing("q"))?;
+
+    // Next.js names the file after the last path segment of the source
+    let name = url.split(['?', '#']).next().unwrap_or_default().to_string();
+    let params = ImageParams {
+        src: Some(url),
+        w: Some(w),
+        q: Some(q),
+        ..Default::default()
+    };
+    let mut response = serve_image(req, params, state).await?;
+
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CONTENT_SECURITY_POLICY,
+        HeaderValue::from_static(NEXT_IMAGE_CONTENT_SECURITY_POLICY),
+    );
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    if let Some(content_type) = content_type {
+        let disposition = content_disposition(DispositionType::Inline, &name, &content_type);
+        if let Ok(value) = HeaderValue::from_str(&disposition.to_string()) {
+            headers.insert(header::CONTENT_DISPOSITION, value);
+        }
+    }
+
+    Ok(response)
+}
+
+async fn serve_image(
+    req: HttpRequest,
+    mut params: ImageParams,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let inputs = resolve_inputs(&req, &mut params, &state);
+    let span = tracing::Span::current();
+    span.record("width", params.w.as_deref());
+    span.record("format", params.f.as_deref());
+
+    if let PreRouteDecision::Redirect(location) = pre_route(&params) {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", location))
+            .finish());
+    }
+
+    let conditions = read_conditions(&req);
+    let download = params.dl.clone();
+
+    let mut timings = PhaseTimings::default();
+    let output = process_image_request(
+        params,
+        &state,
+        conditions.if_none_match.as_ref(),
+        &mut timings,
+    )
+    .await?;
+
+    image_response(
+        output,
+        &conditions,
+        download.as_deref(),
+        inputs,
+        Freshness::MaxAge,
+        &timings,
+        &state.config(),
+    )
+}
+
+/// `POST /img-optimizer/v1/img`: optimizes the image sent as the raw request
+/// body, or as the first part of a `multipart/form-data` body.
+pub async fn upload_image_handler(
+    req: HttpRequest,
+    payload: web::Payload,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let mut params: ImageParams = parse_image_query(req.query_string(), &state.config().limits)?;
+    let inputs = resolve_inputs(&req, &mut params, &state);
+    let image_data = read_upload(&req, payload, state.config().limits.max_image_size).await?;
+    // Ranges only apply to GET
+    let conditions = Conditions {
+        if_none_match: read_if_none_match(&req),
+        range: None,
+    };
+    let download = params.dl.clone();
+
+    let mut timings = PhaseTimings::default();
+    let output = process_upload_request(
+        image_data,
+        params,
+        &state,
+        conditions.if_none_match.as_ref(),
+        &mut timings,
+    )
+    .await?;
+
+    image_response(
+        output,
+        &conditions,
+        download.as_deref(),
+        inputs,
+        Freshness::MaxAge,
+        &timings,
+        &state.config(),
+    )
+}
+
+/// Reads an uploaded image, from the first part of a multipart body or from
+/// the raw body.
+async fn read_upload(
+    req: &HttpRequest,
+    payload: web::Payload,
+    max_size: usize,
+) -> AppResult<Vec<u8>> {
+    use futures_util::StreamExt;
+
+    let is_multipart = req
+        .mime_type()
+        .ok()
+        .flatten()
+        .is_some_and(|mime| mime.essence_str() == "multipart/form-data");
+
+    let bytes = if is_multipart {
+        let mut multipart = Multipart::new(req.headers(), payload);
+        match multipart.next().await {
+            Some(field) => read_limited(field.map_err(upload_failed)?, max_size).await?,
+            None => Vec::new(),
+        }
+    } else {
+        read_limited(payload, max_size).await?
+    };
+
+    if bytes.is_empty() {
+        return Err(AppError::MissingRequiredParameter {
+            param: "body".to_string(),
+        });
+    }
+    Ok(bytes)
+}
+
+/// The request's `If-None-Match` header, for the core pipeline.
+fn read_if_none_match(req: &HttpRequest) -> Option<IfNoneMatch> {
+    req.get_header::<IfNoneMatchHeader>()
+        .map(|header| match header {
+            IfNoneMatchHeader::Any => IfNoneMatch::Any,
+            IfNoneMatchHeader::Items(tags) => {
+                IfNoneMatch::Tags(tags.iter().map(|tag| tag.tag().to_string()).collect())
+            }
+        })
+}
+
+/// The request's `If-None-Match`, `Range` and `If-Range` headers.
+fn read_conditions(req: &HttpRequest) -> Conditions {
+    let header = |name| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    Conditions {
+        if_none_match: read_if_none_match(req),
+        range: RangeRequest::from_headers(header(header::RANGE), header(header::IF_RANGE)),
+    }
+}
+
+/// Builds the response for a pipeline outcome: the image with its validators,
+/// `304 Not Modified` when the client's `If-None-Match` matches, or the
+/// `206 Partial Content` asked for with `Range`.
+fn image_response(
+    output: ImageOutput,
+    conditions: &Conditions,
+    download: Option<&str>,
+    inputs: ResponseInputs,
+    freshness: Freshness,
+    timings: &PhaseTimings,
+    config: &AppConfig,
+) -> Result<HttpResponse> {
+    let cache_control = (header::CACHE_CONTROL, freshness.cache_control(config));
+
+    let mut response = match output {
+        ImageOutput::Image { etag, .. }
+            if conditions
+                .if_none_match
+                .as_ref()
+                .is_some_and(|header| header.matches(&etag)) =>
+        {
+            HttpResponse::NotModified()
+                .insert_header(ETag(EntityTag::new_strong(etag)))
+                .insert_header(cache_control)
+                .finish()
+        }
+        ImageOutput::Image {
+            data,
+            content_type,
+            etag,
+            metadata,
+            warnings,
+        } => {
+            let range = match &conditions.range {
+                Some(range) => range.resolve(&etag, data.len())?,
+                None => None,
+            };
+            let mut response = match range {
+                Some(range) => {
+                    let mut response = HttpResponse::PartialContent();
+                    response
+                        .insert_header((header::CONTENT_RANGE, range.content_range(data.len())));
+                    response
+                }
+                None => HttpResponse::Ok(),
+            };
+            for header in metadata.iter().flat_map(metadata_headers) {
+                response.insert_header(header);
+            }
+            if let Some(header) = warnings_header(&warnings) {
+                response.insert_header(header);
+            }
+            if let Some(filename) = download {
+                response.insert_header(content_disposition(
+                    DispositionType::Attachment,
+                    filename,
+                    &content_type,
+                ));
+            }
+            response
+                .content_type(content_type)
+                .insert_header(ETag(EntityTag::new_strong(etag)))
+                .insert_header(cache_control)
+                .insert_header((header::ACCEPT_RANGES, range::ACCEPT_RANGES))
+                .body(match range {
+                    Some(range) => data.slice(range.start..=range.end),
+                    None => data,
+                })
+        }
+        ImageOutput::NotModified { etag } => HttpResponse::NotModified()
+            .insert_header(ETag(EntityTag::new_strong(etag)))
+            .insert_header(cache_control)
+            .finish(),
+    };
+
+    if let Some(vary) = inputs
+        .vary()
+        .and_then(|vary| HeaderValue::from_str(&vary).ok())
+    {
+        response.headers_mut().append(header::VARY, vary);
+    }
+    if let Some(hints) = inputs.accept_ch() {
+        response.headers_mut().insert(
+            HeaderName::from_static("accept-ch"),
+            HeaderValue::from_static(hints),
+        );
+    }
+    if config.features.server_timing {
+        if let Ok(value) = HeaderValue::from_str(&timings.server_timing()) {
+            response
+                .headers_mut()
+                .insert(HeaderName::from_static("server-timing"), value);
+        }
+    }
+
+    Ok(response)
+}
+
+/// Resolves `f=auto` and client hints from the headers of `req`, see [`ResponseInputs`].
+fn resolve_inputs(req: &HttpRequest, params: &mut ImageParams, state: &AppState) -> ResponseInputs {
+    ResponseInputs::resolve(params, &state.config(), &state.api_keys, |name| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    })
+}
+
+/// `GET /img-optimizer/v1/img/srcset`: JSON manifest of the URLs of a source
+/// at a list of widths, see [`srcset::build_manifest`].
+pub async fn srcset_handler(req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse> {
+    let params = parse_image_query(req.query_string(), &state.config().limits)?;
+    let origin = {
+        let info = req.connection_info();
+        format!("{}://{}", info.scheme(), info.host())
+    };
+    let endpoint = srcset::image_endpoint(
+        state.config().server.public_url.as_deref(),
+        &origin,
+        req.path(),
+    );
+    let accept = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok());
+
+    let manifest = srcset::build_manifest(params, &endpoint, accept, &state).await?;
+    Ok(HttpResponse::Ok().json(manifest))
+}
+
+/// `GET /img-optimizer/v1/img/bundle`: zip archive of several variants of a
+/// source, see [`bundle::build_bundle`].
+pub async fn bundle_handler(req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse> {
+    let params = parse_image_query(req.query_string(), &state.config().limits)?;
+    let accept = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok());
+
+    let bundle = bundle::build_bundle(params, accept, &state).await?;
+    let disposition = ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![DispositionParam::Filename(bundle.filename.clone())],
+    };
+    Ok(HttpResponse::Ok()
+        .content_type("application/zip")
+        .insert_header(disposition)
+        .streaming(bundle.into_stream()))
+}
+
+/// `GET /img-optimizer/v1/img/compare`: quality scores and size of the image
+/// the query describes against its original, for holders of the admin
+/// token. See [`compare::compare`].
+pub async fn compare_handler(req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse> {
+    auth::require_admin_token(&req, state.config().storage.admin_token.as_deref())?;
+    let params = parse_image_query(req.query_string(), &state.config().limits)?;
+    let accept = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok());
+
+    let comparison = compare::compare(params, accept, &state).await?;
+    Ok(HttpResponse::Ok()
+        .insert_header(CacheControl(vec![CacheDirective::NoStore]))
+        .json(comparison))
+}
+
+/// `POST /img-optimizer/v1/jobs`: processes the image the query describes,
+/// with the parameters of [`optimize_image_handler`], in the background.
+/// Answers `202` with the job's id, see [`jobs::submit`].
+pub async fn create_job_handler(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let mut params: ImageParams = parse_image_query(req.query_string(), &state.config().limits)?;
+    resolve_inputs(&req, &mut params, &state);
+    let origin = {
+        let info = req.connection_info();
+        format!("{}://{}", info.scheme(), info.host())
+    };
+    let url = jobs::image_url(
+        state.config().server.public_url.as_deref(),
+        &origin,
+        req.path(),
+        req.query_string(),
+    );
+
+    let id = jobs::submit(params, url, &state)?;
+    Ok(HttpResponse::Accepted()
+        .insert_header((header::LOCATION, format!("{}/{id}", req.path())))
+        .json(jobs::JobStatus::Pending.to_json(&id, state.config().server.error_detail)))
+}
+
+/// `GET /img-optimizer/v1/jobs/{id}`: whether the job is pending, succeeded
+/// (with the URL of its image) or failed (with its error).
+pub async fn job_status_handler(
+    id: web::Path<String>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let status = state
+        .jobs
+        .get(&id)
+        .ok_or_else(|| AppError::JobNotFound { id: id.to_string() })?;
+    Ok(HttpResponse::Ok()
+        .insert_header(CacheControl(vec![CacheDirective::NoStore]))
+        .json(status.to_json(&id, state.config().server.error_detail)))
+}
+
+/// `GET /img-optimizer/v1/audit`: requests per source in the audit log, for
+/// holders of the admin token. 404 while the audit log is off.
+pub async fn audit_handler(req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse> {
+    auth::require_admin_token(&req, state.config().storage.admin_token.as_deref())?;
+    let Some(log) = state.audit.clone() else {
+        return Err(AppError::RouteNotFound {
+            path: req.path().to_string(),
+        }
+        .into());
+    };
+    let query: audit::AuditQuery = parse_query(req.query_string())?;
+    let since = query.since_day()?;
+
+    let host = query.host.clone();
+    let summary = tokio::task::spawn_blocking(move || log.summarize(host.as_deref(), since))
+        .await
+        .map_err(|_| AppError::InternalServerError)?
+        .map_err(|e| {
+            warn!("Failed to read the audit log: {e}");
+            AppError::InternalServerError
+        })?;
+    Ok(HttpResponse::Ok()
+        .insert_header(CacheControl(vec![CacheDirective::NoStore]))
+        .json(serde_json::json!({
+            "host": query.host,
+            "since": query.since,
+            "total": summary.total,
+            "sources": summary.sources,
+        })))
+}
+
+pub async fn direct_image_handler(
+    req: HttpRequest,
+    image_id: web::Path<String>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let content_type = stored_image_content_type(&image_id)?;
+    let mut params: ImageParams = parse_image_query(req.query_string(), &state.config().limits)?;
+    let inputs = resolve_inputs(&req, &mut params, &state);
+    let conditions = read_conditions(&req);
+    let download = params.dl.clone();
+
+    let mut timings = PhaseTimings::default();
+    let output = process_stored_request(
+        &image_id,
+        content_type,
+        params,
+        &state,
+        conditions.if_none_match.as_ref(),
+        &mut timings,
+    )
+    .await?;
+
+    image_response(
+        output,
+        &conditions,
+        download.as_deref(),
+        inputs,
+        Freshness::Immutable,
+        &timings,
+        &state.config(),
+    )
+}
+
+/// `PUT /img-optimizer/v1/img/{image_id}`: stores an original in internal
+/// storage. Requires the admin token.
+pub async fn ingest_image_handler(
+    req: HttpRequest,
+    image_id: web::Path<String>,
+    payload: web::Payload,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    auth::require_admin_token(&req, state.config().storage.admin_token.as_deref())?;
+    stored_image_content_type(&image_id)?;
+
+    let data = read_limited(payload, state.config().limits.max_image_size).await?;
+    if data.is_empty() {
+        return Err(AppError::MissingRequiredParameter {
+            param: "body".to_string(),
+        }
+        .into());
+    }
+    if image::guess_format(&data).is_err() {
+        return Err(AppError::InvalidImageData.into());
+    }
+
+    state.storage.put(&image_id, &data).await.map_err(|e| {
+        warn!("Failed to store image {image_id}: {e}");
+        AppError::from(e)
+    })?;
+
+    Ok(HttpResponse::Created()
+        .insert_header((
+            header::LOCATION,
+            format!("/img-optimizer/v1/img/{image_id}"),
+        ))
+        .json(serde_json::json!({
+            "id": image_id.as_str(),
+            "size": data.len(),
+        })))
+}
+
+/// `POST /img-optimizer/v1/upload`: stores an uploaded original and returns
+/// its id, derived from the content so identical uploads share one entry.
+/// Requires an API key, or the admin token when API keys are disabled.
+pub async fn upload_handler(
+    req: HttpRequest,
+    payload: web::Payload,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    // With API keys enabled, `require_api_key` already authenticated the request
+    if !state.api_keys.is_enabled() {
+        auth::require_admin_token(&req, state.config().storage.admin_token.as_deref())?;
+    }
+
+    let data = read_upload(&req, payload, state.config().limits.max_image_size).await?;
+    let info = ImageProcessor::inspect(&data)?;
+
+    let hash = hex::encode(Sha256::digest(&data));
+    let id = format!("{}.{}", &hash[..32], info.extension);
+
+    let mut response = if state.storage.contains(&id) {
+        HttpResponse::Ok()
+    } else {
+        state.storage.put(&id, &data).await.map_err(|e| {
+            warn!("Failed to store image {id}: {e}");
+            AppError::from(e)
+        })?;
+        HttpResponse::Created()
+    };
+
+    Ok(response
+        .insert_header((header::LOCATION, format!("/img-optimizer/v1/img/{id}")))
+        .json(serde_json::json!({
+            "id": id,
+            "width": info.width,
+            "height": info.height,
+            "size": data.len(),
+        })))
+}
+
+/// `Content-Disposition` naming the image `requested`, see
+/// [`download_filename`]. Non-ASCII names are sent as an RFC 5987 `filename*`
+/// with an ASCII `filename` fallback.
+pub fn content_disposition(
+    disposition: DispositionType,
+    requested: &str,
+    content_type: &str,
+) -> ContentDisposition {
+    let filename = download_filename(requested, content_type);
+
+    let mut parameters = Vec::new();
+    if filename.is_ascii() {
+        parameters.push(DispositionParam::Filename(filename));
+    } else {
+        let fallback = filename
+            .chars()
+            .map(|c| if c.is_ascii() { c } else { '_' })
+            .collect();
+        parameters.push(DispositionParam::Filename(fallback));
+        parameters.push(DispositionParam::FilenameExt(ExtendedValue {
+            charset: Charset::Ext("UTF-8".to_string()),
+            language_tag: None,
+            value: filename.into_bytes(),
+        }));
+    }
+
+    ContentDisposition {
+        disposition,
+        parameters,
+    }
+}
@@ -0,0 +1,253 @@
+//! Detection of image formats from the leading bytes of a file, used to
+//! label cached, passed-through and stored images.
+
+/// Bytes inspected by [`DetectedFormat::detect`]; longer inputs are only
+/// looked at up to this length.
+pub const SNIFF_LEN: usize = 1024;
+
+/// Content type of bytes in no recognized image format.
+pub const UNKNOWN_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Image format recognized from its signature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DetectedFormat {
+    Jpeg,
+    Png,
+    Gif,
+    WebP,
+    Avif,
+    /// HEIF with HEVC coded images.
+    Heic,
+    Tiff,
+    Bmp,
+    Ico,
+    Svg,
+}
+
+impl DetectedFormat {
+    pub const ALL: [DetectedFormat; 10] = [
+        Self::Jpeg,
+        Self::Png,
+        Self::Gif,
+        Self::WebP,
+        Self::Avif,
+        Self::Heic,
+        Self::Tiff,
+        Self::Bmp,
+        Self::Ico,
+        Self::Svg,
+    ];
+
+    /// Detects the format of `data` from its first [`SNIFF_LEN`] bytes.
+    /// Inputs cut before the signature is complete are not recognized.
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        let data = &data[..data.len().min(SNIFF_LEN)];
+
+        match data {
+            [0xFF, 0xD8, 0xFF, ..] => Some(Self::Jpeg),
+            [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, ..] => Some(Self::Png),
+            [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some(Self::Gif),
+            [b'I', b'I', 0x2A, 0x00, ..] | [b'M', b'M', 0x00, 0x2A, ..] => Some(Self::Tiff),
+            [b'R', b'I', b'F', b'F', ..] => detect_webp(data),
+            [_, _, _, _, b'f', b't', b'y', b'p', ..] => detect_ftyp(data),
+            [b'B', b'M', ..] => detect_bmp(data),
+            [0x00, 0x00, 0x01, 0x00, ..] => detect_ico(data),
+            _ => detect_svg(data),
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Jpeg => "image/jpeg",
+            Self::Png => "image/png",
+            Self::Gif => "image/gif",
+            Self::WebP => "image/webp",
+            Self::Avif => "image/avif",
+            Self::Heic => "image/heic",
+            Self::Tiff => "image/tiff",
+            Self::Bmp => "image/bmp",
+            Self::Ico => "image/x-icon",
+            Self::Svg => "image/svg+xml",
+        }
+    }
+
+    /// The format served as `content_type`, ignoring parameters and case.
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let essence = content_type.split(';').next().unwrap_or_default().trim();
+        Self::ALL
+            .into_iter()
+            .find(|format| format.content_type().eq_ignore_ascii_case(essence))
+    }
+
+    /// File extension, without the dot.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::Png => "png",
+            Self::Gif => "gif",
+            Self::WebP => "webp",
+            Self::Avif => "avif",
+            Self::Heic => "heic",
+            Self::Tiff => "tiff",
+            Self::Bmp => "bmp",
+            Self::Ico => "ico",
+            Self::Svg => "svg",
+        }
+    }
+}
+
+/// Content type of `data`, [`UNKNOWN_CONTENT_TYPE`] when its format is not
+/// recognized.
+pub fn content_type(data: &[u8]) -> &'static str {
+    DetectedFormat::detect(data).map_or(UNKNOWN_CONTENT_TYPE, DetectedFormat::content_type)
+}
+
+/// What `data` clearly is when it isn't an image, from its first
+/// [`SNIFF_LEN`] bytes: `HTML`, `XML`, `JSON` or `text`, such as the error
+/// pages some origins serve with a `200`. `None` for images, and for
+/// anything that could still be one.
+pub fn non_image_content(data: &[u8]) -> Option<&'static str> {
+    let data = &data[..data.len().min(SNIFF_LEN)];
+    if DetectedFormat::detect(data).is_some() {
+        return None;
+    }
+    let text = match std::str::from_utf8(data) {
+        Ok(text) => text,
+        // Cut within a character at the end of the sample
+        Err(err) if err.error_len().is_none() => {
+            std::str::from_utf8(&data[..err.valid_up_to()]).ok()?
+        }
+        Err(_) => return None,
+    };
+    if text
+        .chars()
+        .any(|c| c.is_control() && !c.is_ascii_whitespace())
+    {
+        return None;
+    }
+
+    let text = text.trim_start_matches('\u{feff}').trim_start();
+    let head = text.as_bytes();
+    if head.is_empty() {
+        return None;
+    }
+    let kind = if [b"<!doctype html".as_slice(), b"<html", b"<head", b"<body"]
+        .iter()
+        .any(|tag| strip_prefix_ignore_case(head, tag).is_some())
+    {
+        "HTML"
+    } else if head.starts_with(b"<?xml") {
+        "XML"
+    } else if head.starts_with(b"{") || head.starts_with(b"[") {
+        "JSON"
+    } else if matches!(head, [b'P', b'1'..=b'7', ..]) {
+        // Netpbm images, whose headers are text
+        return None;
+    } else {
+        "text"
+    };
+    Some(kind)
+}
+
+fn u32_le(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn u32_be(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// `RIFF <size> WEBP <chunk>`: the RIFF size counts the bytes after itself,
+/// so it covers at least the form type and one chunk header.
+fn detect_webp(data: &[u8]) -> Option<DetectedFormat> {
+    if data.len() < 16 || &data[8..12] != b"WEBP" || u32_le(&data[4..8]) < 12 {
+        return None;
+    }
+    matches!(&data[12..16], b"VP8 " | b"VP8L" | b"VP8X").then_some(DetectedFormat::WebP)
+}
+
+/// ISO BMFF `ftyp` box: the major brand, then the compatible brands up to
+/// the end of the box.
+fn detect_ftyp(data: &[u8]) -> Option<DetectedFormat> {
+    if data.len() < 12 {
+        return None;
+    }
+    let box_size = u32_be(&data[0..4]) as usize;
+    if box_size < 16 || !box_size.is_multiple_of(4) {
+        return None;
+    }
+    let end = box_size.min(data.len());
+    let compatible = data.get(16..end).unwrap_or_default().chunks_exact(4);
+
+    let brands = std::iter::once(&data[8..12]).chain(compatible);
+    let mut heic = false;
+    for brand in brands {
+        match brand {
+            b"avif" | b"avis" => return Some(DetectedFormat::Avif),
+            b"heic" | b"heix" | b"heim" | b"heis" | b"hevc" | b"hevx" => heic = true,
+            _ => {}
+        }
+    }
+    heic.then_some(DetectedFormat::Heic)
+}
+
+/// `BM`, then the file header and the size of a known DIB header.
+fn detect_bmp(data: &[u8]) -> Option<DetectedFormat> {
+    if data.len() < 18 {
+        return None;
+    }
+    matches!(u32_le(&data[14..18]), 12 | 40 | 52 | 56 | 64 | 108 | 124)
+        .then_some(DetectedFormat::Bmp)
+}
+
+/// Reserved zero, type 1 (icon) and at least one image.
+fn detect_ico(data: &[u8]) -> Option<DetectedFormat> {
+    if data.len() < 6 {
+        return None;
+    }
+    (u16::from_le_bytes([data[4], data[5]]) > 0).then_some(DetectedFormat::Ico)
+}
+
+/// An `<svg` root element, after an optional byte order mark, XML
+/// declaration, comments, processing instructions and doctype.
+fn detect_svg(data: &[u8]) -> Option<DetectedFormat> {
+    let mut rest = data.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(data);
+    loop {
+        rest = trim_xml_whitespace(rest);
+        if let Some(after) = rest.strip_prefix(b"<?") {
+            rest = skip_past(after, b"?>")?;
+        } else if let Some(after) = rest.strip_prefix(b"<!--") {
+            rest = skip_past(after, b"-->")?;
+        } else if let Some(after) = strip_prefix_ignore_case(rest, b"<!DOCTYPE") {
+            rest = skip_past(after, b">")?;
+        } else {
+            break;
+        }
+    }
+
+    let name = rest.strip_prefix(b"<svg")?;
+    match name.first() {
+        Some(b' ' | b'\t' | b'\r' | b'\n' | b'>' | b'/') => Some(DetectedFormat::Svg),
+        _ => None,
+    }
+}
+
+fn trim_xml_whitespace(data: &[u8]) -> &[u8] {
+    let start = data
+        .iter()
+        .position(|byte| !matches!(byte, b' ' | b'\t' | b'\r' | b'\n'))
+        .unwrap_or(data.len());
+    &data[start..]
+}
+
+fn skip_past<'a>(data: &'a [u8], terminator: &[u8]) -> Option<&'a [u8]> {
+    data.windows(terminator.len())
+        .position(|window| window == terminator)
+        .map(|start| &data[start + terminator.len()..])
+}
+
+fn strip_prefix_ignore_case<'a>(data: &'a [u8], prefix: &[u8]) -> Option<&'a [u8]> {
+    let head = data.get(..prefix.len())?;
+    head.eq_ignore_ascii_case(prefix)
+        .then(|| &data[prefix.len()..])
+}
@@ -0,0 +1,231 @@
+//! srcset manifests: the optimizer URLs of one source at a ladder of widths,
+//! so templates can emit `<img srcset>` without hardcoding the ladder.
+
+use crate::error::{AppError, AppResult};
+#[cfg(feature = "runtime")]
+use crate::{
+    acquire_permit, generate_cache_key, image_processor::ImageProcessor, limiter::JobClass,
+    load_source, metrics::PhaseTimings, process_image_request, resolve_source, AppState,
+    ImageParams,
+};
+use serde::Deserialize;
+#[cfg(feature = "runtime")]
+use serde::Serialize;
+
+/// Most widths a manifest lists.
+pub const MAX_WIDTHS: usize = 16;
+
+/// Query of `GET /img-optimizer/v1/img/srcset`. `src`, `srcb64`, `tx`, `q`
+/// and `f` are passed on to the variant URLs as in [`crate::ImageParams`].
+#[derive(Debug, Default, Deserialize)]
+pub struct SrcsetParams {
+    #[serde(alias = "url")]
+    pub src: Option<String>,
+    pub srcb64: Option<String>,
+    pub tx: Option<String>,
+    /// Comma-separated widths, see [`parse_widths`].
+    pub widths: Option<String>,
+    pub q: Option<String>,
+    pub f: Option<String>,
+    /// `1` to read the source's width and drop the widths above it.
+    pub probe: Option<String>,
+    /// `1` to process every variant into the cache before answering.
+    pub prefetch: Option<String>,
+}
+
+/// Parses a comma-separated list of widths, each between 1 and `max_width`,
+/// into an ascending list without duplicates.
+pub fn parse_widths(value: &str, max_width: u32) -> AppResult<Vec<u32>> {
+    let invalid = || AppError::InvalidParameterValue {
+        param: "widths".to_string(),
+        value: value.to_string(),
+        expected: format!(
+            "up to {MAX_WIDTHS} comma-separated whole numbers between 1 and {max_width}"
+        ),
+    };
+
+    let mut widths = value
+        .split(',')
+        .map(|width| match width.trim().parse() {
+            Ok(width) if (1..=max_width).contains(&width) => Ok(width),
+            _ => Err(invalid()),
+        })
+        .collect::<AppResult<Vec<u32>>>()?;
+    widths.sort_unstable();
+    widths.dedup();
+    if widths.len() > MAX_WIDTHS {
+        return Err(invalid());
+    }
+    Ok(widths)
+}
+
+/// Parses a `0`/`1` (or `false`/`true`) flag, absent meaning off.
+pub fn parse_flag(param: &str, value: Option<&str>) -> AppResult<bool> {
+    match value.map(str::trim) {
+        None | Some("0") | Some("false") => Ok(false),
+        Some("1") | Some("true") => Ok(true),
+        Some(other) => Err(AppError::InvalidParameterValue {
+            param: param.to_string(),
+            value: other.to_string(),
+            expected: "0 or 1".to_string(),
+        }),
+    }
+}
+
+/// URL of the image endpoint for a manifest requested at `srcset_path`:
+/// `public_url` when configured, the origin the request was made to
+/// otherwise, followed by the path of the image route next to it.
+pub fn image_endpoint(public_url: Option<&str>, request_origin: &str, srcset_path: &str) -> String {
+    let base = public_url.unwrap_or(request_origin).trim_end_matches('/');
+    let path = srcset_path.strip_suffix("/srcset").unwrap_or(srcset_path);
+    format!("{base}{path}")
+}
+
+#[cfg(feature = "runtime")]
+/// Body of a srcset manifest.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    pub src: String,
+    /// Width of the source, when probed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub intrinsic_width: Option<u32>,
+    pub variants: Vec<Variant>,
+    /// The variants as an `<img srcset>` value.
+    pub srcset: String,
+}
+
+#[cfg(feature = "runtime")]
+/// One width of a [`Manifest`].
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Variant {
+    pub width: u32,
+    pub url: String,
+    /// Cache key, which is also the ETag, of the variant for the client
+    /// that requested the manifest.
+    pub cache_key: String,
+    /// `hit` or `miss`, when prefetched.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache: Option<&'static str>,
+    /// Error code of a failed prefetch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<&'static str>,
+}
+
+#[cfg(feature = "runtime")]
+/// Lists the variants of `params` served at `endpoint`. `f=auto` stays
+/// in their URLs, while their cache keys follow the client's `accept`.
+///
+/// Nothing is fetched unless `probe` is set, and nothing is processed
+/// unless `prefetch` is. When the source is narrower than every width,
+/// the only variant is the source's own width.
+pub async fn build_manifest(
+    params: SrcsetParams,
+    endpoint: &str,
+    accept: Option<&str>,
+    state: &AppState,
+) -> AppResult<Manifest> {
+    let limits = &state.config().limits;
+    let widths = params
+        .widths
+        .as_deref()
+        .ok_or_else(|| AppError::MissingRequiredParameter {
+            param: "widths".to_string(),
+        })?;
+    let mut widths = parse_widths(widths, limits.max_width)?;
+    let probe = parse_flag("probe", params.probe.as_deref())?;
+    let prefetch = parse_flag("prefetch", params.prefetch.as_deref())?;
+
+    let template = ImageParams {
+        src: params.src,
+        srcb64: params.srcb64,
+        tx: params.tx,
+        q: params.q,
+        f: params.f,
+        ..Default::default()
+    };
+    let mut resolved = template.clone();
+    resolved.resolve_auto_format(accept);
+    let validated = resolved.validate(limits)?;
+    let src = validated
+        .source
+        .ok_or_else(|| AppError::MissingRequiredParameter {
+            param: "src".to_string(),
+        })?;
+    let (source, identity) = resolve_source(&src, state)?;
+
+    let intrinsic_width = if probe {
+        let mut timings = PhaseTimings::default();
+        let _permit = acquire_permit(state, JobClass::Small).await?;
+        let image_data = load_source(source, state, &mut timings).await?;
+        let (width, _) =
+            ImageProcessor::dimensions(&image_data).ok_or(AppError::InvalidImageData)?;
+        widths.retain(|&w| w <= width);
+        if widths.is_empty() {
+            widths.push(width);
+        }
+        Some(width)
+    } else {
+        None
+    };
+
+    let mut variants = Vec::with_capacity(widths.len());
+    for width in widths {
+        let params = ImageParams {
+            w: Some(width.to_string()),
+            ..template.clone()
+        };
+        let url = format!("{endpoint}?{}", variant_query(&params));
+        let mut resolved = params;
+        resolved.resolve_auto_format(accept);
+        let plan = resolved.clone().validate(limits)?.plan;
+        let mut variant = Variant {
+            width,
+            url,
+            cache_key: generate_cache_key(&identity, &plan),
+            cache: None,
+            error: None,
+        };
+        if prefetch {
+            let mut timings = PhaseTimings::default();
+            match process_image_request(resolved, state, None, &mut timings).await {
+                Ok(_) => variant.cache = timings.cache_status(),
+                Err(err) => variant.error = Some(err.error_code()),
+            }
+        }
+        variants.push(variant);
+    }
+
+    let srcset = variants
+        .iter()
+        .map(|variant| format!("{} {}w", variant.url, variant.width))
+        .collect::<Vec<_>>()
+        .join(", ");
+    Ok(Manifest {
+        src,
+        intrinsic_width,
+        variants,
+        srcset,
+    })
+}
+
+#[cfg(feature = "runtime")]
+/// Query string of a variant URL, with its parameters in a fixed order.
+fn variant_query(params: &ImageParams) -> String {
+    let pairs = [
+        ("src", &params.src),
+        ("srcb64", &params.srcb64),
+        ("tx", &params.tx),
+        ("w", &params.w),
+        ("q", &params.q),
+        ("f", &params.f),
+    ];
+    let mut query = url::form_urlencoded::Serializer::new(String::new());
+    for (name, value) in pairs {
+        if let Some(value) = value {
+            query.append_pair(name, value);
+        }
+    }
+    query.finish()
+}
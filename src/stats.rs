@@ -0,0 +1,128 @@
+//! Counters of the request pipeline, updated from the hot path without
+//! taking any lock: each is an atomic Prometheus handle, so `/metrics`
+//! exports them as they are and [`Stats::snapshot`] reads them for `/status`.
+//! Counters belong here rather than in [`crate::cache::ImageCache`], whose
+//! lock every request goes through.
+
+use prometheus::{IntCounter, IntGauge, Registry};
+use std::time::{Duration, Instant};
+
+#[derive(Clone)]
+pub struct Stats {
+    cache_hits: IntCounter,
+    cache_misses: IntCounter,
+    shed_requests: IntCounter,
+    in_flight: IntGauge,
+    started_at: Instant,
+}
+
+/// Values of [`Stats`] at one point in time. Each is read atomically, not
+/// all of them at once.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StatsSnapshot {
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub shed_requests: u64,
+    pub in_flight_requests: u64,
+    pub uptime: Duration,
+}
+
+impl StatsSnapshot {
+    /// Share of cache lookups that hit, `None` before the first one.
+    pub fn cache_hit_ratio(&self) -> Option<f64> {
+        let lookups = self.cache_hits + self.cache_misses;
+        (lookups > 0).then(|| self.cache_hits as f64 / lookups as f64)
+    }
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self {
+            cache_hits: IntCounter::new(
+                "img_optimizer_cache_hits_total",
+                "Image requests answered from the cache, revalidations included",
+            )
+            .expect("Failed to create cache hits counter"),
+            cache_misses: IntCounter::new(
+                "img_optimizer_cache_misses_total",
+                "Image requests processed because the cache did not have them",
+            )
+            .expect("Failed to create cache misses counter"),
+            shed_requests: IntCounter::new(
+                "img_optimizer_shed_requests_total",
+                "Requests refused because too many were waiting for a processing slot",
+            )
+            .expect("Failed to create shed requests counter"),
+            in_flight: IntGauge::new(
+                "img_optimizer_in_flight_requests",
+                "Image requests currently being handled",
+            )
+            .expect("Failed to create in-flight gauge"),
+            started_at: Instant::now(),
+        }
+    }
+
+    /// Exports the counters through `registry`.
+    pub fn register(&self, registry: &Registry) {
+        registry
+            .register(Box::new(self.cache_hits.clone()))
+            .expect("Failed to register cache hits counter");
+        registry
+            .register(Box::new(self.cache_misses.clone()))
+            .expect("Failed to register cache misses counter");
+        registry
+            .register(Box::new(self.shed_requests.clone()))
+            .expect("Failed to register shed requests counter");
+        registry
+            .register(Box::new(self.in_flight.clone()))
+            .expect("Failed to register in-flight gauge");
+    }
+
+    pub fn record_cache_hit(&self) {
+        self.cache_hits.inc();
+    }
+
+    pub fn record_cache_miss(&self) {
+        self.cache_misses.inc();
+    }
+
+    pub fn record_shed(&self) {
+        self.shed_requests.inc();
+    }
+
+    /// Counts an image request as in flight until the guard is dropped.
+    pub fn track_in_flight(&self) -> InFlight<'_> {
+        self.in_flight.inc();
+        InFlight(&self.in_flight)
+    }
+
+    /// Time since the stats, and so the service, were created.
+    pub fn uptime(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            cache_hits: self.cache_hits.get(),
+            cache_misses: self.cache_misses.get(),
+            shed_requests: self.shed_requests.get(),
+            in_flight_requests: self.in_flight.get().max(0) as u64,
+            uptime: self.uptime(),
+        }
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Guard returned by [`Stats::track_in_flight`].
+pub struct InFlight<'a>(&'a IntGauge);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
@@ -0,0 +1,59 @@
+use log::warn;
+use std::path::PathBuf;
+use tokio::fs;
+
+/// Originals served by the direct image route, one `<hash>.<ext>` file per
+/// image. Files can be ingested through the API or placed in the directory
+/// by the operator.
+pub struct ImageStorage {
+    dir: PathBuf,
+}
+
+impl ImageStorage {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// Reads a stored image. `id` must already be validated against
+    /// [`crate::IMAGE_ID_REGEX`].
+    #[cfg_attr(
+        feature = "otel",
+        tracing::instrument(name = "storage_get", skip(self))
+    )]
+    pub async fn get(&self, id: &str) -> Option<Vec<u8>> {
+        match fs::read(self.dir.join(id)).await {
+            Ok(data) => Some(data),
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    warn!("Failed to read stored image {id}: {e}");
+                }
+                None
+            }
+        }
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.dir.join(id).exists()
+    }
+
+    /// Stores an image, writing to a temporary file first so readers never
+    /// see a partial image.
+    pub async fn put(&self, id: &str, data: &[u8]) -> std::io::Result<()> {
+        fs::create_dir_all(&self.dir).await?;
+        let tmp_path = self.dir.join(format!(".{id}.tmp"));
+        fs::write(&tmp_path, data).await?;
+        fs::rename(&tmp_path, self.dir.join(id)).await
+    }
+}
+
+/// Content type served for a stored image, derived from its extension.
+pub fn content_type_for_extension(extension: &str) -> Option<&'static str> {
+    match extension.to_ascii_lowercase().as_str() {
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "png" => Some("image/png"),
+        "webp" => Some("image/webp"),
+        "avif" => Some("image/avif"),
+        "gif" => Some("image/gif"),
+        _ => None,
+    }
+}
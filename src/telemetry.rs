@@ -0,0 +1,100 @@
+//! OpenTelemetry trace export, compiled in with the `otel` feature.
+//!
+//! The OTLP exporter is configured through the standard `OTEL_*` environment
+//! variables (`OTEL_EXPORTER_OTLP_ENDPOINT`, `OTEL_SERVICE_NAME`, ...) and
+//! can be turned off at runtime with `OTEL_SDK_DISABLED=true`.
+
+use once_cell::sync::OnceCell;
+use opentelemetry::{global, propagation::Injector, trace::TracerProvider as _};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::SdkTracerProvider, Resource};
+use tracing::Subscriber;
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+static PROVIDER: OnceCell<SdkTracerProvider> = OnceCell::new();
+
+/// Builds the tracing layer exporting spans over OTLP, or `None` when the
+/// SDK is disabled or the exporter cannot be created.
+pub fn layer<S>() -> Option<OpenTelemetryLayer<S, opentelemetry_sdk::trace::SdkTracer>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let disabled = std::env::var("OTEL_SDK_DISABLED")
+        .map(|value| value.eq_ignore_ascii_case("true"))
+        .unwrap_or(false);
+    if disabled {
+        return None;
+    }
+
+    let exporter = match opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()
+    {
+        Ok(exporter) => exporter,
+        Err(e) => {
+            eprintln!("Failed to create OTLP span exporter: {e}");
+            return None;
+        }
+    };
+
+    let mut resource = Resource::builder();
+    if std::env::var_os("OTEL_SERVICE_NAME").is_none() {
+        resource = resource.with_service_name("img-optimizer");
+    }
+
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource.build())
+        .build();
+
+    Some(layer_for_provider(provider))
+}
+
+/// Builds the tracing layer for an already configured provider and installs
+/// it, with the W3C trace-context propagator, as the global default.
+pub fn layer_for_provider<S>(
+    provider: SdkTracerProvider,
+) -> OpenTelemetryLayer<S, opentelemetry_sdk::trace::SdkTracer>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let tracer = provider.tracer("img-optimizer");
+    global::set_tracer_provider(provider.clone());
+    let _ = PROVIDER.set(provider);
+    tracing_opentelemetry::layer().with_tracer(tracer)
+}
+
+/// Flushes and shuts down the exporter, so spans of the last requests are
+/// not lost on exit.
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get() {
+        if let Err(e) = provider.shutdown() {
+            eprintln!("Failed to shut down OpenTelemetry provider: {e}");
+        }
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut reqwest::header::HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            reqwest::header::HeaderName::from_bytes(key.as_bytes()),
+            reqwest::header::HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/// Adds `traceparent`/`tracestate` headers for the current span so the
+/// origin fetch joins the distributed trace.
+pub fn inject_trace_context(request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    let context = tracing::Span::current().context();
+    let mut headers = reqwest::header::HeaderMap::new();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(&mut headers))
+    });
+    request.headers(headers)
+}
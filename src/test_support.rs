@@ -0,0 +1,66 @@
+//! Helpers for integration tests. The image fixtures only need `runtime`;
+//! [`TestApp`] needs the `test-util` feature.
+//!
+//! [`TestApp::spawn`] serves every route of the service on a random local
+//! port, with its cache and storage in a temporary directory, so a test
+//! only declares its origins (e.g. with `wiremock`) and the requests it makes:
+//!
+//! ```ignore
+//! #[actix_rt::test]
+//! async fn resizes() {
+//!     let app = TestApp::spawn().await;
+//!     let resp = app.optimize("https://example.com/a.png", &[("w", "100")]).await;
+//!     assert_eq!(resp.status, 200);
+//! }
+//! ```
+//!
+//! The server runs on the actix system of the test, so tests use
+//! `#[actix_rt::test]`.
+
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage, Rgba, RgbaImage};
+use std::io::Cursor;
+
+#[cfg(feature = "test-util")]
+mod app;
+pub mod fixtures;
+
+#[cfg(feature = "test-util")]
+pub use app::{TestApp, TestAppBuilder, TestResponse};
+
+/// `width`x`height` RGBA PNG of a diagonal gradient whose opacity falls
+/// from left to right, so resizing and alpha handling show in the output.
+pub fn fixture_png(width: u32, height: u32) -> Vec<u8> {
+    let img = RgbaImage::from_fn(width, height, |x, y| {
+        Rgba([
+            gradient(x, width),
+            gradient(y, height),
+            gradient(x + y, width + height),
+            255 - gradient(x, width) / 2,
+        ])
+    });
+    encode(DynamicImage::ImageRgba8(img), ImageFormat::Png)
+}
+
+/// `width`x`height` JPEG of an opaque diagonal gradient.
+pub fn fixture_jpeg(width: u32, height: u32) -> Vec<u8> {
+    let img = RgbImage::from_fn(width, height, |x, y| {
+        Rgb([
+            gradient(x, width),
+            gradient(y, height),
+            255 - gradient(x + y, width + height),
+        ])
+    });
+    encode(DynamicImage::ImageRgb8(img), ImageFormat::Jpeg)
+}
+
+/// `position` along `extent`, scaled to a channel value.
+fn gradient(position: u32, extent: u32) -> u8 {
+    (u64::from(position) * 255 / u64::from(extent.max(2) - 1)).min(255) as u8
+}
+
+fn encode(img: DynamicImage, format: ImageFormat) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    img.write_to(&mut Cursor::new(&mut bytes), format)
+        .expect("Failed to encode the fixture");
+    bytes
+}
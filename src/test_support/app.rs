@@ -0,0 +1,209 @@
+//! [`TestApp`]: the service on a local port, behind the `test-util` feature.
+
+use crate::auth::ApiKeys;
+use crate::config::AppConfig;
+use crate::error::problem_details_context;
+use crate::logging::access_log;
+use crate::metrics::count_errors;
+use crate::{routes, AppState, Optimizer};
+use actix_web::dev::ServerHandle;
+use actix_web::{middleware::from_fn, web, App, HttpServer};
+use bytes::Bytes;
+use reqwest::header::HeaderMap;
+use reqwest::{Method, RequestBuilder, StatusCode};
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+/// The service listening on `127.0.0.1`, stopped when dropped.
+pub struct TestApp {
+    /// State shared with the handlers, for inspecting or changing the cache,
+    /// limiter or shutdown flag while the server runs.
+    pub state: AppState,
+    addr: SocketAddr,
+    client: reqwest::Client,
+    server: ServerHandle,
+    dir: TempDir,
+}
+
+impl TestApp {
+    /// Serves the default configuration.
+    pub async fn spawn() -> Self {
+        Self::builder().spawn().await
+    }
+
+    pub fn builder() -> TestAppBuilder {
+        TestAppBuilder::default()
+    }
+
+    /// Temporary directory holding the cache and, under `storage`, the
+    /// stored originals. Removed when the app is dropped.
+    pub fn dir(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Absolute URL of `path`, which may include a query string.
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{path}", self.addr)
+    }
+
+    /// Request to `path`, to add headers or a body to before [`Self::send`].
+    pub fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        self.client.request(method, self.url(path))
+    }
+
+    pub async fn get(&self, path: &str) -> TestResponse {
+        self.send(self.request(Method::GET, path)).await
+    }
+
+    /// `GET /img-optimizer/v1/img` for `src`, with `params` such as
+    /// `[("w", "100"), ("f", "webp")]`. Values are URL-encoded.
+    pub async fn optimize(&self, src: &str, params: &[(&str, &str)]) -> TestResponse {
+        self.send(self.optimize_request(src, params)).await
+    }
+
+    /// The request [`Self::optimize`] sends, for adding headers.
+    pub fn optimize_request(&self, src: &str, params: &[(&str, &str)]) -> RequestBuilder {
+        self.request(Method::GET, "/img-optimizer/v1/img")
+            .query(&[("src", src)])
+            .query(params)
+    }
+
+    /// Sends `request` and reads the whole response.
+    pub async fn send(&self, request: RequestBuilder) -> TestResponse {
+        let response = request.send().await.expect("Failed to send the request");
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = response
+            .bytes()
+            .await
+            .expect("Failed to read the response body");
+        TestResponse {
+            status,
+            headers,
+            body,
+        }
+    }
+}
+
+impl Drop for TestApp {
+    fn drop(&mut self) {
+        // The stop command is sent right away; waiting for it is not needed
+        drop(self.server.stop(false));
+    }
+}
+
+/// Builder of a [`TestApp`]. The configuration defaults to
+/// [`AppConfig::default`], with the cache and storage directories replaced.
+#[derive(Default)]
+pub struct TestAppBuilder {
+    config: Option<AppConfig>,
+    api_keys: Option<ApiKeys>,
+    cache_dir: Option<PathBuf>,
+}
+
+impl TestAppBuilder {
+    pub fn config(mut self, config: AppConfig) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    pub fn api_keys(mut self, api_keys: ApiKeys) -> Self {
+        self.api_keys = Some(api_keys);
+        self
+    }
+
+    /// Cache directory in place of the temporary one, e.g. one that cannot
+    /// be written to.
+    pub fn cache_dir(mut self, cache_dir: PathBuf) -> Self {
+        self.cache_dir = Some(cache_dir);
+        self
+    }
+
+    pub async fn spawn(self) -> TestApp {
+        let dir = TempDir::new().expect("Failed to create the temporary directory");
+        let mut config = self.config.unwrap_or_default();
+        config.cache.dir = self.cache_dir.unwrap_or_else(|| dir.path().to_path_buf());
+        config.storage.dir = dir.path().join("storage");
+
+        // Sources are mostly mock servers on localhost, which a proxy set
+        // in the environment could not reach
+        let client = reqwest::Client::builder()
+            .no_proxy()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("Failed to create the HTTP client");
+        let state = Optimizer::builder()
+            .config(config)
+            .api_keys(self.api_keys.unwrap_or_default())
+            .client(client)
+            .build()
+            .state()
+            .clone();
+
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind a local test port");
+        let addr = listener.local_addr().expect("Failed to read the test port");
+        let app_state = state.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::new(app_state.clone()))
+                .wrap(from_fn(count_errors))
+                .wrap(from_fn(problem_details_context))
+                .wrap(from_fn(access_log))
+                .configure(routes)
+        })
+        .workers(1)
+        .disable_signals()
+        .listen(listener)
+        .expect("Failed to listen on the test port")
+        .run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        TestApp {
+            state,
+            addr,
+            client: reqwest::Client::builder()
+                .no_proxy()
+                .redirect(reqwest::redirect::Policy::none())
+                .build()
+                .expect("Failed to create the HTTP client"),
+            server: handle,
+            dir,
+        }
+    }
+}
+
+/// A response read to the end by [`TestApp::send`].
+#[derive(Debug)]
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl TestResponse {
+    /// Value of the header `name`. Panics unless it is visible ASCII.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(|value| {
+            value
+                .to_str()
+                .unwrap_or_else(|_| panic!("Header {name} is not visible ASCII"))
+        })
+    }
+
+    /// The body parsed as JSON. Panics when it is not.
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.body).unwrap_or_else(|e| {
+            panic!(
+                "Body is not JSON ({e}): {}",
+                String::from_utf8_lossy(&self.body)
+            )
+        })
+    }
+
+    /// The body as UTF-8. Panics when it is not.
+    pub fn text(&self) -> String {
+        String::from_utf8(self.body.to_vec()).expect("Body is not UTF-8")
+    }
+}
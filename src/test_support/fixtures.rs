@@ -0,0 +1,150 @@
+//! Realistic inputs for integration tests, generated from fixed seeds so
+//! every run sees the same bytes without committing binaries. Unlike
+//! [`super::fixture_png`], they exercise what small flat images hide: the
+//! encoders' quality, transparency and the first frame of animations.
+
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::codecs::jpeg::JpegEncoder;
+use image::{Delay, DynamicImage, Frame, ImageFormat, Rgb, RgbImage, Rgba, RgbaImage};
+
+/// An encoded image and what a decoder should find in it.
+#[derive(Debug, Clone)]
+pub struct Fixture {
+    pub name: &'static str,
+    pub content_type: &'static str,
+    pub width: u32,
+    pub height: u32,
+    pub bytes: Vec<u8>,
+}
+
+/// Every fixture this build can decode.
+pub fn all() -> Vec<Fixture> {
+    vec![
+        photo_jpeg(),
+        logo_png(),
+        animated_gif(),
+        #[cfg(feature = "webp")]
+        webp(),
+    ]
+}
+
+/// 640x480 JPEG at quality 90: smooth gradients under sensor-like noise, so
+/// lower qualities visibly lose detail and weigh less.
+pub fn photo_jpeg() -> Fixture {
+    let img = photo(640, 480, 0x5eed_0001);
+    let mut bytes = Vec::new();
+    JpegEncoder::new_with_quality(&mut bytes, 90)
+        .encode_image(&img)
+        .expect("Failed to encode the JPEG fixture");
+    Fixture {
+        name: "photo_jpeg",
+        content_type: "image/jpeg",
+        width: 640,
+        height: 480,
+        bytes,
+    }
+}
+
+/// 320x320 PNG of overlapping soft-edged discs on a transparent background.
+pub fn logo_png() -> Fixture {
+    let discs = [
+        (110.0, 120.0, 80.0, [220, 60, 60]),
+        (210.0, 130.0, 90.0, [60, 160, 220]),
+        (160.0, 220.0, 85.0, [240, 200, 40]),
+    ];
+    let img = RgbaImage::from_fn(320, 320, |x, y| {
+        let mut pixel = Rgba([0, 0, 0, 0]);
+        for (cx, cy, radius, [r, g, b]) in discs {
+            let distance = ((x as f32 - cx).powi(2) + (y as f32 - cy).powi(2)).sqrt();
+            // Fully opaque inside, fading out over the last 12 pixels
+            let alpha = ((radius - distance) / 12.0).clamp(0.0, 1.0);
+            if alpha > 0.0 {
+                pixel = Rgba([r, g, b, ((alpha * 255.0) as u8).max(pixel[3])]);
+            }
+        }
+        pixel
+    });
+    Fixture {
+        name: "logo_png",
+        content_type: "image/png",
+        width: 320,
+        height: 320,
+        bytes: super::encode(DynamicImage::ImageRgba8(img), ImageFormat::Png),
+    }
+}
+
+/// 320x240 GIF of 4 frames of a moving gradient, looping forever.
+pub fn animated_gif() -> Fixture {
+    let frames = (0..4u32).map(|index| {
+        let img = RgbaImage::from_fn(320, 240, |x, y| {
+            let shift = index * 24;
+            Rgba([
+                ((x + shift) % 256) as u8,
+                ((y + shift) % 256) as u8,
+                ((x + y) / 3 % 256) as u8,
+                255,
+            ])
+        });
+        Frame::from_parts(img, 0, 0, Delay::from_numer_denom_ms(80, 1))
+    });
+
+    let mut bytes = Vec::new();
+    {
+        let mut encoder = GifEncoder::new(&mut bytes);
+        encoder
+            .set_repeat(Repeat::Infinite)
+            .expect("Failed to set the GIF repeat");
+        encoder
+            .encode_frames(frames)
+            .expect("Failed to encode the GIF fixture");
+    }
+    Fixture {
+        name: "animated_gif",
+        content_type: "image/gif",
+        width: 320,
+        height: 240,
+        bytes,
+    }
+}
+
+/// 400x300 lossless WebP of a photo-like image.
+#[cfg(feature = "webp")]
+pub fn webp() -> Fixture {
+    let img = photo(400, 300, 0x5eed_0002);
+    Fixture {
+        name: "webp",
+        content_type: "image/webp",
+        width: 400,
+        height: 300,
+        bytes: super::encode(DynamicImage::ImageRgb8(img), ImageFormat::WebP),
+    }
+}
+
+fn photo(width: u32, height: u32, seed: u32) -> RgbImage {
+    let mut noise = Noise(seed);
+    RgbImage::from_fn(width, height, |x, y| {
+        let (fx, fy) = (x as f32 / width as f32, y as f32 / height as f32);
+        let wave = ((fx * 23.0).sin() * (fy * 17.0).cos() * 40.0) as i32;
+        Rgb([
+            channel(60 + (fx * 150.0) as i32 + wave, noise.next()),
+            channel(90 + (fy * 120.0) as i32 - wave / 2, noise.next()),
+            channel(140 - ((fx + fy) * 50.0) as i32 + wave, noise.next()),
+        ])
+    })
+}
+
+fn channel(base: i32, noise: u8) -> u8 {
+    (base + i32::from(noise % 25) - 12).clamp(0, 255) as u8
+}
+
+/// xorshift32, so fixtures do not depend on a random number crate.
+struct Noise(u32);
+
+impl Noise {
+    fn next(&mut self) -> u8 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        (self.0 >> 24) as u8
+    }
+}
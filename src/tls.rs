@@ -0,0 +1,112 @@
+//! Native HTTPS serving with rustls, for deployments without a reverse proxy.
+
+use crate::error::AppError;
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+    Error, HttpResponse,
+};
+use anyhow::{anyhow, Context};
+use rustls::{
+    crypto::ring,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// Reads a PEM certificate chain and its private key, failing when either
+/// file is unreadable or the key does not match the leaf certificate.
+pub fn load_certified_key(cert_path: &Path, key_path: &Path) -> anyhow::Result<CertifiedKey> {
+    let chain = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to read TLS certificate {}", cert_path.display()))?;
+    if chain.is_empty() {
+        return Err(anyhow!(
+            "TLS certificate {} contains no certificate",
+            cert_path.display()
+        ));
+    }
+
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .with_context(|| format!("Failed to read TLS private key {}", key_path.display()))?;
+
+    CertifiedKey::from_der(chain, key, &ring::default_provider()).with_context(|| {
+        format!(
+            "TLS private key {} does not match certificate {}",
+            key_path.display(),
+            cert_path.display()
+        )
+    })
+}
+
+/// Certificate resolver whose certificate can be swapped while serving.
+#[derive(Debug)]
+pub struct ReloadableCert {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ReloadableCert {
+    pub fn load(cert_path: &Path, key_path: &Path) -> anyhow::Result<Self> {
+        let key = load_certified_key(cert_path, key_path)?;
+        Ok(Self {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            current: RwLock::new(Arc::new(key)),
+        })
+    }
+
+    /// Re-reads the certificate and key from disk. On failure the previous
+    /// certificate stays in use.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let key = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(key);
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for ReloadableCert {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(
+            self.current
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone(),
+        )
+    }
+}
+
+/// Builds the rustls server configuration serving `cert`. ALPN protocols are
+/// filled in by actix-web when binding.
+pub fn server_config(cert: Arc<ReloadableCert>) -> anyhow::Result<ServerConfig> {
+    Ok(
+        ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(cert),
+    )
+}
+
+/// Middleware restricting plain-HTTP connections to the health endpoints
+/// when TLS is enabled, so the extra listener only serves probes.
+pub async fn plain_http_health_only(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    if req.app_config().secure() || req.path().starts_with("/health") {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_left_body);
+    }
+
+    let response = HttpResponse::from_error(AppError::RouteNotFound {
+        path: req.path().to_string(),
+    });
+    Ok(req.into_response(response).map_into_right_body())
+}
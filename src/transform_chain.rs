@@ -0,0 +1,357 @@
+//! Chained transformations, the `tx` parameter: an ordered list of steps
+//! such as "crop to 4:5, then resize to 800, then grayscale", which the flat
+//! `w`/`h`/`fit` parameters can't express.
+//!
+//! Grammar of `tx`:
+//!
+//! ```text
+//! tx    = step *( "/" step )
+//! step  = name [ ":" arg *( "," arg ) ]
+//! arg   = key [ "=" value ]
+//! ```
+//!
+//! | Step | Arguments |
+//! |------|-----------|
+//! | `crop` | `ar=W:H` (required), `g=center\|top\|bottom\|left\|right` |
+//! | `resize` | `w`, `h` (at least one), `fit=contain\|cover\|pad` (with both), `bg` (with `pad`) |
+//! | `rotate` | `90`, `180` or `270` (clockwise) |
+//! | `flip` | `h` or `v` |
+//! | `filter` | `grayscale`, `invert` or `blur=1..50` |
+//!
+//! e.g. `crop:ar=4:5/resize:w=800/filter:grayscale`. Steps run in order,
+//! before the flat parameters. [`canonical`] gives the form the cache key is
+//! computed from, so equivalent chains such as `crop:ar=8:10,g=center` and
+//! `crop:ar=4:5` share cached images.
+
+use crate::config::Limits;
+use crate::error::{AppError, AppResult};
+use crate::image_processor::Fit;
+use crate::parse_color;
+use std::fmt;
+
+/// Most steps a chain may have.
+pub const MAX_STEPS: usize = 10;
+
+/// Largest term of a crop aspect ratio.
+pub const MAX_ASPECT_TERM: u32 = 1000;
+
+/// Largest blur radius (the Gaussian's sigma).
+pub const MAX_BLUR: u8 = 50;
+
+/// One step of a chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransformStep {
+    /// Crops to the largest region with the aspect ratio `width:height`,
+    /// kept in lowest terms.
+    Crop {
+        width: u32,
+        height: u32,
+        gravity: Gravity,
+    },
+    /// Downscales like the flat `w`, `h`, `fit` and `bg` parameters.
+    Resize {
+        width: Option<u32>,
+        height: Option<u32>,
+        fit: Fit,
+        background: Option<[u8; 4]>,
+    },
+    Rotate(Rotation),
+    Flip(Axis),
+    Filter(Filter),
+}
+
+/// Side of the image a crop keeps.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Gravity {
+    #[default]
+    Center,
+    Top,
+    Bottom,
+    Left,
+    Right,
+}
+
+impl Gravity {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "center" => Some(Gravity::Center),
+            "top" => Some(Gravity::Top),
+            "bottom" => Some(Gravity::Bottom),
+            "left" => Some(Gravity::Left),
+            "right" => Some(Gravity::Right),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Gravity::Center => "center",
+            Gravity::Top => "top",
+            Gravity::Bottom => "bottom",
+            Gravity::Left => "left",
+            Gravity::Right => "right",
+        }
+    }
+}
+
+/// Clockwise rotation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    Quarter,
+    Half,
+    ThreeQuarters,
+}
+
+impl Rotation {
+    pub fn degrees(&self) -> u16 {
+        match self {
+            Rotation::Quarter => 90,
+            Rotation::Half => 180,
+            Rotation::ThreeQuarters => 270,
+        }
+    }
+}
+
+/// Axis a flip mirrors the image across: `Horizontal` swaps left and right.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Axis {
+    Horizontal,
+    Vertical,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    Grayscale,
+    Invert,
+    /// Gaussian blur of the given sigma, in pixels.
+    Blur(u8),
+}
+
+impl fmt::Display for TransformStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransformStep::Crop {
+                width,
+                height,
+                gravity,
+            } => {
+                write!(f, "crop:ar={width}:{height}")?;
+                if *gravity != Gravity::Center {
+                    write!(f, ",g={}", gravity.as_str())?;
+                }
+                Ok(())
+            }
+            TransformStep::Resize {
+                width,
+                height,
+                fit,
+                background,
+            } => {
+                let mut args = Vec::new();
+                if let Some(width) = width {
+                    args.push(format!("w={width}"));
+                }
+                if let Some(height) = height {
+                    args.push(format!("h={height}"));
+                }
+                if *fit != Fit::Contain {
+                    args.push(format!("fit={}", fit.as_str()));
+                }
+                if let Some(background) = background {
+                    args.push(format!("bg={}", hex::encode(background)));
+                }
+                write!(f, "resize:{}", args.join(","))
+            }
+            TransformStep::Rotate(rotation) => write!(f, "rotate:{}", rotation.degrees()),
+            TransformStep::Flip(Axis::Horizontal) => f.write_str("flip:h"),
+            TransformStep::Flip(Axis::Vertical) => f.write_str("flip:v"),
+            TransformStep::Filter(Filter::Grayscale) => f.write_str("filter:grayscale"),
+            TransformStep::Filter(Filter::Invert) => f.write_str("filter:invert"),
+            TransformStep::Filter(Filter::Blur(sigma)) => write!(f, "filter:blur={sigma}"),
+        }
+    }
+}
+
+/// Canonical form of `steps`, which parses back to the same steps.
+pub fn canonical(steps: &[TransformStep]) -> String {
+    steps
+        .iter()
+        .map(TransformStep::to_string)
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Parses a `tx` value. Errors name the failing step as `tx[<index>]`,
+/// counting from 0, and every invalid step is reported.
+pub fn parse(value: &str, limits: &Limits) -> AppResult<Vec<TransformStep>> {
+    let tokens: Vec<&str> = value.split('/').collect();
+    if tokens.len() > MAX_STEPS {
+        return Err(AppError::InvalidParameterValue {
+            param: "tx".to_string(),
+            value: value.to_string(),
+            expected: format!("at most {MAX_STEPS} steps"),
+        });
+    }
+
+    let mut steps = Vec::with_capacity(tokens.len());
+    let mut errors = Vec::new();
+    for (index, token) in tokens.into_iter().enumerate() {
+        match parse_step(token, limits) {
+            Ok(step) => steps.push(step),
+            Err(expected) => errors.push(AppError::InvalidParameterValue {
+                param: format!("tx[{index}]"),
+                value: token.to_string(),
+                expected,
+            }),
+        }
+    }
+    AppError::from_validation(errors)?;
+    Ok(steps)
+}
+
+/// Arguments of a step, in order, each with its value if it has one.
+type Args<'a> = Vec<(&'a str, Option<&'a str>)>;
+
+/// Parses one step, or describes what was expected of it.
+fn parse_step(token: &str, limits: &Limits) -> Result<TransformStep, String> {
+    let (name, args) = token.split_once(':').unwrap_or((token, ""));
+    let args: Args = if args.is_empty() {
+        Vec::new()
+    } else {
+        args.split(',')
+            .map(|arg| match arg.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (arg, None),
+            })
+            .collect()
+    };
+    for (i, (key, _)) in args.iter().enumerate() {
+        if args[..i].iter().any(|(other, _)| other == key) {
+            return Err(format!("'{key}' at most once"));
+        }
+    }
+
+    match name {
+        "crop" => parse_crop(&args),
+        "resize" => parse_resize(&args, limits),
+        "rotate" => parse_rotate(&args),
+        "flip" => parse_flip(&args),
+        "filter" => parse_filter(&args),
+        _ => Err("a step named crop, resize, rotate, flip or filter".to_string()),
+    }
+}
+
+fn parse_crop(args: &Args) -> Result<TransformStep, String> {
+    let expected = || {
+        format!(
+            "crop:ar=W:H with W and H between 1 and {MAX_ASPECT_TERM}, \
+             and optionally g=center, top, bottom, left or right"
+        )
+    };
+    let mut aspect = None;
+    let mut gravity = Gravity::default();
+    for &(key, value) in args {
+        match (key, value) {
+            ("ar", Some(value)) => {
+                let (width, height) = value.split_once(':').ok_or_else(expected)?;
+                let term = |term: &str| match term.parse::<u32>() {
+                    Ok(term) if (1..=MAX_ASPECT_TERM).contains(&term) => Some(term),
+                    _ => None,
+                };
+                aspect = Some((
+                    term(width).ok_or_else(expected)?,
+                    term(height).ok_or_else(expected)?,
+                ));
+            }
+            ("g", Some(value)) => gravity = Gravity::parse(value).ok_or_else(expected)?,
+            _ => return Err(expected()),
+        }
+    }
+
+    let (width, height) = aspect.ok_or_else(expected)?;
+    let divisor = gcd(width, height);
+    Ok(TransformStep::Crop {
+        width: width / divisor,
+        height: height / divisor,
+        gravity,
+    })
+}
+
+fn parse_resize(args: &Args, limits: &Limits) -> Result<TransformStep, String> {
+    let expected = || {
+        format!(
+            "resize with w between 1 and {}, h between 1 and {}, or both, \
+             optionally fit=contain, cover or pad, and bg=<hex color> with fit=pad",
+            limits.max_width, limits.max_height
+        )
+    };
+    let side = |value: &str, max: u32| match value.parse::<u32>() {
+        Ok(side) if (1..=max).contains(&side) => Ok(side),
+        _ => Err(expected()),
+    };
+
+    let (mut width, mut height, mut fit, mut background) = (None, None, Fit::default(), None);
+    for &(key, value) in args {
+        match (key, value) {
+            ("w", Some(value)) => width = Some(side(value, limits.max_width)?),
+            ("h", Some(value)) => height = Some(side(value, limits.max_height)?),
+            ("fit", Some(value)) => fit = Fit::parse(value).ok_or_else(expected)?,
+            ("bg", Some(value)) => background = Some(parse_color(value).ok_or_else(expected)?),
+            _ => return Err(expected()),
+        }
+    }
+
+    let both = width.is_some() && height.is_some();
+    if (width.is_none() && height.is_none())
+        || (fit != Fit::Contain && !both)
+        || (background.is_some() && fit != Fit::Pad)
+    {
+        return Err(expected());
+    }
+    Ok(TransformStep::Resize {
+        width,
+        height,
+        fit,
+        background,
+    })
+}
+
+fn parse_rotate(args: &Args) -> Result<TransformStep, String> {
+    let rotation = match args.as_slice() {
+        [("90", None)] => Rotation::Quarter,
+        [("180", None)] => Rotation::Half,
+        [("270", None)] => Rotation::ThreeQuarters,
+        _ => return Err("rotate:90, rotate:180 or rotate:270".to_string()),
+    };
+    Ok(TransformStep::Rotate(rotation))
+}
+
+fn parse_flip(args: &Args) -> Result<TransformStep, String> {
+    let axis = match args.as_slice() {
+        [("h", None)] => Axis::Horizontal,
+        [("v", None)] => Axis::Vertical,
+        _ => return Err("flip:h or flip:v".to_string()),
+    };
+    Ok(TransformStep::Flip(axis))
+}
+
+fn parse_filter(args: &Args) -> Result<TransformStep, String> {
+    let expected = || format!("filter:grayscale, filter:invert or filter:blur=<1 to {MAX_BLUR}>");
+    let filter = match args.as_slice() {
+        [("grayscale", None)] => Filter::Grayscale,
+        [("invert", None)] => Filter::Invert,
+        [("blur", Some(sigma))] => match sigma.parse::<u8>() {
+            Ok(sigma) if (1..=MAX_BLUR).contains(&sigma) => Filter::Blur(sigma),
+            _ => return Err(expected()),
+        },
+        _ => return Err(expected()),
+    };
+    Ok(TransformStep::Filter(filter))
+}
+
+fn gcd(mut a: u32, mut b: u32) -> u32 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
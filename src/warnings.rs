@@ -0,0 +1,93 @@
+//! Degraded results: images served, but not quite as asked. Each kind has a
+//! short code, sent in the `X-Optimizer-Warnings` header of the response
+//! and logged, so clients and dashboards can notice without decoding the
+//! image. New degradations get a variant here.
+
+use serde::{Serialize, Serializer};
+use strum::{EnumIter, IntoEnumIterator};
+
+/// Header listing the warnings of a response, comma-separated.
+pub const WARNINGS_HEADER: &str = "x-optimizer-warnings";
+
+/// Response headers browsers let cross-origin scripts read: the metadata
+/// headers of images, [`WARNINGS_HEADER`], the request id, the headers of
+/// range responses and the download filename.
+pub const EXPOSED_HEADERS: &[&str] = &[
+    "x-request-id",
+    "x-image-width",
+    "x-image-height",
+    "x-original-size",
+    "x-alpha-flattened",
+    WARNINGS_HEADER,
+    "content-range",
+    "accept-ranges",
+    "content-disposition",
+];
+
+/// A way a served image falls short of the request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumIter)]
+pub enum Warning {
+    /// Transparent pixels were made opaque for JPEG output, reported under
+    /// the `warn` alpha policy.
+    AlphaFlattened,
+    /// The `w`×`h` box was larger than the image, which is never enlarged.
+    UpscaleRefused,
+}
+
+impl Warning {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Warning::AlphaFlattened => "alpha-flattened",
+            Warning::UpscaleRefused => "upscale-refused",
+        }
+    }
+
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+/// Warnings of one image, listed in declaration order whatever the order
+/// they were added in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Warnings(u32);
+
+impl Warnings {
+    pub fn insert(&mut self, warning: Warning) {
+        self.0 |= warning.bit();
+    }
+
+    pub fn contains(&self, warning: Warning) -> bool {
+        self.0 & warning.bit() != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Warning> + '_ {
+        Warning::iter().filter(|&warning| self.contains(warning))
+    }
+
+    /// Value of [`WARNINGS_HEADER`], `None` without warnings.
+    pub fn header_value(&self) -> Option<String> {
+        (!self.is_empty()).then(|| self.iter().map(|w| w.code()).collect::<Vec<_>>().join(","))
+    }
+}
+
+impl FromIterator<Warning> for Warnings {
+    fn from_iter<I: IntoIterator<Item = Warning>>(iter: I) -> Self {
+        let mut warnings = Warnings::default();
+        for warning in iter {
+            warnings.insert(warning);
+        }
+        warnings
+    }
+}
+
+/// A list of codes.
+impl Serialize for Warnings {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter().map(|warning| warning.code()))
+    }
+}
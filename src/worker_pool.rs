@@ -0,0 +1,212 @@
+//! Dedicated threads doing the CPU work of the pipeline: decoding, resizing
+//! and encoding. Handlers submit a [`ProcessingJob`] to a bounded queue and
+//! await its outcome, so the HTTP workers never block on an image, and the
+//! number of images processed at once does not depend on how many requests
+//! are in flight.
+
+use crate::config::ProcessingConfig;
+use crate::error::{AppError, AppResult};
+use crate::image_processor::{ImageProcessor, ProcessedImage, ProcessingPlan};
+use crate::metrics::PhaseTimings;
+use log::warn;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+/// An image to process into one or more outputs, and where to send the
+/// outcome. The image is decoded once whatever the number of plans.
+pub struct ProcessingJob {
+    pub data: Vec<u8>,
+    pub plans: Vec<ProcessingPlan>,
+    /// Span of the request, entered while processing.
+    pub span: tracing::Span,
+    pub respond_to: oneshot::Sender<JobOutcome>,
+}
+
+/// Result of a job along with the duration of each of its phases.
+#[derive(Debug)]
+pub struct JobOutcome {
+    /// One result per plan, in the order of the job's plans.
+    pub results: Vec<AppResult<ProcessedImage>>,
+    pub timings: PhaseTimings,
+}
+
+impl JobOutcome {
+    /// Result of the first plan, the only one of jobs from
+    /// [`WorkerPool::submit`].
+    pub fn into_result(self) -> AppResult<ProcessedImage> {
+        self.results
+            .into_iter()
+            .next()
+            .unwrap_or(Err(AppError::InternalServerError))
+    }
+}
+
+#[derive(Debug)]
+pub struct WorkerPool {
+    sender: SyncSender<ProcessingJob>,
+    workers: usize,
+    /// Jobs submitted that no worker has picked up yet.
+    queued: Arc<AtomicUsize>,
+    /// Shed jobs get a 503 instead of a 429.
+    shed_unavailable: bool,
+}
+
+impl WorkerPool {
+    /// Starts `workers` threads sharing a queue of `capacity` jobs. Jobs
+    /// submitted while it is full are refused with `Overloaded`.
+    pub fn new(workers: usize, capacity: usize, shed_unavailable: bool) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let queued = Arc::new(AtomicUsize::new(0));
+        for index in 0..workers {
+            let receiver = receiver.clone();
+            let queued = queued.clone();
+            let spawned = std::thread::Builder::new()
+                .name(format!("image-worker-{index}"))
+                .spawn(move || work(&receiver, &queued));
+            if let Err(e) = spawned {
+                warn!("Failed to start image worker {index}: {e}");
+            }
+        }
+        Self {
+            sender,
+            workers,
+            queued,
+            shed_unavailable,
+        }
+    }
+
+    pub fn from_config(config: &ProcessingConfig) -> Self {
+        Self::new(
+            config.workers,
+            config.queue_capacity,
+            config.shed_status == 503,
+        )
+    }
+
+    /// Processes `data` on a worker, recording the phases into `timings`.
+    #[cfg_attr(
+        feature = "otel",
+        tracing::instrument(name = "process_image", skip(self, data, timings))
+    )]
+    pub async fn process(
+        &self,
+        data: Vec<u8>,
+        plan: &ProcessingPlan,
+        timings: &mut PhaseTimings,
+    ) -> AppResult<ProcessedImage> {
+        let outcome = self.submit(data, plan)?;
+        Self::wait(outcome, timings).await?.into_result()
+    }
+
+    /// Processes `data` once per plan on a single worker, which decodes it
+    /// once. Fails as a whole only when the job could not run.
+    #[cfg_attr(
+        feature = "otel",
+        tracing::instrument(name = "process_images", skip_all, fields(plans = plans.len()))
+    )]
+    pub async fn process_many(
+        &self,
+        data: Vec<u8>,
+        plans: &[ProcessingPlan],
+        timings: &mut PhaseTimings,
+    ) -> AppResult<Vec<AppResult<ProcessedImage>>> {
+        let outcome = self.submit_many(data, plans.to_vec())?;
+        Ok(Self::wait(outcome, timings).await?.results)
+    }
+
+    async fn wait(
+        outcome: oneshot::Receiver<JobOutcome>,
+        timings: &mut PhaseTimings,
+    ) -> AppResult<JobOutcome> {
+        let outcome = outcome.await.map_err(|_| AppError::ImageProcessingFailed {
+            reason: "Image worker stopped before finishing the job".to_string(),
+        })?;
+        for &(phase, duration) in outcome.timings.iter() {
+            timings.record(phase, duration);
+        }
+        Ok(outcome)
+    }
+
+    /// Queues a job, failing right away when the queue is full.
+    pub fn submit(
+        &self,
+        data: Vec<u8>,
+        plan: &ProcessingPlan,
+    ) -> AppResult<oneshot::Receiver<JobOutcome>> {
+        self.submit_many(data, vec![plan.clone()])
+    }
+
+    /// Queues a job with several plans, see [`Self::submit`].
+    pub fn submit_many(
+        &self,
+        data: Vec<u8>,
+        plans: Vec<ProcessingPlan>,
+    ) -> AppResult<oneshot::Receiver<JobOutcome>> {
+        let (respond_to, outcome) = oneshot::channel();
+        let job = ProcessingJob {
+            data,
+            plans,
+            span: tracing::Span::current(),
+            respond_to,
+        };
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        match self.sender.try_send(job) {
+            Ok(()) => Ok(outcome),
+            Err(e) => {
+                self.queued.fetch_sub(1, Ordering::Relaxed);
+                Err(match e {
+                    TrySendError::Full(_) => AppError::Overloaded {
+                        retry_after_secs: 1,
+                        unavailable: self.shed_unavailable,
+                    },
+                    TrySendError::Disconnected(_) => AppError::ServiceUnavailable,
+                })
+            }
+        }
+    }
+
+    /// Threads processing jobs.
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+
+    /// Jobs waiting for a worker.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+}
+
+/// Runs jobs until the pool, and so the sending half, is dropped.
+fn work(receiver: &Mutex<Receiver<ProcessingJob>>, queued: &AtomicUsize) {
+    loop {
+        // Held only while waiting for the next job, not while processing it
+        let job = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => return,
+        };
+        let Ok(job) = job else {
+            return;
+        };
+        queued.fetch_sub(1, Ordering::Relaxed);
+
+        let _entered = job.span.enter();
+        let mut timings = PhaseTimings::default();
+        // A panic fails the job, as it would a blocking task, and keeps the
+        // worker
+        let results = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            ImageProcessor::process_many_sync_timed(&job.data, &job.plans, &mut timings)
+        }))
+        .unwrap_or_else(|_| {
+            let err = AppError::ImageProcessingFailed {
+                reason: "Image processing panicked".to_string(),
+            };
+            job.plans.iter().map(|_| Err(err.clone())).collect()
+        });
+        // The request may have been cancelled meanwhile
+        let _ = job.respond_to.send(JobOutcome { results, timings });
+    }
+}
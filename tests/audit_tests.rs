@@ -0,0 +1,235 @@
+#![cfg(feature = "runtime")]
+//! The audit log: records written to daily files by the writer thread,
+//! their aggregation per source, retention, and the dates naming the files.
+
+use std::collections::BTreeMap;
+use std::fs;
+
+use img_optimizer::audit::{
+    format_day, params_query, parse_day, prune, summarize, AuditLog, AuditQuery, AuditRecord,
+};
+use img_optimizer::error::AppError;
+use img_optimizer::ImageParams;
+use tempfile::TempDir;
+
+/// 2026-10-18, in days since the Unix epoch.
+const DAY: u64 = 20_744;
+
+fn record(src: &str, ts: u64, status: u16, bytes_in: u64, bytes_out: u64) -> AuditRecord {
+    let mut record = AuditRecord::new(src, "w=400&f=webp".to_string()).unwrap();
+    record.ts = ts;
+    record.status = status;
+    record.bytes_in = bytes_in;
+    record.bytes_out = bytes_out;
+    record
+}
+
+#[test]
+fn test_new_record() {
+    let record = AuditRecord::new("https://Images.Example/a/b.png?v=2", String::new()).unwrap();
+    assert_eq!(record.host, "images.example");
+    assert_eq!(record.path, "/a/b.png");
+    assert_eq!(record.status, 200);
+    assert!(record.error.is_none());
+
+    // Sources without a host aren't audited
+    assert!(AuditRecord::new("data:image/png;base64,iVBORw0KGgo=", String::new()).is_none());
+    assert!(AuditRecord::new("not a url", String::new()).is_none());
+
+    let mut record = record;
+    record.fail(&AppError::SourceNotFound {
+        url: "https://images.example/a/b.png".to_string(),
+        status: 404,
+    });
+    assert_eq!(record.status, 404);
+    assert_eq!(record.error.as_deref(), Some("IMG_008"));
+
+    // Paths are cut as in logs
+    let long = format!("/{}.png", "a".repeat(4000));
+    let record = AuditRecord::new(&format!("https://images.example{long}"), String::new()).unwrap();
+    assert_eq!(record.path, format!("{}... (4005 bytes)", &long[..256]));
+}
+
+#[test]
+fn test_params_query() {
+    let params = ImageParams {
+        src: Some("https://images.example/a.png".to_string()),
+        w: Some("400".to_string()),
+        f: Some("webp".to_string()),
+        tx: Some("crop:ar=4:5/filter:grayscale".to_string()),
+        dl: Some("a.webp".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(
+        params_query(&params),
+        "w=400&f=webp&tx=crop%3Aar%3D4%3A5%2Ffilter%3Agrayscale"
+    );
+    assert_eq!(params_query(&ImageParams::default()), "");
+}
+
+#[test]
+fn test_records_are_written_to_daily_files() {
+    let dir = TempDir::new().unwrap();
+    let log = AuditLog::start(dir.path().join("audit"), 30).unwrap();
+    let today = DAY * 86_400;
+    log.record(record(
+        "https://a.example/x.png",
+        today - 86_400,
+        200,
+        10,
+        5,
+    ));
+    log.record(record("https://a.example/x.png", today + 60, 200, 10, 5));
+    log.record(record("https://a.example/x.png", today + 120, 304, 0, 0));
+    log.flush();
+
+    let yesterday = fs::read_to_string(log.dir().join("audit-2026-10-17.jsonl")).unwrap();
+    assert_eq!(yesterday.lines().count(), 1);
+    let today = fs::read_to_string(log.dir().join("audit-2026-10-18.jsonl")).unwrap();
+    let lines: Vec<&str> = today.lines().collect();
+    assert_eq!(lines.len(), 2);
+
+    let line: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+    assert_eq!(
+        line,
+        serde_json::json!({
+            "ts": DAY * 86_400 + 60,
+            "host": "a.example",
+            "path": "/x.png",
+            "params": "w=400&f=webp",
+            "status": 200,
+            "bytesIn": 10,
+            "bytesOut": 5,
+        })
+    );
+    assert_eq!(log.dropped(), 0);
+}
+
+#[test]
+fn test_summarize() {
+    let dir = TempDir::new().unwrap();
+    let log = AuditLog::start(dir.path().to_path_buf(), 30).unwrap();
+    let today = DAY * 86_400;
+    log.record(record(
+        "https://a.example/x.png",
+        today - 86_400,
+        200,
+        100,
+        40,
+    ));
+    log.record(record("https://a.example/x.png", today + 10, 200, 0, 40));
+    let mut failed = record("https://a.example/x.png", today + 20, 200, 0, 0);
+    failed.fail(&AppError::InvalidImageData);
+    log.record(failed);
+    log.record(record("https://b.example/y.png", today + 30, 200, 7, 3));
+    log.flush();
+    // Files of other programs and torn lines are skipped
+    fs::write(dir.path().join("notes.txt"), "not audit").unwrap();
+    let torn = dir.path().join("audit-2026-10-18.jsonl");
+    let mut content = fs::read_to_string(&torn).unwrap();
+    content.push_str("{\"ts\":1,\"ho");
+    fs::write(&torn, content).unwrap();
+
+    let summary = log.summarize(None, None).unwrap();
+    assert_eq!(summary.total, 4);
+    let a = &summary.sources[0];
+    assert_eq!((a.host.as_str(), a.path.as_str()), ("a.example", "/x.png"));
+    assert_eq!((a.count, a.bytes_in, a.bytes_out), (3, 100, 80));
+    let invalid = AppError::InvalidImageData;
+    assert_eq!(
+        a.statuses,
+        BTreeMap::from([(200, 2), (invalid.metadata().status.as_u16(), 1)])
+    );
+    assert_eq!(
+        a.errors,
+        BTreeMap::from([(invalid.error_code().to_string(), 1)])
+    );
+    assert_eq!(a.last_seen, today + 20);
+    assert_eq!(summary.sources[1].host, "b.example");
+
+    // Narrowed by host, case-insensitively, and by day
+    let summary = log.summarize(Some("B.example"), None).unwrap();
+    assert_eq!(summary.total, 1);
+    assert_eq!(summary.sources[0].bytes_in, 7);
+    let summary = summarize(dir.path(), Some("a.example"), Some(DAY)).unwrap();
+    assert_eq!(summary.total, 2);
+    assert_eq!(summary.sources[0].bytes_in, 0);
+    let summary = summarize(dir.path(), None, Some(DAY + 1)).unwrap();
+    assert_eq!(summary.total, 0);
+    assert!(summary.sources.is_empty());
+}
+
+#[test]
+fn test_prune() {
+    let dir = TempDir::new().unwrap();
+    for day in ["2026-10-18", "2026-10-12", "2026-10-11", "2025-01-01"] {
+        fs::write(dir.path().join(format!("audit-{day}.jsonl")), "").unwrap();
+    }
+    fs::write(dir.path().join("audit-latest.jsonl"), "").unwrap();
+
+    // Seven days kept, today's included
+    assert_eq!(prune(dir.path(), 7, DAY).unwrap(), 2);
+    let mut left: Vec<String> = fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    left.sort();
+    assert_eq!(
+        left,
+        [
+            "audit-2026-10-12.jsonl",
+            "audit-2026-10-18.jsonl",
+            "audit-latest.jsonl"
+        ]
+    );
+}
+
+#[test]
+fn test_days() {
+    let cases = [
+        (0, "1970-01-01"),
+        (59, "1970-03-01"),
+        (11_016, "2000-02-29"),
+        (DAY, "2026-10-18"),
+        (DAY + 74, "2026-12-31"),
+        (DAY + 75, "2027-01-01"),
+    ];
+    for (days, date) in cases {
+        assert_eq!(format_day(days), date, "{days}");
+        assert_eq!(parse_day(date), Some(days), "{date}");
+    }
+
+    for invalid in [
+        "",
+        "2026-10",
+        "2026-10-18T00:00:00Z",
+        "2026-1-18",
+        "2026-13-01",
+        "2026-02-29",
+        "2026-10-00",
+        "1969-12-31",
+        "+026-10-18",
+        "yesterday",
+    ] {
+        assert_eq!(parse_day(invalid), None, "{invalid:?}");
+    }
+}
+
+#[test]
+fn test_since_parameter() {
+    let query = AuditQuery {
+        since: Some("2026-10-18".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(query.since_day().unwrap(), Some(DAY));
+    assert_eq!(AuditQuery::default().since_day().unwrap(), None);
+
+    let query = AuditQuery {
+        since: Some("last week".to_string()),
+        ..Default::default()
+    };
+    assert!(matches!(
+        query.since_day(),
+        Err(AppError::InvalidParameterValue { param, .. }) if param == "since"
+    ));
+}
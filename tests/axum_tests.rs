@@ -0,0 +1,510 @@
+#![cfg(feature = "axum")]
+//! The tower/axum adapter, mirroring the actix integration tests.
+
+use axum::body::{to_bytes, Body};
+use axum::http::{Request, StatusCode};
+use axum::Router;
+use std::sync::Arc;
+use tempfile::TempDir;
+use tower::ServiceExt;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use img_optimizer::{
+    auth::ApiKeys, axum_service::ImageOptimizerService, cache::ImageCache, config::AppConfig,
+    test_support::fixture_png, AppState, Optimizer,
+};
+
+fn create_app_state(temp_dir: &TempDir) -> AppState {
+    let mut config = AppConfig::default();
+    config.storage.dir = temp_dir.path().join("storage");
+    Optimizer::builder()
+        .config(config)
+        .cache(ImageCache::new(temp_dir.path().to_path_buf()))
+        .build()
+        .state()
+        .clone()
+}
+
+/// An application mounting the optimizer under `/images`.
+fn create_app(state: AppState) -> Router {
+    Router::new().nest_service("/images", ImageOptimizerService::new(state))
+}
+
+async fn get(app: Router, uri: &str) -> axum::response::Response {
+    app.oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        .await
+        .unwrap()
+}
+
+async fn read_json(response: axum::response::Response) -> serde_json::Value {
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+#[tokio::test]
+async fn test_image_optimization_with_mock_server() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/test-image.png"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_bytes(fixture_png(4, 4))
+                .insert_header("content-type", "image/png"),
+        )
+        .mount(&mock_server)
+        .await;
+
+    let temp_dir = TempDir::new().unwrap();
+    let app = create_app(create_app_state(&temp_dir));
+
+    let image_url = format!("{}/test-image.png", mock_server.uri());
+    let uri = format!("/images/img-optimizer/v1/img?src={image_url}&w=2&f=png");
+    let response = get(app.clone(), &uri).await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "image/png");
+    assert_eq!(response.headers()["x-image-width"], "2");
+    assert_eq!(response.headers()["x-image-height"], "2");
+    assert_eq!(response.headers()["x-original-size"], "4x4");
+    assert!(!response.headers().contains_key("x-optimizer-warnings"));
+    let etag = response.headers()["etag"].clone();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(image::load_from_memory(&body).unwrap().width(), 2);
+
+    // Cache hits report the same dimensions
+    let response = get(app.clone(), &uri).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["x-image-width"], "2");
+    assert_eq!(response.headers()["x-original-size"], "4x4");
+
+    let larger = format!("/images/img-optimizer/v1/img?src={image_url}&w=8&f=png");
+    let response = get(app.clone(), &larger).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["x-image-width"], "4");
+    assert_eq!(
+        response.headers()["x-optimizer-warnings"],
+        "upscale-refused"
+    );
+
+    let response = app
+        .oneshot(
+            Request::get(&uri)
+                .header("if-none-match", etag.clone())
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(response.headers()["etag"], etag);
+}
+
+#[tokio::test]
+async fn test_byte_ranges() {
+    let temp_dir = TempDir::new().unwrap();
+    let state = create_app_state(&temp_dir);
+    state
+        .storage
+        .put("0123456789abcdef0123456789abcdef.png", &fixture_png(4, 4))
+        .await
+        .unwrap();
+    let app = create_app(state);
+    let uri = "/images/img-optimizer/v1/img/0123456789abcdef0123456789abcdef.png";
+    let ranged = |value: &'static str| {
+        app.clone().oneshot(
+            Request::get(uri)
+                .header("range", value)
+                .body(Body::empty())
+                .unwrap(),
+        )
+    };
+
+    let response = get(app.clone(), uri).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["accept-ranges"], "bytes");
+
+    let length = fixture_png(4, 4).len();
+    let response = ranged("bytes=4-11").await.unwrap();
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        response.headers()["content-range"],
+        format!("bytes 4-11/{length}").as_str()
+    );
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, fixture_png(4, 4)[4..12]);
+
+    let response = ranged("bytes=100000-").await.unwrap();
+    assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(
+        response.headers()["content-range"],
+        format!("bytes */{length}").as_str()
+    );
+    assert_eq!(read_json(response).await["errorCode"], "SYS_416");
+}
+
+#[tokio::test]
+async fn test_svg_redirect() {
+    let temp_dir = TempDir::new().unwrap();
+    let app = create_app(create_app_state(&temp_dir));
+
+    let svg_url = "https://example.com/test.svg";
+    let response = get(app, &format!("/images/img-optimizer/v1/img?src={svg_url}")).await;
+
+    assert_eq!(response.status(), StatusCode::FOUND);
+    assert_eq!(response.headers()["location"], svg_url);
+}
+
+#[tokio::test]
+async fn test_srcset_manifest() {
+    let temp_dir = TempDir::new().unwrap();
+    let app = create_app(create_app_state(&temp_dir));
+    let request = Request::get(
+        "/images/img-optimizer/v1/img/srcset?src=https%3A%2F%2Fexample.com%2Fa.png&widths=640,320",
+    )
+    .header("host", "internal:3000")
+    .header("x-forwarded-proto", "https")
+    .header("x-forwarded-host", "www.example.com")
+    .body(Body::empty())
+    .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = read_json(response).await;
+    let endpoint = "https://www.example.com/images/img-optimizer/v1/img";
+    assert_eq!(
+        body["srcset"],
+        format!(
+            "{endpoint}?src=https%3A%2F%2Fexample.com%2Fa.png&w=320 320w, \
+             {endpoint}?src=https%3A%2F%2Fexample.com%2Fa.png&w=640 640w"
+        )
+    );
+    assert_eq!(body["variants"][0]["cacheKey"].as_str().unwrap().len(), 64);
+}
+
+#[tokio::test]
+async fn test_bundle_download() {
+    let temp_dir = TempDir::new().unwrap();
+    let app = create_app(create_app_state(&temp_dir));
+    let src = urlencoding::encode(
+        "data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mNkYPhfDwAChwGA60e6kgAAAABJRU5ErkJggg==",
+    );
+    let request = Request::get(format!(
+        "/images/img-optimizer/v1/img/bundle?src={src}&variants=w_1_f_png,w_1_f_jpeg"
+    ))
+    .body(Body::empty())
+    .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "application/zip");
+    assert_eq!(
+        response.headers()["content-disposition"],
+        "attachment; filename=\"image.zip\""
+    );
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let archive = zip::ZipArchive::new(std::io::Cursor::new(body)).unwrap();
+    let names: Vec<_> = archive.file_names().collect();
+    assert_eq!(names, ["image-1w.png", "image-1w.jpg"]);
+}
+
+#[tokio::test]
+async fn test_async_job() {
+    let temp_dir = TempDir::new().unwrap();
+    let app = create_app(create_app_state(&temp_dir));
+    let src = urlencoding::encode(
+        "data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mNkYPhfDwAChwGA60e6kgAAAABJRU5ErkJggg==",
+    );
+    let request = Request::post(format!("/images/img-optimizer/v1/jobs?src={src}&w=1"))
+        .header("host", "app.test")
+        .body(Body::empty())
+        .unwrap();
+
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let location = response.headers()["location"].to_str().unwrap().to_string();
+    let id = read_json(response).await["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    assert_eq!(location, format!("/images/img-optimizer/v1/jobs/{id}"));
+
+    let mut body = serde_json::Value::Null;
+    for _ in 0..100 {
+        body = read_json(get(app.clone(), &location).await).await;
+        if body["status"] != "pending" {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    assert_eq!(body["status"], "succeeded", "{body}");
+    assert_eq!(
+        body["url"],
+        format!("http://app.test/images/img-optimizer/v1/img?src={src}&w=1")
+    );
+
+    let response = get(app, "/images/img-optimizer/v1/jobs/unknown").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(read_json(response).await["errorCode"], "JOB_001");
+}
+
+#[cfg(feature = "webp")]
+#[tokio::test]
+async fn test_auto_format_follows_accept() {
+    let temp_dir = TempDir::new().unwrap();
+    let app = create_app(create_app_state(&temp_dir));
+    let src = urlencoding::encode(
+        "data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mNkYPhfDwAChwGA60e6kgAAAABJRU5ErkJggg==",
+    );
+    let request = |accept: &str| {
+        Request::get(format!("/images/img-optimizer/v1/img?src={src}&w=1&f=auto"))
+            .header("accept", accept)
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let response = app
+        .clone()
+        .oneshot(request("image/webp,*/*"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "image/webp");
+    assert_eq!(response.headers()["vary"], "Accept");
+
+    let response = app.clone().oneshot(request("*/*")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_ne!(response.headers()["content-type"], "image/webp");
+    assert_eq!(response.headers()["vary"], "Accept");
+
+    // Without f=auto, the response depends on the URL alone
+    let response = app
+        .oneshot(
+            Request::get(format!("/images/img-optimizer/v1/img?src={src}&w=1"))
+                .header("accept", "image/webp,*/*")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get("vary").is_none());
+}
+
+#[tokio::test]
+async fn test_vary_on_api_key_header() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut state = create_app_state(&temp_dir);
+    state.api_keys = Arc::new(ApiKeys::parse("site:s3cret"));
+    let app = create_app(state);
+    let src = urlencoding::encode(
+        "data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mNkYPhfDwAChwGA60e6kgAAAABJRU5ErkJggg==",
+    );
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::get(format!("/images/img-optimizer/v1/img?src={src}"))
+                .header("x-api-key", "s3cret")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["vary"], "X-Api-Key");
+
+    // A key in the query string is part of the URL already
+    let response = get(
+        app,
+        &format!("/images/img-optimizer/v1/img?src={src}&key=s3cret"),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get("vary").is_none());
+}
+
+#[tokio::test]
+async fn test_client_hints() {
+    let temp_dir = TempDir::new().unwrap();
+    let state = create_app_state(&temp_dir);
+    let mut config = (*state.config()).clone();
+    config.features.client_hints = true;
+    state.config.store(Arc::new(config));
+    let app = create_app(state);
+    let src = urlencoding::encode(
+        "data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mNkYPhfDwAChwGA60e6kgAAAABJRU5ErkJggg==",
+    );
+
+    let response = app
+        .oneshot(
+            Request::get(format!("/images/img-optimizer/v1/img?src={src}&w=1&f=png"))
+                .header("sec-ch-dpr", "2")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["accept-ch"], "Sec-CH-DPR, Sec-CH-Width");
+    assert_eq!(response.headers()["vary"], "Sec-CH-DPR");
+}
+
+#[tokio::test]
+async fn test_stored_images_are_immutable() {
+    let temp_dir = TempDir::new().unwrap();
+    let storage = temp_dir.path().join("storage");
+    std::fs::create_dir_all(&storage).unwrap();
+    let mut png = Vec::new();
+    image::RgbImage::from_pixel(2, 2, image::Rgb([0, 0, 0]))
+        .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+        .unwrap();
+    std::fs::write(storage.join("0123456789abcdef0123456789abcdef.png"), &png).unwrap();
+    let app = create_app(create_app_state(&temp_dir));
+
+    let response = get(
+        app,
+        "/images/img-optimizer/v1/img/0123456789abcdef0123456789abcdef.png",
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "image/png");
+    assert_eq!(
+        response.headers()["content-length"],
+        png.len().to_string().as_str()
+    );
+    assert_eq!(
+        response.headers()["cache-control"],
+        "public, max-age=31536000, immutable"
+    );
+}
+
+#[tokio::test]
+async fn test_long_queries_are_refused() {
+    let temp_dir = TempDir::new().unwrap();
+    let app = create_app(create_app_state(&temp_dir));
+    let src = format!("https://images.example/{}.png", "a".repeat(20 * 1024));
+
+    let response = get(app, &format!("/images/img-optimizer/v1/img?src={src}")).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = read_json(response).await;
+    assert_eq!(body["errorCode"], "VAL_011");
+    assert!(body["instance"].as_str().unwrap().len() < 512);
+}
+
+#[tokio::test]
+async fn test_error_format_rfc7807() {
+    let temp_dir = TempDir::new().unwrap();
+    let app = create_app(create_app_state(&temp_dir));
+
+    let response = get(app, "/images/img-optimizer/v1/img?src=invalid-url").await;
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(
+        response.headers()["content-type"],
+        "application/problem+json"
+    );
+    let body = read_json(response).await;
+    assert_eq!(body["errorCode"], "IMG_001");
+    assert_eq!(body["status"], 400);
+    assert!(body["title"].is_string());
+    assert!(body["detail"].is_string());
+    assert!(body["howToFix"].is_string());
+    assert_eq!(
+        body["instance"],
+        "/images/img-optimizer/v1/img?src=invalid-url"
+    );
+}
+
+#[tokio::test]
+async fn test_unmatched_routes_and_methods_use_problem_details() {
+    let temp_dir = TempDir::new().unwrap();
+    let app = create_app(create_app_state(&temp_dir));
+
+    let response = get(app.clone(), "/images/nope").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(read_json(response).await["errorCode"], "SYS_404");
+
+    let response = app
+        .oneshot(
+            Request::delete("/images/img-optimizer/v1/img")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(response.headers()["allow"], "GET, POST");
+    assert_eq!(read_json(response).await["errorCode"], "SYS_405");
+}
+
+/// Both adapters answer a table of tricky queries with the same status,
+/// error code and invalid parameters.
+#[cfg(feature = "actix")]
+#[tokio::test]
+async fn test_query_parsing_matches_actix() {
+    use actix_web::{middleware::from_fn, test, web, App};
+    use img_optimizer::{error::problem_details_context, optimize_image_handler};
+
+    let temp_dir = TempDir::new().unwrap();
+    let state = create_app_state(&temp_dir);
+    let actix = test::init_service(
+        App::new()
+            .app_data(web::Data::new(state.clone()))
+            .wrap(from_fn(problem_details_context))
+            .route(
+                "/img-optimizer/v1/img",
+                web::get().to(optimize_image_handler),
+            ),
+    )
+    .await;
+    let axum = img_optimizer::axum_service::router(state);
+
+    let src = urlencoding::encode(
+        "data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mNkYPhfDwAChwGA60e6kgAAAABJRU5ErkJggg==",
+    );
+    let queries = [
+        format!("src={src}&w=1&f=png"),
+        format!("src={src}&w=abc"),
+        format!("src={src}&w="),
+        format!("src={src}&w=-1"),
+        format!("src={src}&w=1.5"),
+        format!("src={src}&w=%2010"),
+        format!("src={src}&w=99999999999"),
+        format!("src={src}&w=10&w=20"),
+        format!("src={src}&w=0&h=abc&q=101&f=gif&fit=stretch&bg=zzz"),
+        format!("src={src}&srcb64=aGk"),
+        format!("src={src}&fm=png&w=abc"),
+        format!("src={src}&auto=compress&q=0"),
+        "w=10".to_string(),
+        "src=not%20a%20url".to_string(),
+    ];
+
+    for query in &queries {
+        let uri = format!("/img-optimizer/v1/img?{query}");
+
+        let resp =
+            test::call_service(&actix, test::TestRequest::get().uri(&uri).to_request()).await;
+        let actix_status = resp.status().as_u16();
+        let actix_body = test::read_body(resp).await;
+
+        let resp = get(axum.clone(), &uri).await;
+        let axum_status = resp.status().as_u16();
+        let axum_body = to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+
+        assert_eq!(actix_status, axum_status, "{query}");
+        if actix_status != 200 {
+            let summary = |body: &[u8]| {
+                let body: serde_json::Value = serde_json::from_slice(body).unwrap();
+                let params: Vec<String> = body["errors"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(|error| error["param"].to_string())
+                    .collect();
+                (body["errorCode"].clone(), params)
+            };
+            assert_eq!(summary(&actix_body), summary(&axum_body), "{query}");
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! Parsing of bundle variants and the names of the archive and its members.
+
+use img_optimizer::bundle::{file_stem, member_name, parse_variants, VariantSpec, MAX_VARIANTS};
+use img_optimizer::error::AppError;
+
+fn variant(w: Option<&str>, f: Option<&str>) -> VariantSpec {
+    VariantSpec {
+        w: w.map(str::to_string),
+        f: f.map(str::to_string),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_parse_variants() {
+    assert_eq!(
+        parse_variants("w_400_f_webp,w_800_f_webp,w_400_f_png").unwrap(),
+        [
+            variant(Some("400"), Some("webp")),
+            variant(Some("800"), Some("webp")),
+            variant(Some("400"), Some("png")),
+        ]
+    );
+    assert_eq!(
+        parse_variants("f_avif_q_50_h_300_w_600_fit_cover").unwrap(),
+        [VariantSpec {
+            w: Some("600".to_string()),
+            h: Some("300".to_string()),
+            fit: Some("cover".to_string()),
+            q: Some("50".to_string()),
+            f: Some("avif".to_string()),
+        }]
+    );
+    // Values are checked later, like the parameters of the same name
+    assert_eq!(
+        parse_variants(" w_abc ").unwrap(),
+        [variant(Some("abc"), None)]
+    );
+}
+
+#[test]
+fn test_parse_variants_rejects_invalid_variants() {
+    let cases = [
+        "",
+        "w",
+        "w_",
+        "_400",
+        "w_400_f",
+        "w_400_w_800",
+        "x_1",
+        "W_400",
+        "w_400__f_png",
+    ];
+    for value in cases {
+        let errors = match parse_variants(value) {
+            Ok(variants) => panic!("{value:?} parsed as {variants:?}"),
+            Err(err) => err,
+        };
+        assert!(
+            matches!(
+                &errors,
+                AppError::InvalidParameterValue { param, value: v, .. }
+                    if param == "variants[0]" && v == value
+            ),
+            "{value:?}: {errors:?}"
+        );
+    }
+}
+
+#[test]
+fn test_parse_variants_names_every_failing_variant() {
+    let Err(AppError::ValidationFailed { errors }) = parse_variants("w_400,x_1,w_800,f") else {
+        panic!("expected several errors");
+    };
+    let params: Vec<_> = errors
+        .iter()
+        .map(|err| match err {
+            AppError::InvalidParameterValue { param, .. } => param.as_str(),
+            other => panic!("unexpected {other:?}"),
+        })
+        .collect();
+    assert_eq!(params, ["variants[1]", "variants[3]"]);
+}
+
+#[test]
+fn test_variant_count_is_limited() {
+    let most = ["w_100"; MAX_VARIANTS].join(",");
+    assert_eq!(parse_variants(&most).unwrap().len(), MAX_VARIANTS);
+
+    let too_many = ["w_100"; MAX_VARIANTS + 1].join(",");
+    assert!(matches!(
+        parse_variants(&too_many),
+        Err(AppError::InvalidParameterValue { param, .. }) if param == "variants"
+    ));
+}
+
+#[test]
+fn test_file_stem() {
+    let cases = [
+        ("https://images.example/photos/hero.png", "hero"),
+        ("https://images.example/hero.final.jpg?v=2", "hero_final"),
+        (
+            "https://images.example/photo%20d%C3%A9t%C3%A9.png",
+            "photo_20d_C3_A9t_C3_A9",
+        ),
+        ("https://images.example/", "image"),
+        ("https://images.example/.png", "png"),
+        ("data:image/png;base64,iVBORw0KGgo=", "image"),
+        ("not a url", "image"),
+    ];
+    for (src, expected) in cases {
+        assert_eq!(file_stem(src), expected, "{src:?}");
+    }
+    let long = format!("https://images.example/{}.png", "a".repeat(200));
+    assert_eq!(file_stem(&long).len(), 64);
+}
+
+#[test]
+fn test_member_name() {
+    assert_eq!(
+        member_name("hero", 400, "image/webp", &[]),
+        "hero-400w.webp"
+    );
+    assert_eq!(member_name("hero", 400, "image/jpeg", &[]), "hero-400w.jpg");
+
+    let taken = vec!["hero-400w.png".to_string(), "hero-400w-2.png".to_string()];
+    assert_eq!(
+        member_name("hero", 400, "image/png", &taken),
+        "hero-400w-3.png"
+    );
+    assert_eq!(
+        member_name("hero", 800, "image/png", &taken),
+        "hero-800w.png"
+    );
+}
@@ -0,0 +1,122 @@
+//! Cache keys: requests for the same image share a key however they spell
+//! it, and requests for different images never do.
+
+use img_optimizer::config::Limits;
+use img_optimizer::image_processor::{Fit, OutputFormat, ProcessingPlan};
+use img_optimizer::{generate_cache_key, parse_query, ImageParams, CACHE_KEY_VERSION};
+
+const SRC: &str = "https://images.example/a.png";
+
+fn key(query: &str) -> String {
+    let params: ImageParams = parse_query(&format!("src={SRC}&{query}")).unwrap();
+    let validated = params.validate(&Limits::default()).unwrap();
+    generate_cache_key(&validated.source.unwrap(), &validated.plan)
+}
+
+fn plan() -> ProcessingPlan {
+    ProcessingPlan {
+        steps: Vec::new(),
+        width: None,
+        height: None,
+        fit: Fit::default(),
+        background: None,
+        quality: 75,
+        format: None,
+    }
+}
+
+#[test]
+fn test_equivalent_requests_share_a_key() {
+    let classes: &[&[&str]] = &[
+        &["f=jpeg", "f=jpg", "f=jpeg&q=75"],
+        &["w=400", "w=400&q=75", "q=75&w=400", "w=0400", "w=%2B400"],
+        &["w=400&q=80", "w=400&q=080"],
+        // Without a box to fit into, fit has no effect
+        &["h=300", "h=300&fit=cover", "h=300&fit=pad"],
+        &["w=400&h=300", "w=400&h=300&fit=contain"],
+        // Colors however written
+        &["f=jpeg&bg=fff", "f=jpeg&bg=FFFFFF", "f=jpeg&bg=%23ffffffff"],
+        // Without padding, only JPEG output uses the background
+        &["f=png", "f=png&bg=000"],
+        &["f=png&w=400&h=300", "f=png&w=400&h=300&bg=000"],
+        &["tx=crop:ar=4:5", "tx=crop:ar=8:10,g=center"],
+    ];
+
+    let mut keys = Vec::new();
+    for class in classes {
+        let first = key(class[0]);
+        for query in &class[1..] {
+            assert_eq!(key(query), first, "{query} and {}", class[0]);
+        }
+        keys.push(first);
+    }
+    // Classes are distinct images
+    let mut distinct = keys.clone();
+    distinct.sort();
+    distinct.dedup();
+    assert_eq!(distinct.len(), keys.len());
+}
+
+#[test]
+fn test_different_images_have_different_keys() {
+    let distinct = [
+        "",
+        "f=png",
+        "q=80",
+        "w=400",
+        "h=400",
+        "w=400&h=300",
+        "w=400&h=300&fit=cover",
+        "w=400&h=300&fit=pad",
+        "w=400&h=300&fit=pad&bg=fff",
+        // The source may be JPEG, flattened onto the background
+        "bg=fff",
+        "tx=flip:h",
+        // Values that ran together before the canonical form
+        "w=12&q=75",
+        "w=127&q=5",
+        "w=1&h=23",
+        "w=12&h=3",
+    ];
+    let mut keys: Vec<String> = distinct.iter().map(|query| key(query)).collect();
+    keys.sort();
+    keys.dedup();
+    assert_eq!(keys.len(), distinct.len());
+
+    assert_ne!(
+        generate_cache_key(SRC, &plan()),
+        generate_cache_key("https://images.example/b.png", &plan())
+    );
+}
+
+#[test]
+fn test_canonical_form() {
+    assert_eq!(
+        plan().canonical(),
+        "w=auto&h=auto&fit=contain&bg=none&q=75&f=source&tx="
+    );
+    let plan = ProcessingPlan {
+        width: Some(400),
+        height: Some(300),
+        fit: Fit::Pad,
+        background: Some([255, 0, 0, 255]),
+        format: Some(OutputFormat::Png),
+        quality: 80,
+        ..plan()
+    };
+    assert_eq!(
+        plan.canonical(),
+        "w=400&h=300&fit=pad&bg=ff0000ff&q=80&f=png&tx="
+    );
+}
+
+#[test]
+fn test_key_version() {
+    // Changing keys without bumping the version would leave entries of the
+    // old scheme to be served for the wrong requests
+    assert_eq!(CACHE_KEY_VERSION, 3);
+    assert_eq!(
+        generate_cache_key(SRC, &plan()),
+        "82af4a86e4334662ecc8b1f8e09d8a75ab4b2ceeda1cd580ee81568ae99bae14"
+    );
+}
@@ -0,0 +1,123 @@
+#![cfg(all(feature = "runtime", unix))]
+//! A cache write stuck on a slow disk, in a test binary of its own: cache
+//! writes go through temp files named after a counter of the process, which
+//! other tests would move on.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use img_optimizer::{
+    cache::{ImageCache, ImageMetadata},
+    image_processor::OutputFormat,
+    test_support::fixture_png,
+    CacheStatus, OptimizeOptions, Optimizer,
+};
+
+/// More than the cache writes of this binary before the slow one.
+const WRITES: u64 = 64;
+
+fn data_url(png: &[u8]) -> String {
+    use base64::{engine::general_purpose, Engine as _};
+    format!(
+        "data:image/png;base64,{}",
+        general_purpose::STANDARD.encode(png)
+    )
+}
+
+async fn loaded(cache: ImageCache) -> ImageCache {
+    for _ in 0..100 {
+        if cache.index_loaded() {
+            return cache;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("The cache index was not loaded");
+}
+
+/// A cache write stuck on a slow disk must not hold up lookups of other
+/// entries. The slow write opens a FIFO in place of the temp file of its
+/// metadata, which blocks until a reader shows up.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_slow_cache_write_does_not_block_lookups() {
+    let dir = tempfile::TempDir::new().unwrap();
+    // Indexed, so that looking the slow entry up doesn't open a FIFO
+    let cache = loaded(ImageCache::indexed(dir.path().to_path_buf())).await;
+    let optimizer = Arc::new(Optimizer::builder().cache(cache).build());
+    let options = |width| OptimizeOptions {
+        width: Some(width),
+        format: Some(OutputFormat::Png),
+        ..Default::default()
+    };
+
+    let hits: Vec<_> = (1..=8)
+        .map(|width| (data_url(&fixture_png(8, 8)), options(width)))
+        .collect();
+    for (src, options) in &hits {
+        optimizer.optimize(src, options).await.unwrap();
+    }
+
+    // Learn the key of a larger image, then make its next write block
+    let slow_src = data_url(&fixture_png(64, 64));
+    let key = optimizer
+        .optimize(&slow_src, &options(48))
+        .await
+        .unwrap()
+        .etag;
+    let mut cache = optimizer.state().cache.read().await.clone();
+    cache.delete(&key).await;
+    let fifos: Vec<PathBuf> = (0..WRITES)
+        .map(|write| {
+            dir.path()
+                .join(format!(".{key}.meta.{}-{write}.tmp", std::process::id()))
+        })
+        .collect();
+    let status = std::process::Command::new("mkfifo")
+        .args(&fifos)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let slow = tokio::spawn({
+        let optimizer = optimizer.clone();
+        let slow_src = slow_src.clone();
+        async move { optimizer.optimize(&slow_src, &options(48)).await }
+    });
+    // Give the slow request time to reach its write
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(!slow.is_finished());
+
+    let lookups = futures_util::future::join_all(
+        hits.iter()
+            .cycle()
+            .take(32)
+            .map(|(src, options)| optimizer.optimize(src, options)),
+    );
+    let results = tokio::time::timeout(Duration::from_secs(5), lookups).await;
+    let finished_early = slow.is_finished();
+
+    // Open every FIFO for reading and writing, which doesn't wait for a
+    // writer, so the slow write completes even when the lookups failed
+    let readers: Vec<File> = fifos
+        .iter()
+        .map(|fifo| File::options().read(true).write(true).open(fifo).unwrap())
+        .collect();
+    let image = slow.await.unwrap().unwrap();
+    // The FIFO written to was renamed over the metadata file
+    let written = fifos.iter().position(|fifo| !fifo.exists()).unwrap();
+    let mut contents = vec![0; 4096];
+    let len = (&readers[written]).read(&mut contents).unwrap();
+    let metadata: ImageMetadata = serde_json::from_slice(&contents[..len]).unwrap();
+    assert_eq!(
+        (metadata.width, metadata.height),
+        (image.width, image.height)
+    );
+
+    let results = results.expect("Lookups waited for the slow cache write");
+    for result in results {
+        assert_eq!(result.unwrap().cache_status, CacheStatus::Hit);
+    }
+    assert!(!finished_early);
+}
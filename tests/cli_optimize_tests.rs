@@ -0,0 +1,181 @@
+#![cfg(feature = "actix")]
+//! The `optimize` subcommand, run as a separate process.
+
+use assert_cmd::Command;
+use img_optimizer::image_processor::OutputFormat;
+use img_optimizer::test_support::fixture_png;
+use tempfile::TempDir;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+/// The binary, run from an empty directory without configuration.
+fn img_optimizer(dir: &TempDir) -> Command {
+    let mut cmd = Command::cargo_bin("img-optimizer").unwrap();
+    cmd.current_dir(dir.path()).env_clear();
+    cmd
+}
+
+/// A 64x32 PNG fixture in `dir`.
+fn write_fixture(dir: &TempDir) -> std::path::PathBuf {
+    let input = dir.path().join("photo.png");
+    std::fs::write(&input, fixture_png(64, 32)).unwrap();
+    input
+}
+
+#[test]
+fn test_optimize_file_prints_summary() {
+    let dir = TempDir::new().unwrap();
+    let input = write_fixture(&dir);
+    let output = dir.path().join("out.webp");
+
+    let assert = img_optimizer(&dir)
+        .args(["optimize", "photo.png", "--width", "16", "--quality", "70"])
+        .args(["--format", "webp", "--output", "out.webp"])
+        .assert()
+        .success();
+
+    let stderr = String::from_utf8(assert.get_output().stderr.clone()).unwrap();
+    let before = std::fs::metadata(&input).unwrap().len();
+    let after = std::fs::metadata(&output).unwrap().len();
+    assert!(
+        stderr.starts_with(&format!(
+            "photo.png: 64x32 -> 16x8 image/webp, {before} -> {after} bytes ("
+        )),
+        "{stderr}"
+    );
+
+    let img = image::load_from_memory(&std::fs::read(&output).unwrap()).unwrap();
+    assert_eq!((img.width(), img.height()), (16, 8));
+}
+
+#[test]
+fn test_optimize_to_stdout_with_json_summary() {
+    let dir = TempDir::new().unwrap();
+    write_fixture(&dir);
+
+    let assert = img_optimizer(&dir)
+        .args(["optimize", "photo.png", "-w", "32", "-f", "png", "-o", "-"])
+        .arg("--json")
+        .assert()
+        .success();
+    let output = assert.get_output();
+
+    let img = image::load_from_memory(&output.stdout).unwrap();
+    assert_eq!((img.width(), img.height()), (32, 16));
+
+    let summary: serde_json::Value = serde_json::from_slice(&output.stderr).unwrap();
+    assert_eq!(summary["input"], "photo.png");
+    assert_eq!(summary["output"], "-");
+    assert_eq!(summary["content_type"], "image/png");
+    assert_eq!(summary["width"], 32);
+    assert_eq!(summary["height"], 16);
+    assert_eq!(summary["original_width"], 64);
+    assert_eq!(summary["original_height"], 32);
+    assert_eq!(summary["bytes_after"], output.stdout.len());
+    let before = summary["bytes_before"].as_f64().unwrap();
+    let expected = 100.0 * (1.0 - output.stdout.len() as f64 / before);
+    assert!((summary["savings_percent"].as_f64().unwrap() - expected).abs() < 0.1);
+    assert!(summary.get("warnings").is_none());
+
+    let assert = img_optimizer(&dir)
+        .args(["optimize", "photo.png", "-w", "128", "-o", "-", "--json"])
+        .assert()
+        .success();
+    let summary: serde_json::Value = serde_json::from_slice(&assert.get_output().stderr).unwrap();
+    assert_eq!(summary["width"], 64);
+    assert_eq!(summary["warnings"], serde_json::json!(["upscale-refused"]));
+}
+
+#[test]
+fn test_optimize_output_is_the_same_across_runs() {
+    let dir = TempDir::new().unwrap();
+    write_fixture(&dir);
+
+    for format in OutputFormat::available() {
+        let run = || {
+            let assert = img_optimizer(&dir)
+                .args(["optimize", "photo.png", "-w", "48", "-q", "60"])
+                .args(["-f", format.name(), "-o", "-"])
+                .assert()
+                .success();
+            assert.get_output().stdout.clone()
+        };
+        let first = run();
+        assert!(!first.is_empty());
+        assert!(first == run(), "{format:?}");
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_optimize_url() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/photo.png"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_bytes(fixture_png(8, 8))
+                .insert_header("content-type", "image/png"),
+        )
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let dir = TempDir::new().unwrap();
+    let url = format!("{}/photo.png", mock_server.uri());
+    let mut cmd = img_optimizer(&dir);
+    cmd.args(["optimize", &url, "-f", "jpeg", "-o", "photo.jpg"]);
+    let output = tokio::task::spawn_blocking(move || cmd.output().unwrap())
+        .await
+        .unwrap();
+
+    assert!(output.status.success(), "{output:?}");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.starts_with(&format!("{url}: 8x8 -> 8x8 image/jpeg")),
+        "{stderr}"
+    );
+    let jpeg = std::fs::read(dir.path().join("photo.jpg")).unwrap();
+    assert_eq!(&jpeg[..3], &[0xFF, 0xD8, 0xFF]);
+}
+
+#[test]
+fn test_optimize_errors_carry_their_code() {
+    let dir = TempDir::new().unwrap();
+    std::fs::write(dir.path().join("notes.png"), "not an image").unwrap();
+
+    let assert = img_optimizer(&dir)
+        .args(["optimize", "notes.png", "-o", "out.png"])
+        .assert()
+        .failure();
+    let stderr = String::from_utf8(assert.get_output().stderr.clone()).unwrap();
+    assert!(stderr.starts_with("Error: IMG_003: "), "{stderr}");
+    assert!(!dir.path().join("out.png").exists());
+
+    let assert = img_optimizer(&dir)
+        .args([
+            "optimize",
+            "notes.png",
+            "-o",
+            "out.png",
+            "-f",
+            "gif",
+            "--json",
+        ])
+        .assert()
+        .failure();
+    let failure: serde_json::Value = serde_json::from_slice(&assert.get_output().stderr).unwrap();
+    assert_eq!(failure["error_code"], "IMG_004");
+    assert!(failure["error"].as_str().unwrap().starts_with("IMG_004: "));
+
+    // Errors outside the pipeline have no code
+    let assert = img_optimizer(&dir)
+        .args(["optimize", "missing.png", "-o", "out.png", "--json"])
+        .assert()
+        .failure();
+    let failure: serde_json::Value = serde_json::from_slice(&assert.get_output().stderr).unwrap();
+    assert!(failure.get("error_code").is_none());
+    assert!(failure["error"]
+        .as_str()
+        .unwrap()
+        .starts_with("Failed to read missing.png"));
+}
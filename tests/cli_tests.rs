@@ -0,0 +1,142 @@
+use clap::Parser;
+use img_optimizer::cli::{parse_rate, CacheCommand, Cli, Command};
+use img_optimizer::config::{parse_size, AppConfig};
+use std::time::Duration;
+
+#[test]
+fn test_flags_override_env_and_file() {
+    let mut config = AppConfig::from_toml(
+        r#"
+        [server]
+        port = 8080
+
+        [cache]
+        dir = "/from/file"
+        "#,
+    )
+    .unwrap();
+    config
+        .apply_env(|name| (name == "PORT").then(|| "9090".to_string()))
+        .unwrap();
+
+    let cli = Cli::try_parse_from([
+        "img-optimizer",
+        "--port",
+        "7070",
+        "--max-image-size",
+        "20MB",
+        "--cors-allowed-origin",
+        "https://a.example",
+        "--cors-allowed-origin",
+        "https://b.example",
+    ])
+    .unwrap();
+    cli.serve.apply(&mut config);
+    config.validate().unwrap();
+
+    assert_eq!(config.server.port, 7070);
+    assert_eq!(config.cache.dir.to_str(), Some("/from/file"));
+    assert_eq!(config.limits.max_image_size, 20 * 1024 * 1024);
+    assert_eq!(
+        config.cors.allowed_origins,
+        vec!["https://a.example", "https://b.example"]
+    );
+}
+
+#[test]
+fn test_subcommands() {
+    let cli = Cli::try_parse_from(["img-optimizer", "serve", "-p", "8080"]).unwrap();
+    assert!(matches!(cli.command, Some(Command::Serve(args)) if args.port == Some(8080)));
+
+    let cli =
+        Cli::try_parse_from(["img-optimizer", "cache", "--config", "c.toml", "clear"]).unwrap();
+    assert_eq!(cli.config.unwrap().to_str(), Some("c.toml"));
+    assert!(matches!(
+        cli.command,
+        Some(Command::Cache(args)) if matches!(args.command, CacheCommand::Clear)
+    ));
+
+    let cli =
+        Cli::try_parse_from(["img-optimizer", "optimize", "in.png", "-o", "out.webp"]).unwrap();
+    assert!(matches!(
+        cli.command,
+        Some(Command::Optimize(args)) if args.width.is_none() && !args.writes_to_stdout() && !args.json
+    ));
+    let cli =
+        Cli::try_parse_from(["img-optimizer", "optimize", "in.png", "-o", "-", "--json"]).unwrap();
+    assert!(matches!(
+        cli.command,
+        Some(Command::Optimize(args)) if args.writes_to_stdout() && args.json
+    ));
+
+    let cli =
+        Cli::try_parse_from(["img-optimizer", "check", "--timeout", "0.5", "--optimize"]).unwrap();
+    assert!(matches!(
+        cli.command,
+        Some(Command::Check(args))
+            if args.url.is_none() && args.optimize && args.timeout == Some(Duration::from_millis(500))
+    ));
+    assert!(Cli::try_parse_from(["img-optimizer", "check", "--timeout", "0"]).is_err());
+
+    let cli = Cli::try_parse_from(["img-optimizer", "pregen", "--manifest", "site.json"]).unwrap();
+    let Some(Command::Pregen(args)) = cli.command else {
+        panic!("expected pregen");
+    };
+    assert_eq!((args.concurrency, args.rate), (4, None));
+    assert_eq!(args.checkpoint().to_str(), Some("site.json.checkpoint"));
+    let cli = Cli::try_parse_from([
+        "img-optimizer",
+        "pregen",
+        "--manifest",
+        "site.json",
+        "--concurrency",
+        "8",
+        "--rate",
+        "10/s",
+        "--checkpoint",
+        "progress",
+    ])
+    .unwrap();
+    let Some(Command::Pregen(args)) = cli.command else {
+        panic!("expected pregen");
+    };
+    assert_eq!(args.concurrency, 8);
+    assert_eq!(args.rate, Some(Duration::from_millis(100)));
+    assert_eq!(args.checkpoint().to_str(), Some("progress"));
+    assert!(Cli::try_parse_from(["img-optimizer", "pregen"]).is_err());
+    assert!(Cli::try_parse_from([
+        "img-optimizer",
+        "pregen",
+        "--manifest",
+        "m",
+        "--concurrency",
+        "0"
+    ])
+    .is_err());
+
+    assert!(Cli::try_parse_from(["img-optimizer", "--max-image-size", "lots"]).is_err());
+    assert!(Cli::try_parse_from(["img-optimizer", "optimize", "in.png"]).is_err());
+}
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(parse_size("1048576"), Ok(1024 * 1024));
+    assert_eq!(parse_size("512KiB"), Ok(512 * 1024));
+    assert_eq!(parse_size("20MB"), Ok(20 * 1024 * 1024));
+    assert_eq!(parse_size(" 1 g "), Ok(1024 * 1024 * 1024));
+    assert!(parse_size("").is_err());
+    assert!(parse_size("MB").is_err());
+    assert!(parse_size("10TB").is_err());
+}
+
+#[test]
+fn test_parse_rate() {
+    assert_eq!(parse_rate("10/s"), Ok(Duration::from_millis(100)));
+    assert_eq!(parse_rate("4"), Ok(Duration::from_millis(250)));
+    assert_eq!(parse_rate("120/m"), Ok(Duration::from_millis(500)));
+    assert_eq!(parse_rate("3600/h"), Ok(Duration::from_secs(1)));
+    assert_eq!(parse_rate("0.5/s"), Ok(Duration::from_secs(2)));
+    for invalid in ["", "0/s", "-1/s", "10/d", "10/", "/s", "fast", "inf/s"] {
+        assert!(parse_rate(invalid).is_err(), "{invalid:?}");
+    }
+}
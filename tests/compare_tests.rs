@@ -0,0 +1,165 @@
+#![cfg(feature = "test-util")]
+//! PSNR and SSIM scoring, and `GET /img-optimizer/v1/img/compare` over the
+//! realistic fixtures.
+
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+use reqwest::Method;
+use std::io::Cursor;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use img_optimizer::{
+    compare::{psnr, score, ssim},
+    config::AppConfig,
+    test_support::{fixtures, TestApp},
+};
+
+fn gradient(width: u32, height: u32) -> RgbImage {
+    RgbImage::from_fn(width, height, |x, y| {
+        Rgb([(x * 255 / width) as u8, (y * 255 / height) as u8, 128])
+    })
+}
+
+fn png(image: &RgbImage) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    DynamicImage::ImageRgb8(image.clone())
+        .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+        .unwrap();
+    bytes
+}
+
+#[test]
+fn test_identical_images() {
+    let image = gradient(64, 48);
+    assert_eq!(psnr(&image, &image), None);
+    assert_eq!(ssim(&image, &image), 1.0);
+
+    let scores = score(&png(&image), &png(&image)).unwrap();
+    assert_eq!(scores.psnr, None);
+    assert_eq!(scores.ssim, 1.0);
+}
+
+#[test]
+fn test_scores_fall_with_distortion() {
+    let image = gradient(64, 48);
+    // Every channel off by 4: MSE 16
+    let shifted = RgbImage::from_fn(64, 48, |x, y| image.get_pixel(x, y).0.map(|c| c ^ 4).into());
+    let psnr_shifted = psnr(&image, &shifted).unwrap();
+    assert!((psnr_shifted - 36.09).abs() < 0.01, "{psnr_shifted}");
+
+    let inverted = RgbImage::from_fn(64, 48, |x, y| image.get_pixel(x, y).0.map(|c| !c).into());
+    assert!(psnr(&image, &inverted).unwrap() < psnr_shifted);
+    assert!(ssim(&image, &inverted) < ssim(&image, &shifted));
+    assert!(ssim(&image, &shifted) < 1.0);
+}
+
+#[test]
+fn test_tiny_images() {
+    // Narrower than a window
+    let image = gradient(3, 5);
+    assert_eq!(ssim(&image, &image), 1.0);
+    let scores = score(&png(&gradient(2, 2)), &png(&gradient(1, 1))).unwrap();
+    assert!(scores.ssim <= 1.0);
+}
+
+/// `GET /img-optimizer/v1/img/compare` of `src` with `params`, with the
+/// admin token.
+async fn compare(app: &TestApp, src: &str, params: &[(&str, &str)]) -> serde_json::Value {
+    let resp = app
+        .send(
+            app.request(Method::GET, "/img-optimizer/v1/img/compare")
+                .query(&[("src", src)])
+                .query(params)
+                .header("Authorization", "Bearer s3cret"),
+        )
+        .await;
+    assert_eq!(resp.status, 200, "{}", resp.text());
+    assert_eq!(resp.header("cache-control"), Some("no-store"));
+    resp.json()
+}
+
+#[actix_rt::test]
+async fn test_compare_endpoint() {
+    let mock_server = MockServer::start().await;
+    let photo = fixtures::photo_jpeg();
+    Mock::given(method("GET"))
+        .and(path("/photo.jpg"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_bytes(photo.bytes.clone())
+                .insert_header("content-type", photo.content_type),
+        )
+        .mount(&mock_server)
+        .await;
+    let src = format!("{}/photo.jpg", mock_server.uri());
+
+    // Admin only, and off without an admin token
+    let app = TestApp::spawn().await;
+    let resp = app
+        .send(
+            app.request(Method::GET, "/img-optimizer/v1/img/compare")
+                .query(&[("src", src.as_str())]),
+        )
+        .await;
+    assert_eq!(resp.status, 401);
+
+    let mut config = AppConfig::default();
+    config.storage.admin_token = Some("s3cret".to_string());
+    let app = TestApp::builder().config(config).spawn().await;
+    let resp = app
+        .send(
+            app.request(Method::GET, "/img-optimizer/v1/img/compare")
+                .query(&[("src", src.as_str())])
+                .header("Authorization", "Bearer wrong"),
+        )
+        .await;
+    assert_eq!(resp.status, 401);
+
+    let high = compare(&app, &src, &[("w", "320"), ("q", "90"), ("f", "jpeg")]).await;
+    let low = compare(&app, &src, &[("w", "320"), ("q", "20"), ("f", "jpeg")]).await;
+    assert_eq!(high["width"], 320);
+    assert_eq!(high["height"], 240);
+    assert_eq!(high["contentType"], "image/jpeg");
+    assert_eq!(high["quality"], 90);
+    assert_eq!(high["originalBytes"], photo.bytes.len());
+
+    let score_of = |report: &serde_json::Value, name: &str| report[name].as_f64().unwrap();
+    assert!((37.0..40.0).contains(&score_of(&high, "psnr")), "{high}");
+    assert!((0.93..0.97).contains(&score_of(&high, "ssim")), "{high}");
+    assert!((32.0..35.0).contains(&score_of(&low, "psnr")), "{low}");
+    assert!((0.88..0.93).contains(&score_of(&low, "ssim")), "{low}");
+    assert!(score_of(&low, "savingsPercent") > score_of(&high, "savingsPercent"));
+    assert!(
+        (85.0..90.0).contains(&score_of(&high, "savingsPercent")),
+        "{high}"
+    );
+
+    // Scored again from the cached output, the same
+    let again = compare(&app, &src, &[("w", "320"), ("q", "90"), ("f", "jpeg")]).await;
+    assert_eq!(again, high);
+}
+
+#[actix_rt::test]
+async fn test_lossless_output_matches_the_downscaled_original() {
+    let mock_server = MockServer::start().await;
+    let logo = fixtures::logo_png();
+    Mock::given(method("GET"))
+        .and(path("/logo.png"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_bytes(logo.bytes.clone())
+                .insert_header("content-type", logo.content_type),
+        )
+        .mount(&mock_server)
+        .await;
+
+    let mut config = AppConfig::default();
+    config.storage.admin_token = Some("s3cret".to_string());
+    let app = TestApp::builder().config(config).spawn().await;
+    let src = format!("{}/logo.png", mock_server.uri());
+
+    let report = compare(&app, &src, &[("w", "160"), ("f", "png")]).await;
+    assert_eq!(report["width"], 160);
+    assert_eq!(report["psnr"], serde_json::Value::Null);
+    assert_eq!(report["ssim"], 1.0);
+}
@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+
+use img_optimizer::config::{AlphaPolicy, AppConfig, CacheWriteMode, ErrorDetail, OriginProfile};
+
+fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+    let vars: HashMap<String, String> = vars
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    move |name| vars.get(name).cloned()
+}
+
+#[test]
+fn test_defaults() {
+    let config = AppConfig::default();
+    config.validate().unwrap();
+    assert_eq!(config.server.port, 3000);
+    assert_eq!(config.server.error_detail, ErrorDetail::Full);
+    assert_eq!(config.limits.max_width, 3840);
+    assert_eq!(config.limits.default_quality, 75);
+    assert_eq!(
+        config.limits.effective_quality(),
+        [("jpeg", 75), ("png", 75), ("source", 75), ("webp", 75)].into()
+    );
+    assert_eq!(config.limits.max_image_size, 50 * 1024 * 1024);
+    assert_eq!(config.limits.max_bundle_size, 100 * 1024 * 1024);
+    assert_eq!(config.limits.max_output_pixels, 3840 * 2160);
+    assert_eq!(config.limits.max_src_length, 8192);
+    assert_eq!(config.limits.max_query_length, 16384);
+    assert!(config.cors.allows_any_origin());
+    assert!(config.cache.index);
+    assert_eq!(config.cache.write_mode, CacheWriteMode::Sync);
+}
+
+#[test]
+fn test_file_then_env_overrides() {
+    let mut config = AppConfig::from_toml(
+        r#"
+        [server]
+        port = 8080
+
+        [limits]
+        max_width = 2048
+        default_quality = 80
+
+        [limits.format_quality]
+        jpeg = 82
+        webp = 85
+
+        [cors]
+        allowed_origins = ["https://example.com"]
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.server.port, 8080);
+    assert_eq!(config.limits.max_width, 2048);
+    assert!(!config.cors.allows_any_origin());
+
+    config
+        .apply_env(env(&[
+            ("PORT", "9090"),
+            ("DEFAULT_QUALITY", "60"),
+            ("DEFAULT_QUALITY_WEBP", "70"),
+            ("CACHE_DIR", "/var/cache/img"),
+            ("CACHE_INDEX", "false"),
+            ("CACHE_WRITE_MODE", "deferred"),
+            ("PROCESSING_SMALL_MAX_CONCURRENT", "0"),
+            ("PROCESSING_WORKERS", "2"),
+            ("ERROR_DETAIL", "minimal"),
+            ("PUBLIC_URL", "https://cdn.example.com"),
+            ("MAX_BUNDLE_SIZE", "20MB"),
+            ("MAX_OUTPUT_PIXELS", "4000000"),
+            ("MAX_SRC_LENGTH", "2048"),
+            ("MAX_QUERY_LENGTH", "4096"),
+            ("ALPHA_POLICY", "Reject"),
+            ("JOB_TTL_SECS", "600"),
+            ("AUDIT_DIR", "/var/log/img-optimizer"),
+            ("AUDIT_RETENTION_DAYS", "30"),
+            ("IPFS_GATEWAY", "https://cloudflare-ipfs.com/ipfs/"),
+            ("IPFS_FALLBACK_GATEWAY", "https://ipfs.io/ipfs/"),
+            ("FETCH_MAX_PER_HOST", "4"),
+            ("FETCH_MIN_INTERVAL_MS", "100"),
+            ("FETCH_HOST_LIMITS", "Slow.Example=1, cdn.example=16"),
+            ("FETCH_POOL_IDLE_TIMEOUT", "30"),
+            ("FETCH_ALLOWED_HOSTS", "Images.Example, *.cdn.example"),
+            ("FETCH_ALLOW_HTTPS_DOWNGRADE", "false"),
+            ("FETCH_MAX_REDIRECTS", "3"),
+        ]))
+        .unwrap();
+    config.validate().unwrap();
+
+    assert_eq!(config.server.port, 9090);
+    assert_eq!(config.limits.max_width, 2048);
+    assert_eq!(config.limits.default_quality, 60);
+    // Formats without a quality of their own follow the global default
+    assert_eq!(
+        config.limits.effective_quality(),
+        [("jpeg", 82), ("png", 60), ("source", 60), ("webp", 70)].into()
+    );
+    assert_eq!(config.cache.dir.to_str(), Some("/var/cache/img"));
+    assert!(!config.cache.index);
+    assert_eq!(config.cache.write_mode, CacheWriteMode::Deferred);
+    assert_eq!(config.processing.small_max_concurrent, 0);
+    assert_eq!(config.processing.workers, 2);
+    assert_eq!(config.server.error_detail, ErrorDetail::Minimal);
+    assert_eq!(
+        config.server.public_url.as_deref(),
+        Some("https://cdn.example.com")
+    );
+    assert_eq!(config.limits.max_bundle_size, 20 * 1024 * 1024);
+    assert_eq!(config.limits.max_output_pixels, 4_000_000);
+    assert_eq!(config.limits.max_src_length, 2048);
+    assert_eq!(config.limits.max_query_length, 4096);
+    assert_eq!(config.limits.alpha_policy, AlphaPolicy::Reject);
+    assert_eq!(config.jobs.ttl_secs, 600);
+    assert_eq!(
+        config.audit.dir.as_deref(),
+        Some(std::path::Path::new("/var/log/img-optimizer"))
+    );
+    assert_eq!(config.audit.retention_days, 30);
+    assert_eq!(
+        config.ipfs.gateways().collect::<Vec<_>>(),
+        ["https://cloudflare-ipfs.com/ipfs/", "https://ipfs.io/ipfs/"]
+    );
+    assert_eq!(
+        (config.fetch.max_per_host, config.fetch.min_interval_ms),
+        (4, 100)
+    );
+    assert_eq!(config.fetch.hosts["slow.example"].max_concurrent, Some(1));
+    assert_eq!(config.fetch.hosts["cdn.example"].max_concurrent, Some(16));
+    assert_eq!(config.fetch.pool_idle_timeout_secs, 30);
+    assert_eq!(
+        config.fetch.allowed_hosts,
+        ["images.example", "*.cdn.example"]
+    );
+    assert!(!config.fetch.allow_https_downgrade);
+    assert_eq!(config.fetch.max_redirects, 3);
+}
+
+#[test]
+fn test_host_limits_from_toml() {
+    let config = AppConfig::from_toml(
+        r#"
+        [fetch]
+        max_per_host = 2
+
+        [fetch.hosts."slow.example"]
+        max_concurrent = 1
+        min_interval_ms = 250
+        "#,
+    )
+    .unwrap();
+    config.validate().unwrap();
+    assert_eq!(config.fetch.max_per_host, 2);
+    let slow = &config.fetch.hosts["slow.example"];
+    assert_eq!(
+        (slow.max_concurrent, slow.min_interval_ms),
+        (Some(1), Some(250))
+    );
+
+    assert!(AppConfig::from_toml("[fetch.hosts.\"a.example\"]\nmax = 1\n").is_err());
+}
+
+#[test]
+fn test_origin_profiles_from_toml() {
+    let config = AppConfig::from_toml(
+        r#"
+        [origin."*"]
+        retries = 1
+
+        [origin."*.partner.example"]
+        timeout_secs = 5
+        max_concurrent = 2
+        headers = { "User-Agent" = "partner-bot/1.0" }
+
+        [origin."legacy.example"]
+        allowed = false
+        "#,
+    )
+    .unwrap();
+    config.validate().unwrap();
+    assert_eq!(config.origin.len(), 3);
+    assert_eq!(config.origin["*"].retries, Some(1));
+    let partner = &config.origin["*.partner.example"];
+    assert_eq!(
+        (partner.timeout_secs, partner.max_concurrent),
+        (Some(5), Some(2))
+    );
+    assert_eq!(partner.headers["User-Agent"], "partner-bot/1.0");
+    assert_eq!(config.origin["legacy.example"].allowed, Some(false));
+
+    assert!(AppConfig::from_toml("[origin.\"a.example\"]\nretry = 1\n").is_err());
+}
+
+#[test]
+fn test_invalid_values_are_rejected() {
+    let mut config = AppConfig::default();
+    let err = config
+        .apply_env(env(&[("PORT", "not-a-port")]))
+        .unwrap_err();
+    assert!(err.to_string().contains("PORT"));
+
+    let err = config
+        .apply_env(env(&[("ERROR_DETAIL", "verbose")]))
+        .unwrap_err();
+    assert!(err.to_string().contains("ERROR_DETAIL"));
+
+    let err = config
+        .apply_env(env(&[("CACHE_WRITE_MODE", "later")]))
+        .unwrap_err();
+    assert!(err.to_string().contains("CACHE_WRITE_MODE"));
+
+    let err = config
+        .apply_env(env(&[("ALPHA_POLICY", "keep")]))
+        .unwrap_err();
+    assert!(err.to_string().contains("ALPHA_POLICY"));
+
+    let config = AppConfig::from_toml("[limits]\ndefault_quality = 0\n").unwrap();
+    let err = config.validate().unwrap_err();
+    assert!(err.to_string().contains("default_quality"));
+
+    let config = AppConfig::from_toml("[limits.format_quality]\nwebp = 101\n").unwrap();
+    let err = config.validate().unwrap_err();
+    assert!(err.to_string().contains("limits.format_quality.webp"));
+
+    let err = AppConfig::default()
+        .apply_env(env(&[("DEFAULT_QUALITY_JPEG", "high")]))
+        .unwrap_err();
+    assert!(err.to_string().contains("DEFAULT_QUALITY_JPEG"));
+
+    let config = AppConfig::from_toml("[limits]\nmax_output_pixels = 0\n").unwrap();
+    let err = config.validate().unwrap_err();
+    assert!(err.to_string().contains("max_output_pixels"));
+
+    // The query carries the source, so it can't be shorter
+    let config = AppConfig::from_toml("[limits]\nmax_query_length = 1024\n").unwrap();
+    let err = config.validate().unwrap_err();
+    assert!(err.to_string().contains("max_query_length"));
+
+    let config = AppConfig::from_toml("[processing]\nshed_status = 500\n").unwrap();
+    let err = config.validate().unwrap_err();
+    assert!(err.to_string().contains("shed_status"));
+
+    let config = AppConfig::from_toml("[processing]\nworkers = 0\n").unwrap();
+    let err = config.validate().unwrap_err();
+    assert!(err.to_string().contains("workers"));
+
+    let config =
+        AppConfig::from_toml("[server]\npublic_url = \"ftp://cdn.example.com\"\n").unwrap();
+    let err = config.validate().unwrap_err();
+    assert!(err.to_string().contains("public_url"));
+
+    let err = AppConfig::default()
+        .apply_env(env(&[("FETCH_HOST_LIMITS", "slow.example")]))
+        .unwrap_err();
+    assert!(err.to_string().contains("FETCH_HOST_LIMITS"));
+
+    let config = AppConfig::from_toml("[fetch]\nmax_per_host = 0\n").unwrap();
+    let err = config.validate().unwrap_err();
+    assert!(err.to_string().contains("max_per_host"));
+
+    let config = AppConfig::from_toml("[fetch.hosts.\"a.example\"]\nmax_concurrent = 0\n").unwrap();
+    let err = config.validate().unwrap_err();
+    assert!(err.to_string().contains("max_concurrent"));
+
+    for pattern in [
+        "https://a.example",
+        "a.example:8080",
+        "*example.com",
+        "a.*.example",
+        "",
+    ] {
+        let config = AppConfig::from_toml(&format!("[origin.\"{pattern}\"]\n")).unwrap();
+        let err = config.validate().unwrap_err();
+        assert!(
+            err.to_string().contains("must be a host"),
+            "{pattern}: {err}"
+        );
+    }
+    let config = AppConfig::from_toml("[origin.\"a.example\"]\ntimeout_secs = 0\n").unwrap();
+    let err = config.validate().unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("origin.\"a.example\".timeout_secs"));
+    let config =
+        AppConfig::from_toml("[origin.\"a.example\"]\nheaders = { \"Bad Name\" = \"x\" }\n")
+            .unwrap();
+    let err = config.validate().unwrap_err();
+    assert!(err.to_string().contains("not a valid header name"));
+
+    let config = AppConfig::from_toml("[ipfs]\ngateway = \"ipfs.io/ipfs/\"\n").unwrap();
+    let err = config.validate().unwrap_err();
+    assert!(err.to_string().contains("ipfs.gateway"));
+
+    let config =
+        AppConfig::from_toml("[ipfs]\nfallback_gateway = \"https://ipfs.io/ipfs/\"\n").unwrap();
+    let err = config.validate().unwrap_err();
+    assert!(err.to_string().contains("requires ipfs.gateway"));
+
+    assert!(AppConfig::from_toml("[limits]\nunknown_knob = 1\n").is_err());
+}
+
+#[test]
+fn test_print_config_redacts_secrets() {
+    let mut config = AppConfig::default();
+    config
+        .apply_env(env(&[("API_KEYS", "website:super-secret")]))
+        .unwrap();
+
+    config.origin.insert(
+        "partner.example".to_string(),
+        OriginProfile {
+            headers: [(
+                "Authorization".to_string(),
+                "Bearer origin-secret".to_string(),
+            )]
+            .into(),
+            ..OriginProfile::default()
+        },
+    );
+
+    let printed = config.redacted().to_toml().unwrap();
+    assert!(!printed.contains("super-secret"));
+    assert!(!printed.contains("origin-secret"));
+    assert!(printed.contains("Authorization = \"<redacted>\""));
+    assert!(printed.contains("api_keys = \"<redacted>\""));
+    assert!(printed.contains("port = 3000"));
+}
+
+#[test]
+fn test_reload_takes_reloadable_settings_only() {
+    let current = AppConfig::default();
+    let mut new = AppConfig::default();
+    new.fetch.allowed_hosts = vec!["images.example.com".to_string()];
+    new.limits.max_width = 2048;
+    new.server.port = 8080;
+    new.auth.api_keys = Some("website:new-secret".to_string());
+
+    let (merged, changes) = current.reloaded(&new).unwrap();
+    assert_eq!(merged.fetch.allowed_hosts, ["images.example.com"]);
+    assert_eq!(merged.limits.max_width, 2048);
+    assert_eq!(merged.server.port, 3000);
+    assert_eq!(merged.auth.api_keys, None);
+
+    let summary: Vec<String> = changes
+        .iter()
+        .map(|change| format!("{change} ({})", change.reloadable))
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            "auth.api_keys: null -> \"<redacted>\" (false)",
+            "fetch.allowed_hosts: [] -> [\"images.example.com\"] (true)",
+            "limits.max_width: 3840 -> 2048 (true)",
+            "server.port: 3000 -> 8080 (false)",
+        ]
+    );
+
+    let (_, changes) = current.reloaded(&current).unwrap();
+    assert!(changes.is_empty());
+}
@@ -0,0 +1,538 @@
+#![cfg(feature = "runtime")]
+//! `HttpFetcher`: what every implementation must do, checked against the
+//! reqwest one, and the pipeline going through whichever is configured.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_util::future::BoxFuture;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use wiremock::matchers::{header, method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use img_optimizer::{
+    cache::ImageCache,
+    config::{AppConfig, FetchConfig, OriginProfile},
+    error::{AppError, AppResult},
+    fetch::{check_redirect, HttpFetcher, ReqwestFetcher},
+    image_processor::OutputFormat,
+    origin::OriginPolicies,
+    test_support::fixture_png,
+    FetchContext, OptimizeOptions, Optimizer,
+};
+
+/// Checks the behavior documented on [`HttpFetcher`] against a mock origin.
+async fn check_contract(fetcher: &dyn HttpFetcher) {
+    let origin = MockServer::start().await;
+    let mut config = AppConfig::default();
+    config.limits.max_image_size = 1024;
+    config.fetch.user_agent = "contract-test".to_string();
+    let mut context = FetchContext::default();
+    context
+        .headers
+        .insert("x-request-id", "req-1".parse().unwrap());
+
+    Mock::given(method("GET"))
+        .and(path("/ok.png"))
+        .and(header("user-agent", "contract-test"))
+        .and(header("x-request-id", "req-1"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(fixture_png(1, 1)))
+        .mount(&origin)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/large.png"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(vec![0; 4096]))
+        .mount(&origin)
+        .await;
+
+    let url = |route: &str| format!("{}{route}", origin.uri());
+
+    let body = fetcher
+        .fetch(&url("/ok.png"), &config, &context)
+        .await
+        .unwrap();
+    assert_eq!(body, fixture_png(1, 1));
+
+    let err = fetcher
+        .fetch(&url("/missing.png"), &config, &context)
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err, AppError::SourceNotFound { status: 404, .. }),
+        "{err:?}"
+    );
+
+    let err = fetcher
+        .fetch(&url("/large.png"), &config, &context)
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err, AppError::SourceTooLargeBytes { limit: 1024, .. }),
+        "{err:?}"
+    );
+}
+
+#[tokio::test]
+async fn test_reqwest_fetcher_contract() {
+    check_contract(&ReqwestFetcher::default()).await;
+}
+
+#[tokio::test]
+async fn test_error_pages_served_with_200_are_refused() {
+    let origin = MockServer::start().await;
+    let page = format!(
+        "<!DOCTYPE html><html><body>Please sign in to the Wi-Fi{}</body></html>",
+        " ".repeat(64 * 1024)
+    );
+    Mock::given(method("GET"))
+        .and(path("/a.png"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_raw(page.into_bytes(), "text/html; charset=utf-8"),
+        )
+        .mount(&origin)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/b.png"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(b"{}".to_vec(), "image/png"))
+        .mount(&origin)
+        .await;
+
+    let fetcher = ReqwestFetcher::default();
+    let (config, context) = (AppConfig::default(), FetchContext::default());
+    let err = fetcher
+        .fetch(&format!("{}/a.png", origin.uri()), &config, &context)
+        .await
+        .unwrap_err();
+    assert_eq!(err.error_code(), "IMG_002");
+    assert!(
+        err.to_string().contains(
+            "the origin returned non-image content (HTML, Content-Type: text/html; charset=utf-8)"
+        ),
+        "{err}"
+    );
+
+    let err = fetcher
+        .fetch(&format!("{}/b.png", origin.uri()), &config, &context)
+        .await
+        .unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("non-image content (JSON, Content-Type: image/png)"),
+        "{err}"
+    );
+}
+
+/// Serves one request with a chunked body of zeros, without a length, that
+/// never ends: only the client hanging up stops it.
+async fn endless_origin() -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut request = [0; 4096];
+        let _ = socket.read(&mut request).await;
+        let head =
+            "HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nTransfer-Encoding: chunked\r\n\r\n";
+        socket.write_all(head.as_bytes()).await.unwrap();
+        let chunk = format!("200\r\n{}\r\n", "\0".repeat(512));
+        while socket.write_all(chunk.as_bytes()).await.is_ok() {}
+    });
+    format!("http://{addr}/a.png")
+}
+
+#[tokio::test]
+async fn test_sources_over_the_limit_are_aborted() {
+    let origin = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/at.png"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(vec![0; 1024]))
+        .mount(&origin)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/over.png"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(vec![0; 1025]))
+        .mount(&origin)
+        .await;
+    let fetcher = ReqwestFetcher::default();
+    let mut config = AppConfig::default();
+    config.limits.max_image_size = 1024;
+    let context = FetchContext::default();
+
+    let body = fetcher
+        .fetch(&format!("{}/at.png", origin.uri()), &config, &context)
+        .await
+        .unwrap();
+    assert_eq!(body.len(), 1024);
+
+    // Refused from its Content-Length
+    let err = fetcher
+        .fetch(&format!("{}/over.png", origin.uri()), &config, &context)
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(
+            err,
+            AppError::SourceTooLargeBytes {
+                limit: 1024,
+                actual: 1025
+            }
+        ),
+        "{err:?}"
+    );
+    assert!(
+        err.to_string()
+            .contains("At least 1025 bytes, over the 1024 byte limit"),
+        "{err}"
+    );
+
+    // Without one, dropped with the first chunk going over the limit
+    let url = endless_origin().await;
+    let download = fetcher.fetch(&url, &config, &context);
+    let err = tokio::time::timeout(Duration::from_secs(5), download)
+        .await
+        .expect("the download was not aborted")
+        .unwrap_err();
+    match err {
+        AppError::SourceTooLargeBytes { limit, actual } => {
+            assert_eq!(limit, 1024);
+            assert!((1025..=1024 + 512).contains(&actual), "{actual}");
+        }
+        err => panic!("{err:?}"),
+    }
+}
+
+fn redirect(to: &str) -> ResponseTemplate {
+    ResponseTemplate::new(302).insert_header("location", to)
+}
+
+/// A chain going from one host to another and back, each hop checked.
+#[tokio::test]
+async fn test_redirects_are_checked_at_every_hop() {
+    let first = MockServer::start().await;
+    let second = MockServer::start().await;
+    let first_url = first.uri();
+    // The same machine under another host name
+    let second_url = second.uri().replace("127.0.0.1", "localhost");
+    Mock::given(method("GET"))
+        .and(path("/start.png"))
+        .respond_with(redirect(&format!("{second_url}/next.png")))
+        .mount(&first)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/next.png"))
+        .respond_with(redirect(&format!("{first_url}/final.png")))
+        .mount(&second)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/final.png"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(fixture_png(1, 1)))
+        .mount(&first)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/ftp.png"))
+        .respond_with(redirect("ftp://10.0.0.1/a.png"))
+        .mount(&first)
+        .await;
+
+    let fetcher = ReqwestFetcher::default();
+    let context = FetchContext::default();
+    let fetch = |allowed_hosts: &[&str], max_redirects: usize, route: &str| {
+        let mut config = AppConfig::default();
+        config.fetch.allowed_hosts = allowed_hosts.iter().map(|h| h.to_string()).collect();
+        config.fetch.max_redirects = max_redirects;
+        let (fetcher, context) = (&fetcher, &context);
+        let url = format!("{first_url}{route}");
+        async move { fetcher.fetch(&url, &config, context).await }
+    };
+
+    let body = fetch(&["127.0.0.1", "localhost"], 10, "/start.png")
+        .await
+        .unwrap();
+    assert_eq!(body, fixture_png(1, 1));
+    let body = fetch(&[], 10, "/start.png").await.unwrap();
+    assert_eq!(body, fixture_png(1, 1));
+    let requests = second.received_requests().await.unwrap().len();
+
+    // Refused before following, naming the hop and the rule but not the target
+    let err = fetch(&["127.0.0.1"], 10, "/start.png").await.unwrap_err();
+    assert_eq!(err.error_code(), "SEC_006");
+    assert_eq!(
+        err.to_string(),
+        "SEC_006: Redirect refused - Redirect 1 of the source leads to a host that is not allowed"
+    );
+    assert_eq!(second.received_requests().await.unwrap().len(), requests);
+
+    let err = fetch(&["localhost"], 10, "/start.png").await.unwrap_err();
+    assert_eq!(err.error_code(), "SEC_004");
+
+    let err = fetch(&[], 1, "/start.png").await.unwrap_err();
+    assert!(
+        matches!(&err, AppError::RedirectRefused { hop: 2, rule } if rule == "goes over FETCH_MAX_REDIRECTS (1)"),
+        "{err:?}"
+    );
+
+    let err = fetch(&[], 10, "/ftp.png").await.unwrap_err();
+    assert!(err
+        .to_string()
+        .ends_with("Redirect 1 of the source leads to a 'ftp:' URL"));
+    assert!(!err.to_string().contains("10.0.0.1"));
+}
+
+#[test]
+fn test_redirect_policy() {
+    let url = |url: &str| url::Url::parse(url).unwrap();
+    let origins = |fetch: &FetchConfig| {
+        OriginPolicies::from_config(&AppConfig {
+            fetch: fetch.clone(),
+            ..AppConfig::default()
+        })
+    };
+    let secure = url("https://images.example/a.png");
+    let config = FetchConfig::default();
+    let all = origins(&config);
+    check_redirect(
+        &config,
+        &all,
+        1,
+        &secure,
+        &url("http://images.example/a.png"),
+    )
+    .unwrap();
+
+    let config = FetchConfig {
+        allow_https_downgrade: false,
+        allowed_hosts: vec!["images.example".to_string(), "*.cdn.example".to_string()],
+        ..FetchConfig::default()
+    };
+    let listed = origins(&config);
+    let err = check_redirect(
+        &config,
+        &listed,
+        3,
+        &secure,
+        &url("http://images.example/a.png"),
+    )
+    .unwrap_err();
+    assert!(
+        err.to_string()
+            .ends_with("Redirect 3 of the source downgrades from https to http"),
+        "{err}"
+    );
+    // Plain http sources may stay on http
+    let plain = url("http://images.example/a.png");
+    check_redirect(
+        &config,
+        &listed,
+        1,
+        &plain,
+        &url("http://images.example/b.png"),
+    )
+    .unwrap();
+
+    for allowed in [
+        "https://IMAGES.example/a.png",
+        "https://eu.cdn.example/a.png",
+    ] {
+        check_redirect(&config, &listed, 1, &secure, &url(allowed)).unwrap();
+    }
+    for refused in [
+        "https://cdn.example/a.png",
+        "https://evilcdn.example/a.png",
+        "https://images.example.evil/a.png",
+        "https://10.0.0.1/a.png",
+    ] {
+        let err = check_redirect(&config, &listed, 1, &secure, &url(refused)).unwrap_err();
+        assert_eq!(err.error_code(), "SEC_006", "{refused}");
+    }
+
+    // Origin profiles take precedence over the allowlist
+    let profiled = OriginPolicies::from_config(&AppConfig {
+        fetch: config.clone(),
+        origin: [
+            ("cdn.example", Some(true)),
+            ("*.cdn.example", Some(false)),
+            ("static.cdn.example", None),
+        ]
+        .into_iter()
+        .map(|(pattern, allowed)| {
+            let profile = OriginProfile {
+                allowed,
+                ..OriginProfile::default()
+            };
+            (pattern.to_string(), profile)
+        })
+        .collect(),
+        ..AppConfig::default()
+    });
+    check_redirect(
+        &config,
+        &profiled,
+        1,
+        &secure,
+        &url("https://cdn.example/a.png"),
+    )
+    .unwrap();
+    check_redirect(
+        &config,
+        &profiled,
+        1,
+        &secure,
+        &url("https://static.cdn.example/a.png"),
+    )
+    .unwrap();
+    let err = check_redirect(
+        &config,
+        &profiled,
+        1,
+        &secure,
+        &url("https://eu.cdn.example/a.png"),
+    )
+    .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "SEC_006: Redirect refused - Redirect 1 of the source leads to a host that is not allowed"
+    );
+}
+
+/// Config with one origin profile, for the host of a mock origin.
+fn with_profile(origin: &MockServer, profile: OriginProfile) -> AppConfig {
+    let host = url::Url::parse(&origin.uri()).unwrap();
+    let mut config = AppConfig::default();
+    config
+        .origin
+        .insert(host.host_str().unwrap().to_string(), profile);
+    config
+}
+
+#[tokio::test]
+async fn test_origin_profile_retries_transient_failures() {
+    let origin = MockServer::start().await;
+    let fetcher = ReqwestFetcher::default();
+    let context = FetchContext::default();
+    let url = format!("{}/a.png", origin.uri());
+    let flaky = || async {
+        origin.reset().await;
+        Mock::given(method("GET"))
+            .and(path("/a.png"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .mount(&origin)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/a.png"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(fixture_png(1, 1)))
+            .mount(&origin)
+            .await;
+    };
+    let profile = |retries| OriginProfile {
+        retries: Some(retries),
+        retry_backoff_ms: Some(1),
+        ..OriginProfile::default()
+    };
+
+    flaky().await;
+    let config = with_profile(&origin, profile(0));
+    let err = fetcher.fetch(&url, &config, &context).await.unwrap_err();
+    assert_eq!(err.error_code(), "IMG_010");
+    assert!(err.is_transient());
+
+    flaky().await;
+    let config = with_profile(&origin, profile(1));
+    let body = fetcher.fetch(&url, &config, &context).await.unwrap();
+    assert_eq!(body, fixture_png(1, 1));
+    assert_eq!(origin.received_requests().await.unwrap().len(), 2);
+
+    // Refusals of the origin are not retried
+    origin.reset().await;
+    let config = with_profile(&origin, profile(3));
+    let err = fetcher.fetch(&url, &config, &context).await.unwrap_err();
+    assert_eq!(err.error_code(), "IMG_008");
+    assert_eq!(origin.received_requests().await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_origin_profile_headers_and_allowlist() {
+    let origin = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/a.png"))
+        .and(header("user-agent", "partner-bot/1.0"))
+        .and(header("x-partner-token", "s3cret"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(fixture_png(1, 1)))
+        .mount(&origin)
+        .await;
+    let fetcher = ReqwestFetcher::default();
+    let context = FetchContext::default();
+    let url = format!("{}/a.png", origin.uri());
+
+    let mut config = with_profile(
+        &origin,
+        OriginProfile {
+            headers: [
+                ("User-Agent", "partner-bot/1.0"),
+                ("X-Partner-Token", "s3cret"),
+            ]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+            allowed: Some(true),
+            ..OriginProfile::default()
+        },
+    );
+    // Allowed by its profile alone
+    config.fetch.allowed_hosts = vec!["images.example".to_string()];
+    let body = fetcher.fetch(&url, &config, &context).await.unwrap();
+    assert_eq!(body, fixture_png(1, 1));
+
+    let config = with_profile(
+        &origin,
+        OriginProfile {
+            allowed: Some(false),
+            ..OriginProfile::default()
+        },
+    );
+    let err = fetcher.fetch(&url, &config, &context).await.unwrap_err();
+    assert_eq!(err.error_code(), "SEC_004");
+    assert_eq!(origin.received_requests().await.unwrap().len(), 1);
+}
+
+/// Serves the same image for every URL, recording them.
+struct StaticFetcher {
+    image: Vec<u8>,
+    urls: Arc<Mutex<Vec<String>>>,
+}
+
+impl HttpFetcher for StaticFetcher {
+    fn fetch<'a>(
+        &'a self,
+        url: &'a str,
+        _config: &'a AppConfig,
+        _context: &'a FetchContext,
+    ) -> BoxFuture<'a, AppResult<Vec<u8>>> {
+        self.urls.lock().unwrap().push(url.to_string());
+        Box::pin(async move { Ok(self.image.clone()) })
+    }
+}
+
+#[tokio::test]
+async fn test_pipeline_uses_the_configured_fetcher() {
+    let urls = Arc::new(Mutex::new(Vec::new()));
+    let optimizer = Optimizer::builder()
+        .cache(ImageCache::in_memory())
+        .fetcher(StaticFetcher {
+            image: fixture_png(8, 4),
+            urls: urls.clone(),
+        })
+        .build();
+    let options = OptimizeOptions {
+        width: Some(4),
+        format: Some(OutputFormat::Png),
+        ..Default::default()
+    };
+
+    let image = optimizer
+        .optimize("https://images.example/a.png", &options)
+        .await
+        .unwrap();
+    assert_eq!((image.width, image.height), (4, 2));
+    assert_eq!(*urls.lock().unwrap(), ["https://images.example/a.png"]);
+}
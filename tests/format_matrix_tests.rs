@@ -0,0 +1,101 @@
+#![cfg(feature = "test-util")]
+//! Every input format through the HTTP pipeline, to every output format
+//! this build encodes, at several widths and qualities: the regression net
+//! of the encoders.
+
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use img_optimizer::{
+    image_processor::OutputFormat,
+    test_support::{fixtures, TestApp},
+};
+
+const WIDTHS: [u32; 2] = [160, 320];
+const QUALITIES: [u8; 3] = [40, 75, 95];
+
+/// Whether `quality` changes what `format` encodes in this build.
+fn is_lossy(format: OutputFormat) -> bool {
+    match format {
+        OutputFormat::Jpeg => true,
+        OutputFormat::Png => false,
+        OutputFormat::WebP => cfg!(feature = "webp-native"),
+    }
+}
+
+#[actix_rt::test]
+async fn test_format_matrix() {
+    let mock_server = MockServer::start().await;
+    let app = TestApp::spawn().await;
+
+    for fixture in fixtures::all() {
+        let route = format!("/{}", fixture.name);
+        Mock::given(method("GET"))
+            .and(path(route.as_str()))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_bytes(fixture.bytes.clone())
+                    .insert_header("content-type", fixture.content_type),
+            )
+            .mount(&mock_server)
+            .await;
+        let src = format!("{}{route}", mock_server.uri());
+
+        for format in OutputFormat::ALL.into_iter().filter(|f| f.is_available()) {
+            for width in WIDTHS {
+                let mut sizes = Vec::new();
+                for quality in QUALITIES {
+                    let case = format!(
+                        "{} to {} at w={width} q={quality}",
+                        fixture.name,
+                        format.name()
+                    );
+                    let params = [
+                        ("f", format.name()),
+                        ("w", &width.to_string()),
+                        ("q", &quality.to_string()),
+                    ];
+                    let resp = app.optimize(&src, &params).await;
+
+                    assert_eq!(
+                        resp.status,
+                        200,
+                        "{case}: {}",
+                        String::from_utf8_lossy(&resp.body)
+                    );
+                    assert_eq!(
+                        resp.header("content-type"),
+                        Some(format.content_type()),
+                        "{case}"
+                    );
+                    let decoded = image::load_from_memory(&resp.body)
+                        .unwrap_or_else(|e| panic!("{case}: not decodable: {e}"));
+                    assert_eq!(
+                        image::guess_format(&resp.body).ok(),
+                        image::ImageFormat::from_mime_type(format.content_type()),
+                        "{case}"
+                    );
+                    assert_eq!(
+                        (decoded.width(), decoded.height()),
+                        (width, fixture.height * width / fixture.width),
+                        "{case}"
+                    );
+                    sizes.push(resp.body.len());
+                }
+
+                let case = format!("{} to {} at w={width}", fixture.name, format.name());
+                if is_lossy(format) {
+                    assert!(
+                        sizes.windows(2).all(|pair| pair[0] < pair[1]),
+                        "{case}: sizes {sizes:?} do not grow with quality {QUALITIES:?}"
+                    );
+                } else {
+                    assert!(
+                        sizes.windows(2).all(|pair| pair[0] == pair[1]),
+                        "{case}: quality changed lossless sizes {sizes:?}"
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,238 @@
+#![cfg(feature = "runtime")]
+//! Per-host fetch limits: overrides, slots and minimum intervals of the
+//! limiter, and the pipeline queueing fetches to a slow origin.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures_util::future::BoxFuture;
+use wiremock::matchers::method;
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use img_optimizer::cache::ImageCache;
+use img_optimizer::config::{AppConfig, FetchConfig, HostLimitConfig};
+use img_optimizer::error::{AppError, AppResult};
+use img_optimizer::fetch::HttpFetcher;
+use img_optimizer::host_limits::{HostLimit, HostLimiter};
+use img_optimizer::metrics::Metrics;
+use img_optimizer::test_support::fixture_png;
+use img_optimizer::{FetchContext, OptimizeOptions, Optimizer};
+
+fn config(max_per_host: usize) -> AppConfig {
+    let mut config = AppConfig::default();
+    config.fetch.max_per_host = max_per_host;
+    // Processing slots aren't what these tests are about
+    config.processing.max_concurrent = 8;
+    config.processing.small_max_concurrent = 0;
+    config
+}
+
+fn width(w: u32) -> OptimizeOptions {
+    OptimizeOptions {
+        width: Some(w),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_limits_from_config() {
+    let config = FetchConfig {
+        max_per_host: 4,
+        min_interval_ms: 50,
+        hosts: BTreeMap::from([
+            (
+                "Slow.Example".to_string(),
+                HostLimitConfig {
+                    max_concurrent: Some(1),
+                    min_interval_ms: Some(500),
+                },
+            ),
+            (
+                "busy.example".to_string(),
+                HostLimitConfig {
+                    max_concurrent: Some(16),
+                    ..Default::default()
+                },
+            ),
+        ]),
+        ..Default::default()
+    };
+    let limiter = HostLimiter::from_config(&config);
+
+    let limit = |max_concurrent, ms| HostLimit {
+        max_concurrent,
+        min_interval: Duration::from_millis(ms),
+    };
+    assert_eq!(limiter.limit("images.example"), limit(4, 50));
+    assert_eq!(limiter.limit("slow.example"), limit(1, 500));
+    assert_eq!(limiter.limit("SLOW.example"), limit(1, 500));
+    // Unset values are the defaults
+    assert_eq!(limiter.limit("busy.example"), limit(16, 50));
+}
+
+#[tokio::test]
+async fn test_slots_per_host() {
+    let limiter = HostLimiter::new(
+        HostLimit {
+            max_concurrent: 2,
+            min_interval: Duration::ZERO,
+        },
+        Default::default(),
+    );
+    let wait = Duration::from_millis(50);
+
+    let first = limiter.acquire("a.example", wait).await.unwrap();
+    let _second = limiter.acquire("A.example", wait).await.unwrap();
+    assert!(limiter.acquire("a.example", wait).await.is_none());
+    // Other hosts have slots of their own
+    let _other = limiter.acquire("b.example", wait).await.unwrap();
+    assert_eq!(
+        limiter.in_flight(),
+        [("a.example".to_string(), 2), ("b.example".to_string(), 1)]
+    );
+
+    drop(first);
+    assert!(limiter.acquire("a.example", wait).await.is_some());
+    assert_eq!(limiter.in_flight()[0], ("a.example".to_string(), 1));
+}
+
+#[tokio::test]
+async fn test_min_interval() {
+    let limiter = HostLimiter::new(
+        HostLimit {
+            max_concurrent: 3,
+            min_interval: Duration::from_millis(100),
+        },
+        Default::default(),
+    );
+    let wait = Duration::from_secs(5);
+
+    let start = Instant::now();
+    let permits =
+        futures_util::future::join_all((0..3).map(|_| limiter.acquire("a.example", wait))).await;
+    assert!(permits.iter().all(Option::is_some));
+    assert!(start.elapsed() >= Duration::from_millis(200));
+
+    // Waiting for the interval counts towards the wait
+    let late = limiter
+        .acquire("a.example", Duration::from_millis(10))
+        .await;
+    assert!(late.is_none());
+}
+
+#[tokio::test]
+async fn test_fetches_to_a_slow_origin_are_queued() {
+    let origin = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_bytes(fixture_png(16, 8))
+                .set_delay(Duration::from_millis(300)),
+        )
+        .expect(3)
+        .mount(&origin)
+        .await;
+    let optimizer = Arc::new(
+        Optimizer::builder()
+            .config(config(1))
+            .cache(ImageCache::in_memory())
+            .build(),
+    );
+    let src = format!("{}/a.png", origin.uri());
+
+    let start = Instant::now();
+    let requests: Vec<_> = [4, 8, 12]
+        .into_iter()
+        .map(|w| {
+            let (optimizer, src) = (optimizer.clone(), src.clone());
+            tokio::spawn(async move { optimizer.optimize(&src, &width(w)).await })
+        })
+        .collect();
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    // One fetch at a time, the others waiting for its slot
+    assert_eq!(
+        optimizer.state().hosts().in_flight(),
+        [("127.0.0.1".to_string(), 1)]
+    );
+
+    for request in requests {
+        request.await.unwrap().unwrap();
+    }
+    assert!(start.elapsed() >= Duration::from_millis(900));
+    assert!(optimizer.state().hosts().in_flight().is_empty());
+}
+
+/// Takes `delay` to serve any image, without a timeout of its own.
+struct SlowFetcher {
+    delay: Duration,
+}
+
+impl HttpFetcher for SlowFetcher {
+    fn fetch<'a>(
+        &'a self,
+        _url: &'a str,
+        _config: &'a AppConfig,
+        _context: &'a FetchContext,
+    ) -> BoxFuture<'a, AppResult<Vec<u8>>> {
+        Box::pin(async move {
+            tokio::time::sleep(self.delay).await;
+            Ok(fixture_png(16, 8))
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_waiting_too_long_for_a_slot_times_out() {
+    let mut config = config(1);
+    config.fetch.timeout_secs = 1;
+    let optimizer = Arc::new(
+        Optimizer::builder()
+            .config(config)
+            .cache(ImageCache::in_memory())
+            .fetcher(SlowFetcher {
+                delay: Duration::from_millis(1600),
+            })
+            .build(),
+    );
+
+    let first = {
+        let optimizer = optimizer.clone();
+        tokio::spawn(async move {
+            optimizer
+                .optimize("https://slow.example/a.png", &width(4))
+                .await
+        })
+    };
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let err = optimizer
+        .optimize("https://slow.example/a.png", &width(8))
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(
+            &err,
+            AppError::ImageFetchTimeout { phase, budget_ms: 1000, .. } if phase == "queue"
+        ),
+        "{err:?}"
+    );
+    assert_eq!(err.error_code(), "IMG_011");
+
+    // The fetch holding the slot completes
+    first.await.unwrap().unwrap();
+}
+
+#[test]
+fn test_in_flight_gauge() {
+    let metrics = Metrics::new();
+    metrics.set_fetch_in_flight(&[("a.example".to_string(), 2), ("b.example".to_string(), 1)]);
+    let rendered = metrics.render();
+    assert!(rendered.contains("img_optimizer_fetch_in_flight{host=\"a.example\"} 2"));
+    assert!(rendered.contains("img_optimizer_fetch_in_flight{host=\"b.example\"} 1"));
+
+    // Hosts done fetching are dropped
+    metrics.set_fetch_in_flight(&[("b.example".to_string(), 3)]);
+    let rendered = metrics.render();
+    assert!(!rendered.contains("a.example"));
+    assert!(rendered.contains("img_optimizer_fetch_in_flight{host=\"b.example\"} 3"));
+}
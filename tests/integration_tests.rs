@@ -1,447 +1,3355 @@
-use actix_web::{test, web, App};
-use std::path::PathBuf;
-use std::sync::Arc;
+#![cfg(feature = "test-util")]
+//! The HTTP service end to end, through `TestApp`: every route and
+//! middleware, served on a local port. Needs `test-util`: run with
+//! `cargo test-all`.
+// Tests of WebP output are skipped in builds without the `webp` feature
+#![cfg_attr(not(feature = "webp"), allow(unused_imports))]
+
+use reqwest::Method;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
 use tempfile::TempDir;
-use tokio::sync::RwLock;
 use wiremock::matchers::{method, path};
 use wiremock::{Mock, MockServer, ResponseTemplate};
 
 use img_optimizer::{
-    cache::ImageCache, direct_image_handler, health_check, optimize_image_handler, AppState,
+    auth::ApiKeys,
+    config::{AlphaPolicy, AppConfig, CacheWriteMode, ErrorDetail},
+    error::AppError,
+    image_processor::OutputFormat,
+    imgix,
+    logging::access_log,
+    path_options, routes,
+    test_support::{fixture_jpeg, fixture_png, TestApp},
+    Optimizer, IMAGE_ID_REGEX,
 };
 
-// Create a small test image - using a valid 1x1 PNG
-fn create_test_png() -> Vec<u8> {
-    // This is a valid 1x1 transparent PNG image
-    let base64_png = "iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mNkYPhfDwAChwGA60e6kgAAAABJRU5ErkJggg==";
-    use base64::{engine::general_purpose, Engine as _};
-    general_purpose::STANDARD.decode(base64_png).unwrap()
-}
-
-fn create_app_state(cache_dir: PathBuf) -> AppState {
-    AppState {
-        cache: Arc::new(RwLock::new(ImageCache::new(cache_dir))),
-        client: reqwest::Client::new(),
-    }
+/// Serves `body` as `image/png` at `route`.
+async fn mount_png(mock_server: &MockServer, route: &str, body: Vec<u8>) {
+    Mock::given(method("GET"))
+        .and(path(route))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_bytes(body)
+                .insert_header("content-type", "image/png"),
+        )
+        .mount(mock_server)
+        .await;
 }
 
 #[actix_rt::test]
 async fn test_health_check() {
-    let mut app =
-        test::init_service(App::new().route("/health", web::get().to(health_check))).await;
+    let app = TestApp::spawn().await;
 
-    let req = test::TestRequest::get().uri("/health").to_request();
-    let resp = test::call_service(&mut app, req).await;
+    let resp = app.get("/health").await;
 
-    assert!(resp.status().is_success());
-    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert!(resp.status.is_success());
+    let body = resp.json();
     assert_eq!(body["status"], "ok");
     assert_eq!(body["service"], "img-optimizer");
 }
 
 #[actix_rt::test]
 async fn test_missing_src_parameter() {
-    let temp_dir = TempDir::new().unwrap();
-    let app_state = create_app_state(temp_dir.path().to_path_buf());
-
-    let mut app = test::init_service(App::new().app_data(web::Data::new(app_state)).route(
-        "/img-optimizer/v1/img",
-        web::get().to(optimize_image_handler),
-    ))
-    .await;
+    let app = TestApp::spawn().await;
 
-    let req = test::TestRequest::get()
-        .uri("/img-optimizer/v1/img")
-        .to_request();
-    let resp = test::call_service(&mut app, req).await;
+    let resp = app.get("/img-optimizer/v1/img").await;
 
-    assert_eq!(resp.status(), 400);
-    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(resp.status, 400);
+    let body = resp.json();
     assert_eq!(body["title"], "Bad Request");
     assert_eq!(body["errorCode"], "VAL_003");
 }
 
 #[actix_rt::test]
 async fn test_invalid_url() {
-    let temp_dir = TempDir::new().unwrap();
-    let app_state = create_app_state(temp_dir.path().to_path_buf());
-
-    let mut app = test::init_service(App::new().app_data(web::Data::new(app_state)).route(
-        "/img-optimizer/v1/img",
-        web::get().to(optimize_image_handler),
-    ))
-    .await;
+    let app = TestApp::spawn().await;
 
-    let req = test::TestRequest::get()
-        .uri("/img-optimizer/v1/img?src=not-a-url")
-        .to_request();
-    let resp = test::call_service(&mut app, req).await;
+    let resp = app.optimize("not-a-url", &[]).await;
 
-    assert_eq!(resp.status(), 400);
-    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(resp.status, 400);
+    let body = resp.json();
     assert_eq!(body["title"], "Bad Request");
     assert_eq!(body["errorCode"], "IMG_001");
 }
 
 #[actix_rt::test]
 async fn test_svg_redirect() {
-    let temp_dir = TempDir::new().unwrap();
-    let app_state = create_app_state(temp_dir.path().to_path_buf());
-
-    let mut app = test::init_service(App::new().app_data(web::Data::new(app_state)).route(
-        "/img-optimizer/v1/img",
-        web::get().to(optimize_image_handler),
-    ))
-    .await;
+    let app = TestApp::spawn().await;
 
     let svg_url = "https://example.com/test.svg";
-    let req = test::TestRequest::get()
-        .uri(&format!("/img-optimizer/v1/img?src={}", svg_url))
-        .to_request();
-    let resp = test::call_service(&mut app, req).await;
+    let resp = app.optimize(svg_url, &[]).await;
 
-    assert_eq!(resp.status(), 302);
-    assert_eq!(
-        resp.headers().get("location").unwrap().to_str().unwrap(),
-        svg_url
-    );
+    assert_eq!(resp.status, 302);
+    assert_eq!(resp.header("location"), Some(svg_url));
 }
 
 #[actix_rt::test]
-async fn test_image_optimization_with_mock_server() {
+async fn test_svg_redirect_ignores_the_query_string() {
     let mock_server = MockServer::start().await;
-    let test_image = create_test_png();
+    let app = TestApp::spawn().await;
+
+    for svg_url in [
+        format!("{}/logo.svg?v=3", mock_server.uri()),
+        format!("{}/LOGO.SVG", mock_server.uri()),
+    ] {
+        let resp = app.optimize(&svg_url, &[("w", "100")]).await;
+        assert_eq!(resp.status, 302, "{svg_url}");
+        assert_eq!(resp.header("location"), Some(svg_url.as_str()));
+    }
+    // Redirected without being fetched
+    assert!(mock_server.received_requests().await.unwrap().is_empty());
+}
 
-    Mock::given(method("GET"))
-        .and(path("/test-image.png"))
-        .respond_with(
-            ResponseTemplate::new(200)
-                .set_body_bytes(test_image.clone())
-                .insert_header("content-type", "image/png"),
-        )
-        .mount(&mock_server)
+#[actix_rt::test]
+async fn test_mislabeled_svg_is_rejected() {
+    let mock_server = MockServer::start().await;
+    let svg = r#"<?xml version="1.0"?><svg xmlns="http://www.w3.org/2000/svg"/>"#;
+    mount_png(&mock_server, "/sneaky.png", svg.as_bytes().to_vec()).await;
+    let app = TestApp::spawn().await;
+
+    let resp = app
+        .optimize(&format!("{}/sneaky.png", mock_server.uri()), &[])
         .await;
+    assert_eq!(resp.status, 400);
+    let body = resp.json();
+    assert_eq!(body["errorCode"], "IMG_004");
+    assert!(
+        body["detail"]
+            .as_str()
+            .unwrap()
+            .contains("sneaky.png serves an SVG image"),
+        "{body}"
+    );
+}
 
-    let temp_dir = TempDir::new().unwrap();
-    let app_state = create_app_state(temp_dir.path().to_path_buf());
+#[actix_rt::test]
+async fn test_image_optimization_with_mock_server() {
+    let mock_server = MockServer::start().await;
+    mount_png(&mock_server, "/test-image.png", fixture_png(64, 48)).await;
 
-    let mut app = test::init_service(App::new().app_data(web::Data::new(app_state)).route(
-        "/img-optimizer/v1/img",
-        web::get().to(optimize_image_handler),
-    ))
-    .await;
+    let app = TestApp::spawn().await;
 
     let image_url = format!("{}/test-image.png", &mock_server.uri());
-    let req = test::TestRequest::get()
-        .uri(&format!("/img-optimizer/v1/img?src={}", &image_url))
-        .to_request();
-    let resp = test::call_service(&mut app, req).await;
+    let resp = app.optimize(&image_url, &[]).await;
 
-    assert!(resp.status().is_success());
-    assert_eq!(
-        resp.headers()
-            .get("content-type")
-            .unwrap()
-            .to_str()
-            .unwrap(),
-        "image/png"
-    );
+    assert!(resp.status.is_success());
+    assert_eq!(resp.header("content-type"), Some("image/png"));
+    let img = image::load_from_memory(&resp.body).unwrap();
+    assert_eq!((img.width(), img.height()), (64, 48));
 }
 
 #[actix_rt::test]
 async fn test_image_format_conversion() {
     let mock_server = MockServer::start().await;
-    let test_image = create_test_png();
-
+    mount_png(&mock_server, "/convert-test.png", fixture_png(64, 48)).await;
     Mock::given(method("GET"))
-        .and(path("/convert-test.png"))
+        .and(path("/photo.jpg"))
         .respond_with(
             ResponseTemplate::new(200)
-                .set_body_bytes(test_image)
-                .insert_header("content-type", "image/png"),
+                .set_body_bytes(fixture_jpeg(64, 48))
+                .insert_header("content-type", "image/jpeg"),
         )
         .mount(&mock_server)
         .await;
 
-    let temp_dir = TempDir::new().unwrap();
-    let app_state = create_app_state(temp_dir.path().to_path_buf());
-
-    let mut app = test::init_service(App::new().app_data(web::Data::new(app_state)).route(
-        "/img-optimizer/v1/img",
-        web::get().to(optimize_image_handler),
-    ))
-    .await;
+    let app = TestApp::spawn().await;
 
     // Convert to JPEG
     let image_url = format!("{}/convert-test.png", &mock_server.uri());
-    let req = test::TestRequest::get()
-        .uri(&format!("/img-optimizer/v1/img?src={}&f=jpeg", &image_url))
-        .to_request();
-    let resp = test::call_service(&mut app, req).await;
+    let resp = app.optimize(&image_url, &[("f", "jpeg")]).await;
+    assert!(resp.status.is_success());
+    assert_eq!(resp.header("content-type"), Some("image/jpeg"));
+
+    // And back to PNG
+    let image_url = format!("{}/photo.jpg", &mock_server.uri());
+    let resp = app.optimize(&image_url, &[("f", "png"), ("w", "32")]).await;
+    assert!(resp.status.is_success());
+    assert_eq!(resp.header("content-type"), Some("image/png"));
+    let img = image::load_from_memory(&resp.body).unwrap();
+    assert_eq!((img.width(), img.height()), (32, 24));
+}
+
+#[actix_rt::test]
+async fn test_warnings_header() {
+    let mock_server = MockServer::start().await;
+    mount_png(&mock_server, "/logo.png", fixture_png(16, 8)).await;
+    let logo = format!("{}/logo.png", mock_server.uri());
+    let app = TestApp::spawn().await;
+
+    let resp = app.optimize(&logo, &[("w", "8"), ("f", "png")]).await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("x-optimizer-warnings"), None);
+
+    // Too large a box, on a miss and then on a hit
+    for _ in 0..2 {
+        let resp = app.optimize(&logo, &[("w", "64"), ("f", "png")]).await;
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.header("x-image-width"), Some("16"));
+        assert_eq!(resp.header("x-optimizer-warnings"), Some("upscale-refused"));
+    }
+    // Covering a box is refused when one side of it is too large
+    let resp = app
+        .optimize(&logo, &[("w", "32"), ("h", "4"), ("fit", "cover")])
+        .await;
+    assert_eq!(resp.header("x-optimizer-warnings"), Some("upscale-refused"));
 
-    assert!(resp.status().is_success());
+    // Transparency lost under the warn policy, with the other warnings
+    let resp = app
+        .optimize(&logo, &[("w", "64"), ("f", "jpeg"), ("alpha", "warn")])
+        .await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(
+        resp.header("x-optimizer-warnings"),
+        Some("alpha-flattened,upscale-refused")
+    );
+    let resp = app
+        .optimize(&logo, &[("f", "jpeg"), ("alpha", "warn")])
+        .await;
+    assert_eq!(resp.header("x-optimizer-warnings"), Some("alpha-flattened"));
+    let resp = app.optimize(&logo, &[("f", "jpeg")]).await;
+    assert_eq!(resp.header("x-optimizer-warnings"), None);
 }
 
 #[actix_rt::test]
-async fn test_direct_image_id_format() {
-    let mut app = test::init_service(App::new().route(
-        "/img-optimizer/v1/img/{image_id}",
-        web::get().to(direct_image_handler),
-    ))
+async fn test_alpha_policy() {
+    let mock_server = MockServer::start().await;
+    // Translucent from left to right
+    mount_png(&mock_server, "/logo.png", fixture_png(16, 8)).await;
+    mount_png(&mock_server, "/opaque.png", {
+        let opaque = image::load_from_memory(&fixture_jpeg(16, 8)).unwrap();
+        let mut bytes = Vec::new();
+        opaque
+            .write_to(
+                &mut std::io::Cursor::new(&mut bytes),
+                image::ImageFormat::Png,
+            )
+            .unwrap();
+        bytes
+    })
     .await;
+    let logo = format!("{}/logo.png", mock_server.uri());
+    let opaque = format!("{}/opaque.png", mock_server.uri());
+    let spawn = |policy| {
+        let mut config = AppConfig::default();
+        config.limits.alpha_policy = policy;
+        TestApp::builder().config(config).spawn()
+    };
+
+    // flatten, the default, drops the transparency silently
+    let app = TestApp::spawn().await;
+    let resp = app.optimize(&logo, &[("f", "jpeg")]).await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("content-type"), Some("image/jpeg"));
+    assert_eq!(resp.header("x-alpha-flattened"), None);
+
+    // warn says so, only when transparency was actually lost
+    let app = spawn(AlphaPolicy::Warn).await;
+    let resp = app.optimize(&logo, &[("f", "jpeg")]).await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("content-type"), Some("image/jpeg"));
+    assert_eq!(resp.header("x-alpha-flattened"), Some("true"));
+    for params in [
+        &[("f", "png")][..],
+        &[("f", "jpeg"), ("bg", "fff")],
+        &[("f", "jpeg"), ("alpha", "flatten")],
+    ] {
+        let resp = app.optimize(&logo, params).await;
+        assert_eq!(resp.status, 200, "{params:?}");
+        assert_eq!(resp.header("x-alpha-flattened"), None, "{params:?}");
+    }
+    let resp = app.optimize(&opaque, &[("f", "jpeg")]).await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("x-alpha-flattened"), None);
+
+    // reject refuses, cached output included, unless bg says what to do
+    let app = spawn(AlphaPolicy::Reject).await;
+    let resp = app
+        .optimize(&logo, &[("f", "jpeg"), ("alpha", "flatten")])
+        .await;
+    assert_eq!(resp.status, 200);
+    let resp = app.optimize(&logo, &[("f", "jpeg")]).await;
+    assert_eq!(resp.status, 400);
+    let body = resp.json();
+    assert_eq!(body["errorCode"], "IMG_014");
+    assert!(body["howToFix"].as_str().unwrap().contains("f=webp"));
+    let resp = app.optimize(&logo, &[("f", "jpeg"), ("bg", "fff")]).await;
+    assert_eq!(resp.status, 200);
+    let resp = app.optimize(&opaque, &[("f", "jpeg")]).await;
+    assert_eq!(resp.status, 200);
+    let resp = app
+        .optimize(&logo, &[("f", "jpeg"), ("alpha", "warn")])
+        .await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("x-alpha-flattened"), Some("true"));
 
-    // Test valid format (32 hex chars + extension)
-    let req = test::TestRequest::get()
-        .uri("/img-optimizer/v1/img/f86d5d7ae700c37dd8db36806074f231.png")
-        .to_request();
-    let resp = test::call_service(&mut app, req).await;
+    let resp = app
+        .optimize(&logo, &[("f", "jpeg"), ("alpha", "keep")])
+        .await;
+    assert_eq!(resp.status, 400);
+    assert_eq!(resp.json()["errorCode"], "VAL_007");
+}
+
+#[actix_rt::test]
+async fn test_direct_image_id_format() {
+    let app = TestApp::spawn().await;
+
+    // Test valid format (32 hex chars + extension) that isn't stored
+    let resp = app
+        .get("/img-optimizer/v1/img/f86d5d7ae700c37dd8db36806074f231.png")
+        .await;
 
-    assert_eq!(resp.status(), 422); // Expected as we don't have internal storage
+    assert_eq!(resp.status, 404);
+    assert_eq!(resp.json()["errorCode"], "IMG_007");
 
     // Test invalid format
-    let req = test::TestRequest::get()
-        .uri("/img-optimizer/v1/img/invalid-id.png")
-        .to_request();
-    let resp = test::call_service(&mut app, req).await;
+    let resp = app.get("/img-optimizer/v1/img/invalid-id.png").await;
 
-    assert_eq!(resp.status(), 400);
+    assert_eq!(resp.status, 400);
 }
 
 #[actix_rt::test]
-async fn test_max_width_constraint() {
+async fn test_long_sources_are_refused_before_any_fetch() {
     let mock_server = MockServer::start().await;
-    let test_image = create_test_png();
-
     Mock::given(method("GET"))
-        .and(path("/large-image.png"))
-        .respond_with(
-            ResponseTemplate::new(200)
-                .set_body_bytes(test_image)
-                .insert_header("content-type", "image/png"),
-        )
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(fixture_png(8, 8)))
+        .expect(0)
         .mount(&mock_server)
         .await;
+    let app = TestApp::spawn().await;
+    let src = |len: usize| format!("{}/{}.png", mock_server.uri(), "a".repeat(len));
+
+    // As long as clients can send: URLs are capped at 64KB
+    let start = std::time::Instant::now();
+    let resp = app.optimize(&src(60 * 1024), &[("w", "100")]).await;
+    assert_eq!(resp.status, 400);
+    let body = resp.json();
+    assert_eq!(body["errorCode"], "VAL_011");
+    let detail = body["detail"].as_str().unwrap();
+    assert!(
+        detail.starts_with("VAL_011: Parameter too long - query is ")
+            && detail.ends_with(" bytes, over the 16384 byte limit"),
+        "{detail}"
+    );
+    // Nothing sent back echoes the whole value
+    assert!(resp.body.len() < 2048, "{}", resp.body.len());
+    assert!(start.elapsed() < std::time::Duration::from_secs(1));
 
-    let temp_dir = TempDir::new().unwrap();
-    let app_state = create_app_state(temp_dir.path().to_path_buf());
+    let long = src(10 * 1024);
+    let resp = app.optimize(&long, &[]).await;
+    assert_eq!(resp.status, 400);
+    assert_eq!(
+        resp.json()["detail"],
+        format!(
+            "VAL_011: Parameter too long - src is {} bytes, over the 8192 byte limit",
+            long.len()
+        )
+    );
+}
 
-    let mut app = test::init_service(App::new().app_data(web::Data::new(app_state)).route(
-        "/img-optimizer/v1/img",
-        web::get().to(optimize_image_handler),
-    ))
-    .await;
+#[actix_rt::test]
+async fn test_max_width_constraint() {
+    let mock_server = MockServer::start().await;
+    mount_png(&mock_server, "/large-image.png", fixture_png(8, 8)).await;
+
+    let app = TestApp::spawn().await;
 
     // Test width above MAX_WIDTH (3840)
     let image_url = format!("{}/large-image.png", &mock_server.uri());
-    let req = test::TestRequest::get()
-        .uri(&format!("/img-optimizer/v1/img?src={}&w=5000", &image_url))
-        .to_request();
-    let resp = test::call_service(&mut app, req).await;
+    let resp = app.optimize(&image_url, &[("w", "5000")]).await;
 
-    assert_eq!(resp.status(), 400); // Width validation should fail
-    let body: serde_json::Value = test::read_body_json(resp).await;
-    assert_eq!(body["errorCode"], "VAL_001");
+    assert_eq!(resp.status, 400); // Width validation should fail
+    assert_eq!(resp.json()["errorCode"], "VAL_001");
+}
+
+#[actix_rt::test]
+async fn test_invalid_parameters_are_reported_together() {
+    let app = TestApp::spawn().await;
+
+    let resp = app
+        .optimize(
+            "https://example.com/a.png",
+            &[("w", "0"), ("q", "500"), ("fit", "stretch")],
+        )
+        .await;
+
+    assert_eq!(resp.status, 400);
+    let body = resp.json();
+    assert_eq!(body["errorCode"], "VAL_009");
+    assert_eq!(
+        body["detail"],
+        "VAL_009: Invalid parameters - 3 parameters are invalid: w, fit, q"
+    );
+    let errors = body["errors"].as_array().unwrap();
+    assert_eq!(errors.len(), 3);
+    assert_eq!(errors[0]["param"], "w");
+    assert_eq!(errors[0]["value"], "0");
+    assert_eq!(errors[0]["constraint"], "between 1 and 3840");
+    assert_eq!(errors[0]["errorCode"], "VAL_001");
+    assert_eq!(errors[1]["param"], "fit");
+    assert_eq!(errors[1]["value"], "stretch");
+    assert_eq!(errors[1]["errorCode"], "VAL_007");
+    assert_eq!(errors[2]["param"], "q");
+    assert_eq!(errors[2]["value"], "500");
+    assert_eq!(errors[2]["constraint"], "between 1 and 100");
+    assert_eq!(
+        errors[2]["detail"],
+        "VAL_002: Invalid quality - Quality must be between 1 and 100, got 500"
+    );
+
+    // A single invalid parameter keeps the single-error shape
+    let body = app
+        .optimize("https://example.com/a.png", &[("q", "500")])
+        .await
+        .json();
+    assert_eq!(body["errorCode"], "VAL_002");
+    assert!(body.get("errors").is_none());
+
+    // Values that are not numbers are reported with the others
+    let resp = app
+        .optimize("https://example.com/a.png", &[("w", "abc"), ("f", "gif")])
+        .await;
+    assert_eq!(resp.status, 400);
+    let body = resp.json();
+    assert_eq!(body["errorCode"], "VAL_009");
+    let errors = body["errors"].as_array().unwrap();
+    assert_eq!(errors[0]["param"], "w");
+    assert_eq!(errors[0]["value"], "abc");
+    assert_eq!(errors[0]["errorCode"], "VAL_007");
+    assert_eq!(errors[1]["param"], "f");
+    assert_eq!(errors[1]["errorCode"], "IMG_004");
 }
 
 #[actix_rt::test]
 async fn test_cache_functionality() {
     let mock_server = MockServer::start().await;
-    let test_image = create_test_png();
 
     Mock::given(method("GET"))
         .and(path("/cached-image.png"))
         .respond_with(
             ResponseTemplate::new(200)
-                .set_body_bytes(test_image)
+                .set_body_bytes(fixture_png(200, 100))
                 .insert_header("content-type", "image/png"),
         )
         .expect(1) // Should only be called once due to caching
         .mount(&mock_server)
         .await;
 
-    let temp_dir = TempDir::new().unwrap();
-    let app_state = create_app_state(temp_dir.path().to_path_buf());
-
-    let mut app = test::init_service(App::new().app_data(web::Data::new(app_state)).route(
-        "/img-optimizer/v1/img",
-        web::get().to(optimize_image_handler),
-    ))
-    .await;
+    let app = TestApp::spawn().await;
 
     let image_url = format!("{}/cached-image.png", &mock_server.uri());
 
     // First request
-    let req = test::TestRequest::get()
-        .uri(&format!("/img-optimizer/v1/img?src={}&w=100", &image_url))
-        .to_request();
-    let resp1 = test::call_service(&mut app, req).await;
-    assert!(resp1.status().is_success());
-    let body1 = test::read_body(resp1).await;
+    let resp1 = app.optimize(&image_url, &[("w", "100")]).await;
+    assert!(resp1.status.is_success());
 
     // Second request (should hit cache)
-    let req = test::TestRequest::get()
-        .uri(&format!("/img-optimizer/v1/img?src={}&w=100", &image_url))
-        .to_request();
-    let resp2 = test::call_service(&mut app, req).await;
-    assert!(resp2.status().is_success());
-    let body2 = test::read_body(resp2).await;
+    let resp2 = app.optimize(&image_url, &[("w", "100")]).await;
+    assert!(resp2.status.is_success());
 
     // Bodies should be identical
-    assert_eq!(body1, body2);
+    assert_eq!(resp1.body, resp2.body);
 }
 
 #[actix_rt::test]
-async fn test_quality_parameter_bounds() {
+async fn test_image_dimension_headers() {
     let mock_server = MockServer::start().await;
-    let test_image = create_test_png();
-
     Mock::given(method("GET"))
-        .and(path("/quality-test.png"))
+        .and(path("/wide.png"))
         .respond_with(
             ResponseTemplate::new(200)
-                .set_body_bytes(test_image)
+                .set_body_bytes(fixture_png(8, 4))
                 .insert_header("content-type", "image/png"),
         )
+        .expect(2)
         .mount(&mock_server)
         .await;
 
-    let temp_dir = TempDir::new().unwrap();
-    let app_state = create_app_state(temp_dir.path().to_path_buf());
+    let app = TestApp::spawn().await;
+
+    let src = format!("{}/wide.png", mock_server.uri());
+    let params = [("w", "4"), ("f", "png")];
+    let dimensions = |resp: &img_optimizer::test_support::TestResponse| {
+        ["x-image-width", "x-image-height", "x-original-size"]
+            .map(|name| resp.header(name).unwrap().to_string())
+    };
+
+    // Fresh, then from the cache
+    let resp = app.optimize(&src, &params).await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(dimensions(&resp), ["4", "2", "8x4"]);
+    let resp = app.optimize(&src, &params).await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(dimensions(&resp), ["4", "2", "8x4"]);
+
+    // Metadata files are not counted as entries
+    let cache = &app.state.cache;
+    assert_eq!(cache.read().await.stats().await.unwrap().entries, 1);
+
+    // Entries cached without their dimensions are processed again
+    let metadata_file = std::fs::read_dir(app.dir())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().is_some_and(|ext| ext == "meta"))
+        .unwrap();
+    std::fs::remove_file(&metadata_file).unwrap();
+    let resp = app.optimize(&src, &params).await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(dimensions(&resp), ["4", "2", "8x4"]);
+    assert!(metadata_file.exists());
+
+    assert_eq!(cache.write().await.clear().await.unwrap(), 1);
+    assert!(!metadata_file.exists());
+}
 
-    let mut app = test::init_service(App::new().app_data(web::Data::new(app_state)).route(
-        "/img-optimizer/v1/img",
-        web::get().to(optimize_image_handler),
-    ))
-    .await;
+#[actix_rt::test]
+async fn test_etag_revalidation() {
+    let mock_server = MockServer::start().await;
 
-    let image_url = format!("{}/quality-test.png", &mock_server.uri());
+    Mock::given(method("GET"))
+        .and(path("/etag-image.png"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(fixture_png(200, 100)))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
 
-    // Test quality below minimum
-    let req = test::TestRequest::get()
-        .uri(&format!("/img-optimizer/v1/img?src={}&q=0", &image_url))
-        .to_request();
-    let resp = test::call_service(&mut app, req).await;
-    assert_eq!(resp.status(), 400);
-    let body: serde_json::Value = test::read_body_json(resp).await;
-    assert_eq!(body["errorCode"], "VAL_002");
+    let app = TestApp::spawn().await;
 
-    // Test quality above maximum
-    let req = test::TestRequest::get()
-        .uri(&format!("/img-optimizer/v1/img?src={}&q=101", &image_url))
-        .to_request();
-    let resp = test::call_service(&mut app, req).await;
-    assert_eq!(resp.status(), 400);
-    let body: serde_json::Value = test::read_body_json(resp).await;
-    assert_eq!(body["errorCode"], "VAL_002");
+    let src = format!("{}/etag-image.png", &mock_server.uri());
+    let params = [("w", "100")];
+
+    let resp = app.optimize(&src, &params).await;
+    assert_eq!(resp.status, 200);
+    let etag = resp.header("etag").unwrap().to_string();
+    let cache_control = resp.header("cache-control").unwrap().to_string();
+    assert!(etag.starts_with('"'));
+
+    let resp = app
+        .send(
+            app.optimize_request(&src, &params)
+                .header("If-None-Match", &etag),
+        )
+        .await;
+    assert_eq!(resp.status, 304);
+    assert_eq!(resp.header("etag"), Some(etag.as_str()));
+    assert_eq!(resp.header("cache-control"), Some(cache_control.as_str()));
+    assert!(resp.body.is_empty());
+
+    // A stale validator gets the full image
+    let resp = app
+        .send(
+            app.optimize_request(&src, &params)
+                .header("If-None-Match", "\"stale\""),
+        )
+        .await;
+    assert_eq!(resp.status, 200);
+    assert!(!resp.body.is_empty());
 }
 
+#[cfg(feature = "webp")]
 #[actix_rt::test]
-async fn test_plasmic_compatible_webp_format() {
+async fn test_auto_format_follows_accept() {
     let mock_server = MockServer::start().await;
-    let test_image = create_test_png();
-
-    Mock::given(method("GET"))
-        .and(path("/plasmic-test.png"))
-        .respond_with(
-            ResponseTemplate::new(200)
-                .set_body_bytes(test_image)
-                .insert_header("content-type", "image/png"),
+    mount_png(&mock_server, "/auto.png", fixture_png(200, 100)).await;
+    let app = TestApp::spawn().await;
+    let src = format!("{}/auto.png", mock_server.uri());
+    let params = [("w", "100"), ("f", "auto")];
+    let request = |accept: &'static str| {
+        app.send(app.optimize_request(&src, &params).header("Accept", accept))
+    };
+
+    let chrome = request("image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8").await;
+    assert_eq!(chrome.status, 200);
+    assert_eq!(chrome.header("content-type"), Some("image/webp"));
+    assert_eq!(chrome.header("vary"), Some("Accept"));
+
+    let safari = request("image/png,image/svg+xml,image/*;q=0.8,video/*;q=0.8,*/*;q=0.5").await;
+    assert_eq!(safari.status, 200);
+    assert_ne!(safari.header("content-type"), Some("image/webp"));
+    assert_eq!(safari.header("vary"), Some("Accept"));
+    // Cached apart, so neither is served to the other
+    assert_ne!(chrome.header("etag"), safari.header("etag"));
+    assert_ne!(chrome.body, safari.body);
+
+    let etag = chrome.header("etag").unwrap();
+    let revalidated = app
+        .send(
+            app.optimize_request(&src, &params)
+                .header("Accept", "image/webp,*/*")
+                .header("If-None-Match", etag),
         )
-        .mount(&mock_server)
         .await;
+    assert_eq!(revalidated.status, 304);
+    assert_eq!(revalidated.header("vary"), Some("Accept"));
+
+    // Explicit formats don't depend on the client
+    let png = app.optimize(&src, &[("w", "100"), ("f", "png")]).await;
+    assert_eq!(png.header("content-type"), Some("image/png"));
+    assert_eq!(png.header("vary"), None);
+    let png = app
+        .send(
+            app.optimize_request(&src, &[("w", "100")])
+                .header("Accept", "image/webp,*/*"),
+        )
+        .await;
+    assert_eq!(png.header("vary"), None);
+}
 
-    let temp_dir = TempDir::new().unwrap();
-    let app_state = create_app_state(temp_dir.path().to_path_buf());
+#[actix_rt::test]
+async fn test_client_hints() {
+    let mock_server = MockServer::start().await;
+    mount_png(&mock_server, "/hints.png", fixture_png(800, 400)).await;
+    let src = format!("{}/hints.png", mock_server.uri());
+    let hinted =
+        |app: &TestApp, params: &[(&str, &str)], hints: &[(&'static str, &'static str)]| {
+            let mut request = app.optimize_request(&src, params);
+            for (name, value) in hints {
+                request = request.header(*name, *value);
+            }
+            request
+        };
+    let width = |resp: &img_optimizer::test_support::TestResponse| {
+        image::load_from_memory(&resp.body).unwrap().width()
+    };
+
+    // Off by default: hints are neither asked for nor read
+    let app = TestApp::spawn().await;
+    let resp = app
+        .send(hinted(&app, &[("f", "png")], &[("Sec-CH-Width", "300")]))
+        .await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(width(&resp), 800);
+    assert_eq!(resp.header("accept-ch"), None);
+    assert_eq!(resp.header("vary"), None);
+
+    let mut config = AppConfig::default();
+    config.features.client_hints = true;
+    config.limits.max_width = 600;
+    let app = TestApp::builder().config(config).spawn().await;
+
+    let resp = app
+        .send(hinted(&app, &[("f", "png")], &[("Sec-CH-Width", "300")]))
+        .await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(width(&resp), 300);
+    assert_eq!(resp.header("accept-ch"), Some("Sec-CH-DPR, Sec-CH-Width"));
+    assert_eq!(resp.header("vary"), Some("Sec-CH-Width"));
+    let wider = app
+        .send(hinted(&app, &[("f", "png")], &[("Sec-CH-Width", "500")]))
+        .await;
+    assert_eq!(width(&wider), 500);
+    // Cached apart
+    assert_ne!(resp.header("etag"), wider.header("etag"));
+
+    let resp = app
+        .send(hinted(
+            &app,
+            &[("w", "100"), ("f", "png")],
+            &[("Sec-CH-DPR", "2"), ("Sec-CH-Width", "300")],
+        ))
+        .await;
+    assert_eq!(width(&resp), 200);
+    assert_eq!(resp.header("vary"), Some("Sec-CH-DPR"));
+    // Capped by MAX_WIDTH
+    let resp = app
+        .send(hinted(
+            &app,
+            &[("w", "250"), ("f", "png")],
+            &[("Sec-CH-DPR", "3")],
+        ))
+        .await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(width(&resp), 600);
 
-    let mut app = test::init_service(App::new().app_data(web::Data::new(app_state)).route(
-        "/img-optimizer/v1/img",
-        web::get().to(optimize_image_handler),
-    ))
-    .await;
+    // Browsers that haven't sent hints yet get the size asked for
+    let resp = app
+        .send(hinted(&app, &[("w", "100"), ("f", "png")], &[]))
+        .await;
+    assert_eq!(width(&resp), 100);
+    assert_eq!(resp.header("vary"), Some("Sec-CH-DPR"));
+    assert_eq!(resp.header("accept-ch"), Some("Sec-CH-DPR, Sec-CH-Width"));
+}
 
-    // Test WebP format conversion (important for Plasmic)
-    let image_url = format!("{}/plasmic-test.png", &mock_server.uri());
-    let req = test::TestRequest::get()
-        .uri(&format!(
-            "/img-optimizer/v1/img?src={}&f=webp&q=80",
-            &image_url
-        ))
-        .to_request();
-    let resp = test::call_service(&mut app, req).await;
+#[actix_rt::test]
+async fn test_chained_transformations() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/chain.png"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(fixture_png(400, 200)))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    let app = TestApp::spawn().await;
+    let src = format!("{}/chain.png", mock_server.uri());
+
+    let resp = app
+        .optimize(&src, &[("tx", "crop:ar=1:1/resize:w=100"), ("f", "png")])
+        .await;
+    assert_eq!(resp.status, 200);
+    let img = image::load_from_memory(&resp.body).unwrap();
+    assert_eq!((img.width(), img.height()), (100, 100));
+
+    // The cache key follows the canonical form, so an equivalent chain is
+    // served from the cache
+    let equivalent = app
+        .optimize(
+            &src,
+            &[
+                ("tx", "crop:ar=2:2,g=center/resize:w=100,fit=contain"),
+                ("f", "png"),
+            ],
+        )
+        .await;
+    assert_eq!(equivalent.status, 200);
+    assert_eq!(equivalent.header("etag"), resp.header("etag"));
 
-    assert!(resp.status().is_success());
+    let resp = app
+        .optimize(
+            &src,
+            &[("tx", "crop:ar=4:5/resize:w=0/flip:x"), ("w", "abc")],
+        )
+        .await;
+    assert_eq!(resp.status, 400);
+    let body = resp.json();
+    assert_eq!(body["errorCode"], "VAL_009");
+    let params: Vec<_> = body["errors"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|error| (error["param"].clone(), error["value"].clone()))
+        .collect();
     assert_eq!(
-        resp.headers()
-            .get("content-type")
-            .unwrap()
-            .to_str()
-            .unwrap(),
-        "image/webp"
+        params,
+        [
+            ("w".into(), "abc".into()),
+            ("tx[1]".into(), "resize:w=0".into()),
+            ("tx[2]".into(), "flip:x".into()),
+        ]
     );
 }
 
 #[actix_rt::test]
-async fn test_plasmic_compatible_resize_and_format() {
+async fn test_srcset_manifest() {
     let mock_server = MockServer::start().await;
-    let test_image = create_test_png();
-
-    Mock::given(method("GET"))
-        .and(path("/resize-format.png"))
-        .respond_with(
-            ResponseTemplate::new(200)
-                .set_body_bytes(test_image)
-                .insert_header("content-type", "image/png"),
+    mount_png(&mock_server, "/photo.png", fixture_png(800, 400)).await;
+    let app = TestApp::spawn().await;
+    let src = format!("{}/photo.png", mock_server.uri());
+    let manifest = |params: &[(&str, &str)]| {
+        app.send(
+            app.request(Method::GET, "/img-optimizer/v1/img/srcset")
+                .query(&[("src", src.as_str()), ("q", "75"), ("f", "auto")])
+                .query(params)
+                .header("Accept", "image/webp,*/*"),
+        )
+    };
+
+    let resp = manifest(&[("widths", "1024,320,640")]).await;
+    assert_eq!(resp.status, 200);
+    let body = resp.json();
+    assert_eq!(body["src"], src.as_str());
+    assert!(body.get("intrinsicWidth").is_none());
+    let variants = body["variants"].as_array().unwrap();
+    let widths: Vec<_> = variants
+        .iter()
+        .map(|v| v["width"].as_u64().unwrap())
+        .collect();
+    assert_eq!(widths, [320, 640, 1024]);
+    let url = variants[0]["url"].as_str().unwrap();
+    assert_eq!(
+        url,
+        format!(
+            "{}?src={}&w=320&q=75&f=auto",
+            app.url("/img-optimizer/v1/img"),
+            urlencoding::encode(&src)
+        )
+    );
+    assert!(body["srcset"]
+        .as_str()
+        .unwrap()
+        .starts_with(&format!("{url} 320w, ")));
+    // Listing variants doesn't touch the source
+    assert!(mock_server.received_requests().await.unwrap().is_empty());
+
+    // The cache key is the ETag the variant is served with
+    let variant = app
+        .send(
+            app.request(Method::GET, url.strip_prefix(&app.url("")).unwrap())
+                .header("Accept", "image/webp,*/*"),
         )
-        .mount(&mock_server)
         .await;
+    assert_eq!(variant.status, 200);
+    let cache_key = variants[0]["cacheKey"].as_str().unwrap();
+    assert_eq!(
+        variant.header("etag"),
+        Some(format!("\"{cache_key}\"").as_str())
+    );
 
-    let temp_dir = TempDir::new().unwrap();
-    let app_state = create_app_state(temp_dir.path().to_path_buf());
+    let resp = manifest(&[("widths", "320,640,1024"), ("probe", "1")]).await;
+    let body = resp.json();
+    assert_eq!(body["intrinsicWidth"], 800);
+    let widths: Vec<_> = body["variants"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|v| v["width"].as_u64().unwrap())
+        .collect();
+    assert_eq!(widths, [320, 640]);
+
+    let resp = manifest(&[("widths", "2000,3000"), ("probe", "1")]).await;
+    assert_eq!(resp.json()["variants"][0]["width"], 800);
+
+    let resp = manifest(&[("widths", "320,480"), ("prefetch", "1")]).await;
+    let body = resp.json();
+    assert_eq!(body["variants"][0]["cache"], "hit");
+    assert_eq!(body["variants"][1]["cache"], "miss");
+    let resp = manifest(&[("widths", "480"), ("prefetch", "1")]).await;
+    assert_eq!(resp.json()["variants"][0]["cache"], "hit");
+}
 
-    let mut app = test::init_service(App::new().app_data(web::Data::new(app_state)).route(
-        "/img-optimizer/v1/img",
-        web::get().to(optimize_image_handler),
-    ))
-    .await;
+#[actix_rt::test]
+async fn test_srcset_manifest_validation() {
+    let app = TestApp::spawn().await;
+    let src = "https://images.example/photo.png";
+    let manifest = |params: &[(&str, &str)]| {
+        app.send(
+            app.request(Method::GET, "/img-optimizer/v1/img/srcset")
+                .query(params),
+        )
+    };
 
-    // Test resize + format conversion (common Plasmic use case)
-    let image_url = format!("{}/resize-format.png", &mock_server.uri());
-    let req = test::TestRequest::get()
-        .uri(&format!(
-            "/img-optimizer/v1/img?src={}&w=800&f=jpeg&q=90",
-            &image_url
-        ))
-        .to_request();
-    let resp = test::call_service(&mut app, req).await;
+    let resp = manifest(&[("src", src)]).await;
+    assert_eq!(resp.status, 400);
+    assert_eq!(resp.json()["errorCode"], "VAL_003");
+
+    for widths in ["", "320,abc", "0", "100000"] {
+        let resp = manifest(&[("src", src), ("widths", widths)]).await;
+        assert_eq!(resp.status, 400, "{widths:?}");
+        assert_eq!(resp.json()["errorCode"], "VAL_007", "{widths:?}");
+    }
 
-    assert!(resp.status().is_success());
+    let resp = manifest(&[("src", src), ("widths", "320"), ("probe", "yes")]).await;
+    assert_eq!(resp.status, 400);
+
+    let resp = manifest(&[("widths", "320")]).await;
+    assert_eq!(resp.status, 400);
+    assert_eq!(resp.json()["errorCode"], "VAL_003");
 }
 
 #[actix_rt::test]
-async fn test_plasmic_error_format_rfc7807() {
-    let temp_dir = TempDir::new().unwrap();
-    let app_state = create_app_state(temp_dir.path().to_path_buf());
+async fn test_srcset_manifest_uses_public_url() {
+    let mut config = AppConfig::default();
+    config.server.public_url = Some("https://cdn.example.com".to_string());
+    let app = TestApp::builder().config(config).spawn().await;
 
-    let mut app = test::init_service(App::new().app_data(web::Data::new(app_state)).route(
-        "/img-optimizer/v1/img",
-        web::get().to(optimize_image_handler),
-    ))
-    .await;
+    let resp = app
+        .get("/img-optimizer/v1/img/srcset?src=https%3A%2F%2Fimages.example%2Fa.png&widths=640")
+        .await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(
+        resp.json()["variants"][0]["url"],
+        "https://cdn.example.com/img-optimizer/v1/img?src=https%3A%2F%2Fimages.example%2Fa.png&w=640"
+    );
+}
 
-    let req = test::TestRequest::get()
-        .uri("/img-optimizer/v1/img?src=invalid-url")
-        .to_request();
-    let resp = test::call_service(&mut app, req).await;
+/// Name, compression and dimensions of each member of a zip archive.
+fn unzip(archive: &[u8]) -> Vec<(String, zip::CompressionMethod, (u32, u32))> {
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(archive)).unwrap();
+    (0..archive.len())
+        .map(|index| {
+            let mut member = archive.by_index(index).unwrap();
+            let mut data = Vec::new();
+            std::io::Read::read_to_end(&mut member, &mut data).unwrap();
+            let image = image::load_from_memory(&data).unwrap();
+            (
+                member.name().to_string(),
+                member.compression(),
+                (image.width(), image.height()),
+            )
+        })
+        .collect()
+}
 
-    assert_eq!(resp.status(), 400);
-    let body: serde_json::Value = test::read_body_json(resp).await;
+#[actix_rt::test]
+async fn test_bundle_download() {
+    let mock_server = MockServer::start().await;
+    mount_png(&mock_server, "/photo.png", fixture_png(800, 400)).await;
+    let app = TestApp::spawn().await;
+    let src = format!("{}/photo.png", mock_server.uri());
+    let bundle = || {
+        app.send(
+            app.request(Method::GET, "/img-optimizer/v1/img/bundle")
+                .query(&[
+                    ("src", src.as_str()),
+                    ("variants", "w_400_f_png,w_200_f_jpeg,w_400_q_50_f_png"),
+                ]),
+        )
+    };
 
-    // Verify RFC7807 Problem Details format
-    assert!(body["type"].is_string());
-    assert!(body["title"].is_string());
-    assert!(body["status"].is_number());
-    assert!(body["detail"].is_string());
-    assert!(body["errorCode"].is_string());
-    assert!(body["howToFix"].is_string());
-    assert!(body["moreInfo"].is_string());
+    let resp = bundle().await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("content-type"), Some("application/zip"));
+    assert_eq!(
+        resp.header("content-disposition"),
+        Some("attachment; filename=\"photo.zip\"")
+    );
+    let stored = zip::CompressionMethod::Stored;
+    assert_eq!(
+        unzip(&resp.body),
+        [
+            ("photo-400w.png".to_string(), stored, (400, 200)),
+            ("photo-200w.jpg".to_string(), stored, (200, 100)),
+            ("photo-400w-2.png".to_string(), stored, (400, 200)),
+        ]
+    );
+    // Fetched once for every variant
+    assert_eq!(mock_server.received_requests().await.unwrap().len(), 1);
+
+    // Variants are cached like the images they match
+    let resp = bundle().await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(unzip(&resp.body).len(), 3);
+    let resp = app.optimize(&src, &[("w", "200"), ("f", "jpeg")]).await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(mock_server.received_requests().await.unwrap().len(), 1);
+}
+
+#[actix_rt::test]
+async fn test_bundle_validation() {
+    let app = TestApp::spawn().await;
+    let bundle = |variants: &str| {
+        app.send(
+            app.request(Method::GET, "/img-optimizer/v1/img/bundle")
+                .query(&[
+                    ("src", "https://images.example/a.png"),
+                    ("variants", variants),
+                ]),
+        )
+    };
+
+    let resp = bundle("w_400_f_png,w_400_x_1,w_800_f").await;
+    assert_eq!(resp.status, 400);
+    let body = resp.json();
+    assert_eq!(body["errorCode"], "VAL_009");
+    let params: Vec<_> = body["errors"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|error| error["param"].clone())
+        .collect();
+    assert_eq!(params, ["variants[1]", "variants[2]"]);
+
+    let resp = bundle(&["w_100"; 13].join(",")).await;
+    assert_eq!(resp.status, 400);
+    assert_eq!(resp.json()["errorCode"], "VAL_007");
+
+    let resp = bundle("w_0_f_png").await;
+    assert_eq!(resp.status, 400);
+
+    let resp = app
+        .get("/img-optimizer/v1/img/bundle?src=https%3A%2F%2Fimages.example%2Fa.png")
+        .await;
+    assert_eq!(resp.status, 400);
+    assert_eq!(resp.json()["errorCode"], "VAL_003");
+}
+
+#[actix_rt::test]
+async fn test_bundle_size_is_limited() {
+    let mock_server = MockServer::start().await;
+    mount_png(&mock_server, "/photo.png", fixture_png(800, 400)).await;
+    let mut config = AppConfig::default();
+    config.limits.max_bundle_size = 1024;
+    let app = TestApp::builder().config(config).spawn().await;
+
+    let resp = app
+        .send(
+            app.request(Method::GET, "/img-optimizer/v1/img/bundle")
+                .query(&[
+                    ("src", format!("{}/photo.png", mock_server.uri()).as_str()),
+                    ("variants", "w_800_f_png,w_400_f_png"),
+                ]),
+        )
+        .await;
+    assert_eq!(resp.status, 422);
+    assert_eq!(resp.json()["errorCode"], "IMG_013");
+}
+
+/// Polls job `location` until it is no longer pending.
+async fn wait_for_job(app: &TestApp, location: &str) -> serde_json::Value {
+    for _ in 0..100 {
+        let resp = app.get(location).await;
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.header("cache-control"), Some("no-store"));
+        let body = resp.json();
+        if body["status"] != "pending" {
+            return body;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    panic!("job {location} still pending");
+}
+
+#[actix_rt::test]
+async fn test_async_job() {
+    let mock_server = MockServer::start().await;
+    mount_png(&mock_server, "/large.png", fixture_png(1200, 800)).await;
+    let app = TestApp::spawn().await;
+    let src = format!("{}/large.png", mock_server.uri());
+
+    let resp = app
+        .send(app.request(Method::POST, "/img-optimizer/v1/jobs").query(&[
+            ("src", src.as_str()),
+            ("w", "600"),
+            ("f", "png"),
+        ]))
+        .await;
+    assert_eq!(resp.status, 202);
+    let body = resp.json();
+    assert_eq!(body["status"], "pending");
+    let id = body["id"].as_str().unwrap();
+    let location = resp.header("location").unwrap().to_string();
+    assert_eq!(location, format!("/img-optimizer/v1/jobs/{id}"));
+
+    let body = wait_for_job(&app, &location).await;
+    assert_eq!(body["status"], "succeeded", "{body}");
+    assert_eq!(body["id"], id);
+    assert_eq!(body["contentType"], "image/png");
+    assert_eq!(
+        (body["width"].as_u64(), body["height"].as_u64()),
+        (Some(600), Some(400))
+    );
+    let url = body["url"].as_str().unwrap();
+    assert_eq!(
+        url,
+        format!(
+            "{}?src={}&w=600&f=png",
+            app.url("/img-optimizer/v1/img"),
+            urlencoding::encode(&src)
+        )
+    );
+
+    // The image is served from the cache
+    let image = app.get(url.strip_prefix(&app.url("")).unwrap()).await;
+    assert_eq!(image.status, 200);
+    assert_eq!(
+        image.header("etag"),
+        Some(format!("\"{}\"", body["cacheKey"].as_str().unwrap()).as_str())
+    );
+    assert_eq!(mock_server.received_requests().await.unwrap().len(), 1);
+}
+
+#[actix_rt::test]
+async fn test_failed_async_job() {
+    let mock_server = MockServer::start().await;
+    let app = TestApp::spawn().await;
+    let src = format!("{}/missing.png", mock_server.uri());
+
+    let resp = app
+        .send(
+            app.request(Method::POST, "/img-optimizer/v1/jobs")
+                .query(&[("src", src.as_str()), ("w", "600")]),
+        )
+        .await;
+    assert_eq!(resp.status, 202);
+
+    let body = wait_for_job(&app, resp.header("location").unwrap()).await;
+    assert_eq!(body["status"], "failed", "{body}");
+    assert_eq!(body["error"]["errorCode"], "IMG_008");
+    assert_eq!(body["error"]["status"], 404);
+    assert!(body.get("url").is_none());
+}
+
+#[actix_rt::test]
+async fn test_async_job_errors() {
+    let app = TestApp::spawn().await;
+
+    // Invalid parameters are rejected right away, without a job
+    let resp = app
+        .send(
+            app.request(Method::POST, "/img-optimizer/v1/jobs")
+                .query(&[("src", "https://images.example/a.png"), ("w", "0")]),
+        )
+        .await;
+    assert_eq!(resp.status, 400);
+    let resp = app
+        .send(app.request(Method::POST, "/img-optimizer/v1/jobs"))
+        .await;
+    assert_eq!(resp.status, 400);
+    assert_eq!(resp.json()["errorCode"], "VAL_003");
+
+    let resp = app.get("/img-optimizer/v1/jobs/unknown").await;
+    assert_eq!(resp.status, 404);
+    assert_eq!(resp.json()["errorCode"], "JOB_001");
+
+    let resp = app.get("/img-optimizer/v1/jobs").await;
+    assert_eq!(resp.status, 405);
+}
+
+#[cfg(feature = "webp")]
+#[actix_rt::test]
+async fn test_download_filename() {
+    use actix_web::http::header::{ContentDisposition, HeaderValue};
+
+    let mock_server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/download.png"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(fixture_png(8, 8)))
+        .expect(1) // `dl` is not part of the cache key
+        .mount(&mock_server)
+        .await;
+
+    let app = TestApp::spawn().await;
+
+    let src = format!("{}/download.png", &mock_server.uri());
+    let download = |dl: &str| {
+        let app = &app;
+        let src = &src;
+        let dl = dl.to_string();
+        async move {
+            let resp = app.optimize(src, &[("f", "webp"), ("dl", &dl)]).await;
+            assert_eq!(resp.status, 200);
+            let value = HeaderValue::from_str(resp.header("content-disposition").unwrap());
+            ContentDisposition::from_raw(&value.unwrap()).unwrap()
+        }
+    };
+
+    let disposition = download("photo d'été.png").await;
+    assert!(disposition.is_attachment());
+    assert_eq!(disposition.get_filename(), Some("photo d'_t_.webp"));
+    let filename_ext = disposition.get_filename_ext().unwrap();
+    assert_eq!(
+        String::from_utf8(filename_ext.value.clone()).unwrap(),
+        "photo d'été.webp"
+    );
+
+    let disposition = download("../../etc/passwd").await;
+    assert_eq!(disposition.get_filename(), Some("passwd.webp"));
+
+    let long_name = format!("{}.jpg", "a".repeat(500));
+    let disposition = download(&long_name).await;
+    assert_eq!(
+        disposition.get_filename().unwrap().len(),
+        128 + ".webp".len()
+    );
+}
+
+#[actix_rt::test]
+async fn test_quality_parameter_bounds() {
+    let mock_server = MockServer::start().await;
+    mount_png(&mock_server, "/quality-test.png", fixture_png(8, 8)).await;
+
+    let app = TestApp::spawn().await;
+
+    let image_url = format!("{}/quality-test.png", &mock_server.uri());
+
+    // Test quality below minimum
+    let resp = app.optimize(&image_url, &[("q", "0")]).await;
+    assert_eq!(resp.status, 400);
+    assert_eq!(resp.json()["errorCode"], "VAL_002");
+
+    // Test quality above maximum
+    let resp = app.optimize(&image_url, &[("q", "101")]).await;
+    assert_eq!(resp.status, 400);
+    assert_eq!(resp.json()["errorCode"], "VAL_002");
+}
+
+#[cfg(feature = "webp")]
+#[actix_rt::test]
+async fn test_plasmic_compatible_webp_format() {
+    let mock_server = MockServer::start().await;
+    mount_png(&mock_server, "/plasmic-test.png", fixture_png(64, 48)).await;
+
+    let app = TestApp::spawn().await;
+
+    // Test WebP format conversion (important for Plasmic)
+    let image_url = format!("{}/plasmic-test.png", &mock_server.uri());
+    let resp = app
+        .optimize(&image_url, &[("f", "webp"), ("q", "80")])
+        .await;
+
+    assert!(resp.status.is_success());
+    assert_eq!(resp.header("content-type"), Some("image/webp"));
+    let img = image::load_from_memory(&resp.body).unwrap();
+    assert_eq!((img.width(), img.height()), (64, 48));
+}
+
+#[actix_rt::test]
+async fn test_plasmic_compatible_resize_and_format() {
+    let mock_server = MockServer::start().await;
+    mount_png(&mock_server, "/resize-format.png", fixture_png(1600, 1200)).await;
+
+    let app = TestApp::spawn().await;
+
+    // Test resize + format conversion (common Plasmic use case)
+    let image_url = format!("{}/resize-format.png", &mock_server.uri());
+    let resp = app
+        .optimize(&image_url, &[("w", "800"), ("f", "jpeg"), ("q", "90")])
+        .await;
+
+    assert!(resp.status.is_success());
+    let img = image::load_from_memory(&resp.body).unwrap();
+    assert_eq!((img.width(), img.height()), (800, 600));
+}
+
+#[actix_rt::test]
+async fn test_plasmic_error_format_rfc7807() {
+    let app = TestApp::spawn().await;
+
+    let resp = app
+        .send(
+            app.request(Method::GET, "/img-optimizer/v1/img?src=invalid-url")
+                .header("X-Request-Id", "rfc7807-test"),
+        )
+        .await;
+
+    assert_eq!(resp.status, 400);
+    assert_eq!(
+        resp.header("content-type"),
+        Some("application/problem+json")
+    );
+    let body = resp.json();
+
+    // Verify RFC7807 Problem Details format
+    assert!(body["type"].is_string());
+    assert!(body["title"].is_string());
+    assert!(body["status"].is_number());
+    assert!(body["detail"].is_string());
+    assert!(body["errorCode"].is_string());
+    assert!(body["howToFix"].is_string());
+    assert!(body["moreInfo"].is_string());
+    assert_eq!(body["instance"], "/img-optimizer/v1/img?src=invalid-url");
+    assert_eq!(body["requestId"], "rfc7807-test");
+
+    // An API key passed in the query is not sent back
+    let app = TestApp::builder()
+        .api_keys(ApiKeys::parse("website:secret-key"))
+        .spawn()
+        .await;
+    let resp = app
+        .get("/img-optimizer/v1/img?key=secret-key&src=invalid-url&w=abc")
+        .await;
+    assert_eq!(resp.status, 400);
+    let body = resp.json();
+    let instance = body["instance"].as_str().unwrap();
+    assert_eq!(instance, "/img-optimizer/v1/img?src=invalid-url&w=abc");
+    assert!(!instance.contains("key="));
+    assert!(!resp.text().contains("secret-key"));
+}
+
+#[actix_rt::test]
+async fn test_minimal_error_detail_hides_values_behind_request_id() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/private/secret.png"))
+        .respond_with(ResponseTemplate::new(404))
+        .mount(&mock_server)
+        .await;
+    let src = format!("{}/private/secret.png", mock_server.uri());
+
+    let mut bodies = Vec::new();
+    for error_detail in [ErrorDetail::Full, ErrorDetail::Minimal] {
+        let mut config = AppConfig::default();
+        config.server.error_detail = error_detail;
+        let app = TestApp::builder().config(config).spawn().await;
+
+        let resp = app
+            .send(
+                app.optimize_request(&src, &[])
+                    .header("X-Request-Id", "detail-test"),
+            )
+            .await;
+        assert_eq!(resp.status, 404);
+        let body = resp.json();
+        assert_eq!(body["errorCode"], "IMG_008");
+        assert_eq!(body["requestId"], "detail-test");
+        bodies.push(body);
+    }
+
+    let (full, minimal) = (&bodies[0], &bodies[1]);
+    assert!(full["detail"]
+        .as_str()
+        .unwrap()
+        .contains("/private/secret.png"));
+
+    let detail = minimal["detail"].as_str().unwrap();
+    assert!(detail.starts_with("IMG_008: "));
+    assert!(detail.contains("[ref detail-test]"));
+    assert!(!detail.contains("secret.png"));
+    assert!(!detail.contains("127.0.0.1"));
+    assert_eq!(minimal["instance"], "/img-optimizer/v1/img");
+    let body = minimal.to_string();
+    assert!(!body.contains("secret.png"));
+    assert!(!body.contains(&mock_server.uri()));
+    assert!(!body.contains("src="));
+    assert_eq!(minimal["howToFix"], full["howToFix"]);
+    assert_eq!(minimal["moreInfo"], full["moreInfo"]);
+}
+
+#[actix_rt::test]
+async fn test_unmatched_routes_and_methods_use_problem_details() {
+    let app = TestApp::spawn().await;
+
+    for uri in ["/nope", "/img-optimizer/v1/nope"] {
+        let resp = app.get(uri).await;
+        assert_eq!(resp.status, 404);
+        assert_eq!(
+            resp.header("content-type"),
+            Some("application/problem+json")
+        );
+        let body = resp.json();
+        assert_eq!(body["errorCode"], "SYS_404");
+        assert_eq!(body["instance"], uri);
+    }
+
+    let resp = app
+        .send(app.request(Method::DELETE, "/img-optimizer/v1/img"))
+        .await;
+    assert_eq!(resp.status, 405);
+    assert_eq!(resp.header("allow"), Some("GET, POST"));
+    let body = resp.json();
+    assert_eq!(body["errorCode"], "SYS_405");
+    assert!(body["detail"].as_str().unwrap().contains("DELETE"));
+
+    let resp = app.send(app.request(Method::POST, "/health")).await;
+    assert_eq!(resp.status, 405);
+    assert_eq!(resp.header("allow"), Some("GET"));
+
+    // Disabled routes answer like unmatched ones
+    let mut config = AppConfig::default();
+    config.features.metrics = false;
+    let app = TestApp::builder().config(config).spawn().await;
+    for uri in ["/metrics", "/debug", "/_next/image?url=/a.png&w=64&q=75"] {
+        let resp = app.get(uri).await;
+        assert_eq!(resp.status, 404, "{uri}");
+        assert_eq!(
+            resp.header("content-type"),
+            Some("application/problem+json")
+        );
+        assert_eq!(resp.json()["errorCode"], "SYS_404", "{uri}");
+    }
+}
+
+#[actix_rt::test]
+async fn test_origin_failures_map_to_distinct_errors() {
+    let mock_server = MockServer::start().await;
+    for status in [404, 410, 401, 403, 400, 500, 503, 504] {
+        Mock::given(method("GET"))
+            .and(path(format!("/status-{status}.png")))
+            .respond_with(ResponseTemplate::new(status))
+            .mount(&mock_server)
+            .await;
+    }
+    Mock::given(method("GET"))
+        .and(path("/slow.png"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_bytes(fixture_png(8, 8))
+                .set_delay(std::time::Duration::from_secs(3)),
+        )
+        .mount(&mock_server)
+        .await;
+
+    let mut config = AppConfig::default();
+    config.fetch.timeout_secs = 1;
+    let app = TestApp::builder().config(config).spawn().await;
+
+    let cases = [
+        ("status-404.png", 404, "IMG_008"),
+        ("status-410.png", 404, "IMG_008"),
+        ("status-401.png", 502, "IMG_009"),
+        ("status-403.png", 502, "IMG_009"),
+        ("status-400.png", 422, "IMG_002"),
+        ("status-500.png", 502, "IMG_010"),
+        ("status-503.png", 502, "IMG_010"),
+        ("status-504.png", 502, "IMG_010"),
+        ("slow.png", 504, "IMG_011"),
+    ];
+    let mut fixes = std::collections::HashSet::new();
+    for (file, status, code) in cases {
+        let resp = app
+            .optimize(&format!("{}/{file}", &mock_server.uri()), &[])
+            .await;
+        assert_eq!(resp.status, status, "{file}");
+        let body = resp.json();
+        assert_eq!(body["errorCode"], code, "{file}");
+        fixes.insert(body["howToFix"].as_str().unwrap().to_string());
+    }
+    // Each error code comes with its own advice
+    assert_eq!(fixes.len(), 5);
+
+    // Unreachable origins are gateway errors too
+    let resp = app
+        .optimize("http://127.0.0.1:1/unreachable.png", &[])
+        .await;
+    assert_eq!(resp.status, 502);
+}
+
+#[actix_rt::test]
+async fn test_origin_timeout_reports_phase_and_budget() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/slow.png"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_bytes(fixture_png(8, 8))
+                .set_delay(std::time::Duration::from_secs(3)),
+        )
+        .mount(&mock_server)
+        .await;
+
+    let mut config = AppConfig::default();
+    config.fetch.timeout_secs = 1;
+    let app = TestApp::builder().config(config).spawn().await;
+
+    let resp = app
+        .optimize(&format!("{}/slow.png", &mock_server.uri()), &[])
+        .await;
+    assert_eq!(resp.status, 504);
+    let body = resp.json();
+    assert_eq!(body["errorCode"], "IMG_011");
+    let detail = body["detail"].as_str().unwrap();
+    assert!(detail.contains("1000 ms"), "{detail}");
+    assert!(detail.contains("during response"), "{detail}");
+    assert!(body["howToFix"].as_str().unwrap().contains("FETCH_TIMEOUT"));
+}
+
+#[actix_rt::test]
+async fn test_fetch_failures_keep_cause_without_credentials() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/broken.png"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&mock_server)
+        .await;
+
+    let app = TestApp::spawn().await;
+
+    let with_credentials = |url: &str| url.replacen("http://", "http://user:s3cret@", 1);
+    let closed_port = with_credentials("http://127.0.0.1:1/closed.png");
+    let broken = with_credentials(&format!("{}/broken.png", mock_server.uri()));
+
+    for (src, failure) in [(closed_port, "connect error"), (broken, "http error")] {
+        let resp = app.optimize(&src, &[]).await;
+        assert_eq!(resp.status, 502);
+        let body = resp.json();
+        assert_eq!(body["errorCode"], "IMG_010");
+        let detail = body["detail"].as_str().unwrap();
+        assert!(detail.contains(failure), "{detail}");
+        assert!(!detail.contains("s3cret"), "{detail}");
+        assert!(!detail.contains("user@"), "{detail}");
+    }
+}
+
+#[actix_rt::test]
+async fn test_api_key_authentication() {
+    let mock_server = MockServer::start().await;
+    mount_png(&mock_server, "/auth-test.png", fixture_png(8, 8)).await;
+
+    let app = TestApp::builder()
+        .api_keys(ApiKeys::parse("website:secret-key,mobile:other-key"))
+        .spawn()
+        .await;
+
+    let image_url = format!("{}/auth-test.png", &mock_server.uri());
+
+    // Missing key
+    let resp = app.optimize(&image_url, &[]).await;
+    assert_eq!(resp.status, 401);
+    let body = resp.json();
+    assert_eq!(body["title"], "Unauthorized");
+    assert_eq!(body["errorCode"], "SEC_001");
+
+    // Wrong key
+    let resp = app
+        .send(
+            app.optimize_request(&image_url, &[])
+                .header("X-Api-Key", "wrong-key"),
+        )
+        .await;
+    assert_eq!(resp.status, 401);
+
+    // Valid key in header, which shared caches must key on
+    let resp = app
+        .send(
+            app.optimize_request(&image_url, &[])
+                .header("X-Api-Key", "secret-key"),
+        )
+        .await;
+    assert!(resp.status.is_success());
+    assert_eq!(resp.header("vary"), Some("X-Api-Key"));
+    let resp = app
+        .send(
+            app.optimize_request(&image_url, &[("f", "auto")])
+                .header("X-Api-Key", "secret-key"),
+        )
+        .await;
+    assert_eq!(resp.header("vary"), Some("Accept, X-Api-Key"));
+
+    // Valid key in query string (for <img> tags), part of the URL
+    let resp = app.optimize(&image_url, &[("key", "other-key")]).await;
+    assert!(resp.status.is_success());
+    assert_eq!(resp.header("vary"), None);
+
+    // Health endpoint stays open
+    let resp = app.get("/health").await;
+    assert!(resp.status.is_success());
+
+    // Authorized requests are counted by key label
+    let metrics = app.get("/metrics").await.text();
+    assert!(metrics.contains("img_optimizer_requests_by_key_total{key=\"website\"} 2"));
+    assert!(metrics.contains("img_optimizer_requests_by_key_total{key=\"mobile\"} 1"));
+}
+
+/// Collects what a tracing subscriber writes.
+#[derive(Clone, Default)]
+struct LogBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl std::io::Write for LogBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[actix_rt::test]
+async fn test_access_log_names_the_api_key() {
+    use actix_web::{middleware::from_fn, test, web, App};
+
+    let logs = LogBuffer::default();
+    let subscriber = tracing_subscriber::fmt()
+        .json()
+        .flatten_event(true)
+        .with_writer({
+            let logs = logs.clone();
+            move || logs.clone()
+        })
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let state = Optimizer::builder()
+        .api_keys(ApiKeys::parse("website:secret-key"))
+        .build()
+        .state()
+        .clone();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(state))
+            .wrap(from_fn(access_log))
+            .configure(routes),
+    )
+    .await;
+    let req = test::TestRequest::get()
+        .uri("/img-optimizer/v1/img")
+        .insert_header(("X-Api-Key", "secret-key"))
+        .to_request();
+    test::call_service(&app, req).await;
+
+    let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    let line = logs
+        .lines()
+        .find(|line| line.contains("request completed"))
+        .unwrap();
+    let entry: serde_json::Value = serde_json::from_str(line).unwrap();
+    assert_eq!(entry["key"], "website");
+    assert_eq!(entry["status"], 400);
+}
+
+#[cfg(feature = "webp")]
+#[actix_rt::test]
+async fn test_metrics_endpoint() {
+    let mock_server = MockServer::start().await;
+    mount_png(&mock_server, "/metrics-test.png", fixture_png(8, 8)).await;
+
+    let app = TestApp::spawn().await;
+
+    let image_url = format!("{}/metrics-test.png", &mock_server.uri());
+    let resp = app.optimize(&image_url, &[("f", "webp")]).await;
+    assert!(resp.status.is_success());
+
+    let resp = app.get("/metrics").await;
+    assert!(resp.status.is_success());
+    let body = resp.text();
+
+    for phase in [
+        "cache_read",
+        "fetch",
+        "decode",
+        "transform",
+        "encode",
+        "cache_write",
+    ] {
+        assert!(body.contains(&format!(
+            "img_optimizer_phase_duration_seconds_count{{phase=\"{phase}\"}} 1"
+        )));
+    }
+    assert!(body.contains(
+        "img_optimizer_requests_total{error_code=\"none\",format=\"webp\",status=\"200\"} 1"
+    ));
+}
+
+#[actix_rt::test]
+async fn test_saturated_processing_queue_sheds_requests() {
+    let mock_server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/slow.png"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_bytes(fixture_png(8, 8))
+                .insert_header("content-type", "image/png")
+                .set_delay(std::time::Duration::from_millis(300)),
+        )
+        .mount(&mock_server)
+        .await;
+
+    let mut config = AppConfig::default();
+    config.processing.max_concurrent = 1;
+    config.processing.max_waiting = 1;
+    let app = TestApp::builder().config(config).spawn().await;
+
+    let image_url = format!("{}/slow.png", &mock_server.uri());
+    let resp = app.optimize(&image_url, &[("w", "100")]).await;
+    assert!(resp.status.is_success());
+
+    let widths: Vec<String> = (1..=6).map(|w| w.to_string()).collect();
+    let params: Vec<_> = widths.iter().map(|w| [("w", w.as_str())]).collect();
+    let responses = futures_util::future::join_all(
+        params.iter().map(|params| app.optimize(&image_url, params)),
+    )
+    .await;
+
+    let shed: Vec<_> = responses.iter().filter(|resp| resp.status == 429).collect();
+    let served = responses
+        .iter()
+        .filter(|resp| resp.status.is_success())
+        .count();
+    // One request holds the permit and one waits for it; the rest are shed
+    assert_eq!(served, 2);
+    assert_eq!(shed.len(), 4);
+    for resp in shed {
+        let retry_after: u64 = resp.header("retry-after").unwrap().parse().unwrap();
+        assert!(retry_after >= 1);
+    }
+
+    // Cache hits are served even with no processing slot available
+    let _permit = app.state.limiter.acquire().await.unwrap();
+    let resp = app.optimize(&image_url, &[("w", "100")]).await;
+    assert!(resp.status.is_success());
+
+    let body = app.get("/metrics").await.text();
+    assert!(body.contains("img_optimizer_shed_requests_total 4"));
+}
+
+#[actix_rt::test]
+async fn test_deferred_cache_writes() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/deferred.png"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_bytes(fixture_png(16, 16))
+                .insert_header("content-type", "image/png"),
+        )
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let cache_dir = TempDir::new().unwrap();
+    let mut config = AppConfig::default();
+    config.cache.write_mode = CacheWriteMode::Deferred;
+    config.features.server_timing = true;
+    let app = TestApp::builder()
+        .config(config)
+        .cache_dir(cache_dir.path().to_path_buf())
+        .spawn()
+        .await;
+    let src = format!("{}/deferred.png", mock_server.uri());
+
+    // The response doesn't wait for the write
+    let first = app.optimize(&src, &[("w", "8")]).await;
+    assert_eq!(first.status, 200);
+    assert!(!first
+        .header("server-timing")
+        .unwrap()
+        .contains("cache_write"));
+
+    // Which lands in the background
+    let written = || {
+        std::fs::read_dir(cache_dir.path()).is_ok_and(|entries| {
+            entries
+                .flatten()
+                .any(|entry| entry.path().extension().is_some_and(|ext| ext == "meta"))
+        })
+    };
+    for _ in 0..200 {
+        if written() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    assert!(written(), "The deferred write never completed");
+
+    let second = app.optimize(&src, &[("w", "8")]).await;
+    assert!(second
+        .header("server-timing")
+        .unwrap()
+        .ends_with("cache;desc=\"hit\""));
+    assert_eq!(first.body, second.body);
+}
+
+#[actix_rt::test]
+async fn test_deferred_cache_writes_are_drained_on_shutdown() {
+    let mock_server = MockServer::start().await;
+    mount_png(&mock_server, "/drained.png", fixture_png(64, 64)).await;
+
+    let cache_dir = TempDir::new().unwrap();
+    let mut config = AppConfig::default();
+    config.cache.write_mode = CacheWriteMode::Deferred;
+    let app = TestApp::builder()
+        .config(config)
+        .cache_dir(cache_dir.path().to_path_buf())
+        .spawn()
+        .await;
+    let src = format!("{}/drained.png", mock_server.uri());
+    assert_eq!(app.optimize(&src, &[("w", "32")]).await.status, 200);
+
+    // The write outlives the server, and is waited for as on SIGTERM
+    let state = app.state.clone();
+    drop(app);
+    assert!(
+        state
+            .pending_writes
+            .drain(std::time::Duration::from_secs(5))
+            .await
+    );
+    assert!(state.pending_writes.is_empty());
+    let on_disk = std::fs::read_dir(cache_dir.path())
+        .unwrap()
+        .flatten()
+        .filter(|entry| entry.path().extension().is_none_or(|ext| ext != "tmp"))
+        .count();
+    // The image and its metadata
+    assert_eq!(on_disk, 2);
+}
+
+#[actix_rt::test]
+async fn test_failed_deferred_cache_write_still_responds() {
+    let mock_server = MockServer::start().await;
+    mount_png(&mock_server, "/unwritable.png", fixture_png(16, 16)).await;
+
+    // Every write fails, see test_readiness_with_unwritable_cache
+    let temp_dir = TempDir::new().unwrap();
+    let cache_dir = temp_dir.path().join("not-a-directory");
+    std::fs::write(&cache_dir, b"").unwrap();
+    let mut config = AppConfig::default();
+    config.cache.write_mode = CacheWriteMode::Deferred;
+    let app = TestApp::builder()
+        .config(config)
+        .cache_dir(cache_dir)
+        .spawn()
+        .await;
+    let src = format!("{}/unwritable.png", mock_server.uri());
+
+    for _ in 0..2 {
+        let resp = app.optimize(&src, &[("w", "8")]).await;
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.header("content-type"), Some("image/png"));
+    }
+}
+
+#[actix_rt::test]
+async fn test_small_jobs_do_not_wait_behind_heavy_ones() {
+    use std::time::{Duration, Instant};
+
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/slow-large.png"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_bytes(fixture_png(16, 16))
+                .insert_header("content-type", "image/png")
+                .set_delay(Duration::from_millis(2000)),
+        )
+        .mount(&mock_server)
+        .await;
+    mount_png(&mock_server, "/large.png", fixture_png(16, 16)).await;
+    mount_png(&mock_server, "/small.png", fixture_png(8, 8)).await;
+
+    // Small jobs read at most 10x10 pixels and produce at most as many
+    let mut config = AppConfig::default();
+    config.processing.max_concurrent = 1;
+    config.processing.small_max_concurrent = 1;
+    config.processing.small_max_source_pixels = 100;
+    config.processing.small_max_target_pixels = 100;
+    let app = TestApp::builder().config(config).spawn().await;
+    let url = |route: &str| format!("{}{route}", mock_server.uri());
+
+    let start = Instant::now();
+    let timed = |delay: u64, src: String, width: &'static str| {
+        let app = &app;
+        async move {
+            actix_rt::time::sleep(Duration::from_millis(delay)).await;
+            let resp = app.optimize(&src, &[("w", width)]).await;
+            (resp, start.elapsed())
+        }
+    };
+    // Polled until the large source was found out and queued as heavy
+    let queues = async {
+        loop {
+            actix_rt::time::sleep(Duration::from_millis(50)).await;
+            let metrics = app.get("/metrics").await.text();
+            if metrics.contains("img_optimizer_processing_waiting{class=\"heavy\"} 1")
+                || start.elapsed() > Duration::from_millis(1500)
+            {
+                break (metrics, app.get("/status").await.json());
+            }
+        }
+    };
+
+    // The heavy job holds the only general slot while its origin is slow. A
+    // small job is served meanwhile; a small output of a large source is
+    // heavy, and waits for it
+    let (
+        (heavy, heavy_done),
+        (small, small_done),
+        (reclassified, reclassified_done),
+        (metrics, status),
+    ) = futures_util::future::join4(
+        timed(0, url("/slow-large.png"), "16"),
+        timed(100, url("/small.png"), "4"),
+        timed(100, url("/large.png"), "4"),
+        queues,
+    )
+    .await;
+
+    for resp in [&heavy, &small, &reclassified] {
+        assert_eq!(resp.status, 200);
+    }
+    // The heavy job holds its slot for at least the origin's delay
+    assert!(small_done < Duration::from_millis(1500), "{small_done:?}");
+    assert!(heavy_done >= Duration::from_millis(2000));
+    assert!(reclassified_done >= Duration::from_millis(2000));
+
+    assert!(metrics.contains("img_optimizer_processing_waiting{class=\"heavy\"} 1"));
+    assert!(metrics.contains("img_optimizer_processing_waiting{class=\"small\"} 0"));
+    assert_eq!(status["processing"]["permitsInUse"], 1);
+    assert_eq!(status["processing"]["waiting"], 1);
+    assert_eq!(status["processing"]["small"]["maxPermits"], 1);
+    assert_eq!(status["processing"]["small"]["waiting"], 0);
+}
+
+#[actix_rt::test]
+async fn test_request_id_header() {
+    let app = TestApp::spawn().await;
+
+    // Generated when absent
+    let resp = app.get("/health").await;
+    let generated = resp.header("x-request-id").unwrap();
+    assert_eq!(generated.len(), 36);
+
+    // Inbound ID is honored
+    let resp = app
+        .send(
+            app.request(Method::GET, "/health")
+                .header("X-Request-Id", "support-ticket-1234"),
+        )
+        .await;
+    assert_eq!(resp.header("x-request-id"), Some("support-ticket-1234"));
+}
+
+#[actix_rt::test]
+async fn test_request_id_is_forwarded_to_origin() {
+    use wiremock::matchers::{header, header_exists};
+
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/inbound-id.png"))
+        .and(header("X-Request-Id", "support-ticket-1234"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(fixture_png(8, 8)))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/correlation-id.png"))
+        .and(header_exists("X-Correlation-Id"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(fixture_png(8, 8)))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let app = TestApp::spawn().await;
+
+    // Inbound ID is forwarded as is
+    let src = format!("{}/inbound-id.png", &mock_server.uri());
+    let resp = app
+        .send(
+            app.optimize_request(&src, &[])
+                .header("X-Request-Id", "support-ticket-1234"),
+        )
+        .await;
+    assert!(resp.status.is_success());
+
+    // Generated ID under a configured header name
+    let mut config = AppConfig::default();
+    config.fetch.request_id_header = "X-Correlation-Id".to_string();
+    let app = TestApp::builder().config(config).spawn().await;
+
+    let resp = app
+        .optimize(&format!("{}/correlation-id.png", &mock_server.uri()), &[])
+        .await;
+    assert!(resp.status.is_success());
+}
+
+#[actix_rt::test]
+async fn test_status_endpoint() {
+    let app = TestApp::spawn().await;
+
+    let first = app.get("/status").await.json();
+    assert_eq!(first["service"], "img-optimizer");
+    assert_eq!(first["version"], env!("CARGO_PKG_VERSION"));
+    assert!(first["gitSha"].is_string());
+    assert_eq!(first["inFlightRequests"], 0);
+    assert_eq!(first["processing"]["permitsInUse"], 0);
+    assert!(first["processing"]["maxPermits"].as_u64().unwrap() > 0);
+    assert_eq!(first["processing"]["waiting"], 0);
+    assert!(first["processing"]["workers"].as_u64().unwrap() > 0);
+    assert_eq!(first["processing"]["queued"], 0);
+    assert_eq!(first["cache"]["backend"], "filesystem");
+    assert_eq!(first["cache"]["hits"], 0);
+    assert_eq!(first["cache"]["misses"], 0);
+    assert!(first["cache"]["hitRatio"].is_null());
+    assert_eq!(first["shedRequests"], 0);
+    assert_eq!(first["defaultQuality"]["jpeg"], 75);
+    assert_eq!(first["defaultQuality"]["source"], 75);
+    if cfg!(target_os = "linux") {
+        assert!(first["residentMemoryBytes"].as_u64().unwrap() > 0);
+    }
+
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    let second = app.get("/status").await.json();
+    assert!(second["uptimeMs"].as_u64().unwrap() > first["uptimeMs"].as_u64().unwrap());
+
+    // Cache lookups, also exported as metrics
+    let mock_server = MockServer::start().await;
+    mount_png(&mock_server, "/status.png", fixture_png(8, 8)).await;
+    let src = format!("{}/status.png", mock_server.uri());
+    for _ in 0..3 {
+        assert_eq!(app.optimize(&src, &[("w", "4")]).await.status, 200);
+    }
+    let status = app.get("/status").await.json();
+    assert_eq!(status["cache"]["hits"], 2);
+    assert_eq!(status["cache"]["misses"], 1);
+    let ratio = status["cache"]["hitRatio"].as_f64().unwrap();
+    assert!((ratio - 2.0 / 3.0).abs() < 1e-9, "{ratio}");
+    let metrics = app.get("/metrics").await.text();
+    assert!(
+        metrics.contains("img_optimizer_cache_hits_total 2"),
+        "{metrics}"
+    );
+    assert!(metrics.contains("img_optimizer_cache_misses_total 1"));
+}
+
+#[actix_rt::test]
+async fn test_error_responses_are_counted_by_code() {
+    let app = TestApp::spawn().await;
+
+    for uri in [
+        "/img-optimizer/v1/img",
+        "/img-optimizer/v1/img?src=https://example.com/a.png&w=5000",
+        "/img-optimizer/v1/img?src=https://example.com/a.png&w=6000",
+        "/nowhere",
+    ] {
+        assert!(app.get(uri).await.status.is_client_error());
+    }
+
+    let metrics = app.get("/metrics").await.text();
+    assert!(metrics.contains("img_optimizer_errors_total{code=\"VAL_001\"} 2"));
+    assert!(metrics.contains("img_optimizer_errors_total{code=\"VAL_003\"} 1"));
+    assert!(metrics.contains("img_optimizer_errors_total{code=\"SYS_404\"} 1"));
+}
+
+#[actix_rt::test]
+async fn test_debug_page() {
+    // Disabled by default
+    let app = TestApp::spawn().await;
+    assert_eq!(app.get("/debug").await.status, 404);
+
+    let mut config = AppConfig::default();
+    config.features.debug_page = true;
+    config.storage.admin_token = Some("s3cret".to_string());
+    let app = TestApp::builder().config(config).spawn().await;
+
+    assert_eq!(app.get("/debug").await.status, 401);
+
+    let resp = app
+        .send(
+            app.request(Method::GET, "/debug")
+                .header("Authorization", "Bearer s3cret"),
+        )
+        .await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(
+        resp.header("content-type"),
+        Some("text/html; charset=utf-8")
+    );
+    let body = resp.text();
+    assert!(body.contains("/img-optimizer/v1/img?"));
+    assert!(!body.contains("<script src"));
+}
+
+#[actix_rt::test]
+async fn test_audit_log() {
+    let mock_server = MockServer::start().await;
+    mount_png(&mock_server, "/audited.png", fixture_png(40, 20)).await;
+
+    // Off by default
+    let mut config = AppConfig::default();
+    config.storage.admin_token = Some("s3cret".to_string());
+    let app = TestApp::builder().config(config.clone()).spawn().await;
+    let resp = app
+        .send(
+            app.request(Method::GET, "/img-optimizer/v1/audit")
+                .header("Authorization", "Bearer s3cret"),
+        )
+        .await;
+    assert_eq!(resp.status, 404);
+
+    let audit_dir = TempDir::new().unwrap();
+    config.audit.dir = Some(audit_dir.path().to_path_buf());
+    let app = TestApp::builder().config(config).spawn().await;
+
+    let src = format!("{}/audited.png", mock_server.uri());
+    assert_eq!(app.optimize(&src, &[("w", "20")]).await.status, 200);
+    // Served from the cache, nothing fetched
+    assert_eq!(app.optimize(&src, &[("w", "20")]).await.status, 200);
+    let missing = format!("{}/missing.png", mock_server.uri());
+    assert_eq!(app.optimize(&missing, &[]).await.status, 404);
+    // Only remote sources are audited
+    app.optimize("data:image/png;base64,AA==", &[]).await;
+
+    let audit = |query: &'static [(&'static str, &'static str)]| {
+        let app = &app;
+        async move {
+            app.send(
+                app.request(Method::GET, "/img-optimizer/v1/audit")
+                    .query(query)
+                    .header("Authorization", "Bearer s3cret"),
+            )
+            .await
+        }
+    };
+    assert_eq!(app.get("/img-optimizer/v1/audit").await.status, 401);
+
+    let resp = audit(&[]).await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("cache-control"), Some("no-store"));
+    let body = resp.json();
+    assert_eq!(body["total"], 3);
+    let sources = body["sources"].as_array().unwrap();
+    assert_eq!(sources.len(), 2);
+    assert_eq!(sources[0]["host"], "127.0.0.1");
+    assert_eq!(sources[0]["path"], "/audited.png");
+    assert_eq!(sources[0]["count"], 2);
+    assert_eq!(sources[0]["statuses"], serde_json::json!({ "200": 2 }));
+    assert_eq!(
+        sources[0]["bytesIn"].as_u64().unwrap(),
+        fixture_png(40, 20).len() as u64
+    );
+    assert!(sources[0]["bytesOut"].as_u64().unwrap() > 0);
+    assert_eq!(sources[1]["path"], "/missing.png");
+    assert_eq!(sources[1]["errors"], serde_json::json!({ "IMG_008": 1 }));
+
+    let body = audit(&[("host", "images.example")]).await.json();
+    assert_eq!(body["total"], 0);
+    let body = audit(&[("since", "2999-01-01")]).await.json();
+    assert_eq!(body["total"], 0);
+    let resp = audit(&[("since", "last week")]).await;
+    assert_eq!(resp.status, 400);
+    assert_eq!(resp.json()["errorCode"], "VAL_007");
+}
+
+#[actix_rt::test]
+async fn test_readiness_during_shutdown() {
+    let app = TestApp::spawn().await;
+
+    let resp = app.get("/health/ready").await;
+    assert_eq!(resp.status, 200);
+
+    app.state.shutting_down.store(true, Ordering::SeqCst);
+
+    let resp = app.get("/health/ready").await;
+    assert_eq!(resp.status, 503);
+    assert_eq!(resp.json()["status"], "shutting_down");
+
+    // Liveness is unaffected
+    let resp = app.get("/health").await;
+    assert_eq!(resp.status, 200);
+}
+
+#[actix_rt::test]
+async fn test_readiness_checks() {
+    let canary_server = MockServer::start().await;
+    Mock::given(method("HEAD"))
+        .and(path("/canary.png"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&canary_server)
+        .await;
+
+    let mut config = AppConfig::default();
+    config.health.canary_url = Some(format!("{}/canary.png", canary_server.uri()));
+    let app = TestApp::builder().config(config).spawn().await;
+
+    let resp = app.get("/health/live").await;
+    assert_eq!(resp.status, 200);
+
+    let resp = app.get("/health/ready").await;
+    assert_eq!(resp.status, 200);
+    let body = resp.json();
+    assert_eq!(body["status"], "ready");
+    assert_eq!(body["checks"]["cache"]["status"], "ok");
+    assert_eq!(body["checks"]["canary"]["status"], "ok");
+    assert!(body["checks"]["cache"]["latencyMs"].is_number());
+}
+
+#[actix_rt::test]
+async fn test_readiness_with_unwritable_cache() {
+    // A regular file in place of the cache directory makes every write fail,
+    // even when the tests run as root
+    let temp_dir = TempDir::new().unwrap();
+    let cache_dir = temp_dir.path().join("not-a-directory");
+    std::fs::write(&cache_dir, b"").unwrap();
+    let app = TestApp::builder().cache_dir(cache_dir).spawn().await;
+
+    let resp = app.get("/health/ready").await;
+    assert_eq!(resp.status, 503);
+    let body = resp.json();
+    assert_eq!(body["status"], "not_ready");
+    assert_eq!(body["checks"]["cache"]["status"], "failed");
+    assert!(body["checks"]["cache"]["error"].is_string());
+}
+
+#[actix_rt::test]
+async fn test_list_errors() {
+    let app = TestApp::spawn().await;
+
+    let resp = app.get("/errors").await;
+    assert!(resp.status.is_success());
+    let body = resp.json();
+    let errors = body["errors"].as_array().unwrap();
+    assert_eq!(body["total"], errors.len());
+    assert!(errors.contains(&serde_json::json!(
+        "VAL_001: Invalid width - Width must be between 1 and {max}, got {width}"
+    )));
+}
+
+#[actix_rt::test]
+async fn test_list_errors_json_catalog() {
+    let app = TestApp::spawn().await;
+
+    let resp = app.get("/errors?format=json").await;
+    assert!(resp.status.is_success());
+    let body = resp.json();
+    let errors = body["errors"].as_array().unwrap();
+    assert_eq!(body["total"], errors.len());
+
+    let fetch_failed = errors
+        .iter()
+        .find(|entry| entry["code"] == "IMG_002")
+        .unwrap();
+    assert_eq!(fetch_failed["httpStatus"], 422);
+    assert_eq!(fetch_failed["title"], "Processing Error");
+    assert_eq!(
+        fetch_failed["messageTemplate"],
+        "Image fetch failed - Unable to download image from {url}: {reason}"
+    );
+    assert!(fetch_failed["howToFix"].is_string());
+    assert_eq!(
+        fetch_failed["moreInfo"],
+        "https://github.com/fgribreau/plasmic-img-optimizer#error-img_002"
+    );
+
+    // Byte size and pixel count are told apart
+    let too_large = |code: &str| {
+        errors
+            .iter()
+            .find(|entry| entry["code"] == code)
+            .unwrap()
+            .clone()
+    };
+    assert!(too_large("IMG_005")["messageTemplate"]
+        .as_str()
+        .unwrap()
+        .contains("byte limit"));
+    assert!(too_large("IMG_012")["messageTemplate"]
+        .as_str()
+        .unwrap()
+        .contains("pixel limit"));
+
+    let unauthorized = errors
+        .iter()
+        .find(|entry| entry["code"] == "SEC_001")
+        .unwrap();
+    assert_eq!(unauthorized["httpStatus"], 401);
+
+    for (code, status) in [
+        ("SEC_003", 403),
+        ("SEC_004", 403),
+        ("SEC_005", 401),
+        ("SEC_006", 403),
+        ("IMG_014", 400),
+    ] {
+        let entry = errors.iter().find(|entry| entry["code"] == code).unwrap();
+        assert_eq!(entry["httpStatus"], status, "{code}");
+    }
+}
+
+#[actix_rt::test]
+async fn test_supported_formats_follow_available_encoders() {
+    let expected = if cfg!(feature = "webp") {
+        "jpeg, jpg, png, webp"
+    } else {
+        "jpeg, jpg, png"
+    };
+    assert_eq!(OutputFormat::supported_values(), expected);
+    assert_eq!(OutputFormat::parse("jpg"), Some(OutputFormat::Jpeg));
+    assert_eq!(
+        OutputFormat::parse("webp").is_some(),
+        cfg!(feature = "webp")
+    );
+
+    let app = TestApp::spawn().await;
+
+    let resp = app
+        .send(
+            app.request(Method::POST, "/img-optimizer/v1/img?f=gif")
+                .body(fixture_png(8, 8)),
+        )
+        .await;
+    assert_eq!(resp.status, 400);
+    let body = resp.json();
+    assert_eq!(body["errorCode"], "IMG_004");
+    assert_eq!(
+        body["howToFix"],
+        format!("Use one of the supported formats: {expected}. Got 'gif'")
+    );
+
+    let body = app.get("/errors?format=json").await.json();
+    let invalid_format = body["errors"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|entry| entry["code"] == "IMG_004")
+        .unwrap();
+    assert_eq!(
+        invalid_format["howToFix"],
+        format!("Use one of the supported formats: {expected}. Got '{{format}}'")
+    );
+}
+
+#[actix_rt::test]
+async fn test_configured_limits() {
+    let mut config = AppConfig::default();
+    config.limits.max_width = 1000;
+    let app = TestApp::builder().config(config).spawn().await;
+
+    let resp = app
+        .optimize("https://example.com/image.png", &[("w", "1200")])
+        .await;
+
+    assert_eq!(resp.status, 400);
+    let body = resp.json();
+    assert_eq!(body["errorCode"], "VAL_001");
+    assert_eq!(
+        body["detail"],
+        "VAL_001: Invalid width - Width must be between 1 and 1000, got 1200"
+    );
+}
+
+#[actix_rt::test]
+async fn test_server_timing_header() {
+    let mock_server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/timing.png"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(fixture_png(8, 8)))
+        .mount(&mock_server)
+        .await;
+
+    let src = format!("{}/timing.png", &mock_server.uri());
+
+    // Disabled by default
+    let app = TestApp::spawn().await;
+    let resp = app.optimize(&src, &[]).await;
+    assert!(resp.header("server-timing").is_none());
+
+    let mut config = AppConfig::default();
+    config.features.server_timing = true;
+    let app = TestApp::builder().config(config).spawn().await;
+
+    let resp = app.optimize(&src, &[]).await;
+    let server_timing = resp.header("server-timing").unwrap();
+    for phase in [
+        "cache_read;dur=",
+        "fetch;dur=",
+        "decode;dur=",
+        "encode;dur=",
+    ] {
+        assert!(server_timing.contains(phase), "{server_timing}");
+    }
+    assert!(server_timing.ends_with("cache;desc=\"miss\""));
+
+    let resp = app.optimize(&src, &[]).await;
+    let server_timing = resp.header("server-timing").unwrap();
+    assert!(!server_timing.contains("fetch"));
+    assert!(server_timing.ends_with("cache;desc=\"hit\""));
+}
+
+#[cfg(feature = "webp")]
+#[actix_rt::test]
+async fn test_upload_image() {
+    let mut config = AppConfig::default();
+    config.limits.max_image_size = 1024;
+    let app = TestApp::builder().config(config).spawn().await;
+
+    let upload = |uri: &str| app.request(Method::POST, uri);
+
+    // Raw body, no parameters
+    let resp = app
+        .send(
+            upload("/img-optimizer/v1/img")
+                .header("Content-Type", "image/png")
+                .body(fixture_png(8, 8)),
+        )
+        .await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("content-type"), Some("image/png"));
+    let etag = resp.header("etag").unwrap().to_string();
+    assert_eq!(&resp.body[..4], b"\x89PNG");
+
+    // Same bytes are cached under their content hash
+    let resp = app
+        .send(
+            upload("/img-optimizer/v1/img")
+                .header("If-None-Match", etag)
+                .body(fixture_png(8, 8)),
+        )
+        .await;
+    assert_eq!(resp.status, 304);
+
+    // Raw body with parameters
+    let resp = app
+        .send(upload("/img-optimizer/v1/img?w=1&q=80&f=webp").body(fixture_png(8, 8)))
+        .await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("content-type"), Some("image/webp"));
+
+    // Multipart with a single file field
+    let boundary = "X-IMG-OPTIMIZER-BOUNDARY";
+    let mut multipart = format!(
+        "--{boundary}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.png\"\r\n\
+         Content-Type: image/png\r\n\r\n"
+    )
+    .into_bytes();
+    multipart.extend_from_slice(&fixture_png(8, 8));
+    multipart.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+    let resp = app
+        .send(
+            upload("/img-optimizer/v1/img?f=jpeg")
+                .header(
+                    "Content-Type",
+                    format!("multipart/form-data; boundary={boundary}"),
+                )
+                .body(multipart),
+        )
+        .await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("content-type"), Some("image/jpeg"));
+
+    // Bodies over MAX_IMAGE_SIZE are rejected
+    let resp = app
+        .send(upload("/img-optimizer/v1/img").body(vec![0u8; 2048]))
+        .await;
+    assert_eq!(resp.status, 422);
+    let body = resp.json();
+    assert_eq!(body["errorCode"], "IMG_005");
+    assert!(body["detail"].as_str().unwrap().contains("1024 byte limit"));
+
+    // Sources over MAX_SOURCE_PIXELS are rejected from their header alone
+    let resp = app
+        .send(upload("/img-optimizer/v1/img").body(b"P6\n20000 20000\n255\n".to_vec()))
+        .await;
+    assert_eq!(resp.status, 422);
+    let body = resp.json();
+    assert_eq!(body["errorCode"], "IMG_012");
+    assert!(body["detail"].as_str().unwrap().contains("20000x20000"));
+
+    // Empty body
+    let resp = app.send(upload("/img-optimizer/v1/img")).await;
+    assert_eq!(resp.status, 400);
+    assert_eq!(resp.json()["errorCode"], "VAL_003");
+}
+
+#[cfg(feature = "webp")]
+#[actix_rt::test]
+async fn test_internal_storage() {
+    let mut config = AppConfig::default();
+    config.storage.admin_token = Some("s3cret".to_string());
+    let app = TestApp::builder().config(config).spawn().await;
+
+    let uri = "/img-optimizer/v1/img/0123456789abcdef0123456789abcdef.png";
+    let ingest = |token: Option<&str>, body: Vec<u8>| {
+        let mut req = app.request(Method::PUT, uri).body(body);
+        if let Some(token) = token {
+            req = req.header("Authorization", format!("Bearer {token}"));
+        }
+        app.send(req)
+    };
+
+    // Ingestion requires the admin token
+    let resp = ingest(None, fixture_png(8, 8)).await;
+    assert_eq!(resp.status, 401);
+    assert_eq!(resp.json()["errorCode"], "SEC_002");
+    let resp = ingest(Some("wrong"), fixture_png(8, 8)).await;
+    assert_eq!(resp.status, 401);
+
+    // Non-image bodies are rejected
+    let resp = ingest(Some("s3cret"), b"not an image".to_vec()).await;
+    assert_eq!(resp.status, 422);
+
+    let resp = ingest(Some("s3cret"), fixture_png(8, 8)).await;
+    assert_eq!(resp.status, 201);
+    assert_eq!(resp.header("location"), Some(uri));
+
+    // The original is served as stored
+    let resp = app.get(uri).await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("content-type"), Some("image/png"));
+    assert_eq!(resp.header("x-image-width"), Some("8"));
+    assert_eq!(resp.header("x-original-size"), Some("8x8"));
+    assert_eq!(
+        resp.header("etag"),
+        Some("\"0123456789abcdef0123456789abcdef.png\"")
+    );
+    assert!(resp.header("cache-control").is_some());
+    assert_eq!(resp.body, fixture_png(8, 8));
+
+    let resp = app
+        .send(
+            app.request(Method::GET, uri)
+                .header("If-None-Match", "\"0123456789abcdef0123456789abcdef.png\""),
+        )
+        .await;
+    assert_eq!(resp.status, 304);
+
+    // Stored originals go through the optimizer when parameters are given
+    let resp = app.get(&format!("{uri}?f=webp&q=80")).await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("content-type"), Some("image/webp"));
+
+    // Files placed in the storage directory by the operator are served too
+    std::fs::write(
+        app.dir()
+            .join("storage/fedcba9876543210fedcba9876543210.png"),
+        fixture_png(8, 8),
+    )
+    .unwrap();
+    let resp = app
+        .get("/img-optimizer/v1/img/fedcba9876543210fedcba9876543210.png?w=1")
+        .await;
+    assert_eq!(resp.status, 200);
+
+    // Originals are labelled by their content, not their extension
+    std::fs::write(
+        app.dir()
+            .join("storage/00000000000000000000000000000000.jpg"),
+        fixture_png(8, 8),
+    )
+    .unwrap();
+    let resp = app
+        .get("/img-optimizer/v1/img/00000000000000000000000000000000.jpg")
+        .await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("content-type"), Some("image/png"));
+}
+
+#[actix_rt::test]
+async fn test_direct_image_static_file_semantics() {
+    let app = TestApp::spawn().await;
+    let storage = app.dir().join("storage");
+    std::fs::create_dir_all(&storage).unwrap();
+    let place = |id: &str, data: &[u8]| std::fs::write(storage.join(id), data).unwrap();
+    let uri = |id: &str| format!("/img-optimizer/v1/img/{id}");
+
+    let id = "0123456789abcdef0123456789abcdef.png";
+    place(id, &fixture_png(8, 8));
+    let resp = app.get(&uri(id)).await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("content-type"), Some("image/png"));
+    assert_eq!(
+        resp.header("content-length"),
+        Some(fixture_png(8, 8).len().to_string().as_str())
+    );
+    assert_eq!(resp.header("etag"), Some(format!("\"{id}\"").as_str()));
+    // The id names the content, so it never needs revalidating
+    assert_eq!(
+        resp.header("cache-control"),
+        Some("public, max-age=31536000, immutable")
+    );
+
+    let resp = app
+        .send(
+            app.request(Method::GET, &uri(id))
+                .header("If-None-Match", format!("\"{id}\"")),
+        )
+        .await;
+    assert_eq!(resp.status, 304);
+    assert_eq!(resp.header("etag"), Some(format!("\"{id}\"").as_str()));
+    assert_eq!(
+        resp.header("cache-control"),
+        Some("public, max-age=31536000, immutable")
+    );
+    assert!(resp.body.is_empty());
+
+    // Images fetched by URL may change, and are revalidated
+    let mock_server = MockServer::start().await;
+    mount_png(&mock_server, "/a.png", fixture_png(8, 8)).await;
+    let resp = app
+        .optimize(&format!("{}/a.png", mock_server.uri()), &[])
+        .await;
+    assert_eq!(
+        resp.header("cache-control"),
+        Some("public, max-age=31536000")
+    );
+
+    // AVIF originals are served as stored
+    let avif = b"\0\0\0\x1cftypavif\0\0\0\0avifmif1miaf\0\0\0\0";
+    place("11111111111111111111111111111111.avif", avif);
+    let resp = app.get(&uri("11111111111111111111111111111111.avif")).await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("content-type"), Some("image/avif"));
+    assert_eq!(resp.body, avif.as_slice());
+
+    // Labelled by their content when the extension is wrong
+    place("22222222222222222222222222222222.png", &fixture_jpeg(8, 8));
+    let resp = app.get(&uri("22222222222222222222222222222222.png")).await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("content-type"), Some("image/jpeg"));
+
+    // Content that isn't a raster image is never served from this origin
+    for content in [
+        b"<html><script>alert(1)</script></html>".as_slice(),
+        b"<svg xmlns=\"http://www.w3.org/2000/svg\"><script>alert(1)</script></svg>",
+    ] {
+        place("33333333333333333333333333333333.png", content);
+        let resp = app.get(&uri("33333333333333333333333333333333.png")).await;
+        assert_eq!(resp.status, 422);
+        assert_eq!(resp.json()["errorCode"], "IMG_006");
+    }
+}
+
+#[actix_rt::test]
+async fn test_byte_ranges() {
+    let mock_server = MockServer::start().await;
+    mount_png(&mock_server, "/large.png", fixture_png(64, 64)).await;
+    let app = TestApp::spawn().await;
+    let src = format!("{}/large.png", mock_server.uri());
+    let params = [("w", "32")];
+
+    let full = app.optimize(&src, &params).await;
+    assert_eq!(full.status, 200);
+    assert_eq!(full.header("accept-ranges"), Some("bytes"));
+    let length = full.body.len();
+    let etag = full.header("etag").unwrap().to_string();
+
+    // The cached entry, a range from the middle
+    let ranged = |value: &str| app.optimize_request(&src, &params).header("Range", value);
+    let resp = app.send(ranged("bytes=10-19")).await;
+    assert_eq!(resp.status, 206);
+    assert_eq!(
+        resp.header("content-range"),
+        Some(format!("bytes 10-19/{length}").as_str())
+    );
+    assert_eq!(resp.header("content-length"), Some("10"));
+    assert_eq!(resp.header("etag"), Some(etag.as_str()));
+    assert_eq!(resp.body, full.body[10..20]);
+
+    // A suffix range
+    let resp = app.send(ranged("bytes=-16")).await;
+    assert_eq!(resp.status, 206);
+    assert_eq!(
+        resp.header("content-range"),
+        Some(format!("bytes {}-{}/{length}", length - 16, length - 1).as_str())
+    );
+    assert_eq!(resp.body, full.body[length - 16..]);
+
+    // Past the end
+    let resp = app.send(ranged(&format!("bytes={length}-"))).await;
+    assert_eq!(resp.status, 416);
+    assert_eq!(
+        resp.header("content-range"),
+        Some(format!("bytes */{length}").as_str())
+    );
+    assert_eq!(resp.json()["errorCode"], "SYS_416");
+
+    // Multipart ranges, and ranges of another representation, get it all
+    let resp = app.send(ranged("bytes=0-9,20-29")).await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.body, full.body);
+    let resp = app
+        .send(ranged("bytes=0-9").header("If-Range", "\"stale\""))
+        .await;
+    assert_eq!(resp.status, 200);
+    let resp = app
+        .send(ranged("bytes=0-9").header("If-Range", &etag))
+        .await;
+    assert_eq!(resp.status, 206);
+
+    // Stored originals on the direct image route
+    let storage = app.dir().join("storage");
+    std::fs::create_dir_all(&storage).unwrap();
+    let original = fixture_png(16, 16);
+    std::fs::write(
+        storage.join("0123456789abcdef0123456789abcdef.png"),
+        &original,
+    )
+    .unwrap();
+    let uri = "/img-optimizer/v1/img/0123456789abcdef0123456789abcdef.png";
+    let resp = app.get(uri).await;
+    assert_eq!(resp.header("accept-ranges"), Some("bytes"));
+    let resp = app
+        .send(app.request(Method::GET, uri).header("Range", "bytes=8-15"))
+        .await;
+    assert_eq!(resp.status, 206);
+    assert_eq!(
+        resp.header("content-range"),
+        Some(format!("bytes 8-15/{}", original.len()).as_str())
+    );
+    assert_eq!(resp.body, original[8..16]);
+    let resp = app
+        .send(app.request(Method::GET, uri).header("Range", "bytes=-0"))
+        .await;
+    assert_eq!(resp.status, 416);
+}
+
+#[actix_rt::test]
+async fn test_config_reload() {
+    let mock_server = MockServer::start().await;
+    mount_png(&mock_server, "/before.png", fixture_png(200, 100)).await;
+    mount_png(&mock_server, "/after.png", fixture_png(200, 100)).await;
+
+    let app = TestApp::spawn().await;
+    let resp = app
+        .optimize(&format!("{}/before.png", mock_server.uri()), &[])
+        .await;
+    assert_eq!(resp.status, 200);
+
+    let config_file = app.dir().join("config.toml");
+    std::fs::write(
+        &config_file,
+        "[fetch]\nallowed_hosts = [\"images.example.com\"]\n\n[cache]\ndir = \"/elsewhere\"\n",
+    )
+    .unwrap();
+    let changes = app
+        .state
+        .reload_config(AppConfig::from_file(&config_file).unwrap())
+        .unwrap();
+    let reloadable = |path: &str| {
+        changes
+            .iter()
+            .find(|change| change.path == path)
+            .unwrap()
+            .reloadable
+    };
+    assert!(reloadable("fetch.allowed_hosts"));
+    // Moving the cache needs a restart
+    assert!(!reloadable("cache.dir"));
+    assert_eq!(app.state.config().cache.dir, app.dir());
+
+    let resp = app
+        .optimize(&format!("{}/after.png", mock_server.uri()), &[])
+        .await;
+    assert_eq!(resp.status, 403);
+    assert_eq!(resp.json()["errorCode"], "SEC_004");
+
+    // An invalid configuration keeps the current one
+    let mut invalid = AppConfig::default();
+    invalid.limits.default_quality = 0;
+    assert!(app.state.reload_config(invalid).is_err());
+    assert_eq!(
+        app.state.config().fetch.allowed_hosts,
+        ["images.example.com"]
+    );
+}
+
+#[actix_rt::test]
+async fn test_upload_returns_stable_id() {
+    let app = TestApp::builder()
+        .api_keys(ApiKeys::parse("uploader:k3y"))
+        .spawn()
+        .await;
+
+    let upload = |body: Vec<u8>| {
+        app.send(
+            app.request(Method::POST, "/img-optimizer/v1/upload")
+                .header("X-Api-Key", "k3y")
+                .body(body),
+        )
+    };
+
+    let resp = app
+        .send(
+            app.request(Method::POST, "/img-optimizer/v1/upload")
+                .body(fixture_png(8, 8)),
+        )
+        .await;
+    assert_eq!(resp.status, 401);
+
+    let resp = upload(fixture_png(8, 8)).await;
+    assert_eq!(resp.status, 201);
+    let body = resp.json();
+    let id = body["id"].as_str().unwrap().to_string();
+    assert!(IMAGE_ID_REGEX.is_match(&id));
+    assert!(id.ends_with(".png"));
+    assert_eq!(body["width"], 8);
+    assert_eq!(body["height"], 8);
+    assert_eq!(body["size"], fixture_png(8, 8).len());
+
+    // Identical bytes map to the same, already stored, id
+    let stored = app.dir().join("storage").join(&id);
+    let stored_at = std::fs::metadata(&stored).unwrap().modified().unwrap();
+    let resp = upload(fixture_png(8, 8)).await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.json()["id"], id.as_str());
+    let modified = std::fs::metadata(&stored).unwrap().modified().unwrap();
+    assert_eq!(stored_at, modified);
+
+    // The id is immediately servable
+    let resp = app
+        .send(
+            app.request(Method::GET, &format!("/img-optimizer/v1/img/{id}"))
+                .header("X-Api-Key", "k3y"),
+        )
+        .await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.body, fixture_png(8, 8));
+
+    let resp = upload(b"definitely not an image".to_vec()).await;
+    assert_eq!(resp.status, 422);
+    assert_eq!(resp.json()["errorCode"], "IMG_006");
+}
+
+#[cfg(feature = "webp")]
+#[actix_rt::test]
+async fn test_data_and_blob_urls() {
+    use base64::{engine::general_purpose, Engine as _};
+
+    let mut config = AppConfig::default();
+    config.limits.max_image_size = 1024;
+    let app = TestApp::builder().config(config).spawn().await;
+
+    let data_url = format!(
+        "data:image/png;base64,{}",
+        general_purpose::STANDARD.encode(fixture_png(8, 8))
+    );
+
+    let resp = app.optimize(&data_url, &[]).await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("content-type"), Some("image/png"));
+    let etag = resp.header("etag").unwrap().to_string();
+
+    // Keyed on the payload, so the same data URL hits the cache
+    let resp = app
+        .send(
+            app.optimize_request(&data_url, &[])
+                .header("If-None-Match", etag),
+        )
+        .await;
+    assert_eq!(resp.status, 304);
+
+    let resp = app.optimize(&data_url, &[("f", "webp")]).await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("content-type"), Some("image/webp"));
+
+    let resp = app.optimize("data:text/plain;base64,aGVsbG8=", &[]).await;
+    assert_eq!(resp.status, 400);
+    assert_eq!(resp.json()["errorCode"], "IMG_004");
+
+    let oversized = format!(
+        "data:image/png;base64,{}",
+        general_purpose::STANDARD.encode(vec![0u8; 2048])
+    );
+    let resp = app.optimize(&oversized, &[]).await;
+    assert_eq!(resp.status, 422);
+    assert_eq!(resp.json()["errorCode"], "IMG_005");
+
+    let resp = app
+        .optimize(
+            "blob:https://example.com/550e8400-e29b-41d4-a716-446655440000",
+            &[],
+        )
+        .await;
+    assert_eq!(resp.status, 400);
+    let body = resp.json();
+    assert_eq!(body["errorCode"], "IMG_001");
+    assert!(body["detail"]
+        .as_str()
+        .unwrap()
+        .contains("'blob:' URLs cannot be fetched"));
+
+    let resp = app.optimize("ftp://example.com/image.png", &[]).await;
+    assert_eq!(resp.status, 400);
+    assert_eq!(resp.json()["errorCode"], "IMG_001");
+
+    if !cfg!(feature = "s3-source") {
+        let resp = app.optimize("s3://originals/image.png", &[]).await;
+        assert_eq!(resp.status, 400);
+        let body = resp.json();
+        assert_eq!(body["errorCode"], "IMG_001");
+        assert!(body["detail"].as_str().unwrap().contains("s3-source"));
+    }
+}
+
+#[actix_rt::test]
+async fn test_base64url_src() {
+    use base64::{engine::general_purpose, Engine as _};
+    use wiremock::matchers::query_param;
+
+    let mock_server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/images/caf%C3%A9.png"))
+        .and(query_param("size", "large"))
+        .and(query_param("v", "2"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(fixture_png(8, 8)))
+        .expect(1) // Encoded and plain forms share the cache entry
+        .mount(&mock_server)
+        .await;
+
+    let app = TestApp::spawn().await;
+
+    let src = format!("{}/images/café.png?size=large&v=2", mock_server.uri());
+    let srcb64 = |src_b64: &str, params: &[(&str, &str)]| {
+        app.send(
+            app.request(Method::GET, "/img-optimizer/v1/img")
+                .query(&[("srcb64", src_b64)])
+                .query(params),
+        )
+    };
+
+    let resp = srcb64(
+        &general_purpose::URL_SAFE_NO_PAD.encode(&src),
+        &[("w", "1")],
+    )
+    .await;
+    assert_eq!(resp.status, 200);
+    let etag = resp.header("etag").unwrap().to_string();
+
+    let resp = app.optimize(&src, &[("w", "1")]).await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("etag"), Some(etag.as_str()));
+
+    // Padded base64url is accepted too
+    let resp = srcb64(&general_purpose::URL_SAFE.encode(&src), &[("w", "1")]).await;
+    assert_eq!(resp.status, 200);
+
+    let resp = srcb64(
+        &general_purpose::URL_SAFE_NO_PAD.encode(&src),
+        &[("src", &src)],
+    )
+    .await;
+    assert_eq!(resp.status, 400);
+    assert_eq!(resp.json()["errorCode"], "VAL_004");
+
+    let resp = app.get("/img-optimizer/v1/img?srcb64=not*base64").await;
+    assert_eq!(resp.status, 400);
+    assert_eq!(resp.json()["errorCode"], "IMG_001");
+}
+
+#[actix_rt::test]
+async fn test_path_options_grammar() {
+    type Expected<'a> = (&'a str, Option<u32>, Option<u32>, Option<&'a str>);
+    let valid: &[Expected] = &[
+        ("-", None, None, None),
+        ("w_800", Some(800), None, None),
+        ("q_75", None, Some(75), None),
+        ("f_webp", None, None, Some("webp")),
+        ("w_800,q_75,f_webp", Some(800), Some(75), Some("webp")),
+        ("f_png,w_1", Some(1), None, Some("png")),
+    ];
+    for (options, w, q, f) in valid {
+        let params = path_options::parse(options, "aHR0cHM6Ly9leGFtcGxlLmNvbS9hLnBuZw").unwrap();
+        assert_eq!(params.w, w.map(|w| w.to_string()), "{options}");
+        assert_eq!(params.q, q.map(|q| q.to_string()), "{options}");
+        assert_eq!(params.f.as_deref(), *f, "{options}");
+        assert!(params.src.is_none());
+        assert_eq!(
+            params.source().unwrap(),
+            "https://example.com/a.png",
+            "{options}"
+        );
+    }
+
+    let invalid: &[(&str, &str)] = &[
+        ("", ""),
+        ("w800", "w800"),
+        ("w_abc", "w_abc"),
+        ("q_300", "q_300"),
+        ("w_800,fit_cover", "fit_cover"),
+        ("w_800,w_900", "w_900"),
+        ("f_", "f_"),
+        ("w_800,,q_75", ""),
+    ];
+    for (options, token) in invalid {
+        match path_options::parse(options, "aHR0cHM6Ly9leGFtcGxlLmNvbS9hLnBuZw") {
+            Err(AppError::InvalidOption { token: found }) => assert_eq!(found, *token),
+            other => panic!("{options}: expected InvalidOption, got {other:?}"),
+        }
+    }
+}
+
+#[cfg(feature = "webp")]
+#[actix_rt::test]
+async fn test_path_style_urls() {
+    use base64::{engine::general_purpose, Engine as _};
+
+    let mock_server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/path-style.png"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(fixture_png(8, 8)))
+        .expect(1) // Shares the cache with the query API
+        .mount(&mock_server)
+        .await;
+
+    let app = TestApp::spawn().await;
+
+    let src = format!("{}/path-style.png", mock_server.uri());
+    let src_b64 = general_purpose::URL_SAFE_NO_PAD.encode(&src);
+
+    let resp = app
+        .get(&format!("/img-optimizer/v1/t/w_1,q_80,f_webp/{src_b64}"))
+        .await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("content-type"), Some("image/webp"));
+    let etag = resp.header("etag").unwrap().to_string();
+
+    let resp = app
+        .optimize(&src, &[("w", "1"), ("q", "80"), ("f", "webp")])
+        .await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("etag"), Some(etag.as_str()));
+
+    let resp = app
+        .get(&format!("/img-optimizer/v1/t/w_1,fit_cover/{src_b64}"))
+        .await;
+    assert_eq!(resp.status, 400);
+    let body = resp.json();
+    assert_eq!(body["errorCode"], "VAL_005");
+    assert!(body["detail"].as_str().unwrap().contains("'fit_cover'"));
+
+    // Values are validated like the query API
+    let resp = app
+        .get(&format!("/img-optimizer/v1/t/w_5000/{src_b64}"))
+        .await;
+    assert_eq!(resp.status, 400);
+    assert_eq!(resp.json()["errorCode"], "VAL_001");
+}
+
+#[actix_rt::test]
+async fn test_nextjs_image_compat() {
+    let mock_server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/assets/hero.png"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(fixture_png(1280, 720)))
+        .mount(&mock_server)
+        .await;
+
+    let src = format!("{}/assets/hero.png", mock_server.uri());
+    let encoded = url::form_urlencoded::byte_serialize(src.as_bytes()).collect::<String>();
+
+    // Not served unless enabled
+    let app = TestApp::spawn().await;
+    let resp = app
+        .get(&format!("/_next/image?url={encoded}&w=640&q=75"))
+        .await;
+    assert_eq!(resp.status, 404);
+
+    let mut config = AppConfig::default();
+    config.features.nextjs_compat = true;
+    let app = TestApp::builder().config(config).spawn().await;
+
+    // What next/image requests through a custom loader
+    let resp = app
+        .send(
+            app.request(
+                Method::GET,
+                &format!("/_next/image?url={encoded}&w=640&q=75"),
+            )
+            .header("Accept", "image/avif,image/webp,*/*"),
+        )
+        .await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(
+        resp.header("content-security-policy"),
+        Some("script-src 'none'; frame-src 'none'; sandbox;")
+    );
+    assert_eq!(
+        resp.header("content-disposition"),
+        Some("inline; filename=\"hero.png\"")
+    );
+    assert_eq!(resp.header("x-image-width"), Some("640"));
+    let etag = resp.header("etag").unwrap().to_string();
+
+    // `url` is also accepted in place of `src` on the regular endpoint
+    let resp = app
+        .get(&format!("/img-optimizer/v1/img?url={encoded}&w=640&q=75"))
+        .await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("etag"), Some(etag.as_str()));
+
+    for (query, param) in [
+        ("w=640&q=75".to_string(), "url"),
+        (format!("url={encoded}&q=75"), "w"),
+        (format!("url={encoded}&w=640"), "q"),
+    ] {
+        let resp = app.get(&format!("/_next/image?{query}")).await;
+        assert_eq!(resp.status, 400, "{query}");
+        let body = resp.json();
+        assert_eq!(body["errorCode"], "VAL_003");
+        assert!(body["detail"].as_str().unwrap().contains(param), "{query}");
+    }
+
+    let resp = app
+        .get(&format!("/_next/image?url={encoded}&w=640&q=0"))
+        .await;
+    assert_eq!(resp.status, 400);
+}
+
+#[actix_rt::test]
+async fn test_imgix_translation() {
+    // (imgix query, expected w, h, fit, bg, q, f, ignored)
+    type Expected<'a> = (
+        &'a str,
+        Option<u32>,
+        Option<u32>,
+        Option<&'a str>,
+        Option<&'a str>,
+        Option<u32>,
+        Option<&'a str>,
+        &'a [&'a str],
+    );
+    let cases: &[Expected] = &[
+        ("w=800", Some(800), None, None, None, None, None, &[]),
+        (
+            "w=800&h=600",
+            Some(800),
+            Some(600),
+            None,
+            None,
+            None,
+            None,
+            &[],
+        ),
+        (
+            "h=600&fit=crop",
+            None,
+            Some(600),
+            Some("cover"),
+            None,
+            None,
+            None,
+            &[],
+        ),
+        (
+            "fit=clip",
+            None,
+            None,
+            Some("contain"),
+            None,
+            None,
+            None,
+            &[],
+        ),
+        (
+            "fit=max",
+            None,
+            None,
+            Some("contain"),
+            None,
+            None,
+            None,
+            &[],
+        ),
+        (
+            "fit=fill&bg=fff",
+            None,
+            None,
+            Some("pad"),
+            Some("ffffffff"),
+            None,
+            None,
+            &[],
+        ),
+        (
+            "bg=8000ff00",
+            None,
+            None,
+            None,
+            Some("00ff0080"),
+            None,
+            None,
+            &[],
+        ),
+        (
+            "bg=F0AB",
+            None,
+            None,
+            None,
+            Some("00aabbff"),
+            None,
+            None,
+            &[],
+        ),
+        (
+            "bg=112233",
+            None,
+            None,
+            None,
+            Some("112233ff"),
+            None,
+            None,
+            &[],
+        ),
+        (
+            "fm=webp&q=60",
+            None,
+            None,
+            None,
+            None,
+            Some(60),
+            Some("webp"),
+            &[],
+        ),
+        ("fm=pjpg", None, None, None, None, None, Some("jpeg"), &[]),
+        ("auto=compress", None, None, None, None, Some(45), None, &[]),
+        (
+            "auto=compress&q=80",
+            None,
+            None,
+            None,
+            None,
+            Some(80),
+            None,
+            &[],
+        ),
+        (
+            "auto=format,compress",
+            None,
+            None,
+            None,
+            None,
+            Some(45),
+            None,
+            &["auto=format"],
+        ),
+        ("fm=avif", None, None, None, None, None, None, &["fm=avif"]),
+        (
+            "fit=facearea",
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            &["fit=facearea"],
+        ),
+        (
+            "crop=faces,entropy",
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            &["crop=faces,entropy"],
+        ),
+        (
+            "w=400&blur=20&sat=-50",
+            Some(400),
+            None,
+            None,
+            None,
+            None,
+            None,
+            &["blur=20", "sat=-50"],
+        ),
+    ];
+
+    for (query, w, h, fit, bg, q, f, ignored) in cases {
+        let query = format!("src=https://example.com/a.png&{query}");
+        let translation = imgix::translate(&query, false).unwrap();
+        let params = translation.params;
+        assert_eq!(params.src.as_deref(), Some("https://example.com/a.png"));
+        assert_eq!(params.w, w.map(|w| w.to_string()), "{query}");
+        assert_eq!(params.h, h.map(|h| h.to_string()), "{query}");
+        assert_eq!(params.fit.as_deref(), *fit, "{query}");
+        assert_eq!(params.bg.as_deref(), *bg, "{query}");
+        assert_eq!(params.q, q.map(|q| q.to_string()), "{query}");
+        assert_eq!(params.f.as_deref(), *f, "{query}");
+        assert_eq!(translation.ignored, *ignored, "{query}");
+
+        match imgix::translate(&query, true) {
+            Ok(_) => assert!(ignored.is_empty(), "{query}"),
+            Err(AppError::UnsupportedParameter { param }) => assert_eq!(param, ignored[0]),
+            Err(other) => panic!("{query}: unexpected {other:?}"),
+        }
+    }
+
+    assert!(imgix::is_imgix_query("src=a&fm=webp"));
+    assert!(imgix::is_imgix_query("src=a&fit=crop"));
+    assert!(!imgix::is_imgix_query("src=a&w=100&fit=cover"));
+}
+
+#[actix_rt::test]
+async fn test_fit_and_imgix_requests() {
+    let mock_server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/wide.png"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(fixture_png(400, 200)))
+        .mount(&mock_server)
+        .await;
+
+    let app = TestApp::spawn().await;
+    let src = format!("{}/wide.png", mock_server.uri());
+
+    for (query, expected) in [
+        ("w=100", (100, 50)),
+        ("h=50", (100, 50)),
+        ("w=100&h=100", (100, 50)),
+        ("w=100&h=100&fit=cover", (100, 100)),
+        ("w=100&h=100&fit=pad&bg=ffffff", (100, 100)),
+        ("w=800&h=800", (400, 200)),
+        // imgix vocabulary
+        ("w=100&h=100&fit=crop", (100, 100)),
+        ("w=100&h=100&fit=clip&fm=png", (100, 50)),
+    ] {
+        let resp = app
+            .get(&format!("/img-optimizer/v1/img?src={src}&{query}"))
+            .await;
+        assert_eq!(resp.status, 200, "{query}");
+        let img = image::load_from_memory(&resp.body).unwrap();
+        assert_eq!((img.width(), img.height()), expected, "{query}");
+    }
+
+    // Unsupported imgix parameters are reported
+    let resp = app
+        .optimize(
+            &src,
+            &[("w", "100"), ("auto", "format,compress"), ("crop", "faces")],
+        )
+        .await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(
+        resp.header("x-imgix-ignored"),
+        Some("auto=format, crop=faces")
+    );
+
+    for (query, code) in [
+        ("h=0", "VAL_006"),
+        ("h=5000", "VAL_006"),
+        ("w=100&h=100&fit=stretch", "VAL_007"),
+        ("bg=red", "VAL_007"),
+    ] {
+        let resp = app
+            .get(&format!("/img-optimizer/v1/img?src={src}&{query}"))
+            .await;
+        assert_eq!(resp.status, 400, "{query}");
+        assert_eq!(resp.json()["errorCode"], code, "{query}");
+    }
+
+    // Strict mode rejects them
+    let mut config = AppConfig::default();
+    config.imgix.strict = true;
+    let app = TestApp::builder().config(config).spawn().await;
+    let resp = app.optimize(&src, &[("w", "100"), ("crop", "faces")]).await;
+    assert_eq!(resp.status, 400);
+    let body = resp.json();
+    assert_eq!(body["errorCode"], "VAL_008");
+    assert!(body["detail"].as_str().unwrap().contains("crop=faces"));
+}
+
+#[actix_rt::test]
+async fn test_local_file_sources() {
+    let temp_dir = TempDir::new().unwrap();
+    let root = temp_dir.path().join("originals");
+    std::fs::create_dir_all(root.join("sub")).unwrap();
+    std::fs::write(root.join("pixel.png"), fixture_png(8, 8)).unwrap();
+    std::fs::write(temp_dir.path().join("secret.png"), fixture_png(8, 8)).unwrap();
+    std::os::unix::fs::symlink(temp_dir.path().join("secret.png"), root.join("link.png")).unwrap();
+    std::os::unix::fs::symlink(temp_dir.path(), root.join("escape")).unwrap();
+
+    let file_url = |path: &str| format!("file://{}/{path}", root.display());
+
+    // Disabled without a root
+    let app = TestApp::spawn().await;
+    let resp = app.optimize(&file_url("pixel.png"), &[]).await;
+    assert_eq!(resp.status, 400);
+    let body = resp.json();
+    assert_eq!(body["errorCode"], "IMG_001");
+    assert!(body["detail"]
+        .as_str()
+        .unwrap()
+        .contains("LOCAL_SOURCE_ROOT"));
+
+    let mut config = AppConfig::default();
+    config.fetch.local_source_root = Some(root.clone());
+    config.limits.max_image_size = 1024;
+    let app = TestApp::builder().config(config).spawn().await;
+
+    let resp = app.optimize(&file_url("pixel.png"), &[]).await;
+    assert_eq!(resp.status, 200);
+
+    for path in [
+        "../secret.png",
+        "%2e%2e/secret.png",
+        "sub/../../secret.png",
+        "link.png",
+        "escape/secret.png",
+    ] {
+        let resp = app.optimize(&file_url(path), &[]).await;
+        assert_eq!(resp.status, 403, "{path}");
+        let body = resp.json();
+        assert_eq!(body["errorCode"], "SEC_003", "{path}");
+        assert_eq!(
+            body["detail"],
+            "SEC_003: Destination blocked - The source cannot be fetched by this service",
+            "{path}"
+        );
+    }
+
+    for (path, reason) in [("missing.png", "does not exist"), ("sub", "not a file")] {
+        let resp = app.optimize(&file_url(path), &[]).await;
+        assert_eq!(resp.status, 422, "{path}");
+        let body = resp.json();
+        assert_eq!(body["errorCode"], "IMG_002", "{path}");
+        let detail = body["detail"].as_str().unwrap();
+        assert!(detail.contains(reason), "{path}: {detail}");
+        assert!(
+            !detail.contains(&*temp_dir.path().to_string_lossy()),
+            "{path}: {detail}"
+        );
+    }
+
+    std::fs::write(root.join("large.png"), vec![0u8; 2048]).unwrap();
+    let resp = app.optimize(&file_url("large.png"), &[]).await;
+    assert_eq!(resp.json()["errorCode"], "IMG_005");
+
+    let resp = app.optimize("file://fileserver/srv/pixel.png", &[]).await;
+    assert_eq!(resp.status, 400);
 }
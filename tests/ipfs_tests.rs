@@ -0,0 +1,209 @@
+#![cfg(feature = "runtime")]
+//! `ipfs://` sources: CID validation, rewriting to gateway URLs, and the
+//! fallback gateway, against mock gateways.
+
+use tempfile::TempDir;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use img_optimizer::cache::ImageCache;
+use img_optimizer::config::AppConfig;
+use img_optimizer::ipfs::{is_valid_cid, IpfsPath};
+use img_optimizer::test_support::fixture_png;
+use img_optimizer::{CacheStatus, OptimizeOptions, Optimizer};
+
+const CID_V0: &str = "QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG";
+const CID_V1: &str = "bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi";
+const CID_V1_RAW: &str = "bafkreigh2akiscaildcqabsyg3dfr6chu3fgpregiymsck7e7aqa4s52zy";
+
+fn optimizer(gateway: &str, fallback_gateway: Option<&str>, cache: ImageCache) -> Optimizer {
+    let mut config = AppConfig::default();
+    config.ipfs.gateway = Some(gateway.to_string());
+    config.ipfs.fallback_gateway = fallback_gateway.map(str::to_string);
+    Optimizer::builder().config(config).cache(cache).build()
+}
+
+#[test]
+fn test_cid_validation() {
+    for cid in [CID_V0, CID_V1, CID_V1_RAW] {
+        assert!(is_valid_cid(cid), "{cid}");
+    }
+
+    let invalid = [
+        "",
+        "Qm",
+        // One character short, or with one more
+        &CID_V0[..45],
+        &format!("{CID_V0}a"),
+        // 0, O, I and l aren't base58
+        &CID_V0.replace('Y', "0"),
+        // Upper case isn't the base32 alphabet
+        &CID_V1.to_uppercase(),
+        &CID_V1.replace('y', "1"),
+        // The digest is shorter than its length says
+        &CID_V1[..CID_V1.len() - 4],
+        "b",
+        "bafy",
+        "images.example",
+        "not-a-cid",
+    ];
+    for cid in invalid {
+        assert!(!is_valid_cid(cid), "{cid:?}");
+    }
+}
+
+#[test]
+fn test_rewriting() {
+    let path = IpfsPath::parse(&format!("ipfs://{CID_V1}/art/./a b.png?filename=x#top")).unwrap();
+    assert_eq!((path.cid(), path.path()), (CID_V1, "/art/a%20b.png"));
+    assert_eq!(path.canonical(), format!("ipfs://{CID_V1}/art/a%20b.png"));
+    for gateway in [
+        "https://cloudflare-ipfs.com/ipfs/",
+        "https://cloudflare-ipfs.com/ipfs",
+    ] {
+        assert_eq!(
+            path.gateway_url(gateway),
+            format!("https://cloudflare-ipfs.com/ipfs/{CID_V1}/art/a%20b.png")
+        );
+    }
+
+    // Content that is itself the image
+    let path = IpfsPath::parse(&format!("ipfs://{CID_V0}/")).unwrap();
+    assert_eq!(path.canonical(), format!("ipfs://{CID_V0}"));
+    assert_eq!(
+        path.gateway_url("https://ipfs.io/ipfs/"),
+        format!("https://ipfs.io/ipfs/{CID_V0}")
+    );
+
+    let err = IpfsPath::parse("ipfs://QmNotACid/a.png").unwrap_err();
+    assert_eq!(err.error_code(), "VAL_007");
+    for src in [
+        format!("ipfs://{CID_V1}:8080/a.png"),
+        format!("ipfs://user@{CID_V1}/a.png"),
+        format!("https://{CID_V1}/a.png"),
+        "ipfs:///a.png".to_string(),
+    ] {
+        assert!(IpfsPath::parse(&src).is_err(), "{src}");
+    }
+}
+
+#[tokio::test]
+async fn test_fallback_gateway() {
+    let primary = MockServer::start().await;
+    let fallback = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(format!("/ipfs/{CID_V1}/a.png")))
+        .respond_with(ResponseTemplate::new(504))
+        .expect(1)
+        .mount(&primary)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(format!("/ipfs/{CID_V1}/a.png")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(fixture_png(32, 16)))
+        .expect(1)
+        .mount(&fallback)
+        .await;
+
+    let optimizer = optimizer(
+        &format!("{}/ipfs/", primary.uri()),
+        Some(&format!("{}/ipfs/", fallback.uri())),
+        ImageCache::in_memory(),
+    );
+    let options = OptimizeOptions {
+        width: Some(16),
+        ..Default::default()
+    };
+    let image = optimizer
+        .optimize(&format!("ipfs://{CID_V1}/a.png"), &options)
+        .await
+        .unwrap();
+    assert_eq!((image.width, image.height), (16, 8));
+}
+
+#[tokio::test]
+async fn test_failure_of_every_gateway() {
+    let primary = MockServer::start().await;
+    let fallback = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(502))
+        .expect(1)
+        .mount(&primary)
+        .await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(404))
+        .expect(1)
+        .mount(&fallback)
+        .await;
+
+    let optimizer = optimizer(
+        &primary.uri(),
+        Some(&fallback.uri()),
+        ImageCache::in_memory(),
+    );
+    let err = optimizer
+        .optimize(&format!("ipfs://{CID_V0}"), &OptimizeOptions::default())
+        .await
+        .unwrap_err();
+    // The failure of the last gateway tried
+    assert_eq!(err.error_code(), "IMG_008");
+
+    // Invalid CIDs are refused before anything is fetched
+    let err = optimizer
+        .optimize("ipfs://bafyinvalid/a.png", &OptimizeOptions::default())
+        .await
+        .unwrap_err();
+    assert_eq!(err.error_code(), "VAL_007");
+}
+
+#[tokio::test]
+async fn test_cache_survives_a_gateway_switch() {
+    let first = MockServer::start().await;
+    let second = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(fixture_png(32, 16)))
+        .expect(1)
+        .mount(&first)
+        .await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(fixture_png(32, 16)))
+        .expect(0)
+        .mount(&second)
+        .await;
+    let dir = TempDir::new().unwrap();
+    let src = format!("ipfs://{CID_V1}/a.png");
+
+    let before = optimizer(
+        &first.uri(),
+        None,
+        ImageCache::new(dir.path().to_path_buf()),
+    );
+    let image = before
+        .optimize(&src, &OptimizeOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(image.cache_status, CacheStatus::Miss);
+
+    let after = optimizer(
+        &second.uri(),
+        None,
+        ImageCache::new(dir.path().to_path_buf()),
+    );
+    let image = after
+        .optimize(&src, &OptimizeOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(image.cache_status, CacheStatus::Hit);
+}
+
+#[tokio::test]
+async fn test_disabled_without_gateway() {
+    let optimizer = Optimizer::builder().cache(ImageCache::in_memory()).build();
+    let err = optimizer
+        .optimize(
+            &format!("ipfs://{CID_V1}/a.png"),
+            &OptimizeOptions::default(),
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(err.error_code(), "IMG_001");
+}
@@ -0,0 +1,129 @@
+#![cfg(feature = "runtime")]
+//! The in-memory job store: lifetimes, the pending cap, and the bodies
+//! reporting each status.
+
+use std::time::Duration;
+
+use img_optimizer::config::ErrorDetail;
+use img_optimizer::error::AppError;
+use img_optimizer::jobs::{image_url, JobResult, JobStatus, JobStore};
+
+fn result() -> JobResult {
+    JobResult {
+        url: "http://app.test/img-optimizer/v1/img?src=a&w=600".to_string(),
+        cache_key: "5d0f".to_string(),
+        content_type: "image/png".to_string(),
+        width: Some(600),
+        height: Some(400),
+    }
+}
+
+#[test]
+fn test_job_lifecycle() {
+    let store = JobStore::new(Duration::from_secs(60), 8);
+    let id = store.create().unwrap();
+    assert!(matches!(store.get(&id), Some(JobStatus::Pending)));
+
+    store.finish(&id, JobStatus::Succeeded(result()));
+    let Some(JobStatus::Succeeded(done)) = store.get(&id) else {
+        panic!("job not succeeded");
+    };
+    assert_eq!(done.cache_key, "5d0f");
+
+    assert!(store.get("unknown").is_none());
+    // Outcomes of unknown jobs are dropped
+    store.finish("unknown", JobStatus::Succeeded(result()));
+    assert!(store.get("unknown").is_none());
+}
+
+#[test]
+fn test_jobs_expire() {
+    let store = JobStore::new(Duration::from_millis(50), 8);
+    let id = store.create().unwrap();
+    std::thread::sleep(Duration::from_millis(80));
+    assert!(store.get(&id).is_none());
+    // A job finishing after it expired stays gone
+    store.finish(&id, JobStatus::Succeeded(result()));
+    assert!(store.get(&id).is_none());
+}
+
+#[test]
+fn test_pending_jobs_are_capped() {
+    let store = JobStore::new(Duration::from_secs(60), 2);
+    let first = store.create().unwrap();
+    store.create().unwrap();
+    assert!(matches!(
+        store.create(),
+        Err(AppError::Overloaded {
+            unavailable: false,
+            ..
+        })
+    ));
+
+    // Finished jobs no longer count
+    store.finish(&first, JobStatus::Failed(AppError::InvalidImageData));
+    store.create().unwrap();
+}
+
+#[test]
+fn test_status_bodies() {
+    let pending = JobStatus::Pending.to_json("j1", ErrorDetail::Full);
+    assert_eq!(
+        pending,
+        serde_json::json!({ "id": "j1", "status": "pending" })
+    );
+
+    let succeeded = JobStatus::Succeeded(result()).to_json("j1", ErrorDetail::Full);
+    assert_eq!(
+        succeeded,
+        serde_json::json!({
+            "id": "j1",
+            "status": "succeeded",
+            "url": "http://app.test/img-optimizer/v1/img?src=a&w=600",
+            "cacheKey": "5d0f",
+            "contentType": "image/png",
+            "width": 600,
+            "height": 400,
+        })
+    );
+
+    let err = AppError::SourceNotFound {
+        url: "https://images.example/a.png".to_string(),
+        status: 404,
+    };
+    let failed = JobStatus::Failed(err.clone()).to_json("j1", ErrorDetail::Full);
+    assert_eq!(failed["status"], "failed");
+    assert_eq!(failed["error"]["errorCode"], "IMG_008");
+    assert!(failed["error"]["detail"]
+        .as_str()
+        .unwrap()
+        .contains("images.example"));
+
+    // Minimal detail withholds the source, referring to the job instead
+    let failed = JobStatus::Failed(err).to_json("j1", ErrorDetail::Minimal);
+    let detail = failed["error"]["detail"].as_str().unwrap();
+    assert!(!detail.contains("images.example"), "{detail}");
+    assert!(detail.contains("[ref j1]"), "{detail}");
+}
+
+#[test]
+fn test_image_url() {
+    assert_eq!(
+        image_url(
+            None,
+            "http://127.0.0.1:3000",
+            "/img-optimizer/v1/jobs",
+            "src=a&w=600"
+        ),
+        "http://127.0.0.1:3000/img-optimizer/v1/img?src=a&w=600"
+    );
+    assert_eq!(
+        image_url(
+            Some("https://cdn.example.com/"),
+            "http://10.0.0.1",
+            "/images/img-optimizer/v1/jobs",
+            "src=a"
+        ),
+        "https://cdn.example.com/images/img-optimizer/v1/img?src=a"
+    );
+}
@@ -0,0 +1,406 @@
+#![cfg(feature = "runtime")]
+//! The `Optimizer` library facade, built on the in-memory cache.
+
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use img_optimizer::{
+    cache::{ImageCache, ImageMetadata},
+    config::Limits,
+    image_processor::{Fit, OutputFormat},
+    test_support::fixture_png,
+    transform_chain::{Gravity, Rotation, TransformStep},
+    CacheStatus, OptimizeOptions, Optimizer,
+};
+
+fn data_url(png: &[u8]) -> String {
+    use base64::{engine::general_purpose, Engine as _};
+    format!(
+        "data:image/png;base64,{}",
+        general_purpose::STANDARD.encode(png)
+    )
+}
+
+fn in_memory_optimizer() -> Optimizer {
+    Optimizer::builder().cache(ImageCache::in_memory()).build()
+}
+
+#[tokio::test]
+async fn test_optimize_reports_dimensions_and_cache_status() {
+    let optimizer = in_memory_optimizer();
+    let src = data_url(&fixture_png(8, 4));
+    let options = OptimizeOptions {
+        width: Some(4),
+        format: Some(OutputFormat::Png),
+        ..Default::default()
+    };
+
+    let image = optimizer.optimize(&src, &options).await.unwrap();
+    assert_eq!(image.content_type, "image/png");
+    assert_eq!((image.width, image.height), (4, 2));
+    assert_eq!((image.original_width, image.original_height), (8, 4));
+    assert_eq!(image.cache_status, CacheStatus::Miss);
+
+    let again = optimizer.optimize(&src, &options).await.unwrap();
+    assert_eq!(again.cache_status, CacheStatus::Hit);
+    assert_eq!((again.width, again.height), (4, 2));
+    assert_eq!((again.original_width, again.original_height), (8, 4));
+    assert_eq!(again.data, image.data);
+    assert_eq!(again.etag, image.etag);
+
+    let cache = optimizer.state().cache.read().await;
+    assert_eq!(cache.backend(), "memory");
+    assert_eq!(cache.stats().await.unwrap().entries, 1);
+}
+
+#[tokio::test]
+async fn test_optimize_applies_chained_steps() {
+    let optimizer = in_memory_optimizer();
+    let src = data_url(&fixture_png(8, 4));
+    let options = OptimizeOptions {
+        steps: vec![
+            TransformStep::Rotate(Rotation::Quarter),
+            TransformStep::Crop {
+                width: 1,
+                height: 1,
+                gravity: Gravity::Top,
+            },
+        ],
+        width: Some(2),
+        format: Some(OutputFormat::Png),
+        ..Default::default()
+    };
+
+    let image = optimizer.optimize(&src, &options).await.unwrap();
+    assert_eq!((image.width, image.height), (2, 2));
+    assert_eq!((image.original_width, image.original_height), (8, 4));
+}
+
+#[tokio::test]
+async fn test_optimize_applies_fit_and_background() {
+    let optimizer = in_memory_optimizer();
+    let src = data_url(&fixture_png(8, 4));
+    let options = OptimizeOptions {
+        width: Some(6),
+        height: Some(6),
+        fit: Some(Fit::Pad),
+        background: Some([0, 0, 255, 255]),
+        format: Some(OutputFormat::Png),
+        ..Default::default()
+    };
+
+    let image = optimizer.optimize(&src, &options).await.unwrap();
+    assert_eq!((image.width, image.height), (6, 6));
+    let decoded = image::load_from_memory(&image.data).unwrap().to_rgba8();
+    assert_eq!(decoded.get_pixel(0, 0).0, [0, 0, 255, 255]);
+}
+
+#[tokio::test]
+async fn test_builder_limits_are_enforced() {
+    let optimizer = Optimizer::builder()
+        .cache(ImageCache::in_memory())
+        .limits(Limits {
+            max_width: 100,
+            ..Default::default()
+        })
+        .build();
+    let src = data_url(&fixture_png(4, 4));
+    let options = OptimizeOptions {
+        width: Some(200),
+        ..Default::default()
+    };
+
+    let err = optimizer.optimize(&src, &options).await.unwrap_err();
+    assert_eq!(err.error_code(), "VAL_001");
+}
+
+#[tokio::test]
+async fn test_optimize_fetches_with_the_builder_client_once() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/photo.png"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_bytes(fixture_png(4, 4))
+                .insert_header("content-type", "image/png"),
+        )
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let optimizer = Optimizer::builder()
+        .cache(ImageCache::in_memory())
+        .client(reqwest::Client::new())
+        .build();
+    let src = format!("{}/photo.png", mock_server.uri());
+    let options = OptimizeOptions {
+        format: Some(OutputFormat::Jpeg),
+        quality: Some(50),
+        ..Default::default()
+    };
+
+    let image = optimizer.optimize(&src, &options).await.unwrap();
+    assert_eq!(image.content_type, "image/jpeg");
+    assert_eq!((image.width, image.height), (4, 4));
+    let again = optimizer.optimize(&src, &options).await.unwrap();
+    assert_eq!(again.cache_status, CacheStatus::Hit);
+}
+
+#[tokio::test]
+async fn test_in_memory_cache() {
+    let mut cache = ImageCache::in_memory();
+    assert!(!cache.contains("key"));
+    cache.check().await.unwrap();
+
+    cache.put("key".to_string(), vec![1, 2, 3]).await;
+    assert!(cache.contains("key"));
+    assert_eq!(cache.get("key").await.as_deref(), Some(&[1, 2, 3][..]));
+    assert_eq!(cache.metadata("key").await, None);
+    assert_eq!(cache.stats().await.unwrap().bytes, 3);
+
+    let metadata = ImageMetadata {
+        width: 1,
+        height: 2,
+        original_width: 3,
+        original_height: 4,
+        alpha_flattened: true,
+        upscale_refused: true,
+    };
+    cache
+        .put_with_metadata("key".to_string(), vec![4, 5], metadata)
+        .await;
+    assert_eq!(cache.get("key").await.as_deref(), Some(&[4, 5][..]));
+    assert_eq!(cache.metadata("key").await, Some(metadata));
+
+    assert_eq!(cache.clear().await.unwrap(), 1);
+    assert_eq!(cache.get("key").await, None);
+    assert_eq!(cache.metadata("key").await, None);
+}
+
+#[tokio::test]
+async fn test_in_memory_cache_clones_share_entries() {
+    let cache = ImageCache::in_memory();
+    let mut clone = cache.clone();
+    clone.put("key".to_string(), vec![1]).await;
+    assert_eq!(cache.get("key").await.as_deref(), Some(&[1][..]));
+}
+
+async fn loaded(cache: ImageCache) -> ImageCache {
+    for _ in 0..100 {
+        if cache.index_loaded() {
+            return cache;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    panic!("The cache index was not loaded");
+}
+
+#[tokio::test]
+async fn test_indexed_cache_scans_existing_entries() {
+    let dir = tempfile::TempDir::new().unwrap();
+    std::fs::write(dir.path().join("cached"), [1, 2]).unwrap();
+    std::fs::write(dir.path().join("cached.meta"), "{}").unwrap();
+    std::fs::write(dir.path().join(".gitkeep"), "").unwrap();
+
+    let cache = loaded(ImageCache::indexed(dir.path().to_path_buf())).await;
+    assert!(cache.contains("cached"));
+    assert_eq!(cache.get("cached").await.as_deref(), Some(&[1, 2][..]));
+    assert!(!cache.contains("cached.meta"));
+    assert!(!cache.contains(".gitkeep"));
+    assert!(!cache.contains("missing"));
+}
+
+#[tokio::test]
+async fn test_indexed_cache_answers_misses_from_memory() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let mut cache = loaded(ImageCache::indexed(dir.path().to_path_buf())).await;
+    assert!(!ImageCache::new(dir.path().to_path_buf()).index_loaded());
+
+    // Written behind the cache's back, so only the filesystem knows of it
+    std::fs::write(dir.path().join("external"), [1]).unwrap();
+    assert!(!cache.contains("external"));
+    assert_eq!(cache.get("external").await, None);
+
+    cache.put("key".to_string(), vec![2]).await;
+    assert!(cache.contains("key"));
+    assert_eq!(cache.clone().get("key").await.as_deref(), Some(&[2][..]));
+
+    // Removed behind the cache's back: the failed read drops it from the index
+    std::fs::remove_file(dir.path().join("key")).unwrap();
+    assert!(cache.contains("key"));
+    assert_eq!(cache.get("key").await, None);
+    assert!(!cache.contains("key"));
+
+    cache.put("key".to_string(), vec![3]).await;
+    cache.delete("key").await;
+    assert!(!cache.contains("key"));
+    cache.put("key".to_string(), vec![4]).await;
+    assert_eq!(cache.clear().await.unwrap(), 2);
+    assert!(!cache.contains("key"));
+}
+
+/// Large entries are mapped with the `mmap` feature: a body being served
+/// must outlive its entry being replaced or deleted.
+#[tokio::test]
+async fn test_large_entry_outlives_its_file() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let mut cache = ImageCache::new(dir.path().to_path_buf());
+    let large: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    cache.put("key".to_string(), large.clone()).await;
+
+    let body = cache.get("key").await.unwrap();
+    cache.put("key".to_string(), vec![1]).await;
+    assert_eq!(cache.get("key").await.as_deref(), Some(&[1][..]));
+    assert_eq!(body, large);
+
+    let body = cache.get("key").await.unwrap();
+    cache.delete("key").await;
+    assert_eq!(body, [1][..]);
+
+    // Entries are written beside the cache and renamed into place. Clearing
+    // leaves recent ones to the writes they may belong to
+    let temp = dir.path().join(".key.1-0.tmp");
+    std::fs::write(&temp, [1]).unwrap();
+    assert_eq!(cache.stats().await.unwrap().entries, 0);
+    assert_eq!(cache.clear().await.unwrap(), 0);
+    assert!(temp.exists());
+    let abandoned = std::time::SystemTime::now() - std::time::Duration::from_secs(120);
+    std::fs::File::options()
+        .write(true)
+        .open(&temp)
+        .unwrap()
+        .set_modified(abandoned)
+        .unwrap();
+    assert_eq!(cache.clear().await.unwrap(), 0);
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+/// Windows refuses to delete the file of an entry a response maps, which a
+/// directory stands in for here: the entry reads as deleted, and is written
+/// again once its file is gone.
+#[tokio::test]
+async fn test_entry_whose_file_cannot_be_deleted() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let mut cache = ImageCache::new(dir.path().to_path_buf());
+    std::fs::create_dir(dir.path().join("key")).unwrap();
+    std::fs::write(dir.path().join("key").join("in-use"), [1]).unwrap();
+
+    cache.delete("key").await;
+    assert!(!cache.contains("key"));
+    assert_eq!(cache.get("key").await, None);
+    cache.put("key".to_string(), vec![2]).await;
+    assert_eq!(cache.get("key").await, None);
+    assert_eq!(cache.clear().await.unwrap(), 0);
+
+    std::fs::remove_dir_all(dir.path().join("key")).unwrap();
+    cache.put("key".to_string(), vec![3]).await;
+    assert_eq!(cache.get("key").await.as_deref(), Some(&[3][..]));
+}
+
+/// Size of the entries written with `fill`, so that a body mixing two of
+/// them, or cut short, can be told apart.
+fn stress_len(fill: u8) -> usize {
+    if fill.is_multiple_of(2) {
+        300 * 1024
+    } else {
+        1000 + usize::from(fill)
+    }
+}
+
+/// Metadata written with the entries of `fill`, to tell whether it was read
+/// whole.
+fn stress_metadata(fill: u8) -> ImageMetadata {
+    ImageMetadata {
+        width: u32::from(fill),
+        height: stress_len(fill) as u32,
+        original_width: u32::from(fill),
+        original_height: stress_len(fill) as u32,
+        alpha_flattened: false,
+        upscale_refused: false,
+    }
+}
+
+/// Many tasks reading, replacing and deleting the same keys, with clears in
+/// between: every read is a whole entry or a miss, and so is every read of
+/// its metadata.
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_concurrent_reads_writes_and_deletes() {
+    const KEYS: [&str; 3] = ["a", "b", "c"];
+
+    for indexed in [false, true] {
+        let dir = tempfile::TempDir::new().unwrap();
+        let cache = if indexed {
+            loaded(ImageCache::indexed(dir.path().to_path_buf())).await
+        } else {
+            ImageCache::new(dir.path().to_path_buf())
+        };
+
+        let tasks: Vec<_> = (0..8u8)
+            .map(|task| {
+                let mut cache = cache.clone();
+                let dir = dir.path().to_path_buf();
+                tokio::spawn(async move {
+                    let mut hits = 0;
+                    for i in 0..150u32 {
+                        let key = KEYS[(i as usize + usize::from(task)) % KEYS.len()];
+                        match (i + u32::from(task)) % 5 {
+                            0 | 1 => {
+                                let fill = task.wrapping_mul(31).wrapping_add(i as u8);
+                                cache
+                                    .put_with_metadata(
+                                        key.to_string(),
+                                        vec![fill; stress_len(fill)],
+                                        stress_metadata(fill),
+                                    )
+                                    .await;
+                            }
+                            2 => cache.delete(key).await,
+                            _ => {
+                                if let Some(body) = cache.get(key).await {
+                                    let fill = body[0];
+                                    assert_eq!(body.len(), stress_len(fill), "{key}");
+                                    assert!(body.iter().all(|&byte| byte == fill), "{key}");
+                                    hits += 1;
+                                }
+                                // Read raw, as a half-written file reads as a miss
+                                let path = dir.join(format!("{key}.meta"));
+                                if let Ok(contents) = std::fs::read(path) {
+                                    let metadata: ImageMetadata =
+                                        serde_json::from_slice(&contents).unwrap();
+                                    let fill = metadata.width as u8;
+                                    assert_eq!(metadata, stress_metadata(fill), "{key}");
+                                }
+                            }
+                        }
+                    }
+                    hits
+                })
+            })
+            .collect();
+        let clears = {
+            let mut cache = cache.clone();
+            tokio::spawn(async move {
+                for _ in 0..20 {
+                    cache.clear().await.unwrap();
+                    cache.stats().await.unwrap();
+                    tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+                }
+            })
+        };
+
+        let mut hits = 0;
+        for task in tasks {
+            hits += task.await.unwrap();
+        }
+        clears.await.unwrap();
+        assert!(hits > 0);
+
+        // No write is left behind half done
+        let leftovers: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| !KEYS.contains(&name.trim_end_matches(".meta")))
+            .collect();
+        assert!(leftovers.is_empty(), "{leftovers:?}");
+    }
+}
@@ -0,0 +1,155 @@
+#![cfg(feature = "runtime")]
+//! Origin profiles: which one applies to a host, and how their values merge
+//! with the default profile and `fetch`.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use img_optimizer::config::{AppConfig, HostLimitConfig, OriginProfile};
+use img_optimizer::host_limits::{HostLimit, HostLimiter};
+use img_optimizer::origin::OriginPolicies;
+
+fn config(profiles: &[(&str, OriginProfile)]) -> AppConfig {
+    let mut config = AppConfig::default();
+    config.fetch.timeout_secs = 30;
+    config.fetch.max_per_host = 8;
+    for (pattern, profile) in profiles {
+        config.origin.insert(pattern.to_string(), profile.clone());
+    }
+    config
+}
+
+fn timeout(secs: u64) -> OriginProfile {
+    OriginProfile {
+        timeout_secs: Some(secs),
+        ..OriginProfile::default()
+    }
+}
+
+#[test]
+fn test_profile_selection() {
+    let origins = OriginPolicies::from_config(&config(&[
+        ("images.example.com", timeout(1)),
+        ("*.example.com", timeout(2)),
+        ("*.eu.example.com", timeout(3)),
+        ("*", timeout(4)),
+    ]));
+    for (host, profile) in [
+        ("images.example.com", "images.example.com"),
+        ("IMAGES.Example.com", "images.example.com"),
+        ("cdn.example.com", "*.example.com"),
+        ("a.b.example.com", "*.example.com"),
+        ("cdn.eu.example.com", "*.eu.example.com"),
+        // The domain itself is not one of its subdomains
+        ("eu.example.com", "*.example.com"),
+        ("example.com", "*"),
+        ("badexample.com", "*"),
+        ("other.test", "*"),
+    ] {
+        assert_eq!(origins.policy(host).profile, profile, "{host}");
+    }
+    assert_eq!(
+        origins.policy("cdn.eu.example.com").timeout,
+        Duration::from_secs(3)
+    );
+
+    // Without a default profile, `fetch` applies
+    let origins = OriginPolicies::from_config(&config(&[("*.example.com", timeout(2))]));
+    let policy = origins.policy("other.test");
+    assert_eq!(policy.profile, "*");
+    assert_eq!(policy.timeout, Duration::from_secs(30));
+    assert_eq!(policy.retries, 0);
+    assert!(policy.headers.is_empty());
+}
+
+#[test]
+fn test_profile_values_merge() {
+    let default = OriginProfile {
+        retries: Some(2),
+        min_interval_ms: Some(50),
+        headers: [("User-Agent", "img-bot"), ("X-Tenant", "acme")]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+        ..OriginProfile::default()
+    };
+    let partner = OriginProfile {
+        timeout_secs: Some(5),
+        max_concurrent: Some(2),
+        headers: [("User-Agent".to_string(), "partner-bot".to_string())].into(),
+        ..OriginProfile::default()
+    };
+    let origins =
+        OriginPolicies::from_config(&config(&[("*", default), ("partner.test", partner)]));
+
+    let policy = origins.policy("partner.test");
+    assert_eq!(policy.timeout, Duration::from_secs(5));
+    assert_eq!(policy.retries, 2);
+    assert_eq!(
+        policy.limit,
+        HostLimit {
+            max_concurrent: 2,
+            min_interval: Duration::from_millis(50),
+        }
+    );
+    assert_eq!(policy.headers["user-agent"], "partner-bot");
+    assert_eq!(policy.headers["x-tenant"], "acme");
+
+    let policy = origins.policy("other.test");
+    assert_eq!(policy.timeout, Duration::from_secs(30));
+    assert_eq!(policy.limit.max_concurrent, 8);
+    assert_eq!(policy.headers["user-agent"], "img-bot");
+}
+
+#[test]
+fn test_profiles_override_the_allowlist() {
+    let allowed = |allowed| OriginProfile {
+        allowed: Some(allowed),
+        ..OriginProfile::default()
+    };
+    let mut config = config(&[
+        ("partner.test", allowed(true)),
+        ("*.images.example", allowed(false)),
+        ("static.images.example", OriginProfile::default()),
+    ]);
+    config.fetch.allowed_hosts = vec!["*.images.example".to_string(), "cdn.test".to_string()];
+    let origins = OriginPolicies::from_config(&config);
+
+    assert!(origins.allows("partner.test"));
+    assert!(origins.allows("cdn.test"));
+    assert!(!origins.allows("eu.images.example"));
+    // A profile leaving it unset defers to `fetch.allowed_hosts`
+    assert!(origins.allows("static.images.example"));
+    assert!(!origins.allows("other.test"));
+
+    // A default profile denying everything leaves only explicit allowances
+    config.origin.insert("*".to_string(), allowed(false));
+    let origins = OriginPolicies::from_config(&config);
+    assert!(origins.allows("partner.test"));
+    assert!(!origins.allows("cdn.test"));
+}
+
+#[test]
+fn test_host_limits_of_profiles() {
+    let mut config = config(&[(
+        "*.slow.test",
+        OriginProfile {
+            max_concurrent: Some(1),
+            ..OriginProfile::default()
+        },
+    )]);
+    config.fetch.hosts.insert(
+        "pinned.slow.test".to_string(),
+        HostLimitConfig {
+            max_concurrent: Some(3),
+            min_interval_ms: None,
+        },
+    );
+    let origins = Arc::new(OriginPolicies::from_config(&config));
+    let hosts = HostLimiter::from_config(&config.fetch).with_origins(origins);
+
+    assert_eq!(hosts.limit("a.slow.test").max_concurrent, 1);
+    // `fetch.hosts` overrides take precedence
+    assert_eq!(hosts.limit("pinned.slow.test").max_concurrent, 3);
+    assert_eq!(hosts.limit("fast.test").max_concurrent, 8);
+}
@@ -0,0 +1,110 @@
+#![cfg(all(feature = "otel", feature = "actix"))]
+
+use actix_web::{test, web, App};
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::trace::{SdkTracerProvider, SpanData, SpanExporter};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tempfile::TempDir;
+use tracing_subscriber::layer::SubscriberExt;
+use wiremock::matchers::{header_exists, method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use img_optimizer::{
+    cache::ImageCache, config::AppConfig, optimize_image_handler, telemetry,
+    test_support::fixture_png, AppState, Optimizer,
+};
+
+#[derive(Debug, Clone, Default)]
+struct CollectingExporter {
+    spans: Arc<Mutex<Vec<SpanData>>>,
+}
+
+impl SpanExporter for CollectingExporter {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        self.spans.lock().unwrap().extend(batch);
+        Ok(())
+    }
+}
+
+fn create_app_state(cache_dir: PathBuf) -> AppState {
+    let mut config = AppConfig::default();
+    config.storage.dir = cache_dir.join("storage");
+    Optimizer::builder()
+        .config(config)
+        .cache(ImageCache::new(cache_dir.clone()))
+        .build()
+        .state()
+        .clone()
+}
+
+#[actix_rt::test]
+async fn test_spans_are_exported() {
+    let exporter = CollectingExporter::default();
+    let provider = SdkTracerProvider::builder()
+        .with_simple_exporter(exporter.clone())
+        .build();
+    let subscriber =
+        tracing_subscriber::registry().with(telemetry::layer_for_provider(provider.clone()));
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/traced.png"))
+        .and(header_exists("traceparent"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_bytes(fixture_png(1, 1))
+                .insert_header("content-type", "image/png"),
+        )
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let temp_dir = TempDir::new().unwrap();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(create_app_state(
+                temp_dir.path().to_path_buf(),
+            )))
+            .route(
+                "/img-optimizer/v1/img",
+                web::get().to(optimize_image_handler),
+            ),
+    )
+    .await;
+
+    let image_url = format!("{}/traced.png", &mock_server.uri());
+    let req = test::TestRequest::get()
+        .uri(&format!("/img-optimizer/v1/img?src={}&w=100", &image_url))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+
+    provider.force_flush().unwrap();
+    let spans = exporter.spans.lock().unwrap();
+    let names: Vec<&str> = spans.iter().map(|span| span.name.as_ref()).collect();
+    for expected in [
+        "optimize_image",
+        "fetch_image",
+        "process_image",
+        "cache_get",
+        "cache_put",
+    ] {
+        assert!(
+            names.contains(&expected),
+            "missing span {expected}: {names:?}"
+        );
+    }
+
+    let handler_span = spans
+        .iter()
+        .find(|span| span.name == "optimize_image")
+        .unwrap();
+    let cache_hit = handler_span
+        .attributes
+        .iter()
+        .find(|kv| kv.key.as_str() == "cache_hit")
+        .unwrap();
+    assert_eq!(cache_hit.value.as_str(), "false");
+}
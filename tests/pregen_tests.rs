@@ -0,0 +1,240 @@
+#![cfg(feature = "runtime")]
+//! Pre-generation from a manifest: expansion into images, runs against a
+//! mock origin, resuming from the checkpoint, and the rate bound.
+
+use std::time::{Duration, Instant};
+
+use tempfile::TempDir;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use img_optimizer::cache::ImageCache;
+use img_optimizer::image_processor::OutputFormat;
+use img_optimizer::pregen::{self, ManifestEntry, PregenOptions};
+use img_optimizer::test_support::fixture_png;
+use img_optimizer::Optimizer;
+
+/// Serves a 64x32 PNG at `route`, expecting `fetches` requests.
+async fn mount_png(mock_server: &MockServer, route: &str, fetches: u64) {
+    Mock::given(method("GET"))
+        .and(path(route))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_bytes(fixture_png(64, 32))
+                .insert_header("content-type", "image/png"),
+        )
+        .expect(fetches)
+        .mount(mock_server)
+        .await;
+}
+
+fn manifest(mock_server: &MockServer) -> Vec<ManifestEntry> {
+    let raw = serde_json::json!([
+        { "src": format!("{}/hero.png", mock_server.uri()), "widths": [16, 32], "format": "png" },
+        { "src": format!("{}/logo.png", mock_server.uri()), "quality": 80 },
+        { "src": format!("{}/missing.png", mock_server.uri()), "widths": [16] },
+    ]);
+    serde_json::from_value(raw).unwrap()
+}
+
+fn options(dir: &TempDir) -> PregenOptions {
+    PregenOptions {
+        concurrency: 2,
+        interval: None,
+        checkpoint: dir.path().join("manifest.json.checkpoint"),
+    }
+}
+
+fn optimizer() -> Optimizer {
+    Optimizer::builder().cache(ImageCache::in_memory()).build()
+}
+
+#[test]
+fn test_manifest_jobs() {
+    let entries: Vec<ManifestEntry> = serde_json::from_str(
+        r#"[
+            {"src": "https://images.example/a b.jpg", "widths": [640, 1280], "format": "png", "quality": 70},
+            {"src": "https://images.example/logo.png"},
+            {"src": "https://images.example/c.png", "widths": [100], "format": "tiff"}
+        ]"#,
+    )
+    .unwrap();
+    let jobs = pregen::jobs(&entries);
+
+    let keys: Vec<&str> = jobs.iter().map(|job| job.key.as_str()).collect();
+    assert_eq!(
+        keys,
+        [
+            "src=https%3A%2F%2Fimages.example%2Fa+b.jpg&w=640&q=70&f=png",
+            "src=https%3A%2F%2Fimages.example%2Fa+b.jpg&w=1280&q=70&f=png",
+            "src=https%3A%2F%2Fimages.example%2Flogo.png",
+            "src=https%3A%2F%2Fimages.example%2Fc.png&w=100&f=tiff",
+        ]
+    );
+    let options = jobs[1].options.as_ref().unwrap();
+    assert_eq!(options.width, Some(1280));
+    assert_eq!(options.quality, Some(70));
+    assert_eq!(options.format, Some(OutputFormat::Png));
+    let options = jobs[2].options.as_ref().unwrap();
+    assert_eq!((options.width, options.format), (None, None));
+    // Reported when processed, like other failures
+    assert_eq!(
+        jobs[3].options.as_ref().unwrap_err().error_code(),
+        "IMG_004"
+    );
+
+    // Unknown fields are mistakes, not options
+    assert!(serde_json::from_str::<Vec<ManifestEntry>>(r#"[{"src": "a", "width": 100}]"#).is_err());
+}
+
+#[tokio::test]
+async fn test_pregen_run() {
+    let mock_server = MockServer::start().await;
+    mount_png(&mock_server, "/hero.png", 2).await;
+    mount_png(&mock_server, "/logo.png", 1).await;
+    let dir = TempDir::new().unwrap();
+    let optimizer = optimizer();
+    let options = options(&dir);
+
+    let jobs = pregen::jobs(&manifest(&mock_server));
+    let summary = pregen::run(&optimizer, jobs.clone(), &options)
+        .await
+        .unwrap();
+    assert_eq!(summary.total, 4);
+    assert_eq!(
+        (summary.processed, summary.cached, summary.resumed),
+        (3, 0, 0)
+    );
+    // The failure doesn't stop the others
+    assert_eq!(summary.failed, 1);
+    assert_eq!(summary.errors.get("IMG_008"), Some(&1));
+    assert!(summary.failures[0].key.contains("missing.png"));
+
+    // The failure is left out of the checkpoint, to be retried
+    let checkpoint = std::fs::read_to_string(&options.checkpoint).unwrap();
+    let mut completed: Vec<&str> = checkpoint.lines().collect();
+    completed.sort();
+    let mut expected: Vec<&str> = jobs[..3].iter().map(|job| job.key.as_str()).collect();
+    expected.sort();
+    assert_eq!(completed, expected);
+
+    let summary = pregen::run(&optimizer, jobs.clone(), &options)
+        .await
+        .unwrap();
+    assert_eq!((summary.resumed, summary.failed), (3, 1));
+
+    // Without the checkpoint, completed images are cache hits
+    std::fs::remove_file(&options.checkpoint).unwrap();
+    let summary = pregen::run(&optimizer, jobs[..3].to_vec(), &options)
+        .await
+        .unwrap();
+    assert_eq!((summary.processed, summary.cached), (0, 3));
+    // Removed once every image completed
+    assert!(!options.checkpoint.exists());
+}
+
+#[tokio::test]
+async fn test_pregen_resumes_after_interrupt() {
+    let mock_server = MockServer::start().await;
+    // Only the images the interrupted run didn't complete are fetched
+    mount_png(&mock_server, "/hero.png", 1).await;
+    mount_png(&mock_server, "/logo.png", 0).await;
+    Mock::given(method("GET"))
+        .and(path("/missing.png"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(fixture_png(8, 8)))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    let dir = TempDir::new().unwrap();
+    let options = options(&dir);
+
+    // The interrupted run completed two images, and was killed while
+    // recording the third
+    let jobs = pregen::jobs(&manifest(&mock_server));
+    let torn = &jobs[1].key[..jobs[1].key.len() / 2];
+    std::fs::write(
+        &options.checkpoint,
+        format!("{}\n{}\n{torn}", jobs[0].key, jobs[2].key),
+    )
+    .unwrap();
+
+    let summary = pregen::run(&optimizer(), jobs, &options).await.unwrap();
+    assert_eq!(summary.total, 4);
+    assert_eq!(
+        (
+            summary.resumed,
+            summary.processed,
+            summary.cached,
+            summary.failed
+        ),
+        (2, 2, 0, 0)
+    );
+    assert!(!options.checkpoint.exists());
+}
+
+#[tokio::test]
+async fn test_pregen_rate() {
+    let mock_server = MockServer::start().await;
+    mount_png(&mock_server, "/hero.png", 2).await;
+    mount_png(&mock_server, "/logo.png", 1).await;
+    let dir = TempDir::new().unwrap();
+    let options = PregenOptions {
+        interval: Some(Duration::from_millis(100)),
+        ..options(&dir)
+    };
+    let jobs = pregen::jobs(&manifest(&mock_server));
+
+    // Four images start at least 100ms apart
+    let start = Instant::now();
+    let summary = pregen::run(&optimizer(), jobs, &options).await.unwrap();
+    assert_eq!(summary.total, 4);
+    assert!(start.elapsed() >= Duration::from_millis(300));
+}
+
+#[cfg(feature = "actix")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pregen_command() {
+    let mock_server = MockServer::start().await;
+    mount_png(&mock_server, "/hero.png", 2).await;
+    mount_png(&mock_server, "/logo.png", 1).await;
+    let dir = TempDir::new().unwrap();
+    let manifest = serde_json::to_string(&serde_json::json!([
+        { "src": format!("{}/hero.png", mock_server.uri()), "widths": [16, 32] },
+        { "src": format!("{}/logo.png", mock_server.uri()), "format": "png" },
+        { "src": format!("{}/missing.png", mock_server.uri()) },
+    ]))
+    .unwrap();
+    std::fs::write(dir.path().join("manifest.json"), manifest).unwrap();
+
+    let mut cmd = assert_cmd::Command::cargo_bin("img-optimizer").unwrap();
+    cmd.current_dir(dir.path())
+        .env_clear()
+        .args([
+            "pregen",
+            "--manifest",
+            "manifest.json",
+            "--concurrency",
+            "2",
+        ])
+        .args(["--rate", "50/s", "--cache-dir", "images"]);
+    let output = tokio::task::spawn_blocking(move || cmd.output().unwrap())
+        .await
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(1), "{output:?}");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout,
+        "4 images: 3 processed, 0 already cached, 0 done by an earlier run, 1 failed\n  IMG_008: 1\n"
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("FAIL src="), "{stderr}");
+    assert!(stderr.contains("missing.png"), "{stderr}");
+    // The checkpoint stays for the failed image to be retried
+    let checkpoint = std::fs::read_to_string(dir.path().join("manifest.json.checkpoint")).unwrap();
+    assert_eq!(checkpoint.lines().count(), 3);
+    assert!(std::fs::read_dir(dir.path().join("images"))
+        .unwrap()
+        .next()
+        .is_some());
+}
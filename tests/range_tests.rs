@@ -0,0 +1,92 @@
+//! Parsing of `Range` headers against a body, and `If-Range` validation.
+
+use img_optimizer::error::AppError;
+use img_optimizer::range::{parse_range, ByteRange, RangeRequest};
+
+fn range(start: usize, end: usize) -> Option<ByteRange> {
+    Some(ByteRange { start, end })
+}
+
+#[test]
+fn test_parse_range() {
+    let cases = [
+        ("bytes=0-99", range(0, 99)),
+        ("bytes=100-199", range(100, 199)),
+        ("bytes=900-", range(900, 999)),
+        ("bytes=-100", range(900, 999)),
+        // Ends past the body are clamped to it
+        ("bytes=500-5000", range(500, 999)),
+        ("bytes=-5000", range(0, 999)),
+        (" Bytes = 10 - 19 ", range(10, 19)),
+        ("bytes=999-999", range(999, 999)),
+    ];
+    for (value, expected) in cases {
+        assert_eq!(parse_range(value, 1000).unwrap(), expected, "{value:?}");
+    }
+}
+
+#[test]
+fn test_ignored_ranges() {
+    for value in [
+        "bytes=0-9,20-29",
+        "items=0-9",
+        "bytes=9-0",
+        "bytes=a-b",
+        "bytes=-",
+        "bytes",
+        "0-9",
+    ] {
+        assert_eq!(parse_range(value, 1000).unwrap(), None, "{value:?}");
+    }
+}
+
+#[test]
+fn test_unsatisfiable_ranges() {
+    for (value, length) in [
+        ("bytes=1000-", 1000),
+        ("bytes=1000-2000", 1000),
+        ("bytes=-0", 1000),
+        ("bytes=-10", 0),
+        ("bytes=0-", 0),
+    ] {
+        match parse_range(value, length) {
+            Err(err @ AppError::RangeNotSatisfiable { .. }) => {
+                assert_eq!(err.error_code(), "SYS_416");
+            }
+            other => panic!("{value:?} on {length} bytes: {other:?}"),
+        }
+    }
+}
+
+#[test]
+fn test_content_range() {
+    assert_eq!(
+        ByteRange { start: 0, end: 99 }.content_range(1000),
+        "bytes 0-99/1000"
+    );
+}
+
+#[test]
+fn test_if_range() {
+    let request = |if_range| RangeRequest::from_headers(Some("bytes=0-9"), if_range).unwrap();
+
+    assert_eq!(request(None).resolve("abc", 100).unwrap(), range(0, 9));
+    assert_eq!(
+        request(Some("\"abc\"")).resolve("abc", 100).unwrap(),
+        range(0, 9)
+    );
+    // Another representation, or a date, gets the whole image
+    assert_eq!(request(Some("\"old\"")).resolve("abc", 100).unwrap(), None);
+    assert_eq!(
+        request(Some("W/\"abc\"")).resolve("abc", 100).unwrap(),
+        None
+    );
+    assert_eq!(
+        request(Some("Wed, 21 Oct 2015 07:28:00 GMT"))
+            .resolve("abc", 100)
+            .unwrap(),
+        None
+    );
+
+    assert_eq!(RangeRequest::from_headers(None, Some("\"abc\"")), None);
+}
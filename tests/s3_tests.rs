@@ -0,0 +1,128 @@
+#![cfg(all(feature = "s3-source", feature = "actix", feature = "webp"))]
+
+//! `s3://` sources. The round trip against a real S3-compatible service
+//! (MinIO, localstack) runs when `S3_TEST_ENDPOINT_URL` and `S3_TEST_BUCKET`
+//! are set, with credentials in the usual `AWS_*` variables, and is skipped
+//! otherwise.
+
+use actix_web::{test, web, App};
+use std::path::PathBuf;
+use tempfile::TempDir;
+
+use img_optimizer::{
+    cache::ImageCache, config::AppConfig, optimize_image_handler, s3, test_support::fixture_png,
+    AppState, Optimizer,
+};
+
+fn create_app_state(cache_dir: PathBuf, mut config: AppConfig) -> AppState {
+    config.storage.dir = cache_dir.join("storage");
+    Optimizer::builder()
+        .config(config)
+        .cache(ImageCache::new(cache_dir.clone()))
+        .build()
+        .state()
+        .clone()
+}
+
+#[actix_rt::test]
+async fn test_parse_s3_url() {
+    assert_eq!(
+        s3::parse_url("s3://originals/photos/a.png").unwrap(),
+        ("originals", "photos/a.png")
+    );
+    assert!(s3::parse_url("s3://originals").is_err());
+    assert!(s3::parse_url("s3://originals/").is_err());
+    assert!(s3::parse_url("s3:///a.png").is_err());
+}
+
+#[actix_rt::test]
+async fn test_s3_bucket_allowlist() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut config = AppConfig::default();
+    config.s3.allowed_buckets = vec!["originals".to_string()];
+    let app_state = create_app_state(temp_dir.path().to_path_buf(), config);
+    let app = test::init_service(App::new().app_data(web::Data::new(app_state)).route(
+        "/img-optimizer/v1/img",
+        web::get().to(optimize_image_handler),
+    ))
+    .await;
+
+    // Rejected before any request to S3
+    let req = test::TestRequest::get()
+        .uri("/img-optimizer/v1/img?src=s3://secrets/a.png")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 403);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["errorCode"], "SEC_004");
+    assert!(!body["detail"].as_str().unwrap().contains("secrets"));
+
+    let req = test::TestRequest::get()
+        .uri("/img-optimizer/v1/img?src=s3://originals")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 400);
+}
+
+#[actix_rt::test]
+async fn test_s3_round_trip() {
+    let (Ok(endpoint_url), Ok(bucket)) = (
+        std::env::var("S3_TEST_ENDPOINT_URL"),
+        std::env::var("S3_TEST_BUCKET"),
+    ) else {
+        eprintln!("S3_TEST_ENDPOINT_URL or S3_TEST_BUCKET not set, skipping");
+        return;
+    };
+
+    let shared = aws_config::defaults(aws_config::BehaviorVersion::latest())
+        .endpoint_url(&endpoint_url)
+        .load()
+        .await;
+    let client = aws_sdk_s3::Client::from_conf(
+        aws_sdk_s3::config::Builder::from(&shared)
+            .force_path_style(true)
+            .build(),
+    );
+    client
+        .put_object()
+        .bucket(&bucket)
+        .key("img-optimizer-tests/pixel.png")
+        .body(fixture_png(1, 1).into())
+        .send()
+        .await
+        .unwrap();
+
+    let temp_dir = TempDir::new().unwrap();
+    let mut config = AppConfig::default();
+    config.s3.allowed_buckets = vec![bucket.clone()];
+    config.s3.endpoint_url = Some(endpoint_url);
+    config.s3.force_path_style = true;
+    let app_state = create_app_state(temp_dir.path().to_path_buf(), config);
+    let app = test::init_service(App::new().app_data(web::Data::new(app_state)).route(
+        "/img-optimizer/v1/img",
+        web::get().to(optimize_image_handler),
+    ))
+    .await;
+
+    let req = test::TestRequest::get()
+        .uri(&format!(
+            "/img-optimizer/v1/img?src=s3://{bucket}/img-optimizer-tests/pixel.png&f=webp"
+        ))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/webp");
+
+    let req = test::TestRequest::get()
+        .uri(&format!(
+            "/img-optimizer/v1/img?src=s3://{bucket}/img-optimizer-tests/missing.png"
+        ))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 422);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert!(body["detail"]
+        .as_str()
+        .unwrap()
+        .contains("the object does not exist"));
+}
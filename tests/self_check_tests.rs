@@ -0,0 +1,138 @@
+#![cfg(feature = "test-util")]
+//! `img-optimizer check` against a server started in-process.
+
+use actix_web::{web, App, HttpServer};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tempfile::TempDir;
+
+use img_optimizer::{
+    auth::ApiKeys,
+    self_check::{self, CheckOptions},
+    test_support::TestApp,
+};
+
+fn options(url: &str, optimize: bool) -> CheckOptions {
+    CheckOptions {
+        url: url.to_string(),
+        timeout: self_check::DEFAULT_TIMEOUT,
+        optimize,
+        api_key: None,
+    }
+}
+
+#[actix_rt::test]
+async fn test_check_passes_against_a_ready_server() {
+    let app = TestApp::spawn().await;
+    let url = app.url("");
+
+    let report = self_check::run(&options(&url, false)).await.unwrap();
+    assert!(report.optimize.is_none());
+    assert!(report.to_string().starts_with("ready in "));
+
+    // Trailing slashes are ignored
+    let report = self_check::run(&options(&format!("{url}/"), true))
+        .await
+        .unwrap();
+    assert!(report.optimize.is_some());
+    assert!(report.to_string().contains(", optimized a 1x1 PNG in "));
+}
+
+#[actix_rt::test]
+async fn test_check_fails_while_shutting_down() {
+    let app = TestApp::spawn().await;
+    app.state.shutting_down.store(true, Ordering::SeqCst);
+    let url = app.url("");
+
+    let err = self_check::run(&options(&url, true)).await.unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "readiness returned 503 Service Unavailable (shutting_down)"
+    );
+}
+
+#[actix_rt::test]
+async fn test_check_names_failing_readiness_checks() {
+    // A regular file in place of the cache directory fails the cache check
+    let temp_dir = TempDir::new().unwrap();
+    let cache_dir = temp_dir.path().join("not-a-directory");
+    std::fs::write(&cache_dir, b"").unwrap();
+    let app = TestApp::builder().cache_dir(cache_dir).spawn().await;
+    let url = app.url("");
+
+    let err = self_check::run(&options(&url, false)).await.unwrap_err();
+    let message = err.to_string();
+    assert!(
+        message.starts_with("readiness returned 503 Service Unavailable (cache: "),
+        "{message}"
+    );
+}
+
+#[actix_rt::test]
+async fn test_check_optimization_with_api_keys() {
+    let app = TestApp::builder()
+        .api_keys(ApiKeys::parse("probe:k3y"))
+        .spawn()
+        .await;
+    let url = app.url("");
+
+    // Readiness is public, the optimization is not
+    self_check::run(&options(&url, false)).await.unwrap();
+    let err = self_check::run(&options(&url, true)).await.unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "optimization returned 401 Unauthorized (SEC_001)"
+    );
+
+    let options = CheckOptions {
+        api_key: Some("k3y".to_string()),
+        ..options(&url, true)
+    };
+    self_check::run(&options).await.unwrap();
+}
+
+#[actix_rt::test]
+async fn test_check_fails_fast_without_a_server() {
+    // Bound then released, so nothing listens on it
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let err = self_check::run(&options(&format!("http://127.0.0.1:{port}"), false))
+        .await
+        .unwrap_err();
+    assert!(
+        err.to_string().starts_with("error sending request"),
+        "{err}"
+    );
+}
+
+#[actix_rt::test]
+async fn test_check_times_out() {
+    let server = HttpServer::new(|| {
+        App::new().route(
+            "/health/ready",
+            web::get().to(|| async {
+                actix_web::rt::time::sleep(Duration::from_secs(5)).await;
+                "late"
+            }),
+        )
+    })
+    .workers(1)
+    .bind(("127.0.0.1", 0))
+    .unwrap();
+    let url = format!("http://{}", server.addrs()[0]);
+    actix_web::rt::spawn(server.run());
+
+    let options = CheckOptions {
+        timeout: Duration::from_millis(200),
+        ..options(&url, false)
+    };
+    let start = std::time::Instant::now();
+    let err = self_check::run(&options).await.unwrap_err();
+    assert!(start.elapsed() < Duration::from_secs(2));
+    // Whichever of the client and the overall budget fires first
+    assert!(err.to_string().contains("timed out"), "{err}");
+}
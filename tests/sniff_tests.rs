@@ -0,0 +1,318 @@
+//! Format detection from real file headers, complete and truncated.
+
+use img_optimizer::sniff::{self, DetectedFormat, SNIFF_LEN, UNKNOWN_CONTENT_TYPE};
+
+const JPEG_JFIF: &[u8] = &[
+    0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x10, b'J', b'F', b'I', b'F', 0x00, 0x01,
+];
+const JPEG_EXIF: &[u8] = &[
+    0xFF, 0xD8, 0xFF, 0xE1, 0x1C, 0x45, b'E', b'x', b'i', b'f', 0x00, 0x00,
+];
+const PNG: &[u8] = &[
+    0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, 0x00, 0x00, 0x00, 0x0D, b'I', b'H', b'D', b'R',
+];
+const GIF87A: &[u8] = b"GIF87a\x01\x00\x01\x00\x80\x00\x00";
+const GIF89A: &[u8] = b"GIF89a\x01\x00\x01\x00\x80\x00\x00";
+const WEBP_LOSSY: &[u8] = b"RIFF\x24\x00\x00\x00WEBPVP8 \x18\x00\x00\x00";
+const WEBP_LOSSLESS: &[u8] = b"RIFF\x1a\x00\x00\x00WEBPVP8L\x0d\x00\x00\x00";
+const WEBP_EXTENDED: &[u8] = b"RIFF\xbc\x01\x00\x00WEBPVP8X\x0a\x00\x00\x00";
+const AVIF: &[u8] = b"\x00\x00\x00\x20ftypavif\x00\x00\x00\x00avifmif1miafMA1B";
+const AVIF_SEQUENCE: &[u8] = b"\x00\x00\x00\x1cftypavis\x00\x00\x00\x00avismsf1miaf";
+const AVIF_COMPATIBLE: &[u8] = b"\x00\x00\x00\x1cftypmif1\x00\x00\x00\x00mif1avifmiaf";
+const HEIC: &[u8] = b"\x00\x00\x00\x18ftypheic\x00\x00\x00\x00mif1heic";
+const HEIC_COMPATIBLE: &[u8] = b"\x00\x00\x00\x18ftypmif1\x00\x00\x00\x00mif1heic";
+const TIFF_LE: &[u8] = b"II\x2a\x00\x08\x00\x00\x00";
+const TIFF_BE: &[u8] = b"MM\x00\x2a\x00\x00\x00\x08";
+const BMP: &[u8] = b"BM\x3a\x00\x00\x00\x00\x00\x00\x00\x36\x00\x00\x00\x28\x00\x00\x00";
+const BMP_CORE: &[u8] = b"BM\x1e\x00\x00\x00\x00\x00\x00\x00\x1a\x00\x00\x00\x0c\x00\x00\x00";
+const ICO: &[u8] = b"\x00\x00\x01\x00\x01\x00\x10\x10\x00\x00\x01\x00\x20\x00";
+
+/// Header bytes, their format and the length from which they are complete.
+const HEADERS: &[(&[u8], DetectedFormat, usize)] = &[
+    (JPEG_JFIF, DetectedFormat::Jpeg, 3),
+    (JPEG_EXIF, DetectedFormat::Jpeg, 3),
+    (PNG, DetectedFormat::Png, 8),
+    (GIF87A, DetectedFormat::Gif, 6),
+    (GIF89A, DetectedFormat::Gif, 6),
+    (WEBP_LOSSY, DetectedFormat::WebP, 16),
+    (WEBP_LOSSLESS, DetectedFormat::WebP, 16),
+    (WEBP_EXTENDED, DetectedFormat::WebP, 16),
+    (AVIF, DetectedFormat::Avif, 12),
+    (AVIF_SEQUENCE, DetectedFormat::Avif, 12),
+    (AVIF_COMPATIBLE, DetectedFormat::Avif, 24),
+    (HEIC, DetectedFormat::Heic, 12),
+    (HEIC_COMPATIBLE, DetectedFormat::Heic, 24),
+    (TIFF_LE, DetectedFormat::Tiff, 4),
+    (TIFF_BE, DetectedFormat::Tiff, 4),
+    (BMP, DetectedFormat::Bmp, 18),
+    (BMP_CORE, DetectedFormat::Bmp, 18),
+    (ICO, DetectedFormat::Ico, 6),
+];
+
+#[test]
+fn test_detects_real_headers() {
+    for (header, format, _) in HEADERS {
+        assert_eq!(DetectedFormat::detect(header), Some(*format), "{format:?}");
+
+        let mut file = header.to_vec();
+        file.extend_from_slice(&[0xAB; 4096]);
+        assert_eq!(DetectedFormat::detect(&file), Some(*format), "{format:?}");
+    }
+}
+
+#[test]
+fn test_truncated_headers_are_not_recognized() {
+    assert_eq!(DetectedFormat::detect(&[]), None);
+    assert_eq!(sniff::content_type(&[]), UNKNOWN_CONTENT_TYPE);
+
+    for (header, format, complete) in HEADERS {
+        for len in 0..*complete {
+            assert_eq!(
+                DetectedFormat::detect(&header[..len]),
+                None,
+                "{format:?} cut at {len}"
+            );
+        }
+        assert_eq!(
+            DetectedFormat::detect(&header[..*complete]),
+            Some(*format),
+            "{format:?} cut at {complete}"
+        );
+    }
+}
+
+#[test]
+fn test_encoded_images_are_recognized() {
+    let img = image::DynamicImage::ImageRgba8(image::RgbaImage::new(2, 2));
+    let cases = [
+        (image::ImageFormat::Png, DetectedFormat::Png),
+        (image::ImageFormat::Gif, DetectedFormat::Gif),
+        (image::ImageFormat::Tiff, DetectedFormat::Tiff),
+        (image::ImageFormat::Bmp, DetectedFormat::Bmp),
+        (image::ImageFormat::Ico, DetectedFormat::Ico),
+        (image::ImageFormat::WebP, DetectedFormat::WebP),
+    ];
+    for (encoding, format) in cases {
+        let mut data = Vec::new();
+        img.write_to(&mut std::io::Cursor::new(&mut data), encoding)
+            .unwrap();
+        assert_eq!(DetectedFormat::detect(&data), Some(format), "{format:?}");
+    }
+
+    let mut jpeg = Vec::new();
+    img.to_rgb8()
+        .write_to(
+            &mut std::io::Cursor::new(&mut jpeg),
+            image::ImageFormat::Jpeg,
+        )
+        .unwrap();
+    assert_eq!(DetectedFormat::detect(&jpeg), Some(DetectedFormat::Jpeg));
+}
+
+#[test]
+fn test_riff_containers_other_than_webp() {
+    // WAVE audio and AVI video share the RIFF container
+    assert_eq!(
+        DetectedFormat::detect(b"RIFF\x24\x08\x00\x00WAVEfmt \x10\x00\x00\x00"),
+        None
+    );
+    assert_eq!(
+        DetectedFormat::detect(b"RIFF\x00\x10\x00\x00AVI LIST\x00\x00\x00\x00"),
+        None
+    );
+    // A RIFF size too small to hold the first chunk header
+    assert_eq!(
+        DetectedFormat::detect(b"RIFF\x08\x00\x00\x00WEBPVP8 \x18\x00\x00\x00"),
+        None
+    );
+    // An unknown WebP chunk
+    assert_eq!(
+        DetectedFormat::detect(b"RIFF\x24\x00\x00\x00WEBPXXXX\x18\x00\x00\x00"),
+        None
+    );
+    // A file cut after its header still declares its full size
+    assert_eq!(
+        DetectedFormat::detect(b"RIFF\xff\xff\x00\x00WEBPVP8 "),
+        Some(DetectedFormat::WebP)
+    );
+}
+
+#[test]
+fn test_ftyp_brands() {
+    // MP4 video and generic HEIF without a known coding
+    assert_eq!(
+        DetectedFormat::detect(b"\x00\x00\x00\x20ftypisom\x00\x00\x02\x00isomiso2avc1mp41"),
+        None
+    );
+    assert_eq!(
+        DetectedFormat::detect(b"\x00\x00\x00\x18ftypmif1\x00\x00\x00\x00mif1miaf"),
+        None
+    );
+    // Brands past the end of the box belong to the next box
+    assert_eq!(
+        DetectedFormat::detect(b"\x00\x00\x00\x10ftypmif1\x00\x00\x00\x00avif"),
+        None
+    );
+    // Box sizes that cannot hold a whole list of brands
+    assert_eq!(
+        DetectedFormat::detect(b"\x00\x00\x00\x0cftypavif\x00\x00\x00\x00"),
+        None
+    );
+    assert_eq!(
+        DetectedFormat::detect(b"\x00\x00\x00\x1aftypavif\x00\x00\x00\x00avifmif1"),
+        None
+    );
+    // AVIF wins over HEIC when both are listed
+    assert_eq!(
+        DetectedFormat::detect(b"\x00\x00\x00\x1cftypheic\x00\x00\x00\x00mif1heicavif"),
+        Some(DetectedFormat::Avif)
+    );
+    // A box larger than the input is scanned as far as it goes
+    assert_eq!(
+        DetectedFormat::detect(b"\x00\x00\x01\x00ftypmif1\x00\x00\x00\x00mif1avif"),
+        Some(DetectedFormat::Avif)
+    );
+}
+
+#[test]
+fn test_bmp_and_ico_headers_are_checked() {
+    // `BM` followed by an unknown DIB header size, e.g. text
+    assert_eq!(DetectedFormat::detect(b"BMW is a car maker"), None);
+    // An icon directory without images
+    assert_eq!(DetectedFormat::detect(b"\x00\x00\x01\x00\x00\x00"), None);
+    // Cursors are not icons
+    assert_eq!(
+        DetectedFormat::detect(b"\x00\x00\x02\x00\x01\x00\x10\x10"),
+        None
+    );
+}
+
+#[test]
+fn test_svg() {
+    let documents: &[&str] = &[
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="1" height="1"/>"#,
+        "<svg>",
+        "<svg\n  xmlns=\"http://www.w3.org/2000/svg\">",
+        r#"<?xml version="1.0" encoding="UTF-8"?><svg xmlns="http://www.w3.org/2000/svg"/>"#,
+        "\u{feff}<?xml version=\"1.0\"?>\n<svg/>",
+        "\n\t  <svg>",
+        "<!-- Generator: Adobe Illustrator -->\n<svg>",
+        r#"<?xml version="1.0"?>
+<!DOCTYPE svg PUBLIC "-//W3C//DTD SVG 1.1//EN" "http://www.w3.org/Graphics/SVG/1.1/DTD/svg11.dtd">
+<!-- a comment --><?xml-stylesheet href="style.css"?>
+<svg version="1.1">"#,
+        "<!doctype svg><svg>",
+    ];
+    for document in documents {
+        assert_eq!(
+            DetectedFormat::detect(document.as_bytes()),
+            Some(DetectedFormat::Svg),
+            "{document}"
+        );
+    }
+
+    let not_svg: &[&str] = &[
+        "",
+        "<html><svg></svg></html>",
+        "<svgx>",
+        "<SVG>",
+        "svg",
+        "<?xml version=\"1.0\"?><html/>",
+        // Prologs cut before the root element
+        "<?xml version=\"1.0\"",
+        "<!-- an unterminated comment <svg>",
+        "<!DOCTYPE svg",
+        "<sv",
+    ];
+    for document in not_svg {
+        assert_eq!(
+            DetectedFormat::detect(document.as_bytes()),
+            None,
+            "{document}"
+        );
+    }
+
+    // Only the first bytes are inspected
+    let long_comment = format!("<!--{}--><svg>", " ".repeat(SNIFF_LEN));
+    assert_eq!(DetectedFormat::detect(long_comment.as_bytes()), None);
+}
+
+#[test]
+fn test_non_image_content() {
+    let cases: &[(&[u8], &str)] = &[
+        (
+            b"<!DOCTYPE html>\n<html><head><title>Sign in</title>",
+            "HTML",
+        ),
+        (b"\n  <html lang=\"en\">", "HTML"),
+        (b"\xEF\xBB\xBF<!doctype html>", "HTML"),
+        (b"<HTML><BODY>Access denied</BODY></HTML>", "HTML"),
+        (
+            b"<?xml version=\"1.0\"?><Error><Code>NoSuchKey</Code></Error>",
+            "XML",
+        ),
+        (b"{\"error\": \"not found\"}", "JSON"),
+        (b"[]", "JSON"),
+        (b"Service temporarily unavailable\r\n", "text"),
+        ("Pas d'image ici \u{2014} r\u{e9}essayez".as_bytes(), "text"),
+    ];
+    for &(data, kind) in cases {
+        assert_eq!(
+            sniff::non_image_content(data),
+            Some(kind),
+            "{}",
+            String::from_utf8_lossy(data)
+        );
+    }
+
+    // A character cut at the end of the sample is still text
+    let mut cut = "é".repeat(SNIFF_LEN).into_bytes();
+    cut.truncate(SNIFF_LEN + 1);
+    assert_eq!(sniff::non_image_content(&cut), Some("text"));
+
+    for &(header, format, _) in HEADERS {
+        assert_eq!(sniff::non_image_content(header), None, "{format:?}");
+    }
+    let images: &[&[u8]] = &[
+        b"",
+        b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>",
+        // Netpbm headers are text
+        b"P3\n2 1\n255\n255 0 0 0 0 255\n",
+        b"P6\n2 1\n255\n",
+        // Binary data in no known format
+        b"\x00\x01\x02\x03",
+        b"ab\xFFcd",
+    ];
+    for data in images {
+        assert_eq!(
+            sniff::non_image_content(data),
+            None,
+            "{}",
+            String::from_utf8_lossy(data)
+        );
+    }
+}
+
+#[test]
+fn test_content_types_and_extensions() {
+    for format in DetectedFormat::ALL {
+        assert!(format.content_type().starts_with("image/"), "{format:?}");
+        assert_eq!(
+            DetectedFormat::from_content_type(format.content_type()),
+            Some(format)
+        );
+        assert!(!format.extension().starts_with('.'), "{format:?}");
+    }
+    assert_eq!(DetectedFormat::Jpeg.extension(), "jpg");
+    assert_eq!(DetectedFormat::Svg.content_type(), "image/svg+xml");
+    assert_eq!(
+        DetectedFormat::from_content_type("Image/PNG; charset=binary"),
+        Some(DetectedFormat::Png)
+    );
+    assert_eq!(
+        DetectedFormat::from_content_type(UNKNOWN_CONTENT_TYPE),
+        None
+    );
+    assert_eq!(sniff::content_type(PNG), "image/png");
+    assert_eq!(sniff::content_type(b"plain text"), UNKNOWN_CONTENT_TYPE);
+}
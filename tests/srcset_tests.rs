@@ -0,0 +1,83 @@
+//! Parsing of srcset manifest requests and the URLs they point at.
+
+use img_optimizer::error::AppError;
+use img_optimizer::srcset::{image_endpoint, parse_flag, parse_widths, MAX_WIDTHS};
+
+#[test]
+fn test_parse_widths() {
+    let cases: &[(&str, &[u32])] = &[
+        ("320", &[320]),
+        ("320,640,1024,1920", &[320, 640, 1024, 1920]),
+        (" 640 , 320 ", &[320, 640]),
+        ("1024,320,1024,320", &[320, 1024]),
+        ("1,3840", &[1, 3840]),
+    ];
+    for (value, expected) in cases {
+        assert_eq!(parse_widths(value, 3840).unwrap(), *expected, "{value:?}");
+    }
+}
+
+#[test]
+fn test_parse_widths_rejects_invalid_lists() {
+    let too_many = (1..=MAX_WIDTHS as u32 + 1)
+        .map(|w| (w * 100).to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    let cases = [
+        "", " ", "0", "-320", "3841", "320,,640", "320,", "320;640", "320w", "1.5", "abc",
+        &too_many,
+    ];
+    for value in cases {
+        let err = parse_widths(value, 3840).unwrap_err();
+        match err {
+            AppError::InvalidParameterValue {
+                param, value: v, ..
+            } => {
+                assert_eq!(param, "widths");
+                assert_eq!(v, value);
+            }
+            other => panic!("{value:?}: unexpected {other:?}"),
+        }
+    }
+}
+
+#[test]
+fn test_parse_widths_counts_duplicates_once() {
+    let repeated = vec!["640"; MAX_WIDTHS + 4].join(",");
+    assert_eq!(parse_widths(&repeated, 3840).unwrap(), [640]);
+}
+
+#[test]
+fn test_parse_flag() {
+    assert!(!parse_flag("probe", None).unwrap());
+    assert!(!parse_flag("probe", Some("0")).unwrap());
+    assert!(!parse_flag("probe", Some("false")).unwrap());
+    assert!(parse_flag("probe", Some("1")).unwrap());
+    assert!(parse_flag("probe", Some("true")).unwrap());
+    assert!(matches!(
+        parse_flag("probe", Some("yes")),
+        Err(AppError::InvalidParameterValue { param, .. }) if param == "probe"
+    ));
+}
+
+#[test]
+fn test_image_endpoint() {
+    let path = "/img-optimizer/v1/img/srcset";
+    assert_eq!(
+        image_endpoint(None, "http://127.0.0.1:3000", path),
+        "http://127.0.0.1:3000/img-optimizer/v1/img"
+    );
+    assert_eq!(
+        image_endpoint(Some("https://cdn.example.com/"), "http://10.0.0.1", path),
+        "https://cdn.example.com/img-optimizer/v1/img"
+    );
+    // Mounted under a prefix, as with `nest_service`
+    assert_eq!(
+        image_endpoint(
+            None,
+            "http://app.test",
+            "/images/img-optimizer/v1/img/srcset"
+        ),
+        "http://app.test/images/img-optimizer/v1/img"
+    );
+}
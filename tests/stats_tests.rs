@@ -0,0 +1,105 @@
+#![cfg(feature = "runtime")]
+//! `Stats`: counters updated from many threads at once lose no update, and
+//! the pipeline counts every cache lookup exactly once.
+
+use std::sync::Arc;
+
+use img_optimizer::{
+    cache::ImageCache, error::AppError, image_processor::OutputFormat, metrics::Metrics,
+    stats::Stats, CacheStatus, OptimizeOptions, Optimizer,
+};
+
+const THREADS: u64 = 16;
+const UPDATES: u64 = 20_000;
+
+#[test]
+fn test_concurrent_updates_are_not_lost() {
+    let stats = Arc::new(Stats::new());
+    let threads: Vec<_> = (0..THREADS)
+        .map(|thread| {
+            let stats = stats.clone();
+            std::thread::spawn(move || {
+                for update in 0..UPDATES {
+                    let _in_flight = stats.track_in_flight();
+                    // Interleaved differently on each thread
+                    if (update + thread) % 3 == 0 {
+                        stats.record_cache_miss();
+                    } else {
+                        stats.record_cache_hit();
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let expected_misses: u64 = (0..THREADS)
+        .map(|thread| {
+            (0..UPDATES)
+                .filter(|update| (update + thread) % 3 == 0)
+                .count() as u64
+        })
+        .sum();
+    let snapshot = stats.snapshot();
+    assert_eq!(snapshot.cache_misses, expected_misses);
+    assert_eq!(snapshot.cache_hits, THREADS * UPDATES - expected_misses);
+    assert_eq!(snapshot.in_flight_requests, 0);
+
+    // Exported as they are
+    let metrics = Metrics::with_stats(&stats);
+    let rendered = metrics.render();
+    assert!(rendered.contains(&format!(
+        "img_optimizer_cache_misses_total {expected_misses}"
+    )));
+    assert!(rendered.contains("img_optimizer_in_flight_requests 0"));
+}
+
+fn data_url(width: u32, height: u32) -> String {
+    use base64::{engine::general_purpose, Engine as _};
+    let img = image::RgbImage::from_pixel(width, height, image::Rgb([200, 40, 40]));
+    let mut png = Vec::new();
+    img.write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+        .unwrap();
+    format!(
+        "data:image/png;base64,{}",
+        general_purpose::STANDARD.encode(png)
+    )
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn test_pipeline_counts_every_lookup() {
+    let optimizer = Optimizer::builder().cache(ImageCache::in_memory()).build();
+    let src = data_url(16, 16);
+    let requests: Vec<_> = (0..256u32)
+        .map(|request| {
+            let (optimizer, src) = (optimizer.clone(), src.clone());
+            tokio::spawn(async move {
+                let options = OptimizeOptions {
+                    width: Some(4 + request % 4),
+                    format: Some(OutputFormat::Png),
+                    ..Default::default()
+                };
+                optimizer.optimize(&src, &options).await
+            })
+        })
+        .collect();
+    // Misses over the processing queue are shed, after their lookup
+    let (mut hits, mut shed) = (0, 0);
+    for request in requests {
+        match request.await.unwrap() {
+            Ok(image) if image.cache_status == CacheStatus::Hit => hits += 1,
+            Ok(_) => {}
+            Err(AppError::Overloaded { .. }) => shed += 1,
+            Err(err) => panic!("{err:?}"),
+        }
+    }
+
+    let snapshot = optimizer.state().stats.snapshot();
+    assert_eq!(snapshot.cache_hits, hits);
+    assert_eq!(snapshot.cache_hits + snapshot.cache_misses, 256);
+    assert_eq!(snapshot.shed_requests, shed);
+    assert!(snapshot.cache_misses >= 4);
+    assert_eq!(snapshot.in_flight_requests, 0);
+}
@@ -0,0 +1,546 @@
+//! The transform matrix through `ImageProcessor::process_sync`, with no async
+//! runtime: `cargo test --no-default-features` runs these without tokio.
+
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage, Rgba, RgbaImage};
+use img_optimizer::config::Limits;
+use img_optimizer::error::AppError;
+use img_optimizer::image_processor::{Fit, ImageProcessor, OutputFormat, ProcessingPlan};
+use img_optimizer::metrics::{Phase, PhaseTimings};
+use img_optimizer::sniff::DetectedFormat;
+use img_optimizer::ImageParams;
+use std::io::Cursor;
+
+/// Opaque 400x200 PNG.
+fn landscape() -> Vec<u8> {
+    encode(DynamicImage::ImageRgb8(RgbImage::from_pixel(
+        400,
+        200,
+        Rgb([200, 40, 40]),
+    )))
+}
+
+/// Fully transparent 200x400 PNG.
+fn portrait() -> Vec<u8> {
+    encode(DynamicImage::ImageRgba8(RgbaImage::from_pixel(
+        200,
+        400,
+        Rgba([0, 0, 0, 0]),
+    )))
+}
+
+/// Opaque 3000x1 PNG.
+fn strip() -> Vec<u8> {
+    encode(DynamicImage::ImageRgb8(RgbImage::from_pixel(
+        3000,
+        1,
+        Rgb([40, 200, 40]),
+    )))
+}
+
+fn encode(img: DynamicImage) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    img.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+        .unwrap();
+    bytes
+}
+
+fn plan(width: Option<u32>, height: Option<u32>, fit: Fit) -> ProcessingPlan {
+    ProcessingPlan {
+        steps: Vec::new(),
+        width,
+        height,
+        fit,
+        background: None,
+        quality: 75,
+        format: None,
+    }
+}
+
+fn decode(bytes: &[u8]) -> DynamicImage {
+    image::load_from_memory(bytes).unwrap()
+}
+
+/// Source, width, height and fit, and the expected output size.
+type ResizeCase = (&'static str, Option<u32>, Option<u32>, Fit, (u32, u32));
+
+#[test]
+fn test_resize_matrix() {
+    let cases: &[ResizeCase] = &[
+        ("landscape", Some(100), None, Fit::Contain, (100, 50)),
+        ("landscape", None, Some(50), Fit::Contain, (100, 50)),
+        ("landscape", Some(100), Some(100), Fit::Contain, (100, 50)),
+        ("landscape", Some(100), Some(100), Fit::Cover, (100, 100)),
+        ("landscape", Some(100), Some(100), Fit::Pad, (100, 100)),
+        ("landscape", None, None, Fit::Contain, (400, 200)),
+        // Never enlarged, though padded or cropped to the box
+        ("landscape", Some(800), None, Fit::Contain, (400, 200)),
+        ("landscape", Some(800), Some(800), Fit::Contain, (400, 200)),
+        ("landscape", Some(800), Some(800), Fit::Pad, (800, 800)),
+        ("landscape", Some(800), Some(100), Fit::Cover, (400, 100)),
+        ("portrait", Some(100), None, Fit::Contain, (100, 200)),
+        ("portrait", None, Some(100), Fit::Contain, (50, 100)),
+        ("portrait", Some(100), Some(100), Fit::Contain, (50, 100)),
+        ("portrait", Some(100), Some(100), Fit::Cover, (100, 100)),
+        ("portrait", Some(100), Some(100), Fit::Pad, (100, 100)),
+        // Sides scaled below one pixel are kept at one
+        ("strip", Some(100), None, Fit::Contain, (100, 1)),
+        ("strip", None, Some(100), Fit::Contain, (3000, 1)),
+        ("strip", Some(100), Some(100), Fit::Contain, (100, 1)),
+        ("strip", Some(100), Some(100), Fit::Cover, (100, 1)),
+        ("strip", Some(100), Some(100), Fit::Pad, (100, 100)),
+    ];
+
+    for &(source, width, height, fit, expected) in cases {
+        let (bytes, original) = match source {
+            "landscape" => (landscape(), (400, 200)),
+            "portrait" => (portrait(), (200, 400)),
+            _ => (strip(), (3000, 1)),
+        };
+        let formats = std::iter::once(None).chain(OutputFormat::available().map(Some));
+        for format in formats {
+            let plan = ProcessingPlan {
+                steps: Vec::new(),
+                format,
+                ..plan(width, height, fit)
+            };
+            let case = format!("{source} {plan:?}");
+            let processed = ImageProcessor::process_sync(&bytes, &plan).unwrap();
+
+            assert_eq!((processed.width, processed.height), expected, "{case}");
+            assert_eq!(
+                (processed.original_width, processed.original_height),
+                original,
+                "{case}"
+            );
+            assert_eq!(
+                ImageProcessor::dimensions(&processed.bytes),
+                Some(expected),
+                "{case}"
+            );
+            assert_eq!(
+                DetectedFormat::detect(&processed.bytes).map(DetectedFormat::content_type),
+                Some(processed.content_type),
+                "{case}"
+            );
+        }
+    }
+}
+
+#[test]
+fn test_output_format_defaults_to_the_source_transparency() {
+    let opaque = ImageProcessor::process_sync(&landscape(), &plan(None, None, Fit::Contain));
+    assert_eq!(opaque.unwrap().content_type, "image/jpeg");
+
+    let transparent = ImageProcessor::process_sync(&portrait(), &plan(None, None, Fit::Contain));
+    assert_eq!(transparent.unwrap().content_type, "image/png");
+}
+
+#[test]
+fn test_explicit_output_formats() {
+    for format in OutputFormat::available() {
+        let plan = ProcessingPlan {
+            steps: Vec::new(),
+            format: Some(format),
+            ..plan(Some(100), None, Fit::Contain)
+        };
+        let processed = ImageProcessor::process_sync(&landscape(), &plan).unwrap();
+        assert_eq!(processed.content_type, format.content_type());
+    }
+}
+
+#[test]
+fn test_pad_fills_with_the_background() {
+    let plan = ProcessingPlan {
+        steps: Vec::new(),
+        background: Some([0, 0, 255, 255]),
+        format: Some(OutputFormat::Png),
+        ..plan(Some(100), Some(100), Fit::Pad)
+    };
+    let processed = ImageProcessor::process_sync(&landscape(), &plan).unwrap();
+    let img = decode(&processed.bytes).to_rgba8();
+
+    // 100x50 image centered vertically, padding above and below
+    assert_eq!(img.get_pixel(0, 0), &Rgba([0, 0, 255, 255]));
+    assert_eq!(img.get_pixel(50, 50), &Rgba([200, 40, 40, 255]));
+    assert_eq!(img.get_pixel(99, 99), &Rgba([0, 0, 255, 255]));
+}
+
+#[test]
+fn test_pad_is_transparent_without_a_background() {
+    let plan = ProcessingPlan {
+        steps: Vec::new(),
+        format: Some(OutputFormat::Png),
+        ..plan(Some(100), Some(100), Fit::Pad)
+    };
+    let processed = ImageProcessor::process_sync(&landscape(), &plan).unwrap();
+    assert_eq!(decode(&processed.bytes).to_rgba8().get_pixel(0, 0)[3], 0);
+}
+
+#[test]
+fn test_jpeg_flattens_transparency_onto_the_background() {
+    let jpeg = |background| {
+        let plan = ProcessingPlan {
+            steps: Vec::new(),
+            background,
+            format: Some(OutputFormat::Jpeg),
+            ..plan(Some(50), None, Fit::Contain)
+        };
+        let processed = ImageProcessor::process_sync(&portrait(), &plan).unwrap();
+        let pixel = *decode(&processed.bytes).to_rgb8().get_pixel(25, 50);
+        (pixel, processed.alpha_flattened)
+    };
+
+    // Only flattening without a background is reported
+    let (white, flattened) = jpeg(Some([255, 255, 255, 255]));
+    assert!(white.0.iter().all(|&channel| channel > 245), "{white:?}");
+    assert!(!flattened);
+    let (black, flattened) = jpeg(None);
+    assert!(black.0.iter().all(|&channel| channel < 10), "{black:?}");
+    assert!(flattened);
+
+    let opaque = ProcessingPlan {
+        format: Some(OutputFormat::Jpeg),
+        ..plan(None, None, Fit::Contain)
+    };
+    let processed = ImageProcessor::process_sync(&landscape(), &opaque).unwrap();
+    assert!(!processed.alpha_flattened);
+    let png = ProcessingPlan {
+        format: Some(OutputFormat::Png),
+        ..plan(None, None, Fit::Contain)
+    };
+    let processed = ImageProcessor::process_sync(&portrait(), &png).unwrap();
+    assert!(!processed.alpha_flattened);
+}
+
+/// Only libwebp takes a quality.
+#[cfg(all(feature = "webp-native", not(target_arch = "wasm32")))]
+#[test]
+fn test_quality_changes_webp_output() {
+    let noisy = RgbImage::from_fn(256, 256, |x, y| {
+        Rgb([
+            (x * 7 % 256) as u8,
+            (y * 13 % 256) as u8,
+            ((x ^ y) % 256) as u8,
+        ])
+    });
+    let bytes = encode(DynamicImage::ImageRgb8(noisy));
+    let size = |quality| {
+        let plan = ProcessingPlan {
+            steps: Vec::new(),
+            quality,
+            format: Some(OutputFormat::WebP),
+            ..plan(None, None, Fit::Contain)
+        };
+        ImageProcessor::process_sync(&bytes, &plan)
+            .unwrap()
+            .bytes
+            .len()
+    };
+
+    assert!(size(10) < size(95));
+}
+
+/// The pure-Rust encoder is lossless whatever the quality.
+#[cfg(all(
+    feature = "webp",
+    any(not(feature = "webp-native"), target_arch = "wasm32")
+))]
+#[test]
+fn test_webp_fallback_is_lossless() {
+    let gradient = RgbaImage::from_fn(64, 64, |x, y| {
+        Rgba([(x * 4) as u8, (y * 4) as u8, 128, 200])
+    });
+    let bytes = encode(DynamicImage::ImageRgba8(gradient.clone()));
+    let plan = ProcessingPlan {
+        steps: Vec::new(),
+        quality: 1,
+        format: Some(OutputFormat::WebP),
+        ..plan(None, None, Fit::Contain)
+    };
+    let processed = ImageProcessor::process_sync(&bytes, &plan).unwrap();
+
+    assert_eq!(
+        DetectedFormat::detect(&processed.bytes),
+        Some(DetectedFormat::WebP)
+    );
+    assert_eq!(decode(&processed.bytes).to_rgba8(), gradient);
+}
+
+#[test]
+fn test_webp_availability_follows_features() {
+    assert_eq!(OutputFormat::WebP.is_available(), cfg!(feature = "webp"));
+    assert_eq!(
+        OutputFormat::parse("webp").is_some(),
+        cfg!(feature = "webp")
+    );
+
+    let params = ImageParams {
+        f: Some("webp".to_string()),
+        ..Default::default()
+    };
+    let result = params.validate(&Limits::default());
+    if cfg!(feature = "webp") {
+        assert_eq!(result.unwrap().plan.format, Some(OutputFormat::WebP));
+    } else {
+        let err = result.unwrap_err();
+        assert_eq!(err.error_code(), "IMG_004", "{err}");
+    }
+}
+
+/// Plans built by hand bypass validation and still fail clearly.
+#[cfg(not(feature = "webp"))]
+#[test]
+fn test_webp_without_an_encoder_is_rejected() {
+    let plan = ProcessingPlan {
+        steps: Vec::new(),
+        format: Some(OutputFormat::WebP),
+        ..plan(None, None, Fit::Contain)
+    };
+    let err = ImageProcessor::process_sync(&landscape(), &plan).unwrap_err();
+    assert!(
+        matches!(err, AppError::InvalidImageFormat { ref format } if format == "webp"),
+        "{err}"
+    );
+}
+
+#[test]
+fn test_timed_variant_records_each_phase() {
+    let mut timings = PhaseTimings::default();
+    ImageProcessor::process_sync_timed(
+        &landscape(),
+        &plan(Some(100), None, Fit::Contain),
+        &mut timings,
+    )
+    .unwrap();
+
+    let phases: Vec<Phase> = timings.iter().map(|(phase, _)| *phase).collect();
+    assert_eq!(phases, [Phase::Decode, Phase::Transform, Phase::Encode]);
+}
+
+#[test]
+fn test_invalid_bytes_fail_to_decode() {
+    let err =
+        ImageProcessor::process_sync(b"not an image", &plan(None, None, Fit::Contain)).unwrap_err();
+    assert!(
+        matches!(err, AppError::ImageProcessingFailed { .. }),
+        "{err}"
+    );
+}
+
+/// Found by fuzzing: a header declaring a side of zero passes the pixel
+/// budget and used to reach the WebP encoder with a 1.4 gigapixel width.
+#[test]
+fn test_images_without_pixels_are_rejected() {
+    let mut farbfeld = b"farbfeld".to_vec();
+    farbfeld.extend_from_slice(&0x5208_0001u32.to_be_bytes());
+    farbfeld.extend_from_slice(&0u32.to_be_bytes());
+
+    for format in OutputFormat::available() {
+        let plan = ProcessingPlan {
+            steps: Vec::new(),
+            format: Some(format),
+            ..plan(None, Some(10), Fit::Contain)
+        };
+        let err = ImageProcessor::process_sync(&farbfeld, &plan).unwrap_err();
+        assert!(
+            matches!(err, AppError::ImageProcessingFailed { .. }),
+            "{err}"
+        );
+    }
+}
+
+/// libwebp errors used to panic in the encoder.
+#[cfg(feature = "webp")]
+#[test]
+fn test_webp_dimension_limit() {
+    let wide = encode(DynamicImage::ImageRgb8(RgbImage::new(20_000, 1)));
+    let webp = |width| ProcessingPlan {
+        steps: Vec::new(),
+        format: Some(OutputFormat::WebP),
+        ..plan(width, None, Fit::Contain)
+    };
+
+    let err = ImageProcessor::process_sync(&wide, &webp(None)).unwrap_err();
+    assert!(err.to_string().contains("16383x16383"), "{err}");
+    // Resizing brings it within the limit
+    let processed = ImageProcessor::process_sync(&wide, &webp(Some(3840))).unwrap();
+    assert_eq!((processed.width, processed.height), (3840, 1));
+}
+
+#[test]
+fn test_plan_from_request_parameters() {
+    let params = ImageParams {
+        w: Some("100".to_string()),
+        h: Some("100".to_string()),
+        fit: Some("cover".to_string()),
+        f: Some("png".to_string()),
+        ..Default::default()
+    };
+    let validated = params.validate(&Limits::default()).unwrap();
+    assert_eq!(validated.source, None);
+
+    let processed = ImageProcessor::process_sync(&landscape(), &validated.plan).unwrap();
+    assert_eq!((processed.width, processed.height), (100, 100));
+    assert_eq!(processed.content_type, "image/png");
+}
+
+/// 400x200 PNG, red on the left half and blue on the right.
+fn halves() -> Vec<u8> {
+    encode(DynamicImage::ImageRgb8(RgbImage::from_fn(
+        400,
+        200,
+        |x, _| {
+            if x < 200 {
+                Rgb([255, 0, 0])
+            } else {
+                Rgb([0, 0, 255])
+            }
+        },
+    )))
+}
+
+fn chain(tx: &str, width: Option<u32>) -> ProcessingPlan {
+    let params = ImageParams {
+        tx: Some(tx.to_string()),
+        w: width.map(|width| width.to_string()),
+        f: Some("png".to_string()),
+        ..Default::default()
+    };
+    params.validate(&Limits::default()).unwrap().plan
+}
+
+#[test]
+fn test_chain_steps_run_in_order() {
+    let cases: &[(&str, Option<u32>, (u32, u32))] = &[
+        ("crop:ar=1:1", None, (200, 200)),
+        ("crop:ar=4:5", None, (160, 200)),
+        ("crop:ar=4:1", None, (400, 100)),
+        ("rotate:90", None, (200, 400)),
+        ("rotate:180", None, (400, 200)),
+        ("resize:w=100", None, (100, 50)),
+        ("resize:w=100,h=100,fit=pad", None, (100, 100)),
+        // Crop of the resized image, then the other way round
+        ("resize:w=100/crop:ar=1:1", None, (50, 50)),
+        ("crop:ar=1:1/resize:w=100", None, (100, 100)),
+        ("rotate:90/crop:ar=1:1", None, (200, 200)),
+        // Flat parameters apply after the chain
+        ("crop:ar=1:1", Some(50), (50, 50)),
+        ("rotate:90", Some(100), (100, 200)),
+    ];
+    for &(tx, width, expected) in cases {
+        let output = ImageProcessor::process_sync(&halves(), &chain(tx, width)).unwrap();
+        assert_eq!((output.width, output.height), expected, "{tx}");
+        assert_eq!((output.original_width, output.original_height), (400, 200));
+        let img = decode(&output.bytes);
+        assert_eq!((img.width(), img.height()), expected, "{tx}");
+    }
+}
+
+#[test]
+fn test_chain_pixels() {
+    let pixel = |tx: &str, x: u32, y: u32| {
+        let output = ImageProcessor::process_sync(&halves(), &chain(tx, None)).unwrap();
+        decode(&output.bytes).to_rgba8().get_pixel(x, y).0
+    };
+
+    // Crops keep the side gravity names
+    assert_eq!(pixel("crop:ar=1:1,g=left", 199, 100), [255, 0, 0, 255]);
+    assert_eq!(pixel("crop:ar=1:1,g=right", 0, 100), [0, 0, 255, 255]);
+    assert_eq!(pixel("flip:h", 0, 0), [0, 0, 255, 255]);
+    assert_eq!(pixel("flip:v", 0, 0), [255, 0, 0, 255]);
+    // Red ends up at the top after a quarter turn clockwise
+    assert_eq!(pixel("rotate:90", 100, 0), [255, 0, 0, 255]);
+    assert_eq!(pixel("filter:invert", 0, 0), [0, 255, 255, 255]);
+
+    let [r, g, b, _] = pixel("filter:grayscale", 0, 0);
+    assert!(r == g && g == b && r > 0, "{r} {g} {b}");
+    // Blur mixes the colors across the boundary, and nowhere else
+    let [r, _, b, _] = pixel("filter:blur=5", 200, 100);
+    assert!(r > 0 && b > 0, "{r} {b}");
+    assert_eq!(pixel("filter:blur=5", 100, 100), [255, 0, 0, 255]);
+}
+
+/// Seed of [`noise`], fixed so every run encodes the same pixels.
+const SEED: u64 = 0x5eed_1234_abcd_0001;
+
+/// `width`x`height` of translucent noise, from xorshift64 seeded with `seed`.
+fn noise(seed: u64, width: u32, height: u32) -> RgbaImage {
+    let mut state = seed;
+    RgbaImage::from_fn(width, height, |_, _| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        let [r, g, b, a, ..] = state.to_le_bytes();
+        Rgba([r, g, b, a])
+    })
+}
+
+/// `png` with a `tIME` chunk after its header, as image editors write.
+fn with_modification_time(png: &[u8]) -> Vec<u8> {
+    fn crc32(bytes: &[u8]) -> u32 {
+        !bytes.iter().fold(!0u32, |crc, &byte| {
+            (0..8).fold(crc ^ u32::from(byte), |crc, _| {
+                (crc >> 1) ^ (0xEDB8_8320 & 0u32.wrapping_sub(crc & 1))
+            })
+        })
+    }
+
+    // 2024-05-17 12:34:56
+    let chunk = [&b"tIME"[..], &[0x07, 0xe8, 5, 17, 12, 34, 56]].concat();
+    // Signature (8 bytes) and IHDR (25 bytes)
+    let (head, tail) = png.split_at(33);
+    [
+        head,
+        &7u32.to_be_bytes(),
+        &chunk,
+        &crc32(&chunk).to_be_bytes(),
+        tail,
+    ]
+    .concat()
+}
+
+#[test]
+fn test_encoding_is_deterministic() {
+    let source = with_modification_time(&encode(DynamicImage::ImageRgba8(noise(SEED, 96, 64))));
+    assert!(source.windows(4).any(|chunk| chunk == b"tIME"));
+
+    for format in OutputFormat::available() {
+        for quality in [40, 90] {
+            let plan = ProcessingPlan {
+                steps: Vec::new(),
+                format: Some(format),
+                quality,
+                ..plan(Some(48), None, Fit::Contain)
+            };
+            let first = ImageProcessor::process_sync(&source, &plan).unwrap().bytes;
+            let second = ImageProcessor::process_sync(&source, &plan).unwrap().bytes;
+            assert!(first == second, "{format:?} q={quality}");
+            // Neither the source's timestamps nor new ones end up in the output
+            for marker in [b"tIME", b"eXIf", b"Exif"] {
+                assert!(
+                    !first.windows(4).any(|chunk| chunk == marker),
+                    "{format:?} q={quality}"
+                );
+            }
+        }
+    }
+}
+
+/// JPEG is encoded at the requested quality, not at the encoder's default.
+#[test]
+fn test_jpeg_quality_is_applied() {
+    let source = encode(DynamicImage::ImageRgba8(noise(SEED, 96, 64)));
+    let size = |quality| {
+        let plan = ProcessingPlan {
+            steps: Vec::new(),
+            format: Some(OutputFormat::Jpeg),
+            quality,
+            ..plan(None, None, Fit::Contain)
+        };
+        ImageProcessor::process_sync(&source, &plan)
+            .unwrap()
+            .bytes
+            .len()
+    };
+    assert!(size(20) < size(60) && size(60) < size(95));
+}
@@ -0,0 +1,153 @@
+#![cfg(feature = "actix")]
+
+use actix_web::{middleware::from_fn, web, App, HttpResponse, HttpServer};
+use img_optimizer::{
+    config::AppConfig,
+    error::{AppError, FetchFailure},
+    fetch_image,
+    tls::{self, load_certified_key, plain_http_health_only, ReloadableCert},
+    FetchContext,
+};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tempfile::TempDir;
+
+/// Writes a fresh self-signed certificate and key, returning their paths.
+fn write_self_signed(dir: &Path, name: &str) -> (PathBuf, PathBuf) {
+    let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let cert_path = dir.join(format!("{name}.crt"));
+    let key_path = dir.join(format!("{name}.key"));
+    std::fs::write(&cert_path, generated.cert.pem()).unwrap();
+    std::fs::write(&key_path, generated.signing_key.serialize_pem()).unwrap();
+    (cert_path, key_path)
+}
+
+#[test]
+fn test_load_certificate_errors() {
+    let dir = TempDir::new().unwrap();
+    let (cert_a, key_a) = write_self_signed(dir.path(), "a");
+    let (_, key_b) = write_self_signed(dir.path(), "b");
+
+    load_certified_key(&cert_a, &key_a).unwrap();
+
+    let err = load_certified_key(&cert_a, &key_b).unwrap_err();
+    assert!(format!("{err:#}").contains("does not match"));
+
+    let err = load_certified_key(&dir.path().join("missing.crt"), &key_a).unwrap_err();
+    assert!(format!("{err:#}").contains("Failed to read TLS certificate"));
+
+    // A key file passed as the certificate contains no certificate
+    assert!(load_certified_key(&key_a, &key_a).is_err());
+}
+
+#[test]
+fn test_reload_keeps_previous_certificate_on_error() {
+    let dir = TempDir::new().unwrap();
+    let (cert_path, key_path) = write_self_signed(dir.path(), "server");
+    let cert = ReloadableCert::load(&cert_path, &key_path).unwrap();
+
+    let (new_cert, new_key) = write_self_signed(dir.path(), "renewed");
+    std::fs::copy(&new_cert, &cert_path).unwrap();
+    std::fs::copy(&new_key, &key_path).unwrap();
+    cert.reload().unwrap();
+
+    std::fs::write(&key_path, "not a key").unwrap();
+    assert!(cert.reload().is_err());
+}
+
+#[actix_rt::test]
+async fn test_https_with_plain_http_health_port() {
+    let dir = TempDir::new().unwrap();
+    let (cert_path, key_path) = write_self_signed(dir.path(), "server");
+    let cert = Arc::new(ReloadableCert::load(&cert_path, &key_path).unwrap());
+
+    let https_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let http_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let https_port = https_listener.local_addr().unwrap().port();
+    let http_port = http_listener.local_addr().unwrap().port();
+
+    let server = HttpServer::new(|| {
+        App::new()
+            .wrap(from_fn(plain_http_health_only))
+            .route("/health", web::get().to(HttpResponse::Ok))
+            .route("/img", web::get().to(HttpResponse::Ok))
+    })
+    .workers(1)
+    .listen_rustls_0_23(https_listener, tls::server_config(cert).unwrap())
+    .unwrap()
+    .listen(http_listener)
+    .unwrap()
+    .run();
+    let handle = server.handle();
+    actix_rt::spawn(server);
+
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+
+    let status = |url: String| {
+        let client = client.clone();
+        async move { client.get(url).send().await.unwrap().status().as_u16() }
+    };
+
+    assert_eq!(
+        status(format!("https://localhost:{https_port}/img")).await,
+        200
+    );
+    assert_eq!(
+        status(format!("https://localhost:{https_port}/health")).await,
+        200
+    );
+    assert_eq!(
+        status(format!("http://127.0.0.1:{http_port}/health")).await,
+        200
+    );
+    assert_eq!(
+        status(format!("http://127.0.0.1:{http_port}/img")).await,
+        404
+    );
+
+    handle.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn test_fetch_from_untrusted_certificate_is_a_tls_failure() {
+    let dir = TempDir::new().unwrap();
+    let (cert_path, key_path) = write_self_signed(dir.path(), "origin");
+    let cert = Arc::new(ReloadableCert::load(&cert_path, &key_path).unwrap());
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = HttpServer::new(|| App::new().route("/img.png", web::get().to(HttpResponse::Ok)))
+        .workers(1)
+        .listen_rustls_0_23(listener, tls::server_config(cert).unwrap())
+        .unwrap()
+        .run();
+    let handle = server.handle();
+    actix_rt::spawn(server);
+
+    let err = fetch_image(
+        &reqwest::Client::new(),
+        &format!("https://localhost:{port}/img.png"),
+        &AppConfig::default(),
+        &FetchContext::default(),
+    )
+    .await
+    .unwrap_err();
+    assert!(
+        matches!(
+            err,
+            AppError::OriginUnavailable {
+                failure: FetchFailure::Tls,
+                ..
+            }
+        ),
+        "{err}"
+    );
+    let body = serde_json::to_value(err.to_response()).unwrap();
+    assert!(body["howToFix"].as_str().unwrap().contains("certificate"));
+
+    handle.stop(false).await;
+}
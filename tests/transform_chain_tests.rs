@@ -0,0 +1,282 @@
+//! Parsing of `tx` chains: every step and argument, their canonical form,
+//! and errors naming the failing step.
+
+use img_optimizer::config::Limits;
+use img_optimizer::error::AppError;
+use img_optimizer::image_processor::Fit;
+use img_optimizer::transform_chain::{
+    canonical, parse, Axis, Filter, Gravity, Rotation, TransformStep, MAX_STEPS,
+};
+
+fn limits() -> Limits {
+    Limits {
+        max_width: 2000,
+        max_height: 1500,
+        ..Limits::default()
+    }
+}
+
+fn parse_ok(value: &str) -> Vec<TransformStep> {
+    parse(value, &limits()).unwrap_or_else(|err| panic!("{value:?}: {err:?}"))
+}
+
+/// `(param, value)` of each error reported for `value`.
+fn parse_errors(value: &str) -> Vec<(String, String)> {
+    let errors = match parse(value, &limits()) {
+        Ok(steps) => panic!("{value:?} parsed as {steps:?}"),
+        Err(AppError::ValidationFailed { errors }) => errors,
+        Err(err) => vec![err],
+    };
+    errors
+        .into_iter()
+        .map(|err| match err {
+            AppError::InvalidParameterValue { param, value, .. } => (param, value),
+            other => panic!("{value:?}: unexpected {other:?}"),
+        })
+        .collect()
+}
+
+fn resize(width: Option<u32>, height: Option<u32>) -> TransformStep {
+    TransformStep::Resize {
+        width,
+        height,
+        fit: Fit::Contain,
+        background: None,
+    }
+}
+
+#[test]
+fn test_parse_chain_in_order() {
+    assert_eq!(
+        parse_ok("crop:ar=4:5/resize:w=800/filter:grayscale"),
+        [
+            TransformStep::Crop {
+                width: 4,
+                height: 5,
+                gravity: Gravity::Center,
+            },
+            resize(Some(800), None),
+            TransformStep::Filter(Filter::Grayscale),
+        ]
+    );
+    // Order is kept, and the same step may repeat
+    assert_eq!(
+        parse_ok("filter:invert/rotate:90/filter:invert"),
+        [
+            TransformStep::Filter(Filter::Invert),
+            TransformStep::Rotate(Rotation::Quarter),
+            TransformStep::Filter(Filter::Invert),
+        ]
+    );
+}
+
+#[test]
+fn test_parse_crop() {
+    let crop = |width, height, gravity| TransformStep::Crop {
+        width,
+        height,
+        gravity,
+    };
+    let cases = [
+        ("crop:ar=1:1", crop(1, 1, Gravity::Center)),
+        ("crop:ar=16:9", crop(16, 9, Gravity::Center)),
+        // Reduced to lowest terms
+        ("crop:ar=8:10", crop(4, 5, Gravity::Center)),
+        ("crop:ar=1000:1000", crop(1, 1, Gravity::Center)),
+        ("crop:ar=3:2,g=top", crop(3, 2, Gravity::Top)),
+        ("crop:g=bottom,ar=3:2", crop(3, 2, Gravity::Bottom)),
+        ("crop:ar=3:2,g=left", crop(3, 2, Gravity::Left)),
+        ("crop:ar=3:2,g=right", crop(3, 2, Gravity::Right)),
+        ("crop:ar=3:2,g=center", crop(3, 2, Gravity::Center)),
+    ];
+    for (value, expected) in cases {
+        assert_eq!(parse_ok(value), [expected], "{value:?}");
+    }
+}
+
+#[test]
+fn test_parse_resize() {
+    let cases = [
+        ("resize:w=800", resize(Some(800), None)),
+        ("resize:h=600", resize(None, Some(600))),
+        ("resize:w=2000,h=1500", resize(Some(2000), Some(1500))),
+        (
+            "resize:h=600,w=800,fit=contain",
+            resize(Some(800), Some(600)),
+        ),
+        (
+            "resize:w=800,h=600,fit=cover",
+            TransformStep::Resize {
+                width: Some(800),
+                height: Some(600),
+                fit: Fit::Cover,
+                background: None,
+            },
+        ),
+        (
+            "resize:w=800,h=600,fit=pad,bg=fff",
+            TransformStep::Resize {
+                width: Some(800),
+                height: Some(600),
+                fit: Fit::Pad,
+                background: Some([255, 255, 255, 255]),
+            },
+        ),
+    ];
+    for (value, expected) in cases {
+        assert_eq!(parse_ok(value), [expected], "{value:?}");
+    }
+}
+
+#[test]
+fn test_parse_rotate_flip_and_filters() {
+    let cases = [
+        ("rotate:90", TransformStep::Rotate(Rotation::Quarter)),
+        ("rotate:180", TransformStep::Rotate(Rotation::Half)),
+        ("rotate:270", TransformStep::Rotate(Rotation::ThreeQuarters)),
+        ("flip:h", TransformStep::Flip(Axis::Horizontal)),
+        ("flip:v", TransformStep::Flip(Axis::Vertical)),
+        ("filter:grayscale", TransformStep::Filter(Filter::Grayscale)),
+        ("filter:invert", TransformStep::Filter(Filter::Invert)),
+        ("filter:blur=1", TransformStep::Filter(Filter::Blur(1))),
+        ("filter:blur=50", TransformStep::Filter(Filter::Blur(50))),
+    ];
+    for (value, expected) in cases {
+        assert_eq!(parse_ok(value), [expected], "{value:?}");
+    }
+}
+
+#[test]
+fn test_invalid_steps_are_rejected() {
+    let cases = [
+        // Grammar
+        "",
+        "crop",
+        "crop:",
+        "unknown:x=1",
+        "Crop:ar=4:5",
+        "resize:w=800,",
+        "resize:w=800,w=900",
+        "resize:=800",
+        // crop
+        "crop:ar=4",
+        "crop:ar=4:",
+        "crop:ar=0:5",
+        "crop:ar=4:1001",
+        "crop:ar=4:5:6",
+        "crop:ar=-4:5",
+        "crop:ar=4:5,g=middle",
+        "crop:ar=4:5,x=10",
+        "crop:g=top",
+        "crop:ar",
+        // resize
+        "resize",
+        "resize:w=0",
+        "resize:w=2001",
+        "resize:h=1501",
+        "resize:w=abc",
+        "resize:w=80.5",
+        "resize:w",
+        "resize:fit=cover",
+        "resize:w=800,fit=cover",
+        "resize:w=800,h=600,fit=stretch",
+        "resize:w=800,h=600,bg=fff",
+        "resize:w=800,h=600,fit=pad,bg=nothex",
+        "resize:w=800,q=80",
+        // rotate and flip
+        "rotate",
+        "rotate:45",
+        "rotate:-90",
+        "rotate:deg=90",
+        "rotate:90,180",
+        "flip",
+        "flip:x",
+        "flip:h,v",
+        // filter
+        "filter",
+        "filter:sepia",
+        "filter:blur",
+        "filter:blur=0",
+        "filter:blur=51",
+        "filter:blur=2.5",
+        "filter:grayscale=1",
+        "filter:grayscale,invert",
+    ];
+    for value in cases {
+        assert_eq!(
+            parse_errors(value),
+            [("tx[0]".to_string(), value.to_string())],
+            "{value:?}"
+        );
+    }
+}
+
+#[test]
+fn test_errors_name_every_failing_step() {
+    assert_eq!(
+        parse_errors("crop:ar=4:5/resize:w=0/filter:grayscale/flip:x"),
+        [
+            ("tx[1]".to_string(), "resize:w=0".to_string()),
+            ("tx[3]".to_string(), "flip:x".to_string()),
+        ]
+    );
+    // Empty steps, e.g. from a trailing slash, are errors too
+    assert_eq!(
+        parse_errors("rotate:90/"),
+        [("tx[1]".to_string(), String::new())]
+    );
+}
+
+#[test]
+fn test_error_describes_the_expected_step() {
+    let err = parse("crop:ar=4:5/resize:w=9999", &limits()).unwrap_err();
+    let AppError::InvalidParameterValue { expected, .. } = err else {
+        panic!("unexpected {err:?}");
+    };
+    assert!(expected.contains("between 1 and 2000"), "{expected}");
+
+    let err = parse("blur:3", &limits()).unwrap_err();
+    let AppError::InvalidParameterValue { expected, .. } = err else {
+        panic!("unexpected {err:?}");
+    };
+    assert!(
+        expected.contains("crop, resize, rotate, flip or filter"),
+        "{expected}"
+    );
+}
+
+#[test]
+fn test_step_count_is_limited() {
+    let longest = ["flip:h"; MAX_STEPS].join("/");
+    assert_eq!(parse_ok(&longest).len(), MAX_STEPS);
+
+    let too_long = ["flip:h"; MAX_STEPS + 1].join("/");
+    assert_eq!(parse_errors(&too_long), [("tx".to_string(), too_long)]);
+}
+
+#[test]
+fn test_canonical_form() {
+    let cases = [
+        (
+            "crop:ar=4:5/resize:w=800/filter:grayscale",
+            "crop:ar=4:5/resize:w=800/filter:grayscale",
+        ),
+        ("crop:ar=8:10,g=center", "crop:ar=4:5"),
+        ("crop:g=top,ar=2:2", "crop:ar=1:1,g=top"),
+        ("resize:h=600,w=800,fit=contain", "resize:w=800,h=600"),
+        (
+            "resize:fit=pad,bg=FFF,h=600,w=800",
+            "resize:w=800,h=600,fit=pad,bg=ffffffff",
+        ),
+        (
+            "rotate:270/flip:v/filter:blur=3",
+            "rotate:270/flip:v/filter:blur=3",
+        ),
+    ];
+    for (value, expected) in cases {
+        let steps = parse_ok(value);
+        assert_eq!(canonical(&steps), expected, "{value:?}");
+        // The canonical form parses back to the same steps
+        assert_eq!(parse_ok(expected), steps, "{expected:?}");
+    }
+}
@@ -0,0 +1,128 @@
+#![cfg(feature = "runtime")]
+//! The processing worker pool: jobs, their outcome and shedding once the
+//! queue is full.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use img_optimizer::{
+    error::AppError,
+    image_processor::{Fit, OutputFormat, ProcessingPlan},
+    metrics::{Phase, PhaseTimings},
+    test_support::fixture_png,
+    worker_pool::WorkerPool,
+};
+
+fn plan(width: u32) -> ProcessingPlan {
+    ProcessingPlan {
+        steps: Vec::new(),
+        width: Some(width),
+        height: None,
+        fit: Fit::Contain,
+        background: None,
+        quality: 75,
+        format: Some(OutputFormat::Png),
+    }
+}
+
+async fn wait_until_picked_up(pool: &WorkerPool) {
+    for _ in 0..500 {
+        if pool.queued() == 0 {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    panic!("No worker picked the job up");
+}
+
+#[tokio::test]
+async fn test_process_records_phases() {
+    let pool = WorkerPool::new(2, 4, false);
+    let mut timings = PhaseTimings::default();
+
+    let processed = pool
+        .process(fixture_png(8, 4), &plan(4), &mut timings)
+        .await
+        .unwrap();
+    assert_eq!((processed.width, processed.height), (4, 2));
+    assert_eq!(processed.content_type, "image/png");
+    let phases: Vec<_> = timings.iter().map(|&(phase, _)| phase).collect();
+    assert_eq!(phases, [Phase::Decode, Phase::Transform, Phase::Encode]);
+    assert_eq!(pool.workers(), 2);
+    assert_eq!(pool.queued(), 0);
+}
+
+#[tokio::test]
+async fn test_processing_errors_are_returned() {
+    let pool = WorkerPool::new(1, 1, false);
+    let err = pool
+        .process(
+            b"not an image".to_vec(),
+            &plan(4),
+            &mut PhaseTimings::default(),
+        )
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err, AppError::ImageProcessingFailed { .. }),
+        "{err:?}"
+    );
+
+    // The worker keeps serving jobs
+    pool.process(fixture_png(8, 8), &plan(4), &mut PhaseTimings::default())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_full_queue_sheds_jobs() {
+    let pool = Arc::new(WorkerPool::new(1, 1, true));
+    let large = fixture_png(1200, 1200);
+
+    // The only worker is busy with the first job, the second waits for it
+    let first = pool.submit(large.clone(), &plan(600)).unwrap();
+    wait_until_picked_up(&pool).await;
+    let second = pool.submit(large, &plan(300)).unwrap();
+    assert_eq!(pool.queued(), 1);
+
+    let err = pool.submit(fixture_png(8, 8), &plan(4)).unwrap_err();
+    assert!(
+        matches!(
+            err,
+            AppError::Overloaded {
+                unavailable: true,
+                ..
+            }
+        ),
+        "{err:?}"
+    );
+    assert_eq!(pool.queued(), 1);
+
+    let first = first.await.unwrap().into_result().unwrap();
+    assert_eq!(first.width, 600);
+    let second = second.await.unwrap().into_result().unwrap();
+    assert_eq!(second.width, 300);
+    assert_eq!(pool.queued(), 0);
+
+    // Accepted again once the queue drained
+    pool.process(fixture_png(8, 8), &plan(4), &mut PhaseTimings::default())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_workers_process_jobs_in_parallel() {
+    let pool = Arc::new(WorkerPool::new(2, 1, false));
+    let large = fixture_png(1200, 1200);
+
+    // Both workers take a job, leaving the queue free for a third
+    let first = pool.submit(large.clone(), &plan(600)).unwrap();
+    wait_until_picked_up(&pool).await;
+    let second = pool.submit(large, &plan(300)).unwrap();
+    wait_until_picked_up(&pool).await;
+    let third = pool.submit(fixture_png(8, 8), &plan(4)).unwrap();
+
+    for outcome in [first, second, third] {
+        outcome.await.unwrap().into_result().unwrap();
+    }
+}